@@ -1,19 +1,33 @@
+// Records can be exported into JSON and imported back.
+const SERDE: &str = "#[derive(serde::Serialize, serde::Deserialize)]";
+
 fn main() -> std::io::Result<()> {
     let mut config = prost_build::Config::new();
 
     config.type_attribute("Point2", "#[derive(Copy)] #[repr(C)]");
     config.type_attribute("Point3", "#[derive(Copy)] #[repr(C)]");
 
-    config.type_attribute("Point2", "#[derive(serde::Serialize)]");
-    config.type_attribute("Point3", "#[derive(serde::Serialize)]");
-    config.type_attribute("Image", "#[derive(serde::Serialize)]");
-    config.type_attribute("ElementView", "#[derive(serde::Serialize)]");
-    config.type_attribute("ElementView.Face", "#[derive(serde::Serialize)]");
-    config.type_attribute("ElementViewState", "#[derive(serde::Serialize)]");
-    config.type_attribute("Scan", "#[derive(serde::Serialize)]");
-    config.type_attribute("ScanFrame", "#[derive(serde::Serialize)]");
-    config.type_attribute("Record", "#[derive(serde::Serialize)]");
-    config.type_attribute("Record.type", "#[derive(serde::Serialize)]");
+    config.type_attribute("Point2", SERDE);
+    config.type_attribute("Point3", SERDE);
+    config.type_attribute("Image", SERDE);
+    config.type_attribute("ElementView", SERDE);
+    config.type_attribute("ElementView.Face", SERDE);
+    config.type_attribute("ElementView.Loop", SERDE);
+    config.type_attribute("ElementViewState", SERDE);
+    config.type_attribute("ElementTexture", SERDE);
+    config.type_attribute("ElementViewRefinement", SERDE);
+    config.type_attribute("ElementViewRefinement.Vertex", SERDE);
+    config.type_attribute("ElementViewRefinement.Face", SERDE);
+    config.type_attribute("Scan", SERDE);
+    config.type_attribute("Scan.Camera", SERDE);
+    config.type_attribute("Scan.ColorCamera", SERDE);
+    config.type_attribute("ScanFrame", SERDE);
+    config.type_attribute("ScanFrame.CameraAngle", SERDE);
+    config.type_attribute("ScanFrame.ImageCrop", SERDE);
+    config.type_attribute("Preview", SERDE);
+    config.type_attribute("Impostors", SERDE);
+    config.type_attribute("Record", SERDE);
+    config.type_attribute("Record.type", SERDE);
 
     config.compile_protos(&["src/fm/data.proto"], &["src/"])?;
 
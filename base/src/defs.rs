@@ -23,6 +23,7 @@ pub enum ErrorKind {
     ArgminError = 11,
     PoissonError = 12,
     ImageError = 13,
+    GeometryError = 14,
 }
 
 #[derive(Debug)]
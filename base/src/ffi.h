@@ -6,6 +6,8 @@
 
 enum FmError {
   kFmOk = 0,
+  kFmBadOperation = 2,
+  kFmInconsistentState = 3,
   kFmIoError = 4,
   kFmMalformedData = 7,
   kFmUnsupportedFeature = 8,
@@ -75,4 +77,34 @@ struct FmScanFrame {
 enum FmError fm_write_scan_frame(FmWriter writer,
                                  const struct FmScanFrame *frame);
 
+typedef void *FmUpload;
+
+// Reads exactly data_size bytes of uploaded data at the given offset.
+typedef enum FmError (*FmUploadReadCallback)(uint64_t offset, uint8_t *data,
+                                             size_t data_size, void *cb_data);
+
+// Zero chunk size means the default one (4 MiB).
+enum FmError fm_create_upload(uint64_t size, uint64_t chunk_size,
+                              FmUploadReadCallback callback, void *cb_data,
+                              FmUpload *upload);
+
+// Returns JSON manifest to start the upload with, which is valid until the
+// upload is destroyed.
+const char *fm_upload_manifest(FmUpload upload);
+
+// Continues after data stored by the server (as negotiated for manifest).
+enum FmError fm_resume_upload(FmUpload upload, uint64_t stored);
+
+struct FmUploadChunk {
+  uint64_t offset;
+  const uint8_t *data;
+  size_t data_size;
+};
+
+// Chunk data is valid until the next call, it's null after the last chunk.
+enum FmError fm_next_upload_chunk(FmUpload upload,
+                                  struct FmUploadChunk *chunk);
+
+void fm_destroy_upload(FmUpload upload);
+
 #endif  // FITSME_BASE_FFI_H_
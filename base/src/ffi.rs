@@ -1,14 +1,16 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::io::{
     Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, Write,
 };
 use std::os::raw::{c_char, c_float, c_int, c_void};
+use std::ptr::null;
 use std::slice::from_raw_parts;
 
 use crate::defs::IntoResult;
 use crate::fm;
 use crate::fm::record::Type::*;
 use crate::fm::{Write as _, Writer, WriterParams};
+use crate::util::upload::{ChunkReader, PositionedReader, DEFAULT_CHUNK_SIZE};
 
 type FmWriteCallback = extern "C" fn(
     fm_data: *const u8,
@@ -111,6 +113,8 @@ pub unsafe extern "C" fn fm_write_scan(
             depth_width: scan.depth_width as u32,
             depth_height: scan.depth_height as u32,
             sensor_plane_depth: scan.sensor_plane_depth != 0,
+            cameras: Vec::new(),
+            color_camera: None,
         })),
     };
 
@@ -147,6 +151,7 @@ pub unsafe extern "C" fn fm_write_scan_frame(
             r#type: (*frame.image).r#type,
             data: from_raw_parts((*frame.image).data, (*frame.image).data_size)
                 .to_vec(),
+            ..Default::default()
         })
     };
 
@@ -163,6 +168,9 @@ pub unsafe extern "C" fn fm_write_scan_frame(
             .iter()
             .map(|c| *c as i32)
             .collect(),
+            camera_angle: None,
+            camera: 0,
+            image_crop: None,
         })),
     };
 
@@ -175,3 +183,103 @@ unsafe fn write_record(writer: FmWriter, rec: &fm::Record) -> c_int {
         Err(err) => err.kind as c_int,
     }
 }
+
+type FmUploadReadCallback = extern "C" fn(
+    offset: u64,
+    data: *mut u8,
+    size: usize,
+    cb_data: *mut c_void,
+) -> c_int;
+
+type FmUploadReadAt = Box<dyn FnMut(u64, &mut [u8]) -> IoResult<()>>;
+
+struct FmUploadData {
+    reader: ChunkReader<PositionedReader<FmUploadReadAt>>,
+    manifest: CString,
+}
+
+pub type FmUpload = *mut c_void;
+
+#[repr(C)]
+pub struct FmUploadChunk {
+    offset: u64,
+    data: *const u8,
+    data_size: usize,
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fm_create_upload(
+    size: u64,
+    chunk_size: u64,
+    callback: FmUploadReadCallback,
+    cb_data: *mut c_void,
+    upload: &mut FmUpload,
+) -> c_int {
+    let read_at: FmUploadReadAt = Box::new(move |offset, buf| {
+        let ret = callback(offset, buf.as_mut_ptr(), buf.len(), cb_data);
+        ret.into_result(|| "FFI error".to_string())
+            .map_err(IoError::other)
+    });
+    let chunk_size = if chunk_size == 0 {
+        DEFAULT_CHUNK_SIZE
+    } else {
+        chunk_size
+    };
+    let reader = PositionedReader::new(read_at, size);
+    match ChunkReader::new(reader, chunk_size) {
+        Ok(reader) => {
+            let manifest = serde_json::to_string(reader.manifest()).unwrap();
+            let data = FmUploadData {
+                reader,
+                manifest: CString::new(manifest).unwrap(),
+            };
+            *upload = Box::into_raw(Box::new(data)) as FmUpload;
+            0
+        }
+        Err(err) => err.kind as c_int,
+    }
+}
+
+// Returned JSON is valid until the upload is destroyed.
+#[no_mangle]
+pub unsafe extern "C" fn fm_upload_manifest(upload: FmUpload) -> *const c_char {
+    (*(upload as *mut FmUploadData)).manifest.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fm_resume_upload(
+    upload: FmUpload,
+    stored: u64,
+) -> c_int {
+    match (*(upload as *mut FmUploadData)).reader.resume(stored) {
+        Ok(()) => 0,
+        Err(err) => err.kind as c_int,
+    }
+}
+
+// Chunk data is valid until the next call, it's null after the last chunk.
+#[no_mangle]
+pub unsafe extern "C" fn fm_next_upload_chunk(
+    upload: FmUpload,
+    chunk: &mut FmUploadChunk,
+) -> c_int {
+    match (*(upload as *mut FmUploadData)).reader.next_chunk() {
+        Ok(Some(next)) => {
+            chunk.offset = next.offset;
+            chunk.data = next.data.as_ptr();
+            chunk.data_size = next.data.len();
+            0
+        }
+        Ok(None) => {
+            chunk.data = null();
+            chunk.data_size = 0;
+            0
+        }
+        Err(err) => err.kind as c_int,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fm_destroy_upload(upload: FmUpload) {
+    drop(Box::from_raw(upload as *mut FmUploadData));
+}
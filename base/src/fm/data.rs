@@ -37,3 +37,31 @@ impl FromStr for scan_frame::DepthConfidence {
         }
     }
 }
+
+impl scan_frame::ImageCrop {
+    // Maps point of the whole image into the cropped one, both are given
+    // in fractions of image height and width.
+    pub fn crop_point(&self, [i, j]: [f64; 2]) -> [f64; 2] {
+        let (top, left) = (self.top as f64, self.left as f64);
+        [
+            (i - top) / (self.bottom as f64 - top),
+            (j - left) / (self.right as f64 - left),
+        ]
+    }
+
+    // Maps point of the cropped image into the whole one.
+    pub fn uncrop_point(&self, [i, j]: [f64; 2]) -> [f64; 2] {
+        let (top, left) = (self.top as f64, self.left as f64);
+        [
+            top + i * (self.bottom as f64 - top),
+            left + j * (self.right as f64 - left),
+        ]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (0.0..self.bottom).contains(&self.top)
+            && self.bottom <= 1.0
+            && (0.0..self.right).contains(&self.left)
+            && self.right <= 1.0
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::io::{Read as _, Write as _};
+use std::path::{Component, Path, PathBuf};
+
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+use crate::fm::{image, image_digest, image_type_extension, Image};
+use crate::util::fs;
+
+// Loads data of external images, so that readers can fetch them from
+// custom storages.
+pub trait ImageResolver: Debug {
+    fn resolve(&self, location: &str) -> Result<Vec<u8>>;
+}
+
+// Reads external images from files relative to the base directory. Unless
+// unrestricted, absolute paths, parent directories and remote locations
+// are rejected, as locations come from input files.
+#[derive(Debug, Default)]
+pub struct FileImageResolver {
+    pub base: PathBuf,
+    pub unrestricted: bool,
+}
+
+impl FileImageResolver {
+    fn check_location(&self, location: &str) -> Result<()> {
+        if self.unrestricted {
+            return Ok(());
+        }
+
+        let confined = fs::remote_url(location).is_none()
+            && Path::new(location)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !confined {
+            let desc = format!(
+                "image location '{}' is outside of .fm file directory",
+                location
+            );
+            return Err(Error::new(BadOperation, desc));
+        }
+        Ok(())
+    }
+}
+
+impl ImageResolver for FileImageResolver {
+    fn resolve(&self, location: &str) -> Result<Vec<u8>> {
+        self.check_location(location)?;
+        let path = match fs::remote_url(location) {
+            Some(_) => PathBuf::from(location),
+            None => self.base.join(location),
+        };
+        let mut data = Vec::new();
+        fs::open_input(&path)?
+            .read_to_end(&mut data)
+            .into_result(|| format!("failed to read image '{}'", location))?;
+        Ok(data)
+    }
+}
+
+// Embeds data of an external image, verifying it against the digest.
+pub fn resolve_image(
+    image: &mut Image,
+    resolver: &dyn ImageResolver,
+) -> Result<()> {
+    if image.location.is_empty() {
+        return Ok(());
+    }
+
+    let data = resolver.resolve(&image.location)?;
+    if image_digest(&data) != image.digest {
+        let desc = format!("digest mismatch for image '{}'", image.location);
+        return Err(Error::new(MalformedData, desc));
+    }
+
+    image.data = data;
+    image.digest.clear();
+    image.location.clear();
+    Ok(())
+}
+
+// Stores images in a directory or remote location once per digest. Local
+// locations are relative to the base directory (current one by default).
+#[derive(Debug)]
+pub struct ImageStore {
+    path: PathBuf,
+    base: PathBuf,
+    digests: HashSet<Vec<u8>>,
+}
+
+impl ImageStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            base: PathBuf::new(),
+            digests: HashSet::new(),
+        }
+    }
+
+    pub fn set_base<P: AsRef<Path>>(&mut self, base: P) {
+        self.base = base.as_ref().to_path_buf();
+    }
+
+    // Moves image data to the store, replacing it with a reference.
+    pub fn externalize(&mut self, image: &mut Image) -> Result<()> {
+        if image.data.is_empty() {
+            return Ok(());
+        }
+
+        let digest = image_digest(&image.data);
+        let r#type = image::Type::from_i32(image.r#type)
+            .filter(|t| *t != image::Type::None)
+            .ok_or_else(|| {
+                let desc = "unsupported external image type".to_string();
+                Error::new(UnsupportedFeature, desc)
+            })?;
+        let name: String =
+            digest.iter().map(|b| format!("{:02x}", b)).collect();
+        let name = format!("{}.{}", name, image_type_extension(r#type));
+        let path = self.path.join(name);
+        let location = fs::relative_path(&path, &self.base)
+            .unwrap_or_else(|| path.clone());
+        let location = location.to_str().ok_or_else(|| {
+            let desc = "non-unicode image store path".to_string();
+            Error::new(BadOperation, desc)
+        })?;
+
+        if self.digests.insert(digest.clone()) {
+            fs::create_output(&path)?
+                .write_all(&image.data)
+                .into_result(|| {
+                    format!("failed to write image '{}'", location)
+                })?;
+        }
+
+        image.location = location.to_string();
+        image.digest = digest;
+        image.data.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_image_resolver_confinement() {
+        let base = std::env::temp_dir()
+            .join(format!("resolver-test-{}", std::process::id()));
+        std::fs::create_dir_all(base.join("images")).unwrap();
+        std::fs::write(base.join("images/a.png"), b"a").unwrap();
+
+        let mut resolver = FileImageResolver {
+            base: base.join("images"),
+            unrestricted: false,
+        };
+        assert_eq!(resolver.resolve("a.png").unwrap(), b"a");
+        assert_eq!(resolver.resolve("./a.png").unwrap(), b"a");
+
+        let abs = base.join("images/a.png");
+        let abs = abs.to_str().unwrap();
+        for location in [abs, "../images/a.png", "https://host/a.png"] {
+            let err = resolver.resolve(location).unwrap_err();
+            assert_eq!(err.kind, BadOperation);
+        }
+
+        resolver.unrestricted = true;
+        assert_eq!(resolver.resolve(abs).unwrap(), b"a");
+        assert_eq!(resolver.resolve("../images/a.png").unwrap(), b"a");
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read as _, Seek, SeekFrom};
+use std::ops::RangeBounds;
+
+use prost::Message;
+
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+use crate::fm::{
+    record, Compression, ElementView, ElementViewState, Index, RawReader, Read,
+    Reader, ReaderLimits, Record, Scan, ScanFrame, Time, FEATURE_INDEX,
+    INDEX_MARKER, MAGIC,
+};
+
+pub trait ReadSeek: io::Read + Seek {}
+
+impl<T: io::Read + Seek> ReadSeek for T {}
+
+// Indexed .fm file whose records are read from their offsets on access.
+struct IndexedFile {
+    inner: Box<dyn ReadSeek>,
+    compression: Compression,
+    offsets: Vec<u64>,
+    limits: ReaderLimits,
+}
+
+impl IndexedFile {
+    // Reads record data (from the unit starting at the offset).
+    fn read(&mut self, offset: u64) -> Result<Vec<u8>> {
+        let desc = || "failed to read indexed .fm record".to_string();
+        self.inner.seek(SeekFrom::Start(offset)).into_result(desc)?;
+        let mut reader = RawReader::new(self.inner.as_mut(), self.compression)?;
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).into_result(desc)?;
+        let size = u32::from_le_bytes(buf) as usize;
+        self.limits.check_record_size(size)?;
+
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data).into_result(desc)?;
+        Ok(data)
+    }
+
+    // Reads index data, returns None unless the index unit starts at the
+    // offset and is followed by the trailer only (so that nothing was
+    // appended to the file).
+    fn read_index(&mut self, offset: u64) -> Result<Option<Vec<u8>>> {
+        let desc = || "failed to read .fm index".to_string();
+        self.inner.seek(SeekFrom::Start(offset)).into_result(desc)?;
+        let mut reader = RawReader::new(self.inner.as_mut(), self.compression)?;
+
+        let mut buf = [0; 4];
+        if reader.read_exact(&mut buf).is_err()
+            || u32::from_le_bytes(buf) != INDEX_MARKER
+        {
+            return Ok(None);
+        }
+        reader.read_exact(&mut buf).into_result(desc)?;
+        let size = u32::from_le_bytes(buf) as usize;
+        self.limits.check_record_size(size)?;
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data).into_result(desc)?;
+
+        reader.finish_unit().into_result(desc)?;
+        let mut trailer = [0; 12];
+        reader
+            .inner_mut()
+            .read_exact(&mut trailer)
+            .into_result(desc)?;
+        Ok((!reader.has_more().into_result(desc)?).then_some(data))
+    }
+}
+
+// Random access to records by element (or scan) and time. Records are kept
+// encoded and decoded on access, or (for indexed files) read on access.
+#[derive(Default)]
+pub struct IndexedReader {
+    records: Vec<Vec<u8>>,
+    file: Option<RefCell<IndexedFile>>,
+    views: Vec<(String, usize)>,
+    states: HashMap<String, BTreeMap<Time, usize>>,
+    scans: Vec<(String, usize)>,
+    frames: HashMap<String, BTreeMap<Time, usize>>,
+}
+
+// Index of a record (of an indexed file) refers to a record of other type.
+fn mismatch_err(index: usize, expected: &str) -> Error {
+    let desc = format!(
+        "indexed .fm record #{} isn't {} (mismatched index)",
+        index, expected
+    );
+    Error::new(MalformedData, desc)
+}
+
+fn range_indices(
+    indices: Option<&BTreeMap<Time, usize>>,
+    range: impl RangeBounds<Time>,
+) -> Vec<usize> {
+    indices
+        .map(|indices| indices.range(range).map(|(_, &i)| i).collect())
+        .unwrap_or_default()
+}
+
+impl IndexedReader {
+    pub fn new(reader: &mut dyn Read) -> Result<Self> {
+        let mut indexed = IndexedReader::default();
+
+        while let Some(raw) = reader.read_raw_record()? {
+            let index = indexed.records.len();
+            let data = raw.as_bytes().to_vec();
+
+            use record::Type::*;
+            match raw.decode()?.r#type {
+                Some(ElementView(v)) => indexed.views.push((v.element, index)),
+                Some(ElementViewState(s)) => {
+                    let states = indexed.states.entry(s.element).or_default();
+                    states.insert(Time(s.time), index);
+                }
+                Some(Scan(s)) => indexed.scans.push((s.name, index)),
+                Some(ScanFrame(f)) => {
+                    let frames = indexed.frames.entry(f.scan).or_default();
+                    frames.insert(Time(f.time), index);
+                }
+                // Kept empty, so that indices are record numbers.
+                _ => {
+                    indexed.records.push(Vec::new());
+                    continue;
+                }
+            }
+
+            indexed.records.push(data);
+        }
+
+        Ok(indexed)
+    }
+
+    // Reads index of .fm file (without reading records), returns None if
+    // the file isn't indexed (or has other streams appended). External
+    // images are left unresolved.
+    pub fn open<R: ReadSeek + 'static>(
+        mut inner: R,
+        limits: ReaderLimits,
+    ) -> Result<Option<Self>> {
+        let reader = Reader::new(&mut inner)?;
+        if reader.features() & FEATURE_INDEX == 0 {
+            return Ok(None);
+        }
+        let compression = reader.compression();
+        drop(reader);
+
+        let desc = || "failed to read .fm index".to_string();
+        let mut buf = [0; 12];
+        inner.seek(SeekFrom::End(-12)).into_result(desc)?;
+        inner.read_exact(&mut buf).into_result(desc)?;
+        let (offset, magic) = buf.split_at(8);
+        if magic != MAGIC.to_le_bytes() {
+            return Ok(None); // Appended data, read sequentially.
+        }
+        let offset = u64::from_le_bytes(offset.try_into().unwrap());
+
+        let mut file = IndexedFile {
+            inner: Box::new(inner),
+            compression,
+            offsets: Vec::new(),
+            limits,
+        };
+        let data = match file.read_index(offset)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let index = Index::decode(data.as_slice()).into_result(desc)?;
+
+        let mut indexed = IndexedReader::default();
+        for (position, entry) in index.entries.into_iter().enumerate() {
+            file.offsets.push(entry.offset);
+            match entry.r#type {
+                1 => indexed.views.push((entry.name, position)),
+                2 => {
+                    let states = indexed.states.entry(entry.name).or_default();
+                    states.insert(Time(entry.time), position);
+                }
+                3 => indexed.scans.push((entry.name, position)),
+                4 => {
+                    let frames = indexed.frames.entry(entry.name).or_default();
+                    frames.insert(Time(entry.time), position);
+                }
+                _ => (),
+            }
+        }
+
+        indexed.file = Some(RefCell::new(file));
+        Ok(Some(indexed))
+    }
+
+    fn record(&self, index: usize) -> Result<record::Type> {
+        let data = match &self.file {
+            Some(file) => {
+                let mut file = file.borrow_mut();
+                let offset = file.offsets[index];
+                file.read(offset)?
+            }
+            None => self.records[index].clone(),
+        };
+        let rec = Record::decode(data.as_slice())
+            .into_result(|| "failed to decode .fm record".to_string())?;
+        rec.r#type.ok_or_else(|| {
+            let desc = format!("indexed .fm record #{} has no type", index);
+            Error::new(MalformedData, desc)
+        })
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &str> {
+        self.views.iter().map(|(e, _)| e.as_str())
+    }
+
+    pub fn view(&self, element: &str) -> Result<Option<ElementView>> {
+        let index = self.views.iter().find(|(e, _)| e == element);
+        match index {
+            Some(&(_, index)) => self.decode_view(index).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_view(&self, index: usize) -> Result<ElementView> {
+        match self.record(index)? {
+            record::Type::ElementView(v) => Ok(v),
+            _ => Err(mismatch_err(index, "ElementView")),
+        }
+    }
+
+    pub fn views(&self) -> impl Iterator<Item = Result<ElementView>> + '_ {
+        self.views.iter().map(|&(_, i)| self.decode_view(i))
+    }
+
+    pub fn states(
+        &self,
+        element: &str,
+        range: impl RangeBounds<Time>,
+    ) -> impl Iterator<Item = Result<ElementViewState>> + '_ {
+        range_indices(self.states.get(element), range)
+            .into_iter()
+            .map(|i| match self.record(i)? {
+                record::Type::ElementViewState(s) => Ok(s),
+                _ => Err(mismatch_err(i, "ElementViewState")),
+            })
+    }
+
+    pub fn scans(&self) -> impl Iterator<Item = Result<Scan>> + '_ {
+        self.scans.iter().map(|&(_, i)| match self.record(i)? {
+            record::Type::Scan(s) => Ok(s),
+            _ => Err(mismatch_err(i, "Scan")),
+        })
+    }
+
+    pub fn frames(
+        &self,
+        scan: &str,
+        range: impl RangeBounds<Time>,
+    ) -> impl Iterator<Item = Result<ScanFrame>> + '_ {
+        self.numbered_frames(scan, range).map(|f| f.map(|(_, f)| f))
+    }
+
+    // Frames along with (zero-based) numbers of their records.
+    pub fn numbered_frames(
+        &self,
+        scan: &str,
+        range: impl RangeBounds<Time>,
+    ) -> impl Iterator<Item = Result<(usize, ScanFrame)>> + '_ {
+        range_indices(self.frames.get(scan), range)
+            .into_iter()
+            .map(|i| match self.record(i)? {
+                record::Type::ScanFrame(f) => Ok((i, f)),
+                _ => Err(mismatch_err(i, "ScanFrame")),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fm::{self, Write as _};
+    use crate::util::test::*;
+
+    fn new_view_rec(element: &str) -> Record {
+        new_element_view_rec(fm::ElementView {
+            element: element.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn new_state_rec(element: &str, time: i64) -> Record {
+        new_element_view_state_rec(fm::ElementViewState {
+            element: element.to_string(),
+            time,
+            ..Default::default()
+        })
+    }
+
+    fn write_records(compression: Compression, records: &[Record]) -> Vec<u8> {
+        let params = fm::WriterParams {
+            compression,
+            index: true,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_indexed_reader() {
+        let records = [
+            new_view_rec("a"),
+            new_view_rec("b"),
+            new_state_rec("a", 1),
+            new_state_rec("b", 2),
+            new_state_rec("a", 3),
+            new_state_rec("a", 5),
+        ];
+
+        let mut reader = create_reader_with_records(&records);
+        let indexed = IndexedReader::new(&mut reader).unwrap();
+        let data = write_records(Compression::Gzip, &records);
+        let opened =
+            IndexedReader::open(io::Cursor::new(data), Default::default())
+                .unwrap()
+                .unwrap();
+
+        for indexed in [indexed, opened] {
+            assert_eq!(indexed.elements().collect::<Vec<_>>(), vec!["a", "b"]);
+            assert_eq!(indexed.view("b").unwrap().unwrap().element, "b");
+            assert!(indexed.view("c").unwrap().is_none());
+
+            let times = |element, range| {
+                indexed
+                    .states(element, range)
+                    .map(|s| s.unwrap().time)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(times("a", Time(2)..=Time(5)), vec![3, 5]);
+            assert_eq!(times("b", Time(0)..=Time(5)), vec![2]);
+            assert_eq!(times("c", Time(0)..=Time(5)), Vec::<i64>::new());
+            assert_eq!(indexed.frames("s", ..).count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_indexed_reader_appended() {
+        // Appended streams aren't covered by the index.
+        let mut data = write_records(Compression::None, &[new_view_rec("a")]);
+        data.extend(write_records(Compression::Gzip, &[new_view_rec("b")]));
+        let indexed =
+            IndexedReader::open(io::Cursor::new(data), Default::default());
+        assert!(indexed.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_indexed_reader_mismatched_index() {
+        let params = fm::WriterParams {
+            index: true,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        writer
+            .write_record(&new_element_view_rec(fm::ElementView {
+                element: "a".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        writer
+            .write_record(&new_scan_rec(fm::Scan {
+                name: "s".to_string(),
+                ..Default::default()
+            }))
+            .unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let cursor = io::Cursor::new(data);
+        let mut indexed = IndexedReader::open(cursor, Default::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(indexed.view("a").unwrap().unwrap().element, "a");
+
+        // Swap entries, as if the index referred to wrong records.
+        std::mem::swap(&mut indexed.views[0].1, &mut indexed.scans[0].1);
+        let err = indexed.view("a").unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        let err = indexed.scans().next().unwrap().unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+
+        indexed
+            .states
+            .entry("a".to_string())
+            .or_default()
+            .insert(Time(1), 0);
+        let err = indexed.states("a", ..).next().unwrap().unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        indexed
+            .frames
+            .entry("s".to_string())
+            .or_default()
+            .insert(Time(1), 1);
+        let err = indexed.frames("s", ..).next().unwrap().unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+
+        // Record without type.
+        let indexed = IndexedReader {
+            records: vec![Vec::new()],
+            views: vec![("a".to_string(), 0)],
+            ..Default::default()
+        };
+        let err = indexed.view("a").unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+    }
+}
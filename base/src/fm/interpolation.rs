@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::ops::Bound::*;
+
+use crate::fm::{ElementViewState, Point3, Time};
+
+// Time-keyed state which can be interpolated between its neighbours.
+pub trait Interpolate: Clone {
+    fn interpolate_linear(at: Time, a: (Time, &Self), b: (Time, &Self))
+        -> Self;
+
+    fn interpolate_quadratic(
+        at: Time,
+        a: (Time, &Self),
+        b: (Time, &Self),
+        c: (Time, &Self),
+    ) -> Self;
+}
+
+// Times are shifted to the first point to keep f32 precision.
+pub fn interpolate_points_linear(
+    at: Time,
+    a: (Time, &[Point3]),
+    b: (Time, &[Point3]),
+) -> Vec<Point3> {
+    #[inline]
+    fn interpolate(at: f32, a: (f32, f32), b: (f32, f32)) -> f32 {
+        (b.1 - a.1) / (b.0 - a.0) * (at - b.0) + b.1
+    }
+
+    let (at, a0, b0) = ((at - a.0).0 as f32, 0.0, (b.0 - a.0).0 as f32);
+
+    a.1.iter()
+        .zip(b.1)
+        .map(|(a1, b1)| Point3 {
+            x: interpolate(at, (a0, a1.x), (b0, b1.x)),
+            y: interpolate(at, (a0, a1.y), (b0, b1.y)),
+            z: interpolate(at, (a0, a1.z), (b0, b1.z)),
+        })
+        .collect()
+}
+
+pub fn interpolate_points_quadratic(
+    at: Time,
+    a: (Time, &[Point3]),
+    b: (Time, &[Point3]),
+    c: (Time, &[Point3]),
+) -> Vec<Point3> {
+    #[inline]
+    fn interpolate(
+        at: f32,
+        a: (f32, f32),
+        b: (f32, f32),
+        c: (f32, f32),
+    ) -> f32 {
+        ((at - c.0)
+            * ((at - b.0) * (b.0 - c.0) * a.1
+                + (at - a.0) * (-a.0 + c.0) * b.1)
+            + (at - a.0) * (at - b.0) * (a.0 - b.0) * c.1)
+            / ((a.0 - b.0) * (a.0 - c.0) * (b.0 - c.0))
+    }
+
+    let (at, a0, b0, c0) = (
+        (at - a.0).0 as f32,
+        0.0,
+        (b.0 - a.0).0 as f32,
+        (c.0 - a.0).0 as f32,
+    );
+
+    a.1.iter()
+        .zip(b.1)
+        .zip(c.1)
+        .map(|((a1, b1), c1)| Point3 {
+            x: interpolate(at, (a0, a1.x), (b0, b1.x), (c0, c1.x)),
+            y: interpolate(at, (a0, a1.y), (b0, b1.y), (c0, c1.y)),
+            z: interpolate(at, (a0, a1.z), (b0, b1.z), (c0, c1.z)),
+        })
+        .collect()
+}
+
+// Returns None before the first state and the last state after it.
+pub fn interpolate_state_at<S: Interpolate>(
+    states: &BTreeMap<Time, S>,
+    at: Time,
+) -> Option<S> {
+    if let Some(state) = states.get(&at) {
+        return Some(state.clone());
+    }
+
+    let mut prange = states.range((Unbounded, Excluded(at)));
+    let prev = prange.next_back().map(|(t, s)| (*t, s))?;
+
+    let mut nrange = states.range((Excluded(at), Unbounded));
+    let next = if let Some((t, s)) = nrange.next() {
+        (*t, s)
+    } else {
+        return Some(prev.1.clone());
+    };
+
+    Some(if let Some((t, s)) = nrange.next() {
+        S::interpolate_quadratic(at, prev, next, (*t, s))
+    } else if let Some((t, s)) = prange.next_back() {
+        S::interpolate_quadratic(at, (*t, s), prev, next)
+    } else {
+        S::interpolate_linear(at, prev, next)
+    })
+}
+
+impl Interpolate for ElementViewState {
+    fn interpolate_linear(
+        at: Time,
+        a: (Time, &Self),
+        b: (Time, &Self),
+    ) -> Self {
+        ElementViewState {
+            element: a.1.element.clone(),
+            time: at.0,
+            vertices: interpolate_points_linear(
+                at,
+                (a.0, &a.1.vertices),
+                (b.0, &b.1.vertices),
+            ),
+            normals: interpolate_points_linear(
+                at,
+                (a.0, &a.1.normals),
+                (b.0, &b.1.normals),
+            ),
+        }
+    }
+
+    fn interpolate_quadratic(
+        at: Time,
+        a: (Time, &Self),
+        b: (Time, &Self),
+        c: (Time, &Self),
+    ) -> Self {
+        ElementViewState {
+            element: a.1.element.clone(),
+            time: at.0,
+            vertices: interpolate_points_quadratic(
+                at,
+                (a.0, &a.1.vertices),
+                (b.0, &b.1.vertices),
+                (c.0, &c.1.vertices),
+            ),
+            normals: interpolate_points_quadratic(
+                at,
+                (a.0, &a.1.normals),
+                (b.0, &b.1.normals),
+                (c.0, &c.1.normals),
+            ),
+        }
+    }
+}
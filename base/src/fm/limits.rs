@@ -0,0 +1,169 @@
+use structopt::StructOpt;
+
+use crate::defs::{Error, ErrorKind::*, Result};
+use crate::fm::{record, Image, Record};
+
+pub const DEFAULT_MAX_RECORD_SIZE: &str = "268435456";
+pub const DEFAULT_MAX_VERTICES: &str = "16777216";
+pub const DEFAULT_MAX_IMAGE_DIMENSION: &str = "16384";
+
+// Hard limits on untrusted .fm input, checked by readers before allocating
+// for records and after decoding them.
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub struct ReaderLimits {
+    #[structopt(
+        name = "fm-max-record-size",
+        help = "Maximum size of input .fm record in bytes",
+        default_value = DEFAULT_MAX_RECORD_SIZE,
+        long
+    )]
+    pub max_record_size: usize,
+
+    // Also limits faces, texture points, normals and depths.
+    #[structopt(
+        name = "fm-max-vertices",
+        help = "Maximum number of vertices in input .fm record",
+        default_value = DEFAULT_MAX_VERTICES,
+        long
+    )]
+    pub max_vertices: usize,
+
+    #[structopt(
+        name = "fm-max-image-dimension",
+        help = "Maximum width or height of images in input .fm file",
+        default_value = DEFAULT_MAX_IMAGE_DIMENSION,
+        long
+    )]
+    pub max_image_dimension: u32,
+}
+
+impl Default for ReaderLimits {
+    fn default() -> Self {
+        Self {
+            max_record_size: DEFAULT_MAX_RECORD_SIZE.parse().unwrap(),
+            max_vertices: DEFAULT_MAX_VERTICES.parse().unwrap(),
+            max_image_dimension: DEFAULT_MAX_IMAGE_DIMENSION.parse().unwrap(),
+        }
+    }
+}
+
+impl ReaderLimits {
+    pub fn check_record_size(&self, size: usize) -> Result<()> {
+        if size > self.max_record_size {
+            let desc = format!(
+                ".fm record size {} exceeds limit {}",
+                size, self.max_record_size
+            );
+            return Err(Error::new(UnsupportedFeature, desc));
+        }
+        Ok(())
+    }
+
+    fn check_count(&self, count: usize, what: &str) -> Result<()> {
+        if count > self.max_vertices {
+            let desc = format!(
+                "number of {} {} exceeds limit {}",
+                what, count, self.max_vertices
+            );
+            return Err(Error::new(UnsupportedFeature, desc));
+        }
+        Ok(())
+    }
+
+    fn check_dimensions(&self, width: u32, height: u32) -> Result<()> {
+        if width.max(height) > self.max_image_dimension {
+            let desc = format!(
+                "image size {}x{} exceeds limit {}",
+                width, height, self.max_image_dimension
+            );
+            return Err(Error::new(UnsupportedFeature, desc));
+        }
+        Ok(())
+    }
+
+    fn check_image(&self, image: Option<&Image>) -> Result<()> {
+        match image.and_then(|i| image_dimensions(&i.data)) {
+            Some((width, height)) => self.check_dimensions(width, height),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_record(&self, record: &Record) -> Result<()> {
+        use record::Type::*;
+        match &record.r#type {
+            Some(ElementView(v)) => {
+                self.check_count(v.texture_points.len(), "texture points")?;
+                self.check_count(v.faces.len(), "faces")?;
+                self.check_count(v.vertex_colors.len(), "vertex colors")?;
+                self.check_count(v.face_labels.len(), "face labels")?;
+                self.check_count(v.face_pages.len(), "face pages")?;
+                self.check_image(v.texture.as_ref())?;
+                self.check_image(v.normal_texture.as_ref())?;
+                for image in v.texture_levels.iter().chain(&v.texture_pages) {
+                    self.check_image(Some(image))?;
+                }
+                Ok(())
+            }
+            Some(ElementViewState(s)) => {
+                self.check_count(s.vertices.len(), "vertices")?;
+                self.check_count(s.normals.len(), "normals")
+            }
+            Some(ElementTexture(t)) => self.check_image(t.texture.as_ref()),
+            Some(ElementViewRefinement(r)) => {
+                self.check_count(r.vertices.len(), "vertices")?;
+                self.check_count(r.faces.len(), "faces")
+            }
+            Some(Scan(s)) => {
+                self.check_dimensions(s.image_width, s.image_height)?;
+                self.check_dimensions(s.depth_width, s.depth_height)?;
+                for camera in &s.cameras {
+                    let (width, height) =
+                        (camera.image_width, camera.image_height);
+                    self.check_dimensions(width, height)?;
+                    let (width, height) =
+                        (camera.depth_width, camera.depth_height);
+                    self.check_dimensions(width, height)?;
+                }
+                Ok(())
+            }
+            Some(ScanFrame(f)) => {
+                self.check_count(f.depths.len(), "depths")?;
+                self.check_image(f.image.as_ref())
+            }
+            Some(Preview(p)) => self.check_image(p.image.as_ref()),
+            Some(Impostors(i)) => self.check_image(i.atlas.as_ref()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Reads dimensions from PNG or JPEG header without decoding the image.
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be16 =
+        |i: usize| Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]));
+    let be32 = |i: usize| {
+        Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?))
+    };
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while *data.get(pos)? == 0xFF {
+        let marker = *data.get(pos + 1)?;
+        // Start of frame markers (except DHT, JPG and DAC) hold the size.
+        if (0xC0..=0xCF).contains(&marker)
+            && ![0xC4, 0xC8, 0xCC].contains(&marker)
+        {
+            let height = be16(pos + 5)? as u32;
+            let width = be16(pos + 7)? as u32;
+            return Some((width, height));
+        }
+        pos += 2 + be16(pos + 2)? as usize;
+    }
+    None
+}
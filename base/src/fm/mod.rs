@@ -1,27 +1,66 @@
 mod data;
+mod external;
+mod indexed;
+mod interpolation;
+mod limits;
+mod patch;
 mod reader;
+mod refinement;
+mod stream;
+mod time;
 mod writer;
 
+use std::fmt;
+use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
 
 use prost::Message;
+use sha2::{Digest, Sha256};
 use structopt::StructOpt;
 
 use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
 pub use data::*;
+pub use external::*;
+pub use indexed::*;
+pub use interpolation::*;
+pub use limits::*;
+pub use patch::*;
 pub use reader::*;
+pub use refinement::*;
+pub use stream::*;
+pub use time::*;
 pub use writer::*;
 
-pub type Time = i64; // Monotonic time with nanosecond precision.
-
 pub const MAGIC: u32 = 0xD0932177;
-pub const VERSION: u32 = 1;
 
-#[derive(Clone, Copy)]
+// Schema evolution rules:
+// - records are protobuf messages, so new fields and record types are added
+//   within the same version; tools passing records through must copy them
+//   raw to preserve fields unknown to them;
+// - optional format features are declared by header flags (since version 2),
+//   a reader refuses files with features it doesn't know about;
+// - incompatible changes bump the version and add a migration step.
+pub const VERSION: u32 = 2;
+pub const MIN_VERSION: u32 = 1;
+
+pub type Features = u32;
+pub const FEATURE_DEDUP: Features = 1; // Images are content-addressed.
+pub const FEATURE_EXTERNAL_IMAGES: Features = 2; // Images can be external.
+pub const FEATURE_INDEX: Features = 4; // Records are indexed by offsets.
+pub const SUPPORTED_FEATURES: Features =
+    FEATURE_DEDUP | FEATURE_EXTERNAL_IMAGES | FEATURE_INDEX;
+
+// Record size which designates the index (trailing indexed .fm files).
+pub const INDEX_MARKER: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Compression {
+    // No compression for piped output, gzip otherwise (never stored).
+    Auto = -1,
     None = 0,
     Gzip = 1,
+    Zstd = 2,
 }
 
 impl FromStr for Compression {
@@ -29,18 +68,41 @@ impl FromStr for Compression {
 
     fn from_str(s: &str) -> Result<Self> {
         match s {
+            "auto" => Ok(Compression::Auto),
             "none" => Ok(Compression::None),
             "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
             _ => Err(Error::new(
                 MalformedData,
-                "unknown .fm compression (can be 'none' or 'gzip')".to_string(),
+                "unknown .fm compression (can be 'auto', 'none', 'gzip' or \
+                 'zstd')"
+                    .to_string(),
             )),
         }
     }
 }
 
-pub const DEFAULT_COMPRESSION: &str = "gzip";
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Compression::Auto => "auto",
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+pub(crate) fn zstd_unsupported_err() -> Error {
+    let desc = "zstd compression requires 'zstd' feature".to_string();
+    Error::new(UnsupportedFeature, desc)
+}
+
+pub const DEFAULT_COMPRESSION: &str = "auto";
 pub const DEFAULT_GZIP_LEVEL: &str = "6";
+pub const DEFAULT_ZSTD_LEVEL: &str = "3";
 
 fn validate_gzip_level(value: String) -> StdResult<(), String> {
     let parsed = value
@@ -52,7 +114,17 @@ fn validate_gzip_level(value: String) -> StdResult<(), String> {
     Ok(())
 }
 
-#[derive(StructOpt)]
+fn validate_zstd_level(value: String) -> StdResult<(), String> {
+    let parsed = value
+        .parse::<i32>()
+        .map_err(|_| "must be a positive integer".to_string())?;
+    if !(1..=22).contains(&parsed) {
+        return Err("unsupported zstd level (can be from 1 to 22)".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Clone, StructOpt)]
 pub struct WriterParams {
     #[structopt(
         name = "fm-compression",
@@ -70,6 +142,36 @@ pub struct WriterParams {
         validator = validate_gzip_level
     )]
     pub gzip_level: u32,
+
+    #[structopt(
+        name = "fm-zstd-level",
+        help = "Level of zstd-compression for output .fm file",
+        default_value = DEFAULT_ZSTD_LEVEL,
+        long,
+        validator = validate_zstd_level
+    )]
+    pub zstd_level: i32,
+
+    #[structopt(
+        name = "fm-dedup",
+        help = "Deduplicate identical images in output .fm file",
+        long
+    )]
+    pub dedup: bool,
+
+    #[structopt(
+        name = "fm-external-images",
+        help = "Store images of output .fm file in given directory or URL",
+        long
+    )]
+    pub external_images: Option<PathBuf>,
+
+    #[structopt(
+        name = "fm-index",
+        help = "Index output .fm file for random access to its records",
+        long
+    )]
+    pub index: bool,
 }
 
 impl Default for WriterParams {
@@ -77,19 +179,215 @@ impl Default for WriterParams {
         Self {
             compression: Compression::from_str(DEFAULT_COMPRESSION).unwrap(),
             gzip_level: DEFAULT_GZIP_LEVEL.parse::<u32>().unwrap(),
+            zstd_level: DEFAULT_ZSTD_LEVEL.parse::<i32>().unwrap(),
+            dedup: false,
+            external_images: None,
+            index: false,
         }
     }
 }
 
-pub struct RawRecord<'a>(&'a [u8]);
+pub struct RawRecord<'a> {
+    data: &'a [u8],
+    limits: Option<ReaderLimits>, // Checked on decoding if present.
+}
 
 impl<'a> RawRecord<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, limits: None }
+    }
+
+    pub fn with_limits(data: &'a [u8], limits: ReaderLimits) -> Self {
+        Self {
+            data,
+            limits: Some(limits),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn limits(&self) -> Option<ReaderLimits> {
+        self.limits
+    }
+
     pub fn decode(&self) -> Result<Record> {
-        Record::decode(self.0)
-            .into_result(|| "failed to decode .fm record".to_string())
+        let record = Record::decode(self.data)
+            .into_result(|| "failed to decode .fm record".to_string())?;
+        if let Some(limits) = &self.limits {
+            limits.check_record(&record)?;
+        }
+        Ok(record)
+    }
+
+    // Decodes only names, times and loops of record, leaving the rest of
+    // its fields empty (limits aren't checked as bulk data is skipped).
+    pub fn decode_header(&self) -> Result<Record> {
+        use record::Type::*;
+        use record_header::Type as Header;
+        let header = RecordHeader::decode(self.data)
+            .into_result(|| "failed to decode .fm record header".to_string())?;
+        let r#type = header.r#type.map(|header| match header {
+            Header::ElementView(v) => ElementView(self::ElementView {
+                element: v.element,
+                animation_loop: v.animation_loop,
+                ..Default::default()
+            }),
+            Header::ElementViewState(t) => {
+                ElementViewState(self::ElementViewState {
+                    element: t.name,
+                    time: t.time,
+                    ..Default::default()
+                })
+            }
+            Header::Scan(n) => Scan(self::Scan {
+                name: n.name,
+                ..Default::default()
+            }),
+            Header::ScanFrame(t) => ScanFrame(self::ScanFrame {
+                scan: t.name,
+                time: t.time,
+                ..Default::default()
+            }),
+            Header::Preview(_) => Preview(Default::default()),
+            Header::ElementViewRefinement(n) => {
+                ElementViewRefinement(self::ElementViewRefinement {
+                    element: n.name,
+                    ..Default::default()
+                })
+            }
+            Header::Impostors(_) => Impostors(Default::default()),
+            Header::ElementTexture(t) => ElementTexture(self::ElementTexture {
+                element: t.name,
+                time: t.time,
+                ..Default::default()
+            }),
+        });
+        Ok(Record { r#type })
+    }
+}
+
+pub fn record_images(record: &Record) -> Vec<&Image> {
+    use record::Type::*;
+    match &record.r#type {
+        Some(ElementView(v)) => v
+            .texture
+            .iter()
+            .chain(v.normal_texture.iter())
+            .chain(v.texture_levels.iter())
+            .chain(v.texture_pages.iter())
+            .collect(),
+        Some(ScanFrame(f)) => f.image.iter().collect(),
+        Some(Preview(p)) => p.image.iter().collect(),
+        Some(Impostors(i)) => i.atlas.iter().collect(),
+        Some(ElementTexture(t)) => t.texture.iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn record_images_mut(record: &mut Record) -> Vec<&mut Image> {
+    use record::Type::*;
+    match &mut record.r#type {
+        Some(ElementView(v)) => v
+            .texture
+            .iter_mut()
+            .chain(v.normal_texture.iter_mut())
+            .chain(v.texture_levels.iter_mut())
+            .chain(v.texture_pages.iter_mut())
+            .collect(),
+        Some(ScanFrame(f)) => f.image.iter_mut().collect(),
+        Some(Preview(p)) => p.image.iter_mut().collect(),
+        Some(Impostors(i)) => i.atlas.iter_mut().collect(),
+        Some(ElementTexture(t)) => t.texture.iter_mut().collect(),
+        _ => Vec::new(),
     }
 }
 
+// Camera angle around Z axis, by default it follows from angular velocity.
+pub fn camera_angle(scan: &Scan, frame: &ScanFrame) -> f64 {
+    match &frame.camera_angle {
+        Some(angle) => angle.radians as f64,
+        None => frame.time as f64 / 1E9 * scan.camera_angular_velocity as f64,
+    }
+}
+
+// Stores camera angle of the frame explicitly if it follows from the time,
+// so that the angle is kept when the time changes.
+pub fn pin_camera_angle(scan: &Scan, frame: &mut ScanFrame) {
+    if frame.camera_angle.is_none() && scan.camera_angular_velocity != 0.0 {
+        let radians = camera_angle(scan, frame) as f32;
+        frame.camera_angle = Some(scan_frame::CameraAngle { radians });
+    }
+}
+
+// Face labels of the view, None if its faces are unlabeled.
+pub fn face_labels(view: &ElementView) -> Result<Option<Vec<u16>>> {
+    if view.face_labels.is_empty() {
+        return Ok(None);
+    }
+    if view.face_labels.len() != view.faces.len() {
+        let desc = format!(
+            "{} face labels for {} faces of element '{}'",
+            view.face_labels.len(),
+            view.faces.len(),
+            view.element
+        );
+        return Err(Error::new(MalformedData, desc));
+    }
+    view.face_labels
+        .iter()
+        .map(|&label| {
+            u16::try_from(label).map_err(|_| {
+                let desc = format!(
+                    "face label {} of element '{}' exceeds {}",
+                    label,
+                    view.element,
+                    u16::MAX
+                );
+                Error::new(MalformedData, desc)
+            })
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+// Atlas pages of the view faces, None if there are no further pages.
+pub fn face_pages(view: &ElementView) -> Result<Option<Vec<usize>>> {
+    if view.texture_pages.is_empty() && view.face_pages.is_empty() {
+        return Ok(None);
+    }
+    if view.face_pages.len() != view.faces.len() {
+        let desc = format!(
+            "{} face pages for {} faces of element '{}'",
+            view.face_pages.len(),
+            view.faces.len(),
+            view.element
+        );
+        return Err(Error::new(MalformedData, desc));
+    }
+    let num_pages = view.texture_pages.len() + 1;
+    view.face_pages
+        .iter()
+        .map(|&page| {
+            if (page as usize) < num_pages {
+                Ok(page as usize)
+            } else {
+                let desc = format!(
+                    "face page {} of element '{}' exceeds {} pages",
+                    page, view.element, num_pages
+                );
+                Err(Error::new(MalformedData, desc))
+            }
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+pub fn image_digest(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
 pub fn image_type_extension(r#type: image::Type) -> &'static str {
     use image::Type::*;
     match r#type {
@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{Read as _, Write as _};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+use crate::fm::{
+    image_digest, patch_op, record_images_mut, PatchHeader, PatchOp, RawRecord,
+    Read, Record, Write,
+};
+
+pub const PATCH_MAGIC: u32 = 0xD0932178;
+pub const PATCH_VERSION: u32 = 1;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PatchStats {
+    pub num_copied: usize,
+    pub num_inserted: usize,
+    pub num_base_images: usize,
+}
+
+// Records of target which are found in base are copied from there (in the
+// order of base, otherwise they are inserted), as well as images of inserted
+// records found in base records copied or skipped before.
+pub fn make_patch(
+    base: &mut dyn Read,
+    target: &mut dyn Read,
+    patch: &mut dyn io::Write,
+) -> Result<PatchStats> {
+    let mut base_records = HashMap::<Vec<u8>, Vec<usize>>::new();
+    let mut base_images = HashMap::new();
+    let mut base_digest = Sha256::new();
+    let mut num_base = 0;
+    while let Some(rec) = base.read_raw_record()? {
+        let digest = image_digest(rec.as_bytes());
+        base_digest.update(&digest);
+        base_records.entry(digest).or_default().push(num_base);
+        for image in record_images_mut(&mut rec.decode()?) {
+            if !image.data.is_empty() {
+                base_images
+                    .entry(image_digest(&image.data))
+                    .or_insert(num_base);
+            }
+        }
+        num_base += 1;
+    }
+
+    let mut stats = PatchStats::default();
+    let mut header = PatchHeader {
+        base_digest: base_digest.finalize().to_vec(),
+        ..Default::default()
+    };
+    let mut target_digest = Sha256::new();
+    let mut referenced = HashSet::new();
+    let mut ops = Vec::new();
+    let mut copy: Option<patch_op::Copy> = None;
+    let mut cursor = 0; // Base records copied or skipped so far.
+    while let Some(rec) = target.read_raw_record()? {
+        let digest = image_digest(rec.as_bytes());
+        let found = base_records
+            .get(&digest)
+            .and_then(|idxs| idxs.iter().find(|&&i| i >= cursor));
+        if let Some(&idx) = found {
+            match copy.as_mut() {
+                Some(copy) if copy.first + copy.count == idx as u64 => {
+                    copy.count += 1;
+                }
+                _ => {
+                    ops.extend(copy.take().map(patch_op::Type::Copy));
+                    copy = Some(patch_op::Copy {
+                        first: idx as u64,
+                        count: 1,
+                    });
+                }
+            }
+            cursor = idx + 1;
+            target_digest.update(&digest);
+            stats.num_copied += 1;
+            continue;
+        }
+
+        ops.extend(copy.take().map(patch_op::Type::Copy));
+        let record = rec.decode()?;
+        let mut processed = record.clone();
+        let mut is_processed = false;
+        for image in record_images_mut(&mut processed) {
+            if image.data.is_empty() {
+                continue;
+            }
+            let digest = image_digest(&image.data);
+            if base_images.get(&digest).is_none_or(|&i| i >= cursor) {
+                continue;
+            }
+            image.data.clear();
+            image.digest = digest.clone();
+            referenced.insert(digest);
+            is_processed = true;
+        }
+        let data = if is_processed {
+            // Restored records are encoded anew.
+            target_digest.update(image_digest(&encode(&record)));
+            encode(&processed)
+        } else {
+            target_digest.update(&digest);
+            rec.as_bytes().to_vec()
+        };
+        ops.push(patch_op::Type::Insert(data));
+        stats.num_inserted += 1;
+    }
+    ops.extend(copy.take().map(patch_op::Type::Copy));
+
+    header.target_digest = target_digest.finalize().to_vec();
+    header.base_images = referenced.into_iter().collect();
+    header.base_images.sort_unstable();
+    stats.num_base_images = header.base_images.len();
+
+    write_patch(patch, &header, ops)?;
+    Ok(stats)
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut data = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut data).unwrap();
+    data
+}
+
+fn write_patch(
+    patch: &mut dyn io::Write,
+    header: &PatchHeader,
+    ops: Vec<patch_op::Type>,
+) -> Result<()> {
+    let write_err = || "failed to write .fm patch".to_string();
+    patch
+        .write_all(&PATCH_MAGIC.to_le_bytes())
+        .and_then(|_| patch.write_all(&PATCH_VERSION.to_le_bytes()))
+        .into_result(write_err)?;
+
+    let mut encoder = GzEncoder::new(patch, flate2::Compression::default());
+    let mut write_message = |data: Vec<u8>| {
+        encoder
+            .write_all(&(data.len() as u32).to_le_bytes())
+            .and_then(|_| encoder.write_all(&data))
+            .into_result(write_err)
+    };
+    write_message(encode(header))?;
+    for op in ops {
+        write_message(encode(&PatchOp { r#type: Some(op) }))?;
+    }
+    encoder
+        .finish()
+        .and_then(|patch| patch.flush())
+        .into_result(write_err)
+}
+
+// Reads base records sequentially, keeping images referenced by the patch.
+struct BaseRecords<'a> {
+    reader: &'a mut dyn Read,
+    digest: Sha256,
+    cursor: usize,
+    images: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl<'a> BaseRecords<'a> {
+    fn next(&mut self) -> Result<Option<RawRecord<'_>>> {
+        let rec = match self.reader.read_raw_record()? {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        self.digest.update(image_digest(rec.as_bytes()));
+        self.cursor += 1;
+
+        if self.images.values().any(Vec::is_empty) {
+            for image in record_images_mut(&mut rec.decode()?) {
+                let digest = image_digest(&image.data);
+                if let Some(data) = self.images.get_mut(&digest) {
+                    if data.is_empty() {
+                        *data = std::mem::take(&mut image.data);
+                    }
+                }
+            }
+        }
+        Ok(Some(rec))
+    }
+}
+
+pub fn apply_patch(
+    base: &mut dyn Read,
+    patch: &mut dyn io::Read,
+    writer: &mut dyn Write,
+) -> Result<PatchStats> {
+    let read_err = || "failed to read .fm patch".to_string();
+    let mut buf = [0; 4];
+    patch.read_exact(&mut buf).into_result(read_err)?;
+    if u32::from_le_bytes(buf) != PATCH_MAGIC {
+        let desc = "not a .fm patch (magic mismatch)".to_string();
+        return Err(Error::new(MalformedData, desc));
+    }
+    patch.read_exact(&mut buf).into_result(read_err)?;
+    let version = u32::from_le_bytes(buf);
+    if version != PATCH_VERSION {
+        let desc = format!("unsupported .fm patch version {}", version);
+        return Err(Error::new(UnsupportedFeature, desc));
+    }
+
+    let mut decoder = GzDecoder::new(patch);
+    let mut read_message = |buf: &mut Vec<u8>| -> Result<bool> {
+        let mut size = [0; 4];
+        if let Err(e) = decoder.read_exact(&mut size) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(false);
+            }
+            return Err(Error::with_source(MalformedData, read_err(), e));
+        }
+        buf.resize(u32::from_le_bytes(size) as usize, 0);
+        decoder.read_exact(buf).into_result(read_err)?;
+        Ok(true)
+    };
+    let malformed_err = || "malformed .fm patch".to_string();
+
+    let mut buf = Vec::new();
+    if !read_message(&mut buf)? {
+        return Err(Error::new(MalformedData, malformed_err()));
+    }
+    let header =
+        PatchHeader::decode(buf.as_slice()).into_result(malformed_err)?;
+
+    let mut base = BaseRecords {
+        reader: base,
+        digest: Sha256::new(),
+        cursor: 0,
+        images: header
+            .base_images
+            .iter()
+            .map(|digest| (digest.clone(), Vec::new()))
+            .collect(),
+    };
+    let mismatch_err = || {
+        let desc = "patch doesn't match base .fm file".to_string();
+        Error::new(InconsistentState, desc)
+    };
+
+    let mut stats = PatchStats {
+        num_base_images: header.base_images.len(),
+        ..Default::default()
+    };
+    let mut target_digest = Sha256::new();
+    while read_message(&mut buf)? {
+        let op = PatchOp::decode(buf.as_slice()).into_result(malformed_err)?;
+        match op.r#type {
+            Some(patch_op::Type::Copy(copy)) => {
+                if copy.first < base.cursor as u64 {
+                    let desc = "patch copies base records out of order";
+                    return Err(Error::new(MalformedData, desc.to_string()));
+                }
+                while (base.cursor as u64) < copy.first {
+                    base.next()?.ok_or_else(mismatch_err)?;
+                }
+                for _ in 0..copy.count {
+                    let rec = base.next()?.ok_or_else(mismatch_err)?;
+                    target_digest.update(image_digest(rec.as_bytes()));
+                    writer.write_raw_record(&rec)?;
+                }
+                stats.num_copied += copy.count as usize;
+            }
+            Some(patch_op::Type::Insert(data)) => {
+                let mut record =
+                    Record::decode(data.as_slice()).into_result(|| {
+                        "failed to decode .fm patch record".to_string()
+                    })?;
+                let mut is_restored = false;
+                for image in record_images_mut(&mut record) {
+                    if !image.data.is_empty() || image.digest.is_empty() {
+                        continue;
+                    }
+                    image.data = match base.images.get(&image.digest) {
+                        Some(data) if !data.is_empty() => data.clone(),
+                        _ => return Err(mismatch_err()),
+                    };
+                    image.digest.clear();
+                    is_restored = true;
+                }
+                let data = if is_restored { encode(&record) } else { data };
+                target_digest.update(image_digest(&data));
+                writer.write_raw_record(&RawRecord::new(&data))?;
+                stats.num_inserted += 1;
+            }
+            None => return Err(Error::new(MalformedData, malformed_err())),
+        }
+    }
+
+    while base.next()?.is_some() {}
+    if base.digest.finalize().as_slice() != header.base_digest {
+        return Err(mismatch_err());
+    }
+    if target_digest.finalize().as_slice() != header.target_digest {
+        let desc = "patched .fm file doesn't match target".to_string();
+        return Err(Error::new(InconsistentState, desc));
+    }
+    Ok(stats)
+}
@@ -1,110 +1,414 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::io::Read as _;
+use std::io::{BufRead as _, Read as _};
 
-use flate2::read::GzDecoder;
+use flate2::bufread::GzDecoder;
+use prost::Message;
+#[cfg(feature = "zstd")]
+use zstd::stream::read::Decoder as ZstdDecoder;
 
 use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
-use crate::fm::{Compression, RawRecord, Record, MAGIC, VERSION};
+use crate::fm::{
+    record_images_mut, resolve_image, Compression, Features, ImageResolver,
+    RawRecord, ReaderLimits, Record, FEATURE_DEDUP, FEATURE_EXTERNAL_IMAGES,
+    FEATURE_INDEX, INDEX_MARKER, MAGIC, MIN_VERSION, SUPPORTED_FEATURES,
+    VERSION,
+};
 
 pub trait Read {
+    fn version(&self) -> u32;
+    fn compression(&self) -> Compression;
+    fn features(&self) -> Features;
     fn read_raw_record(&mut self) -> Result<Option<RawRecord>>;
     fn read_record(&mut self) -> Result<Option<Record>>;
 }
 
-#[derive(Debug)]
-enum RawReader<R: io::Read> {
-    Plain(R),
-    Gzip(GzDecoder<R>),
+pub(crate) struct RawReader<R: io::Read> {
+    unit: Option<Unit<R>>,
+    compression: Compression,
+}
+
+// Compressed data consists of units (gzip members or zstd frames), which
+// are decoded one by one, so that anything following them can be detected.
+enum Unit<R: io::Read> {
+    Plain(io::BufReader<R>),
+    // Between compressed units.
+    Idle(io::BufReader<R>),
+    Gzip(GzDecoder<io::BufReader<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(ZstdDecoder<'static, io::BufReader<R>>),
+}
+
+// Only the first bytes of unit magics are checked, the rest is left to
+// decoders, as a buffered reader can't guarantee longer lookahead.
+const GZIP_MAGIC_BYTE: u8 = 0x1F;
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC_BYTE: u8 = 0x28;
+
+impl<R: io::Read> RawReader<R> {
+    pub(crate) fn new(inner: R, compression: Compression) -> Result<Self> {
+        let mut reader = RawReader {
+            unit: Some(Unit::Idle(io::BufReader::new(inner))),
+            compression,
+        };
+        reader.reset(compression);
+        Ok(reader)
+    }
+
+    pub(crate) fn inner_mut(&mut self) -> &mut io::BufReader<R> {
+        match self.unit.as_mut().unwrap() {
+            Unit::Plain(inner) | Unit::Idle(inner) => inner,
+            Unit::Gzip(decoder) => decoder.get_mut(),
+            #[cfg(feature = "zstd")]
+            Unit::Zstd(decoder) => decoder.get_mut(),
+        }
+    }
+
+    // Reads the rest of the current unit, stopping at its end.
+    pub(crate) fn finish_unit(&mut self) -> io::Result<()> {
+        match self.unit.as_mut().unwrap() {
+            Unit::Gzip(decoder) => io::copy(decoder, &mut io::sink())?,
+            #[cfg(feature = "zstd")]
+            Unit::Zstd(decoder) => io::copy(decoder, &mut io::sink())?,
+            _ => return Ok(()),
+        };
+        let inner = match self.unit.take().unwrap() {
+            Unit::Gzip(decoder) => decoder.into_inner(),
+            #[cfg(feature = "zstd")]
+            Unit::Zstd(decoder) => decoder.finish(),
+            _ => unreachable!(),
+        };
+        self.unit = Some(Unit::Idle(inner));
+        Ok(())
+    }
+
+    // Continues reading with the given compression after the current unit.
+    fn reset(&mut self, compression: Compression) {
+        let inner = match self.unit.take().unwrap() {
+            Unit::Plain(inner) | Unit::Idle(inner) => inner,
+            Unit::Gzip(decoder) => decoder.into_inner(),
+            #[cfg(feature = "zstd")]
+            Unit::Zstd(decoder) => decoder.finish(),
+        };
+        self.compression = compression;
+        self.unit = Some(match compression {
+            Compression::Gzip | Compression::Zstd => Unit::Idle(inner),
+            _ => Unit::Plain(inner),
+        });
+    }
+
+    pub(crate) fn has_more(&mut self) -> io::Result<bool> {
+        Ok(!self.inner_mut().fill_buf()?.is_empty())
+    }
+
+    // Starts the next unit if it follows, called between units.
+    fn next_unit(&mut self) -> io::Result<bool> {
+        let magic_byte = match self.compression {
+            Compression::Gzip => GZIP_MAGIC_BYTE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ZSTD_MAGIC_BYTE,
+            _ => return Ok(false),
+        };
+        if self.inner_mut().fill_buf()?.first() != Some(&magic_byte) {
+            return Ok(false);
+        }
+        let inner = match self.unit.take().unwrap() {
+            Unit::Idle(inner) => inner,
+            _ => unreachable!(),
+        };
+        self.unit = Some(match self.compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                Unit::Zstd(ZstdDecoder::with_buffer(inner)?.single_frame())
+            }
+            _ => Unit::Gzip(GzDecoder::new(inner)),
+        });
+        Ok(true)
+    }
 }
 
 impl<R: io::Read> io::Read for RawReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self {
-            RawReader::Plain(inner) => inner.read(buf),
-            RawReader::Gzip(decoder) => decoder.read(buf),
+        loop {
+            let size = match self.unit.as_mut().unwrap() {
+                Unit::Plain(inner) => return inner.read(buf),
+                Unit::Idle(_) => {
+                    if !self.next_unit()? {
+                        return Ok(0);
+                    }
+                    continue;
+                }
+                Unit::Gzip(decoder) => decoder.read(buf)?,
+                #[cfg(feature = "zstd")]
+                Unit::Zstd(decoder) => decoder.read(buf)?,
+            };
+            if size > 0 || buf.is_empty() {
+                return Ok(size);
+            }
+            self.finish_unit()?;
         }
     }
 }
 
+// Zstd decoder isn't Debug, so its inner reader is shown instead.
+impl<R: io::Read + fmt::Debug> fmt::Debug for RawReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = match self.unit.as_ref().unwrap() {
+            Unit::Plain(inner) | Unit::Idle(inner) => inner.get_ref(),
+            Unit::Gzip(decoder) => decoder.get_ref().get_ref(),
+            #[cfg(feature = "zstd")]
+            Unit::Zstd(decoder) => decoder.get_ref().get_ref(),
+        };
+        f.debug_struct("RawReader")
+            .field("compression", &self.compression)
+            .field("inner", inner)
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 pub struct Reader<R: io::Read> {
     reader: RawReader<R>,
     buffer: Vec<u8>,
+    version: u32,
+    compression: Compression,
+    features: Features,
+    images: Option<HashMap<Vec<u8>, Vec<u8>>>,
+    resolver: Option<Box<dyn ImageResolver>>,
+    limits: ReaderLimits,
 }
 
-impl<R: io::Read> Reader<R> {
-    pub fn new(mut inner: R) -> Result<Self> {
-        let mut buf = [0; 4];
+fn read_magic<T: io::Read>(inner: &mut T) -> Result<()> {
+    let mut buf = [0; 4];
+    inner
+        .read_exact(&mut buf)
+        .into_result(|| "failed to read .fm magic".to_string())?;
+    let val = u32::from_le_bytes(buf);
+    if val != MAGIC {
+        return Err(Error::new(
+            MalformedData,
+            format!("bad .fm magic '{:#X}'", val),
+        ));
+    }
+    Ok(())
+}
 
-        inner
-            .read_exact(&mut buf)
-            .into_result(|| "failed to read .fm magic".to_string())?;
-        let val = u32::from_le_bytes(buf);
-        if val != MAGIC {
-            return Err(Error::new(
-                MalformedData,
-                format!("bad .fm magic '{:#X}'", val),
-            ));
-        }
+// Reads the header following the magic.
+fn read_header<T: io::Read>(
+    inner: &mut T,
+) -> Result<(u32, Compression, Features)> {
+    let mut buf = [0; 4];
 
-        inner
-            .read_exact(&mut buf)
-            .into_result(|| "failed to read .fm version".to_string())?;
-        let val = u32::from_le_bytes(buf);
-        if val != VERSION {
-            return Err(Error::new(
-                UnsupportedFeature,
-                format!("unsupported .fm version '{}'", val),
-            ));
-        }
+    inner
+        .read_exact(&mut buf)
+        .into_result(|| "failed to read .fm version".to_string())?;
+    let version = u32::from_le_bytes(buf);
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(Error::new(
+            UnsupportedFeature,
+            format!("unsupported .fm version '{}'", version),
+        ));
+    }
 
+    inner
+        .read_exact(&mut buf)
+        .into_result(|| "failed to read .fm compression".to_string())?;
+    let val = i32::from_le_bytes(buf);
+
+    let features = if version >= 2 {
+        let mut buf = [0; 4];
         inner
             .read_exact(&mut buf)
-            .into_result(|| "failed to read .fm compression".to_string())?;
-        let val = i32::from_le_bytes(buf);
-
-        const COMPRESSION_NONE: i32 = Compression::None as i32;
-        const COMPRESSION_GZIP: i32 = Compression::Gzip as i32;
-
-        let reader = match val {
-            COMPRESSION_NONE => Ok(RawReader::Plain(inner)),
-            COMPRESSION_GZIP => Ok(RawReader::Gzip(GzDecoder::new(inner))),
-            _ => Err(Error::new(
-                UnsupportedFeature,
-                format!("unsupported compression '{}'", val),
-            )),
-        }?;
+            .into_result(|| "failed to read .fm features".to_string())?;
+        u32::from_le_bytes(buf)
+    } else {
+        0
+    };
+    if features & !SUPPORTED_FEATURES != 0 {
+        return Err(Error::new(
+            UnsupportedFeature,
+            format!("unsupported .fm features '{:#X}'", features),
+        ));
+    }
+
+    const COMPRESSION_NONE: i32 = Compression::None as i32;
+    const COMPRESSION_GZIP: i32 = Compression::Gzip as i32;
+    const COMPRESSION_ZSTD: i32 = Compression::Zstd as i32;
+
+    let compression = match val {
+        COMPRESSION_NONE => Ok(Compression::None),
+        COMPRESSION_GZIP => Ok(Compression::Gzip),
+        #[cfg(feature = "zstd")]
+        COMPRESSION_ZSTD => Ok(Compression::Zstd),
+        #[cfg(not(feature = "zstd"))]
+        COMPRESSION_ZSTD => Err(crate::fm::zstd_unsupported_err()),
+        _ => Err(Error::new(
+            UnsupportedFeature,
+            format!("unsupported compression '{}'", val),
+        )),
+    }?;
+
+    Ok((version, compression, features))
+}
+
+impl<R: io::Read> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        read_magic(&mut inner)?;
+        let (version, compression, features) = read_header(&mut inner)?;
 
         Ok(Self {
-            reader,
+            reader: RawReader::new(inner, compression)?,
             buffer: Vec::<u8>::with_capacity(0),
+            version,
+            compression,
+            features,
+            images: (features & FEATURE_DEDUP != 0).then(HashMap::new),
+            resolver: None,
+            limits: ReaderLimits::default(),
         })
     }
+
+    // Continues with a concatenated stream (whose magic is already read),
+    // its records are read as if they were in the first stream.
+    fn next_stream(&mut self) -> Result<()> {
+        let (_, compression, features) = read_header(self.reader.inner_mut())?;
+        self.reader.reset(compression);
+        self.features |= features;
+        if features & FEATURE_DEDUP != 0 && self.images.is_none() {
+            self.images = Some(HashMap::new());
+        }
+        Ok(())
+    }
+
+    // Skips the index (whose marker is already read) and the trailer.
+    fn skip_index(&mut self) -> Result<()> {
+        let desc = || "failed to skip .fm index".to_string();
+        let mut buf = [0; 4];
+        self.reader.read_exact(&mut buf).into_result(desc)?;
+        let size = u32::from_le_bytes(buf) as u64;
+        let mut index = (&mut self.reader).take(size);
+        if io::copy(&mut index, &mut io::sink()).into_result(desc)? != size {
+            return Err(Error::new(MalformedData, desc()));
+        }
+        self.reader.finish_unit().into_result(desc)?;
+        let mut trailer = [0; 12];
+        self.reader
+            .inner_mut()
+            .read_exact(&mut trailer)
+            .into_result(desc)
+    }
+
+    // Without a resolver external images are left unresolved (with empty
+    // data), which is enough for workflows not touching image data.
+    pub fn set_image_resolver(&mut self, resolver: Box<dyn ImageResolver>) {
+        self.resolver = Some(resolver);
+    }
+
+    pub fn set_limits(&mut self, limits: ReaderLimits) {
+        self.limits = limits;
+    }
 }
 
 impl<R: io::Read> Read for Reader<R> {
+    fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    fn features(&self) -> Features {
+        self.features
+    }
+
     fn read_raw_record(&mut self) -> Result<Option<RawRecord>> {
-        let mut buf = [0; 4];
-        if let Err(e) = self.reader.read_exact(&mut buf) {
-            return if e.kind() == io::ErrorKind::UnexpectedEof {
-                Ok(None)
+        let size = loop {
+            let mut buf = [0; 4];
+            if let Err(e) = self.reader.read_exact(&mut buf) {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    return Err(Error::with_source(
+                        MalformedData,
+                        "failed to read .fm record size".to_string(),
+                        e,
+                    ));
+                }
+                // Compressed units may be followed by another stream.
+                let more = self.reader.has_more().into_result(|| {
+                    "failed to read .fm record size".to_string()
+                })?;
+                if !more {
+                    return Ok(None);
+                }
+                read_magic(self.reader.inner_mut())?;
+                self.next_stream()?;
+                continue;
+            }
+
+            let size = u32::from_le_bytes(buf);
+            if size == INDEX_MARKER && self.features & FEATURE_INDEX != 0 {
+                // Index is only used for random access.
+                self.skip_index()?;
+            } else if size == MAGIC
+                && self.reader.compression == Compression::None
+            {
+                // Records can't be that large, so it's a concatenated stream.
+                self.next_stream()?;
             } else {
-                Err(Error::with_source(
-                    MalformedData,
-                    "failed to read .fm record size".to_string(),
-                    e,
-                ))
-            };
-        }
+                break size;
+            }
+        };
 
-        let size = u32::from_le_bytes(buf) as usize;
+        let size = size as usize;
+        self.limits.check_record_size(size)?;
         self.buffer.resize(size, 0);
 
         self.reader
             .read_exact(&mut self.buffer)
             .into_result(|| "failed to read .fm record".to_string())?;
 
-        Ok(Some(RawRecord(&self.buffer)))
+        let resolver = self
+            .resolver
+            .as_deref()
+            .filter(|_| self.features & FEATURE_EXTERNAL_IMAGES != 0);
+        if self.images.is_some() || resolver.is_some() {
+            let mut record = RawRecord::new(&self.buffer).decode()?;
+            let mut resolved = false;
+            for image in record_images_mut(&mut record) {
+                if !image.location.is_empty() {
+                    if let Some(resolver) = resolver {
+                        resolve_image(image, resolver)?;
+                        resolved = true;
+                    }
+                    continue;
+                }
+                let images = match self.images.as_mut() {
+                    Some(images) if !image.digest.is_empty() => images,
+                    _ => continue,
+                };
+                if image.data.is_empty() {
+                    image.data = images
+                        .get(&image.digest)
+                        .ok_or_else(|| {
+                            let desc =
+                                "unresolved .fm image reference".to_string();
+                            Error::new(MalformedData, desc)
+                        })?
+                        .clone();
+                } else {
+                    images.insert(image.digest.clone(), image.data.clone());
+                }
+                image.digest.clear();
+                resolved = true;
+            }
+            if resolved {
+                self.buffer.clear();
+                record.encode(&mut self.buffer).unwrap();
+            }
+        }
+
+        Ok(Some(RawRecord::with_limits(&self.buffer, self.limits)))
     }
 
     fn read_record(&mut self) -> Result<Option<Record>> {
@@ -115,3 +419,333 @@ impl<R: io::Read> Read for Reader<R> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fm::{self, Write as _};
+    use crate::util::test::*;
+    use std::io::Cursor;
+    use std::ops::Range;
+
+    fn new_frame(time: i64, data: Vec<u8>) -> Record {
+        new_scan_frame_rec(fm::ScanFrame {
+            scan: "a".to_string(),
+            time,
+            image: Some(fm::Image {
+                r#type: fm::image::Type::Png as i32,
+                data,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn write_states(
+        compression: fm::Compression,
+        index: bool,
+        times: Range<i64>,
+    ) -> Vec<u8> {
+        let params = fm::WriterParams {
+            compression,
+            index,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        for time in times {
+            let state = fm::ElementViewState {
+                time,
+                vertices: vec![new_point3(1.0, 2.0, 3.0); 100],
+                ..Default::default()
+            };
+            writer
+                .write_record(&new_element_view_state_rec(state))
+                .unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    fn read_times(data: Vec<u8>) -> Result<Vec<i64>> {
+        let mut reader = Reader::new(Cursor::new(data))?;
+        let mut times = Vec::new();
+        while let Some(rec) = reader.read_record()? {
+            times.push(state_of(rec).time);
+        }
+        Ok(times)
+    }
+
+    fn state_of(rec: Record) -> fm::ElementViewState {
+        match rec.r#type {
+            Some(fm::record::Type::ElementViewState(s)) => s,
+            _ => panic!("unexpected record"),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_reader_concatenated() {
+        use fm::Compression::{Gzip, None, Zstd};
+
+        // Gzip members appended without repeating the header.
+        let mut data = write_states(Gzip, false, 0..3);
+        data.extend_from_slice(&write_states(Gzip, false, 3..5)[16..]);
+        assert_eq!(read_times(data).unwrap(), (0..5).collect::<Vec<_>>());
+
+        // Whole streams of different compression, indexed or not.
+        let mut data = write_states(None, true, 0..2);
+        data.extend(write_states(Gzip, false, 2..5));
+        data.extend(write_states(Zstd, true, 5..6));
+        data.extend(write_states(None, false, 6..8));
+        data.extend(write_states(Gzip, true, 8..10));
+        data.extend(write_states(Zstd, false, 10..13));
+        let reader = Reader::new(Cursor::new(data.clone())).unwrap();
+        assert_eq!(reader.compression(), None);
+        assert_eq!(read_times(data).unwrap(), (0..13).collect::<Vec<_>>());
+
+        // Trailing garbage isn't taken for a stream.
+        let mut data = write_states(Zstd, false, 0..2);
+        data.extend_from_slice(b"garbage");
+        assert!(read_times(data).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_reader_zstd() {
+        let data = write_states(fm::Compression::Zstd, false, 0..100);
+        let raw_size = write_states(fm::Compression::None, false, 0..100).len();
+        assert!(data.len() < raw_size);
+
+        let mut reader = Reader::new(Cursor::new(data)).unwrap();
+        assert_eq!(reader.compression(), fm::Compression::Zstd);
+        for time in 0..100 {
+            let state = state_of(reader.read_record().unwrap().unwrap());
+            assert_eq!(state.time, time);
+            assert_eq!(state.vertices.len(), 100);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_reader_zstd_unsupported() {
+        let params = fm::WriterParams {
+            compression: fm::Compression::Zstd,
+            ..Default::default()
+        };
+        let err = fm::Writer::new(Vec::new(), &params).unwrap_err();
+        assert_eq!(err.kind, UnsupportedFeature);
+
+        let mut data = write_states(fm::Compression::None, false, 0..1);
+        data[8..12]
+            .copy_from_slice(&(fm::Compression::Zstd as i32).to_le_bytes());
+        let err = Reader::new(Cursor::new(data)).unwrap_err();
+        assert_eq!(err.kind, UnsupportedFeature);
+    }
+
+    #[test]
+    fn test_raw_record_decode_header() {
+        let animation_loop = Some(fm::element_view::Loop {
+            start: 1,
+            end: 2,
+            ping_pong: true,
+        });
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture_points: vec![new_point2(0.1, 0.2)],
+            animation_loop: animation_loop.clone(),
+            ..Default::default()
+        });
+        let state = new_element_view_state_rec(fm::ElementViewState {
+            element: "a".to_string(),
+            time: 3,
+            vertices: vec![new_point3(0.1, 0.2, 0.3)],
+            ..Default::default()
+        });
+        let preview = Record {
+            r#type: Some(fm::record::Type::Preview(fm::Preview {
+                image: Some(Default::default()),
+            })),
+        };
+        let scan = new_scan_rec(fm::Scan {
+            name: "a".to_string(),
+            camera_angle_of_view: 0.5,
+            ..Default::default()
+        });
+        let refinement = Record {
+            r#type: Some(fm::record::Type::ElementViewRefinement(
+                fm::ElementViewRefinement {
+                    element: "a".to_string(),
+                    vertices: vec![Default::default()],
+                    ..Default::default()
+                },
+            )),
+        };
+        let records = [
+            view,
+            state,
+            scan,
+            new_frame(4, vec![1, 2]),
+            refinement,
+            preview,
+        ];
+        let data = records
+            .iter()
+            .map(|rec| {
+                let mut data = Vec::new();
+                rec.encode(&mut data).unwrap();
+                data
+            })
+            .collect::<Vec<_>>();
+        let headers = data
+            .iter()
+            .map(|d| RawRecord::new(d).decode_header().unwrap())
+            .collect::<Vec<_>>();
+
+        let expected = [
+            new_element_view_rec(fm::ElementView {
+                element: "a".to_string(),
+                animation_loop,
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "a".to_string(),
+                time: 3,
+                ..Default::default()
+            }),
+            new_scan_rec(fm::Scan {
+                name: "a".to_string(),
+                ..Default::default()
+            }),
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "a".to_string(),
+                time: 4,
+                ..Default::default()
+            }),
+            Record {
+                r#type: Some(fm::record::Type::ElementViewRefinement(
+                    fm::ElementViewRefinement {
+                        element: "a".to_string(),
+                        ..Default::default()
+                    },
+                )),
+            },
+            Record {
+                r#type: Some(fm::record::Type::Preview(Default::default())),
+            },
+        ];
+        assert_eq!(headers, expected);
+    }
+
+    #[test]
+    fn test_reader_dedup() {
+        let mut records = Vec::new();
+        for time in 0..100 {
+            // Every tenth frame repeats the same image.
+            let data = if time % 10 == 0 {
+                vec![0; 10_000]
+            } else {
+                vec![time as u8; 10_000]
+            };
+            records.push(new_frame(time, data));
+        }
+        let write = |dedup| {
+            let params = fm::WriterParams {
+                compression: fm::Compression::None,
+                dedup,
+                ..Default::default()
+            };
+            let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+            for record in &records {
+                writer.write_record(record).unwrap();
+            }
+            writer.into_inner().unwrap()
+        };
+
+        // Repeated image data is written once (images get digests).
+        let data = write(true);
+        let full_len = write(false).len();
+        assert!(data.len() < full_len - 8 * 10_000);
+        assert!(data.len() > full_len - 9 * 10_000);
+
+        let mut reader = Reader::new(Cursor::new(data)).unwrap();
+        assert_eq!(reader.features(), fm::FEATURE_DEDUP);
+        for record in &records {
+            assert_eq!(&reader.read_record().unwrap().unwrap(), record);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+        assert_eq!(reader.images.as_ref().unwrap().len(), 91);
+    }
+
+    #[test]
+    fn test_reader_texture_pages() {
+        let image = |data: Vec<u8>| fm::Image {
+            r#type: fm::image::Type::Png as i32,
+            data,
+            ..Default::default()
+        };
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(image(vec![1; 16])),
+            texture_pages: vec![image(vec![2; 16]), image(vec![1; 16])],
+            face_pages: vec![0, 1, 2],
+            ..Default::default()
+        });
+        let records = [view.clone(), view];
+
+        // Repeated pages are written only once.
+        let params = fm::WriterParams {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        let mut reader = writer_to_reader(writer);
+        for record in &records {
+            assert_eq!(&reader.read_record().unwrap().unwrap(), record);
+        }
+        assert_eq!(reader.images.as_ref().unwrap().len(), 2);
+
+        let dir = std::env::temp_dir()
+            .join(format!("texture-pages-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("images")).unwrap();
+        let params = fm::WriterParams {
+            external_images: Some(dir.join("images")),
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        writer.set_image_base(&dir);
+        for record in &records {
+            writer.write_record(record).unwrap();
+        }
+        let data = writer.into_inner().unwrap();
+
+        // All pages are stored externally.
+        let mut reader = Reader::new(Cursor::new(data.clone())).unwrap();
+        while let Some(rec) = reader.read_record().unwrap() {
+            match rec.r#type {
+                Some(fm::record::Type::ElementView(v)) => {
+                    assert_eq!(v.texture_pages.len(), 2);
+                    for page in v.texture.iter().chain(&v.texture_pages) {
+                        assert!(page.data.is_empty());
+                        assert!(page.location.starts_with("images"));
+                    }
+                }
+                _ => panic!("unexpected record"),
+            }
+        }
+
+        let mut reader = Reader::new(Cursor::new(data)).unwrap();
+        reader.set_image_resolver(Box::new(fm::FileImageResolver {
+            base: dir.clone(),
+            unrestricted: false,
+        }));
+        for record in &records {
+            assert_eq!(&reader.read_record().unwrap().unwrap(), record);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
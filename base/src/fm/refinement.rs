@@ -0,0 +1,62 @@
+use crate::defs::{Error, ErrorKind::*, Result};
+use crate::fm::{ElementView, ElementViewRefinement, ElementViewState};
+
+// Replaces an item by its 1-based index, or appends it if the index is next
+// to the last one.
+fn set_item<T>(items: &mut Vec<T>, index: u32, item: T) -> bool {
+    let index = index as usize;
+    if index == 0 || index > items.len() + 1 {
+        return false;
+    }
+    if index == items.len() + 1 {
+        items.push(item);
+    } else {
+        items[index - 1] = item;
+    }
+    true
+}
+
+pub fn apply_refinement(
+    view: &mut ElementView,
+    state: &mut ElementViewState,
+    refinement: &ElementViewRefinement,
+) -> Result<()> {
+    let bad_index_res = |what, index| {
+        let desc = format!(
+            "bad {} index {} in refinement of element '{}'",
+            what, index, refinement.element
+        );
+        Err(Error::new(MalformedData, desc))
+    };
+
+    for v in &refinement.vertices {
+        let vertex = v.vertex.unwrap_or_default();
+        if !set_item(&mut state.vertices, v.index, vertex) {
+            return bad_index_res("vertex", v.index);
+        }
+        if let Some(normal) = v.normal {
+            if !set_item(&mut state.normals, v.index, normal) {
+                return bad_index_res("normal", v.index);
+            }
+        }
+        if let Some(point) = v.texture_point {
+            if !set_item(&mut view.texture_points, v.index, point) {
+                return bad_index_res("texture point", v.index);
+            }
+        }
+    }
+
+    for f in &refinement.faces {
+        let face = f.face.clone().unwrap_or_default();
+        if !set_item(&mut view.faces, f.index, face) {
+            return bad_index_res("face", f.index);
+        }
+        if !view.face_labels.is_empty()
+            && !set_item(&mut view.face_labels, f.index, f.label)
+        {
+            return bad_index_res("face label", f.index);
+        }
+    }
+
+    Ok(())
+}
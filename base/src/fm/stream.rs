@@ -0,0 +1,258 @@
+use std::iter::Peekable;
+use std::str::FromStr;
+
+use crate::defs::{Error, ErrorKind::*, Result};
+use crate::fm::{record, ElementViewState, Read, Record, Time, Write};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RecordKind {
+    ElementView,
+    ElementViewState,
+    ElementViewRefinement,
+    ElementTexture,
+    Scan,
+    ScanFrame,
+    Preview,
+    Impostors,
+}
+
+impl RecordKind {
+    pub fn of(record: &Record) -> Option<RecordKind> {
+        use record::Type::*;
+        Some(match record.r#type.as_ref()? {
+            ElementView(_) => RecordKind::ElementView,
+            ElementViewState(_) => RecordKind::ElementViewState,
+            ElementViewRefinement(_) => RecordKind::ElementViewRefinement,
+            ElementTexture(_) => RecordKind::ElementTexture,
+            Scan(_) => RecordKind::Scan,
+            ScanFrame(_) => RecordKind::ScanFrame,
+            Preview(_) => RecordKind::Preview,
+            Impostors(_) => RecordKind::Impostors,
+        })
+    }
+
+    pub fn is_scan(self) -> bool {
+        matches!(self, RecordKind::Scan | RecordKind::ScanFrame)
+    }
+}
+
+impl FromStr for RecordKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "element-view" => Ok(RecordKind::ElementView),
+            "element-view-state" => Ok(RecordKind::ElementViewState),
+            "element-view-refinement" => Ok(RecordKind::ElementViewRefinement),
+            "element-texture" => Ok(RecordKind::ElementTexture),
+            "scan" => Ok(RecordKind::Scan),
+            "scan-frame" => Ok(RecordKind::ScanFrame),
+            "preview" => Ok(RecordKind::Preview),
+            "impostors" => Ok(RecordKind::Impostors),
+            _ => Err(Error::new(
+                MalformedData,
+                format!("unknown record kind '{}'", s),
+            )),
+        }
+    }
+}
+
+// Key of the canonical record order: previews, then definitions (views
+// and scans), then time-ordered states, textures and frames, then
+// refinements and impostors.
+pub fn record_order_key(record: &Record) -> (i8, Time) {
+    use record::Type::*;
+    match &record.r#type {
+        Some(Preview(_)) => (-1, Time(0)),
+        Some(ElementView(_)) | Some(Scan(_)) | None => (0, Time(0)),
+        Some(ElementViewState(s)) => (1, Time(s.time)),
+        Some(ElementTexture(t)) => (1, Time(t.time)),
+        Some(ScanFrame(f)) => (1, Time(f.time)),
+        Some(ElementViewRefinement(_)) | Some(Impostors(_)) => (2, Time(0)),
+    }
+}
+
+pub struct Records<'a> {
+    reader: &'a mut dyn Read,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.read_record().transpose()
+    }
+}
+
+pub fn records(reader: &mut dyn Read) -> Records<'_> {
+    Records { reader }
+}
+
+pub struct FilterByType<I> {
+    inner: I,
+    kind: RecordKind,
+}
+
+impl<I: Iterator<Item = Result<Record>>> Iterator for FilterByType<I> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for res in self.inner.by_ref() {
+            match &res {
+                Ok(rec) if RecordKind::of(rec) != Some(self.kind) => continue,
+                _ => return Some(res),
+            }
+        }
+        None
+    }
+}
+
+pub struct MapStates<I, F> {
+    inner: I,
+    f: F,
+}
+
+impl<I, F> Iterator for MapStates<I, F>
+where
+    I: Iterator<Item = Result<Record>>,
+    F: FnMut(ElementViewState) -> Result<ElementViewState>,
+{
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use record::Type::*;
+        Some(self.inner.next()?.and_then(|rec| match rec.r#type {
+            Some(ElementViewState(state)) => Ok(Record {
+                r#type: Some(ElementViewState((self.f)(state)?)),
+            }),
+            _ => Ok(rec),
+        }))
+    }
+}
+
+pub struct MergeSortedByTime<I: Iterator> {
+    inners: Vec<Peekable<I>>,
+}
+
+impl<I: Iterator<Item = Result<Record>>> Iterator for MergeSortedByTime<I> {
+    type Item = Result<Record>;
+
+    // Errors are yielded as soon as they are peeked.
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut best: Option<(usize, (i8, Time))> = None;
+        for (i, inner) in self.inners.iter_mut().enumerate() {
+            match inner.peek() {
+                Some(Ok(rec)) => {
+                    let key = record_order_key(rec);
+                    if best.is_none_or(|(_, k)| key < k) {
+                        best = Some((i, key));
+                    }
+                }
+                Some(Err(_)) => return inner.next(),
+                None => (),
+            }
+        }
+        self.inners[best?.0].next()
+    }
+}
+
+// Merges streams already in the canonical order into a single one.
+pub fn merge_sorted_by_time<I>(inners: Vec<I>) -> MergeSortedByTime<I>
+where
+    I: Iterator<Item = Result<Record>>,
+{
+    MergeSortedByTime {
+        inners: inners.into_iter().map(Iterator::peekable).collect(),
+    }
+}
+
+pub trait RecordIterator: Iterator<Item = Result<Record>> + Sized {
+    fn filter_by_type(self, kind: RecordKind) -> FilterByType<Self> {
+        FilterByType { inner: self, kind }
+    }
+
+    fn map_states<F>(self, f: F) -> MapStates<Self, F>
+    where
+        F: FnMut(ElementViewState) -> Result<ElementViewState>,
+    {
+        MapStates { inner: self, f }
+    }
+
+    fn write_all(self, writer: &mut dyn Write) -> Result<()> {
+        for rec in self {
+            writer.write_record(&rec?)?;
+        }
+        Ok(())
+    }
+}
+
+impl<I: Iterator<Item = Result<Record>>> RecordIterator for I {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fm;
+    use crate::util::test::*;
+
+    fn new_state_rec(element: &str, time: i64) -> Record {
+        new_element_view_state_rec(ElementViewState {
+            element: element.to_string(),
+            time,
+            ..Default::default()
+        })
+    }
+
+    fn state_times(records: Vec<Record>) -> Vec<i64> {
+        records
+            .into_iter()
+            .map(|rec| match rec.r#type {
+                Some(record::Type::ElementViewState(s)) => s.time,
+                _ => -1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_filter_by_type_and_map_states() {
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(fm::ElementView {
+                element: "a".to_string(),
+                ..Default::default()
+            }),
+            new_state_rec("a", 1),
+            new_state_rec("a", 2),
+        ]);
+
+        let recs = records(&mut reader)
+            .filter_by_type(RecordKind::ElementViewState)
+            .map_states(|mut s| {
+                s.time *= 10;
+                Ok(s)
+            })
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(state_times(recs), vec![10, 20]);
+    }
+
+    #[test]
+    fn test_stream_merge_sorted_by_time() {
+        let mut reader1 = create_reader_with_records(&[
+            new_state_rec("a", 1),
+            new_state_rec("a", 4),
+        ]);
+        let mut reader2 = create_reader_with_records(&[
+            new_state_rec("b", 2),
+            new_state_rec("b", 3),
+        ]);
+
+        let recs = merge_sorted_by_time(vec![
+            records(&mut reader1),
+            records(&mut reader2),
+        ])
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+        assert_eq!(state_times(recs), vec![1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,326 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::defs::{Error, ErrorKind::*, Result};
+
+pub const NANOS_PER_MICRO: i64 = 1_000;
+pub const NANOS_PER_MILLI: i64 = 1_000_000;
+pub const NANOS_PER_SEC: i64 = 1_000_000_000;
+pub const NANOS_PER_MIN: i64 = 60 * NANOS_PER_SEC;
+pub const NANOS_PER_HOUR: i64 = 60 * NANOS_PER_MIN;
+
+// Monotonic time with nanosecond precision (as stored in records). It's
+// parsed from and formatted to a human-friendly form: either a number with
+// unit suffix ('1500ms', '1.5s', '2m') or a clock notation ('00:01:20.5');
+// a bare integer is taken as nanoseconds (and serialized as such).
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+)]
+#[serde(transparent)]
+pub struct Time(pub i64);
+
+impl Time {
+    pub const MIN: Time = Time(i64::MIN);
+    pub const MAX: Time = Time(i64::MAX);
+
+    pub fn from_secs_f64(secs: f64) -> Self {
+        Self((secs * NANOS_PER_SEC as f64).round() as i64)
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / NANOS_PER_SEC as f64
+    }
+
+    pub fn from_millis(millis: i64) -> Self {
+        Self(millis * NANOS_PER_MILLI)
+    }
+
+    pub fn as_millis(self) -> i64 {
+        self.0 / NANOS_PER_MILLI
+    }
+
+    pub fn checked_add(self, other: Time) -> Option<Time> {
+        self.0.checked_add(other.0).map(Time)
+    }
+}
+
+impl From<i64> for Time {
+    fn from(nanos: i64) -> Self {
+        Self(nanos)
+    }
+}
+
+impl From<Time> for i64 {
+    fn from(time: Time) -> Self {
+        time.0
+    }
+}
+
+impl TryFrom<Duration> for Time {
+    type Error = Error;
+
+    fn try_from(duration: Duration) -> Result<Self> {
+        i64::try_from(duration.as_nanos()).map(Self).map_err(|_| {
+            let desc = format!("duration {:?} exceeds time range", duration);
+            Error::new(MalformedData, desc)
+        })
+    }
+}
+
+impl TryFrom<Time> for Duration {
+    type Error = Error;
+
+    fn try_from(time: Time) -> Result<Self> {
+        u64::try_from(time.0)
+            .map(Duration::from_nanos)
+            .map_err(|_| {
+                let desc = format!("negative time {} as duration", time);
+                Error::new(MalformedData, desc)
+            })
+    }
+}
+
+impl Add for Time {
+    type Output = Time;
+
+    fn add(self, other: Time) -> Time {
+        Time(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Time {
+    fn add_assign(&mut self, other: Time) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Time {
+    type Output = Time;
+
+    fn sub(self, other: Time) -> Time {
+        Time(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Time {
+    fn sub_assign(&mut self, other: Time) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Time {
+    type Output = Time;
+
+    fn neg(self) -> Time {
+        Time(-self.0)
+    }
+}
+
+impl Mul<i64> for Time {
+    type Output = Time;
+
+    fn mul(self, factor: i64) -> Time {
+        Time(self.0 * factor)
+    }
+}
+
+impl Mul<Time> for i64 {
+    type Output = Time;
+
+    fn mul(self, time: Time) -> Time {
+        Time(self * time.0)
+    }
+}
+
+impl Div<i64> for Time {
+    type Output = Time;
+
+    fn div(self, divisor: i64) -> Time {
+        Time(self.0 / divisor)
+    }
+}
+
+impl Sum for Time {
+    fn sum<I: Iterator<Item = Time>>(iter: I) -> Time {
+        Time(iter.map(|t| t.0).sum())
+    }
+}
+
+impl<'a> Sum<&'a Time> for Time {
+    fn sum<I: Iterator<Item = &'a Time>>(iter: I) -> Time {
+        iter.copied().sum()
+    }
+}
+
+fn malformed_time(s: &str) -> Error {
+    let desc = format!(
+        "malformed time '{}' (expected e.g. '1.5s', '250ms' or '00:01:20.5')",
+        s
+    );
+    Error::new(MalformedData, desc)
+}
+
+fn parse_scaled(s: &str, scale: i64) -> Option<i64> {
+    if let Ok(value) = s.parse::<i64>() {
+        return value.checked_mul(scale);
+    }
+    let value = s.parse::<f64>().ok()? * scale as f64;
+    if !value.is_finite() || value < 0.0 || value >= i64::MAX as f64 {
+        return None;
+    }
+    Some(value.round() as i64)
+}
+
+// Parses '[[hours:]minutes:]seconds', where minutes and seconds (but not
+// the leading hours) are below 60.
+fn parse_clock(s: &str) -> Option<i64> {
+    let parts: Vec<_> = s.split(':').collect();
+    if parts.len() > 3 {
+        return None;
+    }
+
+    let (secs, rest) = parts.split_last()?;
+    let mut time = parse_scaled(secs, NANOS_PER_SEC)?;
+    if time >= NANOS_PER_MIN {
+        return None;
+    }
+    let units = [(NANOS_PER_MIN, 60), (NANOS_PER_HOUR, u32::MAX)];
+    for (part, (scale, limit)) in rest.iter().rev().zip(units) {
+        let value = part.parse::<u32>().ok()?;
+        if value >= limit {
+            return None;
+        }
+        time = time.checked_add((value as i64).checked_mul(scale)?)?;
+    }
+    Some(time)
+}
+
+impl FromStr for Time {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (sign, abs) = match s.strip_prefix('-') {
+            Some(abs) => (-1, abs),
+            None => (1, s),
+        };
+        if abs.starts_with(['-', '+']) {
+            return Err(malformed_time(s));
+        }
+
+        const UNITS: [(&str, i64); 6] = [
+            ("ns", 1),
+            ("us", NANOS_PER_MICRO),
+            ("ms", NANOS_PER_MILLI),
+            ("s", NANOS_PER_SEC),
+            ("m", NANOS_PER_MIN),
+            ("h", NANOS_PER_HOUR),
+        ];
+
+        let time = if abs.contains(':') {
+            parse_clock(abs)
+        } else if let Some((value, scale)) = UNITS
+            .iter()
+            .find_map(|(unit, scale)| Some((abs.strip_suffix(unit)?, *scale)))
+        {
+            parse_scaled(value, scale)
+        } else {
+            abs.parse::<i64>().ok()
+        };
+
+        time.map(|t| Self(sign * t))
+            .ok_or_else(|| malformed_time(s))
+    }
+}
+
+impl Display for Time {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+
+        let abs = self.0.unsigned_abs();
+        let secs = abs / NANOS_PER_SEC as u64;
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        )?;
+
+        let nanos = abs % NANOS_PER_SEC as u64;
+        if nanos != 0 {
+            let frac = format!("{:09}", nanos);
+            write!(f, ".{}", frac.trim_end_matches('0'))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_parsing() {
+        let parse = |s: &str| s.parse::<Time>().map(|t| t.0).ok();
+        assert_eq!(parse("1.5s"), Some(1_500_000_000));
+        assert_eq!(parse("250ms"), Some(250_000_000));
+        assert_eq!(parse("2m"), Some(120_000_000_000));
+        assert_eq!(parse("00:01:20.5"), Some(80_500_000_000));
+        assert_eq!(parse("1:02:03"), Some(3_723_000_000_000));
+        assert_eq!(parse("100:00:00"), Some(360_000_000_000_000));
+        assert_eq!(parse("-10"), Some(-10));
+        assert_eq!(parse("1.5"), None);
+        assert_eq!(parse("1:2:3:4"), None);
+        assert_eq!(parse("abc"), None);
+
+        // Minutes and seconds of a clock are below 60.
+        assert_eq!(parse("00:60"), None);
+        assert_eq!(parse("00:59.9999"), Some(59_999_900_000));
+        assert_eq!(parse("1:60:00"), None);
+
+        // Overflows are malformed rather than wrapped.
+        assert_eq!(parse("4000000000:00:00"), None);
+        assert_eq!(parse("2562047:47:16.854775807"), Some(i64::MAX));
+        assert_eq!(parse("2562047:47:16.854775808"), None);
+        assert_eq!(parse("1e30s"), None);
+        assert_eq!(parse("9223372036854775807s"), None);
+    }
+
+    #[test]
+    fn test_time_display() {
+        let format = |t| Time(t).to_string();
+        assert_eq!(format(0), "00:00:00");
+        assert_eq!(format(80_500_000_000), "00:01:20.5");
+        assert_eq!(format(-3_723_000_000_001), "-01:02:03.000000001");
+    }
+
+    #[test]
+    fn test_time_conversions() {
+        assert_eq!(Time::from_secs_f64(1.5), Time(1_500_000_000));
+        assert_eq!(Time(-250_000_000).as_secs_f64(), -0.25);
+        assert_eq!(Time::from_millis(20).as_millis(), 20);
+
+        let duration = Duration::from_millis(1500);
+        assert_eq!(Time::try_from(duration).unwrap(), Time(1_500_000_000));
+        assert_eq!(Duration::try_from(Time(1_500_000_000)).unwrap(), duration);
+        assert!(Duration::try_from(Time(-1)).is_err());
+        assert!(Time::try_from(Duration::from_secs(u64::MAX)).is_err());
+    }
+}
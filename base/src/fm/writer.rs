@@ -1,22 +1,217 @@
+use std::collections::HashSet;
+use std::fmt;
 use std::io;
 use std::io::Write as _;
+use std::path::Path;
 use std::result;
 
 use flate2::write::GzEncoder;
 use prost::Message;
+#[cfg(feature = "zstd")]
+use zstd::stream::write::Encoder;
 
-use crate::defs::{Error, IntoResult, Result};
-use crate::fm::{Compression, RawRecord, Record, WriterParams, MAGIC, VERSION};
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+use crate::fm::{
+    image_digest, index, record, record_images, record_images_mut, Compression,
+    Features, ImageStore, Index, RawRecord, Record, WriterParams,
+    FEATURE_DEDUP, FEATURE_EXTERNAL_IMAGES, FEATURE_INDEX, INDEX_MARKER, MAGIC,
+    VERSION,
+};
 
 pub trait Write {
     fn write_raw_record<'a>(&mut self, record: &RawRecord<'a>) -> Result<()>;
     fn write_record(&mut self, record: &Record) -> Result<()>;
 }
 
+// Zstd encoder which (like GzEncoder) finishes the stream when dropped.
+#[cfg(feature = "zstd")]
+pub struct ZstdEncoder<W: io::Write>(Option<Encoder<'static, W>>);
+
+#[cfg(feature = "zstd")]
+impl<W: io::Write> ZstdEncoder<W> {
+    fn new(inner: W, level: i32) -> Result<Self> {
+        let encoder = Encoder::new(inner, level)
+            .into_result(|| "failed to create zstd encoder".to_string())?;
+        Ok(Self(Some(encoder)))
+    }
+
+    fn encoder(&mut self) -> &mut Encoder<'static, W> {
+        self.0.as_mut().unwrap()
+    }
+
+    fn try_finish(&mut self) -> io::Result<W> {
+        match self.0.take().unwrap().try_finish() {
+            Ok(inner) => Ok(inner),
+            Err((encoder, err)) => {
+                self.0 = Some(encoder);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: io::Write> Drop for ZstdEncoder<W> {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: io::Write + fmt::Debug> fmt::Debug for ZstdEncoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.0.as_ref().map(|e| e.get_ref());
+        f.debug_tuple("ZstdEncoder").field(&inner).finish()
+    }
+}
+
+// Writer compressing each record separately, so that it can be read from
+// its offset, which appends index of records when finished (or dropped).
+pub struct IndexWriter<W: io::Write> {
+    inner: Option<W>,
+    compression: Compression,
+    gzip_level: u32,
+    #[cfg(feature = "zstd")]
+    zstd_level: i32,
+    record: Vec<u8>,
+    offset: u64,
+    index: Index,
+}
+
+impl<W: io::Write> IndexWriter<W> {
+    fn new(inner: W, compression: Compression, params: &WriterParams) -> Self {
+        Self {
+            inner: Some(inner),
+            compression,
+            gzip_level: params.gzip_level,
+            #[cfg(feature = "zstd")]
+            zstd_level: params.zstd_level,
+            record: Vec::new(),
+            offset: HEADER_SIZE,
+            index: Index::default(),
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self.compression {
+            Compression::Auto | Compression::Gzip => {
+                let level = flate2::Compression::new(self.gzip_level);
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::stream::encode_all(data, self.zstd_level)
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => unreachable!(),
+            Compression::None => Ok(data.to_vec()),
+        }
+    }
+
+    // Writes the data of record written so far at the current offset.
+    fn write_unit(&mut self) -> Result<u64> {
+        let unit = self
+            .compress(&self.record)
+            .into_result(|| "failed to compress .fm record".to_string())?;
+        self.record.clear();
+        self.inner
+            .as_mut()
+            .unwrap()
+            .write_all(&unit)
+            .into_result(|| "failed to write .fm record".to_string())?;
+        let offset = self.offset;
+        self.offset += unit.len() as u64;
+        Ok(offset)
+    }
+
+    fn end_record(&mut self, record: &Record) -> Result<()> {
+        let offset = self.write_unit()?;
+        self.index.entries.push(index_entry(record, offset));
+        Ok(())
+    }
+
+    fn try_finish(&mut self) -> Result<W> {
+        let mut data = Vec::new();
+        self.index.encode(&mut data).unwrap();
+        self.record.extend_from_slice(&INDEX_MARKER.to_le_bytes());
+        self.record
+            .extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.record.extend_from_slice(&data);
+        let offset = self.write_unit()?;
+
+        let inner = self.inner.as_mut().unwrap();
+        inner
+            .write_all(&offset.to_le_bytes())
+            .and_then(|_| inner.write_all(&MAGIC.to_le_bytes()))
+            .and_then(|_| inner.flush())
+            .into_result(|| "failed to write .fm index".to_string())?;
+        Ok(self.inner.take().unwrap())
+    }
+}
+
+impl<W: io::Write> Drop for IndexWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for IndexWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: io::Write + fmt::Debug> fmt::Debug for IndexWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IndexWriter")
+            .field("inner", &self.inner)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+// Magic, version, compression and features.
+const HEADER_SIZE: u64 = 16;
+
+fn index_entry(record: &Record, offset: u64) -> index::Entry {
+    use record::Type::*;
+    let (r#type, name, time) = match &record.r#type {
+        Some(ElementView(v)) => (1, v.element.clone(), 0),
+        Some(ElementViewState(s)) => (2, s.element.clone(), s.time),
+        Some(Scan(s)) => (3, s.name.clone(), 0),
+        Some(ScanFrame(f)) => (4, f.scan.clone(), f.time),
+        Some(Preview(_)) => (5, String::new(), 0),
+        Some(ElementViewRefinement(r)) => (6, r.element.clone(), 0),
+        Some(Impostors(_)) => (7, String::new(), 0),
+        Some(ElementTexture(t)) => (8, t.element.clone(), t.time),
+        None => (0, String::new(), 0),
+    };
+    index::Entry {
+        r#type,
+        name,
+        time,
+        offset,
+    }
+}
+
 #[derive(Debug)]
 pub enum RawWriter<W: io::Write> {
     Plain(W),
     Gzip(GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(ZstdEncoder<W>),
+    Indexed(IndexWriter<W>),
 }
 
 impl<W: io::Write> RawWriter<W> {
@@ -32,6 +227,20 @@ impl<W: io::Write> RawWriter<W> {
                 }
                 Ok(encoder.finish().unwrap())
             }
+            #[cfg(feature = "zstd")]
+            RawWriter::Zstd(mut encoder) => {
+                match encoder
+                    .try_finish()
+                    .into_result(|| "failed to finish encoding".to_string())
+                {
+                    Ok(inner) => Ok(inner),
+                    Err(err) => Err((RawWriter::Zstd(encoder), err)),
+                }
+            }
+            RawWriter::Indexed(mut writer) => match writer.try_finish() {
+                Ok(inner) => Ok(inner),
+                Err(err) => Err((RawWriter::Indexed(writer), err)),
+            },
         }
     }
 }
@@ -41,6 +250,9 @@ impl<W: io::Write> io::Write for RawWriter<W> {
         match self {
             RawWriter::Plain(inner) => inner.write(buf),
             RawWriter::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "zstd")]
+            RawWriter::Zstd(encoder) => encoder.encoder().write(buf),
+            RawWriter::Indexed(writer) => writer.write(buf),
         }
     }
 
@@ -48,6 +260,9 @@ impl<W: io::Write> io::Write for RawWriter<W> {
         match self {
             RawWriter::Plain(inner) => inner.flush(),
             RawWriter::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            RawWriter::Zstd(encoder) => encoder.encoder().flush(),
+            RawWriter::Indexed(writer) => writer.flush(),
         }
     }
 }
@@ -56,10 +271,21 @@ impl<W: io::Write> io::Write for RawWriter<W> {
 pub struct Writer<W: io::Write> {
     writer: RawWriter<W>,
     buffer: Vec<u8>,
+    digests: Option<HashSet<Vec<u8>>>, // Digests of written images.
+    store: Option<ImageStore>,
 }
 
 impl<W: io::Write> Writer<W> {
     pub fn new(mut inner: W, params: &WriterParams) -> Result<Self> {
+        let compression = match params.compression {
+            Compression::Auto => Compression::Gzip,
+            compression => compression,
+        };
+        #[cfg(not(feature = "zstd"))]
+        if compression == Compression::Zstd {
+            return Err(crate::fm::zstd_unsupported_err());
+        }
+
         inner
             .write_all(&MAGIC.to_le_bytes())
             .into_result(|| "failed to write .fm magic".to_string())?;
@@ -67,23 +293,60 @@ impl<W: io::Write> Writer<W> {
             .write_all(&VERSION.to_le_bytes())
             .into_result(|| "failed to write .fm version".to_string())?;
         inner
-            .write_all(&(params.compression as i32).to_le_bytes())
+            .write_all(&(compression as i32).to_le_bytes())
             .into_result(|| "failed to write .fm compression".to_string())?;
+        let mut features: Features = 0;
+        if params.dedup {
+            features |= FEATURE_DEDUP;
+        }
+        if params.external_images.is_some() {
+            features |= FEATURE_EXTERNAL_IMAGES;
+        }
+        if params.index {
+            // Deduplicated images are resolved by reading records in order.
+            if params.dedup {
+                let desc = "can't index .fm file with deduplicated images";
+                return Err(Error::new(UnsupportedFeature, desc.to_string()));
+            }
+            features |= FEATURE_INDEX;
+        }
+        inner
+            .write_all(&features.to_le_bytes())
+            .into_result(|| "failed to write .fm features".to_string())?;
 
-        let writer = match params.compression {
+        let writer = match compression {
+            _ if params.index => {
+                RawWriter::Indexed(IndexWriter::new(inner, compression, params))
+            }
             Compression::None => RawWriter::Plain(inner),
-            Compression::Gzip => {
+            Compression::Auto | Compression::Gzip => {
                 let compression = flate2::Compression::new(params.gzip_level);
                 RawWriter::Gzip(GzEncoder::new(inner, compression))
             }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                RawWriter::Zstd(ZstdEncoder::new(inner, params.zstd_level)?)
+            }
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => unreachable!(),
         };
 
         Ok(Self {
             writer,
             buffer: Vec::<u8>::with_capacity(0),
+            digests: params.dedup.then(HashSet::new),
+            store: params.external_images.as_ref().map(ImageStore::new),
         })
     }
 
+    // Makes locations of external images relative to the directory (of the
+    // output file) instead of the current one, as readers resolve them.
+    pub fn set_image_base<P: AsRef<Path>>(&mut self, dir: P) {
+        if let Some(store) = self.store.as_mut() {
+            store.set_base(dir);
+        }
+    }
+
     pub fn into_inner(self) -> result::Result<W, (Self, Error)> {
         match self.writer.into_inner() {
             Ok(inner) => Ok(inner),
@@ -91,6 +354,8 @@ impl<W: io::Write> Writer<W> {
                 Self {
                     writer,
                     buffer: self.buffer,
+                    digests: self.digests,
+                    store: self.store,
                 },
                 err,
             )),
@@ -100,16 +365,68 @@ impl<W: io::Write> Writer<W> {
 
 impl<W: io::Write> Write for Writer<W> {
     fn write_raw_record<'a>(&mut self, record: &RawRecord<'a>) -> Result<()> {
+        if self.digests.is_some() || self.store.is_some() {
+            return self.write_record(&record.decode()?);
+        }
+
         self.writer
-            .write_all(&(record.0.len() as u32).to_le_bytes())
+            .write_all(&(record.as_bytes().len() as u32).to_le_bytes())
             .into_result(|| "failed to write .fm record size".to_string())?;
 
         self.writer
-            .write_all(record.0)
-            .into_result(|| "failed to write .fm record".to_string())
+            .write_all(record.as_bytes())
+            .into_result(|| "failed to write .fm record".to_string())?;
+
+        if let RawWriter::Indexed(writer) = &mut self.writer {
+            writer.end_record(&record.decode_header()?)?;
+        }
+        Ok(())
     }
 
     fn write_record(&mut self, record: &Record) -> Result<()> {
+        // Records are cloned only if some of their images are rewritten.
+        let mut processed;
+        let record = if let Some(store) = self.store.as_mut() {
+            // External images are stored once, so dedup is not needed.
+            if record_images(record).iter().all(|i| i.data.is_empty()) {
+                record
+            } else {
+                processed = record.clone();
+                for image in record_images_mut(&mut processed) {
+                    store.externalize(image)?;
+                }
+                &processed
+            }
+        } else if let Some(digests) = self.digests.as_mut() {
+            let mut updates = Vec::new();
+            for (i, image) in record_images(record).into_iter().enumerate() {
+                if !image.location.is_empty() || image.data.is_empty() {
+                    continue; // Already referenced by digest or location.
+                }
+                // Images are written once, repetitions refer to them.
+                let digest = image_digest(&image.data);
+                let repeated = !digests.insert(digest.clone());
+                if repeated || image.digest != digest {
+                    updates.push((i, digest, repeated));
+                }
+            }
+            if updates.is_empty() {
+                record
+            } else {
+                processed = record.clone();
+                let mut images = record_images_mut(&mut processed);
+                for (i, digest, repeated) in updates {
+                    if repeated {
+                        images[i].data.clear();
+                    }
+                    images[i].digest = digest;
+                }
+                &processed
+            }
+        } else {
+            record
+        };
+
         let size = record.encoded_len();
         self.buffer.clear();
         self.buffer.reserve(size);
@@ -122,6 +439,11 @@ impl<W: io::Write> Write for Writer<W> {
 
         self.writer
             .write_all(&self.buffer)
-            .into_result(|| "failed to write .fm record".to_string())
+            .into_result(|| "failed to write .fm record".to_string())?;
+
+        if let RawWriter::Indexed(writer) = &mut self.writer {
+            writer.end_record(record)?;
+        }
+        Ok(())
     }
 }
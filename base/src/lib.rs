@@ -1,5 +1,6 @@
 pub mod defs;
 mod ffi;
 pub mod fm;
+pub mod render;
 #[macro_use]
 pub mod util;
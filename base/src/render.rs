@@ -0,0 +1,217 @@
+// Playback logic shared by the viewer and native renderers, so that they
+// show the same animation at the same moments.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound::*;
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+use crate::defs::{Error, ErrorKind::*, Result};
+use crate::fm::{self, Interpolate, Time, NANOS_PER_SEC};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Interpolation {
+    // The state closest in time.
+    Nearest,
+    Linear,
+    // Through three neighbouring states (linear if there are only two).
+    #[default]
+    Quadratic,
+}
+
+impl FromStr for Interpolation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Interpolation::Nearest),
+            "linear" => Ok(Interpolation::Linear),
+            "quadratic" => Ok(Interpolation::Quadratic),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown interpolation (can be 'nearest', 'linear' or \
+                 'quadratic')"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Interpolation::Nearest => "nearest",
+            Interpolation::Linear => "linear",
+            Interpolation::Quadratic => "quadratic",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub const DEFAULT_INTERPOLATION: &str = "quadratic";
+
+// Returns None before the first state and the last state after it.
+pub fn state_at<S: Interpolate>(
+    states: &BTreeMap<Time, S>,
+    at: Time,
+    interpolation: Interpolation,
+) -> Option<S> {
+    if interpolation == Interpolation::Quadratic {
+        return fm::interpolate_state_at(states, at);
+    }
+    if let Some(state) = states.get(&at) {
+        return Some(state.clone());
+    }
+
+    let prev = states.range((Unbounded, Excluded(at))).next_back()?;
+    let next = match states.range((Excluded(at), Unbounded)).next() {
+        Some(next) => next,
+        None => return Some(prev.1.clone()),
+    };
+
+    Some(match interpolation {
+        Interpolation::Nearest if at - *prev.0 <= *next.0 - at => {
+            prev.1.clone()
+        }
+        Interpolation::Nearest => next.1.clone(),
+        _ => S::interpolate_linear(at, (*prev.0, prev.1), (*next.0, next.1)),
+    })
+}
+
+// Time span of all element states, None if there are no states.
+pub fn animation_range<'a, S: 'a>(
+    states: impl IntoIterator<Item = &'a BTreeMap<Time, S>>,
+) -> Option<(Time, Time)> {
+    let spans = states
+        .into_iter()
+        .filter_map(|s| Some((*s.keys().next()?, *s.keys().next_back()?)));
+    spans.reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)))
+}
+
+// Maps time after the loop end back into the loop, so that playback
+// restarts from the loop start (or goes back to it for ping-pong loops).
+pub fn loop_time(at: Time, animation_loop: &fm::element_view::Loop) -> Time {
+    let (start, end) = (animation_loop.start, animation_loop.end);
+    let span = end - start;
+    if at.0 <= end || span <= 0 {
+        return at;
+    }
+    Time(if animation_loop.ping_pong {
+        let phase = (at.0 - start) % (2 * span);
+        if phase <= span {
+            start + phase
+        } else {
+            end - (phase - span)
+        }
+    } else {
+        start + (at.0 - start) % span
+    })
+}
+
+// End time of playback running the loop given number of times (a ping-pong
+// loop runs there and back).
+pub fn loop_end(animation_loop: &fm::element_view::Loop, count: u32) -> Time {
+    let span = animation_loop.end - animation_loop.start;
+    let period = if animation_loop.ping_pong {
+        2 * span
+    } else {
+        span
+    };
+    Time(animation_loop.start + period * count.max(1) as i64)
+}
+
+// Duration of static model renders.
+pub const DEFAULT_TURNTABLE_DURATION: Time = Time(10 * NANOS_PER_SEC);
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct TurntableParams {
+    #[structopt(
+        help = "Frames per second of turntable render",
+        long,
+        default_value = "30"
+    )]
+    pub fps: f64,
+
+    #[structopt(
+        help = "Duration of turntable render (animation span if omitted)",
+        long
+    )]
+    pub duration: Option<Time>,
+
+    #[structopt(
+        help = "Camera orbit angle over turntable render",
+        long,
+        default_value = "360",
+        allow_hyphen_values = true
+    )]
+    pub camera_orbit_degrees: f64,
+
+    #[structopt(
+        help = "Interpolation of element states between their times",
+        long,
+        default_value = DEFAULT_INTERPOLATION
+    )]
+    pub interpolation: Interpolation,
+}
+
+impl Default for TurntableParams {
+    fn default() -> Self {
+        Self {
+            fps: 30.0,
+            duration: None,
+            camera_orbit_degrees: 360.0,
+            interpolation: Interpolation::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurntableFrame {
+    // Time of element states to render.
+    pub time: Time,
+    // Camera angle around Z axis from its initial position.
+    pub camera_angle: f64,
+}
+
+impl TurntableParams {
+    pub fn validate(&self) -> Result<()> {
+        let problem = if !(self.fps > 0.0 && self.fps.is_finite()) {
+            "--fps should be positive"
+        } else if matches!(self.duration, Some(d) if d.0 <= 0) {
+            "--duration should be positive"
+        } else if !self.camera_orbit_degrees.is_finite() {
+            "--camera-orbit-degrees should be finite"
+        } else {
+            return Ok(());
+        };
+        Err(Error::new(BadOperation, problem.to_string()))
+    }
+
+    // Frames play the animation from its start as the viewer does (holding
+    // the last state if the render lasts longer), while the camera orbits
+    // uniformly over the whole render.
+    pub fn frames(&self, range: Option<(Time, Time)>) -> Vec<TurntableFrame> {
+        let (start, span) =
+            range.map_or((Time(0), Time(0)), |(from, to)| (from, to - from));
+        let duration = match self.duration {
+            Some(duration) => duration.0,
+            None if span.0 > 0 => span.0,
+            None => DEFAULT_TURNTABLE_DURATION.0,
+        };
+
+        let period = NANOS_PER_SEC as f64 / self.fps;
+        let num_frames = (duration as f64 / period).ceil().max(1.0) as usize;
+        let orbit = self.camera_orbit_degrees.to_radians();
+        (0..num_frames)
+            .map(|i| {
+                let elapsed = i as f64 * period;
+                TurntableFrame {
+                    time: start + Time(elapsed.round() as i64),
+                    camera_angle: orbit * elapsed / duration as f64,
+                }
+            })
+            .collect()
+    }
+}
@@ -1,7 +1,7 @@
 use std::error::Error as StdError;
 use std::fmt::Debug;
-use std::io::{stdin, stdout};
-use std::path::PathBuf;
+use std::io::{self, stdin, stdout};
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
 use std::str::FromStr;
 
@@ -12,6 +12,7 @@ use crate::defs::{Error, ErrorKind::*, Result};
 use crate::fm;
 use crate::util::fs;
 
+#[derive(Clone, Debug)]
 pub struct Array<T: FromStr, const N: usize>(pub [T; N]);
 
 impl<T: Debug + Default + FromStr, const N: usize> FromStr for Array<T, N> {
@@ -65,20 +66,77 @@ where
     Ok((s[..pos].parse()?, s[pos + 1..].parse()?))
 }
 
+// External images of input .fm files are left unresolved unless asked,
+// since their locations come from (possibly untrusted) files.
+#[derive(Clone, Copy, Debug, Default, StructOpt)]
+pub struct ImageParams {
+    #[structopt(
+        name = "fm-resolve-images",
+        help = "Load external images of input .fm files (from paths relative \
+                to their directories)",
+        long
+    )]
+    pub resolve_images: bool,
+
+    #[structopt(
+        name = "fm-any-image-locations",
+        help = "Load external images from absolute paths, parent \
+                directories and URLs as well",
+        long,
+        requires = "fm-resolve-images"
+    )]
+    pub any_image_locations: bool,
+}
+
+impl ImageParams {
+    // Resolver of images for input at the path (STDIN if None).
+    pub fn resolver(
+        &self,
+        path: Option<&Path>,
+    ) -> Option<fm::FileImageResolver> {
+        self.resolve_images.then(|| fm::FileImageResolver {
+            base: path
+                .and_then(Path::parent)
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+            unrestricted: self.any_image_locations,
+        })
+    }
+}
+
+fn new_fm_reader<R: io::Read + 'static>(
+    inner: R,
+    resolver: Option<fm::FileImageResolver>,
+    limits: fm::ReaderLimits,
+) -> Result<Box<dyn fm::Read>> {
+    let mut reader = fm::Reader::new(inner)?;
+    reader.set_limits(limits);
+    if let Some(resolver) = resolver {
+        reader.set_image_resolver(Box::new(resolver));
+    }
+    Ok(Box::new(reader) as Box<dyn fm::Read>)
+}
+
 #[derive(StructOpt)]
 pub struct FmInput {
     #[structopt(help = "Input .fm file (STDIN if omitted)", name = "in-file")]
     pub path: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub images: ImageParams,
+
+    #[structopt(flatten)]
+    pub limits: fm::ReaderLimits,
 }
 
 impl FmInput {
     pub fn get(&self) -> Result<Box<dyn fm::Read>> {
+        let resolver = self.images.resolver(self.path.as_deref());
         if let Some(path) = &self.path {
-            let reader = fm::Reader::new(fs::open_file(path)?)?;
-            Ok(Box::new(reader) as Box<dyn fm::Read>)
+            let file = fs::open_input(path)?;
+            new_fm_reader(file, resolver, self.limits)
         } else {
-            let reader = fm::Reader::new(stdin())?;
-            Ok(Box::new(reader) as Box<dyn fm::Read>)
+            new_fm_reader(stdin(), resolver, self.limits)
         }
     }
 }
@@ -90,18 +148,25 @@ pub struct FmInputs {
         name = "in-files"
     )]
     pub paths: Vec<PathBuf>,
+
+    #[structopt(flatten)]
+    pub images: ImageParams,
+
+    #[structopt(flatten)]
+    pub limits: fm::ReaderLimits,
 }
 
 impl FmInputs {
     pub fn get(&self) -> Result<Vec<Box<dyn fm::Read>>> {
         let mut readers = Vec::<Box<dyn fm::Read>>::new();
         for path in &self.paths {
-            let file = fs::open_file(path)?;
-            readers.push(Box::new(fm::Reader::new(file)?));
+            let file = fs::open_input(path)?;
+            let resolver = self.images.resolver(Some(path));
+            readers.push(new_fm_reader(file, resolver, self.limits)?);
         }
         if readers.is_empty() {
-            let reader = fm::Reader::new(stdin())?;
-            readers.push(Box::new(reader) as Box<dyn fm::Read>);
+            let resolver = self.images.resolver(None);
+            readers.push(new_fm_reader(stdin(), resolver, self.limits)?);
         }
         Ok(readers)
     }
@@ -123,16 +188,37 @@ pub struct FmOutput {
 impl FmOutput {
     pub fn get(&self) -> Result<Box<dyn fm::Write>> {
         if let Some(path) = &self.path {
-            let writer =
-                fm::Writer::new(fs::create_file(path)?, &self.fm_params)?;
+            let mut writer =
+                fm::Writer::new(fs::create_output(path)?, &self.fm_params)?;
+            if let Some(dir) = path.parent() {
+                writer.set_image_base(dir);
+            }
             Ok(Box::new(writer) as Box<dyn fm::Write>)
         } else {
-            let writer = fm::Writer::new(stdout(), &self.fm_params)?;
+            // Skip compression when piping into another command.
+            let mut params = self.fm_params.clone();
+            if params.compression == fm::Compression::Auto && is_stdout_pipe() {
+                params.compression = fm::Compression::None;
+            }
+            let writer = fm::Writer::new(stdout(), &params)?;
             Ok(Box::new(writer) as Box<dyn fm::Write>)
         }
     }
 }
 
+#[cfg(unix)]
+fn is_stdout_pipe() -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata("/dev/stdout")
+        .map(|m| m.file_type().is_fifo())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_stdout_pipe() -> bool {
+    false
+}
+
 #[macro_export]
 macro_rules! define_raw_input {
     ($name: ident, $ext: expr) => {
@@ -147,7 +233,7 @@ macro_rules! define_raw_input {
         impl $name {
             pub fn get(&self) -> Result<Box<dyn io::Read>> {
                 Ok(if let Some(path) = &self.path {
-                    Box::new(fs::open_file(path)?) as Box<dyn io::Read>
+                    fs::open_input(path)?
                 } else {
                     Box::new(std::io::stdin()) as Box<dyn io::Read>
                 })
@@ -173,7 +259,7 @@ macro_rules! define_raw_output {
             pub fn get(&self) -> Result<Box<dyn io::Write>> {
                 Ok(if let Some(path) = &self.path {
                     let writer = std::io::BufWriter::new(
-                        base::util::fs::create_file(path)?);
+                        base::util::fs::create_output(path)?);
                     Box::new(writer) as Box<dyn io::Write>
                 } else {
                     Box::new(std::io::stdout()) as Box<dyn io::Write>
@@ -1,7 +1,17 @@
-use std::fs::{read, read_to_string, File, write};
-use std::path::Path;
+use std::fs::{
+    read, read_dir, read_to_string, remove_file, rename, write, File,
+};
+use std::io;
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::warn;
 
 use crate::defs::{IntoResult, Result};
+use crate::fm::ReadSeek;
 
 pub fn open_file<P: AsRef<Path>>(path: P) -> Result<File> {
     let path = path.as_ref();
@@ -25,6 +35,264 @@ pub fn create_file<P: AsRef<Path>>(path: P) -> Result<File> {
     })
 }
 
+// Returns URL if the path designates a remote location.
+pub fn remote_url<P: AsRef<Path>>(path: P) -> Option<String> {
+    let path = path.as_ref().to_str()?;
+    ["s3://", "http://", "https://"]
+        .iter()
+        .any(|scheme| path.starts_with(scheme))
+        .then(|| path.to_string())
+}
+
+// Makes path absolute, removing '.' and '..' components lexically.
+fn normalize_path(path: &Path) -> Option<PathBuf> {
+    let mut normal = PathBuf::new();
+    for component in std::env::current_dir().ok()?.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    Some(normal)
+}
+
+// Returns path relative to the base directory (without resolving symbolic
+// links), None if any of them is remote or they have different roots.
+pub fn relative_path<P: AsRef<Path>, B: AsRef<Path>>(
+    path: P,
+    base: B,
+) -> Option<PathBuf> {
+    if remote_url(&path).is_some() || remote_url(&base).is_some() {
+        return None;
+    }
+
+    let path = normalize_path(path.as_ref())?;
+    let base = normalize_path(base.as_ref())?;
+    let mut path_comps = path.components().peekable();
+    let mut base_comps = base.components().peekable();
+    let mut common = 0;
+    while path_comps.peek().is_some() && path_comps.peek() == base_comps.peek()
+    {
+        path_comps.next();
+        base_comps.next();
+        common += 1;
+    }
+    if common == 0 {
+        return None;
+    }
+
+    let mut relative: PathBuf =
+        base_comps.map(|_| Component::ParentDir).collect();
+    relative.extend(path_comps);
+    Some(relative)
+}
+
+pub fn open_input<P: AsRef<Path>>(path: P) -> Result<Box<dyn io::Read>> {
+    if let Some(url) = remote_url(&path) {
+        #[cfg(feature = "remote")]
+        return crate::util::remote::open_url(&url);
+        #[cfg(not(feature = "remote"))]
+        return Err(remote_unsupported_err(&url));
+    }
+    Ok(Box::new(open_file(path)?))
+}
+
+// Opens input for random access, remote ones are read by range requests.
+pub fn open_seekable_input<P: AsRef<Path>>(
+    path: P,
+) -> Result<Box<dyn ReadSeek>> {
+    if let Some(url) = remote_url(&path) {
+        #[cfg(feature = "remote")]
+        return Ok(Box::new(crate::util::remote::RemoteFile::open(&url)?));
+        #[cfg(not(feature = "remote"))]
+        return Err(remote_unsupported_err(&url));
+    }
+    Ok(Box::new(open_file(path)?))
+}
+
+// Naming of temporary files which local outputs are written into until
+// committed, so that failed runs don't leave truncated outputs behind.
+// Names include the process ID, so that concurrent runs don't clash.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TempOutputs {
+    // Outputs are written in place.
+    Disabled,
+    // Into hidden .<name>.<pid>.tmp files.
+    Hidden,
+    // Into <name>.<pid>.part files.
+    Part,
+}
+
+// Age of temporary files of other processes to consider them left by
+// interrupted runs (rather than being written by concurrent ones).
+const STALE_TEMP_OUTPUT_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct PendingOutputs {
+    naming: TempOutputs,
+    // Temporary files along with their destinations.
+    files: Vec<(PathBuf, PathBuf)>,
+}
+
+static PENDING_OUTPUTS: Mutex<PendingOutputs> = Mutex::new(PendingOutputs {
+    naming: TempOutputs::Disabled,
+    files: Vec::new(),
+});
+
+pub fn set_temp_outputs(naming: TempOutputs) {
+    PENDING_OUTPUTS.lock().unwrap().naming = naming;
+}
+
+// Prefix and suffix of temporary file name around the process ID.
+fn temp_output_affixes(
+    name: &str,
+    naming: TempOutputs,
+) -> Option<(String, &'static str)> {
+    match naming {
+        TempOutputs::Disabled => None,
+        TempOutputs::Hidden => Some((format!(".{}.", name), ".tmp")),
+        TempOutputs::Part => Some((format!("{}.", name), ".part")),
+    }
+}
+
+fn temp_output_path(path: &Path, naming: TempOutputs) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let (prefix, suffix) = temp_output_affixes(name, naming)?;
+    let temp = format!("{}{}{}", prefix, process::id(), suffix);
+    Some(path.with_file_name(temp))
+}
+
+// Removes temporary files left for the output by interrupted runs, those
+// of other processes are removed once they are old enough.
+fn remove_stale_temp_outputs(path: &Path) {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return,
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let pid = process::id().to_string();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let temp_pid =
+            match file_name.to_str().and_then(|n| temp_output_pid(n, name)) {
+                Some(temp_pid) => temp_pid,
+                None => continue,
+            };
+        let stale = temp_pid == pid
+            || entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .is_some_and(|age| age >= STALE_TEMP_OUTPUT_AGE);
+        let temp = entry.path();
+        if stale && temp.is_file() && remove_file(&temp).is_ok() {
+            warn!("removed stale temporary file '{}'", temp.display());
+        }
+    }
+}
+
+// Process ID of temporary file name for the output name (if it's one).
+fn temp_output_pid<'a>(file_name: &'a str, name: &str) -> Option<&'a str> {
+    [TempOutputs::Hidden, TempOutputs::Part]
+        .into_iter()
+        .find_map(|naming| {
+            let (prefix, suffix) = temp_output_affixes(name, naming)?;
+            let pid = file_name.strip_prefix(&prefix)?.strip_suffix(suffix)?;
+            let digits = pid.bytes().all(|b| b.is_ascii_digit());
+            (!pid.is_empty() && digits).then_some(pid)
+        })
+}
+
+pub fn create_output<P: AsRef<Path>>(path: P) -> Result<Box<dyn io::Write>> {
+    if let Some(url) = remote_url(&path) {
+        #[cfg(feature = "remote")]
+        return Ok(Box::new(crate::util::remote::Upload::new(url)?));
+        #[cfg(not(feature = "remote"))]
+        return Err(remote_unsupported_err(&url));
+    }
+
+    let path = path.as_ref();
+    let mut pending = PENDING_OUTPUTS.lock().unwrap();
+    let temp = match temp_output_path(path, pending.naming) {
+        Some(temp) => temp,
+        None => return Ok(Box::new(create_file(path)?)),
+    };
+    if !pending.files.iter().any(|(t, _)| *t == temp) {
+        remove_stale_temp_outputs(path);
+        pending.files.push((temp.clone(), path.to_owned()));
+    }
+    Ok(Box::new(create_file(temp)?))
+}
+
+// Writes data into output (see create_output()).
+pub fn write_output<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    create_output(path)?
+        .write_all(data)
+        .into_result(|| format!("failed to write file '{}'", path.display()))
+}
+
+// Moves temporary files of outputs into place once their writers are gone.
+pub fn commit_outputs() -> Result<()> {
+    #[cfg(feature = "remote")]
+    if let Err(err) = crate::util::remote::finish_uploads() {
+        discard_outputs();
+        return Err(err);
+    }
+
+    let files = std::mem::take(&mut PENDING_OUTPUTS.lock().unwrap().files);
+    for (i, (temp, path)) in files.iter().enumerate() {
+        let res = open_file(temp)
+            .and_then(|file| {
+                file.sync_all().into_result(|| {
+                    format!("failed to sync file '{}'", temp.display())
+                })
+            })
+            .and_then(|_| {
+                rename(temp, path).into_result(|| {
+                    format!("failed to move output into '{}'", path.display())
+                })
+            });
+        if res.is_err() {
+            remove_temp_outputs(&files[i..]);
+            return res;
+        }
+    }
+    Ok(())
+}
+
+// Removes temporary files of outputs (e.g. after failure).
+pub fn discard_outputs() {
+    #[cfg(feature = "remote")]
+    crate::util::remote::discard_uploads();
+    let files = std::mem::take(&mut PENDING_OUTPUTS.lock().unwrap().files);
+    remove_temp_outputs(&files);
+}
+
+fn remove_temp_outputs(files: &[(PathBuf, PathBuf)]) {
+    for (temp, _) in files {
+        let _ = remove_file(temp);
+    }
+}
+
+#[cfg(not(feature = "remote"))]
+fn remote_unsupported_err(url: &str) -> crate::defs::Error {
+    use crate::defs::{Error, ErrorKind::*};
+    let desc = format!("remote location '{}' requires 'remote' feature", url);
+    Error::new(UnsupportedFeature, desc)
+}
+
 pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
     let path = path.as_ref();
     read(path).into_result(|| {
@@ -57,3 +325,51 @@ pub fn write_file<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<()> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_relative_path() {
+        let rel = |path, base| relative_path(path, base).unwrap();
+        assert_eq!(rel("out/images/a.png", "out"), Path::new("images/a.png"));
+        assert_eq!(rel("/a/b/c.png", "/a/./b"), Path::new("c.png"));
+        assert_eq!(rel("/a/c/d.png", "/a/b"), Path::new("../c/d.png"));
+        assert_eq!(rel("a.png", ""), Path::new("a.png"));
+        assert_eq!(rel("/a/b/../c.png", "/a"), Path::new("c.png"));
+        assert!(relative_path("https://host/a.png", "/a").is_none());
+    }
+
+    #[test]
+    fn test_remove_stale_temp_outputs() {
+        let dir = std::env::temp_dir()
+            .join(format!("temp-outputs-test-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let own = temp_output_path(&dir.join("out.fm"), TempOutputs::Hidden);
+        let names = [
+            ".out.fm.1.tmp",   // Foreign, in progress.
+            "out.fm.2.part",   // Foreign, left long ago.
+            ".out.fm.x.tmp",   // Not a temporary file.
+            ".other.fm.3.tmp", // Of other output.
+        ];
+        for path in names.iter().map(|n| dir.join(n)).chain(own.clone()) {
+            write(&path, b"").unwrap();
+        }
+        let old = SystemTime::now() - 2 * STALE_TEMP_OUTPUT_AGE;
+        File::options()
+            .write(true)
+            .open(dir.join(names[1]))
+            .and_then(|f| f.set_modified(old))
+            .unwrap();
+
+        remove_stale_temp_outputs(&dir.join("out.fm"));
+        assert!(!own.unwrap().exists());
+        let exists: Vec<_> =
+            names.iter().map(|n| dir.join(n).exists()).collect();
+        assert_eq!(exists, [true, false, true, true]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
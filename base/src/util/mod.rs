@@ -1,4 +1,9 @@
 pub mod fs;
+#[cfg(feature = "remote")]
+pub mod remote;
+#[cfg(feature = "remote")]
+pub mod s3;
 #[macro_use]
 pub mod test;
 pub mod cli;
+pub mod upload;
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{self, Read as _, Seek, SeekFrom};
+use std::mem;
+use std::sync::Mutex;
+
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+use crate::util::s3;
+
+// Size of ranges requested by seekable remote inputs.
+const RANGE_SIZE: u64 = 1 << 20;
+
+fn check_url(url: &str) -> Result<()> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Ok(());
+    }
+    let desc = format!("unsupported remote location '{}'", url);
+    Err(Error::new(UnsupportedFeature, desc))
+}
+
+// Only http(s) is spoken, so s3:// locations are presigned for the request
+// method (right before the request, as presigned URLs expire).
+fn request_url(url: &str, method: &str) -> Result<String> {
+    if url.starts_with("s3://") {
+        return s3::presign(url, method);
+    }
+    check_url(url)?;
+    Ok(url.to_string())
+}
+
+fn request_err(url: &str, err: ureq::Error) -> Error {
+    let desc = format!("failed to request '{}'", url);
+    Error::with_source(IoError, desc, err)
+}
+
+pub fn open_url(url: &str) -> Result<Box<dyn io::Read>> {
+    let resp = ureq::get(&request_url(url, "GET")?)
+        .call()
+        .map_err(|e| request_err(url, e))?;
+    Ok(Box::new(resp.into_reader()))
+}
+
+pub fn post_json(url: &str, data: &[u8]) -> Result<()> {
+    check_url(url)?;
+    ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_bytes(data)
+        .map_err(|e| {
+            let desc = format!("failed to post to '{}'", url);
+            Error::with_source(IoError, desc, e)
+        })?;
+    Ok(())
+}
+
+// Remote input read by range requests, so that indexed .fm files can be
+// accessed without downloading them.
+pub struct RemoteFile {
+    url: String,
+    len: u64,
+    pos: u64,
+    // Data of the last requested range along with its offset.
+    range: Vec<u8>,
+    range_start: u64,
+}
+
+impl RemoteFile {
+    pub fn open(url: &str) -> Result<Self> {
+        let resp = ureq::head(&request_url(url, "HEAD")?)
+            .call()
+            .map_err(|e| request_err(url, e))?;
+        let len = resp
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| {
+                let desc = format!("unknown size of '{}'", url);
+                Error::new(UnsupportedFeature, desc)
+            })?;
+        Ok(Self {
+            url: url.to_string(),
+            len,
+            pos: 0,
+            range: Vec::new(),
+            range_start: 0,
+        })
+    }
+
+    fn fetch(&mut self) -> io::Result<()> {
+        let end = (self.pos + RANGE_SIZE).min(self.len);
+        let url = request_url(&self.url, "GET").map_err(io::Error::other)?;
+        let resp = ureq::get(&url)
+            .set("Range", &format!("bytes={}-{}", self.pos, end - 1))
+            .call()
+            .map_err(io::Error::other)?;
+        if resp.status() != 206 {
+            let desc = format!("no range requests for '{}'", self.url);
+            return Err(io::Error::new(io::ErrorKind::Unsupported, desc));
+        }
+
+        self.range.clear();
+        self.range_start = self.pos;
+        resp.into_reader()
+            .take(end - self.pos)
+            .read_to_end(&mut self.range)?;
+        Ok(())
+    }
+}
+
+impl io::Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let range_end = self.range_start + self.range.len() as u64;
+        if !(self.range_start..range_end).contains(&self.pos) {
+            self.fetch()?;
+            if self.range.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        let offset = (self.pos - self.range_start) as usize;
+        let num = buf.len().min(self.range.len() - offset);
+        buf[..num].copy_from_slice(&self.range[offset..offset + num]);
+        self.pos += num as u64;
+        Ok(num)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i128,
+            SeekFrom::End(delta) => self.len as i128 + delta as i128,
+            SeekFrom::Current(delta) => self.pos as i128 + delta as i128,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+// Uploads whose writers were dropped before finishing.
+static PENDING_UPLOADS: Mutex<Vec<(String, File)>> = Mutex::new(Vec::new());
+
+// Spools written data into a temporary file (rather than memory) and
+// uploads it with a PUT request on finish, with the known length that
+// presigned S3 URLs require.
+pub struct Upload {
+    url: String,
+    file: Option<File>,
+}
+
+impl Upload {
+    pub fn new(url: String) -> Result<Self> {
+        request_url(&url, "PUT")?; // Fails early without credentials.
+        let file = tempfile::tempfile().into_result(|| {
+            format!("failed to create spool file for '{}'", url)
+        })?;
+        Ok(Self {
+            url,
+            file: Some(file),
+        })
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        match self.file.take() {
+            Some(file) => upload(&self.url, file),
+            None => Ok(()),
+        }
+    }
+}
+
+fn upload(url: &str, mut file: File) -> Result<()> {
+    let desc = || format!("failed to upload '{}'", url);
+    let len = file.seek(SeekFrom::End(0)).into_result(desc)?;
+    file.rewind().into_result(desc)?;
+    ureq::put(&request_url(url, "PUT")?)
+        .set("Content-Length", &len.to_string())
+        .send(file)
+        .map_err(|e| Error::with_source(IoError, desc(), e))?;
+    Ok(())
+}
+
+impl io::Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.as_mut() {
+            Some(file) => file.write(buf),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Unfinished uploads are left to finish_uploads(), so that their failures
+// aren't lost.
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let url = mem::take(&mut self.url);
+            PENDING_UPLOADS.lock().unwrap().push((url, file));
+        }
+    }
+}
+
+// Uploads outputs whose writers were dropped without finishing.
+pub fn finish_uploads() -> Result<()> {
+    let uploads = mem::take(&mut *PENDING_UPLOADS.lock().unwrap());
+    for (url, file) in uploads {
+        upload(&url, file)?;
+    }
+    Ok(())
+}
+
+// Drops unfinished uploads (e.g. after failure).
+pub fn discard_uploads() {
+    PENDING_UPLOADS.lock().unwrap().clear();
+}
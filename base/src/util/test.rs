@@ -97,6 +97,25 @@ pub fn new_element_view_state_rec(
     }
 }
 
+pub fn new_element_texture_rec(texture: fm::ElementTexture) -> fm::Record {
+    use fm::record::Type;
+    fm::Record {
+        r#type: Some(Type::ElementTexture(texture)),
+    }
+}
+
+pub fn new_scan_rec(scan: fm::Scan) -> fm::Record {
+    fm::Record {
+        r#type: Some(fm::record::Type::Scan(scan)),
+    }
+}
+
+pub fn new_scan_frame_rec(frame: fm::ScanFrame) -> fm::Record {
+    fm::Record {
+        r#type: Some(fm::record::Type::ScanFrame(frame)),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn new_ev_face(
     vertex1: u32,
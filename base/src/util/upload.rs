@@ -0,0 +1,459 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::defs::{Error, ErrorKind::*, IntoResult, Result};
+
+pub const DEFAULT_CHUNK_SIZE: u64 = 4 << 20;
+
+// Describes uploaded data, so that the receiving side can verify chunks as
+// they arrive. Digests are hex-encoded SHA-256, the total one is of
+// concatenated chunk digests (so that it's checked without the data).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadManifest {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunk_digests: Vec<String>,
+    pub digest: String,
+}
+
+impl UploadManifest {
+    pub fn new<R: Read>(mut inner: R, chunk_size: u64) -> Result<Self> {
+        if chunk_size == 0 {
+            let desc = "upload chunk size should be positive".to_string();
+            return Err(Error::new(BadOperation, desc));
+        }
+
+        let mut size = 0;
+        let mut chunk_digests = Vec::new();
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            (&mut inner)
+                .take(chunk_size)
+                .read_to_end(&mut buf)
+                .into_result(|| "failed to read uploaded data".to_string())?;
+            if buf.is_empty() {
+                break;
+            }
+            size += buf.len() as u64;
+            chunk_digests.push(hex_digest(&buf));
+        }
+
+        Ok(UploadManifest {
+            size,
+            chunk_size,
+            digest: total_digest(&chunk_digests),
+            chunk_digests,
+        })
+    }
+
+    // Checks consistency of manifests received from the other side.
+    pub fn check(&self) -> Result<()> {
+        let num_chunks = if self.chunk_size > 0 {
+            self.size.div_ceil(self.chunk_size) as usize
+        } else {
+            usize::MAX
+        };
+        if num_chunks != self.chunk_digests.len()
+            || total_digest(&self.chunk_digests) != self.digest
+        {
+            let desc = "inconsistent upload manifest".to_string();
+            return Err(Error::new(MalformedData, desc));
+        }
+        Ok(())
+    }
+
+    // Offset to continue from given the amount of stored data, partially
+    // stored chunks are sent again.
+    pub fn resume_offset(&self, stored: u64) -> u64 {
+        if stored >= self.size {
+            self.size
+        } else {
+            stored - stored % self.chunk_size
+        }
+    }
+
+    fn chunk_len(&self, index: usize) -> u64 {
+        let offset = index as u64 * self.chunk_size;
+        self.chunk_size.min(self.size - offset)
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn total_digest(chunk_digests: &[String]) -> String {
+    hex_digest(chunk_digests.concat().as_bytes())
+}
+
+pub struct Chunk<'a> {
+    pub offset: u64,
+    pub data: &'a [u8],
+    pub digest: &'a str,
+}
+
+// Sending side, reads chunks starting from the negotiated offset.
+pub struct ChunkReader<R: Read + Seek> {
+    inner: R,
+    manifest: UploadManifest,
+    next_chunk: usize,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read + Seek> ChunkReader<R> {
+    pub fn new(mut inner: R, chunk_size: u64) -> Result<Self> {
+        let desc = || "failed to rewind uploaded data".to_string();
+        inner.seek(SeekFrom::Start(0)).into_result(desc)?;
+        let manifest = UploadManifest::new(&mut inner, chunk_size)?;
+        Ok(ChunkReader {
+            inner,
+            manifest,
+            next_chunk: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn manifest(&self) -> &UploadManifest {
+        &self.manifest
+    }
+
+    // Continues after data already stored by the receiving side.
+    pub fn resume(&mut self, stored: u64) -> Result<()> {
+        if stored > self.manifest.size {
+            return Err(Error::new(
+                InconsistentState,
+                format!(
+                    "{} bytes are stored out of {} uploaded",
+                    stored, self.manifest.size
+                ),
+            ));
+        }
+        let offset = self.manifest.resume_offset(stored);
+        self.next_chunk = offset.div_ceil(self.manifest.chunk_size) as usize;
+        Ok(())
+    }
+
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk<'_>>> {
+        let index = self.next_chunk;
+        if index == self.manifest.chunk_digests.len() {
+            return Ok(None);
+        }
+
+        let offset = index as u64 * self.manifest.chunk_size;
+        let len = self.manifest.chunk_len(index) as usize;
+        self.buffer.resize(len, 0);
+        self.inner
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.inner.read_exact(&mut self.buffer))
+            .into_result(|| "failed to read uploaded data".to_string())?;
+
+        let digest = &self.manifest.chunk_digests[index];
+        if hex_digest(&self.buffer) != *digest {
+            let desc = "uploaded data changed since manifest".to_string();
+            return Err(Error::new(InconsistentState, desc));
+        }
+
+        self.next_chunk += 1;
+        Ok(Some(Chunk {
+            offset,
+            data: &self.buffer,
+            digest,
+        }))
+    }
+}
+
+pub trait UploadTransport {
+    // Starts (or looks up) the upload, returns the amount of stored data.
+    fn negotiate(&mut self, manifest: &UploadManifest) -> Result<u64>;
+    fn send_chunk(&mut self, chunk: &Chunk) -> Result<()>;
+}
+
+// Sends all chunks, renegotiating the offset after transport (I/O) errors,
+// up to the given number of failures in a row.
+pub fn upload<R: Read + Seek>(
+    reader: &mut ChunkReader<R>,
+    transport: &mut dyn UploadTransport,
+    max_retries: usize,
+) -> Result<()> {
+    let mut num_failures = 0;
+    loop {
+        let err = match send_chunks(reader, transport, &mut num_failures) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if err.kind != IoError || num_failures == max_retries {
+            return Err(err);
+        }
+        num_failures += 1;
+    }
+}
+
+fn send_chunks<R: Read + Seek>(
+    reader: &mut ChunkReader<R>,
+    transport: &mut dyn UploadTransport,
+    num_failures: &mut usize,
+) -> Result<()> {
+    let stored = transport.negotiate(reader.manifest())?;
+    reader.resume(stored)?;
+    while let Some(chunk) = reader.next_chunk()? {
+        transport.send_chunk(&chunk)?;
+        *num_failures = 0;
+    }
+    Ok(())
+}
+
+// Receiving side, appends verified chunks to the inner writer. When resumed
+// the stored data should be truncated to the offset first.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    manifest: UploadManifest,
+    offset: u64,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(
+        inner: W,
+        manifest: UploadManifest,
+        stored: u64,
+    ) -> Result<Self> {
+        manifest.check()?;
+        Ok(ChunkWriter {
+            inner,
+            offset: manifest.resume_offset(stored),
+            manifest,
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.manifest.size
+    }
+
+    // Chunks preceding the offset are ignored, as they might be resent
+    // after their acknowledgement is lost.
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset < self.offset {
+            return Ok(());
+        }
+        if self.is_complete() {
+            let desc = format!("chunk at {} follows complete upload", offset);
+            return Err(Error::new(BadOperation, desc));
+        }
+        if offset != self.offset {
+            return Err(Error::new(
+                BadOperation,
+                format!(
+                    "chunk at {} doesn't continue upload at {}",
+                    offset, self.offset
+                ),
+            ));
+        }
+
+        let index = (offset / self.manifest.chunk_size) as usize;
+        if data.len() as u64 != self.manifest.chunk_len(index)
+            || hex_digest(data) != self.manifest.chunk_digests[index]
+        {
+            let desc = format!("corrupted upload chunk at {}", offset);
+            return Err(Error::new(MalformedData, desc));
+        }
+
+        self.inner
+            .write_all(data)
+            .and_then(|_| self.inner.flush())
+            .into_result(|| "failed to store upload chunk".to_string())?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Result<W> {
+        if !self.is_complete() {
+            return Err(Error::new(
+                InconsistentState,
+                format!(
+                    "upload is incomplete ({} of {} bytes)",
+                    self.offset, self.manifest.size
+                ),
+            ));
+        }
+        Ok(self.inner)
+    }
+}
+
+// Adapts a reader of known size which can't seek (e.g. an FFI callback).
+pub struct PositionedReader<F: FnMut(u64, &mut [u8]) -> io::Result<()>> {
+    read_at: F,
+    size: u64,
+    pos: u64,
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> io::Result<()>> PositionedReader<F> {
+    pub fn new(read_at: F, size: u64) -> Self {
+        PositionedReader {
+            read_at,
+            size,
+            pos: 0,
+        }
+    }
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> io::Result<()>> Read for PositionedReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.size.saturating_sub(self.pos) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        (self.read_at)(self.pos, &mut buf[..len])?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> io::Result<()>> Seek for PositionedReader<F> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid seek")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK_SIZE: u64 = 4;
+
+    fn new_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    fn new_writer(data: &[u8], stored: usize) -> ChunkWriter<Vec<u8>> {
+        let manifest = UploadManifest::new(data, CHUNK_SIZE).unwrap();
+        let offset = manifest.resume_offset(stored as u64) as usize;
+        ChunkWriter::new(data[..offset].to_vec(), manifest, stored as u64)
+            .unwrap()
+    }
+
+    // Stores chunks on the receiving side, failing on given offsets once.
+    struct TestTransport {
+        data: Vec<u8>,
+        writer: Option<ChunkWriter<Vec<u8>>>,
+        failures: Vec<u64>,
+        num_negotiations: usize,
+    }
+
+    impl UploadTransport for TestTransport {
+        fn negotiate(&mut self, manifest: &UploadManifest) -> Result<u64> {
+            self.num_negotiations += 1;
+            let stored = match self.writer.take() {
+                Some(writer) => writer.inner.len(),
+                None => 0,
+            };
+            self.writer = Some(new_writer(&self.data, stored));
+            assert_eq!(self.writer.as_ref().unwrap().manifest, *manifest);
+            Ok(stored as u64)
+        }
+
+        fn send_chunk(&mut self, chunk: &Chunk) -> Result<()> {
+            if let Some(pos) =
+                self.failures.iter().position(|&o| o == chunk.offset)
+            {
+                self.failures.remove(pos);
+                let desc = "connection reset".to_string();
+                return Err(Error::new(IoError, desc));
+            }
+            let writer = self.writer.as_mut().unwrap();
+            writer.write_chunk(chunk.offset, chunk.data)
+        }
+    }
+
+    #[test]
+    fn test_upload_resume() {
+        let data = new_data(10);
+        let mut transport = TestTransport {
+            data: data.clone(),
+            writer: None,
+            failures: vec![4, 8],
+            num_negotiations: 0,
+        };
+        let cursor = io::Cursor::new(data.clone());
+        let mut reader = ChunkReader::new(cursor, CHUNK_SIZE).unwrap();
+        upload(&mut reader, &mut transport, 1).unwrap();
+        assert_eq!(transport.num_negotiations, 3);
+
+        let writer = transport.writer.unwrap();
+        assert!(writer.is_complete());
+        assert_eq!(writer.into_inner().unwrap(), data);
+
+        // Partially stored chunks are sent again.
+        let mut writer = new_writer(&data, 6);
+        assert_eq!(writer.offset(), 4);
+        writer.write_chunk(4, &data[4..8]).unwrap();
+        writer.write_chunk(8, &data[8..]).unwrap();
+        assert_eq!(writer.into_inner().unwrap(), data);
+
+        let mut reader =
+            ChunkReader::new(io::Cursor::new(&data), CHUNK_SIZE).unwrap();
+        reader.resume(6).unwrap();
+        assert_eq!(reader.next_chunk().unwrap().unwrap().offset, 4);
+        assert!(reader.resume(11).is_err());
+    }
+
+    #[test]
+    fn test_upload_out_of_order_chunks() {
+        let data = new_data(10);
+        let mut writer = new_writer(&data, 0);
+        let err = writer.write_chunk(4, &data[4..8]).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+
+        writer.write_chunk(0, &data[..4]).unwrap();
+        writer.write_chunk(0, &data[..4]).unwrap(); // Resent, ignored.
+        assert_eq!(writer.offset(), 4);
+        let err = writer.write_chunk(8, &data[8..]).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+        assert!(!writer.is_complete());
+        assert_eq!(writer.into_inner().unwrap_err().kind, InconsistentState);
+    }
+
+    #[test]
+    fn test_upload_corrupted_chunk() {
+        let data = new_data(10);
+        let mut writer = new_writer(&data, 0);
+        let err = writer.write_chunk(0, &[0, 1, 2, 4]).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        let err = writer.write_chunk(0, &data[..3]).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        assert_eq!(writer.offset(), 0);
+
+        let mut manifest = UploadManifest::new(&data[..], CHUNK_SIZE).unwrap();
+        manifest.chunk_digests[1] = hex_digest(b"");
+        let err = ChunkWriter::new(Vec::new(), manifest, 0).err().unwrap();
+        assert_eq!(err.kind, MalformedData);
+    }
+
+    #[test]
+    fn test_upload_complete() {
+        let data = new_data(8);
+        let mut writer = new_writer(&data, 8);
+        assert!(writer.is_complete());
+        let err = writer.write_chunk(8, &[]).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+        writer.write_chunk(4, &data[4..]).unwrap(); // Resent, ignored.
+        assert_eq!(writer.into_inner().unwrap(), data);
+    }
+}
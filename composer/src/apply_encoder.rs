@@ -0,0 +1,205 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::{cli, fs};
+
+#[derive(StructOpt)]
+#[structopt(about = "Apply turntable encoder log to scan frames")]
+pub struct ApplyEncoderCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Input .csv file of time (e.g. '1.25s') and angle in degrees",
+        long
+    )]
+    csv: PathBuf,
+
+    #[structopt(
+        help = "Scan to apply to (all scans if omitted)",
+        long = "scan",
+        number_of_values = 1
+    )]
+    scans: Vec<String>,
+
+    #[structopt(
+        help = "Time of encoder log corresponding to zero scan time",
+        long,
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    time_offset: fm::Time,
+}
+
+impl ApplyEncoderCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        let csv = fs::read_file_to_string(&self.csv)?;
+        let log = EncoderLog::parse(&csv)?;
+        apply_encoder(
+            reader.as_mut(),
+            writer.as_mut(),
+            &log,
+            &self.scans,
+            self.time_offset,
+        )
+    }
+}
+
+// Time-ordered encoder samples with unwrapped angles (in radians).
+pub struct EncoderLog {
+    samples: Vec<(fm::Time, f64)>,
+}
+
+impl EncoderLog {
+    pub fn parse(csv: &str) -> Result<Self> {
+        let mut samples: Vec<(fm::Time, f64)> = Vec::new();
+
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let malformed_err = || {
+                let desc = format!("malformed encoder log line {}", i + 1);
+                Error::new(MalformedData, desc)
+            };
+
+            let mut cells = line.split(',').map(str::trim);
+            let (time, angle) = match (cells.next(), cells.next()) {
+                (Some(time), Some(angle)) => (time, angle),
+                _ => return Err(malformed_err()),
+            };
+            let time = match time.parse::<fm::Time>() {
+                Ok(time) => time,
+                Err(_) if samples.is_empty() => continue, // Header.
+                Err(_) => return Err(malformed_err()),
+            };
+            let mut angle = angle
+                .parse::<f64>()
+                .map_err(|_| malformed_err())?
+                .to_radians();
+
+            if let Some(&(last_time, last_angle)) = samples.last() {
+                if time <= last_time {
+                    let desc = format!(
+                        "non-monotonic encoder log time at line {}",
+                        i + 1
+                    );
+                    return Err(Error::new(MalformedData, desc));
+                }
+
+                // Encoder counter wraps around a full turn.
+                use std::f64::consts::{PI, TAU};
+                angle += ((last_angle - angle + PI) / TAU).floor() * TAU;
+            }
+            samples.push((time, angle));
+        }
+
+        if samples.is_empty() {
+            let desc = "empty encoder log".to_string();
+            return Err(Error::new(MalformedData, desc));
+        }
+        Ok(Self { samples })
+    }
+
+    pub fn angle_at(&self, time: fm::Time) -> Option<f64> {
+        let i = self.samples.partition_point(|&(t, _)| t < time);
+        let &(t1, a1) = self.samples.get(i)?;
+        if t1 == time {
+            return Some(a1);
+        }
+        let &(t0, a0) = self.samples.get(i.checked_sub(1)?)?;
+        let k = (time - t0).as_secs_f64() / (t1 - t0).as_secs_f64();
+        Some(a0 + (a1 - a0) * k)
+    }
+}
+
+pub fn apply_encoder(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    log: &EncoderLog,
+    scans: &[String],
+    time_offset: fm::Time,
+) -> Result<()> {
+    while let Some(mut rec) = reader.read_record()? {
+        if let Some(fm::record::Type::ScanFrame(frame)) = rec.r#type.as_mut() {
+            if scans.is_empty() || scans.contains(&frame.scan) {
+                let time = fm::Time(frame.time) + time_offset;
+                let radians = log.angle_at(time).ok_or_else(|| {
+                    let desc = format!(
+                        "encoder log doesn't cover frame of scan '{}' at {}",
+                        frame.scan,
+                        fm::Time(frame.time)
+                    );
+                    Error::new(BadOperation, desc)
+                })?;
+                frame.camera_angle = Some(fm::scan_frame::CameraAngle {
+                    radians: radians as f32,
+                });
+            }
+        }
+        writer.write_record(&rec)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    #[test]
+    fn test_apply_encoder() {
+        let log =
+            EncoderLog::parse("time,angle\n1s,350\n2s,10\n# Comment.\n3s,30\n")
+                .unwrap();
+
+        let frame = |scan: &str, time| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: scan.to_string(),
+                time,
+                ..Default::default()
+            })
+        };
+        let mut reader = create_reader_with_records(&[
+            frame("a", 500_000_000),
+            frame("a", 1_500_000_000),
+            frame("b", 0),
+        ]);
+        let mut writer = create_writer();
+        let scans = ["a".to_string()];
+        let offset = fm::Time(1_000_000_000);
+        apply_encoder(&mut reader, &mut writer, &log, &scans, offset).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let mut angles = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            let frame = record_variant!(ScanFrame, rec);
+            angles.push(frame.camera_angle.map(|a| a.radians.to_degrees()));
+        }
+
+        assert_eq!(angles.len(), 3);
+        assert!((angles[0].unwrap() - 360.0).abs() < 1e-3);
+        assert!((angles[1].unwrap() - 380.0).abs() < 1e-3);
+        assert!(angles[2].is_none());
+
+        let mut reader = create_reader_with_records(&[frame("a", 0)]);
+        let mut writer = create_writer();
+        let result =
+            apply_encoder(&mut reader, &mut writer, &log, &[], fm::Time(0));
+        assert!(result.is_err());
+    }
+}
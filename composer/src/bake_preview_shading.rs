@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+
+use image::{imageops, RgbImage};
+use log::{info, warn};
+use structopt::StructOpt;
+
+use crate::build_view::{downscale_texture, encode_image};
+use crate::mesh::Mesh;
+use crate::misc::sphere_directions;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{Point3, Vector3};
+use crate::preview::rasterize_triangle_rect;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+// Gray of elements having neither texture nor vertex colors.
+const BASE_COLOR: f64 = 220.0;
+
+// Pixels around texture islands which get shading of their neighbors, so
+// that filtering doesn't bring unshaded colors in.
+const TEXTURE_PADDING: usize = 2;
+
+// Limit of depth map bias (in pixels) for surfaces seen at grazing angles.
+const MAX_DEPTH_BIAS: f64 = 8.0;
+
+#[derive(StructOpt)]
+#[structopt(
+    about = "Bake ambient occlusion and directional light into .fm file \
+             element colors for quick previews"
+)]
+pub struct BakePreviewShadingCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Element to shade (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(flatten)]
+    params: BakePreviewShadingParams,
+}
+
+impl BakePreviewShadingCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        bake_preview_shading(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.elements,
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct BakePreviewShadingParams {
+    #[structopt(
+        help = "Number of directions to sample ambient occlusion from",
+        long,
+        default_value = "64"
+    )]
+    pub ao_directions: usize,
+
+    #[structopt(
+        help = "Size of depth maps rendered to sample ambient occlusion",
+        long,
+        default_value = "256"
+    )]
+    pub ao_resolution: u32,
+
+    #[structopt(
+        help = "Darkening of fully occluded vertices (within [0, 1])",
+        long,
+        default_value = "0.8"
+    )]
+    pub ao_strength: f64,
+
+    #[structopt(
+        allow_hyphen_values = true,
+        help = "Direction towards the light in form 'x,y,z'",
+        long,
+        default_value = "0.3,-1,0.5"
+    )]
+    pub light_direction: cli::Array<f64, 3>,
+
+    #[structopt(
+        help = "Share of directional light, the rest being ambient \
+                (within [0, 1])",
+        long,
+        default_value = "0.5"
+    )]
+    pub light_strength: f64,
+
+    #[structopt(
+        help = "Maximum width and height of shaded textures",
+        long,
+        default_value = "256"
+    )]
+    pub texture_size: u32,
+
+    #[structopt(
+        help = "Texture JPEG quality (1-100) of shaded textures",
+        long,
+        default_value = "80"
+    )]
+    pub texture_jpeg_quality: u8,
+}
+
+impl CheckParams for BakePreviewShadingParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.ao_directions > 0, || {
+            "--ao-directions should be positive".to_string()
+        });
+        check.require(self.ao_resolution > 0, || {
+            "--ao-resolution should be positive".to_string()
+        });
+        check.require((0.0..=1.0).contains(&self.ao_strength), || {
+            "--ao-strength should be within [0, 1]".to_string()
+        });
+        check.require(self.light().norm() > 0.0, || {
+            "--light-direction should be non-zero".to_string()
+        });
+        check.require((0.0..=1.0).contains(&self.light_strength), || {
+            "--light-strength should be within [0, 1]".to_string()
+        });
+        check.require(self.texture_size > 0, || {
+            "--texture-size should be positive".to_string()
+        });
+        check.require((1..=100).contains(&self.texture_jpeg_quality), || {
+            "--texture-jpeg-quality should be within [1, 100]".to_string()
+        });
+    }
+}
+
+impl BakePreviewShadingParams {
+    fn light(&self) -> Vector3 {
+        Vector3::from(self.light_direction.0)
+    }
+}
+
+// Cosine-weighted share of directions from which vertices are seen, found
+// by comparing vertex depths with orthographic depth maps of the mesh.
+fn ambient_visibility(
+    mesh: &Mesh,
+    directions: &[Vector3],
+    resolution: u32,
+) -> Vec<f64> {
+    let mut visible = vec![0.0; mesh.vertices.len()];
+    let mut total = vec![0.0; mesh.vertices.len()];
+    if mesh.vertices.is_empty() {
+        return visible;
+    }
+
+    let center = mesh.vertices.iter().fold(Vector3::zeros(), |c, v| {
+        c + v.coords / mesh.vertices.len() as f64
+    });
+    let radius = mesh
+        .vertices
+        .iter()
+        .map(|v| (v.coords - center).norm())
+        .fold(f64::EPSILON, f64::max);
+    let scale = resolution as f64 / (2.0 * radius);
+
+    let mut depths = vec![0.0; (resolution * resolution) as usize];
+    for dir in directions {
+        let up = if dir.z.abs() < 0.9 {
+            Vector3::z()
+        } else {
+            Vector3::x()
+        };
+        let u = dir.cross(&up).normalize();
+        let v = dir.cross(&u);
+        let project = |p: &Point3| {
+            let p = p.coords - center;
+            (
+                p.dot(&u) * scale + resolution as f64 / 2.0,
+                p.dot(&v) * scale + resolution as f64 / 2.0,
+                -p.dot(dir),
+            )
+        };
+
+        depths.fill(f64::INFINITY);
+        for face in &mesh.faces {
+            let points = face.map(|i| project(&mesh.vertices[i]));
+            rasterize_triangle_rect(
+                points,
+                (resolution, resolution),
+                &mut depths,
+                |_, _, _| {},
+            );
+        }
+
+        for (i, vertex) in mesh.vertices.iter().enumerate() {
+            let weight = mesh.normals[i].dot(dir);
+            if weight <= 0.0 {
+                continue;
+            }
+            total[i] += weight;
+
+            let (x, y, depth) = project(vertex);
+            let (x, y) = (x as u32, y as u32);
+            let index = (y.min(resolution - 1) * resolution
+                + x.min(resolution - 1)) as usize;
+            // Surfaces slope within pixels, so vertices may be a bit
+            // behind them.
+            let slope = (1.0 - weight * weight).sqrt() / weight;
+            let bias = (1.0 + slope).min(MAX_DEPTH_BIAS) / scale;
+            if depth <= depths[index] + bias {
+                visible[i] += weight;
+            }
+        }
+    }
+
+    visible
+        .iter()
+        .zip(&total)
+        .map(|(v, t)| if *t > 0.0 { v / t } else { 1.0 })
+        .collect()
+}
+
+// Shading factors of vertices within [0, 1].
+fn vertex_shading(mesh: &Mesh, params: &BakePreviewShadingParams) -> Vec<f64> {
+    let directions = sphere_directions(params.ao_directions);
+    let visibility =
+        ambient_visibility(mesh, &directions, params.ao_resolution);
+    let light = params.light().normalize();
+
+    visibility
+        .iter()
+        .zip(&mesh.normals)
+        .map(|(visibility, normal)| {
+            let occlusion = 1.0 - params.ao_strength * (1.0 - visibility);
+            let diffuse = normal.dot(&light).max(0.0);
+            occlusion
+                * (1.0 - params.light_strength
+                    + params.light_strength * diffuse)
+        })
+        .collect()
+}
+
+fn shade_color(color: u32, shade: f64) -> u32 {
+    [16, 8, 0].iter().fold(0, |result, shift| {
+        let channel = ((color >> shift) & 0xFF) as f64 * shade;
+        result | (channel.round().clamp(0.0, 255.0) as u32) << shift
+    })
+}
+
+fn decode_texture(image: &fm::Image, element: &str) -> Result<RgbImage> {
+    let image = image::load_from_memory(&image.data).map_err(|e| {
+        let desc = format!("failed to decode texture of '{}'", element);
+        Error::with_source(ImageError, desc, e)
+    })?;
+    Ok(image.into_rgb8())
+}
+
+fn fit_texture(texture: RgbImage, size: u32) -> RgbImage {
+    let (width, height) = texture.dimensions();
+    let max = width.max(height);
+    if max <= size {
+        return texture;
+    }
+    imageops::resize(
+        &texture,
+        (width * size / max).max(1),
+        (height * size / max).max(1),
+        imageops::FilterType::Triangle,
+    )
+}
+
+// Multiplies texels by shading interpolated across faces in texture space.
+fn shade_texture(
+    texture: &mut RgbImage,
+    faces: &[([fm::Point2; 3], [f64; 3])],
+) {
+    let (width, height) = texture.dimensions();
+    let mut depths = vec![f64::INFINITY; (width * height) as usize];
+    let mut shading = vec![None; depths.len()];
+    for (points, shades) in faces {
+        let points = points.map(|p| {
+            (p.x as f64 * width as f64, p.y as f64 * height as f64, 0.0)
+        });
+        rasterize_triangle_rect(
+            points,
+            (width, height),
+            &mut depths,
+            |x, y, weights| {
+                let shade = (0..3).map(|i| weights[i] * shades[i]).sum();
+                shading[(y * width + x) as usize] = Some(shade);
+            },
+        );
+    }
+
+    for _ in 0..TEXTURE_PADDING {
+        let known = shading.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                if known[index].is_some() {
+                    continue;
+                }
+                let neighbors: Vec<f64> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .filter_map(|(dx, dy)| {
+                        let (x, y) = (x as i64 + dx, y as i64 + dy);
+                        if x < 0
+                            || y < 0
+                            || x >= width as i64
+                            || y >= height as i64
+                        {
+                            return None;
+                        }
+                        known[(y * width as i64 + x) as usize]
+                    })
+                    .collect();
+                if !neighbors.is_empty() {
+                    shading[index] = Some(
+                        neighbors.iter().sum::<f64>() / neighbors.len() as f64,
+                    );
+                }
+            }
+        }
+    }
+
+    for (pixel, shade) in texture.pixels_mut().zip(shading) {
+        if let Some(shade) = shade {
+            for channel in pixel.0.iter_mut() {
+                *channel =
+                    (*channel as f64 * shade).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+// Shades the texture and its atlas pages, returns false if the view has no
+// texture to shade.
+fn shade_view_textures(
+    view: &mut fm::ElementView,
+    mesh: &Mesh,
+    shading: &[f64],
+    params: &BakePreviewShadingParams,
+) -> Result<bool> {
+    let image_type = match &view.texture {
+        Some(texture) if !texture.data.is_empty() => texture.r#type(),
+        _ => return Ok(false),
+    };
+    if image_type == fm::image::Type::None {
+        warn!("texture of element '{}' has no type", view.element);
+        return Ok(true);
+    }
+
+    let mut pages = HashMap::<usize, Vec<_>>::new();
+    for (i, face) in view.faces.iter().enumerate() {
+        let corners = [face.texture1, face.texture2, face.texture3];
+        if corners
+            .iter()
+            .any(|&t| t == 0 || t as usize > view.texture_points.len())
+        {
+            continue;
+        }
+        let points = corners.map(|t| view.texture_points[t as usize - 1]);
+        let shades = mesh.faces[i].map(|v| shading[v]);
+        let page = view.face_pages.get(i).copied().unwrap_or_default();
+        pages
+            .entry(page as usize)
+            .or_default()
+            .push((points, shades));
+    }
+
+    let element = view.element.clone();
+    let mut images: Vec<&mut fm::Image> = view.texture.iter_mut().collect();
+    images.extend(view.texture_pages.iter_mut());
+    let mut first_page = None;
+    for (page, image) in images.into_iter().enumerate() {
+        let r#type = match image.r#type() {
+            fm::image::Type::None => image_type,
+            r#type => r#type,
+        };
+        let mut texture =
+            fit_texture(decode_texture(image, &element)?, params.texture_size);
+        shade_texture(&mut texture, pages.get(&page).map_or(&[][..], |f| f));
+        *image = encode_image(&texture, r#type, params.texture_jpeg_quality);
+        first_page.get_or_insert(texture);
+    }
+
+    if let Some(texture) = first_page {
+        let num_levels = view.texture_levels.len() as u32;
+        view.texture_levels = downscale_texture(&texture, num_levels)
+            .iter()
+            .map(|image| {
+                encode_image(image, image_type, params.texture_jpeg_quality)
+            })
+            .collect();
+    }
+    Ok(true)
+}
+
+// Vertex colors are shaded if present, otherwise textures are, elements
+// without both get shaded gray vertex colors.
+fn shade_view(
+    view: &mut fm::ElementView,
+    state: &fm::ElementViewState,
+    params: &BakePreviewShadingParams,
+) -> Result<()> {
+    let mesh = Mesh::from_element(view, state)?;
+    let shading = vertex_shading(&mesh, params);
+
+    if !view.vertex_colors.is_empty() {
+        if view.vertex_colors.len() != shading.len() {
+            let desc = format!(
+                "vertex colors of element '{}' mismatch its vertices",
+                view.element
+            );
+            return Err(Error::new(MalformedData, desc));
+        }
+        for (color, shade) in view.vertex_colors.iter_mut().zip(&shading) {
+            *color = shade_color(*color, *shade);
+        }
+        info!("shaded vertex colors of element '{}'", view.element);
+    } else if shade_view_textures(view, &mesh, &shading, params)? {
+        info!("shaded textures of element '{}'", view.element);
+    } else {
+        let gray = (BASE_COLOR as u32) * 0x010101;
+        view.vertex_colors =
+            shading.iter().map(|s| shade_color(gray, *s)).collect();
+        info!("added shaded vertex colors to element '{}'", view.element);
+    }
+    Ok(())
+}
+
+// Shading comes from the first state of each element.
+pub fn bake_preview_shading(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    elements: &[String],
+    params: &BakePreviewShadingParams,
+) -> Result<()> {
+    params.check()?;
+    let selected = |element: &str| {
+        elements.is_empty() || elements.iter().any(|e| e == element)
+    };
+
+    let mut records = Vec::new();
+    let mut views = HashMap::new();
+    let mut states = HashMap::new();
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementView(v)) if selected(&v.element) => {
+                views.insert(v.element.clone(), records.len());
+            }
+            Some(ElementViewState(s)) if !states.contains_key(&s.element) => {
+                states.insert(s.element.clone(), records.len());
+            }
+            _ => {}
+        }
+        records.push(rec);
+    }
+
+    for (element, &index) in &views {
+        let state = match states.get(element).map(|&i| &records[i].r#type) {
+            Some(Some(fm::record::Type::ElementViewState(s))) => s.clone(),
+            _ => {
+                let desc = format!("no state for element '{}'", element);
+                return Err(Error::new(InconsistentState, desc));
+            }
+        };
+        if let Some(fm::record::Type::ElementView(view)) =
+            &mut records[index].r#type
+        {
+            shade_view(view, &state, params)?;
+        }
+    }
+
+    for rec in records {
+        writer.write_record(&rec)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::Read as _;
+
+    const GRID: u32 = 5;
+
+    // Grid facing -Y with texture points matching vertex positions.
+    fn create_element() -> (fm::ElementView, fm::ElementViewState) {
+        let mut view = fm::ElementView {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+        let mut state = fm::ElementViewState {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+        for i in 0..GRID {
+            for j in 0..GRID {
+                let (x, z) = (i as f32 * 0.25 - 0.5, j as f32 * 0.25 - 0.5);
+                state.vertices.push(new_point3(x, 0.0, z));
+                state.normals.push(new_point3(0.0, -1.0, 0.0));
+                let (u, v) = (i as f32 / 4.0, j as f32 / 4.0);
+                view.texture_points.push(new_point2(u, v));
+            }
+        }
+        let index = |i, j| i * GRID + j + 1;
+        for i in 0..GRID - 1 {
+            for j in 0..GRID - 1 {
+                for [a, b, c] in [
+                    [index(i, j), index(i + 1, j), index(i, j + 1)],
+                    [index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)],
+                ] {
+                    view.faces.push(new_ev_face(a, b, c, a, b, c, a, b, c));
+                }
+            }
+        }
+        (view, state)
+    }
+
+    fn bake(view: fm::ElementView, args: &[&str]) -> fm::ElementView {
+        let (_, state) = create_element();
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(view),
+            new_element_view_state_rec(state.clone()),
+        ]);
+        let mut writer = create_writer();
+        let args = ["bake-preview-shading"].iter().chain(args);
+        let params = BakePreviewShadingParams::from_iter(args);
+        bake_preview_shading(&mut reader, &mut writer, &[], &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let view = reader.read_record().unwrap().unwrap();
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(
+            record_variant!(fm::record::Type::ElementViewState, rec),
+            state
+        );
+        record_variant!(fm::record::Type::ElementView, view)
+    }
+
+    #[test]
+    fn test_ambient_visibility() {
+        // Floor along a wall standing at x = 0.
+        let mut mesh = Mesh::default();
+        for i in 0..=8 {
+            for j in 0..=8 {
+                let (x, y) = (i as f64 / 8.0, j as f64 / 8.0);
+                mesh.vertices.push(Point3::new(x, y, 0.0));
+            }
+        }
+        for i in 0..8 {
+            for j in 0..8 {
+                let a = i * 9 + j;
+                mesh.faces.push([a, a + 9, a + 1]);
+                mesh.faces.push([a + 9, a + 10, a + 1]);
+            }
+        }
+        let n = mesh.vertices.len();
+        for [y, z] in [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]] {
+            mesh.vertices.push(Point3::new(0.0, y, z));
+        }
+        mesh.faces.extend([[n, n + 1, n + 2], [n, n + 2, n + 3]]);
+        mesh.set_area_weighted_normals();
+
+        let directions = sphere_directions(64);
+        let visibility = ambient_visibility(&mesh, &directions, 128);
+        let (near, far) = (visibility[9 + 4], visibility[8 * 9 + 4]);
+        assert!(near < 0.75, "{}", near);
+        assert!(far > near + 0.1, "{} {}", far, near);
+        assert!(visibility.iter().all(|v| (0.0..=1.0).contains(v)));
+
+        // Nothing occludes a lone floor.
+        mesh.vertices.truncate(n);
+        mesh.faces.truncate(mesh.faces.len() - 2);
+        mesh.set_area_weighted_normals();
+        let visibility = ambient_visibility(&mesh, &directions, 128);
+        assert!(visibility.iter().all(|v| *v > 0.95), "{:?}", visibility);
+    }
+
+    #[test]
+    fn test_bake_preview_shading() {
+        // Light from the side, so half of the light is ambient.
+        let args = ["--light-direction=1,0,0", "--ao-strength", "0"];
+
+        let (mut view, _) = create_element();
+        view.vertex_colors = vec![0x804020; (GRID * GRID) as usize];
+        let shaded = bake(view, &args);
+        assert_eq!(shaded.vertex_colors, [0x402010; 25]);
+
+        let (view, _) = create_element();
+        let shaded = bake(view, &args);
+        assert_eq!(shaded.vertex_colors, [0x6E6E6E; 25]);
+
+        let (mut view, _) = create_element();
+        let texture = RgbImage::from_pixel(8, 8, Rgb([200, 100, 50]));
+        view.texture = Some(encode_image(&texture, fm::image::Type::Png, 80));
+        view.texture_levels =
+            vec![encode_image(&texture, fm::image::Type::Png, 80)];
+        let shaded =
+            bake(view, &[&args[..], &["--texture-size", "4"]].concat());
+        assert!(shaded.vertex_colors.is_empty());
+        let texture =
+            decode_texture(shaded.texture.as_ref().unwrap(), "a").unwrap();
+        assert_eq!(texture.dimensions(), (4, 4));
+        assert!(texture.pixels().all(|p| *p == Rgb([100, 50, 25])));
+        assert_eq!(shaded.texture_levels.len(), 1);
+
+        // Frontal light with ambient occlusion of a flat grid.
+        let (mut view, _) = create_element();
+        view.vertex_colors = vec![0x808080; (GRID * GRID) as usize];
+        let args = ["--light-direction=0,-1,0", "--light-strength", "1"];
+        let shaded = bake(view, &args);
+        for color in shaded.vertex_colors {
+            let gray = color & 0xFF;
+            assert_eq!(color, gray * 0x010101);
+            assert!((0x78..=0x80).contains(&gray), "{:x}", color);
+        }
+    }
+
+    #[test]
+    fn test_check_params() {
+        let params = BakePreviewShadingParams::from_iter([
+            "bake-preview-shading",
+            "--light-direction=0,0,0",
+            "--ao-strength",
+            "2",
+        ]);
+        let err = params.check().unwrap_err();
+        assert!(err.description.contains("2 parameter problems"));
+    }
+}
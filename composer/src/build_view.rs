@@ -1,19 +1,42 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
 use image::codecs::jpeg::JpegEncoder;
 use image::codecs::png::{CompressionType, FilterType, PngEncoder};
-use image::ImageEncoder;
-use log::info;
+use image::{imageops, ImageEncoder, RgbImage};
+use indexmap::IndexMap;
+use log::{info, warn};
+use rayon::ThreadPool;
+use serde_json::json;
 use structopt::StructOpt;
+use tracing::info_span;
 use uuid::Uuid;
 
-use crate::mesh::Mesh;
+use crate::decimate::{TexturedDecimator, WedgeMesh};
+use crate::dry_run::{format_size, output_location, Plan};
+use crate::dual_contouring;
+use crate::mesh::{read_elements, Mesh};
+use crate::param_check::{CheckParams, ParamCheck};
 use crate::point_cloud::{build_frame_clouds, PointCloudParams, PointNormal};
 use crate::poisson;
-use crate::scan::{read_scans, ScanParams};
-use crate::texture::{TextureParams, TexturedMesh};
-use base::defs::{Error, ErrorKind::*, Result};
+use crate::preview::create_preview;
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::telemetry;
+use crate::texture::{
+    read_forbidden_seams, read_texture_labels_config, vertex_colors,
+    LabelTextureParams, TextureLabelsConfig, TextureParams, TexturedMesh,
+    Vector3,
+};
+use crate::threads::{create_thread_pool, parse_threads, ThreadParams, AUTO};
+use crate::tsdf;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
 use base::fm;
 use base::fm::record::Type::*;
-use base::util::cli;
+use base::util::{cli, fs};
+
+const MAX_TEXTURE_LEVELS: u32 = 8;
 
 #[derive(StructOpt)]
 #[structopt(about = "Build element view from scan .fm file")]
@@ -26,28 +49,84 @@ pub struct BuildViewCommand {
 
     #[structopt(flatten)]
     params: BuildViewParams,
+
+    #[structopt(
+        help = "Output chrome-tracing .json file with stage timings",
+        long
+    )]
+    profile: Option<PathBuf>,
+
+    #[structopt(help = "Print execution plan without running it", long)]
+    dry_run: bool,
 }
 
 impl BuildViewCommand {
     pub fn run(&self) -> Result<()> {
         let mut reader = self.input.get()?;
+        if self.dry_run {
+            let output = output_location(&self.output);
+            let plan = plan_build_view(reader.as_mut(), &output, &self.params)?;
+            plan.print();
+            return Ok(());
+        }
         let mut writer = self.output.get()?;
-
         build_view(reader.as_mut(), writer.as_mut(), &self.params)
     }
+
+    // Path of chrome-tracing profile, recorded by the global subscriber.
+    pub fn profile(&self) -> Option<&Path> {
+        self.profile.as_deref().filter(|_| !self.dry_run)
+    }
 }
 
-#[derive(StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 pub struct BuildViewParams {
     #[structopt(flatten)]
     pub scan: ScanParams,
 
+    #[structopt(flatten)]
+    pub threads: ThreadParams,
+
+    #[structopt(
+        help = "Number of threads for texturing (or auto to follow --threads)",
+        long = "texture-threads",
+        default_value = "auto",
+        parse(try_from_str = parse_threads)
+    )]
+    pub texture_threads: usize,
+
     #[structopt(flatten)]
     pub point_cloud: PointCloudParams,
 
+    #[structopt(
+        help = "Model .fm file with element to texture instead of \
+                reconstructing one (its first element is used)",
+        long
+    )]
+    pub mesh: Option<PathBuf>,
+
+    #[structopt(
+        help = "Surface reconstruction method (poisson, dc or tsdf)",
+        long,
+        default_value = "poisson"
+    )]
+    pub reconstruction: Reconstruction,
+
     #[structopt(flatten)]
     pub poisson: poisson::Params,
 
+    #[structopt(
+        help = "Output JSON lines file with Poisson progress events",
+        long
+    )]
+    pub progress_json: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub dual_contouring: dual_contouring::Params,
+
+    #[structopt(flatten)]
+    pub tsdf: tsdf::Params,
+
     #[structopt(
         help = "Number of Laplacian smoothing iterations",
         long,
@@ -56,6 +135,41 @@ pub struct BuildViewParams {
     )]
     pub num_smooth_iters: usize,
 
+    #[structopt(
+        help = "Fill mesh holes bounded by up to given number of edges",
+        long,
+        conflicts_with = "mesh"
+    )]
+    pub fill_holes: Option<usize>,
+
+    #[structopt(
+        help = "Flatten and smooth mesh rims left by --min-z/--max-z cuts",
+        long,
+        conflicts_with = "mesh"
+    )]
+    pub cut_rims: bool,
+
+    #[structopt(
+        help = "Close mesh rims left by cuts with flat caps",
+        long,
+        conflicts_with = "mesh"
+    )]
+    pub cut_caps: bool,
+
+    #[structopt(
+        help = "Maximum distance of cut rim vertices from cut planes",
+        long,
+        default_value = "0.01"
+    )]
+    pub cut_rim_tolerance: f64,
+
+    #[structopt(
+        help = "Number of smoothing iterations along cut rims",
+        long,
+        default_value = "3"
+    )]
+    pub num_cut_rim_smooth_iters: usize,
+
     #[structopt(
         help = "Surface decimation ratio",
         long,
@@ -63,6 +177,14 @@ pub struct BuildViewParams {
     )]
     pub decimate_ratio: f64,
 
+    #[structopt(
+        help = "Face counts of further decimated levels of detail (e.g. \
+                100k,25k,5k), written as elements '<element>-lod<N>'",
+        long,
+        use_delimiter = true
+    )]
+    pub lods: Vec<FaceCount>,
+
     #[structopt(
         help = "Disable texturing",
         long,
@@ -71,6 +193,14 @@ pub struct BuildViewParams {
     )]
     pub disable_texturing: bool,
 
+    #[structopt(
+        help = "Mesh coloring: texture (atlas) or vertex (colors of \
+                vertices, fast but coarse)",
+        long,
+        default_value = "texture"
+    )]
+    pub color_mode: ColorMode,
+
     #[structopt(help = "Output element name", long, short = "e")]
     pub element: Option<String>,
 
@@ -86,6 +216,178 @@ pub struct BuildViewParams {
         default_value = "80" // TODO: Make it conflicting with non-jpeg.
     )]
     pub texture_jpeg_quality: u8,
+
+    #[structopt(
+        help = "Number of downscaled texture copies for streaming viewers",
+        long,
+        default_value = "0"
+    )]
+    pub texture_levels: u32,
+
+    #[structopt(help = "Size of embedded preview image", long)]
+    pub preview_size: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reconstruction {
+    Poisson,
+    // Dual contouring, which keeps sharp edges of boxy objects.
+    DualContouring,
+    // Fusion of depth maps, which keeps thin structures.
+    Tsdf,
+}
+
+impl FromStr for Reconstruction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "poisson" => Ok(Reconstruction::Poisson),
+            "dc" => Ok(Reconstruction::DualContouring),
+            "tsdf" => Ok(Reconstruction::Tsdf),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown reconstruction method".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMode {
+    Texture,
+    // Per-vertex colors instead of texture atlas (e.g. for previews).
+    Vertex,
+}
+
+impl FromStr for ColorMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "texture" => Ok(ColorMode::Texture),
+            "vertex" => Ok(ColorMode::Vertex),
+            _ => {
+                Err(Error::new(MalformedData, "unknown color mode".to_string()))
+            }
+        }
+    }
+}
+
+// Number of faces, possibly with k (thousands) or M (millions) suffix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaceCount(pub usize);
+
+impl FromStr for FaceCount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (digits, multiplier) = match s.strip_suffix(['k', 'K']) {
+            Some(digits) => (digits, 1_000),
+            None => match s.strip_suffix('M') {
+                Some(digits) => (digits, 1_000_000),
+                None => (s, 1),
+            },
+        };
+        digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(FaceCount)
+            .ok_or_else(|| {
+                let desc = format!("malformed face count '{}'", s);
+                Error::new(MalformedData, desc)
+            })
+    }
+}
+
+impl BuildViewParams {
+    pub fn with_scans_config(&self) -> Result<BuildViewParams> {
+        let mut params = self.clone();
+        if let Some(path) = &self.scan.scans_config {
+            let config = read_scans_config(path)?;
+            params.scan.merge_config(&config);
+            params.point_cloud.merge_config(&config);
+        }
+        Ok(params)
+    }
+
+    // Z-coordinates of finite bounds, where the mesh is cut.
+    pub fn cut_planes(&self) -> Vec<f64> {
+        let point_cloud = &self.point_cloud;
+        [point_cloud.min_z, point_cloud.max_z]
+            .into_iter()
+            .filter(|z| z.is_finite())
+            .map(|z| z as f64)
+            .collect()
+    }
+}
+
+impl CheckParams for BuildViewParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        match self.reconstruction {
+            Reconstruction::Poisson => self.poisson.check_into(check),
+            Reconstruction::DualContouring => {
+                self.dual_contouring.check_into(check)
+            }
+            Reconstruction::Tsdf => self.tsdf.check_into(check),
+        }
+        check.require(
+            self.decimate_ratio > 0.0 && self.decimate_ratio <= 1.0,
+            || {
+                format!(
+                    "--decimate-ratio {} should be within (0, 1]",
+                    self.decimate_ratio
+                )
+            },
+        );
+        check.require(self.lods.iter().all(|c| c.0 > 0), || {
+            "--lods face counts should be positive".to_string()
+        });
+        check.require(self.lods.windows(2).all(|w| w[0].0 > w[1].0), || {
+            "--lods face counts should be decreasing".to_string()
+        });
+        check.require(
+            self.lods.is_empty()
+                || self.disable_texturing
+                || self.texture.max_atlas_pages == 1,
+            || "--lods can't be used with --max-atlas-pages".to_string(),
+        );
+        if self.color_mode == ColorMode::Vertex {
+            check.require(!self.disable_texturing, || {
+                "--color-mode vertex can't be used with --disable-texturing"
+                    .to_string()
+            });
+            check.require(self.lods.is_empty(), || {
+                "--lods can't be used with --color-mode vertex".to_string()
+            });
+        }
+        check.require(self.fill_holes.is_none_or(|n| n >= 3), || {
+            "--fill-holes should be at least 3".to_string()
+        });
+        if self.cut_rims || self.cut_caps {
+            check.require(!self.cut_planes().is_empty(), || {
+                "--cut-rims and --cut-caps need --min-z or --max-z".to_string()
+            });
+        }
+        check.require(self.cut_rim_tolerance >= 0.0, || {
+            "--cut-rim-tolerance should be non-negative".to_string()
+        });
+        if !self.disable_texturing {
+            self.texture.check_into(check);
+        }
+        check.require((1..=100).contains(&self.texture_jpeg_quality), || {
+            "--texture-jpeg-quality should be within [1, 100]".to_string()
+        });
+        check.require(self.texture_levels <= MAX_TEXTURE_LEVELS, || {
+            format!("--texture-levels should not exceed {}", MAX_TEXTURE_LEVELS)
+        });
+        check.require(self.preview_size != Some(0), || {
+            "--preview-size should be positive".to_string()
+        });
+    }
 }
 
 pub fn build_view(
@@ -93,24 +395,191 @@ pub fn build_view(
     writer: &mut dyn fm::Write,
     params: &BuildViewParams,
 ) -> Result<()> {
+    let _span = info_span!("build_view").entered();
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
+    // Poisson and texturing have their own threads, which follow the pool
+    // unless set.
+    let pool = params.threads.pool()?;
+    let texture_pool = match params.texture_threads {
+        AUTO => None,
+        threads => Some(create_thread_pool(threads)?),
+    };
+    let texture_pool = texture_pool.as_ref().unwrap_or(&pool);
+    let mut poisson_params = params.poisson;
+    if poisson_params.threads as usize == AUTO {
+        poisson_params.threads = pool.current_num_threads() as _;
+    }
+
+    let input_mesh = match &params.mesh {
+        Some(path) => {
+            info!("reading mesh...");
+            Some(info_span!("read_mesh").in_scope(|| read_mesh(path))?)
+        }
+        None => None,
+    };
+    let labels_config = match &params.texture.texture_labels_config {
+        Some(path) if !params.disable_texturing => {
+            read_texture_labels_config(path)?
+        }
+        _ => TextureLabelsConfig::default(),
+    };
+    let forbidden_seams = match &params.texture.forbidden_seams {
+        Some(path) if !params.disable_texturing => read_forbidden_seams(path)?,
+        _ => Vec::new(),
+    };
+
     info!("reading scans...");
-    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+    let (scans, scan_frames) = info_span!("read_scans")
+        .in_scope(|| read_scans(reader, &params.scan))?;
+    telemetry::count("scans", scans.len());
+    telemetry::count("scan_frames", scan_frames.len());
 
     params
         .point_cloud
         .validate(scans.keys().map(String::as_str))?;
 
+    let (mesh, face_labels) = match input_mesh {
+        Some(input_mesh) => input_mesh,
+        None => (
+            reconstruct_mesh(
+                params,
+                &pool,
+                &poisson_params,
+                &scans,
+                &scan_frames,
+            )?,
+            None,
+        ),
+    };
+
+    let preview = params
+        .preview_size
+        .map(|size| {
+            info!("rendering preview...");
+            info_span!("preview").in_scope(|| create_preview(&mesh, size))
+        })
+        .transpose()?;
+
+    let (mut view, state) = if params.disable_texturing {
+        create_non_textured_element(params, &mesh)?
+    } else {
+        let labels = match &face_labels {
+            Some(face_labels) => LabelTextureParams::new(
+                &params.texture,
+                &labels_config,
+                face_labels,
+            ),
+            None => {
+                if !labels_config.is_empty() {
+                    warn!(
+                        "texture labels config is ignored for unlabeled mesh"
+                    );
+                }
+                LabelTextureParams::uniform(&params.texture, mesh.faces.len())
+            }
+        };
+        match params.color_mode {
+            ColorMode::Texture => {
+                info!(
+                    "texturing mesh of {} vertices and {} faces...",
+                    mesh.vertices.len(),
+                    mesh.faces.len()
+                );
+                let tmesh = info_span!("texture").in_scope(|| {
+                    texture_pool.install(|| {
+                        TexturedMesh::new(
+                            &scans,
+                            &scan_frames,
+                            mesh,
+                            &labels,
+                            &forbidden_seams,
+                            &params.texture,
+                        )
+                    })
+                })?;
+                if let Some(path) = &params.texture.seams_report {
+                    tmesh.seams.write(path)?;
+                }
+                create_textured_element(params, &tmesh)?
+            }
+            ColorMode::Vertex => {
+                info!("coloring {} vertices of mesh...", mesh.vertices.len());
+                let colors = info_span!("vertex_colors").in_scope(|| {
+                    texture_pool.install(|| {
+                        vertex_colors(
+                            &scans,
+                            &scan_frames,
+                            &mesh,
+                            &labels,
+                            &params.texture,
+                        )
+                    })
+                })?;
+                create_vertex_colored_element(params, &mesh, &colors)?
+            }
+        }
+    };
+    if let Some(face_labels) = face_labels {
+        view.face_labels = face_labels.into_iter().map(u32::from).collect();
+    }
+
+    telemetry::count("vertices", state.vertices.len());
+    telemetry::count("faces", view.faces.len());
+
+    let lods = if params.lods.is_empty() {
+        Vec::new()
+    } else {
+        info_span!("lods")
+            .in_scope(|| create_lods(&view, &state, &params.lods))?
+    };
+
+    info!("writing generated model...");
+    let _span = info_span!("write").entered();
+    if let Some(preview) = preview {
+        writer.write_record(&fm::Record {
+            r#type: Some(Preview(preview)),
+        })?;
+    }
+    writer.write_record(&fm::Record {
+        r#type: Some(ElementView(view)),
+    })?;
+    writer.write_record(&fm::Record {
+        r#type: Some(ElementViewState(state)),
+    })?;
+    for (view, state) in lods {
+        writer.write_record(&fm::Record {
+            r#type: Some(ElementView(view)),
+        })?;
+        writer.write_record(&fm::Record {
+            r#type: Some(ElementViewState(state)),
+        })?;
+    }
+
+    info!("done");
+    Ok(())
+}
+
+fn reconstruct_mesh(
+    params: &BuildViewParams,
+    pool: &ThreadPool,
+    poisson_params: &poisson::Params,
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+) -> Result<Mesh> {
     info!(
         "building point clouds from {} scans ({} frames)...",
         scans.len(),
         scan_frames.len()
     );
-    let cloud = Cloud(
-        build_frame_clouds(&scans, &scan_frames, &params.point_cloud)
-            .into_iter()
-            .flatten()
-            .collect(),
-    );
+    let cloud = info_span!("build_cloud").in_scope(|| {
+        pool.install(|| {
+            build_frame_clouds(scans, scan_frames, &params.point_cloud)
+        })
+        .map(|clouds| Cloud(clouds.into_iter().flatten().collect()))
+    })?;
+    telemetry::count("cloud_points", cloud.0.len());
 
     let mut mesh = Mesh::default();
 
@@ -118,11 +587,48 @@ pub fn build_view(
         "reconstructing mesh from cloud of {} points...",
         cloud.0.len()
     );
-    if !poisson::reconstruct(&params.poisson, &cloud, &mut mesh) {
-        return Err(Error::new(
-            PoissonError,
-            "failed to reconstruct surface".to_string(),
-        ));
+    match params.reconstruction {
+        Reconstruction::Poisson => {
+            let json = match &params.progress_json {
+                Some(path) => Some(fs::create_file(path)?),
+                None => None,
+            };
+            let mut progress = PoissonProgress::new(json);
+            if !info_span!("poisson").in_scope(|| {
+                poisson::reconstruct(
+                    poisson_params,
+                    &cloud,
+                    &mut mesh,
+                    &mut progress,
+                )
+            }) {
+                return Err(Error::new(
+                    PoissonError,
+                    "failed to reconstruct surface".to_string(),
+                ));
+            }
+            progress.finish()?;
+        }
+        Reconstruction::DualContouring => {
+            info_span!("dual_contouring").in_scope(|| {
+                let params = &params.dual_contouring;
+                dual_contouring::reconstruct(params, &cloud, &mut mesh)
+            })?;
+        }
+        Reconstruction::Tsdf => {
+            info_span!("tsdf").in_scope(|| {
+                pool.install(|| {
+                    tsdf::reconstruct(
+                        &params.tsdf,
+                        &params.point_cloud,
+                        scans,
+                        scan_frames,
+                        &cloud,
+                        &mut mesh,
+                    )
+                })
+            })?;
+        }
     }
     mesh.apply_bounds(&params.point_cloud);
     for vn in mesh.normals.iter_mut() {
@@ -130,9 +636,30 @@ pub fn build_view(
     }
     mesh.clean();
 
+    if let Some(max_boundary_edges) = params.fill_holes {
+        info!("filling mesh holes...");
+        let num_filled = info_span!("fill_holes")
+            .in_scope(|| mesh.fill_holes(max_boundary_edges));
+        telemetry::count("filled_holes", num_filled);
+    }
+
     if params.num_smooth_iters > 0 {
         info!("smoothing mesh...");
-        mesh.smoothen(params.num_smooth_iters);
+        info_span!("smoothen")
+            .in_scope(|| mesh.smoothen(params.num_smooth_iters));
+    }
+
+    if params.cut_rims || params.cut_caps {
+        info!("finishing mesh cuts...");
+        let num_finished = info_span!("finish_cuts").in_scope(|| {
+            mesh.finish_cuts(
+                &params.cut_planes(),
+                params.cut_rim_tolerance,
+                params.num_cut_rim_smooth_iters,
+                params.cut_caps,
+            )
+        });
+        telemetry::count("finished_cuts", num_finished);
     }
 
     if params.decimate_ratio > 0.0 && params.decimate_ratio < 1.0 {
@@ -141,32 +668,267 @@ pub fn build_view(
             mesh.vertices.len(),
             mesh.faces.len()
         );
-        mesh = mesh.decimate(params.decimate_ratio);
+        mesh = info_span!("decimate")
+            .in_scope(|| mesh.decimate(params.decimate_ratio));
     }
 
-    let (view, state) = if params.disable_texturing {
-        create_non_textured_element(params, &mesh)?
-    } else {
-        info!(
-            "texturing mesh of {} vertices and {} faces...",
-            mesh.vertices.len(),
-            mesh.faces.len()
-        );
-        let tmesh =
-            TexturedMesh::new(&scans, &scan_frames, mesh, &params.texture)?;
-        create_textured_element(params, &tmesh)?
+    Ok(mesh)
+}
+
+// Each level is decimated from the previous one, keeping texture points
+// within the same atlas (so that all levels share the texture).
+fn create_lods(
+    view: &fm::ElementView,
+    state: &fm::ElementViewState,
+    face_counts: &[FaceCount],
+) -> Result<Vec<(fm::ElementView, fm::ElementViewState)>> {
+    let mut mesh = WedgeMesh::from_element(view, state)?;
+    let mut lods = Vec::with_capacity(face_counts.len());
+    for (i, &FaceCount(num_faces)) in face_counts.iter().enumerate() {
+        let ratio = num_faces as f64 / mesh.faces.len() as f64;
+        if ratio < 1.0 {
+            info!(
+                "decimating level of detail {} to {} faces...",
+                i + 1,
+                num_faces
+            );
+            mesh = TexturedDecimator::execute(mesh, ratio, 1.0);
+        } else {
+            warn!(
+                "level of detail {} isn't decimated ({} faces)",
+                i + 1,
+                mesh.faces.len()
+            );
+        }
+
+        let (mut lod_view, mut lod_state) = mesh.to_element(view);
+        lod_view.element = format!("{}-lod{}", view.element, i + 1);
+        lod_state.element.clone_from(&lod_view.element);
+        lods.push((lod_view, lod_state));
+    }
+    telemetry::count("lods", lods.len());
+    Ok(lods)
+}
+
+// The first element of the model is used along with its face labels.
+fn read_mesh(path: &Path) -> Result<(Mesh, Option<Vec<u16>>)> {
+    let mut reader = fm::Reader::new(fs::open_input(path)?)?;
+    let (view, state) = read_elements(&mut reader, &[])?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            let desc = format!("no elements in '{}'", path.display());
+            Error::new(MalformedData, desc)
+        })?;
+    let face_labels = fm::face_labels(&view)?;
+    Ok((Mesh::from_element(&view, &state)?, face_labels))
+}
+
+// Resolves parameters and reads scans, but skips all the heavy stages.
+pub fn plan_build_view(
+    reader: &mut dyn fm::Read,
+    output: &str,
+    params: &BuildViewParams,
+) -> Result<Plan> {
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
+    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+    params
+        .point_cloud
+        .validate(scans.keys().map(String::as_str))?;
+
+    let labels_config = match &params.texture.texture_labels_config {
+        Some(path) if !params.disable_texturing => {
+            read_texture_labels_config(path)?
+        }
+        _ => TextureLabelsConfig::default(),
+    };
+    let forbidden_seams = match &params.texture.forbidden_seams {
+        Some(path) if !params.disable_texturing => read_forbidden_seams(path)?,
+        _ => Vec::new(),
     };
 
-    info!("writing generated model...");
-    writer.write_record(&fm::Record {
-        r#type: Some(ElementView(view)),
-    })?;
-    writer.write_record(&fm::Record {
-        r#type: Some(ElementViewState(state)),
-    })?;
+    let mut plan = Plan::new(params);
+    if let Some(path) = &params.mesh {
+        plan.stage(format!("read mesh from {}", path.display()));
+    }
+    plan.stage(format!(
+        "read {} scans ({} frames)",
+        scans.len(),
+        scan_frames.len()
+    ));
+    if params.mesh.is_none() {
+        plan.stage("build point clouds");
+        if params.point_cloud.orient_normals {
+            plan.stage("orient point normals");
+        }
+        plan.stage(match params.reconstruction {
+            Reconstruction::Poisson => "reconstruct surface (Poisson)",
+            Reconstruction::DualContouring => {
+                "reconstruct surface (dual contouring)"
+            }
+            Reconstruction::Tsdf => "reconstruct surface (TSDF fusion)",
+        });
+        if let Some(max_boundary_edges) = params.fill_holes {
+            plan.stage(format!(
+                "fill mesh holes (up to {} edges)",
+                max_boundary_edges
+            ));
+        }
+        if params.num_smooth_iters > 0 {
+            plan.stage(format!(
+                "smooth mesh ({} iterations)",
+                params.num_smooth_iters
+            ));
+        }
+        if params.cut_rims || params.cut_caps {
+            plan.stage(if params.cut_caps {
+                "flatten and cap mesh cuts"
+            } else {
+                "flatten mesh cuts"
+            });
+        }
+        if params.decimate_ratio > 0.0 && params.decimate_ratio < 1.0 {
+            plan.stage(format!(
+                "decimate mesh (ratio {})",
+                params.decimate_ratio
+            ));
+        }
+    }
+    if let Some(size) = params.preview_size {
+        plan.stage(format!("render {}x{} preview", size, size));
+    }
+    if !params.disable_texturing && params.color_mode == ColorMode::Vertex {
+        plan.stage("color mesh vertices");
+    } else if !params.disable_texturing {
+        plan.stage(if labels_config.is_empty() {
+            "texture mesh".to_string()
+        } else {
+            format!("texture mesh ({} label overrides)", labels_config.len())
+        });
+        if !forbidden_seams.is_empty() {
+            plan.stage(format!(
+                "keep {} forbidden seam edges",
+                forbidden_seams.len()
+            ));
+        }
+        if let Some(path) = &params.texture.seams_report {
+            plan.stage(format!("write seams report to {}", path.display()));
+        }
+        if params.texture_levels > 0 {
+            plan.stage(format!(
+                "downscale texture ({} levels)",
+                params.texture_levels
+            ));
+        }
+    }
+    if !params.lods.is_empty() {
+        let counts: Vec<_> =
+            params.lods.iter().map(|c| c.0.to_string()).collect();
+        plan.stage(format!(
+            "decimate levels of detail ({} faces)",
+            counts.join(", ")
+        ));
+    }
+    plan.stage("write element");
 
-    info!("done");
-    Ok(())
+    let max_points = params.point_cloud.max_num_frame_points;
+    let num_depths: usize = scan_frames.iter().map(|f| f.depths.len()).sum();
+    let num_points: usize = scan_frames
+        .iter()
+        .map(|f| max_points.map_or(f.depths.len(), |m| m.min(f.depths.len())))
+        .sum();
+    let num_images = scan_frames.iter().filter(|f| f.image.is_some()).count();
+    if params.mesh.is_none() {
+        plan.estimate("depth samples", num_depths);
+        plan.estimate("cloud points (at most)", num_points);
+    }
+    plan.estimate("frames with images", num_images);
+    match params.reconstruction {
+        _ if params.mesh.is_some() => {}
+        Reconstruction::Poisson if params.poisson.depth > 0 => {
+            let cells = 1u64 << params.poisson.depth;
+            plan.estimate("octree resolution", format!("{0}x{0}x{0}", cells));
+        }
+        Reconstruction::Poisson => {
+            let width = params.poisson.finest_cell_width;
+            plan.estimate("octree cell width", width);
+        }
+        Reconstruction::DualContouring => {
+            let cells = params.dual_contouring.resolution;
+            plan.estimate(
+                "grid resolution (at most)",
+                format!("{0}x{0}x{0}", cells),
+            );
+        }
+        Reconstruction::Tsdf => {
+            let cells = params.tsdf.tsdf_resolution;
+            plan.estimate(
+                "voxel grid resolution (at most)",
+                format!("{0}x{0}x{0}", cells),
+            );
+        }
+    }
+    if !params.disable_texturing && params.color_mode == ColorMode::Texture {
+        let res = params.texture.image_resolution as u64;
+        let size = format_size(res * res * 3);
+        match params.texture.texels_per_meter {
+            Some(texels) if params.texture.max_atlas_pages > 1 => plan
+                .estimate(
+                    "texture pages (at most)",
+                    format!(
+                        "{0} of {1}x{1} ({2} raw each) for {3} texels/m",
+                        params.texture.max_atlas_pages, res, size, texels
+                    ),
+                ),
+            Some(texels) => plan.estimate(
+                "texture image (at most)",
+                format!(
+                    "{0}x{0} ({1} raw) for {2} texels/m",
+                    res, size, texels
+                ),
+            ),
+            None => plan.estimate(
+                "texture image",
+                format!("{0}x{0} ({1} raw)", res, size),
+            ),
+        }
+    }
+    if !params.disable_texturing {
+        if num_images == 0 {
+            plan.warn("no frame images to texture from (see --drop-images)");
+        }
+        if !labels_config.is_empty() && params.mesh.is_none() {
+            plan.warn("texture labels config needs a labeled --mesh");
+        }
+        if !forbidden_seams.is_empty() && params.mesh.is_none() {
+            plan.warn("forbidden seams are stable only for the same --mesh");
+        }
+    }
+    if num_depths == 0 && params.mesh.is_none() {
+        plan.warn("no frame depths to build point clouds from");
+    }
+
+    let element = params.element.as_deref().unwrap_or("<random UUID>");
+    let mut records = vec!["ElementView", "ElementViewState"];
+    if params.preview_size.is_some() {
+        records.insert(0, "Preview");
+    }
+    let lods = match params.lods.len() {
+        0 => String::new(),
+        1 => format!(" (and its level of detail '{}-lod1')", element),
+        n => format!(" (and its levels of detail '{}-lod1..{}')", element, n),
+    };
+    plan.output(format!(
+        "{}: {} of element '{}'{}",
+        output,
+        records.join(", "),
+        element,
+        lods
+    ));
+
+    Ok(plan)
 }
 
 pub struct Cloud(Vec<PointNormal>);
@@ -189,6 +951,65 @@ impl poisson::Cloud<f64> for Cloud {
     }
 }
 
+// Logs Poisson progress in steps of 10% (or on each report if total is
+// unknown) and streams all reports into optional JSON lines output.
+struct PoissonProgress<W: io::Write> {
+    json: Option<W>,
+    started: Instant,
+    logged: Option<(poisson::Stage, usize)>,
+    error: Option<io::Error>,
+}
+
+impl<W: io::Write> PoissonProgress<W> {
+    fn new(json: Option<W>) -> PoissonProgress<W> {
+        PoissonProgress {
+            json,
+            started: Instant::now(),
+            logged: None,
+            error: None,
+        }
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let err = || "failed to write Poisson progress".to_string();
+        if let Some(e) = self.error {
+            return Err(e).into_result(err);
+        }
+        match &mut self.json {
+            Some(json) => json.flush().into_result(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: io::Write> poisson::Progress for PoissonProgress<W> {
+    fn report(&mut self, stage: poisson::Stage, done: usize, total: usize) {
+        let step = (done * 10).checked_div(total).unwrap_or(done);
+        if self.logged != Some((stage, step)) {
+            self.logged = Some((stage, step));
+            if let Some(percent) = (done * 100).checked_div(total) {
+                info!("poisson {}: {}%", stage, percent);
+            } else if done > 0 {
+                info!("poisson {}: {} vertices", stage, done);
+            } else {
+                info!("poisson {}...", stage);
+            }
+        }
+
+        if let (Some(json), None) = (&mut self.json, &self.error) {
+            let event = json!({
+                "stage": stage.to_string(),
+                "done": done,
+                "total": total,
+                "elapsed": self.started.elapsed().as_secs_f64(),
+            });
+            if let Err(e) = writeln!(json, "{}", event) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
 fn create_non_textured_element(
     params: &BuildViewParams,
     mesh: &Mesh,
@@ -198,61 +1019,16 @@ fn create_non_textured_element(
         .clone()
         .unwrap_or_else(|| Uuid::new_v4().to_string());
 
-    let mut view = fm::ElementView {
-        element,
-        ..Default::default()
-    };
-
-    view.faces = mesh
-        .faces
-        .iter()
-        .map(|f| fm::element_view::Face {
-            vertex1: f[0] as u32 + 1,
-            vertex2: f[1] as u32 + 1,
-            vertex3: f[2] as u32 + 1,
-            normal1: f[0] as u32 + 1,
-            normal2: f[1] as u32 + 1,
-            normal3: f[2] as u32 + 1,
-            ..Default::default()
-        })
-        .collect();
-
-    let mut state = fm::ElementViewState {
-        element: view.element.clone(),
-        ..Default::default()
-    };
-
-    state.vertices = mesh
-        .vertices
-        .iter()
-        .map(|p| fm::Point3 {
-            x: p[0] as f32,
-            y: p[1] as f32,
-            z: p[2] as f32,
-        })
-        .collect();
-
-    state.normals = mesh
-        .normals
-        .iter()
-        .map(|p| fm::Point3 {
-            x: p[0] as f32,
-            y: p[1] as f32,
-            z: p[2] as f32,
-        })
-        .collect();
-
-    Ok((view, state))
+    Ok(mesh.to_element(element))
 }
 
-fn create_textured_element(
-    params: &BuildViewParams,
-    mesh: &TexturedMesh,
-) -> Result<(fm::ElementView, fm::ElementViewState)> {
-    let (mut view, state) = create_non_textured_element(params, &mesh.mesh)?;
-
+pub fn encode_image(
+    image: &RgbImage,
+    image_type: fm::image::Type,
+    jpeg_quality: u8,
+) -> fm::Image {
     let mut data = Vec::new();
-    match params.texture_image_type {
+    match image_type {
         fm::image::Type::Png => {
             let encoder = PngEncoder::new_with_quality(
                 &mut data,
@@ -261,40 +1037,101 @@ fn create_textured_element(
             );
             encoder
                 .write_image(
-                    mesh.image.as_ref(),
-                    mesh.image.width(),
-                    mesh.image.height(),
+                    image.as_ref(),
+                    image.width(),
+                    image.height(),
                     image::ColorType::Rgb8,
                 )
                 .unwrap();
-            view.texture = Some(fm::Image {
-                r#type: fm::image::Type::Png as i32,
-                data,
-            });
         }
         fm::image::Type::Jpeg => {
-            let encoder = JpegEncoder::new_with_quality(
-                &mut data,
-                params.texture_jpeg_quality,
-            );
+            let encoder =
+                JpegEncoder::new_with_quality(&mut data, jpeg_quality);
             encoder
                 .write_image(
-                    mesh.image.as_ref(),
-                    mesh.image.width(),
-                    mesh.image.height(),
+                    image.as_ref(),
+                    image.width(),
+                    image.height(),
                     image::ColorType::Rgb8,
                 )
                 .unwrap();
-            view.texture = Some(fm::Image {
-                r#type: fm::image::Type::Jpeg as i32,
-                data,
-            });
         }
         fm::image::Type::None => {
             panic!("unsupported texture image type");
         }
     }
 
+    fm::Image {
+        r#type: image_type as i32,
+        data,
+        ..Default::default()
+    }
+}
+
+fn encode_texture(params: &BuildViewParams, image: &RgbImage) -> fm::Image {
+    encode_image(
+        image,
+        params.texture_image_type,
+        params.texture_jpeg_quality,
+    )
+}
+
+// Copies are ordered from the coarsest, each is twice smaller than
+// the next one (or the texture itself).
+pub fn downscale_texture(image: &RgbImage, num_levels: u32) -> Vec<RgbImage> {
+    (1..=num_levels)
+        .rev()
+        .map(|level| {
+            let (width, height) = image.dimensions();
+            imageops::resize(
+                image,
+                (width >> level).max(1),
+                (height >> level).max(1),
+                imageops::FilterType::Triangle,
+            )
+        })
+        .collect()
+}
+
+fn create_vertex_colored_element(
+    params: &BuildViewParams,
+    mesh: &Mesh,
+    colors: &[Vector3],
+) -> Result<(fm::ElementView, fm::ElementViewState)> {
+    let (mut view, state) = create_non_textured_element(params, mesh)?;
+    view.vertex_colors = colors
+        .iter()
+        .map(|color| {
+            let [r, g, b] =
+                [0, 1, 2].map(|i| color[i].clamp(0.0, 255.0).round() as u32);
+            r << 16 | g << 8 | b
+        })
+        .collect();
+    Ok((view, state))
+}
+
+fn create_textured_element(
+    params: &BuildViewParams,
+    mesh: &TexturedMesh,
+) -> Result<(fm::ElementView, fm::ElementViewState)> {
+    let (mut view, state) = create_non_textured_element(params, &mesh.mesh)?;
+
+    view.texture = Some(encode_texture(params, &mesh.image));
+
+    view.texture_levels = downscale_texture(&mesh.image, params.texture_levels)
+        .iter()
+        .map(|image| encode_texture(params, image))
+        .collect();
+
+    if !mesh.pages.is_empty() {
+        view.texture_pages = mesh
+            .pages
+            .iter()
+            .map(|image| encode_texture(params, image))
+            .collect();
+        view.face_pages = mesh.face_pages.iter().map(|&p| p as u32).collect();
+    }
+
     view.texture_points = mesh
         .uv_coords
         .iter()
@@ -312,3 +1149,108 @@ fn create_textured_element(
 
     Ok((view, state))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::{Point3, Vector3};
+    use poisson::{Progress as _, Stage};
+    use serde_json::Value;
+
+    #[test]
+    fn test_poisson_progress_json() {
+        let mut progress = PoissonProgress::new(Some(Vec::new()));
+        progress.report(Stage::Octree, 0, 200);
+        progress.report(Stage::Octree, 200, 200);
+        progress.report(Stage::Solver, 0, 0);
+        progress.report(Stage::Extraction, 65536, 0);
+        assert_eq!(progress.logged, Some((Stage::Extraction, 65536)));
+
+        let json = progress.json.take().unwrap();
+        let events: Vec<Value> = json
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1]["stage"], "octree");
+        assert_eq!(events[1]["done"], 200);
+        assert_eq!(events[2]["stage"], "solver");
+        assert_eq!(events[3]["total"], 0);
+        assert!(events[3]["elapsed"].as_f64().unwrap() >= 0.0);
+        progress.finish().unwrap();
+    }
+
+    #[test]
+    fn test_face_count() {
+        assert_eq!("5000".parse::<FaceCount>().unwrap(), FaceCount(5000));
+        assert_eq!("25k".parse::<FaceCount>().unwrap(), FaceCount(25_000));
+        assert_eq!("2M".parse::<FaceCount>().unwrap(), FaceCount(2_000_000));
+        assert!("2.5k".parse::<FaceCount>().is_err());
+        assert!("k".parse::<FaceCount>().is_err());
+    }
+
+    #[test]
+    fn test_vertex_color_mode() {
+        let params = |args: &[&str]| {
+            let args = [&["build-view", "-e", "a"], args].concat();
+            BuildViewParams::from_iter(args)
+        };
+        assert_eq!(params(&[]).color_mode, ColorMode::Texture);
+        let vertex = params(&["--color-mode", "vertex"]);
+        assert!(vertex.check().is_ok());
+        let lods = params(&["--color-mode", "vertex", "--lods", "10"]);
+        assert!(lods.check().is_err());
+
+        let mesh = Mesh {
+            vertices: vec![Point3::origin(); 3],
+            normals: vec![Vector3::z(); 3],
+            faces: vec![[0, 1, 2]],
+        };
+        let colors = [
+            Vector3::new(0.0, 128.0, 255.0),
+            Vector3::new(-10.0, 300.0, 16.4),
+            Vector3::zeros(),
+        ];
+        let (view, state) =
+            create_vertex_colored_element(&vertex, &mesh, &colors).unwrap();
+        assert_eq!(view.element, "a");
+        assert_eq!(view.vertex_colors, [0x0080FF, 0x00FF10, 0]);
+        assert!(view.texture.is_none());
+        assert_eq!(state.vertices.len(), 3);
+    }
+
+    #[test]
+    fn test_create_lods() {
+        // Flat 11x11 grid of 200 faces (its locked boundary limits decimation).
+        let mut mesh = Mesh::default();
+        for i in 0..11 {
+            for j in 0..11 {
+                mesh.vertices.push(Point3::new(i as f64, j as f64, 0.0));
+                mesh.normals.push(Vector3::z());
+            }
+        }
+        for i in 0..10 {
+            for j in 0..10 {
+                let v = i * 11 + j;
+                mesh.faces.push([v, v + 11, v + 1]);
+                mesh.faces.push([v + 1, v + 11, v + 12]);
+            }
+        }
+        let (view, state) = mesh.to_element("grid".to_string());
+
+        let counts = [FaceCount(120), FaceCount(80), FaceCount(1000)];
+        let lods = create_lods(&view, &state, &counts).unwrap();
+        assert_eq!(lods.len(), 3);
+        let num_faces: Vec<_> =
+            lods.iter().map(|(v, _)| v.faces.len()).collect();
+        assert!(num_faces[0] <= 120 && num_faces[0] > 80);
+        assert!(num_faces[1] <= 80 && num_faces[1] > 0);
+        assert_eq!(num_faces[2], num_faces[1]);
+        for (i, (view, state)) in lods.iter().enumerate() {
+            let element = format!("grid-lod{}", i + 1);
+            assert_eq!(view.element, element);
+            assert_eq!(state.element, element);
+        }
+    }
+}
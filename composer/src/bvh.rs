@@ -0,0 +1,118 @@
+use crate::point_cloud::{Point3, Vector3};
+
+const LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Aabb {
+    pub min: Point3,
+    pub max: Point3,
+}
+
+impl Aabb {
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::from(Vector3::repeat(f64::MAX)),
+            max: Point3::from(Vector3::repeat(f64::MIN)),
+        }
+    }
+
+    pub fn from_points(points: &[Point3]) -> Self {
+        let mut aabb = Self::empty();
+        for p in points {
+            aabb.add_point(p);
+        }
+        aabb
+    }
+
+    pub fn add_point(&mut self, p: &Point3) {
+        self.min = self.min.inf(p);
+        self.max = self.max.sup(p);
+    }
+
+    pub fn add_aabb(&mut self, other: &Aabb) {
+        self.min = self.min.inf(&other.min);
+        self.max = self.max.sup(&other.max);
+    }
+
+    pub fn center(&self) -> Point3 {
+        nalgebra::center(&self.min, &self.max)
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        (0..3)
+            .all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i])
+    }
+}
+
+enum Node {
+    Leaf(Aabb, Vec<usize>),
+    Branch(Aabb, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn aabb(&self) -> &Aabb {
+        match self {
+            Node::Leaf(aabb, _) | Node::Branch(aabb, _, _) => aabb,
+        }
+    }
+}
+
+// Bounding volume hierarchy over indexed boxes (e.g. mesh faces).
+pub struct Bvh {
+    root: Option<Node>,
+}
+
+impl Bvh {
+    pub fn new(aabbs: &[Aabb]) -> Self {
+        let indices = (0..aabbs.len()).collect::<Vec<_>>();
+        Self {
+            root: (!aabbs.is_empty()).then(|| Self::build(aabbs, indices)),
+        }
+    }
+
+    fn build(aabbs: &[Aabb], mut indices: Vec<usize>) -> Node {
+        let mut aabb = Aabb::empty();
+        let mut centers = Aabb::empty();
+        for &i in &indices {
+            aabb.add_aabb(&aabbs[i]);
+            centers.add_point(&aabbs[i].center());
+        }
+
+        if indices.len() <= LEAF_SIZE {
+            return Node::Leaf(aabb, indices);
+        }
+
+        let axis = (centers.max - centers.min).imax();
+        indices.sort_by(|&a, &b| {
+            aabbs[a].center()[axis].total_cmp(&aabbs[b].center()[axis])
+        });
+        let right = indices.split_off(indices.len() / 2);
+
+        Node::Branch(
+            aabb,
+            Box::new(Self::build(aabbs, indices)),
+            Box::new(Self::build(aabbs, right)),
+        )
+    }
+
+    // Calls the closure for candidate boxes which may intersect the given one.
+    pub fn for_each_intersecting<F: FnMut(usize)>(
+        &self,
+        aabb: &Aabb,
+        mut f: F,
+    ) {
+        let mut stack: Vec<&Node> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            if !node.aabb().intersects(aabb) {
+                continue;
+            }
+            match node {
+                Node::Leaf(_, indices) => indices.iter().for_each(|&i| f(i)),
+                Node::Branch(_, left, right) => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+    }
+}
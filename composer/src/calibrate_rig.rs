@@ -0,0 +1,383 @@
+use indexmap::IndexMap;
+use log::info;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{set_camera_pose, unproject_depth, Matrix3, Vector3};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Calibrate scan cameras by turntable scan of a sphere")]
+pub struct CalibrateRigCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(flatten)]
+    scan: ScanParams,
+
+    #[structopt(flatten)]
+    params: CalibrateRigParams,
+}
+
+impl CalibrateRigCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        calibrate_rig(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.scan,
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct CalibrateRigParams {
+    #[structopt(help = "Radius of calibration sphere", long)]
+    pub calibration_sphere_radius: f64,
+
+    #[structopt(
+        help = "Height of calibration sphere center over turntable",
+        long,
+        default_value = "0"
+    )]
+    pub calibration_sphere_height: f64,
+
+    #[structopt(
+        help = "Max distance from calibration sphere surface to its points",
+        long,
+        default_value = "0.003"
+    )]
+    pub calibration_tolerance: f64,
+}
+
+impl CheckParams for CalibrateRigParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.calibration_sphere_radius > 0.0, || {
+            "--calibration-sphere-radius should be positive".to_string()
+        });
+        check.require(self.calibration_tolerance > 0.0, || {
+            "--calibration-tolerance should be positive".to_string()
+        });
+    }
+}
+
+const MIN_SPHERE_POINTS: usize = 16;
+const RANSAC_ITERATIONS: usize = 256;
+const REFINE_ITERATIONS: usize = 16;
+
+// Finds center of sphere of given radius in camera space of the frame.
+pub fn detect_sphere(
+    scan: &fm::Scan,
+    frame: &fm::ScanFrame,
+    radius: f64,
+    tolerance: f64,
+) -> Option<Vector3> {
+    let (width, height) =
+        (scan.depth_width as usize, scan.depth_height as usize);
+    if width < 2 || height < 2 || frame.depths.len() != width * height {
+        return None;
+    }
+
+    let tan = (scan.camera_angle_of_view as f64 / 2.0).tan();
+    let points: Vec<_> = frame
+        .depths
+        .iter()
+        .enumerate()
+        .map(|(k, &depth)| {
+            depth.is_finite().then(|| {
+                let pixel = (k / width, k % width);
+                unproject_depth(scan, tan, (width, height), pixel, depth as f64)
+            })
+        })
+        .collect();
+
+    // Each point suggests a center by its normal facing the camera.
+    let mut samples = Vec::new();
+    let mut candidates = Vec::new();
+    for i in 0..height - 1 {
+        for j in 0..width - 1 {
+            let k = i * width + j;
+            let (p, right, down) =
+                match (points[k], points[k + 1], points[k + width]) {
+                    (Some(p), Some(r), Some(d)) => (p, r, d),
+                    _ => continue,
+                };
+            let normal = match (right - p)
+                .cross(&(down - p))
+                .try_normalize(f64::EPSILON)
+            {
+                Some(n) if n.dot(&p) > 0.0 => -n,
+                Some(n) => n,
+                None => continue,
+            };
+            samples.push(p);
+            candidates.push(p - normal * radius);
+        }
+    }
+    if samples.len() < MIN_SPHERE_POINTS {
+        return None;
+    }
+
+    let inliers = |center: Vector3| {
+        samples
+            .iter()
+            .filter(move |p| ((*p - center).norm() - radius).abs() < tolerance)
+    };
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let (mut best_num, mut center) = (0, Vector3::zeros());
+    for _ in 0..RANSAC_ITERATIONS {
+        let candidate = candidates[rng.gen_range(0..candidates.len())];
+        let num = inliers(candidate).count();
+        if num > best_num {
+            (best_num, center) = (num, candidate);
+        }
+    }
+    if best_num < MIN_SPHERE_POINTS {
+        return None;
+    }
+
+    // Fixed-point iteration of least squares fit with known radius.
+    for _ in 0..REFINE_ITERATIONS {
+        let (mut sum, mut num) = (Vector3::zeros(), 0);
+        for p in inliers(center) {
+            sum += p - (p - center).normalize() * radius;
+            num += 1;
+        }
+        if num < MIN_SPHERE_POINTS {
+            return None;
+        }
+        center = sum / num as f64;
+    }
+
+    Some(center)
+}
+
+// Camera pose (camera-to-scene rotation and eye position) with its RMS
+// error by sphere centers observed at turntable angles. The sphere stays at
+// (r, 0, h) in scene space, so its observations are mapped rigidly onto the
+// circle it describes in the opposite direction.
+pub fn solve_camera_pose(
+    observations: &[(f64, Vector3)],
+    height: f64,
+) -> Option<(Matrix3, Vector3, f64)> {
+    if observations.len() < 3 {
+        return None;
+    }
+
+    // Chords of the circle give its radius.
+    let (mut num, mut den) = (0.0, 0.0);
+    for (i, (a1, c1)) in observations.iter().enumerate() {
+        for (a2, c2) in &observations[i + 1..] {
+            let chord = 2.0 * ((a1 - a2) / 2.0).sin().abs();
+            num += (c1 - c2).norm() * chord;
+            den += chord * chord;
+        }
+    }
+    let radius = num / den;
+    if !radius.is_finite() {
+        return None;
+    }
+
+    let targets: Vec<_> = observations
+        .iter()
+        .map(|(a, _)| Vector3::new(radius * a.cos(), -radius * a.sin(), height))
+        .collect();
+
+    let n = observations.len() as f64;
+    let source_center =
+        observations.iter().map(|(_, c)| c).sum::<Vector3>() / n;
+    let target_center = targets.iter().sum::<Vector3>() / n;
+
+    let mut h = Matrix3::zeros();
+    for ((_, c), t) in observations.iter().zip(&targets) {
+        h += (c - source_center) * (t - target_center).transpose();
+    }
+
+    let svd = h.svd(true, true);
+    if svd.singular_values[1] <= 1e-9 * svd.singular_values[0] {
+        return None; // Not enough of turntable rotation.
+    }
+    let (u, v_t) = (svd.u?, svd.v_t?);
+    let mut d = Matrix3::identity();
+    if (v_t.transpose() * u.transpose()).determinant() < 0.0 {
+        d[(2, 2)] = -1.0;
+    }
+    let rotation = v_t.transpose() * d * u.transpose();
+    let eye = target_center - rotation * source_center;
+
+    let sum_sq: f64 = observations
+        .iter()
+        .zip(&targets)
+        .map(|((_, c), t)| (rotation * c + eye - t).norm_squared())
+        .sum();
+
+    Some((rotation, eye, (sum_sq / n).sqrt()))
+}
+
+pub fn calibrate_rig(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    scan_params: &ScanParams,
+    params: &CalibrateRigParams,
+) -> Result<()> {
+    let mut scan_params = scan_params.clone();
+    if let Some(path) = &scan_params.scans_config {
+        scan_params.merge_config(&read_scans_config(path)?);
+    }
+    let scan_params = &scan_params;
+
+    let mut check = ParamCheck::default();
+    scan_params.check_into(&mut check);
+    params.check_into(&mut check);
+    check.finish()?;
+
+    let radius = params.calibration_sphere_radius;
+    let (scans, frames) = read_scans(reader, scan_params)?;
+
+    let mut observations = IndexMap::<&str, Vec<_>>::new();
+    for frame in &frames {
+        let scan = &scans[&frame.scan];
+        let tolerance = params.calibration_tolerance;
+        if let Some(center) = detect_sphere(scan, frame, radius, tolerance) {
+            let angle = fm::camera_angle(scan, frame);
+            let scan_observations = observations.entry(&frame.scan);
+            scan_observations.or_default().push((angle, center));
+        }
+    }
+
+    for (name, scan) in &scans {
+        let scan_observations = observations.get(name.as_str());
+        let scan_observations = scan_observations.map_or(&[][..], |o| o);
+        let height = params.calibration_sphere_height;
+        let (rotation, eye, error) =
+            solve_camera_pose(scan_observations, height).ok_or_else(|| {
+                let desc = format!(
+                    "failed to calibrate scan '{}' by {} sphere detections",
+                    name,
+                    scan_observations.len()
+                );
+                Error::new(GeometryError, desc)
+            })?;
+        info!(
+            "calibrated scan '{}' by {} sphere detections (RMS error {:.4})",
+            name,
+            scan_observations.len(),
+            error
+        );
+
+        let mut scan = scan.clone();
+        set_camera_pose(&mut scan, &rotation, &eye)?;
+        writer.write_record(&fm::Record {
+            r#type: Some(fm::record::Type::Scan(scan)),
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::camera_view_rotation;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    // Depth frame of sphere of radius 0.08 at (0.15, 0, 0.1) on turntable.
+    fn render_sphere_frame(scan: &fm::Scan, time: i64) -> fm::Record {
+        let frame = fm::ScanFrame {
+            scan: scan.name.clone(),
+            time,
+            ..Default::default()
+        };
+        let angle = fm::camera_angle(scan, &frame);
+        let rotation = camera_view_rotation(scan).unwrap();
+        let rotation = rotation.fixed_slice::<3, 3>(0, 0).into_owned();
+        let p = scan.camera_initial_position.unwrap();
+        let eye = Vector3::new(p.x as f64, p.y as f64, p.z as f64);
+        let sphere = Vector3::new(0.15 * angle.cos(), -0.15 * angle.sin(), 0.1);
+        let center = rotation.transpose() * (sphere - eye);
+
+        let (width, height) =
+            (scan.depth_width as usize, scan.depth_height as usize);
+        let tan = (scan.camera_angle_of_view as f64 / 2.0).tan();
+        let mut depths = Vec::with_capacity(width * height);
+        for i in 0..height {
+            for j in 0..width {
+                let ray =
+                    unproject_depth(scan, tan, (width, height), (i, j), 1.0);
+                let (a, b) = (ray.norm_squared(), ray.dot(&center));
+                let disc = b * b - a * (center.norm_squared() - 0.08 * 0.08);
+                depths.push(if disc >= 0.0 {
+                    ((b - disc.sqrt()) / a) as f32
+                } else {
+                    f32::NAN
+                });
+            }
+        }
+
+        new_scan_frame_rec(fm::ScanFrame { depths, ..frame })
+    }
+
+    #[test]
+    fn test_calibrate_rig() {
+        let scan = fm::Scan {
+            name: "a".to_string(),
+            camera_angle_of_view: 1.0,
+            camera_up_angle: 0.1,
+            camera_angular_velocity: 1.0,
+            camera_initial_position: Some(new_point3(0.0, -1.0, 0.3)),
+            camera_initial_direction: Some(new_point3(0.0, 0.0, 0.1)),
+            depth_width: 128,
+            depth_height: 96,
+            sensor_plane_depth: true,
+            ..Default::default()
+        };
+
+        let mut records = vec![new_scan_rec(fm::Scan {
+            camera_initial_position: Some(new_point3(0.0, -2.0, 0.0)),
+            camera_up_angle: 0.0,
+            ..scan.clone()
+        })];
+        for i in 0..12 {
+            records.push(render_sphere_frame(&scan, i * 500_000_000));
+        }
+
+        let mut reader = create_reader_with_records(&records);
+        let mut writer = create_writer();
+        let scan_params = ScanParams::from_iter(&["test"]);
+        let params = CalibrateRigParams {
+            calibration_sphere_radius: 0.08,
+            calibration_sphere_height: 0.1,
+            calibration_tolerance: 0.003,
+        };
+        calibrate_rig(&mut reader, &mut writer, &scan_params, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let calibrated = record_variant!(Scan, rec);
+        assert!(reader.read_record().unwrap().is_none());
+
+        let eye = calibrated.camera_initial_position.unwrap();
+        assert!((eye.x - 0.0).abs() < 1e-3);
+        assert!((eye.y + 1.0).abs() < 1e-3);
+        assert!((eye.z - 0.3).abs() < 1e-3);
+        assert!((calibrated.camera_up_angle - 0.1).abs() < 1e-3);
+
+        let rotation = camera_view_rotation(&scan).unwrap();
+        let calibrated_rotation = camera_view_rotation(&calibrated).unwrap();
+        assert!((rotation - calibrated_rotation).abs().max() < 1e-3);
+    }
+}
@@ -0,0 +1,233 @@
+use log::warn;
+use structopt::StructOpt;
+
+use crate::mesh::Mesh;
+use crate::misc::sphere_directions;
+use crate::point_cloud::{Point3, Vector3};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Generate convex collision mesh of element")]
+pub struct CollisionMeshCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(help = "Element to build collision mesh for", long)]
+    element: String,
+
+    #[structopt(flatten)]
+    params: CollisionMeshParams,
+}
+
+impl CollisionMeshCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        collision_mesh(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.element,
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct CollisionMeshParams {
+    #[structopt(
+        help = "Number of convex parts to decompose element into",
+        long,
+        default_value = "1"
+    )]
+    pub collision_num_parts: usize,
+
+    #[structopt(
+        help = "Maximal number of vertices per convex part",
+        long,
+        default_value = "64"
+    )]
+    pub collision_max_vertices: usize,
+}
+
+// Points are taken as extreme ones along evenly distributed directions,
+// which bounds the hull complexity while keeping it inscribed.
+fn select_extreme_points(points: &[Point3], num: usize) -> Vec<Point3> {
+    let mut indices: Vec<_> = sphere_directions(num)
+        .into_iter()
+        .filter_map(|dir| {
+            (0..points.len()).max_by(|&a, &b| {
+                let (a, b) = (points[a].coords, points[b].coords);
+                a.dot(&dir).total_cmp(&b.dot(&dir))
+            })
+        })
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices.into_iter().map(|i| points[i]).collect()
+}
+
+fn bounding_box_extent(points: &[Point3]) -> Vector3 {
+    let mut min = Vector3::repeat(f64::MAX);
+    let mut max = Vector3::repeat(f64::MIN);
+    for p in points {
+        min = min.inf(&p.coords);
+        max = max.sup(&p.coords);
+    }
+    max - min
+}
+
+// Splits the biggest part in halves along its longest axis until the
+// requested number of parts is reached.
+fn decompose(points: Vec<Point3>, num_parts: usize) -> Vec<Vec<Point3>> {
+    let mut parts = vec![points];
+    while parts.len() < num_parts {
+        let volume = |p: &Vec<Point3>| bounding_box_extent(p).product();
+        let i = match parts
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.len() >= 8)
+            .max_by(|(_, a), (_, b)| volume(a).total_cmp(&volume(b)))
+        {
+            Some((i, _)) => i,
+            None => break,
+        };
+
+        let mut part = parts.swap_remove(i);
+        let axis = bounding_box_extent(&part).imax();
+        part.sort_by(|a, b| a[axis].total_cmp(&b[axis]));
+        let half = part.split_off(part.len() / 2);
+        parts.push(part);
+        parts.push(half);
+    }
+    parts
+}
+
+pub fn collision_mesh(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    element: &str,
+    params: &CollisionMeshParams,
+) -> Result<()> {
+    let mut records = Vec::new();
+    let mut state = None;
+
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        if let Some(ElementViewState(s)) = &rec.r#type {
+            if s.element == element && state.is_none() {
+                state = Some(s.clone());
+            }
+        }
+        records.push(rec);
+    }
+
+    let state = state.ok_or_else(|| {
+        let desc = format!("no states for element '{}'", element);
+        Error::new(InconsistentState, desc)
+    })?;
+
+    let points: Vec<_> = state
+        .vertices
+        .iter()
+        .map(|p| Point3::new(p.x as f64, p.y as f64, p.z as f64))
+        .collect();
+
+    let parts = decompose(points, params.collision_num_parts.max(1));
+    let mut hulls = Vec::with_capacity(parts.len());
+    for (i, part) in parts.iter().enumerate() {
+        let points = select_extreme_points(part, params.collision_max_vertices);
+        if let Some(hull) = Mesh::convex_hull(&points) {
+            hulls.push(hull);
+        } else {
+            warn!("skipped degenerate part {} of element '{}'", i, element);
+        }
+    }
+
+    let mut collision = Vec::with_capacity(hulls.len() * 2);
+    for (i, hull) in hulls.iter().enumerate() {
+        let name = if hulls.len() == 1 {
+            format!("{}-collision", element)
+        } else {
+            format!("{}-collision-{}", element, i)
+        };
+
+        use fm::record::Type::*;
+        let (view, mut hull_state) = hull.to_element(name);
+        hull_state.time = state.time;
+        collision.push(fm::Record {
+            r#type: Some(ElementView(view)),
+        });
+        collision.push(fm::Record {
+            r#type: Some(ElementViewState(hull_state)),
+        });
+    }
+
+    let streams: Vec<Box<dyn Iterator<Item = Result<fm::Record>>>> = vec![
+        Box::new(records.into_iter().map(Ok)),
+        Box::new(collision.into_iter().map(Ok)),
+    ];
+    for rec in fm::merge_sorted_by_time(streams) {
+        writer.write_record(&rec?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    #[test]
+    fn test_collision_mesh() {
+        let mut vertices = Vec::new();
+        for i in 0..27 {
+            let (x, y, z) = (i % 3, i / 3 % 3, i / 9);
+            vertices.push(new_point3(x as f32, y as f32, z as f32));
+        }
+        let mut reader =
+            create_reader_with_records(&[new_element_view_state_rec(
+                fm::ElementViewState {
+                    element: "a".to_string(),
+                    time: 5,
+                    vertices,
+                    ..Default::default()
+                },
+            )]);
+
+        let mut writer = create_writer();
+        let params = CollisionMeshParams {
+            collision_num_parts: 1,
+            collision_max_vertices: 64,
+        };
+        collision_mesh(&mut reader, &mut writer, "a", &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let view = record_variant!(ElementView, rec);
+        assert_eq!(view.element, "a-collision");
+        assert_eq!(view.faces.len(), 12);
+
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(ElementViewState, rec).element, "a");
+
+        let rec = reader.read_record().unwrap().unwrap();
+        let state = record_variant!(ElementViewState, rec);
+        assert_eq!(state.element, "a-collision");
+        assert_eq!(state.time, 5);
+        assert_eq!(state.vertices.len(), 8);
+        for v in state.vertices {
+            for c in [v.x, v.y, v.z] {
+                assert!(c == 0.0 || c == 2.0);
+            }
+        }
+        assert!(reader.read_record().unwrap().is_none());
+    }
+}
@@ -1,12 +1,20 @@
 use std::cmp::{Eq, Ord, Ordering, Ordering::*, PartialEq, PartialOrd};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
+use std::str::FromStr;
 
+use log::warn;
+use serde::Deserialize;
 use structopt::StructOpt;
 
-use base::defs::Result;
+use crate::dry_run::{format_size, output_location, Plan};
+use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
 use base::util::cli;
 use base::util::cli::{parse_key_val, Array as CliArray};
+use base::util::fs;
 
 #[derive(StructOpt)]
 #[structopt(about = "Combine multiple .fm files")]
@@ -19,23 +27,33 @@ pub struct CombineCommand {
 
     #[structopt(flatten)]
     params: CombineParams,
+
+    #[structopt(help = "Print execution plan without running it", long)]
+    dry_run: bool,
 }
 
 impl CombineCommand {
     pub fn run(&self) -> Result<()> {
         let mut readers = self.inputs.get()?;
-        let mut writer = self.output.get()?;
 
         let mut reader_refs: Vec<&mut dyn fm::Read> = Vec::new();
         for reader in &mut readers {
             reader_refs.push(reader.as_mut());
         }
 
+        if self.dry_run {
+            let output = output_location(&self.output);
+            let plan = plan_combine(&mut reader_refs, &output, &self.params)?;
+            plan.print();
+            return Ok(());
+        }
+        let mut writer = self.output.get()?;
+
         combine(&mut reader_refs, writer.as_mut(), &self.params)
     }
 }
 
-#[derive(StructOpt)]
+#[derive(Debug, StructOpt)]
 pub struct CombineParams {
     #[structopt(
         help="Element displacement in form 'element=dx,dy,dz'",
@@ -64,52 +82,269 @@ pub struct CombineParams {
         short = "s"
     )]
     scalings: Vec<(String, f32)>,
+
+    #[structopt(
+        help = concat!("Element affine transform in form ",
+            "'element=m11,m12,...,m44' (4x4 matrix in row-major order, ",
+            "applied after displacement, rotation and scaling)"),
+        long = "matrix",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    matrices: Vec<(String, CliArray<f32, 16>)>,
+
+    #[structopt(
+        help = concat!("JSON or YAML file mapping elements to affine ",
+            "transforms (4x4 matrices given as lists of 4 rows, same as ",
+            "--matrix)"),
+        long
+    )]
+    transform_file: Option<PathBuf>,
+
+    #[structopt(
+        help = concat!("Element renaming in form 'old=new' applied after ",
+            "--prefix (e.g. '2-model=shirt' with --prefix=file-index), ",
+            "transforms refer to new names"),
+        long = "rename",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    renames: Vec<(String, String)>,
+
+    #[structopt(
+        help = concat!("Prefix of element names to tell apart equally ",
+            "named elements of inputs: file-index (e.g. '2-model' for ",
+            "element 'model' of input 2)"),
+        long
+    )]
+    prefix: Option<ElementPrefix>,
+
+    #[structopt(
+        help = concat!("Allow merging scan and model records (ordered as ",
+            "previews, views and scans, then states and frames by time, ",
+            "then refinements)"),
+        long
+    )]
+    allow_mixed: bool,
+
+    #[structopt(
+        help = concat!("Record kinds to keep from input in form ",
+            "'input=kind,...' (inputs are numbered from 1)"),
+        long = "keep",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    keeps: Vec<(usize, RecordKinds)>,
+
+    #[structopt(
+        allow_hyphen_values = true,
+        help = concat!("Time offset added to input records in form ",
+            "'input=offset' (e.g. '2=-1.5s', inputs are numbered from 1)"),
+        long = "sync",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    syncs: Vec<(usize, fm::Time)>,
+
+    #[structopt(
+        conflicts_with = "syncs",
+        help = "Align first state or frame times of inputs with each other",
+        long
+    )]
+    auto_sync: bool,
+
+    #[structopt(
+        allow_hyphen_values = true,
+        help = concat!("Time offset added to states and textures of ",
+            "element (or frames of scan) in form 'element=offset' (e.g. ",
+            "'shirt=-1.5s' or 'shirt=-1500000000', applied after renaming ",
+            "and --sync-start; use --order=spec-compliant to reorder ",
+            "shifted records within inputs)"),
+        long = "time-offset",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    time_offsets: Vec<(String, fm::Time)>,
+
+    #[structopt(
+        conflicts_with_all = &["syncs", "auto-sync"],
+        help = concat!("Shift each element and scan to start at the ",
+            "earliest first state, texture or frame time of all of them ",
+            "(reads inputs ahead)"),
+        long
+    )]
+    sync_start: bool,
+
+    #[structopt(
+        help = concat!("Output record order: chronological (merging inputs ",
+            "by time), spec-compliant (buffering records to put them into ",
+            "the canonical order) or by-input"),
+        long,
+        default_value = "chronological"
+    )]
+    order: RecordOrder,
 }
 
-type Point3 = nalgebra::Point3<f32>;
-type Quaternion = nalgebra::UnitQuaternion<f32>;
-type Vector3 = nalgebra::Vector3<f32>;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordOrder {
+    // Previews, then views and scans, then states and frames by time, then
+    // refinements, whatever the order of input records.
+    SpecCompliant,
+    // Merges inputs by their current records, which keeps the canonical
+    // order of canonically ordered inputs.
+    Chronological,
+    // Inputs one after another.
+    ByInput,
+}
 
-pub fn combine(
-    readers: &mut [&mut dyn fm::Read],
-    writer: &mut dyn fm::Write,
-    params: &CombineParams,
-) -> Result<()> {
-    let mut items = Vec::new();
-    for reader in readers.iter_mut() {
-        items.push(Item(reader.read_record()?));
+impl FromStr for RecordOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "spec-compliant" => Ok(RecordOrder::SpecCompliant),
+            "chronological" => Ok(RecordOrder::Chronological),
+            "by-input" => Ok(RecordOrder::ByInput),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown record order".to_string(),
+            )),
+        }
     }
+}
 
-    loop {
-        let (i, _) = items.iter().enumerate().min_by_key(|i| i.1).unwrap();
-        let item = &mut items[i]; // How to obtain &mut Item in above?
-        if item.0.is_none() {
-            break;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ElementPrefix {
+    // Input number (counted from 1) followed by a hyphen.
+    FileIndex,
+}
+
+impl FromStr for ElementPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "file-index" => Ok(ElementPrefix::FileIndex),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown element prefix (can be 'file-index')".to_string(),
+            )),
         }
+    }
+}
 
-        if let Some(fm::record::Type::ElementViewState(state)) =
-            &mut item.0.as_mut().unwrap().r#type
-        {
-            if let Some((_, disp)) = params
-                .displacements
-                .iter()
-                .find(|(e, _)| e == &state.element)
-            {
-                for i in 0..state.vertices.len() {
-                    state.vertices[i].x += disp.0[0];
-                    state.vertices[i].y += disp.0[1];
-                    state.vertices[i].z += disp.0[2];
-                }
+#[derive(Debug)]
+pub struct RecordKinds(Vec<fm::RecordKind>);
 
-                for i in 0..state.normals.len() {
-                    state.normals[i].x += disp.0[0];
-                    state.normals[i].y += disp.0[1];
-                    state.normals[i].z += disp.0[2];
-                }
+impl FromStr for RecordKinds {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(fm::RecordKind::from_str)
+            .collect::<Result<_>>()
+            .map(RecordKinds)
+    }
+}
+
+impl CombineParams {
+    fn validate(&self, num_inputs: usize) -> Result<()> {
+        let keeps = self.keeps.iter().map(|(i, _)| ("--keep", *i));
+        let syncs = self.syncs.iter().map(|(i, _)| ("--sync", *i));
+        for (flag, input) in keeps.chain(syncs) {
+            if input == 0 || input > num_inputs {
+                let desc = format!(
+                    "{} refers to input {} while there are {} inputs",
+                    flag, input, num_inputs
+                );
+                return Err(Error::new(BadOperation, desc));
+            }
+        }
+        let mut renamed = HashSet::new();
+        for (old, _) in &self.renames {
+            if !renamed.insert(old) {
+                let desc = format!("element '{}' is renamed twice", old);
+                return Err(Error::new(BadOperation, desc));
             }
+        }
+        let mut shifted = HashSet::new();
+        for (element, _) in &self.time_offsets {
+            if !shifted.insert(element) {
+                let desc =
+                    format!("multiple time offsets for element '{}'", element);
+                return Err(Error::new(BadOperation, desc));
+            }
+        }
+        Ok(())
+    }
 
+    // Output name of input element.
+    fn element_name(&self, input: usize, element: &str) -> String {
+        let name = match self.prefix {
+            Some(ElementPrefix::FileIndex) => {
+                format!("{}-{}", input + 1, element)
+            }
+            None => element.to_string(),
+        };
+        match self.renames.iter().find(|(old, _)| *old == name) {
+            Some((_, new)) => new.clone(),
+            None => name,
+        }
+    }
+
+    // Renames the record element, returns whether its name has changed.
+    fn rename_element(&self, input: usize, record: &mut fm::Record) -> bool {
+        use fm::record::Type::*;
+        let element = match &mut record.r#type {
+            Some(ElementView(v)) => &mut v.element,
+            Some(ElementViewState(s)) => &mut s.element,
+            Some(ElementTexture(t)) => &mut t.element,
+            Some(ElementViewRefinement(r)) => &mut r.element,
+            _ => return false,
+        };
+        let name = self.element_name(input, element);
+        if name == *element {
+            return false;
+        }
+        *element = name;
+        true
+    }
+
+    // Composes displacement, rotation, scaling and matrix of each
+    // transformed element.
+    fn transforms(&self) -> Result<BTreeMap<String, Transform>> {
+        let mut matrices = match &self.transform_file {
+            Some(path) => read_transform_file(path)?,
+            None => BTreeMap::new(),
+        };
+        for (element, matrix) in &self.matrices {
+            let matrix = Matrix4::from_row_slice(&matrix.0);
+            if matrices.insert(element.clone(), matrix).is_some() {
+                let desc =
+                    format!("multiple matrices for element '{}'", element);
+                return Err(Error::new(BadOperation, desc));
+            }
+        }
+
+        let elements: BTreeSet<_> = self
+            .displacements
+            .iter()
+            .map(|(e, _)| e)
+            .chain(self.rotations.iter().map(|(e, _)| e))
+            .chain(self.scalings.iter().map(|(e, _)| e))
+            .chain(matrices.keys())
+            .collect();
+
+        let mut transforms = BTreeMap::new();
+        for element in elements {
+            let mut matrix = Matrix4::identity();
+            if let Some((_, disp)) =
+                self.displacements.iter().find(|(e, _)| e == element)
+            {
+                matrix = Matrix4::new_translation(&Vector3::from(disp.0));
+            }
             if let Some((_, rot)) =
-                params.rotations.iter().find(|(e, _)| e == &state.element)
+                self.rotations.iter().find(|(e, _)| e == element)
             {
                 let x_quat =
                     Quaternion::from_axis_angle(&Vector3::x_axis(), rot.0[0]);
@@ -118,43 +353,675 @@ pub fn combine(
                 let z_quat =
                     Quaternion::from_axis_angle(&Vector3::z_axis(), rot.0[2]);
                 let quat = x_quat * y_quat * z_quat;
+                matrix = quat.to_homogeneous() * matrix;
+            }
+            if let Some((_, scale)) =
+                self.scalings.iter().find(|(e, _)| e == element)
+            {
+                matrix = matrix.append_scaling(*scale);
+            }
+            if let Some(user_matrix) = matrices.get(element) {
+                matrix = user_matrix * matrix;
+            }
+            transforms
+                .insert(element.clone(), Transform::new(element, matrix)?);
+        }
+        Ok(transforms)
+    }
 
-                for i in 0..state.vertices.len() {
-                    let p = state.vertices[i];
-                    let p = quat * Point3::new(p.x, p.y, p.z);
-                    state.vertices[i] = point3_to_fm_point3(&p);
+    // Computes time offsets of inputs given times of their first kept
+    // states or frames (used for --auto-sync only).
+    fn offsets(&self, first_times: &[Option<fm::Time>]) -> Vec<fm::Time> {
+        let mut offsets = vec![fm::Time(0); first_times.len()];
+        for (input, offset) in &self.syncs {
+            offsets[input - 1] += *offset;
+        }
+        if self.auto_sync {
+            if let Some(base) = first_times.iter().flatten().next() {
+                for (offset, time) in offsets.iter_mut().zip(first_times) {
+                    if let Some(time) = time {
+                        *offset = *base - *time;
+                    }
                 }
+            }
+        }
+        offsets
+    }
 
-                for i in 0..state.normals.len() {
-                    let p = state.normals[i];
-                    let p = quat * Point3::new(p.x, p.y, p.z);
-                    state.normals[i] = point3_to_fm_point3(&p);
-                }
+    // Output name of element (or scan) of timed record and its time.
+    fn timeline(
+        &self,
+        input: usize,
+        record: &fm::Record,
+    ) -> Option<(String, fm::Time)> {
+        use fm::record::Type::*;
+        match &record.r#type {
+            Some(ElementViewState(s)) => {
+                Some((self.element_name(input, &s.element), fm::Time(s.time)))
+            }
+            Some(ElementTexture(t)) => {
+                Some((self.element_name(input, &t.element), fm::Time(t.time)))
             }
+            Some(ScanFrame(f)) => Some((f.scan.clone(), fm::Time(f.time))),
+            _ => None,
+        }
+    }
 
-            if let Some((_, scale)) =
-                params.scalings.iter().find(|(e, _)| e == &state.element)
+    // Output name of element (or scan) whose timeline record belongs to,
+    // including views holding loops over it.
+    fn timeline_name(
+        &self,
+        input: usize,
+        record: &fm::Record,
+    ) -> Option<String> {
+        match &record.r#type {
+            Some(fm::record::Type::ElementView(v)) => {
+                Some(self.element_name(input, &v.element))
+            }
+            _ => self.timeline(input, record).map(|(name, _)| name),
+        }
+    }
+
+    // Updates first times of elements and scans with kept record.
+    fn add_first_time(
+        &self,
+        input: usize,
+        record: &fm::Record,
+        first_times: &mut BTreeMap<String, fm::Time>,
+    ) {
+        if !self.keeps(input, fm::RecordKind::of(record)) {
+            return;
+        }
+        if let Some((name, time)) = self.timeline(input, record) {
+            let first = first_times.entry(name).or_insert(time);
+            *first = (*first).min(time);
+        }
+    }
+
+    // Computes time offsets of elements and scans given their first times
+    // (used for --sync-start only).
+    fn element_offsets(
+        &self,
+        first_times: &BTreeMap<String, fm::Time>,
+    ) -> BTreeMap<String, fm::Time> {
+        let mut offsets = BTreeMap::new();
+        if let (true, Some(&start)) =
+            (self.sync_start, first_times.values().min())
+        {
+            for (name, time) in first_times {
+                offsets.insert(name.clone(), start - *time);
+            }
+        }
+        for (name, offset) in &self.time_offsets {
+            *offsets.entry(name.clone()).or_default() += *offset;
+        }
+        offsets.retain(|_, offset| offset.0 != 0);
+        offsets
+    }
+
+    fn keeps(&self, input: usize, kind: Option<fm::RecordKind>) -> bool {
+        let kinds: Vec<_> = self
+            .keeps
+            .iter()
+            .filter(|(i, _)| *i == input + 1)
+            .flat_map(|(_, kinds)| kinds.0.iter())
+            .collect();
+        kinds.is_empty() || matches!(kind, Some(k) if kinds.contains(&&k))
+    }
+}
+
+// Detects inputs mixing scan and model records.
+#[derive(Default)]
+struct MixedKindsCheck {
+    first: Option<(usize, fm::RecordKind)>,
+}
+
+impl MixedKindsCheck {
+    fn check(&mut self, input: usize, kind: fm::RecordKind) -> Result<()> {
+        match self.first {
+            None => {
+                self.first = Some((input, kind));
+                Ok(())
+            }
+            Some((first_input, first_kind))
+                if first_kind.is_scan() != kind.is_scan() =>
             {
-                for i in 0..state.vertices.len() {
-                    state.vertices[i].x *= scale;
-                    state.vertices[i].y *= scale;
-                    state.vertices[i].z *= scale;
+                let desc = format!(
+                    "input {} has {:?} records while input {} has {:?} ones \
+                     (use --allow-mixed or --keep to merge them)",
+                    input + 1,
+                    kind,
+                    first_input + 1,
+                    first_kind
+                );
+                Err(Error::new(InconsistentState, desc))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Affine transform of element vertices and its counterpart for normals.
+struct Transform {
+    matrix: Matrix4,
+    normal_matrix: Matrix3,
+}
+
+impl Transform {
+    fn new(element: &str, matrix: Matrix4) -> Result<Transform> {
+        let last_row = matrix.row(3);
+        if last_row != Matrix4::identity().row(3) {
+            let desc =
+                format!("transform of element '{}' is not affine", element);
+            return Err(Error::new(BadOperation, desc));
+        }
+        // Normals are transformed by the inverse transpose to stay
+        // perpendicular to surfaces under non-uniform scaling and shear.
+        let normal_matrix = matrix
+            .fixed_slice::<3, 3>(0, 0)
+            .try_inverse()
+            .ok_or_else(|| {
+                let desc =
+                    format!("transform of element '{}' is degenerate", element);
+                Error::new(BadOperation, desc)
+            })?
+            .transpose();
+        Ok(Transform {
+            matrix,
+            normal_matrix,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct TransformFile(BTreeMap<String, [[f32; 4]; 4]>);
+
+// Reads element matrices from JSON or YAML file (JSON being a subset of
+// YAML).
+fn read_transform_file(path: &Path) -> Result<BTreeMap<String, Matrix4>> {
+    let text = fs::read_file_to_string(path)?;
+    let file: TransformFile = serde_yaml::from_str(&text).map_err(|err| {
+        let desc =
+            format!("malformed transform file '{}': {}", path.display(), err);
+        Error::with_source(MalformedData, desc, err)
+    })?;
+    Ok(file
+        .0
+        .into_iter()
+        .map(|(element, rows)| {
+            let values: Vec<_> = rows.iter().flatten().copied().collect();
+            (element, Matrix4::from_row_slice(&values))
+        })
+        .collect())
+}
+
+type Matrix3 = nalgebra::Matrix3<f32>;
+type Matrix4 = nalgebra::Matrix4<f32>;
+type Point3 = nalgebra::Point3<f32>;
+type Quaternion = nalgebra::UnitQuaternion<f32>;
+type Vector3 = nalgebra::Vector3<f32>;
+
+pub fn combine(
+    readers: &mut [&mut dyn fm::Read],
+    writer: &mut dyn fm::Write,
+    params: &CombineParams,
+) -> Result<()> {
+    params.validate(readers.len())?;
+    let transforms = params.transforms()?;
+
+    // Records read ahead to find first times for --auto-sync.
+    let mut buffers: Vec<_> = readers.iter().map(|_| VecDeque::new()).collect();
+    let mut first_times = vec![None; readers.len()];
+    if params.auto_sync {
+        for (i, reader) in readers.iter_mut().enumerate() {
+            first_times[i] =
+                read_first_time(*reader, &mut buffers[i], |kind| {
+                    params.keeps(i, kind)
+                })?;
+        }
+    }
+    let offsets = params.offsets(&first_times);
+
+    // All records are read ahead to find first times for --sync-start.
+    let mut first_element_times = BTreeMap::new();
+    if params.sync_start {
+        for (i, reader) in readers.iter_mut().enumerate() {
+            read_all(*reader, &mut buffers[i])?;
+            let records =
+                buffers[i].iter().filter_map(|item| item.record.as_ref());
+            for record in records {
+                params.add_first_time(i, record, &mut first_element_times);
+            }
+        }
+    }
+    let element_offsets = params.element_offsets(&first_element_times);
+    let shift = |input: usize, record: &fm::Record| {
+        let element_offset = match element_offsets.is_empty() {
+            true => None,
+            false => params
+                .timeline_name(input, record)
+                .and_then(|name| element_offsets.get(&name)),
+        };
+        offsets[input] + element_offset.copied().unwrap_or_default()
+    };
+
+    let mut mixed_check = MixedKindsCheck::default();
+    let mut duplicate_check = DuplicateElementsCheck::default();
+    let mut order_check = OrderCheck::default();
+    let mut write = |input: usize, mut item: Item| -> Result<()> {
+        let record = item.record.as_ref().unwrap();
+        let kind = fm::RecordKind::of(record);
+        if !params.keeps(input, kind) {
+            return Ok(());
+        }
+        if let (Some(kind), false) = (kind, params.allow_mixed) {
+            mixed_check.check(input, kind)?;
+        }
+        order_check.add(record);
+
+        // Records are decoded fully only to be modified, the others are
+        // copied as is to avoid re-encoding.
+        item.modify(|record| params.rename_element(input, record))?;
+        duplicate_check.check(input, item.record.as_ref().unwrap())?;
+        item.modify(|record| transform_record(record, &transforms))?;
+        match &item.record {
+            Some(record) if item.modified => writer.write_record(record),
+            _ => writer.write_raw_record(&fm::RawRecord::new(&item.raw)),
+        }
+    };
+
+    match params.order {
+        RecordOrder::SpecCompliant => {
+            let mut items = Vec::new();
+            for (i, reader) in readers.iter_mut().enumerate() {
+                loop {
+                    let item =
+                        next_item(*reader, &mut buffers[i], |r| shift(i, r))?;
+                    match &item.record {
+                        None => break,
+                        Some(r) if params.keeps(i, fm::RecordKind::of(r)) => {
+                            items.push((i, item))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // Stable, so that equal records keep their input order.
+            items.sort_by(|(_, a), (_, b)| a.cmp(b));
+            for (i, item) in items {
+                write(i, item)?;
+            }
+        }
+        RecordOrder::Chronological => {
+            let mut items = Vec::new();
+            for (i, reader) in readers.iter_mut().enumerate() {
+                items.push(next_item(*reader, &mut buffers[i], |r| {
+                    shift(i, r)
+                })?);
+            }
+            loop {
+                let (i, _) =
+                    items.iter().enumerate().min_by_key(|i| i.1).unwrap();
+                if items[i].record.is_none() {
+                    break;
+                }
+                let next =
+                    next_item(readers[i], &mut buffers[i], |r| shift(i, r))?;
+                write(i, mem::replace(&mut items[i], next))?;
+            }
+        }
+        RecordOrder::ByInput => {
+            for (i, reader) in readers.iter_mut().enumerate() {
+                loop {
+                    let item =
+                        next_item(*reader, &mut buffers[i], |r| shift(i, r))?;
+                    if item.record.is_none() {
+                        break;
+                    }
+                    write(i, item)?;
                 }
+            }
+        }
+    }
+
+    if order_check.num_violations > 0 {
+        let desc = format!(
+            "{} output records break the canonical order",
+            order_check.num_violations
+        );
+        if params.order == RecordOrder::SpecCompliant {
+            return Err(Error::new(InconsistentState, desc));
+        }
+        warn!("{} (see --order)", desc);
+    }
+    Ok(())
+}
+
+// Transforms geometry of state or refinement, returns false if its element
+// has no transform.
+fn transform_record(
+    record: &mut fm::Record,
+    transforms: &BTreeMap<String, Transform>,
+) -> bool {
+    use fm::record::Type::*;
+    match &mut record.r#type {
+        Some(ElementViewState(state)) => match transforms.get(&state.element) {
+            Some(transform) => transform_state(state, transform),
+            None => return false,
+        },
+        Some(ElementViewRefinement(refinement)) => {
+            match transforms.get(&refinement.element) {
+                Some(transform) => transform_refinement(refinement, transform),
+                None => return false,
+            }
+        }
+        _ => return false,
+    }
+    true
+}
+
+fn transform_point(point: &mut fm::Point3, transform: &Transform) {
+    let p = Point3::new(point.x, point.y, point.z);
+    *point = point3_to_fm_point3(&transform.matrix.transform_point(&p));
+}
+
+fn transform_normal(normal: &mut fm::Point3, transform: &Transform) {
+    let n = Vector3::new(normal.x, normal.y, normal.z);
+    let n = (transform.normal_matrix * n)
+        .try_normalize(0.0)
+        .unwrap_or_else(Vector3::zeros);
+    *normal = point3_to_fm_point3(&n.into());
+}
+
+fn transform_state(state: &mut fm::ElementViewState, transform: &Transform) {
+    for vertex in &mut state.vertices {
+        transform_point(vertex, transform);
+    }
+    for normal in &mut state.normals {
+        transform_normal(normal, transform);
+    }
+}
+
+fn transform_refinement(
+    refinement: &mut fm::ElementViewRefinement,
+    transform: &Transform,
+) {
+    for vertex in &mut refinement.vertices {
+        if let Some(point) = &mut vertex.vertex {
+            transform_point(point, transform);
+        }
+        if let Some(normal) = &mut vertex.normal {
+            transform_normal(normal, transform);
+        }
+    }
+}
+
+// Detects elements of the same name (after renaming) having multiple views.
+#[derive(Default)]
+struct DuplicateElementsCheck {
+    inputs: HashMap<String, usize>,
+}
+
+impl DuplicateElementsCheck {
+    fn check(&mut self, input: usize, record: &fm::Record) -> Result<()> {
+        let view = match &record.r#type {
+            Some(fm::record::Type::ElementView(view)) => view,
+            _ => return Ok(()),
+        };
+        match self.inputs.insert(view.element.clone(), input) {
+            None => Ok(()),
+            Some(first_input) => {
+                let desc = format!(
+                    "element '{}' of input {} duplicates the one of input {} \
+                     (use --rename or --prefix to tell them apart)",
+                    view.element,
+                    input + 1,
+                    first_input + 1
+                );
+                Err(Error::new(InconsistentState, desc))
+            }
+        }
+    }
+}
+
+// Counts records preceding the ones written before them in the canonical
+// order (see fm::record_order_key).
+#[derive(Default)]
+struct OrderCheck {
+    max_key: Option<(i8, fm::Time)>,
+    num_violations: usize,
+}
+
+impl OrderCheck {
+    fn add(&mut self, record: &fm::Record) {
+        let key = fm::record_order_key(record);
+        match self.max_key {
+            Some(max_key) if key < max_key => self.num_violations += 1,
+            _ => self.max_key = Some(key),
+        }
+    }
+}
+
+// Reads inputs to count their records, but writes nothing.
+pub fn plan_combine(
+    readers: &mut [&mut dyn fm::Read],
+    output: &str,
+    params: &CombineParams,
+) -> Result<Plan> {
+    params.validate(readers.len())?;
+    let transforms = params.transforms()?;
 
-                for i in 0..state.normals.len() {
-                    state.normals[i].x *= scale;
-                    state.normals[i].y *= scale;
-                    state.normals[i].z *= scale;
+    let mut plan = Plan::new(params);
+    let mut counts = BTreeMap::new();
+    let mut elements = HashSet::new();
+    let mut size = 0;
+    let mut mixed_check = MixedKindsCheck::default();
+    let mut mixed_err = None;
+    let mut duplicate_check = DuplicateElementsCheck::default();
+    let mut duplicate_err = None;
+    let mut renamed = Vec::new();
+    let mut first_times = vec![None; readers.len()];
+    let mut first_element_times = BTreeMap::new();
+    for (i, reader) in readers.iter_mut().enumerate() {
+        let (mut num_records, mut num_dropped) = (0, 0);
+        while let Some(raw) = reader.read_raw_record()? {
+            let mut record = raw.decode_header()?;
+            num_records += 1;
+            let kind = fm::RecordKind::of(&record);
+            if !params.keeps(i, kind) {
+                num_dropped += 1;
+                continue;
+            }
+            if let (None, (1, time)) =
+                (first_times[i], fm::record_order_key(&record))
+            {
+                first_times[i] = Some(time);
+            }
+            params.add_first_time(i, &record, &mut first_element_times);
+            let old_name = match &record.r#type {
+                Some(fm::record::Type::ElementView(v)) => {
+                    Some(v.element.clone())
                 }
+                _ => None,
+            };
+            params.rename_element(i, &mut record);
+            if let Some(fm::record::Type::ElementView(view)) = &record.r#type {
+                elements.insert(view.element.clone());
+                if let Some(old_name) = old_name.filter(|n| *n != view.element)
+                {
+                    renamed.push((i, old_name, view.element.clone()));
+                }
+            }
+            if let Err(err) = duplicate_check.check(i, &record) {
+                duplicate_err.get_or_insert(err);
             }
+            if let Some(kind) = kind {
+                *counts.entry(format!("{:?}", kind)).or_insert(0) += 1;
+                if let Err(err) = mixed_check.check(i, kind) {
+                    mixed_err.get_or_insert(err);
+                }
+            }
+            size += raw.as_bytes().len() as u64;
+        }
+        plan.stage(format!("read input #{} ({} records)", i + 1, num_records));
+        if num_dropped > 0 {
+            plan.stage(format!(
+                "drop {} records of input #{} (see --keep)",
+                num_dropped,
+                i + 1
+            ));
+        }
+    }
+    if let (Some(err), false) = (mixed_err, params.allow_mixed) {
+        plan.warn(err.description);
+    }
+    if let Some(err) = duplicate_err {
+        plan.warn(err.description);
+    }
+    for (i, old_name, new_name) in renamed {
+        plan.stage(format!(
+            "rename element '{}' of input #{} to '{}'",
+            old_name,
+            i + 1,
+            new_name
+        ));
+    }
+    let offsets = params.offsets(&first_times);
+    for (i, offset) in offsets.iter().enumerate() {
+        if offset.0 != 0 {
+            plan.stage(format!(
+                "shift times of input #{} by {}",
+                i + 1,
+                offset
+            ));
+        }
+    }
+    for (name, offset) in params.element_offsets(&first_element_times) {
+        if first_element_times.contains_key(&name) {
+            plan.stage(format!("shift times of '{}' by {}", name, offset));
+        } else {
+            plan.warn(format!("element '{}' has no timed records", name));
         }
+    }
+    plan.stage(match params.order {
+        RecordOrder::SpecCompliant => "sort records into canonical order",
+        RecordOrder::Chronological => "merge records by time",
+        RecordOrder::ByInput => "concatenate inputs",
+    });
 
-        writer.write_record(item.0.as_ref().unwrap())?;
+    for element in transforms.keys() {
+        if elements.contains(element) {
+            plan.stage(format!("transform states of element '{}'", element));
+        } else {
+            plan.warn(format!("element '{}' not found in inputs", element));
+        }
+    }
+    plan.stage("write merged records");
 
-        items[i] = Item(readers[i].read_record()?);
+    for (kind, count) in &counts {
+        plan.estimate(&format!("{} records", kind), count);
     }
+    plan.estimate("record data size", format_size(size));
+    let total: usize = counts.values().sum();
+    plan.output(format!("{}: {} records", output, total));
 
-    Ok(())
+    Ok(plan)
+}
+
+fn read_item(reader: &mut dyn fm::Read) -> Result<Item> {
+    Ok(match reader.read_raw_record()? {
+        Some(raw) => Item {
+            record: Some(raw.decode_header()?),
+            raw: raw.as_bytes().to_vec(),
+            limits: raw.limits(),
+            modified: false,
+        },
+        None => Item {
+            record: None,
+            raw: vec![],
+            limits: None,
+            modified: false,
+        },
+    })
+}
+
+// Reads records into buffer until the first kept state or frame is met.
+fn read_first_time<F: Fn(Option<fm::RecordKind>) -> bool>(
+    reader: &mut dyn fm::Read,
+    buffer: &mut VecDeque<Item>,
+    keeps: F,
+) -> Result<Option<fm::Time>> {
+    loop {
+        let item = read_item(reader)?;
+        let time = match &item.record {
+            None => None,
+            Some(record) if !keeps(fm::RecordKind::of(record)) => None,
+            Some(record) => match fm::record_order_key(record) {
+                (1, time) => Some(time),
+                _ => None,
+            },
+        };
+        let end = item.record.is_none();
+        buffer.push_back(item);
+        if time.is_some() || end {
+            return Ok(time);
+        }
+    }
+}
+
+// Reads all records into buffer.
+fn read_all(
+    reader: &mut dyn fm::Read,
+    buffer: &mut VecDeque<Item>,
+) -> Result<()> {
+    loop {
+        let item = read_item(reader)?;
+        let end = item.record.is_none();
+        buffer.push_back(item);
+        if end {
+            return Ok(());
+        }
+    }
+}
+
+// Takes next item from buffer or reader shifting its time (or loop bounds
+// of view) by offset of its record.
+fn next_item<F: Fn(&fm::Record) -> fm::Time>(
+    reader: &mut dyn fm::Read,
+    buffer: &mut VecDeque<Item>,
+    offset: F,
+) -> Result<Item> {
+    let mut item = match buffer.pop_front() {
+        Some(item) => item,
+        None => read_item(reader)?,
+    };
+    let offset = item.record.as_ref().map_or(fm::Time(0), offset);
+    if offset.0 != 0 {
+        item.modify(|record| shift_record(record, offset))?;
+    }
+    Ok(item)
+}
+
+// Shifts time (or loop bounds of view) of record, returns false if there
+// is none.
+fn shift_record(record: &mut fm::Record, offset: fm::Time) -> bool {
+    use fm::record::Type::*;
+    let offset = offset.0;
+    match record.r#type.as_mut() {
+        Some(ElementViewState(state)) => state.time += offset,
+        Some(ElementTexture(texture)) => texture.time += offset,
+        Some(ScanFrame(frame)) => frame.time += offset,
+        Some(ElementView(fm::ElementView {
+            animation_loop: Some(animation_loop),
+            ..
+        })) => {
+            animation_loop.start += offset;
+            animation_loop.end += offset;
+        }
+        _ => return false,
+    }
+    true
 }
 
 fn point3_to_fm_point3(p: &Point3) -> fm::Point3 {
@@ -165,7 +1032,44 @@ fn point3_to_fm_point3(p: &Point3) -> fm::Point3 {
     }
 }
 
-struct Item(Option<fm::Record>);
+// Record decoded partially (see RawRecord::decode_header) until modified,
+// along with its raw bytes which unmodified records are copied from.
+struct Item {
+    record: Option<fm::Record>,
+    raw: Vec<u8>,
+    limits: Option<fm::ReaderLimits>,
+    modified: bool,
+}
+
+impl Item {
+    // Applies modification returning whether it has changed the record,
+    // which is decoded fully to be changed.
+    fn modify<F: Fn(&mut fm::Record) -> bool>(
+        &mut self,
+        modify: F,
+    ) -> Result<bool> {
+        let record = match self.record.as_mut() {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        if self.modified {
+            return Ok(modify(record));
+        }
+        // Headers are small, so the modification is tried on a copy first.
+        if !modify(&mut record.clone()) {
+            return Ok(false);
+        }
+        let raw = match self.limits {
+            Some(limits) => fm::RawRecord::with_limits(&self.raw, limits),
+            None => fm::RawRecord::new(&self.raw),
+        };
+        let mut record = raw.decode()?;
+        modify(&mut record);
+        self.record = Some(record);
+        self.modified = true;
+        Ok(true)
+    }
+}
 
 impl Ord for Item {
     fn cmp(&self, other: &Self) -> Ordering {
@@ -182,36 +1086,10 @@ impl Ord for Item {
             }
         }
 
-        use fm::record::Type;
-
-        fn type_prio(r#type: &Type) -> i8 {
-            match r#type {
-                Type::ElementView(_) => 0,
-                Type::ElementViewState(_) => 1,
-                Type::Scan(_) => 0,
-                Type::ScanFrame(_) => 1,
-            }
-        }
-
-        fn type_time(r#type: &Type) -> fm::Time {
-            match r#type {
-                Type::ElementViewState(fm::ElementViewState {
-                    time, ..
-                })
-                | Type::ScanFrame(fm::ScanFrame { time, .. }) => *time,
-                _ => 0,
-            }
-        }
-
         fn cmp_items(a: &Item, b: &Item) -> StdResult<(), Ordering> {
-            let (this, that) = cmp_options(&a.0, &b.0)?;
-            let (this, that) = cmp_options(&this.r#type, &that.r#type)?;
-            let ordering = type_prio(this).cmp(&type_prio(that));
-            Err(if ordering == Equal {
-                type_time(this).cmp(&type_time(that))
-            } else {
-                ordering
-            })
+            let (this, that) = cmp_options(&a.record, &b.record)?;
+            cmp_options(&this.r#type, &that.r#type)?;
+            Err(fm::record_order_key(this).cmp(&fm::record_order_key(that)))
         }
 
         cmp_items(self, other).unwrap_err()
@@ -242,7 +1120,7 @@ mod tests {
 
     fn new_simple_element_view_rec(element: &str) -> fm::Record {
         new_element_view_rec(fm::ElementView {
-            element: format!("{}", element),
+            element: element.to_string(),
             ..Default::default()
         })
     }
@@ -252,18 +1130,17 @@ mod tests {
         time: i64,
     ) -> fm::Record {
         new_element_view_state_rec(fm::ElementViewState {
-            element: format!("{}", element),
-            time: time,
+            element: element.to_string(),
+            time,
             vertices: vec![new_point3(0.1, 0.2, 0.3)],
             normals: vec![new_point3(0.2, 0.3, 0.4)],
-            ..Default::default()
         })
     }
 
     #[test]
     fn test_combine_sanity() {
         let new_reader = |element, time| {
-            create_reader_with_records(&vec![
+            create_reader_with_records(&[
                 new_simple_element_view_rec(element),
                 new_simple_element_view_state_rec(element, time),
                 new_simple_element_view_state_rec(element, time + 2),
@@ -278,9 +1155,20 @@ mod tests {
             displacements: vec![("e2".to_string(), [0.3, 0.4, 0.5].into())],
             rotations: vec![("e1".to_string(), [0.6, 0.7, 0.8].into())],
             scalings: vec![("e1".to_string(), 2.0)],
+            matrices: vec![],
+            transform_file: None,
+            renames: vec![],
+            prefix: None,
+            allow_mixed: false,
+            keeps: vec![],
+            syncs: vec![],
+            auto_sync: false,
+            time_offsets: vec![],
+            sync_start: false,
+            order: RecordOrder::Chronological,
         };
         let mut writer = create_writer();
-        combine(&mut readers[..], &mut writer, &params).unwrap();
+        combine(&mut readers[..], &mut writer, params).unwrap();
 
         let mut reader = writer_to_reader(writer);
 
@@ -304,7 +1192,7 @@ mod tests {
         assert_eq!(state.normals.len(), 1);
         assert_eq_point3!(
             state.normals[0],
-            new_point3(0.39932388, 0.18115145, 0.9837299)
+            new_point3(0.37076293, 0.1681949, 0.9133703)
         );
 
         let rec = reader.read_record().unwrap().unwrap();
@@ -314,7 +1202,10 @@ mod tests {
         assert_eq!(state.vertices.len(), 1);
         assert_eq_point3!(state.vertices[0], new_point3(0.4, 0.6, 0.8));
         assert_eq!(state.normals.len(), 1);
-        assert_eq_point3!(state.normals[0], new_point3(0.5, 0.7, 0.9));
+        assert_eq_point3!(
+            state.normals[0],
+            new_point3(0.37139068, 0.557086, 0.74278134)
+        );
 
         let rec = reader.read_record().unwrap().unwrap();
         let state = record_variant!(ElementViewState, rec);
@@ -328,7 +1219,7 @@ mod tests {
         assert_eq!(state.normals.len(), 1);
         assert_eq_point3!(
             state.normals[0],
-            new_point3(0.39932388, 0.18115145, 0.9837299)
+            new_point3(0.37076293, 0.1681949, 0.9133703)
         );
 
         let rec = reader.read_record().unwrap().unwrap();
@@ -338,8 +1229,562 @@ mod tests {
         assert_eq!(state.vertices.len(), 1);
         assert_eq_point3!(state.vertices[0], new_point3(0.4, 0.6, 0.8));
         assert_eq!(state.normals.len(), 1);
-        assert_eq_point3!(state.normals[0], new_point3(0.5, 0.7, 0.9));
+        assert_eq_point3!(
+            state.normals[0],
+            new_point3(0.37139068, 0.557086, 0.74278134)
+        );
 
         assert!(reader.read_record().unwrap().is_none());
     }
+
+    #[test]
+    fn test_combine_transforms_refinements() {
+        use fm::element_view_refinement::Vertex;
+
+        let refinement = fm::ElementViewRefinement {
+            element: "e1".to_string(),
+            vertices: vec![
+                Vertex {
+                    index: 0,
+                    vertex: Some(new_point3(0.1, 0.2, 0.3)),
+                    normal: Some(new_point3(1.0, 1.0, 0.0)),
+                    texture_point: None,
+                },
+                Vertex {
+                    index: 1,
+                    vertex: Some(new_point3(0.0, 1.0, 0.0)),
+                    normal: None,
+                    texture_point: Some(new_point2(0.5, 0.5)),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut reader = create_reader_with_records(&[
+            new_simple_element_view_rec("e1"),
+            fm::Record {
+                r#type: Some(ElementViewRefinement(refinement)),
+            },
+        ]);
+        let mut readers: [&mut dyn fm::Read; 1] = [&mut reader];
+
+        // Shear along x, so that normals need the normal matrix.
+        let shear = "1,1,0,0,0,1,0,0,0,0,1,0,0,0,0,1";
+        let params = CombineParams {
+            displacements: vec![],
+            rotations: vec![],
+            scalings: vec![],
+            matrices: vec![("e1".to_string(), shear.parse().unwrap())],
+            transform_file: None,
+            renames: vec![],
+            prefix: None,
+            allow_mixed: false,
+            keeps: vec![],
+            syncs: vec![],
+            auto_sync: false,
+            time_offsets: vec![],
+            sync_start: false,
+            order: RecordOrder::ByInput,
+        };
+        let mut writer = create_writer();
+        combine(&mut readers[..], &mut writer, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        reader.read_record().unwrap().unwrap();
+
+        let rec = reader.read_record().unwrap().unwrap();
+        let refinement = record_variant!(ElementViewRefinement, rec);
+        assert_eq!(refinement.element.as_str(), "e1");
+        let vertex = &refinement.vertices[0];
+        assert_eq_point3!(vertex.vertex.unwrap(), new_point3(0.3, 0.2, 0.3));
+        assert_eq_point3!(vertex.normal.unwrap(), new_point3(1.0, 0.0, 0.0));
+        let vertex = &refinement.vertices[1];
+        assert_eq_point3!(vertex.vertex.unwrap(), new_point3(1.0, 1.0, 0.0));
+        assert!(vertex.normal.is_none());
+        assert_eq!(vertex.texture_point, Some(new_point2(0.5, 0.5)));
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    fn state_times(records: Vec<fm::Record>) -> Vec<i64> {
+        records
+            .into_iter()
+            .map(|rec| match rec.r#type {
+                Some(ElementViewState(s)) => s.time,
+                _ => -1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_combine_mixed() {
+        let new_readers = || {
+            let model = create_reader_with_records(&[
+                new_simple_element_view_rec("e1"),
+                new_simple_element_view_state_rec("e1", 1),
+            ]);
+            let scan = create_reader_with_records(&[
+                new_scan_rec(fm::Scan {
+                    name: "s1".to_string(),
+                    ..Default::default()
+                }),
+                new_simple_element_view_rec("e2"),
+                new_scan_frame_rec(fm::ScanFrame {
+                    scan: "s1".to_string(),
+                    time: 2,
+                    ..Default::default()
+                }),
+            ]);
+            (model, scan)
+        };
+        let combine_with = |args: &[&str]| {
+            let (mut model, mut scan) = new_readers();
+            let mut readers: [&mut dyn fm::Read; 2] = [&mut model, &mut scan];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader)
+                .map(|r| Ok(fm::RecordKind::of(&r?).unwrap()))
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let err = combine_with(&["test"]).unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+
+        let kinds = combine_with(&["test", "--allow-mixed"]).unwrap();
+        use fm::RecordKind as Kind;
+        assert_eq!(
+            kinds,
+            [
+                Kind::ElementView,
+                Kind::Scan,
+                Kind::ElementView,
+                Kind::ElementViewState,
+                Kind::ScanFrame
+            ]
+        );
+
+        let kinds = combine_with(&["test", "--keep", "2=element-view"]);
+        assert_eq!(
+            kinds.unwrap(),
+            [Kind::ElementView, Kind::ElementView, Kind::ElementViewState]
+        );
+
+        let err = combine_with(&["test", "--keep", "3=scan"]).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+    }
+
+    #[test]
+    fn test_combine_sync() {
+        let combine_with = |args: &[&str]| -> Result<Vec<i64>> {
+            let mut reader1 = create_reader_with_records(&[
+                new_simple_element_view_rec("e1"),
+                new_simple_element_view_state_rec("e1", 1000),
+                new_simple_element_view_state_rec("e1", 2000),
+            ]);
+            let mut reader2 = create_reader_with_records(&[
+                new_simple_element_view_rec("e2"),
+                new_simple_element_view_state_rec("e2", 10500),
+                new_simple_element_view_state_rec("e2", 11500),
+            ]);
+            let mut readers: [&mut dyn fm::Read; 2] =
+                [&mut reader1, &mut reader2];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            Ok(state_times(
+                fm::records(&mut reader).collect::<Result<_>>()?,
+            ))
+        };
+
+        let times = combine_with(&["test"]).unwrap();
+        assert_eq!(times, [-1, -1, 1000, 2000, 10500, 11500]);
+
+        let times = combine_with(&["test", "--sync", "2=-10us"]).unwrap();
+        assert_eq!(times, [-1, -1, 500, 1000, 1500, 2000]);
+
+        let times = combine_with(&["test", "--sync", "1=9.5us"]).unwrap();
+        assert_eq!(times, [-1, -1, 10500, 10500, 11500, 11500]);
+
+        let times = combine_with(&["test", "--auto-sync"]).unwrap();
+        assert_eq!(times, [-1, -1, 1000, 1000, 2000, 2000]);
+
+        let err = combine_with(&["test", "--sync", "3=1s"]).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+
+        let args = ["test", "--sync", "2=1s", "--auto-sync"];
+        assert!(CombineParams::from_iter_safe(&args).is_err());
+    }
+
+    #[test]
+    fn test_combine_time_offset() {
+        let combine_with = |args: &[&str]| -> Result<Vec<i64>> {
+            let mut reader1 = create_reader_with_records(&[
+                new_simple_element_view_rec("e1"),
+                new_simple_element_view_rec("e3"),
+                new_simple_element_view_state_rec("e1", 1000),
+                new_simple_element_view_state_rec("e1", 2000),
+                new_simple_element_view_state_rec("e3", 5000),
+            ]);
+            let mut reader2 = create_reader_with_records(&[
+                new_simple_element_view_rec("e2"),
+                new_simple_element_view_state_rec("e2", 10500),
+                new_simple_element_view_state_rec("e2", 11500),
+            ]);
+            let mut readers: [&mut dyn fm::Read; 2] =
+                [&mut reader1, &mut reader2];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            Ok(state_times(
+                fm::records(&mut reader).collect::<Result<_>>()?,
+            ))
+        };
+
+        let args = ["test", "--time-offset", "e2=-9500"];
+        let times = combine_with(&args).unwrap();
+        assert_eq!(times, [-1, -1, -1, 1000, 1000, 2000, 2000, 5000]);
+
+        let args = ["test", "--rename=e2=b", "--time-offset=b=-9.5us"];
+        let times = combine_with(&args).unwrap();
+        assert_eq!(times, [-1, -1, -1, 1000, 1000, 2000, 2000, 5000]);
+
+        // Elements of the same input are aligned too.
+        let args = ["test", "--sync-start", "--order=spec-compliant"];
+        let times = combine_with(&args).unwrap();
+        assert_eq!(times, [-1, -1, -1, 1000, 1000, 1000, 2000, 2000]);
+
+        let args = ["test", "--sync-start", "--time-offset", "e1=1us"];
+        let args = [&args[..], &["--order=spec-compliant"]].concat();
+        let times = combine_with(&args).unwrap();
+        assert_eq!(times, [-1, -1, -1, 1000, 1000, 2000, 2000, 3000]);
+
+        let args = ["test", "--time-offset=e1=1", "--time-offset=e1=2"];
+        let err = combine_with(&args).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+
+        let args = ["test", "--sync-start", "--auto-sync"];
+        assert!(CombineParams::from_iter_safe(&args).is_err());
+        let args = ["test", "--sync-start", "--sync", "2=1s"];
+        assert!(CombineParams::from_iter_safe(&args).is_err());
+    }
+
+    #[test]
+    fn test_combine_time_offset_of_loop() {
+        let combine_with = |args: &[&str]| -> Result<Vec<fm::Record>> {
+            let mut reader1 = create_reader_with_records(&[
+                new_element_view_rec(fm::ElementView {
+                    element: "e1".to_string(),
+                    animation_loop: Some(fm::element_view::Loop {
+                        start: 2000,
+                        end: 3000,
+                        ping_pong: false,
+                    }),
+                    ..Default::default()
+                }),
+                new_simple_element_view_state_rec("e1", 1000),
+                new_simple_element_view_state_rec("e1", 2000),
+                new_simple_element_view_state_rec("e1", 3000),
+            ]);
+            let mut reader2 = create_reader_with_records(&[
+                new_simple_element_view_rec("e2"),
+                new_simple_element_view_state_rec("e2", 500),
+            ]);
+            let mut readers: [&mut dyn fm::Read; 2] =
+                [&mut reader1, &mut reader2];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader).collect()
+        };
+        let loop_of = |records: &[fm::Record]| {
+            let view = records.iter().find_map(|rec| match &rec.r#type {
+                Some(ElementView(v)) if v.element == "e1" => Some(v),
+                _ => None,
+            });
+            let animation_loop = view.unwrap().animation_loop.as_ref().unwrap();
+            (animation_loop.start, animation_loop.end)
+        };
+
+        let records = combine_with(&["test", "--time-offset=e1=-1us"]).unwrap();
+        assert_eq!(loop_of(&records), (1000, 2000));
+        assert_eq!(&state_times(records)[2..5], [0, 500, 1000]);
+
+        let records = combine_with(&["test", "--sync", "1=1us"]).unwrap();
+        assert_eq!(loop_of(&records), (3000, 4000));
+
+        // Loop follows the states aligned to the start of e2.
+        let records = combine_with(&["test", "--sync-start"]).unwrap();
+        assert_eq!(loop_of(&records), (1500, 2500));
+    }
+
+    #[test]
+    fn test_item_decoded_on_modification() {
+        let mut reader = create_reader_with_records(&[
+            new_simple_element_view_state_rec("e1", 1),
+            new_simple_element_view_state_rec("e2", 2),
+        ]);
+        let rename = |record: &mut fm::Record| match &mut record.r#type {
+            Some(ElementViewState(s)) if s.element == "e2" => {
+                s.element = "b".to_string();
+                true
+            }
+            _ => false,
+        };
+
+        let mut item = read_item(&mut reader).unwrap();
+        assert!(!item.modify(rename).unwrap());
+        assert!(!item.modified);
+        let state = record_variant!(ElementViewState, item.record.unwrap());
+        assert_eq!((state.element.as_str(), state.time), ("e1", 1));
+        assert!(state.vertices.is_empty());
+
+        let mut item = read_item(&mut reader).unwrap();
+        assert!(item.modify(rename).unwrap());
+        assert!(item.modified);
+        let state = record_variant!(ElementViewState, item.record.unwrap());
+        assert_eq!((state.element.as_str(), state.time), ("b", 2));
+        assert_eq!(state.vertices.len(), 1);
+    }
+
+    #[test]
+    fn test_combine_order() {
+        let combine_with = |args: &[&str]| -> Result<Vec<String>> {
+            let mut reader1 = create_reader_with_records(&[
+                new_simple_element_view_rec("e1"),
+                new_simple_element_view_state_rec("e1", 1),
+                new_simple_element_view_state_rec("e1", 3),
+            ]);
+            // The view follows its state.
+            let mut reader2 = create_reader_with_records(&[
+                new_simple_element_view_state_rec("e2", 2),
+                new_simple_element_view_rec("e2"),
+            ]);
+            let mut readers: [&mut dyn fm::Read; 2] =
+                [&mut reader1, &mut reader2];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader)
+                .map(|r| {
+                    Ok(match r?.r#type {
+                        Some(ElementView(v)) => v.element,
+                        Some(ElementViewState(s)) => {
+                            format!("{}@{}", s.element, s.time)
+                        }
+                        _ => String::new(),
+                    })
+                })
+                .collect()
+        };
+
+        let records = combine_with(&["test"]).unwrap();
+        assert_eq!(records, ["e1", "e1@1", "e2@2", "e2", "e1@3"]);
+
+        let args = ["test", "--order", "spec-compliant"];
+        let records = combine_with(&args).unwrap();
+        assert_eq!(records, ["e1", "e2", "e1@1", "e2@2", "e1@3"]);
+
+        let records = combine_with(&["test", "--order", "by-input"]).unwrap();
+        assert_eq!(records, ["e1", "e1@1", "e1@3", "e2@2", "e2"]);
+
+        let args = ["test", "--order", "by-input", "--sync", "1=1ns"];
+        let records = combine_with(&args).unwrap();
+        assert_eq!(records, ["e1", "e1@2", "e1@4", "e2@2", "e2"]);
+
+        let args = ["test", "--order", "random"];
+        assert!(CombineParams::from_iter_safe(&args).is_err());
+    }
+
+    #[test]
+    fn test_combine_rename() {
+        let combine_with = |args: &[&str]| -> Result<Vec<fm::Record>> {
+            let new_reader = || {
+                create_reader_with_records(&[
+                    new_simple_element_view_rec("model"),
+                    new_simple_element_view_state_rec("model", 1),
+                ])
+            };
+            let (mut reader1, mut reader2) = (new_reader(), new_reader());
+            let mut readers: [&mut dyn fm::Read; 2] =
+                [&mut reader1, &mut reader2];
+            let params = CombineParams::from_iter(args);
+            let mut writer = create_writer();
+            combine(&mut readers[..], &mut writer, &params)?;
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader).collect()
+        };
+        let element = |rec: &fm::Record| match &rec.r#type {
+            Some(ElementView(v)) => v.element.clone(),
+            Some(ElementViewState(s)) => s.element.clone(),
+            _ => String::new(),
+        };
+
+        let err = combine_with(&["test"]).unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+
+        let records = combine_with(&["test", "--prefix=file-index"]).unwrap();
+        let elements: Vec<_> = records.iter().map(element).collect();
+        assert_eq!(elements, ["1-model", "2-model", "1-model", "2-model"]);
+
+        let args = [
+            "test",
+            "--prefix=file-index",
+            "--rename=2-model=shirt",
+            "--displacement=shirt=1,0,0",
+        ];
+        let records = combine_with(&args).unwrap();
+        let elements: Vec<_> = records.iter().map(element).collect();
+        assert_eq!(elements, ["1-model", "shirt", "1-model", "shirt"]);
+        let state = record_variant!(ElementViewState, records[3].clone());
+        assert_eq_point3!(state.vertices[0], new_point3(1.1, 0.2, 0.3));
+
+        let args = ["test", "--rename=model=shirt"];
+        assert!(combine_with(&args).is_err());
+
+        let args = ["test", "--rename=model=a", "--rename=model=b"];
+        assert_eq!(combine_with(&args).unwrap_err().kind, BadOperation);
+
+        let mut reader1 =
+            create_reader_with_records(&[new_simple_element_view_rec("e1")]);
+        let mut reader2 =
+            create_reader_with_records(&[new_simple_element_view_rec("e1")]);
+        let mut readers: [&mut dyn fm::Read; 2] = [&mut reader1, &mut reader2];
+        let params = CombineParams::from_iter(["test", "--rename=e1=e2"]);
+        let plan = plan_combine(&mut readers[..], "out.fm", &params)
+            .unwrap()
+            .to_string();
+        assert!(plan.contains("rename element 'e1' of input #1 to 'e2'"));
+        assert!(plan.contains("element 'e2' of input 2 duplicates"));
+    }
+
+    #[test]
+    fn test_combine_matrix() {
+        let new_state_rec = |element: &str| {
+            new_element_view_state_rec(fm::ElementViewState {
+                element: element.to_string(),
+                vertices: vec![new_point3(0.1, 0.2, 0.3)],
+                normals: vec![new_point3(1.0, 1.0, 0.0)],
+                ..Default::default()
+            })
+        };
+        let mut reader = create_reader_with_records(&[
+            new_simple_element_view_rec("e1"),
+            new_simple_element_view_rec("e2"),
+            new_state_rec("e1"),
+            new_state_rec("e2"),
+        ]);
+        let mut readers: [&mut dyn fm::Read; 1] = [&mut reader];
+
+        let path = std::env::temp_dir().join("test_combine_matrix.yaml");
+        let yaml = "e2:\n  - [2, 0, 0, 1]\n  - [0, 1, 0, 0]\n  \
+                    - [0, 0, 1, 0]\n  - [0, 0, 0, 1]\n";
+        fs::write_file(&path, yaml.as_bytes()).unwrap();
+
+        // Shear of e1 along x and non-uniform scaling of e2 (from file).
+        let shear = "1,1,0,0,0,1,0,0,0,0,1,0,0,0,0,1";
+        let mut params = CombineParams {
+            displacements: vec![],
+            rotations: vec![],
+            scalings: vec![],
+            matrices: vec![("e1".to_string(), shear.parse().unwrap())],
+            transform_file: Some(path.clone()),
+            renames: vec![],
+            prefix: None,
+            allow_mixed: false,
+            keeps: vec![],
+            syncs: vec![],
+            auto_sync: false,
+            time_offsets: vec![],
+            sync_start: false,
+            order: RecordOrder::Chronological,
+        };
+        let mut writer = create_writer();
+        combine(&mut readers[..], &mut writer, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        reader.read_record().unwrap().unwrap();
+        reader.read_record().unwrap().unwrap();
+
+        let rec = reader.read_record().unwrap().unwrap();
+        let state = record_variant!(ElementViewState, rec);
+        assert_eq!(state.element.as_str(), "e1");
+        assert_eq_point3!(state.vertices[0], new_point3(0.3, 0.2, 0.3));
+        assert_eq_point3!(state.normals[0], new_point3(1.0, 0.0, 0.0));
+
+        let rec = reader.read_record().unwrap().unwrap();
+        let state = record_variant!(ElementViewState, rec);
+        assert_eq!(state.element.as_str(), "e2");
+        assert_eq_point3!(state.vertices[0], new_point3(1.2, 0.2, 0.3));
+        assert_eq_point3!(
+            state.normals[0],
+            new_point3(0.4472136, 0.8944272, 0.0)
+        );
+
+        params
+            .matrices
+            .push(("e2".to_string(), shear.parse().unwrap()));
+        assert!(params.transforms().is_err());
+
+        params.transform_file = None;
+        params.matrices[1].1 .0[12] = 1.0;
+        assert!(params.transforms().is_err());
+
+        params.matrices.pop();
+        params.scalings.push(("e1".to_string(), 0.0));
+        assert!(params.transforms().is_err());
+    }
+
+    #[test]
+    fn test_order_check() {
+        let mut check = OrderCheck::default();
+        for (element, time) in [("a", 1), ("b", 3), ("a", 2), ("b", 4)] {
+            check.add(&new_simple_element_view_state_rec(element, time));
+        }
+        check.add(&new_simple_element_view_rec("c"));
+        assert_eq!(check.num_violations, 2);
+    }
+
+    #[test]
+    fn test_plan_combine() {
+        let mut reader1 = create_reader_with_records(&[
+            new_simple_element_view_rec("e1"),
+            new_simple_element_view_state_rec("e1", 1),
+        ]);
+        let mut reader2 =
+            create_reader_with_records(&[new_simple_element_view_state_rec(
+                "e1", 2,
+            )]);
+        let mut readers: [&mut dyn fm::Read; 2] = [&mut reader1, &mut reader2];
+
+        let params = &CombineParams {
+            displacements: vec![("e1".to_string(), [0.3, 0.4, 0.5].into())],
+            rotations: vec![],
+            scalings: vec![("e2".to_string(), 2.0)],
+            matrices: vec![],
+            transform_file: None,
+            renames: vec![],
+            prefix: None,
+            allow_mixed: false,
+            keeps: vec![],
+            syncs: vec![],
+            auto_sync: false,
+            time_offsets: vec![],
+            sync_start: false,
+            order: RecordOrder::Chronological,
+        };
+        let plan = plan_combine(&mut readers[..], "out.fm", params)
+            .unwrap()
+            .to_string();
+
+        assert!(plan.contains("1. read input #1 (2 records)"));
+        assert!(plan.contains("2. read input #2 (1 records)"));
+        assert!(plan.contains("4. transform states of element 'e1'"));
+        assert!(plan.contains("ElementViewState records: 2"));
+        assert!(plan.contains("element 'e2' not found in inputs"));
+        assert!(plan.contains("out.fm: 3 records"));
+    }
 }
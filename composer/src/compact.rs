@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use log::info;
+use structopt::StructOpt;
+
+use crate::telemetry;
+use base::defs::Result;
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Drop superseded records of .fm file")]
+pub struct CompactCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl CompactCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let output = cli::FmOutput {
+            path: self.output.path.clone(),
+            fm_params: fm::WriterParams {
+                dedup: true,
+                ..self.output.fm_params.clone()
+            },
+        };
+        let mut writer = output.get()?;
+
+        compact(reader.as_mut(), writer.as_mut())
+    }
+}
+
+// Element or scan with all its records.
+type Subject = (bool, String);
+
+// Encoded record with its order key.
+type KeyedRecord = ((i8, fm::Time), Vec<u8>);
+
+#[derive(Default)]
+struct LiveRecords {
+    records: Vec<Option<KeyedRecord>>,
+    subjects: HashMap<Subject, Vec<usize>>,
+    moments: HashMap<(Subject, fm::RecordKind, fm::Time), usize>,
+    singletons: HashMap<fm::RecordKind, usize>,
+}
+
+impl LiveRecords {
+    fn remove(&mut self, index: usize) {
+        self.records[index] = None;
+    }
+
+    fn add(&mut self, record: &fm::Record, data: Vec<u8>) {
+        use fm::record::Type::*;
+
+        let index = self.records.len();
+        let key = fm::record_order_key(record);
+        self.records.push(Some((key, data)));
+
+        let (subject, time) = match &record.r#type {
+            // A new definition replaces the one with its states, textures,
+            // frames and refinements, as those relate to the former geometry.
+            Some(ElementView(v)) => ((false, v.element.clone()), None),
+            Some(Scan(s)) => ((true, s.name.clone()), None),
+            Some(ElementViewState(s)) => {
+                ((false, s.element.clone()), Some(s.time))
+            }
+            Some(ElementTexture(t)) => {
+                ((false, t.element.clone()), Some(t.time))
+            }
+            Some(ScanFrame(f)) => ((true, f.scan.clone()), Some(f.time)),
+            Some(ElementViewRefinement(r)) => {
+                let subject = (false, r.element.clone());
+                self.subjects.entry(subject).or_default().push(index);
+                return;
+            }
+            Some(Preview(_)) | Some(Impostors(_)) => {
+                let kind = fm::RecordKind::of(record).unwrap();
+                if let Some(old) = self.singletons.insert(kind, index) {
+                    self.remove(old);
+                }
+                return;
+            }
+            None => return, // Kept as is, being unknown.
+        };
+
+        match time {
+            Some(time) => {
+                let kind = fm::RecordKind::of(record).unwrap();
+                let moment = (subject.clone(), kind, fm::Time(time));
+                if let Some(old) = self.moments.insert(moment, index) {
+                    self.remove(old);
+                }
+            }
+            None => {
+                let old = self.subjects.remove(&subject).unwrap_or_default();
+                for old in old {
+                    self.remove(old);
+                }
+                self.moments.retain(|(s, _, _), _| *s != subject);
+            }
+        }
+        self.subjects.entry(subject).or_default().push(index);
+    }
+}
+
+// Keeps the latest versions of records, writing them in the canonical order.
+pub fn compact(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+) -> Result<()> {
+    let mut live = LiveRecords::default();
+    while let Some(raw) = reader.read_raw_record()? {
+        let record = raw.decode()?;
+        live.add(&record, raw.as_bytes().to_vec());
+    }
+
+    let num_total = live.records.len();
+    let mut records: Vec<_> = live.records.into_iter().flatten().collect();
+    records.sort_by_key(|(key, _)| *key);
+
+    for (_, data) in &records {
+        writer.write_raw_record(&fm::RawRecord::new(data))?;
+    }
+
+    telemetry::count("records", num_total);
+    telemetry::count("kept_records", records.len());
+    info!("kept {} of {} records", records.len(), num_total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn new_state(element: &str, time: i64, x: f32) -> fm::Record {
+        new_element_view_state_rec(fm::ElementViewState {
+            element: element.to_string(),
+            time,
+            vertices: vec![new_point3(x, 0.0, 0.0)],
+            ..Default::default()
+        })
+    }
+
+    fn new_view(element: &str, data: Vec<u8>) -> fm::Record {
+        new_element_view_rec(fm::ElementView {
+            element: element.to_string(),
+            texture: Some(fm::Image {
+                r#type: fm::image::Type::Png as i32,
+                data,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_compact() {
+        let preview = |data| fm::Record {
+            r#type: Some(Preview(fm::Preview {
+                image: Some(fm::Image {
+                    data: vec![data],
+                    ..Default::default()
+                }),
+            })),
+        };
+        let mut reader = create_reader_with_records(&[
+            new_view("a", vec![1, 2, 3]),
+            new_state("a", 1, 1.0),
+            preview(1),
+            new_view("b", vec![4]),
+            new_state("b", 1, 2.0),
+            new_state("a", 2, 3.0),
+            new_state("a", 1, 4.0),
+            new_view("b", vec![1, 2, 3]),
+            new_state("b", 2, 5.0),
+            preview(2),
+        ]);
+
+        let params = fm::WriterParams {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        compact(&mut reader, &mut writer).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        assert_eq!(reader.features(), fm::FEATURE_DEDUP);
+        let mut records = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            records.push(rec);
+        }
+        assert_eq!(
+            records,
+            vec![
+                preview(2),
+                new_view("a", vec![1, 2, 3]),
+                new_view("b", vec![1, 2, 3]),
+                new_state("a", 1, 4.0),
+                new_state("a", 2, 3.0),
+                new_state("b", 2, 5.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use image::{ColorType, GenericImage, Rgb, RgbImage};
+use log::{info, warn};
+use structopt::StructOpt;
+
+use crate::impostors::Element;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::preview::{encode_png, rasterize_triangle_rect};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::texture::project_like_camera;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::{cli, fs};
+
+#[derive(StructOpt)]
+#[structopt(about = "Compare model rendered from frame cameras with photos")]
+pub struct CompareFramesCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(help = "Model .fm file (as built from the scans)", long)]
+    model: PathBuf,
+
+    #[structopt(
+        help = "Directory to write photo, render and difference images to",
+        long
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    params: CompareFramesParams,
+}
+
+impl CompareFramesCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut model = fm::Reader::new(fs::open_input(&self.model)?)?;
+        compare_frames(
+            reader.as_mut(),
+            &mut model,
+            self.output_dir.as_ref(),
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct CompareFramesParams {
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(help = "Compare each n-th frame", long, default_value = "1")]
+    pub frame_step: usize,
+}
+
+impl CheckParams for CompareFramesParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        check.require(self.frame_step > 0, || {
+            "--frame-step should be positive".to_string()
+        });
+    }
+}
+
+pub struct Comparison {
+    // Photo, render and their absolute difference side by side.
+    pub image: RgbImage,
+    // Fraction of pixels covered by the model.
+    pub coverage: f64,
+    // Mean absolute color difference over covered pixels (0-255).
+    pub error: Option<f64>,
+}
+
+pub fn compare_frames(
+    reader: &mut dyn fm::Read,
+    model: &mut dyn fm::Read,
+    output_dir: Option<&PathBuf>,
+    params: &CompareFramesParams,
+) -> Result<()> {
+    let mut params = params.clone();
+    if let Some(path) = &params.scan.scans_config {
+        let config = read_scans_config(path)?;
+        params.scan.merge_config(&config);
+    }
+    params.check()?;
+
+    info!("reading scans...");
+    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+
+    info!("reading model...");
+    let elements = read_elements(model)?;
+
+    if let Some(dir) = output_dir {
+        std::fs::create_dir_all(dir).into_result(|| {
+            format!("failed to create dir '{}'", dir.display())
+        })?;
+    }
+
+    let (mut sum_coverage, mut sum_error, mut num, mut num_covered) =
+        (0.0, 0.0, 0, 0);
+    for (index, frame) in
+        scan_frames.iter().enumerate().step_by(params.frame_step)
+    {
+        let scan = &scans[&frame.scan];
+        let cmp = match compare_frame(scan, frame, &elements)? {
+            Some(cmp) => cmp,
+            None => {
+                warn!("skipped frame {} without image", index);
+                continue;
+            }
+        };
+
+        let error = match cmp.error {
+            Some(error) => {
+                sum_error += error;
+                num_covered += 1;
+                format!("{:.1}", error)
+            }
+            None => "n/a".to_string(),
+        };
+        sum_coverage += cmp.coverage;
+        num += 1;
+        println!(
+            "frame {} of '{}': coverage {:.1}%, error {}",
+            index,
+            frame.scan,
+            cmp.coverage * 100.0,
+            error
+        );
+
+        if let Some(dir) = output_dir {
+            let (width, height) = cmp.image.dimensions();
+            let png =
+                encode_png(cmp.image.as_ref(), width, height, ColorType::Rgb8)?;
+            let name = format!("{}-{:05}.png", frame.scan, index);
+            fs::write_output(dir.join(name), &png.data)?;
+        }
+    }
+
+    if num > 0 {
+        println!(
+            "total of {} frames: coverage {:.1}%, error {}",
+            num,
+            sum_coverage / num as f64 * 100.0,
+            if num_covered > 0 {
+                format!("{:.1}", sum_error / num_covered as f64)
+            } else {
+                "n/a".to_string()
+            }
+        );
+    }
+
+    info!("done");
+    Ok(())
+}
+
+// Reads elements in their first states.
+fn read_elements(reader: &mut dyn fm::Read) -> Result<Vec<Element>> {
+    let mut views = Vec::new();
+    let mut states = HashMap::<String, fm::ElementViewState>::new();
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match rec.r#type {
+            Some(ElementView(v)) => views.push(v),
+            Some(ElementViewState(s)) => {
+                let first =
+                    states.entry(s.element.clone()).or_insert(s.clone());
+                if s.time < first.time {
+                    *first = s;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut elements = Vec::with_capacity(views.len());
+    for view in &views {
+        if let Some(state) = states.get(&view.element) {
+            elements.push(Element::new(view, state)?);
+        }
+    }
+    if elements.is_empty() {
+        let desc = "no element states to compare with frames".to_string();
+        return Err(Error::new(InconsistentState, desc));
+    }
+    Ok(elements)
+}
+
+// Renders elements from the frame camera and compares the result with
+// the frame photo. Returns None if the frame has no image.
+pub fn compare_frame(
+    scan: &fm::Scan,
+    frame: &fm::ScanFrame,
+    elements: &[Element],
+) -> Result<Option<Comparison>> {
+    let photo = match crate::texture::load_frame_image(frame) {
+        Some(photo) => photo,
+        None => return Ok(None),
+    };
+    let (width, height) = photo.dimensions();
+    let mut render = RgbImage::new(width, height);
+    let mut covered = vec![false; (width * height) as usize];
+    let mut depths = vec![f64::INFINITY; covered.len()];
+
+    for element in elements {
+        let projected = project_like_camera(scan, frame, &element.vertices)?;
+        let project = |i: usize| {
+            let p = &projected[i];
+            (
+                p.point[1] * width as f64,
+                p.point[0] * height as f64,
+                p.depth,
+            )
+        };
+
+        for triangle in &element.triangles {
+            let points = triangle.vertices.map(project);
+            if points.iter().any(|p| p.2 <= 0.0) {
+                continue; // Behind the camera.
+            }
+            let size = (width, height);
+            rasterize_triangle_rect(points, size, &mut depths, |x, y, w| {
+                // Screen weights are not linear in space under perspective.
+                let w = [
+                    w[0] / points[0].2,
+                    w[1] / points[1].2,
+                    w[2] / points[2].2,
+                ];
+                let sum = w[0] + w[1] + w[2];
+                let w = w.map(|v| v / sum);
+                let color = element.color(triangle, w);
+                render.put_pixel(x, y, Rgb([color[0], color[1], color[2]]));
+                covered[(y * width + x) as usize] = true;
+            });
+        }
+    }
+
+    let mut diff = RgbImage::new(width, height);
+    let (mut sum, mut num) = (0.0, 0);
+    for (x, y, pixel) in diff.enumerate_pixels_mut() {
+        if !covered[(y * width + x) as usize] {
+            continue;
+        }
+        let (a, b) = (photo.get_pixel(x, y), render.get_pixel(x, y));
+        for c in 0..3 {
+            pixel[c] = a[c].abs_diff(b[c]);
+            sum += pixel[c] as f64 / 3.0;
+        }
+        num += 1;
+    }
+
+    let mut image = RgbImage::new(width * 3, height);
+    for (i, part) in [&photo, &render, &diff].into_iter().enumerate() {
+        image.copy_from(part, width * i as u32, 0).map_err(|e| {
+            let desc = "failed to compose comparison image".to_string();
+            Error::with_source(ImageError, desc, e)
+        })?;
+    }
+
+    Ok(Some(Comparison {
+        image,
+        coverage: num as f64 / covered.len().max(1) as f64,
+        error: (num > 0).then(|| sum / num as f64),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+
+    fn new_quad() -> Element {
+        let view = fm::ElementView {
+            element: "a".to_string(),
+            faces: vec![
+                new_ev_face(1, 2, 3, 0, 0, 0, 0, 0, 0),
+                new_ev_face(1, 3, 4, 0, 0, 0, 0, 0, 0),
+            ],
+            ..Default::default()
+        };
+        let state = fm::ElementViewState {
+            element: "a".to_string(),
+            vertices: vec![
+                new_point3(-0.5, 0.0, -0.5),
+                new_point3(0.5, 0.0, -0.5),
+                new_point3(0.5, 0.0, 0.5),
+                new_point3(-0.5, 0.0, 0.5),
+            ],
+            ..Default::default()
+        };
+        Element::new(&view, &state).unwrap()
+    }
+
+    fn new_frame(color: [u8; 3]) -> fm::ScanFrame {
+        let photo = RgbImage::from_pixel(20, 20, Rgb(color));
+        let image = encode_png(photo.as_ref(), 20, 20, ColorType::Rgb8);
+        fm::ScanFrame {
+            scan: "a".to_string(),
+            image: Some(image.unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_compare_frame() {
+        let scan = fm::Scan {
+            name: "a".to_string(),
+            camera_initial_position: Some(new_point3(0.0, -2.0, 0.0)),
+            camera_initial_direction: Some(new_point3(0.0, 1.0, 0.0)),
+            camera_angle_of_view: 1.0,
+            depth_width: 20,
+            depth_height: 20,
+            ..Default::default()
+        };
+        let elements = vec![new_quad()];
+
+        let frame = new_frame([200, 200, 200]);
+        let cmp = compare_frame(&scan, &frame, &elements).unwrap().unwrap();
+        assert_eq!(cmp.image.dimensions(), (60, 20));
+        assert!(cmp.coverage > 0.1 && cmp.coverage < 1.0);
+        assert_eq!(cmp.error, Some(0.0));
+        assert_eq!(*cmp.image.get_pixel(30, 10), Rgb([200, 200, 200]));
+        assert_eq!(*cmp.image.get_pixel(21, 1), Rgb([0, 0, 0]));
+
+        let frame = new_frame([100, 200, 230]);
+        let cmp = compare_frame(&scan, &frame, &elements).unwrap().unwrap();
+        assert!((cmp.error.unwrap() - 130.0 / 3.0).abs() < 1e-9);
+        assert_eq!(*cmp.image.get_pixel(50, 10), Rgb([100, 0, 30]));
+
+        let frame = fm::ScanFrame::default();
+        assert!(compare_frame(&scan, &frame, &elements).unwrap().is_none());
+    }
+}
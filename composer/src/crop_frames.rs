@@ -0,0 +1,389 @@
+use std::path::PathBuf;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{imageops, ColorType, ImageEncoder, RgbImage};
+use log::info;
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::dry_run::format_size;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{build_frame_clouds, Point3, PointCloudParams};
+use crate::preview::encode_png;
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::telemetry;
+use crate::texture::{load_frame_image, project_like_camera};
+use crate::threads::ThreadParams;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::{cli, fs};
+
+#[derive(StructOpt)]
+#[structopt(about = "Crop scan frame images to subject bounding boxes")]
+pub struct CropFramesCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Model .fm file to bound subject (scan clouds if omitted)",
+        long
+    )]
+    model: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    params: CropFramesParams,
+}
+
+impl CropFramesCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        let mut model = match &self.model {
+            Some(path) => Some(fm::Reader::new(fs::open_input(path)?)?),
+            None => None,
+        };
+        crop_frames(
+            reader.as_mut(),
+            model.as_mut().map(|m| m as &mut dyn fm::Read),
+            writer.as_mut(),
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct CropFramesParams {
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(flatten)]
+    pub point_cloud: PointCloudParams,
+
+    #[structopt(flatten)]
+    pub threads: ThreadParams,
+
+    #[structopt(
+        help = "Margin around subject (in fractions of image sides)",
+        long,
+        default_value = "0.05"
+    )]
+    pub crop_margin: f64,
+
+    #[structopt(
+        help = "JPEG quality of cropped images (1-100)",
+        long,
+        default_value = "90"
+    )]
+    pub crop_jpeg_quality: u8,
+}
+
+impl CropFramesParams {
+    pub fn with_scans_config(&self) -> Result<CropFramesParams> {
+        let mut params = self.clone();
+        if let Some(path) = &self.scan.scans_config {
+            let config = read_scans_config(path)?;
+            params.scan.merge_config(&config);
+            params.point_cloud.merge_config(&config);
+        }
+        Ok(params)
+    }
+}
+
+impl CheckParams for CropFramesParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        check.require((0.0..1.0).contains(&self.crop_margin), || {
+            "--crop-margin should be within [0, 1)".to_string()
+        });
+        check.require((1..=100).contains(&self.crop_jpeg_quality), || {
+            "--crop-jpeg-quality should be within [1, 100]".to_string()
+        });
+    }
+}
+
+pub fn crop_frames(
+    reader: &mut dyn fm::Read,
+    model: Option<&mut dyn fm::Read>,
+    writer: &mut dyn fm::Write,
+    params: &CropFramesParams,
+) -> Result<()> {
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
+    info!("reading scans...");
+    let (scans, mut scan_frames) = read_scans(reader, &params.scan)?;
+    let pool = params.threads.pool()?;
+
+    let points = match model {
+        Some(model) => {
+            info!("reading model...");
+            read_model_points(model)?
+        }
+        None => {
+            params
+                .point_cloud
+                .validate(scans.keys().map(String::as_str))?;
+            info!("building point clouds...");
+            pool.install(|| {
+                build_frame_clouds(&scans, &scan_frames, &params.point_cloud)
+            })?
+            .into_iter()
+            .flatten()
+            .map(|p| p.0)
+            .collect()
+        }
+    };
+    if points.is_empty() {
+        let desc = "no subject points to crop frames to".to_string();
+        return Err(Error::new(InconsistentState, desc));
+    }
+
+    let image_size = |frames: &[fm::ScanFrame]| {
+        let images = frames.iter().filter_map(|f| f.image.as_ref());
+        images.map(|i| i.data.len() as u64).sum::<u64>()
+    };
+    let size = image_size(&scan_frames);
+
+    info!(
+        "cropping {} frames to {} subject points...",
+        scan_frames.len(),
+        points.len()
+    );
+    let num_cropped = pool
+        .install(|| {
+            scan_frames
+                .par_iter_mut()
+                .map(|frame| {
+                    crop_frame(&scans[&frame.scan], frame, &points, params)
+                })
+                .collect::<Result<Vec<bool>>>()
+        })?
+        .into_iter()
+        .filter(|c| *c)
+        .count();
+    telemetry::count("cropped frames", num_cropped);
+    info!(
+        "cropped {} frames, images shrunk from {} to {}",
+        num_cropped,
+        format_size(size),
+        format_size(image_size(&scan_frames))
+    );
+
+    info!("writing scans...");
+    use fm::record::Type;
+    for (_, scan) in scans {
+        writer.write_record(&fm::Record {
+            r#type: Some(Type::Scan(scan)),
+        })?;
+    }
+    for frame in scan_frames {
+        writer.write_record(&fm::Record {
+            r#type: Some(Type::ScanFrame(frame)),
+        })?;
+    }
+
+    info!("done");
+    Ok(())
+}
+
+// Reads vertices of all element states, so that crops bound all poses.
+fn read_model_points(reader: &mut dyn fm::Read) -> Result<Vec<Point3>> {
+    let mut points = Vec::new();
+    while let Some(rec) = reader.read_record()? {
+        if let Some(fm::record::Type::ElementViewState(state)) = rec.r#type {
+            points.extend(
+                state
+                    .vertices
+                    .iter()
+                    .map(|v| Point3::new(v.x as f64, v.y as f64, v.z as f64)),
+            );
+        }
+    }
+    Ok(points)
+}
+
+// Crops frame image to the bounding box of points projected into it
+// (with margin). Returns false if the frame is left as is.
+pub fn crop_frame(
+    scan: &fm::Scan,
+    frame: &mut fm::ScanFrame,
+    points: &[Point3],
+    params: &CropFramesParams,
+) -> Result<bool> {
+    let image = match load_frame_image(frame) {
+        Some(image) => image,
+        None => return Ok(false),
+    };
+
+    // Points beyond image sides widen the box up to them.
+    let mut bounds: Option<([f64; 2], [f64; 2])> = None;
+    for projected in project_like_camera(scan, frame, points)? {
+        if projected.depth <= 0.0 {
+            continue;
+        }
+        let point = projected.point.map(|c| c.clamp(0.0, 1.0));
+        let point = [point[0], point[1]];
+        let (min, max) = bounds.get_or_insert((point, point));
+        for k in 0..2 {
+            min[k] = min[k].min(point[k]);
+            max[k] = max[k].max(point[k]);
+        }
+    }
+    let (min, max) = match bounds {
+        Some(bounds) => bounds,
+        None => return Ok(false),
+    };
+
+    let (width, height) = image.dimensions();
+    let margin = params.crop_margin;
+    let to_pixel = |c: f64, size: u32| c.clamp(0.0, 1.0) * size as f64;
+    let (y0, x0) = (
+        to_pixel(min[0] - margin, height).floor() as u32,
+        to_pixel(min[1] - margin, width).floor() as u32,
+    );
+    let (y1, x1) = (
+        to_pixel(max[0] + margin, height).ceil() as u32,
+        to_pixel(max[1] + margin, width).ceil() as u32,
+    );
+    if y1 <= y0 || x1 <= x0 || (x0, y0, x1, y1) == (0, 0, width, height) {
+        return Ok(false);
+    }
+
+    let cropped = imageops::crop_imm(&image, x0, y0, x1 - x0, y1 - y0);
+    let image_type = frame.image.as_ref().unwrap().r#type();
+    let image = encode_image(&cropped.to_image(), image_type, params)?;
+
+    // Crop of previously cropped image is relative to it.
+    let whole = |y: u32, x: u32| {
+        let point = [y as f64 / height as f64, x as f64 / width as f64];
+        match &frame.image_crop {
+            Some(crop) => crop.uncrop_point(point),
+            None => point,
+        }
+    };
+    let ([top, left], [bottom, right]) = (whole(y0, x0), whole(y1, x1));
+    frame.image_crop = Some(fm::scan_frame::ImageCrop {
+        top: top as f32,
+        left: left as f32,
+        bottom: bottom as f32,
+        right: right as f32,
+    });
+    frame.image = Some(image);
+    Ok(true)
+}
+
+fn encode_image(
+    image: &RgbImage,
+    image_type: fm::image::Type,
+    params: &CropFramesParams,
+) -> Result<fm::Image> {
+    let (width, height) = image.dimensions();
+    if image_type != fm::image::Type::Jpeg {
+        return encode_png(image.as_ref(), width, height, ColorType::Rgb8);
+    }
+
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, params.crop_jpeg_quality)
+        .write_image(image.as_ref(), width, height, ColorType::Rgb8)
+        .map_err(|e| {
+            let desc = "failed to encode JPEG image".to_string();
+            Error::with_source(ImageError, desc, e)
+        })?;
+    Ok(fm::Image {
+        r#type: fm::image::Type::Jpeg as i32,
+        data,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+
+    fn create_params() -> CropFramesParams {
+        CropFramesParams::from_iter(["crop-frames"])
+    }
+
+    fn create_scan() -> fm::Scan {
+        fm::Scan {
+            name: "a".to_string(),
+            camera_initial_position: Some(new_point3(0.0, -2.0, 0.0)),
+            camera_initial_direction: Some(new_point3(0.0, 1.0, 0.0)),
+            camera_angle_of_view: 1.0,
+            depth_width: 40,
+            depth_height: 40,
+            ..Default::default()
+        }
+    }
+
+    fn create_frame() -> fm::ScanFrame {
+        let image = RgbImage::new(40, 40);
+        let image = encode_png(image.as_ref(), 40, 40, ColorType::Rgb8);
+        fm::ScanFrame {
+            scan: "a".to_string(),
+            image: Some(image.unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_crop_frame() {
+        let scan = create_scan();
+        let points = [Point3::new(-0.2, 0.0, -0.1), Point3::new(0.1, 0.0, 0.3)];
+        let projected = project_like_camera(&scan, &create_frame(), &points)
+            .unwrap()
+            .iter()
+            .map(|p| p.point)
+            .collect::<Vec<_>>();
+
+        let mut params = create_params();
+        params.crop_margin = 0.0;
+        let mut frame = create_frame();
+        assert!(crop_frame(&scan, &mut frame, &points, &params).unwrap());
+
+        let image = load_frame_image(&frame).unwrap();
+        let crop = frame.image_crop.clone().unwrap();
+        let (width, height) = image.dimensions();
+        assert!(width < 40 && height < 40);
+        assert!(crop.is_valid());
+        let width_ratio = (crop.right - crop.left) * 40.0;
+        let height_ratio = (crop.bottom - crop.top) * 40.0;
+        assert!((width_ratio - width as f32).abs() < 1e-4);
+        assert!((height_ratio - height as f32).abs() < 1e-4);
+
+        // Points stay within the cropped image.
+        let recropped = project_like_camera(&scan, &frame, &points).unwrap();
+        for (whole, cropped) in projected.iter().zip(recropped) {
+            let point = [cropped.point[0], cropped.point[1]];
+            assert!(point.iter().all(|c| (0.0..=1.0).contains(c)));
+            let point = crop.uncrop_point(point);
+            assert!((point[0] - whole[0]).abs() < 1e-6);
+            assert!((point[1] - whole[1]).abs() < 1e-6);
+        }
+
+        // Cropping again is relative to the cropped image.
+        params.crop_margin = 0.5;
+        assert!(!crop_frame(&scan, &mut frame, &points, &params).unwrap());
+        params.crop_margin = 0.0;
+        let points = &points[..1];
+        assert!(crop_frame(&scan, &mut frame, points, &params).unwrap());
+        let recrop = frame.image_crop.unwrap();
+        assert!(recrop.top >= crop.top && recrop.bottom <= crop.bottom);
+        assert!(recrop.left >= crop.left && recrop.right <= crop.right);
+    }
+
+    #[test]
+    fn test_crop_frame_unseen() {
+        let scan = create_scan();
+        let mut frame = create_frame();
+        let points = [Point3::new(0.0, -3.0, 0.0)];
+        let params = create_params();
+        assert!(!crop_frame(&scan, &mut frame, &points, &params).unwrap());
+        assert!(frame.image_crop.is_none());
+    }
+}
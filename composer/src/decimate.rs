@@ -0,0 +1,1354 @@
+use std::cmp::{Ord, Ordering};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+
+use derive_more::{Add, AddAssign};
+use image::codecs::png::PngEncoder;
+use image::{ImageEncoder, Rgb, RgbImage};
+use log::info;
+use petgraph::unionfind::UnionFind;
+use structopt::StructOpt;
+
+use crate::bvh::{Aabb, Bvh};
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{Point3, Vector3};
+use crate::texture::BarycentricCoordinateSystem;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+pub type Vector2 = nalgebra::Vector2<f64>;
+pub type Vector5 = nalgebra::SVector<f64, 5>;
+pub type Matrix5 = nalgebra::SMatrix<f64, 5, 5>;
+
+#[derive(StructOpt)]
+#[structopt(about = "Decimate (possibly textured) elements of .fm file")]
+pub struct DecimateCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Element to decimate (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(flatten)]
+    params: DecimateParams,
+}
+
+impl DecimateCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        decimate(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.elements,
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct DecimateParams {
+    #[structopt(
+        help = "Surface decimation ratio",
+        long,
+        default_value = "0.5"
+    )]
+    pub decimate_ratio: f64,
+
+    #[structopt(
+        help = "Weight of texture deviation relative to geometric one",
+        long,
+        default_value = "1"
+    )]
+    pub decimate_texture_weight: f64,
+
+    #[structopt(
+        help = "Size of normal map capturing decimation error (for exports, \
+                it isn't rendered by viewer)",
+        long
+    )]
+    pub decimate_normal_map_size: Option<u32>,
+
+    #[structopt(
+        help = "Write decimated mesh with vertex splits restoring the original",
+        long
+    )]
+    pub decimate_progressive: bool,
+}
+
+impl CheckParams for DecimateParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        let ratio = self.decimate_ratio;
+        check.require(ratio > 0.0 && ratio <= 1.0, || {
+            format!("--decimate-ratio {} should be within (0, 1]", ratio)
+        });
+        check.require(self.decimate_texture_weight > 0.0, || {
+            "--decimate-texture-weight should be positive".to_string()
+        });
+        check.require(
+            !self.decimate_progressive
+                || self.decimate_normal_map_size.is_none(),
+            || {
+                "--decimate-normal-map-size can't be used with \
+                 --decimate-progressive"
+                    .to_string()
+            },
+        );
+    }
+}
+
+// Corner attributes of mesh vertex: a vertex on a UV seam (or a hard edge)
+// has several wedges differing in texture point (or normal).
+#[derive(Clone, Copy, Debug)]
+pub struct Wedge {
+    pub vertex: usize, // Index of the original vertex.
+    pub position: Point3,
+    pub normal: Vector3,
+    pub uv: Vector2,
+}
+
+impl Wedge {
+    fn fm_vertex(&self) -> fm::Point3 {
+        fm::Point3 {
+            x: self.position.x as f32,
+            y: self.position.y as f32,
+            z: self.position.z as f32,
+        }
+    }
+
+    fn fm_normal(&self) -> fm::Point3 {
+        fm::Point3 {
+            x: self.normal.x as f32,
+            y: self.normal.y as f32,
+            z: self.normal.z as f32,
+        }
+    }
+
+    fn fm_texture_point(&self) -> fm::Point2 {
+        fm::Point2 {
+            x: self.uv.x as f32,
+            y: self.uv.y as f32,
+        }
+    }
+}
+
+// Face of 1-based vertices, its texture points and normals are numbered
+// by wedges.
+fn new_fm_face(
+    vertices: [u32; 3],
+    wedges: [usize; 3],
+    has_texture: bool,
+    has_normals: bool,
+) -> fm::element_view::Face {
+    let attr = |w: usize, enabled| if enabled { w as u32 + 1 } else { 0 };
+    fm::element_view::Face {
+        vertex1: vertices[0],
+        vertex2: vertices[1],
+        vertex3: vertices[2],
+        texture1: attr(wedges[0], has_texture),
+        texture2: attr(wedges[1], has_texture),
+        texture3: attr(wedges[2], has_texture),
+        normal1: attr(wedges[0], has_normals),
+        normal2: attr(wedges[1], has_normals),
+        normal3: attr(wedges[2], has_normals),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WedgeMesh {
+    pub wedges: Vec<Wedge>,
+    pub faces: Vec<[usize; 3]>,
+    pub labels: Vec<u16>, // Face labels, empty if the faces are unlabeled.
+}
+
+impl WedgeMesh {
+    pub fn from_element(
+        view: &fm::ElementView,
+        state: &fm::ElementViewState,
+    ) -> Result<WedgeMesh> {
+        if !view.texture_pages.is_empty() {
+            let desc = format!(
+                "decimation of multi-page texture of element '{}' isn't \
+                 supported",
+                view.element
+            );
+            return Err(Error::new(UnsupportedFeature, desc));
+        }
+
+        let to_vector =
+            |p: &fm::Point3| Vector3::new(p.x as f64, p.y as f64, p.z as f64);
+        let index_err = || {
+            let desc = format!(
+                "face index out of bounds for element '{}'",
+                view.element
+            );
+            Error::new(MalformedData, desc)
+        };
+
+        let mut mesh = WedgeMesh {
+            labels: fm::face_labels(view)?.unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut wedges = HashMap::new();
+        for face in &view.faces {
+            let corners = [
+                (face.vertex1, face.texture1, face.normal1),
+                (face.vertex2, face.texture2, face.normal2),
+                (face.vertex3, face.texture3, face.normal3),
+            ];
+
+            let mut indices = [0; 3];
+            for (i, &(v, t, n)) in corners.iter().enumerate() {
+                if v == 0 || v as usize > state.vertices.len() {
+                    return Err(index_err());
+                }
+                if t as usize > view.texture_points.len()
+                    || n as usize > state.normals.len()
+                {
+                    return Err(index_err());
+                }
+
+                indices[i] = *wedges.entry((v, t, n)).or_insert_with(|| {
+                    let uv = match t {
+                        0 => Vector2::zeros(),
+                        t => {
+                            let p = &view.texture_points[t as usize - 1];
+                            Vector2::new(p.x as f64, p.y as f64)
+                        }
+                    };
+                    let normal = match n {
+                        0 => Vector3::zeros(),
+                        n => to_vector(&state.normals[n as usize - 1]),
+                    };
+                    mesh.wedges.push(Wedge {
+                        vertex: v as usize - 1,
+                        position: Point3::from(to_vector(
+                            &state.vertices[v as usize - 1],
+                        )),
+                        normal,
+                        uv,
+                    });
+                    mesh.wedges.len() - 1
+                });
+            }
+            mesh.faces.push(indices);
+        }
+
+        Ok(mesh)
+    }
+
+    // Texture and normals of the view are replaced if it has them.
+    pub fn to_element(
+        &self,
+        view: &fm::ElementView,
+    ) -> (fm::ElementView, fm::ElementViewState) {
+        let has_texture = !view.texture_points.is_empty();
+        let has_normals =
+            self.wedges.iter().any(|w| w.normal != Vector3::zeros());
+
+        let mut state = fm::ElementViewState {
+            element: view.element.clone(),
+            ..Default::default()
+        };
+        let mut vertices = HashMap::new();
+        let vertex_indices: Vec<u32> = self
+            .wedges
+            .iter()
+            .map(|w| {
+                *vertices.entry(w.vertex).or_insert_with(|| {
+                    state.vertices.push(w.fm_vertex());
+                    state.vertices.len() as u32
+                })
+            })
+            .collect();
+
+        if has_normals {
+            state.normals = self.wedges.iter().map(Wedge::fm_normal).collect();
+        }
+
+        let mut view = fm::ElementView {
+            faces: Vec::with_capacity(self.faces.len()),
+            texture_points: Vec::new(),
+            face_labels: self.labels.iter().map(|&l| l as u32).collect(),
+            ..view.clone()
+        };
+        if has_texture {
+            view.texture_points =
+                self.wedges.iter().map(Wedge::fm_texture_point).collect();
+        }
+
+        for face in &self.faces {
+            view.faces.push(new_fm_face(
+                face.map(|w| vertex_indices[w]),
+                *face,
+                has_texture,
+                has_normals,
+            ));
+        }
+
+        (view, state)
+    }
+}
+
+// Generalized quadric over position and scaled texture coordinates
+// (Garland and Heckbert, 1998).
+#[derive(Add, AddAssign, Copy, Clone)]
+struct AttributeQuadric {
+    a: Matrix5,
+    b: Vector5,
+    c: f64,
+}
+
+impl AttributeQuadric {
+    fn zero() -> Self {
+        Self {
+            a: Matrix5::zeros(),
+            b: Vector5::zeros(),
+            c: 0.0,
+        }
+    }
+
+    fn make_triangle(p: &Vector5, q: &Vector5, r: &Vector5) -> Option<Self> {
+        let e1 = (q - p).try_normalize(f64::EPSILON)?;
+        let r = r - p;
+        let e2 = (r - e1 * e1.dot(&r)).try_normalize(f64::EPSILON)?;
+        let (pe1, pe2) = (p.dot(&e1), p.dot(&e2));
+        Some(Self {
+            a: Matrix5::identity() - e1 * e1.transpose() - e2 * e2.transpose(),
+            b: e1 * pe1 + e2 * pe2 - p,
+            c: p.dot(p) - pe1 * pe1 - pe2 * pe2,
+        })
+    }
+
+    fn optimum(&self) -> Option<Vector5> {
+        const EPSILON: f64 = 1e-10;
+        let inverse = self.a.pseudo_inverse(EPSILON).ok()?;
+        Some(-(inverse * self.b))
+    }
+
+    fn eval(&self, v: &Vector5) -> f64 {
+        v.dot(&(self.a * v)) + 2.0 * self.b.dot(v) + self.c
+    }
+}
+
+struct Candidate {
+    cost: f64,             // Cost of contraction.
+    edge: [usize; 2],      // Edge to be contracted.
+    point: Vector5,        // Optimal point of contraction.
+    timestamp: [usize; 2], // Used to identify and discard obsolete candidates.
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cost.total_cmp(&other.cost).reverse()
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for Candidate {}
+
+// Contraction of two wedges, recorded to be reversed by a vertex split.
+struct Contraction {
+    kept: usize,
+    removed: usize,
+    kept_before: Wedge,
+    removed_before: Wedge,
+}
+
+// Wedges and faces (by 0-based indices, with their labels) to be replaced
+// or appended.
+#[derive(Clone, Debug, Default)]
+pub struct VertexSplit {
+    pub wedges: Vec<(usize, Wedge)>,
+    pub faces: Vec<(usize, [usize; 3], u16)>,
+}
+
+// Quadric edge collapse decimation over wedges. Wedges of seam vertices
+// and of boundary edges are locked, so seams keep their shape and sides
+// of a seam stay stitched.
+pub struct TexturedDecimator {
+    mesh: WedgeMesh,
+    uv_scale: f64,
+    points: Vec<Vector5>,
+    locked: Vec<bool>,
+    quadrics: Vec<AttributeQuadric>,
+    partition: UnionFind<usize>,
+    partition_sizes: Vec<usize>,
+    neighbors: Vec<HashSet<usize>>,
+    heap: BinaryHeap<Candidate>,
+    history: Vec<Contraction>,
+}
+
+impl TexturedDecimator {
+    pub fn execute(
+        mesh: WedgeMesh,
+        ratio: f64,
+        texture_weight: f64,
+    ) -> WedgeMesh {
+        assert!(0.0 < ratio && ratio <= 1.0);
+        assert!(texture_weight > 0.0);
+
+        let mut d = TexturedDecimator::new(mesh, texture_weight);
+        d.contract(ratio);
+        d.finalize()
+    }
+
+    // Returns the decimated mesh and vertex splits which restore the
+    // original one (up to wedges of a vertex becoming separate vertices).
+    pub fn execute_progressive(
+        mesh: WedgeMesh,
+        ratio: f64,
+        texture_weight: f64,
+    ) -> (WedgeMesh, Vec<VertexSplit>) {
+        assert!(0.0 < ratio && ratio <= 1.0);
+        assert!(texture_weight > 0.0);
+
+        let mut d = TexturedDecimator::new(mesh, texture_weight);
+        d.contract(ratio);
+        d.split()
+    }
+
+    fn contract(&mut self, ratio: f64) {
+        let mut faces_to_remove =
+            (self.mesh.faces.len() as f64 * (1.0 - ratio)) as isize;
+        while faces_to_remove > 0 {
+            let c = match self.heap.pop() {
+                Some(c) => c,
+                None => break,
+            };
+            if self.try_contract(c.point, c.edge, c.timestamp) {
+                faces_to_remove -= 2;
+            }
+        }
+    }
+
+    fn new(mesh: WedgeMesh, texture_weight: f64) -> TexturedDecimator {
+        // UVs are scaled to be comparable with geometric distances.
+        let mut min = Vector3::repeat(f64::MAX);
+        let mut max = Vector3::repeat(f64::MIN);
+        for w in &mesh.wedges {
+            min = min.inf(&w.position.coords);
+            max = max.sup(&w.position.coords);
+        }
+        let diagonal = (max - min).norm();
+        let uv_scale = texture_weight * diagonal.max(f64::EPSILON);
+
+        let points: Vec<_> = mesh
+            .wedges
+            .iter()
+            .map(|w| {
+                let p = w.position;
+                let uv = w.uv * uv_scale;
+                Vector5::new(p.x, p.y, p.z, uv.x, uv.y)
+            })
+            .collect();
+
+        let mut quadrics = vec![AttributeQuadric::zero(); points.len()];
+        for &[w0, w1, w2] in &mesh.faces {
+            let (p0, p1, p2) = (&points[w0], &points[w1], &points[w2]);
+            if let Some(q) = AttributeQuadric::make_triangle(p0, p1, p2) {
+                quadrics[w0] += q;
+                quadrics[w1] += q;
+                quadrics[w2] += q;
+            }
+        }
+
+        let mut num_vertex_wedges = HashMap::<usize, usize>::new();
+        for w in &mesh.wedges {
+            *num_vertex_wedges.entry(w.vertex).or_default() += 1;
+        }
+        let mut locked: Vec<_> = mesh
+            .wedges
+            .iter()
+            .map(|w| num_vertex_wedges[&w.vertex] > 1)
+            .collect();
+
+        let mut edge_faces = HashMap::<[usize; 2], usize>::new();
+        let mut neighbors = vec![HashSet::new(); points.len()];
+        for &[w0, w1, w2] in &mesh.faces {
+            for [a, b] in [[w0, w1], [w1, w2], [w2, w0]] {
+                *edge_faces.entry([a.min(b), a.max(b)]).or_default() += 1;
+                neighbors[a].insert(b);
+                neighbors[b].insert(a);
+            }
+        }
+        for (edge, num) in edge_faces {
+            if num == 1 {
+                locked[edge[0]] = true;
+                locked[edge[1]] = true;
+            }
+        }
+
+        let mut decimator = TexturedDecimator {
+            partition: UnionFind::new(points.len()),
+            partition_sizes: vec![1; points.len()],
+            mesh,
+            uv_scale,
+            points,
+            locked,
+            quadrics,
+            neighbors,
+            heap: BinaryHeap::new(),
+            history: Vec::new(),
+        };
+
+        for w0 in 0..decimator.points.len() {
+            let edges: Vec<_> = decimator.neighbors[w0]
+                .iter()
+                .filter(|&&w1| w0 < w1)
+                .map(|&w1| [w0, w1])
+                .collect();
+            for edge in edges {
+                decimator.push_candidate(edge);
+            }
+        }
+
+        decimator
+    }
+
+    fn push_candidate(&mut self, edge: [usize; 2]) {
+        let [w0, w1] = edge;
+        let quadric = self.quadrics[w0] + self.quadrics[w1];
+        let (p0, p1) = (self.points[w0], self.points[w1]);
+
+        let point = match (self.locked[w0], self.locked[w1]) {
+            (true, true) => return,
+            (true, false) => p0,
+            (false, true) => p1,
+            (false, false) => {
+                let mut options = vec![p0, p1, (p0 + p1) / 2.0];
+                options.extend(quadric.optimum());
+                options
+                    .into_iter()
+                    .min_by(|a, b| quadric.eval(a).total_cmp(&quadric.eval(b)))
+                    .unwrap()
+            }
+        };
+
+        let timestamp = self.edge_timestamp(edge);
+        self.heap.push(Candidate {
+            cost: quadric.eval(&point),
+            edge,
+            point,
+            timestamp,
+        });
+    }
+
+    fn edge_timestamp(&self, e: [usize; 2]) -> [usize; 2] {
+        [self.partition_sizes[e[0]], self.partition_sizes[e[1]]]
+    }
+
+    fn try_contract(
+        &mut self,
+        point: Vector5,
+        edge: [usize; 2],
+        ts: [usize; 2],
+    ) -> bool {
+        let [w0, w1] = edge;
+        if w0 != self.partition.find(w0)
+            || w1 != self.partition.find(w1)
+            || self.edge_timestamp(edge) != ts
+        {
+            return false;
+        }
+
+        let vertex = if self.locked[w1] {
+            self.mesh.wedges[w1].vertex
+        } else {
+            self.mesh.wedges[w0].vertex
+        };
+        let normal = self.mesh.wedges[w0].normal + self.mesh.wedges[w1].normal;
+        let before = [self.wedge(w0), self.wedge(w1)];
+
+        self.partition.union(w0, w1);
+        let w = self.partition.find(w0);
+        let kept = (w != w0) as usize;
+        self.history.push(Contraction {
+            kept: edge[kept],
+            removed: edge[1 - kept],
+            kept_before: before[kept],
+            removed_before: before[1 - kept],
+        });
+
+        self.partition_sizes[w] =
+            self.partition_sizes[w0] + self.partition_sizes[w1];
+        self.points[w] = point;
+        self.locked[w] = self.locked[w0] || self.locked[w1];
+        self.quadrics[w] = self.quadrics[w0] + self.quadrics[w1];
+        self.mesh.wedges[w].vertex = vertex;
+        self.mesh.wedges[w].normal =
+            normal.try_normalize(f64::EPSILON).unwrap_or(normal);
+        self.neighbors[w] = self.neighbors[w0]
+            .union(&self.neighbors[w1])
+            .map(|&n| self.partition.find(n))
+            .filter(|&n| n != w)
+            .collect();
+
+        let neighbors: Vec<_> = self.neighbors[w].iter().copied().collect();
+        for n in neighbors {
+            self.push_candidate([w, n]);
+        }
+
+        true
+    }
+
+    fn wedge(&self, w: usize) -> Wedge {
+        let p = &self.points[w];
+        Wedge {
+            position: Point3::new(p[0], p[1], p[2]),
+            uv: Vector2::new(p[3], p[4]) / self.uv_scale,
+            ..self.mesh.wedges[w]
+        }
+    }
+
+    // Reverses contractions, wedges are numbered in order of appearance
+    // and keep their numbers as vertices.
+    fn split(self) -> (WedgeMesh, Vec<VertexSplit>) {
+        let num_wedges = self.points.len();
+        let mut absorbed = vec![None; num_wedges];
+        let mut children = vec![Vec::new(); num_wedges];
+        for (i, c) in self.history.iter().enumerate() {
+            absorbed[c.removed] = Some((i, c.kept));
+            children[c.kept].push(c.removed);
+        }
+
+        // Wedge which represents given one after given number of
+        // contractions.
+        let represent = |mut w: usize, level: usize| {
+            while let Some((i, kept)) = absorbed[w] {
+                if i >= level {
+                    break;
+                }
+                w = kept;
+            }
+            w
+        };
+
+        let mut wedge_faces = vec![Vec::new(); num_wedges];
+        for (i, face) in self.mesh.faces.iter().enumerate() {
+            for &w in face {
+                wedge_faces[w].push(i);
+            }
+        }
+
+        let mut wedge_ids = vec![None; num_wedges];
+        let mut base = WedgeMesh::default();
+        for w in 0..num_wedges {
+            if absorbed[w].is_none() {
+                wedge_ids[w] = Some(base.wedges.len());
+                base.wedges.push(Wedge {
+                    vertex: base.wedges.len(),
+                    ..self.wedge(w)
+                });
+            }
+        }
+        let mut num_wedges = base.wedges.len();
+
+        let level = self.history.len();
+        let face_at = |face: &[usize; 3], level, ids: &[Option<usize>]| {
+            let [w0, w1, w2] = face.map(|w| represent(w, level));
+            if w0 == w1 || w0 == w2 || w1 == w2 {
+                return None;
+            }
+            Some([w0, w1, w2].map(|w| ids[w].unwrap()))
+        };
+
+        // Faces keep their original labels, so that the splits restore
+        // them exactly.
+        let label = |i: usize| self.mesh.labels.get(i).copied().unwrap_or(0);
+        let mut face_ids = vec![None; self.mesh.faces.len()];
+        for (i, face) in self.mesh.faces.iter().enumerate() {
+            if let Some(face) = face_at(face, level, &wedge_ids) {
+                face_ids[i] = Some(base.faces.len());
+                base.faces.push(face);
+                if !self.mesh.labels.is_empty() {
+                    base.labels.push(label(i));
+                }
+            }
+        }
+        let mut num_faces = base.faces.len();
+
+        let mut splits = Vec::with_capacity(level);
+        for (level, c) in self.history.iter().enumerate().rev() {
+            let mut split = VertexSplit::default();
+            let kept_id = wedge_ids[c.kept].unwrap();
+            split.wedges.push((
+                kept_id,
+                Wedge {
+                    vertex: kept_id,
+                    ..c.kept_before
+                },
+            ));
+            wedge_ids[c.removed] = Some(num_wedges);
+            split.wedges.push((
+                num_wedges,
+                Wedge {
+                    vertex: num_wedges,
+                    ..c.removed_before
+                },
+            ));
+            num_wedges += 1;
+
+            let mut faces = Vec::new();
+            let mut stack = vec![c.removed];
+            while let Some(w) = stack.pop() {
+                faces.extend_from_slice(&wedge_faces[w]);
+                stack.extend_from_slice(&children[w]);
+            }
+            faces.sort_unstable();
+            faces.dedup();
+
+            for i in faces {
+                let face = &self.mesh.faces[i];
+                if let Some(face) = face_at(face, level, &wedge_ids) {
+                    let id = *face_ids[i].get_or_insert_with(|| {
+                        num_faces += 1;
+                        num_faces - 1
+                    });
+                    split.faces.push((id, face, label(i)));
+                }
+            }
+
+            splits.push(split);
+        }
+
+        (base, splits)
+    }
+
+    fn finalize(self) -> WedgeMesh {
+        let mut kept = self.partition.clone().into_labeling();
+        kept.sort_unstable();
+        kept.dedup();
+        let new_indices: HashMap<_, _> =
+            kept.iter().enumerate().map(|(i, &w)| (w, i)).collect();
+
+        let wedges = kept
+            .iter()
+            .map(|&w| {
+                let p = &self.points[w];
+                Wedge {
+                    position: Point3::new(p[0], p[1], p[2]),
+                    uv: Vector2::new(p[3], p[4]) / self.uv_scale,
+                    ..self.mesh.wedges[w]
+                }
+            })
+            .collect();
+
+        // Surviving faces along with their original indices.
+        let mut faces: Vec<_> = self
+            .mesh
+            .faces
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.map(|w| new_indices[&self.partition.find(w)]), i))
+            .filter(|([w0, w1, w2], _)| w0 != w1 && w0 != w2 && w1 != w2)
+            .collect();
+        faces.sort_unstable();
+        faces.dedup_by_key(|(face, _)| *face);
+
+        let labels = if self.mesh.labels.is_empty() {
+            Vec::new()
+        } else {
+            self.vote_labels(&faces, &new_indices)
+        };
+        let faces = faces.into_iter().map(|(face, _)| face).collect();
+
+        WedgeMesh {
+            wedges,
+            faces,
+            labels,
+        }
+    }
+
+    // Labels of surviving faces by majority vote (weighted by area) among
+    // the original face and the collapsed faces around it.
+    fn vote_labels(
+        &self,
+        faces: &[([usize; 3], usize)],
+        new_indices: &HashMap<usize, usize>,
+    ) -> Vec<u16> {
+        let mesh = &self.mesh;
+        let area = |i: usize| {
+            let [p0, p1, p2] = mesh.faces[i].map(|w| mesh.wedges[w].position);
+            ((p1 - p0).cross(&(p2 - p0)).norm() / 2.0).max(f64::MIN_POSITIVE)
+        };
+
+        let mut votes = vec![BTreeMap::<u16, f64>::new(); faces.len()];
+        let mut wedge_faces = vec![Vec::new(); new_indices.len()];
+        for (j, (face, i)) in faces.iter().enumerate() {
+            votes[j].insert(mesh.labels[*i], area(*i));
+            for &w in face {
+                wedge_faces[w].push(j);
+            }
+        }
+
+        for (i, face) in mesh.faces.iter().enumerate() {
+            let [w0, w1, w2] =
+                face.map(|w| new_indices[&self.partition.find(w)]);
+            if w0 != w1 && w0 != w2 && w1 != w2 {
+                continue;
+            }
+            let mut neighbors: Vec<_> = [w0, w1, w2]
+                .iter()
+                .flat_map(|&w| wedge_faces[w].iter().copied())
+                .collect();
+            neighbors.sort_unstable();
+            neighbors.dedup();
+            for j in neighbors {
+                *votes[j].entry(mesh.labels[i]).or_default() += area(i);
+            }
+        }
+
+        // Ties are resolved to the smallest label.
+        votes
+            .into_iter()
+            .map(|v| {
+                let max = v.values().copied().fold(0.0, f64::max);
+                *v.iter().find(|(_, &w)| w == max).unwrap().0
+            })
+            .collect()
+    }
+}
+
+impl WedgeMesh {
+    fn face_uvs(&self, face: &[usize; 3]) -> [Vector2; 3] {
+        face.map(|w| self.wedges[w].uv)
+    }
+
+    // Smooth normal at barycentric coordinates, falls back to face normal.
+    fn normal_at(&self, face: &[usize; 3], bary: &Vector3) -> Vector3 {
+        let [a, b, c] = face.map(|w| &self.wedges[w]);
+        let normal =
+            a.normal * bary[0] + b.normal * bary[1] + c.normal * bary[2];
+        normal.try_normalize(f64::EPSILON).unwrap_or_else(|| {
+            (b.position - a.position)
+                .cross(&(c.position - a.position))
+                .try_normalize(f64::EPSILON)
+                .unwrap_or_else(Vector3::z)
+        })
+    }
+}
+
+// Tangent-space normals of the original surface are baked by texture
+// coordinates, which the decimation keeps close to the original ones.
+pub fn bake_normal_map(
+    original: &WedgeMesh,
+    decimated: &WedgeMesh,
+    size: u32,
+) -> RgbImage {
+    let flat = Rgb([128, 128, 255]);
+    let mut image = RgbImage::from_pixel(size, size, flat);
+
+    let to_point = |uv: &Vector2| Point3::new(uv.x, uv.y, 0.0);
+    let aabbs: Vec<_> = original
+        .faces
+        .iter()
+        .map(|f| {
+            Aabb::from_points(&original.face_uvs(f).map(|uv| to_point(&uv)))
+        })
+        .collect();
+    let bvh = Bvh::new(&aabbs);
+
+    let find_original_normal = |uv: &Vector2| {
+        let point = to_point(uv);
+        let mut normal = None;
+        bvh.for_each_intersecting(&Aabb::from_points(&[point]), |i| {
+            let face = &original.faces[i];
+            if normal.is_some() {
+                return;
+            }
+            if let Some(bcs) =
+                BarycentricCoordinateSystem::new(original.face_uvs(face))
+            {
+                let bary = bcs.infer(*uv);
+                if bary.iter().all(|&c| c >= -1e-9) {
+                    normal = Some(original.normal_at(face, &bary));
+                }
+            }
+        });
+        normal
+    };
+
+    for face in &decimated.faces {
+        let uvs = decimated.face_uvs(face);
+        let bcs = match BarycentricCoordinateSystem::new(uvs) {
+            Some(bcs) => bcs,
+            None => continue,
+        };
+
+        // Tangent and bitangent follow texture axes.
+        let [p0, p1, p2] = face.map(|w| decimated.wedges[w].position);
+        let (dp1, dp2) = (p1 - p0, p2 - p0);
+        let (duv1, duv2) = (uvs[1] - uvs[0], uvs[2] - uvs[0]);
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        let tangent = (dp1 * duv2.y - dp2 * duv1.y) / det;
+        let bitangent = (dp2 * duv1.x - dp1 * duv2.x) / det;
+
+        let to_pixel = |c: f64| (c * size as f64).clamp(0.0, size as f64);
+        let min_x =
+            uvs.iter().map(|uv| to_pixel(uv.x)).fold(f64::MAX, f64::min);
+        let max_x = uvs.iter().map(|uv| to_pixel(uv.x)).fold(0.0, f64::max);
+        let min_y =
+            uvs.iter().map(|uv| to_pixel(uv.y)).fold(f64::MAX, f64::min);
+        let max_y = uvs.iter().map(|uv| to_pixel(uv.y)).fold(0.0, f64::max);
+
+        for y in min_y as u32..(max_y.ceil() as u32).min(size) {
+            for x in min_x as u32..(max_x.ceil() as u32).min(size) {
+                let uv = Vector2::new(
+                    (x as f64 + 0.5) / size as f64,
+                    (y as f64 + 0.5) / size as f64,
+                );
+                let bary = bcs.infer(uv);
+                if bary.iter().any(|&c| c < 0.0) {
+                    continue;
+                }
+
+                let n = decimated.normal_at(face, &bary);
+                let t = (tangent - n * n.dot(&tangent))
+                    .try_normalize(f64::EPSILON)
+                    .unwrap_or_else(|| n.cross(&Vector3::x()));
+                let mut b = n.cross(&t);
+                if b.dot(&bitangent) < 0.0 {
+                    b = -b;
+                }
+
+                let original_n = find_original_normal(&uv).unwrap_or(n);
+                let local = Vector3::new(
+                    original_n.dot(&t),
+                    original_n.dot(&b),
+                    original_n.dot(&n),
+                );
+                let color = local.map(|c| ((c + 1.0) * 127.5).round() as u8);
+                image.put_pixel(x, y, Rgb([color.x, color.y, color.z]));
+            }
+        }
+    }
+
+    image
+}
+
+// Vertex splits are grouped so that each refinement about doubles number
+// of faces.
+fn to_refinements(
+    view: &fm::ElementView,
+    state: &fm::ElementViewState,
+    splits: &[VertexSplit],
+) -> Vec<fm::ElementViewRefinement> {
+    use fm::element_view_refinement::{Face, Vertex};
+
+    let has_texture = !view.texture_points.is_empty();
+    let has_normals = !state.normals.is_empty();
+    let has_labels = !view.face_labels.is_empty();
+    let new_refinement = || fm::ElementViewRefinement {
+        element: view.element.clone(),
+        ..Default::default()
+    };
+
+    let mut refinements = Vec::new();
+    let mut refinement = new_refinement();
+    let mut num_faces = view.faces.len();
+    let mut batch_num_faces = num_faces.max(1);
+
+    for split in splits {
+        for (i, w) in &split.wedges {
+            refinement.vertices.push(Vertex {
+                index: *i as u32 + 1,
+                vertex: Some(w.fm_vertex()),
+                normal: has_normals.then(|| w.fm_normal()),
+                texture_point: has_texture.then(|| w.fm_texture_point()),
+            });
+        }
+        for (i, face, label) in &split.faces {
+            num_faces = num_faces.max(i + 1);
+            refinement.faces.push(Face {
+                index: *i as u32 + 1,
+                face: Some(new_fm_face(
+                    face.map(|w| w as u32 + 1),
+                    *face,
+                    has_texture,
+                    has_normals,
+                )),
+                label: if has_labels { *label as u32 } else { 0 },
+            });
+        }
+
+        if num_faces >= 2 * batch_num_faces {
+            refinements.push(refinement);
+            refinement = new_refinement();
+            batch_num_faces = num_faces;
+        }
+    }
+
+    if !refinement.vertices.is_empty() {
+        refinements.push(refinement);
+    }
+    refinements
+}
+
+fn encode_png(image: &RgbImage) -> Result<fm::Image> {
+    let mut data = Vec::new();
+    PngEncoder::new(&mut data)
+        .write_image(
+            image.as_ref(),
+            image.width(),
+            image.height(),
+            image::ColorType::Rgb8,
+        )
+        .map_err(|e| {
+            let desc = "failed to encode normal map".to_string();
+            Error::with_source(ImageError, desc, e)
+        })?;
+    Ok(fm::Image {
+        r#type: fm::image::Type::Png as i32,
+        data,
+        ..Default::default()
+    })
+}
+
+pub fn decimate(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    elements: &[String],
+    params: &DecimateParams,
+) -> Result<()> {
+    params.check()?;
+    let ratio = params.decimate_ratio;
+    let texture_weight = params.decimate_texture_weight;
+
+    let selected = |element: &str| {
+        elements.is_empty() || elements.iter().any(|e| e == element)
+    };
+
+    let mut records = Vec::new();
+    let mut refinements = Vec::new();
+    let mut views = HashMap::new();
+    let mut decimated = HashSet::new();
+
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match rec.r#type {
+            Some(ElementView(v)) if selected(&v.element) => {
+                records.push(None);
+                views.insert(v.element.clone(), (records.len() - 1, v));
+            }
+            Some(ElementViewState(s)) if views.contains_key(&s.element) => {
+                if !decimated.insert(s.element.clone()) {
+                    let desc = format!(
+                        "decimation of animated element '{}' isn't supported",
+                        s.element
+                    );
+                    return Err(Error::new(UnsupportedFeature, desc));
+                }
+
+                let (index, view) = &views[&s.element];
+                let mesh = WedgeMesh::from_element(view, &s)?;
+                info!(
+                    "decimating element '{}' of {} faces...",
+                    s.element,
+                    mesh.faces.len()
+                );
+
+                let (view, state) = if params.decimate_progressive {
+                    let (base, splits) = TexturedDecimator::execute_progressive(
+                        mesh,
+                        ratio,
+                        texture_weight,
+                    );
+                    let (view, state) = base.to_element(view);
+                    refinements.extend(
+                        to_refinements(&view, &state, &splits).into_iter().map(
+                            |r| fm::Record {
+                                r#type: Some(ElementViewRefinement(r)),
+                            },
+                        ),
+                    );
+                    (view, state)
+                } else {
+                    let decimated = TexturedDecimator::execute(
+                        mesh.clone(),
+                        ratio,
+                        texture_weight,
+                    );
+                    let (mut view, state) = decimated.to_element(view);
+                    if let Some(size) = params.decimate_normal_map_size {
+                        if !view.texture_points.is_empty() {
+                            let image =
+                                bake_normal_map(&mesh, &decimated, size);
+                            view.normal_texture = Some(encode_png(&image)?);
+                        }
+                    }
+                    (view, state)
+                };
+
+                records[*index] = Some(fm::Record {
+                    r#type: Some(ElementView(view)),
+                });
+                records.push(Some(fm::Record {
+                    r#type: Some(ElementViewState(state)),
+                }));
+            }
+            _ => records.push(Some(rec)),
+        }
+    }
+
+    for (element, (index, view)) in views {
+        if !decimated.contains(&element) {
+            records[index] = Some(fm::Record {
+                r#type: Some(fm::record::Type::ElementView(view)),
+            });
+        }
+    }
+
+    for rec in records.into_iter().flatten().chain(refinements) {
+        writer.write_record(&rec)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    // Flat 9x9 grid with two texture charts (labeled as 1 and 2) and UV seam
+    // in the middle.
+    fn new_grid_recs() -> [fm::Record; 2] {
+        const N: u32 = 9;
+        let mut view = fm::ElementView {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+        let mut state = fm::ElementViewState {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+
+        let mut texture_indices = HashMap::new();
+        for y in 0..N {
+            for x in 0..N {
+                state.vertices.push(new_point3(x as f32, y as f32, 0.0));
+                for right in [false, true] {
+                    if (right && x < N / 2) || (!right && x > N / 2) {
+                        continue;
+                    }
+                    let u = x as f32 / 16.0 + if right { 0.5 } else { 0.0 };
+                    view.texture_points.push(new_point2(u, y as f32 / 8.0));
+                    let index = view.texture_points.len() as u32;
+                    texture_indices.insert((x, y, right), index);
+                }
+            }
+        }
+
+        for y in 0..N - 1 {
+            for x in 0..N - 1 {
+                let right = x >= N / 2;
+                let corner = |x: u32, y: u32| {
+                    (y * N + x + 1, texture_indices[&(x, y, right)])
+                };
+                let (a, b, c, d) = (
+                    corner(x, y),
+                    corner(x + 1, y),
+                    corner(x + 1, y + 1),
+                    corner(x, y + 1),
+                );
+                for [p, q, r] in [[a, b, c], [a, c, d]] {
+                    view.face_labels.push(if right { 2 } else { 1 });
+                    view.faces.push(fm::element_view::Face {
+                        vertex1: p.0,
+                        vertex2: q.0,
+                        vertex3: r.0,
+                        texture1: p.1,
+                        texture2: q.1,
+                        texture3: r.1,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        [
+            new_element_view_rec(view),
+            new_element_view_state_rec(state),
+        ]
+    }
+
+    #[test]
+    fn test_decimate_textured() {
+        let mut reader = create_reader_with_records(&new_grid_recs());
+        let mut writer = create_writer();
+        let params = DecimateParams {
+            decimate_ratio: 0.5,
+            decimate_texture_weight: 1.0,
+            decimate_normal_map_size: Some(16),
+            decimate_progressive: false,
+        };
+        decimate(&mut reader, &mut writer, &[], &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let view = record_variant!(ElementView, rec);
+        let rec = reader.read_record().unwrap().unwrap();
+        let state = record_variant!(ElementViewState, rec);
+        assert!(reader.read_record().unwrap().is_none());
+
+        assert!(view.normal_texture.is_some());
+        assert!(view.faces.len() <= 64);
+        assert!(view.faces.len() > 32);
+
+        assert_eq!(view.face_labels.len(), view.faces.len());
+        let mut seam_vertices = HashSet::new();
+        for (face, &label) in view.faces.iter().zip(&view.face_labels) {
+            let corners = [
+                (face.vertex1, face.texture1),
+                (face.vertex2, face.texture2),
+                (face.vertex3, face.texture3),
+            ];
+            let mut charts = HashSet::new();
+            for (v, t) in corners {
+                let p = &state.vertices[v as usize - 1];
+                let uv = &view.texture_points[t as usize - 1];
+                let right = uv.x > 0.5;
+                assert_eq!(label, if right { 2 } else { 1 });
+                let shift = if right { 0.5 } else { 0.0 };
+                assert!((uv.x - (p.x / 16.0 + shift)).abs() < 1e-4);
+                assert!((uv.y - p.y / 8.0).abs() < 1e-4);
+                assert_eq!(p.z, 0.0);
+                charts.insert(right);
+                if p.x == 4.0 {
+                    seam_vertices.insert(v);
+                }
+            }
+            assert_eq!(charts.len(), 1);
+        }
+        assert_eq!(seam_vertices.len(), 9);
+    }
+
+    #[test]
+    fn test_decimate_progressive() {
+        let recs = new_grid_recs();
+        let mut reader = create_reader_with_records(&recs);
+        let mut writer = create_writer();
+        let params = DecimateParams {
+            decimate_ratio: 0.25,
+            decimate_texture_weight: 1.0,
+            decimate_normal_map_size: None,
+            decimate_progressive: true,
+        };
+        decimate(&mut reader, &mut writer, &[], &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let mut view = record_variant!(ElementView, rec);
+        let rec = reader.read_record().unwrap().unwrap();
+        let mut state = record_variant!(ElementViewState, rec);
+        assert!(view.faces.len() <= 64);
+
+        let mut num_refinements = 0;
+        while let Some(rec) = reader.read_record().unwrap() {
+            let refinement = record_variant!(ElementViewRefinement, rec);
+            fm::apply_refinement(&mut view, &mut state, &refinement).unwrap();
+            num_refinements += 1;
+        }
+        assert!(num_refinements > 1);
+
+        let [original_view, original_state] = recs;
+        let original_view = record_variant!(ElementView, original_view);
+        let original_state = record_variant!(ElementViewState, original_state);
+        assert_eq!(view.faces.len(), original_view.faces.len());
+        assert_eq!(view.face_labels.len(), view.faces.len());
+
+        let face_key = |view: &fm::ElementView,
+                        state: &fm::ElementViewState,
+                        face: &fm::element_view::Face,
+                        label: u32| {
+            let mut corners = [
+                (face.vertex1, face.texture1),
+                (face.vertex2, face.texture2),
+                (face.vertex3, face.texture3),
+            ]
+            .map(|(v, t)| {
+                let p = &state.vertices[v as usize - 1];
+                let uv = &view.texture_points[t as usize - 1];
+                [p.x, p.y, p.z, uv.x, uv.y].map(|c| (c * 1024.0).round() as i32)
+            });
+            corners.sort_unstable();
+            (corners, label)
+        };
+        let keys = |view: &fm::ElementView, state: &fm::ElementViewState| {
+            let mut keys: Vec<_> = view
+                .faces
+                .iter()
+                .zip(&view.face_labels)
+                .map(|(f, &l)| face_key(view, state, f, l))
+                .collect();
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(keys(&view, &state), keys(&original_view, &original_state));
+    }
+
+    #[test]
+    fn test_decimate_label_vote() {
+        let [view, state] = new_grid_recs();
+        let mut view = record_variant!(ElementView, view);
+        let state = record_variant!(ElementViewState, state);
+
+        // Without texture there is no seam, a stray label in the middle is
+        // outvoted by collapsed neighbors.
+        view.texture_points.clear();
+        for face in view.faces.iter_mut() {
+            face.texture1 = 0;
+            face.texture2 = 0;
+            face.texture3 = 0;
+        }
+        view.face_labels = vec![1; view.faces.len()];
+        view.face_labels[(4 * 8 + 4) * 2] = 2;
+
+        let mesh = WedgeMesh::from_element(&view, &state).unwrap();
+        let decimated = TexturedDecimator::execute(mesh, 0.3, 1.0);
+        assert_eq!(decimated.labels.len(), decimated.faces.len());
+        assert!(decimated.labels.iter().all(|&l| l == 1));
+
+        view.face_labels.pop();
+        assert!(WedgeMesh::from_element(&view, &state).is_err());
+    }
+
+    #[test]
+    fn test_bake_normal_map() {
+        let [view, state] = new_grid_recs();
+        let view = record_variant!(ElementView, view);
+        let state = record_variant!(ElementViewState, state);
+
+        let decimated = WedgeMesh::from_element(&view, &state).unwrap();
+        let mut original = decimated.clone();
+        for wedge in original.wedges.iter_mut() {
+            wedge.normal = Vector3::new(1.0, 0.0, 1.0).normalize();
+        }
+
+        let image = bake_normal_map(&original, &decimated, 16);
+        assert_eq!(image.get_pixel(2, 2), &Rgb([218, 128, 218]));
+        assert_eq!(image.get_pixel(8, 8), &Rgb([128, 128, 255]));
+        assert_eq!(image.get_pixel(13, 2), &Rgb([218, 128, 218]));
+    }
+}
@@ -0,0 +1,91 @@
+use structopt::StructOpt;
+
+use crate::migrate::migrate;
+use base::defs::Result;
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Deduplicate identical images of .fm file")]
+pub struct DedupCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl DedupCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let output = cli::FmOutput {
+            path: self.output.path.clone(),
+            fm_params: fm::WriterParams {
+                dedup: true,
+                ..self.output.fm_params.clone()
+            },
+        };
+        let mut writer = output.get()?;
+
+        // The writer deduplicates images of records copied by migration.
+        migrate(reader.as_mut(), writer.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+    use fm::{Read as _, Write as _};
+
+    #[test]
+    fn test_dedup() {
+        let new_frame = |time| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "a".to_string(),
+                time,
+                image: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![1, 2, 3],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+        let records: Vec<_> = (1..=3).map(new_frame).collect();
+        let mut writer = create_writer();
+        for rec in &records {
+            writer.write_record(rec).unwrap();
+        }
+
+        let dir = std::env::temp_dir()
+            .join(format!("dedup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (in_path, out_path) = (dir.join("in.fm"), dir.join("out.fm"));
+        std::fs::write(&in_path, writer.into_inner().unwrap()).unwrap();
+
+        DedupCommand {
+            input: cli::FmInput {
+                path: Some(in_path),
+                images: Default::default(),
+                limits: Default::default(),
+            },
+            output: cli::FmOutput {
+                path: Some(out_path.clone()),
+                fm_params: Default::default(),
+            },
+        }
+        .run()
+        .unwrap();
+
+        let data = std::fs::read(&out_path).unwrap();
+        let mut reader = fm::Reader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.features(), fm::FEATURE_DEDUP);
+        for record in &records {
+            assert_eq!(&reader.read_record().unwrap().unwrap(), record);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
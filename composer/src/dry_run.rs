@@ -0,0 +1,118 @@
+use std::fmt::{Debug, Display};
+
+use base::util::cli;
+
+// Execution plan printed instead of running a command with --dry-run.
+pub struct Plan {
+    stages: Vec<String>,
+    estimates: Vec<(String, String)>,
+    warnings: Vec<String>,
+    outputs: Vec<String>,
+    params: String,
+}
+
+impl Plan {
+    pub fn new<P: Debug>(params: &P) -> Plan {
+        Plan {
+            stages: Vec::new(),
+            estimates: Vec::new(),
+            warnings: Vec::new(),
+            outputs: Vec::new(),
+            params: format!("{:#?}", params),
+        }
+    }
+
+    pub fn stage<S: Into<String>>(&mut self, desc: S) {
+        self.stages.push(desc.into());
+    }
+
+    pub fn estimate<V: Display>(&mut self, name: &str, value: V) {
+        self.estimates.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn warn<S: Into<String>>(&mut self, desc: S) {
+        self.warnings.push(desc.into());
+    }
+
+    pub fn output<S: Into<String>>(&mut self, desc: S) {
+        self.outputs.push(desc.into());
+    }
+
+    pub fn print(&self) {
+        print!("{}", self);
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "stages:")?;
+        for (i, stage) in self.stages.iter().enumerate() {
+            writeln!(f, "  {}. {}", i + 1, stage)?;
+        }
+        writeln!(f, "estimates:")?;
+        for (name, value) in &self.estimates {
+            writeln!(f, "  {}: {}", name, value)?;
+        }
+        if !self.warnings.is_empty() {
+            writeln!(f, "warnings:")?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+        }
+        writeln!(f, "outputs:")?;
+        for output in &self.outputs {
+            writeln!(f, "  {}", output)?;
+        }
+        writeln!(f, "parameters: {}", self.params)
+    }
+}
+
+pub fn output_location(output: &cli::FmOutput) -> String {
+    match &output.path {
+        Some(path) => path.display().to_string(),
+        None => "STDOUT".to_string(),
+    }
+}
+
+// Formats byte count using binary units.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let mut plan = Plan::new(&Some(0.5));
+        plan.stage("read scans");
+        plan.stage("write scans");
+        plan.estimate("frames", 10);
+        plan.output("out.fm: Scan, ScanFrame");
+        assert_eq!(
+            plan.to_string(),
+            "stages:\n  1. read scans\n  2. write scans\nestimates:\n  \
+             frames: 10\noutputs:\n  out.fm: Scan, ScanFrame\n\
+             parameters: Some(\n    0.5,\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(format_size(48 * 1024 * 1024 * 1024 * 1024), "49152.0 GiB");
+    }
+}
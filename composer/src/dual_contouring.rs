@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+
+use structopt::StructOpt;
+
+use crate::mesh::Quadric;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{Point3, Vector3};
+use crate::poisson::{Cloud, Mesh};
+use base::defs::{Error, ErrorKind::*, Result};
+
+const MAX_RESOLUTION: usize = 4096;
+
+// Weight of pulling cell vertices towards the mass point of edge
+// intersections, which keeps them stable on flat areas.
+const REGULARIZATION: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub struct Params {
+    #[structopt(
+        help = "Number of dual contouring cells along longest cloud side",
+        long = "dc-resolution",
+        default_value = "128"
+    )]
+    pub resolution: usize,
+
+    #[structopt(
+        help = "Dual contouring distance field truncation (in cells)",
+        long = "dc-truncation",
+        default_value = "2"
+    )]
+    pub truncation: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            resolution: 128,
+            truncation: 2,
+        }
+    }
+}
+
+impl CheckParams for Params {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(
+            self.resolution > 0 && self.resolution <= MAX_RESOLUTION,
+            || {
+                format!(
+                    "--dc-resolution should be within [1, {}]",
+                    MAX_RESOLUTION
+                )
+            },
+        );
+        check.require(self.truncation > 0, || {
+            "--dc-truncation should be positive".to_string()
+        });
+    }
+}
+
+type Key = [i32; 3];
+
+fn offset(mut key: Key, axis: usize, delta: i32) -> Key {
+    key[axis] += delta;
+    key
+}
+
+// Weighted sums of signed distances and normals of nearby points.
+#[derive(Clone, Copy)]
+struct Voxel {
+    distance: f64,
+    normal: Vector3,
+    weight: f64,
+}
+
+impl Voxel {
+    fn distance(&self) -> f64 {
+        self.distance / self.weight
+    }
+
+    fn normal(&self) -> Vector3 {
+        self.normal.try_normalize(f64::EPSILON).unwrap_or_default()
+    }
+}
+
+// Sparse truncated signed distance field sampled at grid corners.
+struct Field {
+    origin: Point3,
+    cell: f64,
+    voxels: HashMap<Key, Voxel>,
+}
+
+impl Field {
+    fn new(cloud: &dyn Cloud<f64>, params: &Params) -> Option<Field> {
+        let points: Vec<(Point3, Vector3)> = (0..cloud.len())
+            .filter_map(|i| {
+                let point = Point3::from(cloud.point(i));
+                let normal = Vector3::from(cloud.normal(i));
+                let normal = normal.try_normalize(f64::EPSILON)?;
+                Some((point, normal))
+                    .filter(|_| point.coords.norm().is_finite())
+            })
+            .collect();
+
+        let (first, _) = points.first()?;
+        let (mut min, mut max) = (first.coords, first.coords);
+        for (point, _) in &points {
+            min = min.inf(&point.coords);
+            max = max.sup(&point.coords);
+        }
+        let extent = (max - min).max();
+        let cell = if extent > 0.0 {
+            extent / params.resolution as f64
+        } else {
+            1.0
+        };
+
+        let trunc = params.truncation as i32;
+        let radius = params.truncation as f64 * cell;
+        let mut field = Field {
+            origin: Point3::from(min) - Vector3::repeat(radius + cell),
+            cell,
+            voxels: HashMap::new(),
+        };
+
+        for (point, normal) in &points {
+            let base = (point - field.origin) / cell;
+            let base = base.map(|c| c.floor() as i32);
+            for dx in -trunc..=trunc + 1 {
+                for dy in -trunc..=trunc + 1 {
+                    for dz in -trunc..=trunc + 1 {
+                        let key = [base[0] + dx, base[1] + dy, base[2] + dz];
+                        let delta = field.position(key) - point;
+                        let weight = 1.0 - delta.norm() / radius;
+                        if weight <= 0.0 {
+                            continue;
+                        }
+                        let voxel = field.voxels.entry(key).or_insert(Voxel {
+                            distance: 0.0,
+                            normal: Vector3::zeros(),
+                            weight: 0.0,
+                        });
+                        voxel.distance += weight * delta.dot(normal);
+                        voxel.normal += weight * normal;
+                        voxel.weight += weight;
+                    }
+                }
+            }
+        }
+
+        Some(field)
+    }
+
+    fn position(&self, key: Key) -> Point3 {
+        let index = Vector3::new(key[0] as f64, key[1] as f64, key[2] as f64);
+        self.origin + index * self.cell
+    }
+
+    // Places a vertex into a cell (given by its minimum corner) minimizing
+    // distances to tangent planes at edge intersections, so that sharp
+    // edges and corners are kept.
+    fn cell_vertex(&self, cell: Key) -> Option<(Point3, Vector3)> {
+        let mut quadric = Quadric::zero();
+        let (mut mass, mut normal, mut num) =
+            (Vector3::zeros(), Vector3::zeros(), 0);
+
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for (du, dv) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let a = offset(offset(cell, u, du), v, dv);
+                let b = offset(a, axis, 1);
+                let (va, vb) = (self.voxels.get(&a)?, self.voxels.get(&b)?);
+                let (da, db) = (va.distance(), vb.distance());
+                if (da < 0.0) == (db < 0.0) {
+                    continue;
+                }
+
+                let t = da / (da - db);
+                let p =
+                    self.position(a).coords.lerp(&self.position(b).coords, t);
+                let n = va.normal().lerp(&vb.normal(), t);
+                if let Some(n) = n.try_normalize(f64::EPSILON) {
+                    quadric += Quadric::make_planar(p, n);
+                    normal += n;
+                }
+                mass += p;
+                num += 1;
+            }
+        }
+        if num == 0 {
+            return None;
+        }
+
+        let mass = mass / num as f64;
+        for axis in 0..3 {
+            let mut n = Vector3::zeros();
+            n[axis] = REGULARIZATION;
+            quadric += Quadric::make_planar(mass, n);
+        }
+
+        let min = self.position(cell).coords;
+        let max = min + Vector3::repeat(self.cell);
+        let point = quadric
+            .optimum()
+            .filter(|p| p >= &min && p <= &max)
+            .unwrap_or(mass);
+        let normal = normal.try_normalize(f64::EPSILON).unwrap_or_default();
+        Some((Point3::from(point), normal))
+    }
+}
+
+// Reconstructs surface from oriented cloud by dual contouring of its
+// truncated signed distance field, which (unlike Poisson) keeps sharp
+// features of man-made objects.
+pub fn reconstruct(
+    params: &Params,
+    cloud: &dyn Cloud<f64>,
+    mesh: &mut dyn Mesh<f64>,
+) -> Result<()> {
+    let err = || {
+        let desc = "failed to reconstruct surface by dual contouring";
+        Error::new(GeometryError, desc.to_string())
+    };
+    if !cloud.has_normals() {
+        return Err(err());
+    }
+    let field = Field::new(cloud, params).ok_or_else(err)?;
+
+    let mut num_vertices = 0;
+    let mut vertices: HashMap<Key, Option<usize>> = HashMap::new();
+    let mut vertex = |cell: Key, mesh: &mut dyn Mesh<f64>| {
+        *vertices.entry(cell).or_insert_with(|| {
+            let (point, normal) = field.cell_vertex(cell)?;
+            mesh.add_vertex(point.coords.as_ref());
+            mesh.add_normal(normal.as_ref());
+            num_vertices += 1;
+            Some(num_vertices - 1)
+        })
+    };
+
+    // Sorted for the output not to depend on hashing.
+    let mut keys: Vec<Key> = field.voxels.keys().copied().collect();
+    keys.sort_unstable();
+
+    let mut num_faces = 0;
+    for key in keys {
+        let inside = field.voxels[&key].distance() < 0.0;
+        for axis in 0..3 {
+            let next = match field.voxels.get(&offset(key, axis, 1)) {
+                Some(next) => next,
+                None => continue,
+            };
+            if inside == (next.distance() < 0.0) {
+                continue;
+            }
+
+            // Cells around the edge counter-clockwise when looking against
+            // the axis, so that the quad faces along it.
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let mut quad = [0; 4];
+            for (i, (du, dv)) in
+                [(1, 1), (0, 1), (0, 0), (1, 0)].into_iter().enumerate()
+            {
+                let cell = offset(offset(key, u, -du), v, -dv);
+                match vertex(cell, mesh) {
+                    Some(index) => quad[i] = index,
+                    None => break,
+                }
+                if i == 3 {
+                    if !inside {
+                        quad.reverse();
+                    }
+                    mesh.add_triangle(&[quad[0], quad[1], quad[2]]);
+                    mesh.add_triangle(&[quad[0], quad[2], quad[3]]);
+                    num_faces += 2;
+                }
+            }
+        }
+    }
+
+    if num_faces == 0 {
+        return Err(err());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestCloud(Vec<([f64; 3], [f64; 3])>);
+
+    impl Cloud<f64> for TestCloud {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn has_normals(&self) -> bool {
+            true
+        }
+
+        fn point(&self, index: usize) -> [f64; 3] {
+            self.0[index].0
+        }
+
+        fn normal(&self, index: usize) -> [f64; 3] {
+            self.0[index].1
+        }
+    }
+
+    #[derive(Default)]
+    struct TestMesh {
+        vertices: Vec<Point3>,
+        normals: Vec<Vector3>,
+        faces: Vec<[usize; 3]>,
+    }
+
+    impl Mesh<f64> for TestMesh {
+        fn add_vertex(&mut self, vertex: &[f64; 3]) {
+            self.vertices.push(Point3::from(*vertex));
+        }
+
+        fn add_normal(&mut self, normal: &[f64; 3]) {
+            self.normals.push(Vector3::from(*normal));
+        }
+
+        fn add_triangle(&mut self, triangle: &[usize; 3]) {
+            self.faces.push(*triangle);
+        }
+    }
+
+    // Samples surface of [-1, 1] cube.
+    fn create_cube_cloud(num: usize) -> TestCloud {
+        let mut points = Vec::new();
+        for axis in 0..3 {
+            for side in [-1.0, 1.0] {
+                for i in 0..=num {
+                    for j in 0..=num {
+                        let a = i as f64 / num as f64 * 2.0 - 1.0;
+                        let b = j as f64 / num as f64 * 2.0 - 1.0;
+                        let mut point = [0.0; 3];
+                        point[axis] = side;
+                        point[(axis + 1) % 3] = a;
+                        point[(axis + 2) % 3] = b;
+                        let mut normal = [0.0; 3];
+                        normal[axis] = side;
+                        points.push((point, normal));
+                    }
+                }
+            }
+        }
+        TestCloud(points)
+    }
+
+    #[test]
+    fn test_reconstruct_cube() {
+        let cloud = create_cube_cloud(24);
+        let params = Params {
+            resolution: 16,
+            truncation: 2,
+        };
+        let mut mesh = TestMesh::default();
+        reconstruct(&params, &cloud, &mut mesh).unwrap();
+
+        assert!(!mesh.faces.is_empty());
+        assert_eq!(mesh.vertices.len(), mesh.normals.len());
+        for vertex in &mesh.vertices {
+            let dist = vertex.coords.abs().max();
+            assert!((dist - 1.0).abs() < 0.03, "{} is off surface", vertex);
+        }
+
+        // Sharp corners are kept.
+        for corner in [[1.0, 1.0, 1.0], [-1.0, 1.0, -1.0], [1.0, -1.0, -1.0]] {
+            let corner = Point3::from(corner);
+            let dist = mesh
+                .vertices
+                .iter()
+                .map(|v| (v - corner).norm())
+                .fold(f64::MAX, f64::min);
+            assert!(dist < 1e-6, "corner {} is rounded off", corner);
+        }
+
+        // Faces look outwards.
+        for face in &mesh.faces {
+            let [a, b, c] = face.map(|i| mesh.vertices[i]);
+            let normal = (b - a).cross(&(c - a));
+            let center = (a.coords + b.coords + c.coords) / 3.0;
+            assert!(normal.dot(&center) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_empty() {
+        let mut mesh = TestMesh::default();
+        let err =
+            reconstruct(&Params::default(), &TestCloud(vec![]), &mut mesh)
+                .unwrap_err();
+        assert_eq!(err.kind, GeometryError);
+    }
+}
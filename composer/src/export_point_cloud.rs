@@ -0,0 +1,307 @@
+use std::io;
+use std::str::FromStr;
+
+use log::info;
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{build_frame_clouds, PointCloudParams, PointNormal};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::telemetry;
+use crate::texture::{load_frame_image, project_like_camera, sample_pixel};
+use crate::threads::ThreadParams;
+use base::define_raw_output;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+
+define_raw_output!(CloudOutput, "ply or .xyz");
+
+#[derive(StructOpt)]
+#[structopt(about = "Export scan point cloud into PLY or XYZ without meshing")]
+pub struct ExportPointCloudCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: CloudOutput,
+
+    #[structopt(flatten)]
+    params: ExportPointCloudParams,
+}
+
+impl ExportPointCloudCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        export_point_cloud(reader.as_mut(), &mut writer, &self.params)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct ExportPointCloudParams {
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(flatten)]
+    pub point_cloud: PointCloudParams,
+
+    #[structopt(flatten)]
+    pub threads: ThreadParams,
+
+    #[structopt(
+        help = "Output format (ply or xyz)",
+        long,
+        default_value = "ply"
+    )]
+    pub format: CloudFormat,
+
+    #[structopt(help = "Write binary little-endian PLY", long)]
+    pub binary: bool,
+
+    #[structopt(help = "Sample point colors from frame images", long)]
+    pub colors: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloudFormat {
+    Ply,
+    // Text lines of coordinates, normals and colors (if any).
+    Xyz,
+}
+
+impl FromStr for CloudFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ply" => Ok(CloudFormat::Ply),
+            "xyz" => Ok(CloudFormat::Xyz),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown point cloud format".to_string(),
+            )),
+        }
+    }
+}
+
+impl CheckParams for ExportPointCloudParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        check.require(!self.binary || self.format == CloudFormat::Ply, || {
+            "--binary is supported for ply format only".to_string()
+        });
+    }
+}
+
+pub fn export_point_cloud(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn io::Write,
+    params: &ExportPointCloudParams,
+) -> Result<()> {
+    let mut params = params.clone();
+    if let Some(path) = &params.scan.scans_config {
+        let config = read_scans_config(path)?;
+        params.scan.merge_config(&config);
+        params.point_cloud.merge_config(&config);
+    }
+    params.check()?;
+
+    info!("reading scans...");
+    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+    params
+        .point_cloud
+        .validate(scans.keys().map(String::as_str))?;
+
+    info!(
+        "building point clouds from {} scans ({} frames)...",
+        scans.len(),
+        scan_frames.len()
+    );
+    let pool = params.threads.pool()?;
+    let clouds = pool.install(|| {
+        build_frame_clouds(&scans, &scan_frames, &params.point_cloud)
+    })?;
+
+    let colors: Option<Vec<[u8; 3]>> = if params.colors {
+        info!("sampling point colors...");
+        let colors = pool.install(|| {
+            (0..scan_frames.len())
+                .into_par_iter()
+                .map(|i| {
+                    let frame = &scan_frames[i];
+                    // Scans of frames are checked while building clouds.
+                    sample_colors(&scans[&frame.scan], frame, &clouds[i])
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Some(colors.into_iter().flatten().collect())
+    } else {
+        None
+    };
+
+    let points: Vec<PointNormal> = clouds.into_iter().flatten().collect();
+    telemetry::count("cloud_points", points.len());
+
+    info!("writing cloud of {} points...", points.len());
+    match params.format {
+        CloudFormat::Ply => {
+            write_ply(writer, &points, colors.as_deref(), params.binary)
+        }
+        CloudFormat::Xyz => write_xyz(writer, &points, colors.as_deref()),
+    }
+}
+
+// Points of frames without images are black.
+fn sample_colors(
+    scan: &fm::Scan,
+    frame: &fm::ScanFrame,
+    cloud: &[PointNormal],
+) -> Result<Vec<[u8; 3]>> {
+    let image = match load_frame_image(frame) {
+        Some(image) => image,
+        None => return Ok(vec![[0; 3]; cloud.len()]),
+    };
+    let points: Vec<_> = cloud.iter().map(|p| p.0).collect();
+    Ok(project_like_camera(scan, frame, &points)?
+        .iter()
+        .map(|p| {
+            sample_pixel(p.point, &image)
+                .map(|c| c.round() as u8)
+                .into()
+        })
+        .collect())
+}
+
+fn write_ply(
+    writer: &mut dyn io::Write,
+    points: &[PointNormal],
+    colors: Option<&[[u8; 3]]>,
+    binary: bool,
+) -> Result<()> {
+    let write_err = || "failed to write PLY-file".to_string();
+
+    let format = if binary {
+        "binary_little_endian"
+    } else {
+        "ascii"
+    };
+    let mut header = format!(
+        "ply\nformat {} 1.0\nelement vertex {}\n\
+         property float x\nproperty float y\nproperty float z\n\
+         property float nx\nproperty float ny\nproperty float nz\n",
+        format,
+        points.len()
+    );
+    if colors.is_some() {
+        header +=
+            "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+    }
+    header += "end_header\n";
+    writer.write_all(header.as_bytes()).into_result(write_err)?;
+
+    if binary {
+        let mut data = Vec::new();
+        for (i, PointNormal(p, n)) in points.iter().enumerate() {
+            for f in p.iter().chain(n.iter()) {
+                data.extend_from_slice(&(*f as f32).to_le_bytes());
+            }
+            if let Some(colors) = colors {
+                data.extend_from_slice(&colors[i]);
+            }
+        }
+        writer.write_all(&data).into_result(write_err)
+    } else {
+        write_lines(writer, points, colors).into_result(write_err)
+    }
+}
+
+fn write_xyz(
+    writer: &mut dyn io::Write,
+    points: &[PointNormal],
+    colors: Option<&[[u8; 3]]>,
+) -> Result<()> {
+    write_lines(writer, points, colors)
+        .into_result(|| "failed to write XYZ-file".to_string())
+}
+
+fn write_lines(
+    writer: &mut dyn io::Write,
+    points: &[PointNormal],
+    colors: Option<&[[u8; 3]]>,
+) -> io::Result<()> {
+    for (i, PointNormal(p, n)) in points.iter().enumerate() {
+        let (p, n) = (p.cast::<f32>(), n.cast::<f32>());
+        write!(writer, "{} {} {} {} {} {}", p.x, p.y, p.z, n.x, n.y, n.z)?;
+        if let Some(colors) = colors {
+            let [r, g, b] = colors[i];
+            write!(writer, " {} {} {}", r, g, b)?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::{Point3, Vector3};
+
+    fn create_points() -> Vec<PointNormal> {
+        vec![
+            PointNormal(Point3::new(1.0, 2.0, 3.0), Vector3::z()),
+            PointNormal(Point3::new(-0.5, 0.0, 0.25), Vector3::x()),
+        ]
+    }
+
+    #[test]
+    fn test_write_ply() {
+        let points = create_points();
+        let colors = [[255, 0, 0], [0, 128, 255]];
+
+        let mut data = Vec::new();
+        write_ply(&mut data, &points, Some(&colors), false).unwrap();
+        let text = String::from_utf8(data).unwrap();
+        let (header, body) = text.split_once("end_header\n").unwrap();
+        assert!(header.contains("element vertex 2\n"));
+        assert!(header.contains("property uchar red\n"));
+        assert!(!header.contains("element face"));
+        assert_eq!(body, "1 2 3 0 0 1 255 0 0\n-0.5 0 0.25 1 0 0 0 128 255\n");
+
+        let mut data = Vec::new();
+        write_ply(&mut data, &points, None, true).unwrap();
+        let end = b"end_header\n";
+        let pos = data.windows(end.len()).position(|w| w == end).unwrap();
+        let body = &data[pos + end.len()..];
+        assert_eq!(body.len(), 2 * 6 * 4);
+        assert_eq!(body[..4], 1f32.to_le_bytes());
+        assert_eq!(body[24..28], (-0.5f32).to_le_bytes());
+    }
+
+    #[test]
+    fn test_write_xyz() {
+        let mut data = Vec::new();
+        write_xyz(&mut data, &create_points(), None).unwrap();
+        let text = String::from_utf8(data).unwrap();
+        assert_eq!(text, "1 2 3 0 0 1\n-0.5 0 0.25 1 0 0\n");
+    }
+
+    #[test]
+    fn test_check_params() {
+        let params = ExportPointCloudParams::from_iter([
+            "export-point-cloud",
+            "--binary",
+        ]);
+        assert!(params.check().is_ok());
+
+        let params = ExportPointCloudParams::from_iter([
+            "export-point-cloud",
+            "--binary",
+            "--format",
+            "xyz",
+        ]);
+        assert!(params.check().is_err());
+    }
+}
@@ -0,0 +1,757 @@
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::io::{self, stdout};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+use structopt::StructOpt;
+
+use crate::export_to_obj::read_element;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+use base::util::fs;
+
+const GLB_MAGIC: u32 = 0x46546C67;
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A;
+const GLB_CHUNK_BIN: u32 = 0x004E4942;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+// Vertical field of view of the default camera.
+const CAMERA_YFOV: f32 = 0.8;
+
+// Key, fill and rim lights by directions towards them (seen from the default
+// camera) and illuminances in lux.
+const LIGHTS: [(&str, [f32; 3], f32); 3] = [
+    ("key", [1.0, 1.0, 1.0], 3.0),
+    ("fill", [-1.0, 0.3, 1.0], 1.0),
+    ("rim", [0.0, 1.0, -1.0], 2.0),
+];
+
+type Vector3 = nalgebra::Vector3<f32>;
+type Quaternion = nalgebra::UnitQuaternion<f32>;
+
+#[derive(StructOpt)]
+#[structopt(about = "Export .fm file into glTF")]
+pub struct ExportToGltfCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(
+        help = "Output .gltf (with .bin alongside) or .glb file \
+                (.glb to STDOUT if omitted)",
+        long = "out-file",
+        short = "o"
+    )]
+    path: Option<PathBuf>,
+
+    #[structopt(help = "Skip texture output", long, short = "g")]
+    skip_texture: bool,
+
+    #[structopt(
+        help = "Units of model coordinates (m, cm, mm or in)",
+        long,
+        default_value = "m"
+    )]
+    units: Units,
+
+    #[structopt(help = "Skip default camera and lights", long)]
+    skip_scene: bool,
+}
+
+impl ExportToGltfCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let gltf = export_to_gltf(
+            reader.as_mut(),
+            !self.skip_texture,
+            self.units,
+            !self.skip_scene,
+        )?;
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return gltf.write_glb(&mut stdout()),
+        };
+
+        if path.extension().is_some_and(|e| e == "gltf") {
+            let bin_path = path.with_extension("bin");
+            let bin_name = bin_path.file_name().unwrap().to_str().unwrap();
+            let json = gltf.to_json(Some(bin_name));
+            fs::write_output(path, json.to_string().as_bytes())?;
+            fs::write_output(&bin_path, &gltf.bin)
+        } else {
+            let mut writer = fs::create_output(path)?;
+            gltf.write_glb(&mut writer)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Units {
+    Meters,
+    Centimeters,
+    Millimeters,
+    Inches,
+}
+
+impl Units {
+    // Length in meters, the units of glTF.
+    fn meters(self) -> f64 {
+        match self {
+            Units::Meters => 1.0,
+            Units::Centimeters => 0.01,
+            Units::Millimeters => 0.001,
+            Units::Inches => 0.0254,
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "m" => Ok(Units::Meters),
+            "cm" => Ok(Units::Centimeters),
+            "mm" => Ok(Units::Millimeters),
+            "in" => Ok(Units::Inches),
+            _ => Err(Error::new(MalformedData, "unknown units".to_string())),
+        }
+    }
+}
+
+// glTF 2.0 asset with its single binary buffer.
+pub struct Gltf {
+    pub json: Value,
+    pub bin: Vec<u8>,
+}
+
+impl Gltf {
+    // Refers to the buffer by given URI if any (i.e. for .gltf files).
+    pub fn to_json(&self, bin_uri: Option<&str>) -> Value {
+        let mut json = self.json.clone();
+        let buffer = &mut json["buffers"][0];
+        buffer["byteLength"] = json!(self.bin.len());
+        if let Some(uri) = bin_uri {
+            buffer["uri"] = json!(uri);
+        }
+        json
+    }
+
+    pub fn write_glb(&self, writer: &mut dyn io::Write) -> Result<()> {
+        let mut json = self.to_json(None).to_string().into_bytes();
+        pad(&mut json, b' ');
+        let mut bin = self.bin.clone();
+        pad(&mut bin, 0);
+
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut data = Vec::with_capacity(length);
+        for word in [GLB_MAGIC, GLB_VERSION, length as u32] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        for (r#type, chunk) in [(GLB_CHUNK_JSON, json), (GLB_CHUNK_BIN, bin)] {
+            data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            data.extend_from_slice(&r#type.to_le_bytes());
+            data.extend_from_slice(&chunk);
+        }
+
+        writer
+            .write_all(&data)
+            .into_result(|| "failed to write glTF-file".to_string())
+    }
+}
+
+fn pad(data: &mut Vec<u8>, byte: u8) {
+    data.resize(data.len().div_ceil(4) * 4, byte);
+}
+
+#[derive(Default)]
+struct BufferBuilder {
+    bin: Vec<u8>,
+    views: Vec<Value>,
+    accessors: Vec<Value>,
+}
+
+impl BufferBuilder {
+    fn push_view(&mut self, data: &[u8], target: Option<u32>) -> usize {
+        pad(&mut self.bin, 0);
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.bin.len(),
+            "byteLength": data.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.bin.extend_from_slice(data);
+        self.views.push(view);
+        self.views.len() - 1
+    }
+
+    fn push_floats<const N: usize>(
+        &mut self,
+        items: &[[f32; N]],
+        bounds: bool,
+    ) -> usize {
+        let data: Vec<u8> = items
+            .iter()
+            .flatten()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        let view = self.push_view(&data, Some(ARRAY_BUFFER));
+        let mut accessor = json!({
+            "bufferView": view,
+            "componentType": FLOAT,
+            "count": items.len(),
+            "type": format!("VEC{}", N),
+        });
+
+        // Required for positions.
+        if bounds {
+            let (mut min, mut max) = ([f32::MAX; N], [f32::MIN; N]);
+            for item in items {
+                for i in 0..N {
+                    min[i] = min[i].min(item[i]);
+                    max[i] = max[i].max(item[i]);
+                }
+            }
+            accessor["min"] = json!(min.to_vec());
+            accessor["max"] = json!(max.to_vec());
+        }
+
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    fn push_indices(&mut self, indices: &[u32]) -> usize {
+        let data: Vec<u8> =
+            indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let view = self.push_view(&data, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": UNSIGNED_INT,
+            "count": indices.len(),
+            "type": "SCALAR",
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn push_image(&mut self, image: &fm::Image) -> Value {
+        let mime_type = match image.r#type() {
+            fm::image::Type::Png => "image/png",
+            _ => "image/jpeg",
+        };
+        let view = self.push_view(&image.data, None);
+        json!({"bufferView": view, "mimeType": mime_type})
+    }
+}
+
+// Converts the Z-up model coordinates into the Y-up ones of glTF.
+fn to_y_up(p: &fm::Point3) -> [f32; 3] {
+    [p.x, p.z, -p.y]
+}
+
+// Converts 0xRRGGBB sRGB color into the linear one of glTF.
+fn to_linear_color(color: u32) -> [f32; 3] {
+    [16, 8, 0].map(|shift| {
+        let c = ((color >> shift) & 0xFF) as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+// Camera framing the bounding sphere (given in meters) from the front and
+// directional lights around it.
+fn scene_nodes(
+    center: Vector3,
+    radius: f32,
+) -> (Vec<Value>, Value, Vec<Value>) {
+    let distance = radius / (CAMERA_YFOV / 2.0).sin();
+    let mut nodes = vec![json!({
+        "camera": 0,
+        "name": "camera",
+        "translation": (center + Vector3::z() * distance).as_slice(),
+    })];
+    let camera = json!({
+        "type": "perspective",
+        "perspective": {
+            "yfov": CAMERA_YFOV,
+            "znear": (distance - radius) / 2.0,
+            "zfar": (distance + radius) * 2.0,
+        },
+    });
+
+    let mut lights = Vec::new();
+    for (i, (name, towards, intensity)) in LIGHTS.iter().enumerate() {
+        // Directional lights shine along -Z of their nodes.
+        let towards = Vector3::from(*towards).normalize();
+        let rotation = Quaternion::rotation_between(&-Vector3::z(), &-towards)
+            .unwrap_or_else(|| {
+                Quaternion::from_axis_angle(&Vector3::y_axis(), PI)
+            });
+        nodes.push(json!({
+            "name": name,
+            "rotation": rotation.coords.as_slice(),
+            "translation": (center + towards * distance).as_slice(),
+            "extensions": {"KHR_lights_punctual": {"light": i}},
+        }));
+        lights.push(json!({
+            "name": name,
+            "type": "directional",
+            "intensity": intensity,
+        }));
+    }
+
+    (nodes, camera, lights)
+}
+
+// Units scale the mesh to meters, the scene adds a camera and lights
+// fitting its bounds.
+pub fn export_to_gltf(
+    reader: &mut dyn fm::Read,
+    with_texture: bool,
+    units: Units,
+    with_scene: bool,
+) -> Result<Gltf> {
+    let (view, state) = read_element(reader)?;
+    let with_texture = with_texture && view.texture.is_some();
+    let with_normals = !state.normals.is_empty();
+    let with_colors = !view.vertex_colors.is_empty();
+    if with_colors && view.vertex_colors.len() != state.vertices.len() {
+        return Err(Error::new(
+            InconsistentState,
+            "number of vertex colors differs from one of vertices".to_string(),
+        ));
+    }
+    let labels = fm::face_labels(&view)?;
+    let pages = fm::face_pages(&view)?.filter(|_| with_texture);
+
+    // Unlike OBJ, glTF vertices share the same index for all attributes.
+    let corners: Vec<(u32, u32, u32)> = view
+        .faces
+        .iter()
+        .flat_map(|f| {
+            [
+                (f.vertex1, f.texture1, f.normal1),
+                (f.vertex2, f.texture2, f.normal2),
+                (f.vertex3, f.texture3, f.normal3),
+            ]
+        })
+        .collect();
+    let mut unique = corners.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    let indices: Vec<u32> = corners
+        .iter()
+        .map(|c| unique.binary_search(c).unwrap() as u32)
+        .collect();
+
+    let get = |items_len: usize, index: u32, what: &str| {
+        if index == 0 || index as usize > items_len {
+            let desc = format!("bad {} number {} in view face", what, index);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        Ok(index as usize - 1)
+    };
+
+    let (mut positions, mut normals, mut uvs) = (vec![], vec![], vec![]);
+    let mut colors = vec![];
+    for &(vertex, texture, normal) in &unique {
+        let i = get(state.vertices.len(), vertex, "vertex")?;
+        positions.push(to_y_up(&state.vertices[i]));
+        if with_colors {
+            colors.push(to_linear_color(view.vertex_colors[i]));
+        }
+        if with_normals {
+            let i = get(state.normals.len(), normal, "normal")?;
+            let n = to_y_up(&state.normals[i]);
+            let len = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            let len = if len > 0.0 { len } else { 1.0 };
+            normals.push([n[0] / len, n[1] / len, n[2] / len]);
+        }
+        if with_texture {
+            let i = get(view.texture_points.len(), texture, "texture point")?;
+            let p = &view.texture_points[i];
+            uvs.push([p.x, p.y]);
+        }
+    }
+
+    let mut builder = BufferBuilder::default();
+    let mut attributes = json!({
+        "POSITION": builder.push_floats(&positions, true),
+    });
+    if with_normals {
+        attributes["NORMAL"] = json!(builder.push_floats(&normals, false));
+    }
+    if with_texture {
+        attributes["TEXCOORD_0"] = json!(builder.push_floats(&uvs, false));
+    }
+    if with_colors {
+        attributes["COLOR_0"] = json!(builder.push_floats(&colors, false));
+    }
+
+    // Labeled faces (and ones of different texture atlas pages) are split
+    // into primitives sharing the attributes.
+    let mut groups = BTreeMap::<(Option<u16>, usize), Vec<u32>>::new();
+    for (i, face) in indices.chunks(3).enumerate() {
+        let label = labels.as_ref().map(|labels| labels[i]);
+        let page = pages.as_ref().map_or(0, |pages| pages[i]);
+        groups
+            .entry((label, page))
+            .or_default()
+            .extend_from_slice(face);
+    }
+    let primitives: Vec<_> = groups
+        .into_iter()
+        .map(|((label, page), indices)| {
+            let mut primitive = json!({
+                "attributes": attributes,
+                "indices": builder.push_indices(&indices),
+            });
+            if let Some(label) = label {
+                primitive["extras"] = json!({"label": label});
+            }
+            if with_texture {
+                primitive["material"] = json!(page);
+            }
+            primitive
+        })
+        .collect();
+
+    let mut json = json!({
+        "asset": {"version": "2.0", "generator": "tdscan composer"},
+        "scene": 0,
+        "scenes": [{"nodes": [0]}],
+        "nodes": [{"mesh": 0, "name": view.element}],
+        "meshes": [{"name": view.element, "primitives": []}],
+    });
+
+    let scale = units.meters();
+    if scale != 1.0 {
+        json["nodes"][0]["scale"] = json!([scale, scale, scale]);
+    }
+    if with_scene && !positions.is_empty() {
+        let mut min = Vector3::repeat(f32::MAX);
+        let mut max = -min;
+        for p in &positions {
+            min = min.inf(&Vector3::from(*p));
+            max = max.sup(&Vector3::from(*p));
+        }
+        let center = (min + max) / 2.0 * scale as f32;
+        let radius =
+            ((max - min).norm() / 2.0 * scale as f32).max(f32::EPSILON);
+        let (nodes, camera, lights) = scene_nodes(center, radius);
+
+        json["nodes"].as_array_mut().unwrap().extend(nodes);
+        let num_nodes = json["nodes"].as_array().unwrap().len();
+        json["scenes"][0]["nodes"] = json!((0..num_nodes).collect::<Vec<_>>());
+        json["cameras"] = json!([camera]);
+        json["extensionsUsed"] = json!(["KHR_lights_punctual"]);
+        json["extensions"] = json!({"KHR_lights_punctual": {"lights": lights}});
+    }
+
+    if with_texture {
+        let textures = view.texture.iter().chain(&view.texture_pages);
+        let mut images: Vec<_> =
+            textures.map(|image| builder.push_image(image)).collect();
+        let mut materials: Vec<_> = (0..images.len())
+            .map(|i| {
+                json!({
+                    "pbrMetallicRoughness": {
+                        "baseColorTexture": {"index": i},
+                        "metallicFactor": 0.0,
+                    },
+                })
+            })
+            .collect();
+        // The normal texture shares texture points with the first page.
+        if let Some(normal_texture) = &view.normal_texture {
+            materials[0]["normalTexture"] = json!({"index": images.len()});
+            images.push(builder.push_image(normal_texture));
+        }
+        let textures: Vec<_> =
+            (0..images.len()).map(|i| json!({"source": i})).collect();
+
+        json["materials"] = json!(materials);
+        json["textures"] = json!(textures);
+        json["images"] = json!(images);
+    }
+
+    json["meshes"][0]["primitives"] = json!(primitives);
+    json["accessors"] = json!(builder.accessors);
+    json["bufferViews"] = json!(builder.views);
+    json["buffers"] = json!([{}]);
+
+    Ok(Gltf {
+        json,
+        bin: builder.bin,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+
+    fn new_element_recs() -> [fm::Record; 2] {
+        [
+            new_element_view_rec(fm::ElementView {
+                element: "element".to_string(),
+                texture_points: vec![
+                    new_point2(0.0, 0.0),
+                    new_point2(1.0, 0.0),
+                    new_point2(0.0, 1.0),
+                ],
+                faces: vec![
+                    new_ev_face(1, 2, 3, 1, 2, 3, 1, 1, 1),
+                    new_ev_face(1, 3, 4, 2, 3, 2, 1, 1, 1),
+                ],
+                texture: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![1, 2, 3],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "element".to_string(),
+                vertices: vec![
+                    new_point3(0.0, 0.0, 0.0),
+                    new_point3(1.0, 0.0, 0.0),
+                    new_point3(0.0, 1.0, 0.0),
+                    new_point3(0.0, 0.0, 2.0),
+                ],
+                normals: vec![new_point3(0.0, -2.0, 0.0)],
+                ..Default::default()
+            }),
+        ]
+    }
+
+    fn create_element() -> fm::Reader<io::Cursor<Vec<u8>>> {
+        create_reader_with_records(&new_element_recs())
+    }
+
+    #[test]
+    fn test_export_to_gltf() {
+        let gltf =
+            export_to_gltf(&mut create_element(), true, Units::Meters, true)
+                .unwrap();
+        let json = gltf.to_json(Some("element.bin"));
+
+        let primitive = &json["meshes"][0]["primitives"][0];
+        assert_eq!(
+            primitive["attributes"],
+            json!({"POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2})
+        );
+        assert_eq!(primitive["indices"], 3);
+        assert_eq!(primitive["material"], 0);
+
+        // Vertex 1 is split as the faces use different texture points.
+        let accessors = &json["accessors"];
+        assert_eq!(accessors[0]["count"], 5);
+        assert_eq!(accessors[0]["min"], json!([0.0, 0.0, -1.0]));
+        assert_eq!(accessors[0]["max"], json!([1.0, 2.0, 0.0]));
+        assert_eq!(accessors[3]["count"], 6);
+        assert_eq!(json["images"][0]["mimeType"], "image/png");
+        assert_eq!(json["buffers"][0]["uri"], "element.bin");
+
+        // 5 positions and normals, 5 UVs, 6 indices and the image.
+        assert_eq!(gltf.bin.len(), 60 + 60 + 40 + 24 + 3);
+        assert_eq!(json["buffers"][0]["byteLength"], gltf.bin.len());
+        assert_eq!(&gltf.bin[184..], &[1, 2, 3]);
+        let indices: Vec<u32> = gltf.bin[160..184]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(indices, vec![0, 2, 3, 1, 3, 4]);
+        let normal = &gltf.bin[60..72];
+        assert_eq!(normal, [0.0f32, 0.0, 1.0].map(f32::to_le_bytes).concat());
+    }
+
+    #[test]
+    fn test_export_vertex_colored_to_gltf() {
+        let [view, state] = new_element_recs();
+        let mut view = record_variant!(ElementView, view);
+        view.vertex_colors = vec![0xFFFFFF, 0x000000, 0x808080, 0xFF0000];
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(view.clone()),
+            state.clone(),
+        ]);
+
+        let gltf =
+            export_to_gltf(&mut reader, false, Units::Meters, true).unwrap();
+        let json = gltf.to_json(None);
+        let primitive = &json["meshes"][0]["primitives"][0];
+        assert_eq!(
+            primitive["attributes"],
+            json!({"POSITION": 0, "NORMAL": 1, "COLOR_0": 2})
+        );
+        assert_eq!(json["accessors"][2]["type"], "VEC3");
+
+        // Colors follow 5 positions and normals, the first two corners are
+        // of vertex 1.
+        let colors: Vec<f32> = gltf.bin[120..180]
+            .chunks(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(colors[..9], [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+        assert!(colors[9..12].iter().all(|c| (c - 0.2158605).abs() < 1E-6));
+        assert_eq!(colors[12..], [1.0, 0.0, 0.0]);
+
+        view.vertex_colors.pop();
+        let mut reader =
+            create_reader_with_records(&[new_element_view_rec(view), state]);
+        let res = export_to_gltf(&mut reader, false, Units::Meters, true);
+        assert_eq!(res.err().unwrap().kind, InconsistentState);
+    }
+
+    #[test]
+    fn test_export_labeled_to_gltf() {
+        let [view, state] = new_element_recs();
+        let mut view = record_variant!(ElementView, view);
+        view.faces.push(view.faces[0].clone());
+        view.face_labels = vec![7, 3, 7];
+        let mut reader =
+            create_reader_with_records(&[new_element_view_rec(view), state]);
+
+        let gltf =
+            export_to_gltf(&mut reader, true, Units::Meters, true).unwrap();
+        let json = gltf.to_json(None);
+        let primitives = json["meshes"][0]["primitives"].as_array().unwrap();
+        assert_eq!(primitives.len(), 2);
+        for (primitive, label, count) in
+            [(&primitives[0], 3, 3), (&primitives[1], 7, 6)]
+        {
+            assert_eq!(primitive["extras"], json!({ "label": label }));
+            assert_eq!(primitive["attributes"], primitives[0]["attributes"]);
+            assert_eq!(primitive["material"], 0);
+            let accessor = &json["accessors"]
+                [primitive["indices"].as_u64().unwrap() as usize];
+            assert_eq!(accessor["count"], count);
+        }
+    }
+
+    #[test]
+    fn test_export_paged_to_gltf() {
+        let [view, state] = new_element_recs();
+        let mut view = record_variant!(ElementView, view);
+        view.texture_pages = vec![fm::Image {
+            r#type: fm::image::Type::Jpeg as i32,
+            data: vec![4, 5],
+            ..Default::default()
+        }];
+        view.face_pages = vec![1, 0];
+        let mut reader =
+            create_reader_with_records(&[new_element_view_rec(view), state]);
+
+        let gltf =
+            export_to_gltf(&mut reader, true, Units::Meters, true).unwrap();
+        let json = gltf.to_json(None);
+        let primitives = json["meshes"][0]["primitives"].as_array().unwrap();
+        assert_eq!(primitives.len(), 2);
+        assert_eq!(primitives[0]["material"], 0);
+        assert_eq!(primitives[1]["material"], 1);
+        assert_eq!(json["materials"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            json["materials"][1]["pbrMetallicRoughness"]["baseColorTexture"],
+            json!({"index": 1})
+        );
+        assert_eq!(json["images"][1]["mimeType"], "image/jpeg");
+        assert_eq!(&gltf.bin[gltf.bin.len() - 2..], &[4, 5]);
+
+        // Pages are ignored without texture.
+        let gltf =
+            export_to_gltf(&mut create_element(), false, Units::Meters, true)
+                .unwrap();
+        let primitives = &gltf.json["meshes"][0]["primitives"];
+        assert_eq!(primitives.as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_export_scene_to_gltf() {
+        let mut reader = create_element();
+        let gltf = export_to_gltf(&mut reader, false, Units::Centimeters, true)
+            .unwrap();
+        let nodes = gltf.json["nodes"].as_array().unwrap();
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes[0]["scale"], json!([0.01, 0.01, 0.01]));
+        assert_eq!(gltf.json["scenes"][0]["nodes"], json!([0, 1, 2, 3, 4]));
+
+        // The camera looks at the center of bounds from the front.
+        let vector = |value: &Value| {
+            let coords: Vec<f32> = value
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|c| c.as_f64().unwrap() as f32)
+                .collect();
+            coords
+        };
+        let radius = 6f32.sqrt() / 2.0 * 0.01;
+        let distance = radius / (CAMERA_YFOV / 2.0).sin();
+        let camera = vector(&nodes[1]["translation"]);
+        let expected = [0.005, 0.01, -0.005 + distance];
+        for (c, e) in camera.iter().zip(expected) {
+            assert!((c - e).abs() < 1E-6, "{:?}", camera);
+        }
+        let perspective = &gltf.json["cameras"][0]["perspective"];
+        assert!(perspective["znear"].as_f64().unwrap() > 0.0);
+
+        // The key light shines from the top right.
+        let lights = &gltf.json["extensions"]["KHR_lights_punctual"]["lights"];
+        assert_eq!(lights.as_array().unwrap().len(), 3);
+        assert_eq!(nodes[2]["extensions"]["KHR_lights_punctual"]["light"], 0);
+        let [x, y, z, w] = vector(&nodes[2]["rotation"])[..] else {
+            panic!()
+        };
+        let rotation =
+            Quaternion::from_quaternion(nalgebra::Quaternion::new(w, x, y, z));
+        let direction = rotation * -Vector3::z();
+        let expected = -Vector3::repeat(1.0).normalize();
+        assert!((direction - expected).norm() < 1E-6, "{}", direction);
+
+        let mut reader = create_element();
+        let gltf =
+            export_to_gltf(&mut reader, false, Units::Meters, false).unwrap();
+        assert_eq!(gltf.json["nodes"].as_array().unwrap().len(), 1);
+        assert!(gltf.json["nodes"][0].get("scale").is_none());
+        assert!(gltf.json.get("cameras").is_none());
+        assert!(gltf.json.get("extensionsUsed").is_none());
+    }
+
+    #[test]
+    fn test_write_glb() {
+        let gltf =
+            export_to_gltf(&mut create_element(), false, Units::Meters, true)
+                .unwrap();
+        assert!(gltf.json.get("images").is_none());
+
+        let mut data = Vec::new();
+        gltf.write_glb(&mut data).unwrap();
+
+        let word =
+            |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+        assert_eq!(word(0), GLB_MAGIC);
+        assert_eq!(word(8) as usize, data.len());
+        let json_len = word(12) as usize;
+        assert_eq!(json_len % 4, 0);
+        assert_eq!(word(16), GLB_CHUNK_JSON);
+        let json: Value =
+            serde_json::from_slice(&data[20..20 + json_len]).unwrap();
+        assert!(json["buffers"][0].get("uri").is_none());
+        assert_eq!(word(20 + json_len + 4), GLB_CHUNK_BIN);
+        assert_eq!(word(20 + json_len) as usize, gltf.bin.len());
+    }
+}
@@ -31,6 +31,14 @@ pub struct ExportToJsonCommand {
 
     #[structopt(help = "Prettify JSON output", long, short = "p")]
     pretty: bool,
+
+    #[structopt(
+        help = concat!("Write newline-delimited JSON (one record per line, ",
+            "streamed as records are read)"),
+        long,
+        conflicts_with = "pretty"
+    )]
+    ndjson: bool,
 }
 
 impl ExportToJsonCommand {
@@ -38,12 +46,9 @@ impl ExportToJsonCommand {
         let mut reader = self.input.get()?;
         let mut writer = self.output.get()?;
 
-        export_to_json(
-            reader.as_mut(),
-            &mut writer,
-            self.truncate_len,
-            self.pretty,
-        )
+        // Compact records never contain line breaks, so they're NDJSON.
+        let pretty = self.pretty && !self.ndjson;
+        export_to_json(reader.as_mut(), &mut writer, self.truncate_len, pretty)
     }
 }
 
@@ -122,7 +127,7 @@ mod tests {
         assert_eq!(
             export(None, false),
             r#"
-{"type":{"ElementView":{"element":"element","texture":null,"texture_points":[{"x":1.0,"y":2.0},{"x":3.0,"y":4.0}],"faces":[]}}}
+{"type":{"ElementView":{"element":"element","texture":null,"texture_points":[{"x":1.0,"y":2.0},{"x":3.0,"y":4.0}],"faces":[],"normal_texture":null,"texture_levels":[],"vertex_colors":[],"face_labels":[],"texture_pages":[],"face_pages":[],"animation_loop":null}}}
 {"type":{"ElementViewState":{"element":"element","time":0,"vertices":[{"x":5.0,"y":6.0,"z":7.0},{"x":8.0,"y":9.0,"z":10.0},{"x":11.0,"y":12.0,"z":13.0}],"normals":[]}}}
 "#
         );
@@ -148,7 +153,14 @@ mod tests {
           "y": 4.0
         }
       ],
-      "faces": []
+      "faces": [],
+      "normal_texture": null,
+      "texture_levels": [],
+      "vertex_colors": [],
+      "face_labels": [],
+      "texture_pages": [],
+      "face_pages": [],
+      "animation_loop": null
     }
   }
 }
@@ -190,8 +202,8 @@ mod tests {
 {
   "type": {
     "ElementView": {
-      "element": "el",
-      "faces": []
+      "animation_loop": null,
+      "element": "el"
     }
   }
 }
@@ -66,7 +66,7 @@ impl ExportToObjCommand {
             Some(MtlParams {
                 dir: mtl_dir,
                 name: material.as_str(),
-                write_file: |p, d| fs::write_file(p, d),
+                write_file: |p, d| fs::write_output(p, d),
             })
         };
 
@@ -91,10 +91,35 @@ pub fn export_to_obj<F: Fn(&Path, &[u8]) -> Result<()>>(
     writer: &mut dyn io::Write,
     mtl_params: Option<MtlParams<F>>,
 ) -> Result<()> {
-    let (view, state) = read_element(reader)?;
+    let (view, state, animated) = read_textured_element(reader)?;
 
     let write_err = || "failed to write OBJ-file".to_string();
 
+    // Labeled faces are written in groups, one per label, and faces of
+    // further texture atlas pages use materials of their own.
+    let labels = match fm::face_labels(&view)? {
+        Some(labels) => labels.into_iter().map(Some).collect(),
+        None => vec![None; view.faces.len()],
+    };
+    let pages =
+        fm::face_pages(&view)?.unwrap_or_else(|| vec![0; view.faces.len()]);
+    let mut faces: Vec<_> = labels
+        .into_iter()
+        .zip(pages)
+        .zip(view.faces)
+        .map(|((label, page), f)| (label, page, f))
+        .collect();
+    faces.sort_by_key(|(label, page, _)| (*label, *page));
+    let mut group = None;
+    let mut write_group = |writer: &mut dyn io::Write, label| {
+        if label != group {
+            group = label;
+            writeln!(writer, "g label_{}", label.unwrap_or_default())
+                .into_result(write_err)?;
+        }
+        Ok(())
+    };
+
     for v in state.vertices {
         writeln!(writer, "v {} {} {}", v.x, v.y, v.z).into_result(write_err)?;
     }
@@ -106,19 +131,47 @@ pub fn export_to_obj<F: Fn(&Path, &[u8]) -> Result<()>>(
 
     if view.texture.is_some() && mtl_params.is_some() {
         let mtl = mtl_params.unwrap();
-        let ext =
-            fm::image_type_extension(view.texture.as_ref().unwrap().r#type());
-        let txr_filename = mtl.dir.join(mtl.name).with_extension(ext);
-        (mtl.write_file)(&txr_filename, &view.texture.unwrap().data)?;
+        let page_name = |page: usize| match page {
+            0 => mtl.name.to_string(),
+            _ => format!("{}_page{}", mtl.name, page),
+        };
 
+        let mut mtl_content = String::new();
+        let textures = view.texture.iter().chain(&view.texture_pages);
+        for (page, texture) in textures.enumerate() {
+            let name = page_name(page);
+            let ext = fm::image_type_extension(texture.r#type());
+            let txr_filename = mtl.dir.join(&name).with_extension(ext);
+            (mtl.write_file)(&txr_filename, &texture.data)?;
+
+            mtl_content += format!("newmtl {}\n", name).as_str();
+            mtl_content += format!("map_Ka {}.{}\n", name, ext).as_str();
+            mtl_content += format!("map_Kd {}.{}\n", name, ext).as_str();
+            // The normal texture shares texture points with the first page.
+            if let (0, Some(normal_texture)) = (page, &view.normal_texture) {
+                let ext = fm::image_type_extension(normal_texture.r#type());
+                let name = format!("{}_normal", mtl.name);
+                let filename = mtl.dir.join(&name).with_extension(ext);
+                (mtl.write_file)(&filename, &normal_texture.data)?;
+                mtl_content += format!("norm {}.{}\n", name, ext).as_str();
+            }
+        }
+        // Animated textures are written as an image sequence, listed in
+        // comments along with times (in nanoseconds) they apply from.
+        for (i, texture) in animated.iter().enumerate() {
+            let image = texture.texture.as_ref().unwrap();
+            let ext = fm::image_type_extension(image.r#type());
+            let name = format!("{}_{:04}", mtl.name, i + 1);
+            let filename = mtl.dir.join(&name).with_extension(ext);
+            (mtl.write_file)(&filename, &image.data)?;
+            mtl_content +=
+                format!("# texture {} {}.{}\n", texture.time, name, ext)
+                    .as_str();
+        }
         let mtl_filename = mtl.dir.join(mtl.name).with_extension("mtl");
-        let mut mtl_content = format!("newmtl {}\n", mtl.name);
-        mtl_content += format!("map_Ka {}.{}\n", mtl.name, ext).as_str();
-        mtl_content += format!("map_Kd {}.{}\n", mtl.name, ext).as_str();
         (mtl.write_file)(&mtl_filename, mtl_content.as_bytes())?;
 
-        writeln!(writer, "mtllib {}.mtl", mtl.name)
-            .into_result(write_err)?;
+        writeln!(writer, "mtllib {}.mtl", mtl.name).into_result(write_err)?;
         writeln!(writer, "usemtl {}", mtl.name).into_result(write_err)?;
 
         for p in view.texture_points {
@@ -126,21 +179,29 @@ pub fn export_to_obj<F: Fn(&Path, &[u8]) -> Result<()>>(
                 .into_result(write_err)?;
         }
 
-        for f in view.faces {
+        let mut material = 0;
+        for (label, page, f) in faces {
+            write_group(writer, label)?;
+            if page != material {
+                material = page;
+                writeln!(writer, "usemtl {}", page_name(page))
+                    .into_result(write_err)?;
+            }
             #[rustfmt::skip]
-            writeln!(writer, "f {}/{}/{} {}/{}/{} {}/{}/{}",
-                f.vertex1, f.texture1, f.normal1,
-                f.vertex2, f.texture2, f.normal2,
-                f.vertex3, f.texture3, f.normal3,
+            writeln!(writer, "f {} {} {}",
+                face_vertex(f.vertex1, f.texture1, f.normal1),
+                face_vertex(f.vertex2, f.texture2, f.normal2),
+                face_vertex(f.vertex3, f.texture3, f.normal3),
             ).into_result(write_err)?;
         }
     } else {
-        for f in view.faces {
+        for (label, _, f) in faces {
+            write_group(writer, label)?;
             #[rustfmt::skip]
-            writeln!(writer, "f {}//{} {}//{} {}//{}",
-                f.vertex1, f.normal1,
-                f.vertex2, f.normal2,
-                f.vertex3, f.normal3,
+            writeln!(writer, "f {} {} {}",
+                face_vertex(f.vertex1, 0, f.normal1),
+                face_vertex(f.vertex2, 0, f.normal2),
+                face_vertex(f.vertex3, 0, f.normal3),
             ).into_result(write_err)?;
         }
     }
@@ -148,11 +209,34 @@ pub fn export_to_obj<F: Fn(&Path, &[u8]) -> Result<()>>(
     Ok(())
 }
 
-fn read_element(
+// Omits absent (zero) texture point and normal indices.
+fn face_vertex(vertex: u32, texture: u32, normal: u32) -> String {
+    match (texture, normal) {
+        (0, 0) => vertex.to_string(),
+        (_, 0) => format!("{}/{}", vertex, texture),
+        (0, _) => format!("{}//{}", vertex, normal),
+        _ => format!("{}/{}/{}", vertex, texture, normal),
+    }
+}
+
+pub fn read_element(
     reader: &mut dyn fm::Read,
 ) -> Result<(fm::ElementView, fm::ElementViewState)> {
+    let (view, state, _) = read_textured_element(reader)?;
+    Ok((view, state))
+}
+
+// Also returns animated textures of the element ordered by time.
+pub fn read_textured_element(
+    reader: &mut dyn fm::Read,
+) -> Result<(
+    fm::ElementView,
+    fm::ElementViewState,
+    Vec<fm::ElementTexture>,
+)> {
     let mut view: Option<fm::ElementView> = None;
     let mut state: Option<fm::ElementViewState> = None;
+    let mut textures = Vec::new();
 
     loop {
         let rec = reader.read_record()?;
@@ -188,6 +272,18 @@ fn read_element(
                 }
                 state = Some(s);
             }
+            Some(ElementTexture(t)) => {
+                if view.is_none() || t.element != view.as_ref().unwrap().element
+                {
+                    return Err(Error::new(
+                        InconsistentState,
+                        format!("unknown texture element {}", t.element),
+                    ));
+                }
+                if t.texture.is_some() {
+                    textures.push(t);
+                }
+            }
             _ => {}
         }
     }
@@ -199,11 +295,14 @@ fn read_element(
         ));
     }
 
-    Ok((view.unwrap(), state.unwrap()))
+    textures.sort_by_key(|t: &fm::ElementTexture| t.time);
+    Ok((view.unwrap(), state.unwrap(), textures))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::path::PathBuf;
     use std::str;
 
@@ -286,7 +385,17 @@ mod tests {
         assert_eq!(&err.description, "unknown view state element element");
     }
 
-    fn create_element() -> fm::Reader<io::Cursor<Vec<u8>>> {
+    fn create_element(
+        face_labels: Vec<u32>,
+    ) -> fm::Reader<io::Cursor<Vec<u8>>> {
+        create_paged_element(face_labels, vec![], vec![])
+    }
+
+    fn create_paged_element(
+        face_labels: Vec<u32>,
+        texture_pages: Vec<fm::Image>,
+        face_pages: Vec<u32>,
+    ) -> fm::Reader<io::Cursor<Vec<u8>>> {
         create_reader_with_records(&vec![
             new_element_view_rec(fm::ElementView {
                 element: "element".to_string(),
@@ -304,7 +413,11 @@ mod tests {
                 texture: Some(fm::Image {
                     r#type: fm::image::Type::Jpeg as i32,
                     data: vec![1, 2, 3],
+                    ..Default::default()
                 }),
+                face_labels,
+                texture_pages,
+                face_pages,
                 ..Default::default()
             }),
             new_element_view_state_rec(fm::ElementViewState {
@@ -328,15 +441,15 @@ mod tests {
 
     #[test]
     fn test_export_textured_element() {
-        let mut reader = create_element();
+        let mut reader = create_element(vec![]);
 
         let write_file = |p: &Path, d: &[u8]| {
-            if p == &PathBuf::from("/some/path/abc.mtl") {
+            if p == Path::new("/some/path/abc.mtl") {
                 assert_eq!(
                     str::from_utf8(d).unwrap(),
                     "newmtl abc\nmap_Ka abc.jpg\nmap_Kd abc.jpg\n"
                 );
-            } else if p == &PathBuf::from("/some/path/abc.jpg") {
+            } else if p == Path::new("/some/path/abc.jpg") {
                 assert_eq!(d, &[1, 2, 3]);
             } else {
                 panic!("unexpected write_file path");
@@ -378,7 +491,7 @@ f 1/1/1 2/2/2 4/4/4
 
     #[test]
     fn test_export_non_textured_element() {
-        let mut reader = create_element();
+        let mut reader = create_element(vec![]);
 
         let mut writer = Vec::new();
         export_to_obj(&mut reader, &mut writer, NO_MTL).unwrap();
@@ -396,4 +509,136 @@ f 1//1 2//2 4//4
 "#;
         assert_eq!(str::from_utf8(&writer).unwrap(), expected);
     }
+
+    #[test]
+    fn test_export_labeled_element() {
+        let mut reader = create_element(vec![5, 2]);
+
+        let mut writer = Vec::new();
+        export_to_obj(&mut reader, &mut writer, NO_MTL).unwrap();
+
+        let expected = r#"v 1 2 3
+v 2 3 4
+v 3 4 5
+v 4 5 6
+vn 2 3 4
+vn 3 4 5
+vn 4 5 6
+vn 5 6 7
+g label_2
+f 1//1 2//2 4//4
+g label_5
+f 1//1 2//2 3//3
+"#;
+        assert_eq!(str::from_utf8(&writer).unwrap(), expected);
+
+        let mut reader = create_element(vec![1]);
+        let mut writer = Vec::new();
+        let err = export_to_obj(&mut reader, &mut writer, NO_MTL).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+    }
+
+    #[test]
+    fn test_export_animated_textures() {
+        let mut records = Vec::new();
+        let mut reader = create_element(vec![]);
+        while let Some(rec) = fm::Read::read_record(&mut reader).unwrap() {
+            records.push(rec);
+        }
+        for (time, data) in [(20, 5), (10, 4)] {
+            records.push(new_element_texture_rec(fm::ElementTexture {
+                element: "element".to_string(),
+                time,
+                texture: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![data],
+                    ..Default::default()
+                }),
+            }));
+        }
+        let mut reader = create_reader_with_records(&records);
+
+        let files = RefCell::new(HashMap::new());
+        let write_file = |p: &Path, d: &[u8]| {
+            files.borrow_mut().insert(p.to_owned(), d.to_vec());
+            Ok(())
+        };
+        let mtl = Some(MtlParams {
+            dir: &PathBuf::from("/some/path"),
+            name: "abc",
+            write_file,
+        });
+        export_to_obj(&mut reader, &mut Vec::new(), mtl).unwrap();
+
+        let files = files.into_inner();
+        assert_eq!(files[&PathBuf::from("/some/path/abc_0001.png")], vec![4]);
+        assert_eq!(files[&PathBuf::from("/some/path/abc_0002.png")], vec![5]);
+        assert_eq!(
+            str::from_utf8(&files[&PathBuf::from("/some/path/abc.mtl")])
+                .unwrap(),
+            "newmtl abc\nmap_Ka abc.jpg\nmap_Kd abc.jpg\n\
+             # texture 10 abc_0001.png\n# texture 20 abc_0002.png\n"
+        );
+
+        records.push(new_element_texture_rec(fm::ElementTexture {
+            element: "other".to_string(),
+            ..Default::default()
+        }));
+        let mut reader = create_reader_with_records(&records);
+        let err = read_element(&mut reader).unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+    }
+
+    #[test]
+    fn test_export_paged_element() {
+        let page = fm::Image {
+            r#type: fm::image::Type::Png as i32,
+            data: vec![4, 5],
+            ..Default::default()
+        };
+        let mut reader = create_paged_element(vec![], vec![page], vec![1, 0]);
+
+        let files = RefCell::new(HashMap::new());
+        let write_file = |p: &Path, d: &[u8]| {
+            files.borrow_mut().insert(p.to_owned(), d.to_vec());
+            Ok(())
+        };
+
+        let mut writer = Vec::new();
+        export_to_obj(
+            &mut reader,
+            &mut writer,
+            Some(MtlParams {
+                dir: &PathBuf::from("/some/path"),
+                name: "abc",
+                write_file,
+            }),
+        )
+        .unwrap();
+
+        let files = files.into_inner();
+        assert_eq!(files[&PathBuf::from("/some/path/abc.jpg")], vec![1, 2, 3]);
+        assert_eq!(
+            files[&PathBuf::from("/some/path/abc_page1.png")],
+            vec![4, 5]
+        );
+        assert_eq!(
+            str::from_utf8(&files[&PathBuf::from("/some/path/abc.mtl")])
+                .unwrap(),
+            "newmtl abc\nmap_Ka abc.jpg\nmap_Kd abc.jpg\n\
+             newmtl abc_page1\nmap_Ka abc_page1.png\nmap_Kd abc_page1.png\n"
+        );
+
+        let text = String::from_utf8(writer).unwrap();
+        let (_, faces) = text.split_once("vt 7 -7\n").unwrap();
+        assert_eq!(
+            faces,
+            "f 1/1/1 2/2/2 4/4/4\nusemtl abc_page1\nf 1/1/1 2/2/2 3/3/3\n"
+        );
+
+        let mut reader = create_paged_element(vec![], vec![], vec![0, 1]);
+        let mut writer = Vec::new();
+        let err = export_to_obj(&mut reader, &mut writer, NO_MTL).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+    }
 }
@@ -0,0 +1,295 @@
+use std::io;
+
+use structopt::StructOpt;
+
+use crate::export_to_obj::read_element;
+use base::define_raw_output;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+
+define_raw_output!(PlyOutput, "ply");
+
+#[derive(StructOpt)]
+#[structopt(about = "Export .fm file into PLY")]
+pub struct ExportToPlyCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: PlyOutput,
+
+    #[structopt(help = "Write binary little-endian PLY", long, short = "b")]
+    binary: bool,
+}
+
+impl ExportToPlyCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        export_to_ply(reader.as_mut(), &mut writer, self.binary)
+    }
+}
+
+pub fn export_to_ply(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn io::Write,
+    binary: bool,
+) -> Result<()> {
+    let (view, state) = read_element(reader)?;
+
+    let with_colors = !view.vertex_colors.is_empty();
+    if with_colors && view.vertex_colors.len() != state.vertices.len() {
+        return Err(Error::new(
+            InconsistentState,
+            "number of vertex colors differs from one of vertices".to_string(),
+        ));
+    }
+
+    // Unlike .fm, PLY vertices share the same index for normals, so face
+    // corners with distinct normals become distinct vertices.
+    let corners: Vec<(u32, u32)> = view
+        .faces
+        .iter()
+        .flat_map(|f| {
+            [
+                (f.vertex1, f.normal1),
+                (f.vertex2, f.normal2),
+                (f.vertex3, f.normal3),
+            ]
+        })
+        .collect();
+    let with_normals = if corners.is_empty() {
+        state.normals.len() == state.vertices.len()
+    } else {
+        !state.normals.is_empty()
+    };
+    let mut unique = if corners.is_empty() {
+        // Point cloud with normals (if any) sharing indices with vertices.
+        let len = state.vertices.len() as u32;
+        (1..=len)
+            .map(|i| (i, if with_normals { i } else { 0 }))
+            .collect()
+    } else {
+        corners.clone()
+    };
+    unique.sort_unstable();
+    unique.dedup();
+
+    let get = |items_len: usize, index: u32, what: &str| {
+        if index == 0 || index as usize > items_len {
+            let desc = format!("bad {} number {} in view face", what, index);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        Ok(index as usize - 1)
+    };
+
+    let mut vertices = Vec::with_capacity(unique.len());
+    for &(vertex, normal) in &unique {
+        let v = get(state.vertices.len(), vertex, "vertex")?;
+        let p = &state.vertices[v];
+        let mut floats = vec![p.x, p.y, p.z];
+        if with_normals {
+            let n = &state.normals[get(state.normals.len(), normal, "normal")?];
+            floats.extend_from_slice(&[n.x, n.y, n.z]);
+        }
+        let color = view
+            .vertex_colors
+            .get(v)
+            .map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8]);
+        vertices.push((floats, color));
+    }
+
+    let write_err = || "failed to write PLY-file".to_string();
+
+    let format = if binary {
+        "binary_little_endian"
+    } else {
+        "ascii"
+    };
+    let mut header = format!(
+        "ply\nformat {} 1.0\nelement vertex {}\n\
+         property float x\nproperty float y\nproperty float z\n",
+        format,
+        vertices.len()
+    );
+    if with_normals {
+        header += "property float nx\nproperty float ny\nproperty float nz\n";
+    }
+    if with_colors {
+        header +=
+            "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+    }
+    header += &format!(
+        "element face {}\nproperty list uchar int vertex_indices\n\
+         end_header\n",
+        view.faces.len()
+    );
+    writer.write_all(header.as_bytes()).into_result(write_err)?;
+
+    let indices: Vec<u32> = corners
+        .iter()
+        .map(|c| unique.binary_search(c).unwrap() as u32)
+        .collect();
+
+    if binary {
+        let mut data = Vec::new();
+        for (floats, color) in &vertices {
+            for f in floats {
+                data.extend_from_slice(&f.to_le_bytes());
+            }
+            if let Some(color) = color {
+                data.extend_from_slice(color);
+            }
+        }
+        for face in indices.chunks(3) {
+            data.push(3);
+            for i in face {
+                data.extend_from_slice(&i.to_le_bytes());
+            }
+        }
+        writer.write_all(&data).into_result(write_err)?;
+    } else {
+        for (floats, color) in &vertices {
+            let mut line: Vec<String> =
+                floats.iter().map(|f| f.to_string()).collect();
+            for c in color.iter().flatten() {
+                line.push(c.to_string());
+            }
+            writeln!(writer, "{}", line.join(" ")).into_result(write_err)?;
+        }
+        for face in indices.chunks(3) {
+            writeln!(writer, "3 {} {} {}", face[0], face[1], face[2])
+                .into_result(write_err)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use super::*;
+    use crate::import_from_ply::import_ply;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn create_element() -> fm::Reader<io::Cursor<Vec<u8>>> {
+        let face = |v: [u32; 3], n: [u32; 3]| fm::element_view::Face {
+            vertex1: v[0],
+            vertex2: v[1],
+            vertex3: v[2],
+            normal1: n[0],
+            normal2: n[1],
+            normal3: n[2],
+            ..Default::default()
+        };
+        create_reader_with_records(&[
+            new_element_view_rec(fm::ElementView {
+                element: "foo".to_string(),
+                faces: vec![
+                    face([1, 2, 3], [1, 1, 1]),
+                    face([1, 3, 4], [1, 1, 2]),
+                ],
+                vertex_colors: vec![0xFF0000, 0x00FF00, 0x0000FF, 0x102030],
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "foo".to_string(),
+                vertices: vec![
+                    new_point3(0.0, 0.0, 0.0),
+                    new_point3(1.0, 0.0, 0.0),
+                    new_point3(1.0, 1.0, 0.0),
+                    new_point3(0.0, 1.0, 0.5),
+                ],
+                normals: vec![
+                    new_point3(0.0, 0.0, 1.0),
+                    new_point3(0.0, 1.0, 0.0),
+                ],
+                ..Default::default()
+            }),
+        ])
+    }
+
+    #[test]
+    fn test_export_to_ply_ascii() {
+        let mut reader = create_element();
+        let mut data = Vec::new();
+        export_to_ply(&mut reader, &mut data, false).unwrap();
+        assert_eq!(
+            str::from_utf8(&data).unwrap(),
+            "ply
+format ascii 1.0
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 2
+property list uchar int vertex_indices
+end_header
+0 0 0 0 0 1 255 0 0
+1 0 0 0 0 1 0 255 0
+1 1 0 0 0 1 0 0 255
+0 1 0.5 0 1 0 16 32 48
+3 0 1 2
+3 0 2 3
+"
+        );
+    }
+
+    #[test]
+    fn test_export_to_ply_binary() {
+        let mut reader = create_element();
+        let mut data = Vec::new();
+        export_to_ply(&mut reader, &mut data, true).unwrap();
+
+        let mut writer = create_writer();
+        import_ply(&mut data.as_slice(), &mut writer, "bar").unwrap();
+        let mut reader = writer_to_reader(writer);
+        let view = record_variant!(
+            ElementView,
+            reader.read_record().unwrap().unwrap()
+        );
+        let state = record_variant!(
+            ElementViewState,
+            reader.read_record().unwrap().unwrap()
+        );
+
+        assert_eq!(
+            view.vertex_colors,
+            vec![0xFF0000, 0x00FF00, 0x0000FF, 0x102030]
+        );
+        assert_eq!(view.faces.len(), 2);
+        assert_eq!(view.faces[1].vertex3, 4);
+        assert_eq!(state.vertices[3], new_point3(0.0, 1.0, 0.5));
+        assert_eq!(state.normals[3], new_point3(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn test_export_to_ply_bad_colors() {
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(fm::ElementView {
+                element: "foo".to_string(),
+                vertex_colors: vec![0xFF0000],
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "foo".to_string(),
+                ..Default::default()
+            }),
+        ]);
+        let err =
+            export_to_ply(&mut reader, &mut Vec::new(), false).unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+    }
+}
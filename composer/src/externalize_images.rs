@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+
+use log::info;
+use structopt::StructOpt;
+
+use crate::telemetry;
+use base::defs::Result;
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Move images of .fm file to external storage")]
+pub struct ExternalizeImagesCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(help = "Directory or URL to store images in", long)]
+    store: PathBuf,
+}
+
+impl ExternalizeImagesCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let output = cli::FmOutput {
+            path: self.output.path.clone(),
+            fm_params: fm::WriterParams {
+                external_images: Some(self.store.clone()),
+                ..self.output.fm_params.clone()
+            },
+        };
+        let mut writer = output.get()?;
+
+        externalize_images(reader.as_mut(), writer.as_mut())
+    }
+}
+
+pub fn externalize_images(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+) -> Result<()> {
+    let mut num = 0;
+    while let Some(rec) = reader.read_record()? {
+        writer.write_record(&rec)?;
+        num += 1;
+    }
+    telemetry::count("records", num);
+    info!("externalized images of {} records", num);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::{Read as _, Write as _};
+
+    #[test]
+    fn test_externalize_images() {
+        let new_frame = |time, data| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "a".to_string(),
+                time,
+                image: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+        let mut writer = create_writer();
+        for rec in [
+            new_frame(1, vec![1, 2, 3]),
+            new_frame(2, vec![1, 2, 3]),
+            new_frame(3, vec![4, 5]),
+        ] {
+            writer.write_record(&rec).unwrap();
+        }
+
+        let dir = std::env::temp_dir()
+            .join(format!("externalize-images-test-{}", std::process::id()));
+        let (out_dir, store) = (dir.join("out"), dir.join("out/images"));
+        std::fs::create_dir_all(&store).unwrap();
+        let in_path = dir.join("in.fm");
+        std::fs::write(&in_path, writer.into_inner().unwrap()).unwrap();
+
+        let out_path = out_dir.join("x.fm");
+        ExternalizeImagesCommand {
+            input: cli::FmInput {
+                path: Some(in_path),
+                images: Default::default(),
+                limits: Default::default(),
+            },
+            output: cli::FmOutput {
+                path: Some(out_path.clone()),
+                fm_params: Default::default(),
+            },
+            store: store.clone(),
+        }
+        .run()
+        .unwrap();
+
+        let data = std::fs::read(&out_path).unwrap();
+        let mut reader = fm::Reader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.features(), fm::FEATURE_EXTERNAL_IMAGES);
+        let mut locations = Vec::new();
+        for time in [1, 2, 3] {
+            let rec = reader.read_record().unwrap().unwrap();
+            let frame = record_variant!(ScanFrame, rec);
+            assert_eq!(frame.time, time);
+            let image = frame.image.unwrap();
+            assert!(image.data.is_empty());
+            assert!(image.location.starts_with("images"));
+            assert!(image.location.ends_with(".png"));
+            locations.push(image.location);
+        }
+        assert_eq!(locations[0], locations[1]);
+        assert_ne!(locations[0], locations[2]);
+
+        // Locations are resolved against the directory of .fm file without
+        // allowing any other ones.
+        let input = cli::FmInput {
+            path: Some(out_path),
+            images: cli::ImageParams {
+                resolve_images: true,
+                any_image_locations: false,
+            },
+            limits: Default::default(),
+        };
+        let mut reader = input.get().unwrap();
+        for data in [vec![1, 2, 3], vec![1, 2, 3], vec![4, 5]] {
+            let rec = reader.read_record().unwrap().unwrap();
+            let image = record_variant!(ScanFrame, rec).image.unwrap();
+            assert_eq!(image.data, data);
+            assert!(image.digest.is_empty());
+            assert!(image.location.is_empty());
+        }
+        assert!(reader.read_record().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,9 @@
 use std::io::Cursor;
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 
 use image::io::Reader as ImageReader;
-use image::ImageOutputFormat;
+use image::{ImageOutputFormat, RgbImage};
 use structopt::StructOpt;
 
 use crate::texture::{
@@ -33,32 +34,79 @@ pub struct ExtractScanImagesCommand {
         default_value = "#ff0000"
     )]
     pub highlight_color: Vector3,
+
+    #[structopt(
+        help = "Extract frames since given time (e.g. 1m30s)",
+        long,
+        allow_hyphen_values = true
+    )]
+    from: Option<fm::Time>,
+
+    #[structopt(
+        help = "Extract frames until given time (inclusive)",
+        long,
+        allow_hyphen_values = true
+    )]
+    to: Option<fm::Time>,
 }
 
 impl ExtractScanImagesCommand {
     pub fn run(&self) -> Result<()> {
-        let mut reader = self.input.get()?;
-
         let output_dir =
             self.output_dir.as_deref().unwrap_or_else(|| ".".as_ref());
+        let range = self.from.unwrap_or(fm::Time::MIN)
+            ..=self.to.unwrap_or(fm::Time::MAX);
 
+        if let Some(indexed) = self.open_indexed()? {
+            let resolver =
+                self.input.images.resolver(self.input.path.as_deref());
+            let resolver =
+                resolver.as_ref().map(|r| r as &dyn fm::ImageResolver);
+            return extract_indexed_scan_images(
+                &indexed,
+                resolver,
+                |p, d| fs::write_output(p, d),
+                output_dir,
+                range,
+                &self.background,
+                &self.highlight_color,
+            );
+        }
+
+        let mut reader = self.input.get()?;
         extract_scan_images(
             reader.as_mut(),
-            |p, d| fs::write_file(p, d),
+            |p, d| fs::write_output(p, d),
             output_dir,
+            range,
             &self.background,
             &self.highlight_color,
         )
     }
+
+    // Opens input to seek frames of the time range (remote inputs are read
+    // by ranges), returns None if there is no range or the input isn't
+    // indexed.
+    fn open_indexed(&self) -> Result<Option<fm::IndexedReader>> {
+        match &self.input.path {
+            Some(path) if self.from.is_some() || self.to.is_some() => {
+                let file = fs::open_seekable_input(path)?;
+                fm::IndexedReader::open(file, self.input.limits)
+            }
+            _ => Ok(None),
+        }
+    }
 }
 
 pub fn extract_scan_images<F: Fn(&Path, &[u8]) -> Result<()>>(
     reader: &mut dyn fm::Read,
     write_file: F,
     output_dir: &Path,
+    range: RangeInclusive<fm::Time>,
     background: &BackgroundParams,
     highlight_color: &Vector3,
 ) -> Result<()> {
+    let background_images = background.load_images()?;
     for n in 1.. {
         let rec = reader.read_record()?;
         if rec.is_none() {
@@ -66,18 +114,55 @@ pub fn extract_scan_images<F: Fn(&Path, &[u8]) -> Result<()>>(
         }
 
         if let Some(fm::record::Type::ScanFrame(frame)) = rec.unwrap().r#type {
+            if !range.contains(&fm::Time(frame.time)) {
+                continue;
+            }
+            if let Some(image) = frame.image {
+                let (filename, data) = extract_image(
+                    n,
+                    image,
+                    output_dir,
+                    background,
+                    background_images.get(&frame.scan),
+                    highlight_color,
+                )?;
+                write_file(&filename, &data)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Seeks frames of the time range, naming images as extract_scan_images
+// does (by numbers of frame records).
+pub fn extract_indexed_scan_images<F: Fn(&Path, &[u8]) -> Result<()>>(
+    indexed: &fm::IndexedReader,
+    resolver: Option<&dyn fm::ImageResolver>,
+    write_file: F,
+    output_dir: &Path,
+    range: RangeInclusive<fm::Time>,
+    background: &BackgroundParams,
+    highlight_color: &Vector3,
+) -> Result<()> {
+    let background_images = background.load_images()?;
+    for scan in indexed.scans() {
+        let scan = scan?;
+        for frame in indexed.numbered_frames(&scan.name, range.clone()) {
+            let (index, frame) = frame?;
             if let Some(mut image) = frame.image {
-                if background.deviation > 0.0 {
-                    image = highlight_background(
-                        &image,
-                        background,
-                        highlight_color,
-                    )?;
+                if let Some(resolver) = resolver {
+                    fm::resolve_image(&mut image, resolver)?;
                 }
-                let ext = fm::image_type_extension(image.r#type());
-                let filename =
-                    output_dir.join(&n.to_string()).with_extension(ext);
-                write_file(&filename, &image.data)?;
+                let (filename, data) = extract_image(
+                    index + 1,
+                    image,
+                    output_dir,
+                    background,
+                    background_images.get(&frame.scan),
+                    highlight_color,
+                )?;
+                write_file(&filename, &data)?;
             }
         }
     }
@@ -85,9 +170,31 @@ pub fn extract_scan_images<F: Fn(&Path, &[u8]) -> Result<()>>(
     Ok(())
 }
 
+fn extract_image(
+    n: usize,
+    mut image: fm::Image,
+    output_dir: &Path,
+    background: &BackgroundParams,
+    background_image: Option<&RgbImage>,
+    highlight_color: &Vector3,
+) -> Result<(PathBuf, Vec<u8>)> {
+    if background.deviation > 0.0 {
+        image = highlight_background(
+            &image,
+            background,
+            background_image,
+            highlight_color,
+        )?;
+    }
+    let ext = fm::image_type_extension(image.r#type());
+    let filename = output_dir.join(n.to_string()).with_extension(ext);
+    Ok((filename, image.data))
+}
+
 fn highlight_background(
     image: &fm::Image,
     params: &BackgroundParams,
+    background_image: Option<&RgbImage>,
     highlight_color: &Vector3,
 ) -> Result<fm::Image> {
     let err_fn = || "failed to decode frame image".to_string();
@@ -98,7 +205,7 @@ fn highlight_background(
         .map_err(|e| Error::with_source(ErrorKind::ImageError, err_fn(), e))?
         .into_rgb8();
 
-    let detector = BackgroundDetector::new(&rgb, params);
+    let detector = BackgroundDetector::new(&rgb, params, background_image);
     for i in 0..rgb.height() {
         for j in 0..rgb.width() {
             let uv = Vector2::new(
@@ -120,5 +227,114 @@ fn highlight_background(
     Ok(fm::Image {
         r#type: fm::image::Type::Png as i32,
         data: data.into_inner(),
+        ..Default::default()
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+
+    use super::*;
+    use base::util::test::*;
+    use fm::{Read as _, Write as _};
+
+    fn write_scan(params: &fm::WriterParams) -> Vec<u8> {
+        let mut writer = fm::Writer::new(Vec::new(), params).unwrap();
+        let scan = fm::Scan {
+            name: "a".to_string(),
+            ..Default::default()
+        };
+        writer.write_record(&new_scan_rec(scan)).unwrap();
+        for time in 0..5 {
+            let frame = fm::ScanFrame {
+                scan: "a".to_string(),
+                time: time * 1_000_000_000,
+                image: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![time as u8; 100],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            writer.write_record(&new_scan_frame_rec(frame)).unwrap();
+        }
+        writer.into_inner().unwrap()
+    }
+
+    fn extracted_files(
+        extract: impl FnOnce(&dyn Fn(&Path, &[u8]) -> Result<()>),
+    ) -> Vec<(PathBuf, Vec<u8>)> {
+        let files = RefCell::new(Vec::new());
+        extract(&|path, data| {
+            files.borrow_mut().push((path.to_path_buf(), data.to_vec()));
+            Ok(())
+        });
+        files.into_inner()
+    }
+
+    #[test]
+    fn test_extract_indexed_scan_images() {
+        let cmd = ExtractScanImagesCommand::from_iter(["extract-scan-images"]);
+        let range = fm::Time(1_000_000_000)..=fm::Time(3_000_000_000);
+        use fm::Compression::*;
+        for compression in [None, Gzip, Zstd] {
+            let params = fm::WriterParams {
+                compression,
+                index: true,
+                ..Default::default()
+            };
+            let data = write_scan(&params);
+
+            // Sequential reading stops at the index.
+            let mut reader = fm::Reader::new(io::Cursor::new(&data)).unwrap();
+            assert_eq!(reader.features(), fm::FEATURE_INDEX);
+            let mut num_records = 0;
+            while reader.read_record().unwrap().is_some() {
+                num_records += 1;
+            }
+            assert_eq!(num_records, 6);
+
+            let cursor = io::Cursor::new(data.clone());
+            let indexed = fm::IndexedReader::open(cursor, Default::default())
+                .unwrap()
+                .unwrap();
+            let files = extracted_files(|write_file| {
+                extract_indexed_scan_images(
+                    &indexed,
+                    Option::None,
+                    write_file,
+                    "out".as_ref(),
+                    range.clone(),
+                    &cmd.background,
+                    &cmd.highlight_color,
+                )
+                .unwrap()
+            });
+
+            let mut reader = fm::Reader::new(io::Cursor::new(data)).unwrap();
+            let expected = extracted_files(|write_file| {
+                extract_scan_images(
+                    &mut reader,
+                    write_file,
+                    "out".as_ref(),
+                    range.clone(),
+                    &cmd.background,
+                    &cmd.highlight_color,
+                )
+                .unwrap()
+            });
+            assert_eq!(files, expected);
+            assert_eq!(files.len(), 3);
+            assert_eq!(files[0].0, PathBuf::from("out/3.png"));
+            assert_eq!(files[0].1, vec![1; 100]);
+        }
+
+        // Files without index are read sequentially.
+        let data = write_scan(&Default::default());
+        let cursor = io::Cursor::new(data);
+        let indexed = fm::IndexedReader::open(cursor, Default::default());
+        assert!(indexed.unwrap().is_none());
+    }
+}
@@ -0,0 +1,232 @@
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use base::define_raw_input;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+use base::util::fs;
+
+define_raw_input!(JsonInput, "json");
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JsonFormat {
+    // Records one after another (as written by export-to-json).
+    Json,
+    // Records one per line (as written by export-to-json --ndjson).
+    Ndjson,
+    // Records as documents separated by '---'.
+    Yaml,
+}
+
+impl FromStr for JsonFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(JsonFormat::Json),
+            "ndjson" => Ok(JsonFormat::Ndjson),
+            "yaml" => Ok(JsonFormat::Yaml),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown JSON format".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Import .fm records from JSON (or YAML)")]
+pub struct ImportFromJsonCommand {
+    #[structopt(flatten)]
+    input: JsonInput,
+
+    #[structopt(
+        help = concat!("Input format: json or ndjson (streamed), or yaml ",
+            "(buffered), by default ndjson for .ndjson and .jsonl files, yaml ",
+            "for .yaml and .yml files, otherwise json"),
+        long
+    )]
+    format: Option<JsonFormat>,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl ImportFromJsonCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        let format = self.format.unwrap_or_else(|| {
+            let ext = self.input.path.as_ref().and_then(|p| p.extension());
+            match ext.and_then(|e| e.to_str()) {
+                Some("ndjson" | "jsonl") => JsonFormat::Ndjson,
+                Some("yaml" | "yml") => JsonFormat::Yaml,
+                _ => JsonFormat::Json,
+            }
+        });
+
+        import_from_json(reader.as_mut(), writer.as_mut(), format)
+    }
+}
+
+pub fn import_from_json(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn fm::Write,
+    format: JsonFormat,
+) -> Result<()> {
+    match format {
+        JsonFormat::Json => {
+            let reader = BufReader::new(reader);
+            let records = serde_json::Deserializer::from_reader(reader)
+                .into_iter::<fm::Record>();
+            for (i, record) in records.enumerate() {
+                let record = record.map_err(|err| {
+                    let desc = format!("failed to parse record #{}", i + 1);
+                    Error::with_source(MalformedData, desc, err)
+                })?;
+                writer.write_record(&record)?;
+            }
+        }
+        JsonFormat::Ndjson => {
+            // Blank lines (e.g. the trailing one) are skipped.
+            for (i, line) in BufReader::new(reader).lines().enumerate() {
+                let line = line.into_result(|| {
+                    format!("failed to read line {} of NDJSON input", i + 1)
+                })?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str(&line).map_err(|err| {
+                    let desc =
+                        format!("failed to parse record at line {}", i + 1);
+                    Error::with_source(MalformedData, desc, err)
+                })?;
+                writer.write_record(&record)?;
+            }
+        }
+        JsonFormat::Yaml => {
+            // YAML deserializer loads all documents at once anyway.
+            let mut text = String::new();
+            reader
+                .read_to_string(&mut text)
+                .into_result(|| "failed to read YAML input".to_string())?;
+            let documents = serde_yaml::Deserializer::from_str(&text);
+            for (i, document) in documents.enumerate() {
+                let record =
+                    fm::Record::deserialize(document).map_err(|err| {
+                        let desc = format!("failed to parse record #{}", i + 1);
+                        Error::with_source(MalformedData, desc, err)
+                    })?;
+                writer.write_record(&record)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export_to_json::export_to_json;
+    use base::util::test::*;
+
+    fn new_records() -> Vec<fm::Record> {
+        vec![
+            new_element_view_rec(fm::ElementView {
+                element: "element".to_string(),
+                texture: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![1, 2, 3],
+                    ..Default::default()
+                }),
+                texture_points: vec![
+                    new_point2(1.0, 2.0),
+                    new_point2(3.0, 4.0),
+                ],
+                faces: vec![new_ev_face(1, 2, 2, 1, 2, 2, 0, 0, 0)],
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "element".to_string(),
+                time: 123,
+                vertices: vec![
+                    new_point3(5.0, 6.0, 7.0),
+                    new_point3(8.0, 9.0, 10.0),
+                    new_point3(11.0, 12.0, 13.0),
+                ],
+                ..Default::default()
+            }),
+        ]
+    }
+
+    fn import(text: &str, format: JsonFormat) -> Result<Vec<fm::Record>> {
+        let mut writer = create_writer();
+        import_from_json(&mut text.as_bytes(), &mut writer, format)?;
+        let mut reader = writer_to_reader(writer);
+        fm::records(&mut reader).collect()
+    }
+
+    #[test]
+    fn test_import_from_json_round_trip() {
+        for pretty in [false, true] {
+            let mut reader = create_reader_with_records(&new_records());
+            let mut json = Vec::new();
+            export_to_json(&mut reader, &mut json, None, pretty).unwrap();
+            let text = String::from_utf8(json).unwrap();
+            assert_eq!(import(&text, JsonFormat::Json).unwrap(), new_records());
+        }
+    }
+
+    #[test]
+    fn test_import_from_json_ndjson() {
+        let mut reader = create_reader_with_records(&new_records());
+        let mut json = Vec::new();
+        export_to_json(&mut reader, &mut json, None, false).unwrap();
+        let text = String::from_utf8(json).unwrap();
+        assert_eq!(text.lines().count(), new_records().len());
+        let text = text.replace('\n', "\n\n");
+        assert_eq!(import(&text, JsonFormat::Ndjson).unwrap(), new_records());
+
+        let text = r#"{"type":null}
+
+{"type":{"ElementViewState":{"element":"e"}}}"#;
+        let err = import(text, JsonFormat::Ndjson).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        assert_eq!(err.description, "failed to parse record at line 3");
+
+        // Unlike json, ndjson doesn't allow records to span lines.
+        let text = "{\"type\":\nnull}";
+        assert!(import(text, JsonFormat::Json).is_ok());
+        assert!(import(text, JsonFormat::Ndjson).is_err());
+    }
+
+    #[test]
+    fn test_import_from_json_yaml() {
+        // Each serialized record starts with '---'.
+        let text: String = new_records()
+            .iter()
+            .map(|r| serde_yaml::to_string(r).unwrap())
+            .collect();
+        assert_eq!(import(&text, JsonFormat::Yaml).unwrap(), new_records());
+    }
+
+    #[test]
+    fn test_import_from_json_malformed() {
+        let text = r#"{"type":{"ElementViewState":{"element":"e"}}}"#;
+        let err = import(text, JsonFormat::Json).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        assert_eq!(err.description, "failed to parse record #1");
+
+        let text = r#"{"type":null} {"type":"#;
+        let err = import(text, JsonFormat::Json).unwrap_err();
+        assert_eq!(err.description, "failed to parse record #2");
+    }
+}
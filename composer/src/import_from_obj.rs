@@ -22,6 +22,21 @@ pub struct ImportFromObjCommand {
     #[structopt(flatten)]
     input: ObjInput,
 
+    #[structopt(
+        help = "Pattern of OBJ sequence files to import as element states \
+                (e.g. 'frame_%04d.obj'), numbered from 0 or 1",
+        long,
+        conflicts_with = "in-file"
+    )]
+    sequence: Option<String>,
+
+    #[structopt(
+        help = "Time step between states of OBJ sequence",
+        long,
+        default_value = "33333333"
+    )]
+    timestep: fm::Time,
+
     #[structopt(help = "Element ID for imported data", long, short = "e")]
     element: Option<String>,
 
@@ -31,6 +46,10 @@ pub struct ImportFromObjCommand {
 
 impl ImportFromObjCommand {
     pub fn run(&self) -> Result<()> {
+        if let Some(pattern) = &self.sequence {
+            return self.run_sequence(pattern);
+        }
+
         let mut reader = self.input.get()?;
         let mut writer = self.output.get()?;
 
@@ -63,6 +82,34 @@ impl ImportFromObjCommand {
             element.as_str(),
         )
     }
+
+    fn run_sequence(&self, pattern: &str) -> Result<()> {
+        let paths = sequence_paths(pattern, |p| p.exists())?;
+        let mut writer = self.output.get()?;
+
+        let element = match &self.element {
+            Some(id) => id.clone(),
+            None => {
+                let stem = Path::new(pattern).file_stem().unwrap_or_default();
+                let stem = stem.to_str().unwrap_or_default();
+                let (prefix, _, suffix) =
+                    parse_sequence_pattern(stem).unwrap_or((stem, 0, ""));
+                let separators: &[char] = &['_', '-', '.', ' '];
+                let prefix = prefix.trim_end_matches(separators);
+                let suffix = suffix.trim_start_matches(separators);
+                format!("{}{}", prefix, suffix)
+            }
+        };
+
+        #[allow(clippy::redundant_closure)]
+        import_obj_sequence(
+            &paths,
+            writer.as_mut(),
+            |p| fs::read_file(p),
+            element.as_str(),
+            self.timestep,
+        )
+    }
 }
 
 pub fn import_obj<F: Fn(&Path) -> Result<Vec<u8>>>(
@@ -72,6 +119,127 @@ pub fn import_obj<F: Fn(&Path) -> Result<Vec<u8>>>(
     mtl_dir: &Path,
     element: &str,
 ) -> Result<()> {
+    let (view, state) = parse_obj(reader, &read_file, mtl_dir, element)?;
+
+    use fm::record::Type;
+
+    writer.write_record(&fm::Record {
+        r#type: Some(Type::ElementView(view)),
+    })?;
+
+    writer.write_record(&fm::Record {
+        r#type: Some(Type::ElementViewState(state)),
+    })?;
+
+    Ok(())
+}
+
+// Imports a sequence of OBJ files sharing the same topology as states of a
+// single element, so that scans of moving subjects become animated models.
+pub fn import_obj_sequence<F: Fn(&Path) -> Result<Vec<u8>>>(
+    paths: &[PathBuf],
+    writer: &mut dyn fm::Write,
+    read_file: F,
+    element: &str,
+    timestep: fm::Time,
+) -> Result<()> {
+    use fm::record::Type;
+
+    // View and number of vertices of the first file.
+    let mut first: Option<(fm::ElementView, usize)> = None;
+    let mut time = fm::Time(0);
+    for path in paths {
+        let data = read_file(path)?;
+        let mtl_dir = path.parent().unwrap_or_else(|| ".".as_ref());
+        let (view, mut state) =
+            parse_obj(&mut data.as_slice(), &read_file, mtl_dir, element)?;
+
+        match &first {
+            Some((first_view, num_vertices)) => {
+                if view.faces != first_view.faces
+                    || state.vertices.len() != *num_vertices
+                {
+                    let desc = format!(
+                        "topology of '{}' differs from first sequence file",
+                        path.display()
+                    );
+                    return Err(Error::new(InconsistentState, desc));
+                }
+            }
+            None => {
+                writer.write_record(&fm::Record {
+                    r#type: Some(Type::ElementView(view.clone())),
+                })?;
+                first = Some((view, state.vertices.len()));
+            }
+        }
+
+        state.time = time.0;
+        writer.write_record(&fm::Record {
+            r#type: Some(Type::ElementViewState(state)),
+        })?;
+        time = time.checked_add(timestep).ok_or_else(|| {
+            Error::new(BadOperation, "sequence time overflow".to_string())
+        })?;
+    }
+
+    if first.is_none() {
+        let desc = "empty OBJ sequence".to_string();
+        return Err(Error::new(BadOperation, desc));
+    }
+    Ok(())
+}
+
+// Splits pattern into prefix, number width and suffix around its single
+// '%d' or '%0Nd' placeholder.
+fn parse_sequence_pattern(pattern: &str) -> Option<(&str, usize, &str)> {
+    let (prefix, rest) = pattern.split_once('%')?;
+    let (spec, suffix) = rest.split_once('d')?;
+    let width = if spec.is_empty() {
+        0
+    } else if spec.starts_with('0') {
+        spec.parse().ok()?
+    } else {
+        return None;
+    };
+    if suffix.contains('%') {
+        return None;
+    }
+    Some((prefix, width, suffix))
+}
+
+// Lists existing files of the sequence, numbered from 0 or 1 without gaps.
+pub fn sequence_paths<F: Fn(&Path) -> bool>(
+    pattern: &str,
+    exists: F,
+) -> Result<Vec<PathBuf>> {
+    let (prefix, width, suffix) =
+        parse_sequence_pattern(pattern).ok_or_else(|| {
+            let desc = format!(
+                "malformed sequence pattern '{}' (expected e.g. \
+                 'frame_%04d.obj')",
+                pattern
+            );
+            Error::new(MalformedData, desc)
+        })?;
+    let path =
+        |i: usize| PathBuf::from(format!("{}{:0width$}{}", prefix, i, suffix));
+
+    let first = if exists(&path(0)) { 0 } else { 1 };
+    let paths: Vec<_> = (first..).map(path).take_while(|p| exists(p)).collect();
+    if paths.is_empty() {
+        let desc = format!("no files match sequence pattern '{}'", pattern);
+        return Err(Error::new(IoError, desc));
+    }
+    Ok(paths)
+}
+
+fn parse_obj<F: Fn(&Path) -> Result<Vec<u8>>>(
+    reader: &mut dyn io::Read,
+    read_file: &F,
+    mtl_dir: &Path,
+    element: &str,
+) -> Result<(fm::ElementView, fm::ElementViewState)> {
     let mut data = ImportData {
         view: fm::ElementView {
             element: element.to_string(),
@@ -92,7 +260,7 @@ pub fn import_obj<F: Fn(&Path) -> Result<Vec<u8>>>(
             match parts[0] {
                 "f" => import_f(&mut data, &parts)?,
                 "mtllib" => {
-                    import_mtllib(&read_file, mtl_dir, &mut data, &parts)?
+                    import_mtllib(read_file, mtl_dir, &mut data, &parts)?
                 }
                 "usemtl" => import_usemtl(&mut data, &parts)?,
                 "v" => import_v(&mut data, &parts)?,
@@ -103,17 +271,7 @@ pub fn import_obj<F: Fn(&Path) -> Result<Vec<u8>>>(
         }
     }
 
-    use fm::record::Type;
-
-    writer.write_record(&fm::Record {
-        r#type: Some(Type::ElementView(take(&mut data.view))),
-    })?;
-
-    writer.write_record(&fm::Record {
-        r#type: Some(Type::ElementViewState(take(&mut data.state))),
-    })?;
-
-    Ok(())
+    Ok((take(&mut data.view), take(&mut data.state)))
 }
 
 #[derive(Default)]
@@ -160,7 +318,7 @@ fn import_f(data: &mut ImportData, parts: &[&str]) -> Result<()> {
 
         validate_face_vertex(data, v1, t1, n1)?;
         validate_face_vertex(data, v2, t2, n2)?;
-        validate_face_vertex(data, v2, t2, n2)?;
+        validate_face_vertex(data, v3, t3, n3)?;
 
         data.view.faces.push(fm::element_view::Face {
             vertex1: v1,
@@ -303,6 +461,7 @@ fn import_mtl_map_kad<F: Fn(&Path) -> Result<Vec<u8>>>(
     let texture = fm::Image {
         r#type: image_type as i32,
         data: read_file(&mtl_dir.join(path))?,
+        ..Default::default()
     };
     data.view.texture = Some(texture);
 
@@ -310,6 +469,12 @@ fn import_mtl_map_kad<F: Fn(&Path) -> Result<Vec<u8>>>(
 }
 
 fn import_mtl_newmtl(data: &mut ImportData, parts: &[&str]) -> Result<()> {
+    if parts.len() != 2 {
+        let desc =
+            format!("malformed newmtl-statement at line {}", data.mtl_line);
+        return Err(Error::new(MalformedData, desc));
+    }
+
     if data.mtl_material.is_some() {
         let desc = format!(
             "multiple materials are not supported, found at line {}",
@@ -418,7 +583,13 @@ fn validate_face_vertex(
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::export_to_obj::{export_to_obj, MtlParams};
     use base::record_variant;
     use base::util::test::*;
     use fm::record::Type::*;
@@ -428,11 +599,11 @@ mod tests {
         panic!("unexpected call to read_file");
     }
 
-    fn create_read_mtl(
-        mtl: &'static str,
-    ) -> Box<dyn Fn(&Path) -> Result<Vec<u8>>> {
+    type ReadFile = Box<dyn Fn(&Path) -> Result<Vec<u8>>>;
+
+    fn create_read_mtl(mtl: &'static str) -> ReadFile {
         Box::new(move |p: &Path| {
-            if p == &Path::new("obj-path").join("foo.mtl") {
+            if p == Path::new("obj-path").join("foo.mtl") {
                 Ok(mtl.as_bytes().to_vec())
             } else {
                 panic!("unexpected path passed to read_file");
@@ -555,6 +726,17 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mtl_newmtl_malformed() {
+        let read_file = create_read_mtl("newmtl");
+        let err = import_obj_err("mtllib foo.mtl", read_file);
+        assert_eq!(err.kind, MalformedData);
+        assert_eq!(
+            err.description.as_str(),
+            "malformed newmtl-statement at line 1"
+        );
+    }
+
     #[test]
     fn test_mtl_newmtl_multiple_materials() {
         let mtl = r#"
@@ -615,12 +797,12 @@ mod tests {
         let mut writer = create_writer();
 
         let read_file = |p: &Path| {
-            if p == &Path::new("obj-path").join("foo.mtl") {
+            if p == Path::new("obj-path").join("foo.mtl") {
                 Ok(mtl.as_bytes().to_vec())
-            } else if p == &Path::new("obj-path").join("bar.jpg") {
+            } else if p == Path::new("obj-path").join("bar.jpg") {
                 Ok(vec![1, 2, 3])
             } else {
-                Err(Error::new(IoError, format!("bad file path")))
+                Err(Error::new(IoError, "bad file path".to_string()))
             }
         };
 
@@ -685,6 +867,63 @@ mod tests {
         assert!(fm_reader.read_record().unwrap().is_none());
     }
 
+    #[test]
+    fn test_obj_sequence() {
+        let obj =
+            |z| format!("v 0 0 {z}\nv 1 0 {z}\nv 0 1 {z}\nf 1 2 3\n", z = z);
+        let files = [
+            ("seq/a_0001.obj", obj(0)),
+            ("seq/a_0002.obj", obj(1)),
+            ("seq/a_0003.obj", obj(2)),
+            ("seq/b_0000.obj", "v 0 0 0\nv 1 0 0\nf 1 2 2\n".to_string()),
+        ];
+        let read_file = |p: &Path| {
+            let (_, obj) =
+                files.iter().find(|(f, _)| p == Path::new(f)).unwrap();
+            Ok(obj.as_bytes().to_vec())
+        };
+        let exists = |p: &Path| files.iter().any(|(f, _)| p == Path::new(f));
+
+        let paths = sequence_paths("seq/a_%04d.obj", exists).unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[0], Path::new("seq/a_0001.obj"));
+
+        let mut writer = create_writer();
+        let timestep = fm::Time(10);
+        import_obj_sequence(&paths, &mut writer, read_file, "a", timestep)
+            .unwrap();
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let view = record_variant!(ElementView, rec);
+        assert_eq!(view.element, "a");
+        assert_eq!(view.faces.len(), 1);
+        for (i, time) in [0, 10, 20].into_iter().enumerate() {
+            let rec = reader.read_record().unwrap().unwrap();
+            let state = record_variant!(ElementViewState, rec);
+            assert_eq!(state.time, time);
+            assert_eq!(state.vertices[0], new_point3(0.0, 0.0, i as f32));
+        }
+        assert!(reader.read_record().unwrap().is_none());
+
+        let mut paths = paths;
+        paths
+            .push(sequence_paths("seq/b_%04d.obj", exists).unwrap()[0].clone());
+        let mut writer = create_writer();
+        let err =
+            import_obj_sequence(&paths, &mut writer, read_file, "a", timestep)
+                .unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+        assert_eq!(
+            err.description,
+            "topology of 'seq/b_0000.obj' differs from first sequence file"
+        );
+
+        let err = sequence_paths("seq/a_%04.obj", exists).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        let err = sequence_paths("seq/c_%d.obj", exists).unwrap_err();
+        assert_eq!(err.kind, IoError);
+    }
+
     #[test]
     fn test_usemtl_malformed() {
         let obj = r#"
@@ -808,4 +1047,128 @@ mod tests {
             );
         }
     }
+
+    fn element_strategy(
+    ) -> impl Strategy<Value = (fm::ElementView, fm::ElementViewState)> {
+        let sizes = (0..20usize, 0..20usize, 0..20usize);
+        sizes.prop_flat_map(
+            |(num_vertices, num_normals, num_texture_points)| {
+                let coord = || -1e3f32..1e3;
+                let point3 = (coord(), coord(), coord())
+                    .prop_map(|(x, y, z)| new_point3(x, y, z));
+                let point2 =
+                    (0f32..1.0, 0f32..1.0).prop_map(|(x, y)| new_point2(x, y));
+                // Zero indices of texture points and normals stand for absent.
+                let face = (
+                    prop::array::uniform3(1..=num_vertices.max(1) as u32),
+                    prop::array::uniform3(0..=num_texture_points as u32),
+                    prop::array::uniform3(0..=num_normals as u32),
+                )
+                    .prop_map(
+                        |([v1, v2, v3], [t1, t2, t3], [n1, n2, n3])| {
+                            new_ev_face(v1, v2, v3, t1, t2, t3, n1, n2, n3)
+                        },
+                    );
+                let num_faces = if num_vertices == 0 { 0..=0 } else { 0..=30 };
+                (
+                    prop::collection::vec(point3.clone(), num_vertices),
+                    prop::collection::vec(point3, num_normals),
+                    prop::collection::vec(point2, num_texture_points),
+                    prop::collection::vec(face, num_faces),
+                )
+                    .prop_map(
+                        |(vertices, normals, texture_points, faces)| {
+                            let view = fm::ElementView {
+                                element: "buzz".to_string(),
+                                texture_points,
+                                faces,
+                                texture: Some(fm::Image {
+                                    r#type: fm::image::Type::Png as i32,
+                                    data: vec![1, 2, 3],
+                                    ..Default::default()
+                                }),
+                                ..Default::default()
+                            };
+                            let state = fm::ElementViewState {
+                                element: "buzz".to_string(),
+                                vertices,
+                                normals,
+                                ..Default::default()
+                            };
+                            (view, state)
+                        },
+                    )
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_obj_round_trip(
+            (view, state) in element_strategy(),
+            textured in any::<bool>(),
+        ) {
+            let mut reader = create_reader_with_records(&[
+                new_element_view_rec(view.clone()),
+                new_element_view_state_rec(state.clone()),
+            ]);
+            let files = RefCell::new(HashMap::new());
+            let mtl_params = MtlParams {
+                dir: "obj-path".as_ref(),
+                name: "buzz",
+                write_file: |p: &Path, d: &[u8]| {
+                    files.borrow_mut().insert(p.to_owned(), d.to_vec());
+                    Ok(())
+                },
+            };
+            let mut obj = Vec::new();
+            export_to_obj(
+                &mut reader,
+                &mut obj,
+                if textured { Some(mtl_params) } else { None },
+            )
+            .unwrap();
+
+            let mut writer = create_writer();
+            import_obj(
+                &mut obj.as_slice(),
+                &mut writer,
+                |p| Ok(files.borrow()[p].clone()),
+                "obj-path".as_ref(),
+                "buzz",
+            )
+            .unwrap();
+            let mut reader = writer_to_reader(writer);
+            let mut read = || reader.read_record().unwrap().unwrap();
+            let imported_view = record_variant!(ElementView, read());
+            let imported_state = record_variant!(ElementViewState, read());
+
+            prop_assert_eq!(imported_state.vertices, state.vertices);
+            prop_assert_eq!(imported_state.normals, state.normals);
+            if textured {
+                prop_assert_eq!(imported_view.faces, view.faces);
+                let num_points = view.texture_points.len();
+                prop_assert_eq!(imported_view.texture_points.len(), num_points);
+                let points = imported_view.texture_points.iter();
+                for (imported, original) in points.zip(&view.texture_points) {
+                    prop_assert!((imported.x - original.x).abs() <= 1e-6);
+                    prop_assert!((imported.y - original.y).abs() <= 1e-6);
+                }
+                prop_assert_eq!(imported_view.texture, view.texture);
+            } else {
+                let faces: Vec<_> = view
+                    .faces
+                    .iter()
+                    .map(|f| fm::element_view::Face {
+                        texture1: 0,
+                        texture2: 0,
+                        texture3: 0,
+                        ..*f
+                    })
+                    .collect();
+                prop_assert_eq!(imported_view.faces, faces);
+                prop_assert!(imported_view.texture_points.is_empty());
+            }
+        }
+    }
 }
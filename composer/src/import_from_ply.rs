@@ -0,0 +1,674 @@
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use base::define_raw_input;
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+use base::util::fs;
+
+define_raw_input!(PlyInput, "ply");
+
+#[derive(StructOpt)]
+#[structopt(about = "Import data from .ply file")]
+pub struct ImportFromPlyCommand {
+    #[structopt(flatten)]
+    input: PlyInput,
+
+    #[structopt(help = "Element ID for imported data", long, short = "e")]
+    element: Option<String>,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl ImportFromPlyCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        let element = if let Some(id) = &self.element {
+            id.clone()
+        } else if let Some(path) = &self.input.path {
+            path.file_stem()
+                .unwrap_or_default()
+                .to_str()
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            String::default()
+        };
+
+        import_ply(&mut reader, writer.as_mut(), element.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scalar {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Scalar> {
+        use Scalar::*;
+        Some(match name {
+            "char" | "int8" => Int8,
+            "uchar" | "uint8" => Uint8,
+            "short" | "int16" => Int16,
+            "ushort" | "uint16" => Uint16,
+            "int" | "int32" => Int32,
+            "uint" | "uint32" => Uint32,
+            "float" | "float32" => Float32,
+            "double" | "float64" => Float64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        use Scalar::*;
+        match self {
+            Int8 | Uint8 => 1,
+            Int16 | Uint16 => 2,
+            Int32 | Uint32 | Float32 => 4,
+            Float64 => 8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, Scalar::Float32 | Scalar::Float64)
+    }
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    // Type of item count for list properties.
+    count: Option<Scalar>,
+    item: Scalar,
+}
+
+#[derive(Debug)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn malformed_err(desc: String) -> Error {
+    Error::new(MalformedData, desc)
+}
+
+fn read_header(reader: &mut dyn BufRead) -> Result<(Format, Vec<Element>)> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+
+    let mut line = String::new();
+    for i in 1.. {
+        line.clear();
+        let len = reader
+            .read_line(&mut line)
+            .into_result(|| "failed to read PLY header".to_string())?;
+        if len == 0 {
+            return Err(malformed_err("missing PLY end_header".to_string()));
+        }
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if i == 1 {
+            if parts != ["ply"] {
+                return Err(malformed_err("missing PLY signature".to_string()));
+            }
+            continue;
+        }
+
+        let malformed_statement_err = || {
+            let kind = parts.first().unwrap_or(&"empty");
+            let desc = format!("malformed {}-statement at line {}", kind, i);
+            Err(malformed_err(desc))
+        };
+
+        match parts.first().copied() {
+            Some("format") => {
+                if parts.len() != 3 {
+                    return malformed_statement_err();
+                }
+                format = Some(match parts[1] {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    _ => {
+                        let desc =
+                            format!("unsupported PLY format '{}'", parts[1]);
+                        return Err(Error::new(UnsupportedFeature, desc));
+                    }
+                });
+            }
+            Some("element") => {
+                let count = parts.get(2).and_then(|c| c.parse().ok());
+                if parts.len() != 3 || count.is_none() {
+                    return malformed_statement_err();
+                }
+                elements.push(Element {
+                    name: parts[1].to_string(),
+                    count: count.unwrap(),
+                    properties: Vec::new(),
+                });
+            }
+            Some("property") => {
+                let property = match parts[1..] {
+                    [item, name] => Scalar::parse(item).map(|item| Property {
+                        name: name.to_string(),
+                        count: None,
+                        item,
+                    }),
+                    ["list", count, item, name] => Scalar::parse(count)
+                        .filter(|c| !c.is_float())
+                        .zip(Scalar::parse(item))
+                        .map(|(count, item)| Property {
+                            name: name.to_string(),
+                            count: Some(count),
+                            item,
+                        }),
+                    _ => None,
+                };
+                match (property, elements.last_mut()) {
+                    (Some(property), Some(element)) => {
+                        element.properties.push(property)
+                    }
+                    _ => return malformed_statement_err(),
+                }
+            }
+            Some("end_header") => break,
+            Some("comment") | Some("obj_info") | None => {}
+            Some(_) => return malformed_statement_err(),
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements)),
+        None => Err(malformed_err("missing PLY format".to_string())),
+    }
+}
+
+// Reads property values of element instances following the header.
+struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead,
+    format: Format,
+    line: String,
+    pos: usize,
+}
+
+impl<'a> BodyReader<'a> {
+    fn new(reader: &'a mut dyn BufRead, format: Format) -> Self {
+        Self {
+            reader,
+            format,
+            line: String::new(),
+            pos: 0,
+        }
+    }
+
+    fn eof_err() -> Error {
+        malformed_err("unexpected end of PLY data".to_string())
+    }
+
+    fn next_token(&mut self) -> Result<&str> {
+        loop {
+            let rest = &self.line[self.pos..];
+            let start = rest.len() - rest.trim_start().len();
+            let rest = &rest[start..];
+            if !rest.is_empty() {
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let start = self.pos + start;
+                self.pos = start + len;
+                return Ok(&self.line[start..start + len]);
+            }
+
+            self.line.clear();
+            self.pos = 0;
+            let len = self
+                .reader
+                .read_line(&mut self.line)
+                .into_result(|| "failed to read PLY data".to_string())?;
+            if len == 0 {
+                return Err(Self::eof_err());
+            }
+        }
+    }
+
+    fn read_scalar(&mut self, scalar: Scalar) -> Result<f64> {
+        if self.format == Format::Ascii {
+            let token = self.next_token()?;
+            return token.parse().map_err(|_| {
+                malformed_err(format!("malformed PLY value '{}'", token))
+            });
+        }
+
+        let mut buf = [0; 8];
+        let buf = &mut buf[..scalar.size()];
+        self.reader.read_exact(buf).map_err(|_| Self::eof_err())?;
+        use Scalar::*;
+        Ok(match scalar {
+            Int8 => buf[0] as i8 as f64,
+            Uint8 => buf[0] as f64,
+            Int16 => i16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Uint16 => u16::from_le_bytes([buf[0], buf[1]]) as f64,
+            Int32 => i32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            Uint32 => u32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            Float32 => f32::from_le_bytes(buf.try_into().unwrap()) as f64,
+            Float64 => f64::from_le_bytes(buf.try_into().unwrap()),
+        })
+    }
+
+    // Reads an element instance, returning scalar values and list items.
+    fn read_instance(
+        &mut self,
+        element: &Element,
+        scalars: &mut Vec<f64>,
+        lists: &mut Vec<Vec<f64>>,
+    ) -> Result<()> {
+        scalars.clear();
+        lists.clear();
+        for property in &element.properties {
+            match property.count {
+                Some(count) => {
+                    let count = self.read_scalar(count)?;
+                    if count < 0.0 {
+                        let desc = format!(
+                            "negative item count of PLY property '{}'",
+                            property.name
+                        );
+                        return Err(malformed_err(desc));
+                    }
+                    let mut items = Vec::new();
+                    for _ in 0..count as usize {
+                        items.push(self.read_scalar(property.item)?);
+                    }
+                    scalars.push(0.0);
+                    lists.push(items);
+                }
+                None => scalars.push(self.read_scalar(property.item)?),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn find_properties<const N: usize>(
+    element: &Element,
+    names: [&str; N],
+) -> Option<[usize; N]> {
+    let mut indices = [0; N];
+    for (index, name) in indices.iter_mut().zip(names) {
+        *index = element.properties.iter().position(|p| p.name == name)?;
+    }
+    Some(indices)
+}
+
+fn to_color_component(value: f64, scalar: Scalar) -> u32 {
+    let value = if scalar.is_float() {
+        value * 255.0
+    } else {
+        value
+    };
+    value.round().clamp(0.0, 255.0) as u32
+}
+
+pub fn import_ply(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn fm::Write,
+    element: &str,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let (format, elements) = read_header(&mut reader)?;
+    let mut body = BodyReader::new(&mut reader, format);
+
+    let mut view = fm::ElementView {
+        element: element.to_string(),
+        ..Default::default()
+    };
+    let mut state = fm::ElementViewState {
+        element: element.to_string(),
+        ..Default::default()
+    };
+
+    let (mut scalars, mut lists) = (Vec::new(), Vec::new());
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let [x, y, z] = find_properties(element, ["x", "y", "z"])
+                    .ok_or_else(|| {
+                        let desc = "missing PLY vertex coordinates";
+                        malformed_err(desc.to_string())
+                    })?;
+                let normal = find_properties(element, ["nx", "ny", "nz"]);
+                let color = find_properties(element, ["red", "green", "blue"]);
+
+                for _ in 0..element.count {
+                    body.read_instance(element, &mut scalars, &mut lists)?;
+                    let point = |[x, y, z]: [usize; 3]| fm::Point3 {
+                        x: scalars[x] as f32,
+                        y: scalars[y] as f32,
+                        z: scalars[z] as f32,
+                    };
+                    state.vertices.push(point([x, y, z]));
+                    if let Some(normal) = normal {
+                        state.normals.push(point(normal));
+                    }
+                    if let Some(color) = color {
+                        let rgb = color.map(|i| {
+                            let scalar = element.properties[i].item;
+                            to_color_component(scalars[i], scalar)
+                        });
+                        let rgb = rgb[0] << 16 | rgb[1] << 8 | rgb[2];
+                        view.vertex_colors.push(rgb);
+                    }
+                }
+            }
+            "face" => {
+                let index = element.properties.iter().position(|p| {
+                    p.count.is_some()
+                        && (p.name == "vertex_indices"
+                            || p.name == "vertex_index")
+                });
+                // List index among list properties.
+                let index = index.map(|i| {
+                    let properties = &element.properties[..i];
+                    properties.iter().filter(|p| p.count.is_some()).count()
+                });
+
+                for i in 0..element.count {
+                    body.read_instance(element, &mut scalars, &mut lists)?;
+                    if let Some(index) = index {
+                        import_face(&mut view, &state, &lists[index], i)?;
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    body.read_instance(element, &mut scalars, &mut lists)?;
+                }
+            }
+        }
+    }
+
+    use fm::record::Type;
+
+    writer.write_record(&fm::Record {
+        r#type: Some(Type::ElementView(view)),
+    })?;
+
+    writer.write_record(&fm::Record {
+        r#type: Some(Type::ElementViewState(state)),
+    })?;
+
+    Ok(())
+}
+
+fn import_face(
+    view: &mut fm::ElementView,
+    state: &fm::ElementViewState,
+    indices: &[f64],
+    face: usize,
+) -> Result<()> {
+    if indices.len() < 3 {
+        let desc = format!("bad number of vertices in PLY face {}", face);
+        return Err(malformed_err(desc));
+    }
+
+    let mut vertices = Vec::with_capacity(indices.len());
+    for &index in indices {
+        if index < 0.0 || index as usize >= state.vertices.len() {
+            let desc = format!(
+                "reference to unknown vertex {} in PLY face {}",
+                index, face
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+        vertices.push(index as u32 + 1);
+    }
+
+    // Normals share indices with vertices.
+    let normal = |v| if state.normals.is_empty() { 0 } else { v };
+    for i in 1..vertices.len() - 1 {
+        let (v1, v2, v3) = (vertices[0], vertices[i], vertices[i + 1]);
+        view.faces.push(fm::element_view::Face {
+            vertex1: v1,
+            vertex2: v2,
+            vertex3: v3,
+            normal1: normal(v1),
+            normal2: normal(v2),
+            normal3: normal(v3),
+            ..Default::default()
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn import(data: &[u8]) -> Result<(fm::ElementView, fm::ElementViewState)> {
+        let mut writer = create_writer();
+        import_ply(&mut &data[..], &mut writer, "foo")?;
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let view = record_variant!(ElementView, rec);
+        let rec = reader.read_record().unwrap().unwrap();
+        let state = record_variant!(ElementViewState, rec);
+        assert!(reader.read_record().unwrap().is_none());
+        Ok((view, state))
+    }
+
+    fn new_face(v1: u32, v2: u32, v3: u32) -> fm::element_view::Face {
+        fm::element_view::Face {
+            vertex1: v1,
+            vertex2: v2,
+            vertex3: v3,
+            normal1: v1,
+            normal2: v2,
+            normal3: v3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_import_ascii() {
+        let ply = b"ply
+format ascii 1.0
+comment foo
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property uchar flags
+property list uchar int vertex_indices
+element bar 1
+property list uchar float baz
+end_header
+0 0 0 0 0 1 255 0 0
+1 0 0 0 0 1 0 255 0
+1 1 0 0 0 1 0 0 255
+0 1 0.5 0 0 1 1 2 3
+7 4 0 1 2 3
+2 1.5 2.5
+";
+        let (view, state) = import(ply).unwrap();
+        assert_eq!(view.element, "foo");
+        assert_eq!(
+            state.vertices,
+            vec![
+                new_point3(0.0, 0.0, 0.0),
+                new_point3(1.0, 0.0, 0.0),
+                new_point3(1.0, 1.0, 0.0),
+                new_point3(0.0, 1.0, 0.5),
+            ]
+        );
+        assert_eq!(state.normals, vec![new_point3(0.0, 0.0, 1.0); 4]);
+        assert_eq!(
+            view.vertex_colors,
+            vec![0xFF0000, 0x00FF00, 0x0000FF, 0x010203]
+        );
+        assert_eq!(view.faces, vec![new_face(1, 2, 3), new_face(1, 3, 4)]);
+    }
+
+    #[test]
+    fn test_import_binary() {
+        let mut ply = b"ply
+format binary_little_endian 1.0
+element vertex 3
+property double x
+property double y
+property double z
+property float red
+property float green
+property float blue
+element face 1
+property list uchar uint vertex_index
+end_header
+"
+        .to_vec();
+        for (v, c) in [(0.0, 1.0), (1.0, 0.5), (2.0, 0.0)] {
+            for coord in [v, v + 0.5, -v] {
+                ply.extend_from_slice(&f64::to_le_bytes(coord));
+            }
+            for _ in 0..3 {
+                ply.extend_from_slice(&f32::to_le_bytes(c));
+            }
+        }
+        ply.push(3);
+        for index in [2u32, 1, 0] {
+            ply.extend_from_slice(&index.to_le_bytes());
+        }
+
+        let (view, state) = import(&ply).unwrap();
+        assert_eq!(
+            state.vertices,
+            vec![
+                new_point3(0.0, 0.5, 0.0),
+                new_point3(1.0, 1.5, -1.0),
+                new_point3(2.0, 2.5, -2.0),
+            ]
+        );
+        assert!(state.normals.is_empty());
+        assert_eq!(view.vertex_colors, vec![0xFFFFFF, 0x808080, 0x000000]);
+        assert_eq!(
+            view.faces,
+            vec![fm::element_view::Face {
+                vertex1: 3,
+                vertex2: 2,
+                vertex3: 1,
+                ..Default::default()
+            }]
+        );
+
+        // Truncated data.
+        ply.pop();
+        let err = import(&ply).unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+        assert_eq!(err.description, "unexpected end of PLY data");
+    }
+
+    #[test]
+    fn test_import_point_cloud() {
+        let ply = b"ply
+format ascii 1.0
+element vertex 2
+property float x
+property float y
+property float z
+end_header
+1 2 3
+4 5 6
+";
+        let (view, state) = import(ply).unwrap();
+        assert_eq!(
+            state.vertices,
+            vec![new_point3(1.0, 2.0, 3.0), new_point3(4.0, 5.0, 6.0)]
+        );
+        assert!(view.faces.is_empty());
+        assert!(view.vertex_colors.is_empty());
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let header = "ply\nformat ascii 1.0\nelement vertex 1\n\
+                      property float x\nproperty float y\nproperty float z\n";
+        let cases = [
+            ("foo\n".to_string(), MalformedData, "missing PLY signature"),
+            (
+                "ply\nformat binary_big_endian 1.0\nend_header\n".to_string(),
+                UnsupportedFeature,
+                "unsupported PLY format 'binary_big_endian'",
+            ),
+            (
+                "ply\nformat ascii 1.0\nproperty float x\nend_header\n"
+                    .to_string(),
+                MalformedData,
+                "malformed property-statement at line 3",
+            ),
+            (
+                "ply\nformat ascii 1.0\n".to_string(),
+                MalformedData,
+                "missing PLY end_header",
+            ),
+            (
+                format!("{}end_header\n1 2 x\n", header),
+                MalformedData,
+                "malformed PLY value 'x'",
+            ),
+            (
+                format!(
+                    "{}element face 1\nproperty list uchar int \
+                     vertex_indices\nend_header\n1 2 3\n3 0 1 1\n",
+                    header
+                ),
+                InconsistentState,
+                "reference to unknown vertex 1 in PLY face 0",
+            ),
+            (
+                format!(
+                    "{}element face 1\nproperty list uchar int \
+                     vertex_indices\nend_header\n1 2 3\n2 0 0\n",
+                    header
+                ),
+                MalformedData,
+                "bad number of vertices in PLY face 0",
+            ),
+        ];
+
+        for (ply, kind, desc) in cases {
+            let err = import(ply.as_bytes()).unwrap_err();
+            assert_eq!(err.kind, kind);
+            assert_eq!(err.description, desc);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use image::io::Reader as ImageReader;
+use image::{ColorType, GenericImage, RgbImage, Rgba, RgbaImage};
+use log::info;
+use structopt::StructOpt;
+
+use crate::mesh::element_faces;
+use crate::misc::sphere_directions;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{Point3, Vector3};
+use crate::preview::{encode_png, rasterize_triangle};
+use base::defs::{Error, ErrorKind::*, IntoResult, Result};
+use base::fm;
+use base::util::cli;
+
+// Common WebGL texture size limit of low-end devices.
+const MAX_ATLAS_SIZE: u32 = 4096;
+const UNTEXTURED_COLOR: Rgba<u8> = Rgba([200, 200, 200, 255]);
+
+#[derive(StructOpt)]
+#[structopt(
+    about = "Pre-render views of model for devices unable to render meshes"
+)]
+pub struct RenderImpostorsCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(flatten)]
+    params: ImpostorParams,
+}
+
+impl RenderImpostorsCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        render_impostors(reader.as_mut(), writer.as_mut(), &self.params)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct ImpostorParams {
+    #[structopt(
+        help = "Number of views evenly distributed over sphere",
+        long,
+        default_value = "26"
+    )]
+    pub impostor_views: usize,
+
+    #[structopt(
+        help = "Width and height of each view in pixels",
+        long,
+        default_value = "256"
+    )]
+    pub impostor_size: u32,
+
+    #[structopt(
+        help = "Write impostors alone (to be shipped alongside the model)",
+        long
+    )]
+    pub impostors_only: bool,
+}
+
+impl ImpostorParams {
+    fn atlas_layout(&self) -> (u32, u32) {
+        let num = self.impostor_views as u32;
+        let columns = (num as f64).sqrt().ceil() as u32;
+        (columns, (num + columns - 1) / columns.max(1))
+    }
+}
+
+impl CheckParams for ImpostorParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.impostor_views > 0, || {
+            "--impostor-views should be positive".to_string()
+        });
+        check.require(self.impostor_size > 0, || {
+            "--impostor-size should be positive".to_string()
+        });
+        let (columns, rows) = self.atlas_layout();
+        let size = columns.max(rows) as u64 * self.impostor_size as u64;
+        check.require(size <= MAX_ATLAS_SIZE as u64, || {
+            format!(
+                "impostor atlas size {} exceeds {}, reduce --impostor-views \
+                 or --impostor-size",
+                size, MAX_ATLAS_SIZE
+            )
+        });
+    }
+}
+
+pub struct Triangle {
+    pub vertices: [usize; 3],
+    texture_points: Option<[fm::Point2; 3]>,
+}
+
+// Element in its first state.
+pub struct Element {
+    pub vertices: Vec<Point3>,
+    pub triangles: Vec<Triangle>,
+    texture: Option<RgbImage>,
+}
+
+impl Element {
+    pub fn new(
+        view: &fm::ElementView,
+        state: &fm::ElementViewState,
+    ) -> Result<Self> {
+        let vertices: Vec<_> = state
+            .vertices
+            .iter()
+            .map(|v| Point3::new(v.x as f64, v.y as f64, v.z as f64))
+            .collect();
+
+        let texture_point = |index: u32| {
+            view.texture_points.get((index as usize).checked_sub(1)?)
+        };
+        let faces = element_faces(view, vertices.len())?;
+        let triangles = faces
+            .into_iter()
+            .zip(view.faces.iter())
+            .map(|(vertices, face)| {
+                let points = [face.texture1, face.texture2, face.texture3]
+                    .map(texture_point);
+                let texture_points = match points {
+                    [Some(a), Some(b), Some(c)] => Some([*a, *b, *c]),
+                    _ => None,
+                };
+                Triangle {
+                    vertices,
+                    texture_points,
+                }
+            })
+            .collect();
+
+        let texture = match &view.texture {
+            Some(image) => {
+                let err_fn = || {
+                    format!(
+                        "failed to decode texture of element '{}'",
+                        view.element
+                    )
+                };
+                let rgb = ImageReader::new(Cursor::new(&image.data))
+                    .with_guessed_format()
+                    .into_result(err_fn)?
+                    .decode()
+                    .map_err(|e| Error::with_source(ImageError, err_fn(), e))?
+                    .into_rgb8();
+                Some(rgb)
+            }
+            None => None,
+        };
+
+        Ok(Element {
+            vertices,
+            triangles,
+            texture,
+        })
+    }
+
+    pub fn color(&self, triangle: &Triangle, weights: [f64; 3]) -> Rgba<u8> {
+        let (texture, points) = match (&self.texture, &triangle.texture_points)
+        {
+            (Some(texture), Some(points)) => (texture, points),
+            _ => return UNTEXTURED_COLOR,
+        };
+
+        let (mut u, mut v) = (0.0, 0.0);
+        for (point, weight) in points.iter().zip(weights) {
+            u += point.x as f64 * weight;
+            v += point.y as f64 * weight;
+        }
+        let x = (u * texture.width() as f64) as u32;
+        let y = (v * texture.height() as f64) as u32;
+        let pixel = texture
+            .get_pixel(x.min(texture.width() - 1), y.min(texture.height() - 1));
+        Rgba([pixel[0], pixel[1], pixel[2], 255])
+    }
+}
+
+// Bounding sphere of all element vertices.
+fn bounds(elements: &[Element]) -> Option<(Point3, f64)> {
+    let vertices = || elements.iter().flat_map(|e| e.vertices.iter());
+    let mut min = vertices().next()?.coords;
+    let mut max = min;
+    for v in vertices() {
+        min = min.inf(&v.coords);
+        max = max.sup(&v.coords);
+    }
+    let center = Point3::from((min + max) / 2.0);
+    let radius = vertices().map(|v| (v - center).norm()).fold(0.0, f64::max);
+    Some((center, radius.max(f64::EPSILON)))
+}
+
+// Image axes for the view from given direction (matching the viewer camera
+// with Z axis up).
+fn view_axes(direction: &Vector3) -> (Vector3, Vector3) {
+    let up = if direction.z.abs() > 0.999 {
+        Vector3::y()
+    } else {
+        Vector3::z()
+    };
+    let right = up.cross(direction).normalize();
+    (right, direction.cross(&right))
+}
+
+// Renders elements orthographically from the direction, so that
+// the bounding sphere fits into the image with transparent background.
+fn render_view(
+    elements: &[Element],
+    (center, radius): (Point3, f64),
+    direction: &Vector3,
+    size: u32,
+) -> RgbaImage {
+    let mut image = RgbaImage::new(size, size);
+    let mut depths = vec![f64::INFINITY; (size * size) as usize];
+    let (right, up) = view_axes(direction);
+    let scale = size as f64 / 2.0 / radius;
+
+    for element in elements {
+        let project = |i: usize| {
+            let p = element.vertices[i] - center;
+            (
+                (p.dot(&right) + radius) * scale,
+                (radius - p.dot(&up)) * scale,
+                -p.dot(direction),
+            )
+        };
+
+        for triangle in &element.triangles {
+            let [a, b, c] = triangle.vertices.map(project);
+            rasterize_triangle([a, b, c], size, &mut depths, |x, y, w| {
+                image.put_pixel(x, y, element.color(triangle, w));
+            });
+        }
+    }
+
+    image
+}
+
+pub fn create_impostors(
+    views: &[fm::ElementView],
+    states: &HashMap<String, fm::ElementViewState>,
+    params: &ImpostorParams,
+) -> Result<fm::Impostors> {
+    params.check()?;
+
+    let mut elements = Vec::with_capacity(views.len());
+    for view in views {
+        if let Some(state) = states.get(&view.element) {
+            elements.push(Element::new(view, state)?);
+        }
+    }
+    let bounds = bounds(&elements).ok_or_else(|| {
+        let desc = "no element states to render impostors".to_string();
+        Error::new(InconsistentState, desc)
+    })?;
+
+    let size = params.impostor_size;
+    let (columns, rows) = params.atlas_layout();
+    let mut atlas = RgbaImage::new(columns * size, rows * size);
+    let directions = sphere_directions(params.impostor_views);
+    for (i, direction) in directions.iter().enumerate() {
+        let view = render_view(&elements, bounds, direction, size);
+        let (x, y) = (i as u32 % columns * size, i as u32 / columns * size);
+        atlas.copy_from(&view, x, y).map_err(|e| {
+            let desc = "failed to compose impostor atlas".to_string();
+            Error::with_source(ImageError, desc, e)
+        })?;
+    }
+
+    let to_fm_point = |p: &Vector3| fm::Point3 {
+        x: p.x as f32,
+        y: p.y as f32,
+        z: p.z as f32,
+    };
+    let (width, height) = atlas.dimensions();
+    Ok(fm::Impostors {
+        center: Some(to_fm_point(&bounds.0.coords)),
+        radius: bounds.1 as f32,
+        atlas: Some(encode_png(
+            atlas.as_ref(),
+            width,
+            height,
+            ColorType::Rgba8,
+        )?),
+        columns,
+        rows,
+        directions: directions.iter().map(to_fm_point).collect(),
+    })
+}
+
+pub fn render_impostors(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    params: &ImpostorParams,
+) -> Result<()> {
+    params.check()?;
+
+    let mut records = Vec::new();
+    let mut views = Vec::new();
+    let mut states = HashMap::new();
+
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementView(v)) => views.push(v.clone()),
+            Some(ElementViewState(s)) => {
+                let first =
+                    states.entry(s.element.clone()).or_insert(s.clone());
+                if s.time < first.time {
+                    *first = s.clone();
+                }
+            }
+            Some(Impostors(_)) => continue, // Replaced by the new ones.
+            _ => (),
+        }
+        if !params.impostors_only {
+            records.push(rec);
+        }
+    }
+
+    info!(
+        "rendering {} impostor views of {} elements...",
+        params.impostor_views,
+        views.len()
+    );
+    let impostors = create_impostors(&views, &states, params)?;
+
+    for rec in records {
+        writer.write_record(&rec)?;
+    }
+    writer.write_record(&fm::Record {
+        r#type: Some(fm::record::Type::Impostors(impostors)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn new_square() -> (fm::ElementView, fm::ElementViewState) {
+        let mut texture = RgbImage::new(2, 1);
+        texture.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+        texture.put_pixel(1, 0, image::Rgb([0, 0, 255]));
+        let texture = encode_png(texture.as_ref(), 2, 1, ColorType::Rgb8);
+
+        let view = fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(texture.unwrap()),
+            texture_points: vec![
+                new_point2(0.0, 0.5),
+                new_point2(1.0, 0.5),
+                new_point2(1.0, 0.5),
+                new_point2(0.0, 0.5),
+            ],
+            faces: vec![
+                new_ev_face(1, 2, 3, 1, 2, 3, 0, 0, 0),
+                new_ev_face(1, 3, 4, 1, 3, 4, 0, 0, 0),
+            ],
+            ..Default::default()
+        };
+        let state = fm::ElementViewState {
+            element: "a".to_string(),
+            time: 5,
+            vertices: vec![
+                new_point3(-1.0, 0.0, -1.0),
+                new_point3(1.0, 0.0, -1.0),
+                new_point3(1.0, 0.0, 1.0),
+                new_point3(-1.0, 0.0, 1.0),
+            ],
+            ..Default::default()
+        };
+        (view, state)
+    }
+
+    #[test]
+    fn test_render_view() {
+        let (view, state) = new_square();
+        let elements = vec![Element::new(&view, &state).unwrap()];
+        let bounds = bounds(&elements).unwrap();
+        assert_eq!(bounds.0, Point3::origin());
+        assert!((bounds.1 - 2f64.sqrt()).abs() < 1e-6);
+
+        // From the front the red (left) texel is on the left.
+        let front = Vector3::new(0.0, -1.0, 0.0);
+        let image = render_view(&elements, bounds, &front, 20);
+        assert_eq!(*image.get_pixel(4, 10), Rgba([255, 0, 0, 255]));
+        assert_eq!(*image.get_pixel(15, 10), Rgba([0, 0, 255, 255]));
+        assert_eq!(*image.get_pixel(1, 1), Rgba([0, 0, 0, 0]));
+
+        let back = Vector3::new(0.0, 1.0, 0.0);
+        let image = render_view(&elements, bounds, &back, 20);
+        assert_eq!(*image.get_pixel(4, 10), Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn test_render_impostors() {
+        let (view, state) = new_square();
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(view),
+            new_element_view_state_rec(state),
+        ]);
+
+        let mut writer = create_writer();
+        let params = ImpostorParams {
+            impostor_views: 5,
+            impostor_size: 8,
+            impostors_only: true,
+        };
+        render_impostors(&mut reader, &mut writer, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let impostors = record_variant!(Impostors, rec);
+        assert_eq!((impostors.columns, impostors.rows), (3, 2));
+        assert_eq!(impostors.directions.len(), 5);
+        let atlas = image::load_from_memory(&impostors.atlas.unwrap().data);
+        let atlas = atlas.unwrap();
+        assert_eq!((atlas.width(), atlas.height()), (24, 16));
+        assert!(reader.read_record().unwrap().is_none());
+
+        let params = ImpostorParams {
+            impostor_size: 2048,
+            ..params
+        };
+        let err = params.check().unwrap_err();
+        assert_eq!(
+            err.description,
+            "impostor atlas size 6144 exceeds 4096, reduce --impostor-views \
+             or --impostor-size"
+        );
+    }
+}
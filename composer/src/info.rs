@@ -0,0 +1,243 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use image::io::Reader as ImageReader;
+use indexmap::IndexMap;
+use structopt::StructOpt;
+
+use crate::dry_run::format_size;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+use base::util::fs;
+
+#[derive(StructOpt)]
+#[structopt(about = "Print information about .fm file")]
+pub struct InfoCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(help = "Output .png file for embedded preview", long)]
+    thumbnail: Option<PathBuf>,
+}
+
+impl InfoCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+
+        println!("version: {}", reader.version());
+        println!("features: {:#X}", reader.features());
+        println!("compression: {}", reader.compression());
+
+        let summary = summarize(reader.as_mut())?;
+        println!(
+            "decompressed size: {} ({} records)",
+            format_size(summary.records_size),
+            summary.num_records
+        );
+        println!("scans: {}", summary.num_scans);
+        println!("frames: {}", summary.num_frames);
+        println!("elements: {}", summary.elements.len());
+        for (name, element) in &summary.elements {
+            let mut texture = match element.texture_size {
+                Some((width, height)) => format!("{}x{}", width, height),
+                None => "none".to_string(),
+            };
+            if element.num_textures > 0 {
+                texture += &format!(" ({} animated)", element.num_textures);
+            }
+            println!(
+                "  {}: {} view states, {} vertices, {} faces, texture {}",
+                name,
+                element.num_states,
+                element.num_vertices,
+                element.num_faces,
+                texture
+            );
+        }
+        if let Some((from, to)) = summary.time_range {
+            println!("time range: {} - {}", from, to);
+        }
+
+        if let Some(path) = &self.thumbnail {
+            let image = summary.preview.ok_or_else(|| {
+                let desc = "no preview found".to_string();
+                Error::new(InconsistentState, desc)
+            })?;
+            fs::write_output(path, &image.data)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ElementSummary {
+    pub num_states: usize,
+    // Maximum number of vertices among view states.
+    pub num_vertices: usize,
+    pub num_faces: usize,
+    pub texture_size: Option<(u32, u32)>,
+    // Number of timestamped textures replacing the view one.
+    pub num_textures: usize,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub preview: Option<fm::Image>,
+    pub time_range: Option<(fm::Time, fm::Time)>,
+    pub num_records: usize,
+    // Size of records after decompression (with deduplicated and resolved
+    // images counted in every record referencing them).
+    pub records_size: u64,
+    pub num_scans: usize,
+    pub num_frames: usize,
+    pub elements: IndexMap<String, ElementSummary>,
+}
+
+// Reads dimensions from image header, which fails for unresolved images.
+fn image_size(image: &fm::Image) -> Option<(u32, u32)> {
+    let format = match image.r#type() {
+        fm::image::Type::Png => image::ImageFormat::Png,
+        fm::image::Type::Jpeg => image::ImageFormat::Jpeg,
+        fm::image::Type::None => return None,
+    };
+    ImageReader::with_format(Cursor::new(&image.data), format)
+        .into_dimensions()
+        .ok()
+}
+
+pub fn summarize(reader: &mut dyn fm::Read) -> Result<Summary> {
+    let mut summary = Summary::default();
+
+    let mut first = true;
+    while let Some(raw) = reader.read_raw_record()? {
+        summary.num_records += 1;
+        summary.records_size += raw.as_bytes().len() as u64;
+        let rec = raw.decode()?;
+
+        use fm::record::Type::*;
+        let time = match rec.r#type {
+            // Preview is written first, so it's looked up only there.
+            Some(Preview(p)) if first => {
+                summary.preview = p.image;
+                None
+            }
+            Some(Scan(_)) => {
+                summary.num_scans += 1;
+                None
+            }
+            Some(ElementView(v)) => {
+                let element = summary.elements.entry(v.element).or_default();
+                element.num_faces = v.faces.len();
+                element.texture_size = v.texture.as_ref().and_then(image_size);
+                None
+            }
+            Some(ElementViewState(s)) => {
+                let element = summary.elements.entry(s.element).or_default();
+                element.num_states += 1;
+                element.num_vertices =
+                    element.num_vertices.max(s.vertices.len());
+                Some(s.time)
+            }
+            Some(ElementTexture(t)) => {
+                let element = summary.elements.entry(t.element).or_default();
+                element.num_textures += 1;
+                Some(t.time)
+            }
+            Some(ScanFrame(f)) => {
+                summary.num_frames += 1;
+                Some(f.time)
+            }
+            _ => None,
+        };
+        first = false;
+
+        if let Some(time) = time.map(fm::Time) {
+            let (from, to) = summary.time_range.get_or_insert((time, time));
+            *from = time.min(*from);
+            *to = time.max(*to);
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+
+    #[test]
+    fn test_summarize() {
+        let mut reader = create_reader_with_records(&[
+            new_element_view_state_rec(fm::ElementViewState {
+                time: 1_500_000_000,
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                time: 80_500_000_000,
+                ..Default::default()
+            }),
+        ]);
+
+        let summary = summarize(&mut reader).unwrap();
+        assert!(summary.preview.is_none());
+        assert_eq!(summary.num_records, 2);
+        assert_eq!(summary.elements[""].num_states, 2);
+        let (from, to) = summary.time_range.unwrap();
+        assert_eq!(from.to_string(), "00:00:01.5");
+        assert_eq!(to.to_string(), "00:01:20.5");
+    }
+
+    #[test]
+    fn test_summarize_elements() {
+        let mut texture = Vec::new();
+        image::RgbImage::new(8, 4)
+            .write_to(&mut Cursor::new(&mut texture), image::ImageFormat::Png)
+            .unwrap();
+
+        let mut reader = create_reader_with_records(&[
+            new_scan_rec(fm::Scan {
+                name: "foo".to_string(),
+                ..Default::default()
+            }),
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "foo".to_string(),
+                ..Default::default()
+            }),
+            new_element_view_rec(fm::ElementView {
+                element: "bar".to_string(),
+                texture: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: texture,
+                    ..Default::default()
+                }),
+                faces: vec![Default::default(); 3],
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "bar".to_string(),
+                vertices: vec![new_point3(0.0, 0.0, 0.0); 5],
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "bar".to_string(),
+                time: 1,
+                ..Default::default()
+            }),
+        ]);
+
+        let summary = summarize(&mut reader).unwrap();
+        assert_eq!(summary.num_records, 5);
+        assert!(summary.records_size > 0);
+        assert_eq!(summary.num_scans, 1);
+        assert_eq!(summary.num_frames, 1);
+        assert_eq!(summary.elements.len(), 1);
+        let element = &summary.elements["bar"];
+        assert_eq!(element.num_states, 2);
+        assert_eq!(element.num_vertices, 5);
+        assert_eq!(element.num_faces, 3);
+        assert_eq!(element.texture_size, Some((8, 4)));
+    }
+}
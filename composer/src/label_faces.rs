@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+use image::GrayImage;
+use log::info;
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::Vector3;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::{cli, fs};
+
+#[derive(StructOpt)]
+#[structopt(about = "Label faces of .fm file elements by mask or planes")]
+pub struct LabelFacesCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Element to label (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(flatten)]
+    params: LabelFacesParams,
+}
+
+impl LabelFacesCommand {
+    pub fn run(&self) -> Result<()> {
+        self.params.check()?;
+        let mask = match &self.params.mask {
+            Some(path) => Some(read_mask(path)?),
+            None => None,
+        };
+
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        label_faces(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.elements,
+            mask.as_ref(),
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct LabelFacesParams {
+    #[structopt(
+        help = "Label to assign (faces unlabeled before get 0 otherwise)",
+        long,
+        short = "l"
+    )]
+    pub label: u16,
+
+    #[structopt(
+        help = "Texture-space mask selecting faces by their texture centers",
+        long
+    )]
+    pub mask: Option<PathBuf>,
+
+    #[structopt(help = "Select faces of planar regions", long)]
+    pub planes: bool,
+
+    #[structopt(
+        help = "Maximum angle between face and region normals (in degrees)",
+        long,
+        default_value = "5"
+    )]
+    pub plane_max_angle: f64,
+
+    #[structopt(
+        help = "Maximum distance from face vertices to region plane",
+        long,
+        default_value = "0.01"
+    )]
+    pub plane_max_distance: f64,
+
+    #[structopt(
+        help = "Minimum number of faces per planar region",
+        long,
+        default_value = "100"
+    )]
+    pub plane_min_faces: usize,
+}
+
+impl CheckParams for LabelFacesParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.mask.is_some() != self.planes, || {
+            "either --mask or --planes should be given".to_string()
+        });
+        check.require(
+            self.plane_max_angle >= 0.0 && self.plane_max_angle <= 90.0,
+            || "--plane-max-angle should be within [0, 90]".to_string(),
+        );
+        check.require(self.plane_max_distance > 0.0, || {
+            "--plane-max-distance should be positive".to_string()
+        });
+        check.require(self.plane_min_faces > 0, || {
+            "--plane-min-faces should be positive".to_string()
+        });
+    }
+}
+
+fn read_mask(path: &Path) -> Result<GrayImage> {
+    let data = fs::read_file(path)?;
+    let image = image::load_from_memory(&data).map_err(|e| {
+        let desc = format!("failed to decode mask '{}'", path.display());
+        Error::with_source(ImageError, desc, e)
+    })?;
+    Ok(image.into_luma8())
+}
+
+// Faces with texture centers on bright mask pixels.
+fn select_by_mask(view: &fm::ElementView, mask: &GrayImage) -> Vec<bool> {
+    let (width, height) = mask.dimensions();
+    let pixel = |c: f32, size: u32| {
+        ((c * size as f32) as i64).clamp(0, size as i64 - 1) as u32
+    };
+
+    view.faces
+        .iter()
+        .map(|f| {
+            let corners = [f.texture1, f.texture2, f.texture3];
+            if corners
+                .iter()
+                .any(|&t| t == 0 || t as usize > view.texture_points.len())
+            {
+                return false;
+            }
+            let (mut u, mut v) = (0.0, 0.0);
+            for t in corners {
+                let p = &view.texture_points[t as usize - 1];
+                u += p.x / 3.0;
+                v += p.y / 3.0;
+            }
+            mask.get_pixel(pixel(u, width), pixel(v, height))[0] >= 128
+        })
+        .collect()
+}
+
+// Grows regions of faces through their edges while they stay close to the
+// plane of the region's first face.
+fn select_planes(
+    view: &fm::ElementView,
+    state: &fm::ElementViewState,
+    params: &LabelFacesParams,
+) -> Result<Vec<bool>> {
+    let vertex = |index: u32| {
+        let p = state.vertices.get(index.wrapping_sub(1) as usize)?;
+        Some(Vector3::new(p.x as f64, p.y as f64, p.z as f64))
+    };
+    let faces = view
+        .faces
+        .iter()
+        .map(|f| {
+            let indices = [f.vertex1, f.vertex2, f.vertex3];
+            match (vertex(indices[0]), vertex(indices[1]), vertex(indices[2])) {
+                (Some(a), Some(b), Some(c)) => Ok((indices, [a, b, c])),
+                _ => {
+                    let desc = format!(
+                        "face vertex index out of bounds for element '{}'",
+                        view.element
+                    );
+                    Err(Error::new(MalformedData, desc))
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let normals: Vec<_> = faces
+        .iter()
+        .map(|(_, [a, b, c])| (b - a).cross(&(c - a)).try_normalize(0.0))
+        .collect();
+
+    let mut edge_faces = HashMap::<[u32; 2], Vec<usize>>::new();
+    for (i, ([v0, v1, v2], _)) in faces.iter().enumerate() {
+        for [a, b] in [[v0, v1], [v1, v2], [v2, v0]] {
+            edge_faces
+                .entry([*a.min(b), *a.max(b)])
+                .or_default()
+                .push(i);
+        }
+    }
+
+    let min_cos = params.plane_max_angle.to_radians().cos();
+    let mut visited = vec![false; faces.len()];
+    let mut selected = vec![false; faces.len()];
+    for seed in 0..faces.len() {
+        let normal = match normals[seed] {
+            Some(normal) if !visited[seed] => normal,
+            _ => continue,
+        };
+        let origin = faces[seed].1[0];
+        let fits = |i: usize| {
+            normals[i].is_some_and(|n| n.dot(&normal) >= min_cos)
+                && faces[i].1.iter().all(|v| {
+                    (v - origin).dot(&normal).abs() <= params.plane_max_distance
+                })
+        };
+
+        let mut region = vec![seed];
+        let mut queue = VecDeque::from([seed]);
+        let mut seen = HashSet::from([seed]);
+        visited[seed] = true;
+        while let Some(i) = queue.pop_front() {
+            let [v0, v1, v2] = faces[i].0;
+            for [a, b] in [[v0, v1], [v1, v2], [v2, v0]] {
+                for &j in &edge_faces[&[a.min(b), a.max(b)]] {
+                    if !visited[j] && seen.insert(j) && fits(j) {
+                        visited[j] = true;
+                        region.push(j);
+                        queue.push_back(j);
+                    }
+                }
+            }
+        }
+
+        if region.len() >= params.plane_min_faces {
+            for i in region {
+                selected[i] = true;
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+fn label_view(
+    view: &mut fm::ElementView,
+    state: Option<&fm::ElementViewState>,
+    mask: Option<&GrayImage>,
+    params: &LabelFacesParams,
+) -> Result<()> {
+    let selected = match (mask, state) {
+        (Some(mask), _) => select_by_mask(view, mask),
+        (None, Some(state)) => select_planes(view, state, params)?,
+        (None, None) => {
+            let desc = format!("no state for element '{}'", view.element);
+            return Err(Error::new(InconsistentState, desc));
+        }
+    };
+
+    let mut labels =
+        fm::face_labels(view)?.unwrap_or_else(|| vec![0; view.faces.len()]);
+    for (label, _) in labels.iter_mut().zip(&selected).filter(|(_, &s)| s) {
+        *label = params.label;
+    }
+    view.face_labels = labels.into_iter().map(|l| l as u32).collect();
+
+    info!(
+        "labeled {} of {} faces of element '{}'",
+        selected.iter().filter(|&&s| s).count(),
+        selected.len(),
+        view.element
+    );
+    Ok(())
+}
+
+// Planes are found in the first state of each element.
+pub fn label_faces(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    elements: &[String],
+    mask: Option<&GrayImage>,
+    params: &LabelFacesParams,
+) -> Result<()> {
+    params.check()?;
+    let selected = |element: &str| {
+        elements.is_empty() || elements.iter().any(|e| e == element)
+    };
+
+    let mut records = Vec::new();
+    let mut views = HashMap::new();
+    let mut states = HashMap::new();
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementView(v)) if selected(&v.element) => {
+                views.insert(v.element.clone(), records.len());
+            }
+            Some(ElementViewState(s)) if !states.contains_key(&s.element) => {
+                states.insert(s.element.clone(), records.len());
+            }
+            _ => {}
+        }
+        records.push(rec);
+    }
+
+    for (element, &index) in &views {
+        let state = states.get(element).map(|&i| match &records[i].r#type {
+            Some(fm::record::Type::ElementViewState(s)) => s.clone(),
+            _ => unreachable!(),
+        });
+        if let Some(fm::record::Type::ElementView(view)) =
+            &mut records[index].r#type
+        {
+            label_view(view, state.as_ref(), mask, params)?;
+        }
+    }
+
+    for rec in records {
+        writer.write_record(&rec)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    const N: u32 = 5;
+
+    // Grid of NxN vertices, flat in its left half and bumpy in the right
+    // one, with texture points matching vertex positions.
+    fn new_grid_recs(face_labels: Vec<u32>) -> [fm::Record; 2] {
+        let mut view = fm::ElementView {
+            element: "a".to_string(),
+            face_labels,
+            ..Default::default()
+        };
+        let mut state = fm::ElementViewState {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+
+        for y in 0..N {
+            for x in 0..N {
+                let z = if x > N / 2 {
+                    (1 + (x * 7 + y * 13) % 4) as f32 * 0.1
+                } else {
+                    0.0
+                };
+                state.vertices.push(new_point3(x as f32, y as f32, z));
+                let size = (N - 1) as f32;
+                view.texture_points
+                    .push(new_point2(x as f32 / size, y as f32 / size));
+            }
+        }
+
+        for y in 0..N - 1 {
+            for x in 0..N - 1 {
+                let v = |x: u32, y: u32| y * N + x + 1;
+                let (a, b, c, d) =
+                    (v(x, y), v(x + 1, y), v(x + 1, y + 1), v(x, y + 1));
+                for [p, q, r] in [[a, b, c], [a, c, d]] {
+                    view.faces.push(new_ev_face(p, q, r, p, q, r, 0, 0, 0));
+                }
+            }
+        }
+
+        [
+            new_element_view_rec(view),
+            new_element_view_state_rec(state),
+        ]
+    }
+
+    fn new_params() -> LabelFacesParams {
+        LabelFacesParams {
+            label: 7,
+            mask: None,
+            planes: false,
+            plane_max_angle: 5.0,
+            plane_max_distance: 0.01,
+            plane_min_faces: 8,
+        }
+    }
+
+    fn label(
+        face_labels: Vec<u32>,
+        mask: Option<&GrayImage>,
+        params: &LabelFacesParams,
+    ) -> Vec<u32> {
+        let mut reader =
+            create_reader_with_records(&new_grid_recs(face_labels));
+        let mut writer = create_writer();
+        label_faces(&mut reader, &mut writer, &[], mask, params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let view = record_variant!(
+            ElementView,
+            reader.read_record().unwrap().unwrap()
+        );
+        record_variant!(
+            ElementViewState,
+            reader.read_record().unwrap().unwrap()
+        );
+        assert!(reader.read_record().unwrap().is_none());
+        view.face_labels
+    }
+
+    // Faces of quads to the left of given column.
+    fn left_of(column: u32) -> Vec<bool> {
+        (0..N - 1)
+            .flat_map(|_| (0..N - 1).flat_map(|x| [x < column; 2]))
+            .collect()
+    }
+
+    #[test]
+    fn test_label_faces_by_planes() {
+        let params = LabelFacesParams {
+            planes: true,
+            ..new_params()
+        };
+        let labels = label(vec![], None, &params);
+        let expected: Vec<_> = left_of(N / 2)
+            .into_iter()
+            .map(|l| if l { 7 } else { 0 })
+            .collect();
+        assert_eq!(labels, expected);
+
+        // Regions smaller than required stay unlabeled.
+        let params = LabelFacesParams {
+            plane_min_faces: 17,
+            ..params
+        };
+        assert!(label(vec![], None, &params).iter().all(|&l| l == 0));
+    }
+
+    #[test]
+    fn test_label_faces_by_mask() {
+        let mut mask = GrayImage::new(4, 4);
+        for y in 0..4 {
+            mask.put_pixel(0, y, Luma([255]));
+        }
+        let params = LabelFacesParams {
+            mask: Some("mask.png".into()),
+            ..new_params()
+        };
+
+        let num_faces = ((N - 1) * (N - 1) * 2) as usize;
+        let labels = label(vec![3; num_faces], Some(&mask), &params);
+        let expected: Vec<_> = left_of(1)
+            .into_iter()
+            .map(|l| if l { 7 } else { 3 })
+            .collect();
+        assert_eq!(labels, expected);
+    }
+
+    #[test]
+    fn test_label_faces_params() {
+        assert!(new_params().check().is_err());
+        let params = LabelFacesParams {
+            mask: Some("mask.png".into()),
+            planes: true,
+            ..new_params()
+        };
+        assert!(params.check().is_err());
+        let params = LabelFacesParams {
+            planes: true,
+            plane_max_distance: 0.0,
+            ..new_params()
+        };
+        assert!(params.check().is_err());
+    }
+}
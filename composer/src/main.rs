@@ -1,17 +1,55 @@
+mod apply_encoder;
+mod bake_preview_shading;
 mod build_view;
+mod bvh;
+mod calibrate_rig;
+mod collision_mesh;
 mod combine;
+mod compact;
+mod compare_frames;
+mod crop_frames;
+mod decimate;
+mod dedup;
+mod dry_run;
+mod dual_contouring;
+mod export_point_cloud;
+mod export_to_gltf;
 mod export_to_json;
 mod export_to_obj;
+mod export_to_ply;
+mod externalize_images;
 mod extract_scan_images;
+mod import_from_json;
 mod import_from_obj;
+mod import_from_ply;
+mod impostors;
+mod info;
+mod label_faces;
+mod measure;
 mod mesh;
+mod migrate;
 mod misc;
 mod optimize_scan_geometry;
+mod overlap;
+mod param_check;
+mod patch;
 mod point_cloud;
 mod poisson;
+mod preview;
+mod redact;
+mod repair_regions;
+mod resample;
+mod retarget;
 mod scan;
+mod segment_cloud;
 mod select;
+mod set_loop;
+mod telemetry;
 mod texture;
+mod threads;
+mod tsdf;
+mod validate;
+mod validate_mesh;
 
 use log::error;
 use simplelog::{
@@ -19,25 +57,68 @@ use simplelog::{
 };
 use structopt::StructOpt;
 
+use base::util::fs;
+
 #[derive(StructOpt)]
 #[structopt(about = "Fitsme model composer")]
 struct Opts {
+    #[structopt(flatten)]
+    telemetry: telemetry::TelemetryParams,
+
+    #[structopt(
+        help = "Write output files as <path>.<pid>.part until done \
+                (instead of hidden temporary files)",
+        long
+    )]
+    part_outputs: bool,
+
     #[structopt(subcommand)]
     command: Command,
 }
 
 #[derive(StructOpt)]
 enum Command {
+    ApplyEncoder(Box<apply_encoder::ApplyEncoderCommand>),
+    ApplyPatch(Box<patch::ApplyPatchCommand>),
+    BakePreviewShading(Box<bake_preview_shading::BakePreviewShadingCommand>),
     BuildView(Box<build_view::BuildViewCommand>),
+    CalibrateRig(Box<calibrate_rig::CalibrateRigCommand>),
+    CollisionMesh(Box<collision_mesh::CollisionMeshCommand>),
     Combine(Box<combine::CombineCommand>),
+    Compact(Box<compact::CompactCommand>),
+    CompareFrames(Box<compare_frames::CompareFramesCommand>),
+    CropFrames(Box<crop_frames::CropFramesCommand>),
+    Decimate(Box<decimate::DecimateCommand>),
+    Dedup(Box<dedup::DedupCommand>),
+    ExportPointCloud(Box<export_point_cloud::ExportPointCloudCommand>),
+    ExportToGltf(Box<export_to_gltf::ExportToGltfCommand>),
     ExportToJson(Box<export_to_json::ExportToJsonCommand>),
     ExportToObj(Box<export_to_obj::ExportToObjCommand>),
+    ExportToPly(Box<export_to_ply::ExportToPlyCommand>),
+    ExternalizeImages(Box<externalize_images::ExternalizeImagesCommand>),
     ExtractScanImages(Box<extract_scan_images::ExtractScanImagesCommand>),
+    ImportFromJson(Box<import_from_json::ImportFromJsonCommand>),
     ImportFromObj(Box<import_from_obj::ImportFromObjCommand>),
+    ImportFromPly(Box<import_from_ply::ImportFromPlyCommand>),
+    Info(Box<info::InfoCommand>),
+    LabelFaces(Box<label_faces::LabelFacesCommand>),
+    MakePatch(Box<patch::MakePatchCommand>),
+    Measure(Box<measure::MeasureCommand>),
+    Migrate(Box<migrate::MigrateCommand>),
     OptimizeScanGeometry(
         Box<optimize_scan_geometry::OptimizeScanGeometryCommand>,
     ),
+    Overlap(Box<overlap::OverlapCommand>),
+    Redact(Box<redact::RedactCommand>),
+    RenderImpostors(Box<impostors::RenderImpostorsCommand>),
+    RepairRegions(Box<repair_regions::RepairRegionsCommand>),
+    Resample(Box<resample::ResampleCommand>),
+    Retarget(Box<retarget::RetargetCommand>),
+    SegmentCloud(Box<segment_cloud::SegmentCloudCommand>),
     Select(Box<select::SelectCommand>),
+    SetLoop(Box<set_loop::SetLoopCommand>),
+    Validate(Box<validate::ValidateCommand>),
+    ValidateMesh(Box<validate_mesh::ValidateMeshCommand>),
 }
 
 fn main() {
@@ -49,20 +130,78 @@ fn main() {
     )
     .unwrap();
 
-    let opts: Opts = Opts::from_args();
+    let matches = Opts::clap().get_matches();
+    let opts = Opts::from_clap(&matches);
+    let command = matches.subcommand_name().unwrap_or_default();
+    if let Err(err) = telemetry::init(&opts.telemetry, command) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    fs::set_temp_outputs(if opts.part_outputs {
+        fs::TempOutputs::Part
+    } else {
+        fs::TempOutputs::Hidden
+    });
 
     use Command::*;
+    let profile = match &opts.command {
+        BuildView(cmd) => cmd.profile(),
+        _ => None,
+    };
+    let guard = telemetry::init_tracing(profile);
+
     let res = match opts.command {
+        ApplyEncoder(cmd) => cmd.run(),
+        ApplyPatch(cmd) => cmd.run(),
+        BakePreviewShading(cmd) => cmd.run(),
         BuildView(cmd) => cmd.run(),
+        CalibrateRig(cmd) => cmd.run(),
+        CollisionMesh(cmd) => cmd.run(),
         Combine(cmd) => cmd.run(),
+        Compact(cmd) => cmd.run(),
+        CompareFrames(cmd) => cmd.run(),
+        CropFrames(cmd) => cmd.run(),
+        Decimate(cmd) => cmd.run(),
+        Dedup(cmd) => cmd.run(),
+        ExportPointCloud(cmd) => cmd.run(),
+        ExportToGltf(cmd) => cmd.run(),
         ExportToJson(cmd) => cmd.run(),
         ExportToObj(cmd) => cmd.run(),
+        ExportToPly(cmd) => cmd.run(),
+        ExternalizeImages(cmd) => cmd.run(),
         ExtractScanImages(cmd) => cmd.run(),
+        ImportFromJson(cmd) => cmd.run(),
         ImportFromObj(cmd) => cmd.run(),
+        ImportFromPly(cmd) => cmd.run(),
+        Info(cmd) => cmd.run(),
+        LabelFaces(cmd) => cmd.run(),
+        MakePatch(cmd) => cmd.run(),
+        Measure(cmd) => cmd.run(),
+        Migrate(cmd) => cmd.run(),
         OptimizeScanGeometry(cmd) => cmd.run(),
+        Overlap(cmd) => cmd.run(),
+        Redact(cmd) => cmd.run(),
+        RenderImpostors(cmd) => cmd.run(),
+        RepairRegions(cmd) => cmd.run(),
+        Resample(cmd) => cmd.run(),
+        Retarget(cmd) => cmd.run(),
+        SegmentCloud(cmd) => cmd.run(),
         Select(cmd) => cmd.run(),
+        SetLoop(cmd) => cmd.run(),
+        Validate(cmd) => cmd.run(),
+        ValidateMesh(cmd) => cmd.run(),
     };
 
+    // Outputs are in place only if the command succeeds.
+    let res = res.and_then(|_| fs::commit_outputs());
+    if res.is_err() {
+        fs::discard_outputs();
+    }
+
+    drop(guard);
+    telemetry::finish(&res);
+
     if let Err(err) = res {
         error!("{}", err);
         std::process::exit(1);
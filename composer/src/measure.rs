@@ -0,0 +1,240 @@
+use std::io;
+
+use nalgebra::{Matrix3, SymmetricEigen};
+use serde::Serialize;
+use serde_json::{to_writer, to_writer_pretty};
+use structopt::StructOpt;
+
+use crate::mesh::{element_faces, read_elements};
+use crate::point_cloud::{Point3, Vector3};
+use base::define_raw_output;
+use base::defs::{IntoResult, Result};
+use base::fm;
+use base::util::cli;
+
+define_raw_output!(JsonOutput, "json");
+
+#[derive(StructOpt)]
+#[structopt(about = "Measure geometric properties of element meshes")]
+pub struct MeasureCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: JsonOutput,
+
+    #[structopt(
+        help = "Element to measure (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(help = "Prettify JSON output", long, short = "p")]
+    pretty: bool,
+}
+
+impl MeasureCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        for measurements in measure(reader.as_mut(), &self.elements)? {
+            if self.pretty {
+                to_writer_pretty(&mut writer, &measurements)
+            } else {
+                to_writer(&mut writer, &measurements)
+            }
+            .into_result(|| "failed to write measurements".to_string())?;
+            writeln!(writer)
+                .into_result(|| "failed to write measurements".to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BoundingBox {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrientedBoundingBox {
+    pub center: [f64; 3],
+    pub axes: [[f64; 3]; 3],
+    pub half_extents: [f64; 3],
+}
+
+#[derive(Debug, Serialize)]
+pub struct Measurements {
+    pub element: String,
+    pub volume: f64,
+    pub area: f64,
+    pub centroid: [f64; 3],
+    pub bounding_box: BoundingBox,
+    pub oriented_bounding_box: OrientedBoundingBox,
+}
+
+// The first state of each element is measured.
+pub fn measure(
+    reader: &mut dyn fm::Read,
+    elements: &[String],
+) -> Result<Vec<Measurements>> {
+    read_elements(reader, elements)?
+        .iter()
+        .map(|(view, state)| {
+            let vertices: Vec<_> = state
+                .vertices
+                .iter()
+                .map(|p| Point3::new(p.x as f64, p.y as f64, p.z as f64))
+                .collect();
+            let faces = element_faces(view, vertices.len())?;
+            Ok(measure_mesh(view.element.clone(), &vertices, &faces))
+        })
+        .collect()
+}
+
+fn to_array(v: &Vector3) -> [f64; 3] {
+    [v.x, v.y, v.z]
+}
+
+// Volume and its centroid come from the divergence theorem, so they are
+// meaningful for closed consistently oriented meshes only; the area-weighted
+// centroid is used for degenerate volumes.
+pub fn measure_mesh(
+    element: String,
+    vertices: &[Point3],
+    faces: &[[usize; 3]],
+) -> Measurements {
+    let mut volume = 0.0;
+    let mut area = 0.0;
+    let mut volume_moment = Vector3::zeros();
+    let mut area_moment = Vector3::zeros();
+
+    for face in faces {
+        let [a, b, c] = face.map(|v| vertices[v].coords);
+        let tetra_volume = a.dot(&b.cross(&c)) / 6.0;
+        let triangle_area = (b - a).cross(&(c - a)).norm() / 2.0;
+
+        volume += tetra_volume;
+        area += triangle_area;
+        volume_moment += (a + b + c) / 4.0 * tetra_volume;
+        area_moment += (a + b + c) / 3.0 * triangle_area;
+    }
+
+    let centroid = if volume.abs() > f64::EPSILON {
+        volume_moment / volume
+    } else if area > 0.0 {
+        area_moment / area
+    } else {
+        Vector3::zeros()
+    };
+
+    Measurements {
+        element,
+        volume,
+        area,
+        centroid: to_array(&centroid),
+        bounding_box: bounding_box(vertices),
+        oriented_bounding_box: oriented_bounding_box(vertices),
+    }
+}
+
+fn bounding_box(vertices: &[Point3]) -> BoundingBox {
+    if vertices.is_empty() {
+        return BoundingBox {
+            min: [0.0; 3],
+            max: [0.0; 3],
+        };
+    }
+
+    let mut min = vertices[0].coords;
+    let mut max = min;
+    for v in vertices {
+        min = min.inf(&v.coords);
+        max = max.sup(&v.coords);
+    }
+
+    BoundingBox {
+        min: to_array(&min),
+        max: to_array(&max),
+    }
+}
+
+// Axes are principal components of vertices (largest variance first).
+fn oriented_bounding_box(vertices: &[Point3]) -> OrientedBoundingBox {
+    let mean = vertices.iter().map(|v| v.coords).sum::<Vector3>()
+        / vertices.len().max(1) as f64;
+
+    let mut covariance = Matrix3::zeros();
+    for v in vertices {
+        let d = v.coords - mean;
+        covariance += d * d.transpose();
+    }
+
+    let eigen = SymmetricEigen::new(covariance);
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| {
+        eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a])
+    });
+    let axes = order.map(|i| Vector3::from(eigen.eigenvectors.column(i)));
+
+    let mut min = [f64::MAX; 3];
+    let mut max = [f64::MIN; 3];
+    for v in vertices {
+        for (i, axis) in axes.iter().enumerate() {
+            let x = (v.coords - mean).dot(axis);
+            min[i] = min[i].min(x);
+            max[i] = max[i].max(x);
+        }
+    }
+    if vertices.is_empty() {
+        min = [0.0; 3];
+        max = [0.0; 3];
+    }
+
+    let center = (0..3)
+        .map(|i| axes[i] * (min[i] + max[i]) / 2.0)
+        .sum::<Vector3>()
+        + mean;
+
+    OrientedBoundingBox {
+        center: to_array(&center),
+        axes: axes.map(|a| to_array(&a)),
+        half_extents: [0, 1, 2].map(|i| (max[i] - min[i]) / 2.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::Mesh;
+
+    #[test]
+    fn test_measure_mesh() {
+        let corners: Vec<_> = (0..8)
+            .map(|i| {
+                let (x, y, z) = (i % 2, i / 2 % 2, i / 4);
+                Point3::new(2.0 * x as f64, y as f64, 3.0 * z as f64)
+            })
+            .collect();
+        let mesh = Mesh::convex_hull(&corners).unwrap();
+
+        let m = measure_mesh("a".to_string(), &mesh.vertices, &mesh.faces);
+        assert!((m.volume - 6.0).abs() < 1e-9);
+        assert!((m.area - 22.0).abs() < 1e-9);
+        for (c, e) in m.centroid.iter().zip([1.0, 0.5, 1.5]) {
+            assert!((c - e).abs() < 1e-9);
+        }
+        assert_eq!(m.bounding_box.min, [0.0, 0.0, 0.0]);
+        assert_eq!(m.bounding_box.max, [2.0, 1.0, 3.0]);
+
+        let obb = m.oriented_bounding_box;
+        for (h, e) in obb.half_extents.iter().zip([1.5, 1.0, 0.5]) {
+            assert!((h - e).abs() < 1e-9);
+        }
+        assert!((obb.axes[0][2].abs() - 1.0).abs() < 1e-9);
+    }
+}
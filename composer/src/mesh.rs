@@ -9,6 +9,8 @@ use crate::point_cloud::{
     validate_point_bounds, Matrix4, Point3, PointCloudParams, Vector3, Vector4,
 };
 use crate::poisson;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
 
 #[derive(Default, Clone)]
 pub struct Mesh {
@@ -136,10 +138,384 @@ impl Mesh {
         }
         self.faces = faces;
     }
+
+    // Closed boundary loops going against the orientation of their adjacent
+    // faces. Loops passing through non-manifold vertices are skipped.
+    pub fn boundary_loops(&self) -> Vec<Vec<usize>> {
+        let mut num_edge_faces = HashMap::new();
+        for &[v0, v1, v2] in &self.faces {
+            for (a, b) in [(v0, v1), (v1, v2), (v2, v0)] {
+                *num_edge_faces.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        let mut next = HashMap::new();
+        let mut ambiguous = HashSet::new();
+        for &[v0, v1, v2] in &self.faces {
+            for (a, b) in [(v0, v1), (v1, v2), (v2, v0)] {
+                if num_edge_faces[&(a.min(b), a.max(b))] == 1
+                    && next.insert(b, a).is_some()
+                {
+                    ambiguous.insert(b);
+                }
+            }
+        }
+
+        let mut visited = HashSet::new();
+        let mut starts: Vec<usize> = next.keys().cloned().collect();
+        starts.sort_unstable();
+        let mut loops = vec![];
+        for start in starts {
+            let mut boundary = vec![];
+            let mut v = start;
+            let closed = loop {
+                if !visited.insert(v) || ambiguous.contains(&v) {
+                    break false;
+                }
+                boundary.push(v);
+                match next.get(&v) {
+                    Some(&u) if u == start => break true,
+                    Some(&u) => v = u,
+                    None => break false,
+                }
+            };
+            if closed {
+                loops.push(boundary);
+            }
+        }
+        loops
+    }
+
+    // Closes boundary loops of up to given number of edges with fans around
+    // their centroids (or single triangles), returns the number of loops.
+    pub fn fill_holes(&mut self, max_boundary_edges: usize) -> usize {
+        let mut num_filled = 0;
+        for boundary in self.boundary_loops() {
+            if boundary.len() > max_boundary_edges {
+                continue;
+            }
+
+            if boundary.len() == 3 {
+                self.faces.push([boundary[0], boundary[1], boundary[2]]);
+            } else {
+                let n = boundary.len() as f64;
+                let center = self.vertices.len();
+                let sum = boundary.iter().fold(Vector3::zeros(), |sum, &v| {
+                    sum + self.vertices[v].coords
+                });
+                self.vertices.push(Point3::from(sum / n));
+                let normal = boundary
+                    .iter()
+                    .fold(Vector3::zeros(), |sum, &v| sum + self.normals[v]);
+                self.normals.push(normal.normalize());
+                for (i, &v) in boundary.iter().enumerate() {
+                    let u = boundary[(i + 1) % boundary.len()];
+                    self.faces.push([v, u, center]);
+                }
+            }
+            num_filled += 1;
+        }
+        num_filled
+    }
+
+    // Boundary loops within the tolerance from given Z planes (left after
+    // cutting at bounds) are projected onto them and smoothed along, then
+    // optionally closed with flat caps. Returns the number of loops.
+    pub fn finish_cuts(
+        &mut self,
+        plane_zs: &[f64],
+        tolerance: f64,
+        num_smooth_iters: usize,
+        caps: bool,
+    ) -> usize {
+        let mut num_finished = 0;
+        for boundary in self.boundary_loops() {
+            let near = |z: f64| {
+                boundary
+                    .iter()
+                    .all(|&v| (self.vertices[v].z - z).abs() <= tolerance)
+            };
+            let z = match plane_zs.iter().find(|&&z| near(z)) {
+                Some(&z) => z,
+                None => continue,
+            };
+
+            let n = boundary.len();
+            let mut points: Vec<Point3> = boundary
+                .iter()
+                .map(|&v| {
+                    Point3::new(self.vertices[v].x, self.vertices[v].y, z)
+                })
+                .collect();
+            for _ in 0..num_smooth_iters {
+                points = (0..n)
+                    .map(|i| {
+                        let prev = points[(i + n - 1) % n].coords;
+                        let next = points[(i + 1) % n].coords;
+                        Point3::from(
+                            (prev + next) * 0.25 + points[i].coords * 0.5,
+                        )
+                    })
+                    .collect();
+            }
+            for (&v, &p) in boundary.iter().zip(&points) {
+                self.vertices[v] = p;
+            }
+
+            if caps {
+                let triangles = triangulate_polygon(&points);
+                self.faces
+                    .extend(triangles.iter().map(|t| t.map(|i| boundary[i])));
+            }
+            num_finished += 1;
+        }
+        num_finished
+    }
+
+    pub fn to_element(
+        &self,
+        element: String,
+    ) -> (fm::ElementView, fm::ElementViewState) {
+        let mut view = fm::ElementView {
+            element,
+            ..Default::default()
+        };
+
+        view.faces = self
+            .faces
+            .iter()
+            .map(|f| fm::element_view::Face {
+                vertex1: f[0] as u32 + 1,
+                vertex2: f[1] as u32 + 1,
+                vertex3: f[2] as u32 + 1,
+                normal1: f[0] as u32 + 1,
+                normal2: f[1] as u32 + 1,
+                normal3: f[2] as u32 + 1,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut state = fm::ElementViewState {
+            element: view.element.clone(),
+            ..Default::default()
+        };
+
+        state.vertices = self
+            .vertices
+            .iter()
+            .map(|p| fm::Point3 {
+                x: p[0] as f32,
+                y: p[1] as f32,
+                z: p[2] as f32,
+            })
+            .collect();
+
+        state.normals = self
+            .normals
+            .iter()
+            .map(|p| fm::Point3 {
+                x: p[0] as f32,
+                y: p[1] as f32,
+                z: p[2] as f32,
+            })
+            .collect();
+
+        (view, state)
+    }
+
+    // Incremental convex hull, returns None for degenerate (flat) inputs.
+    pub fn convex_hull(points: &[Point3]) -> Option<Mesh> {
+        const EPSILON: f64 = 1e-9;
+
+        let farthest = |dist: &dyn Fn(&Point3) -> f64| {
+            (0..points.len())
+                .max_by(|&i, &j| dist(&points[i]).total_cmp(&dist(&points[j])))
+        };
+
+        let p0 = 0;
+        let p1 = farthest(&|p| (p - points[p0]).norm())?;
+        let dir = (points[p1] - points[p0]).try_normalize(EPSILON)?;
+        let p2 = farthest(&|p| (p - points[p0]).cross(&dir).norm())?;
+        let normal = (points[p1] - points[p0])
+            .cross(&(points[p2] - points[p0]))
+            .try_normalize(EPSILON)?;
+        let p3 = farthest(&|p| (p - points[p0]).dot(&normal).abs())?;
+        let height = (points[p3] - points[p0]).dot(&normal);
+        let scale = (points[p1] - points[p0]).norm();
+        if height.abs() <= EPSILON * scale.max(1.0) {
+            return None;
+        }
+
+        let face_normal = |f: &[usize; 3]| {
+            (points[f[1]] - points[f[0]]).cross(&(points[f[2]] - points[f[0]]))
+        };
+        let eps = EPSILON * scale.max(1.0);
+        let is_visible = |f: &[usize; 3], p: &Point3| {
+            let n = face_normal(f);
+            n.dot(&(p - points[f[0]])) > eps * n.norm()
+        };
+
+        let mut faces = if height > 0.0 {
+            vec![[p0, p2, p1], [p0, p1, p3], [p1, p2, p3], [p2, p0, p3]]
+        } else {
+            vec![[p0, p1, p2], [p0, p3, p1], [p1, p3, p2], [p2, p3, p0]]
+        };
+
+        for (i, p) in points.iter().enumerate() {
+            let (visible, hidden): (Vec<[usize; 3]>, Vec<_>) =
+                faces.iter().partition(|f| is_visible(f, p));
+            if visible.is_empty() {
+                continue;
+            }
+
+            let edges: HashSet<_> = visible
+                .iter()
+                .flat_map(|f| [(f[0], f[1]), (f[1], f[2]), (f[2], f[0])])
+                .collect();
+            faces = hidden;
+            for &(a, b) in &edges {
+                if !edges.contains(&(b, a)) {
+                    faces.push([a, b, i]);
+                }
+            }
+        }
+
+        let mut mappings = HashMap::new();
+        let mut mesh = Mesh::default();
+        for f in faces.iter_mut() {
+            for v in f.iter_mut() {
+                *v = *mappings.entry(*v).or_insert_with(|| {
+                    mesh.vertices.push(points[*v]);
+                    mesh.vertices.len() - 1
+                });
+            }
+        }
+
+        mesh.faces = faces;
+        mesh.set_area_weighted_normals();
+        Some(mesh)
+    }
+
+    // Vertex normals are recomputed, since element normals may be indexed
+    // independently of vertices.
+    pub fn from_element(
+        view: &fm::ElementView,
+        state: &fm::ElementViewState,
+    ) -> Result<Mesh> {
+        let mut mesh = Mesh {
+            vertices: state
+                .vertices
+                .iter()
+                .map(|p| Point3::new(p.x as f64, p.y as f64, p.z as f64))
+                .collect(),
+            ..Default::default()
+        };
+        mesh.faces = element_faces(view, mesh.vertices.len())?;
+        mesh.set_area_weighted_normals();
+        Ok(mesh)
+    }
+
+    pub fn set_area_weighted_normals(&mut self) {
+        self.normals = vec![Vector3::zeros(); self.vertices.len()];
+        for f in &self.faces {
+            let n = (self.vertices[f[1]] - self.vertices[f[0]])
+                .cross(&(self.vertices[f[2]] - self.vertices[f[0]]));
+            for v in f {
+                self.normals[*v] += n;
+            }
+        }
+        for n in self.normals.iter_mut() {
+            n.normalize_mut();
+        }
+    }
+}
+
+// Ear clipping of a simple polygon given in XY plane, triangles follow its
+// orientation. Degenerate polygons fall back to clipping non-ears.
+fn triangulate_polygon(points: &[Point3]) -> Vec<[usize; 3]> {
+    let cross = |a: usize, b: usize, c: usize| {
+        let (a, b, c) = (points[a], points[b], points[c]);
+        (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+    };
+    let n = points.len();
+    let area: f64 = (0..n).map(|i| cross(0, i, (i + 1) % n)).sum();
+    let sign = area.signum();
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n.saturating_sub(2));
+    let (mut i, mut num_misses) = (0, 0);
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        i %= m;
+        let (a, b, c) = (
+            remaining[(i + m - 1) % m],
+            remaining[i],
+            remaining[(i + 1) % m],
+        );
+        let is_ear = cross(a, b, c) * sign > 0.0
+            && remaining.iter().all(|&v| {
+                [a, b, c].contains(&v)
+                    || cross(a, b, v) * sign <= 0.0
+                    || cross(b, c, v) * sign <= 0.0
+                    || cross(c, a, v) * sign <= 0.0
+            });
+        if is_ear || num_misses > m {
+            triangles.push([a, b, c]);
+            remaining.remove(i);
+            num_misses = 0;
+        } else {
+            i += 1;
+            num_misses += 1;
+        }
+    }
+    if remaining.len() == 3 {
+        triangles.push([remaining[0], remaining[1], remaining[2]]);
+    }
+    triangles
+}
+
+// Reads element views along with their first states.
+pub fn read_elements(
+    reader: &mut dyn fm::Read,
+    elements: &[String],
+) -> Result<Vec<(fm::ElementView, fm::ElementViewState)>> {
+    let indexed = fm::IndexedReader::new(reader)?;
+    let mut result = Vec::new();
+
+    for view in indexed.views() {
+        let view = view?;
+        if !elements.is_empty() && !elements.contains(&view.element) {
+            continue;
+        }
+        let state = indexed.states(&view.element, ..).next().transpose()?;
+        result.push((view, state.unwrap_or_default()));
+    }
+
+    Ok(result)
+}
+
+// Converts 1-based face vertex indices into 0-based ones.
+pub fn element_faces(
+    view: &fm::ElementView,
+    num_vertices: usize,
+) -> Result<Vec<[usize; 3]>> {
+    let mut faces = Vec::with_capacity(view.faces.len());
+    for face in &view.faces {
+        let face = [face.vertex1, face.vertex2, face.vertex3];
+        if face.iter().any(|&v| v == 0 || v as usize > num_vertices) {
+            let desc = format!(
+                "face vertex index out of bounds for element '{}'",
+                view.element
+            );
+            return Err(Error::new(MalformedData, desc));
+        }
+        faces.push(face.map(|v| v as usize - 1));
+    }
+    Ok(faces)
 }
 
 #[derive(Add, AddAssign, Copy, Clone)]
-struct Quadric(Matrix4);
+pub struct Quadric(Matrix4);
 
 impl Quadric {
     // Chosen by the Blender devs and represents a value
@@ -155,7 +531,7 @@ impl Quadric {
         let a = self.0.fixed_slice::<3, 3>(0, 0);
         if a.determinant().abs() > Self::OPTIMIZE_EPS {
             let b = self.0.fixed_slice::<3, 1>(0, 3);
-            Some(-a.cholesky().unwrap().solve(&b))
+            Some(-a.cholesky()?.solve(&b))
         } else {
             None
         }
@@ -178,9 +554,14 @@ struct Candidate {
     timestamp: [usize; 2], // Used to identify and discard obsolete candidates.
 }
 
+// Ties are broken by edges, so that decimation is deterministic despite
+// hash set orders of neighbouring vertices.
 impl Ord for Candidate {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.cost.partial_cmp(&other.cost).unwrap().reverse()
+        let cost = self.cost.partial_cmp(&other.cost).unwrap();
+        cost.then_with(|| self.edge.cmp(&other.edge))
+            .then_with(|| self.timestamp.cmp(&other.timestamp))
+            .reverse()
     }
 }
 
@@ -192,7 +573,7 @@ impl PartialOrd for Candidate {
 
 impl PartialEq for Candidate {
     fn eq(&self, other: &Self) -> bool {
-        self.cost == other.cost
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -401,3 +782,100 @@ impl Decimator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num_boundary_edges(mesh: &Mesh) -> usize {
+        let edges: HashSet<_> = mesh
+            .faces
+            .iter()
+            .flat_map(|f| [(f[0], f[1]), (f[1], f[2]), (f[2], f[0])])
+            .collect();
+        edges
+            .iter()
+            .filter(|(a, b)| !edges.contains(&(*b, *a)))
+            .count()
+    }
+
+    // Cube with its top side open.
+    fn create_open_cube() -> Mesh {
+        let corners: Vec<_> = (0..8)
+            .map(|i| {
+                let coord = |bit| ((i >> bit) & 1) as f64;
+                Point3::new(coord(0), coord(1), coord(2))
+            })
+            .collect();
+        let mut mesh = Mesh::convex_hull(&corners).unwrap();
+        assert_eq!(num_boundary_edges(&mesh), 0);
+
+        let vertices = mesh.vertices.clone();
+        mesh.faces
+            .retain(|f| f.iter().any(|&v| vertices[v].z < 0.5));
+        assert_eq!(mesh.faces.len(), 10);
+        assert_eq!(num_boundary_edges(&mesh), 4);
+        mesh
+    }
+
+    #[test]
+    fn test_fill_holes() {
+        let mut mesh = create_open_cube();
+        assert_eq!(mesh.fill_holes(3), 0);
+        assert_eq!(mesh.faces.len(), 10);
+
+        assert_eq!(mesh.fill_holes(4), 1);
+        assert_eq!(num_boundary_edges(&mesh), 0);
+        assert_eq!(mesh.faces.len(), 14);
+        assert_eq!(mesh.vertices.len(), 9);
+        assert_eq!(mesh.vertices[8], Point3::new(0.5, 0.5, 1.0));
+        assert!(mesh.normals[8].z > 0.5);
+
+        // Fans are oriented along with the rest of the surface.
+        mesh.set_area_weighted_normals();
+        assert!((mesh.normals[8] - Vector3::z()).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_finish_cuts() {
+        let mut mesh = create_open_cube();
+        let rim = mesh.boundary_loops().pop().unwrap();
+        mesh.vertices[rim[0]].z = 0.95;
+
+        assert_eq!(mesh.finish_cuts(&[0.0, 2.0], 0.1, 1, true), 0);
+        assert_eq!(mesh.finish_cuts(&[1.0], 0.01, 1, true), 0);
+
+        let mut flat = mesh.clone();
+        assert_eq!(flat.finish_cuts(&[1.0], 0.1, 0, false), 1);
+        assert!(rim.iter().all(|&v| flat.vertices[v].z == 1.0));
+        assert_eq!(num_boundary_edges(&flat), 4);
+
+        assert_eq!(mesh.finish_cuts(&[1.0], 0.1, 1, true), 1);
+        assert_eq!(num_boundary_edges(&mesh), 0);
+        assert_eq!(mesh.faces.len(), 12);
+        assert_eq!(mesh.vertices.len(), 8);
+        // Smoothing shrinks the square rim towards its center.
+        let p = mesh.vertices[rim[0]];
+        assert!(p.x > 0.0 && p.x < 1.0 && p.y > 0.0 && p.y < 1.0);
+        assert_eq!(p.z, 1.0);
+    }
+
+    #[test]
+    fn test_triangulate_polygon() {
+        // L-shaped polygon of area 3 with a reflex vertex.
+        let points: Vec<_> = [(0, 0), (2, 0), (2, 1), (1, 1), (1, 2), (0, 2)]
+            .iter()
+            .map(|&(x, y)| Point3::new(x as f64, y as f64, 0.0))
+            .collect();
+        let triangles = triangulate_polygon(&points);
+        assert_eq!(triangles.len(), 4);
+        let area: f64 = triangles
+            .iter()
+            .map(|t| {
+                let (a, b, c) = (points[t[0]], points[t[1]], points[t[2]]);
+                (b - a).cross(&(c - a)).z / 2.0
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 1e-9);
+    }
+}
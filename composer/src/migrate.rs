@@ -0,0 +1,131 @@
+use log::info;
+use structopt::StructOpt;
+
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(
+    about = "Upgrade .fm file to the current format version (also rewrites \
+             it with given output options, e.g. --fm-dedup)"
+)]
+pub struct MigrateCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl MigrateCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        migrate(reader.as_mut(), writer.as_mut())
+    }
+}
+
+pub fn migrate(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+) -> Result<()> {
+    let version = reader.version();
+    info!("migrating .fm version {} to {}...", version, fm::VERSION);
+
+    match version {
+        // Records are unchanged since version 1, only the header got
+        // feature flags. Raw copying preserves unknown fields.
+        1 | fm::VERSION => {
+            while let Some(raw) = reader.read_raw_record()? {
+                writer.write_raw_record(&raw)?;
+            }
+            Ok(())
+        }
+        _ => Err(Error::new(
+            UnsupportedFeature,
+            format!("no migration from .fm version '{}'", version),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::{Read as _, Write as _};
+    use std::io;
+
+    #[test]
+    fn test_migrate_version1() {
+        let view = fm::ElementView {
+            element: "e".to_string(),
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(
+            Vec::new(),
+            &fm::WriterParams {
+                compression: fm::Compression::None,
+                gzip_level: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        writer.write_record(&new_element_view_rec(view)).unwrap();
+        let data = writer.into_inner().unwrap();
+
+        // Version 1 header has no feature flags.
+        let mut old = Vec::new();
+        old.extend_from_slice(&fm::MAGIC.to_le_bytes());
+        old.extend_from_slice(&1u32.to_le_bytes());
+        old.extend_from_slice(&(fm::Compression::None as i32).to_le_bytes());
+        old.extend_from_slice(&data[16..]);
+
+        let mut reader = fm::Reader::new(io::Cursor::new(old)).unwrap();
+        assert_eq!(reader.version(), 1);
+
+        let mut writer = create_writer();
+        migrate(&mut reader, &mut writer).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        assert_eq!(reader.version(), fm::VERSION);
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(ElementView, rec).element, "e");
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_migrate_dedup() {
+        let new_frame = |time| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "a".to_string(),
+                time,
+                image: Some(fm::Image {
+                    r#type: fm::image::Type::Png as i32,
+                    data: vec![1, 2, 3],
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+        };
+        let records: Vec<_> = (1..=3).map(new_frame).collect();
+        let mut reader = create_reader_with_records(&records);
+
+        let params = fm::WriterParams {
+            dedup: true,
+            ..Default::default()
+        };
+        let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+        migrate(&mut reader, &mut writer).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        assert_eq!(reader.features(), fm::FEATURE_DEDUP);
+        for record in &records {
+            assert_eq!(&reader.read_record().unwrap().unwrap(), record);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::f64::consts::PI;
 use std::hash::Hash;
 
 use petgraph::unionfind::UnionFind;
@@ -6,6 +7,7 @@ use rand::Rng;
 use rlua::Value as LuaValue;
 use serde_json::Value as JsonValue;
 
+use crate::point_cloud::Vector3;
 use base::defs::{Error, ErrorKind::*};
 use base::fm;
 
@@ -17,6 +19,14 @@ pub fn lua_err_to_err(err: rlua::Error) -> Error {
     )
 }
 
+pub fn kdtree_err_to_err(err: kiddo::ErrorKind) -> Error {
+    Error::with_source(
+        GeometryError,
+        "failed to query point kd-tree".to_string(),
+        err,
+    )
+}
+
 pub fn lua_table_from_record<'a>(
     ctx: rlua::Context<'a>,
     record: &fm::Record,
@@ -114,14 +124,14 @@ pub fn extract_biggest_partition_component(
 
 pub fn vec_inv<T>(v: &[T]) -> HashMap<T, usize>
 where
-    T: Copy + Eq + Hash
+    T: Copy + Eq + Hash,
 {
     HashMap::from_iter(v.iter().enumerate().map(|(i, &j)| (j, i)))
 }
 
 pub fn vec_inv_many<T>(labeling: &[T]) -> HashMap<T, Vec<usize>>
 where
-    T: Copy + Eq + Hash
+    T: Copy + Eq + Hash,
 {
     let mut family = HashMap::<T, Vec<usize>>::new();
     for (i, &j) in labeling.iter().enumerate() {
@@ -131,3 +141,41 @@ where
     family
 }
 
+// Directions evenly distributed over the unit sphere (Fibonacci lattice).
+pub fn sphere_directions(num: usize) -> Vec<Vector3> {
+    let golden_angle = PI * (3.0 - 5f64.sqrt());
+    (0..num)
+        .map(|i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / num as f64;
+            let r = (1.0 - z * z).sqrt();
+            let angle = golden_angle * i as f64;
+            Vector3::new(r * angle.cos(), r * angle.sin(), z)
+        })
+        .collect()
+}
+
+// Matches name against shell-like pattern with '*' and '?' wildcards.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // Pattern position after the last star and name position it matches to.
+    let mut star = None;
+    while j < name.len() {
+        if i < pattern.len() && (pattern[i] == '?' || pattern[i] == name[j]) {
+            i += 1;
+            j += 1;
+        } else if i < pattern.len() && pattern[i] == '*' {
+            i += 1;
+            star = Some((i, j));
+        } else if let Some((star_i, star_j)) = star {
+            // Let the star match one more character.
+            i = star_i;
+            j = star_j + 1;
+            star = Some((star_i, j));
+        } else {
+            return false;
+        }
+    }
+    pattern[i..].iter().all(|&c| c == '*')
+}
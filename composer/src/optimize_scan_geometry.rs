@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use std::fmt::Write;
 use std::result::Result as StdResult;
 
@@ -9,14 +8,23 @@ use argmin::core::{
 use argmin::solver::gradientdescent::SteepestDescent;
 use argmin::solver::linesearch::MoreThuenteLineSearch;
 use indexmap::IndexMap;
-use log::info;
+use kiddo::distance::squared_euclidean;
+use kiddo::KdTree;
+use log::{info, warn};
+use nalgebra::{Matrix6, Rotation3, Vector6};
+use rayon::prelude::*;
 use structopt::StructOpt;
 
+use crate::dry_run::{output_location, Plan};
+use crate::misc::kdtree_err_to_err;
+use crate::param_check::{CheckParams, ParamCheck};
 use crate::point_cloud::{
-    build_frame_clouds, distance_between_point_clouds, Matrix4,
-    PointCloudParams, PointNormal, Vector3, Vector4,
+    build_frame_clouds, camera_view_rotation, distance_between_point_clouds,
+    set_camera_pose, Matrix3, Matrix4, PointCloudParams, PointNormal, Vector3,
+    Vector4,
 };
-use crate::scan::{read_scans, ScanParams};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::threads::ThreadParams;
 use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
 use base::util::cli;
@@ -32,18 +40,31 @@ pub struct OptimizeScanGeometryCommand {
 
     #[structopt(flatten)]
     params: OptimizeScanGeometryParams,
+
+    #[structopt(help = "Print execution plan without running it", long)]
+    dry_run: bool,
 }
 
 impl OptimizeScanGeometryCommand {
     pub fn run(&self) -> Result<()> {
         let mut reader = self.input.get()?;
+        if self.dry_run {
+            let output = output_location(&self.output);
+            let plan = plan_optimize_scan_geometry(
+                reader.as_mut(),
+                &output,
+                &self.params,
+            )?;
+            plan.print();
+            return Ok(());
+        }
         let mut writer = self.output.get()?;
 
         optimize_scan_geometry(reader.as_mut(), writer.as_mut(), &self.params)
     }
 }
 
-#[derive(StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 pub struct OptimizeScanGeometryParams {
     #[structopt(
         help = "Match scan clouds without altering their shapes",
@@ -60,6 +81,26 @@ pub struct OptimizeScanGeometryParams {
     )]
     num_iters: usize,
 
+    #[structopt(
+        help = "Refine camera poses by point-to-plane ICP after matching",
+        long
+    )]
+    icp: bool,
+
+    #[structopt(
+        help = "Maximum number of ICP iterations per scan",
+        long,
+        default_value = "30"
+    )]
+    icp_max_iters: usize,
+
+    #[structopt(
+        help = "Maximum distance between ICP corresponding points",
+        long,
+        default_value = "0.05"
+    )]
+    icp_max_distance: f64,
+
     #[structopt(
         help = "Scan to optimize (all the scans if not specified)",
         long = "optimized-scan",
@@ -71,16 +112,138 @@ pub struct OptimizeScanGeometryParams {
     #[structopt(flatten)]
     point_cloud: PointCloudParams,
 
+    #[structopt(flatten)]
+    threads: ThreadParams,
+
     #[structopt(flatten)]
     scan: ScanParams,
 }
 
+impl OptimizeScanGeometryParams {
+    pub fn with_scans_config(&self) -> Result<OptimizeScanGeometryParams> {
+        let mut params = self.clone();
+        if let Some(path) = &self.scan.scans_config {
+            let config = read_scans_config(path)?;
+            params.scan.merge_config(&config);
+            params.point_cloud.merge_config(&config);
+        }
+        Ok(params)
+    }
+}
+
+impl CheckParams for OptimizeScanGeometryParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        check.require(self.icp || self.num_iters > 0, || {
+            "--num-iters should be positive unless --icp is given".to_string()
+        });
+        check.require(!self.icp || self.icp_max_iters > 0, || {
+            "--icp-max-iters should be positive".to_string()
+        });
+        check.require(
+            !self.icp
+                || self.icp_max_distance > 0.0
+                    && self.icp_max_distance.is_finite(),
+            || "--icp-max-distance should be positive".to_string(),
+        );
+    }
+}
+
+fn optimized_scans(
+    params: &OptimizeScanGeometryParams,
+    scans: &IndexMap<String, fm::Scan>,
+) -> Result<Vec<String>> {
+    if params.optimized_scans.is_empty() {
+        Ok(scans.keys().cloned().collect())
+    } else {
+        if let Some(target) = params
+            .optimized_scans
+            .iter()
+            .find(|t| !scans.contains_key(t.as_str()))
+        {
+            let desc = format!("unknown target scan '{}'", target);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        Ok(params.optimized_scans.clone())
+    }
+}
+
+// Resolves parameters and reads scans, but skips the optimization.
+pub fn plan_optimize_scan_geometry(
+    reader: &mut dyn fm::Read,
+    output: &str,
+    params: &OptimizeScanGeometryParams,
+) -> Result<Plan> {
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
+    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+    params
+        .point_cloud
+        .validate(scans.keys().map(String::as_str))?;
+    let optimized = optimized_scans(params, &scans)?;
+
+    let mut plan = Plan::new(params);
+    plan.stage(format!(
+        "read {} scans ({} frames)",
+        scans.len(),
+        scan_frames.len()
+    ));
+    plan.stage("build point clouds");
+    if params.num_iters > 0 {
+        plan.stage(format!(
+            "match {} of scans {} ({} iterations at most)",
+            if params.match_scans {
+                "point clouds"
+            } else {
+                "frame clouds"
+            },
+            optimized.join(", "),
+            params.num_iters
+        ));
+    }
+    if params.icp {
+        plan.stage(format!(
+            "refine camera poses of scans {} by point-to-plane ICP \
+             ({} iterations per scan at most)",
+            optimized.join(", "),
+            params.icp_max_iters
+        ));
+    }
+    plan.stage("write scans with updated geometry");
+
+    let num_depths: usize = scan_frames.iter().map(|f| f.depths.len()).sum();
+    plan.estimate("depth samples", num_depths);
+    plan.estimate("optimized parameters", optimized.len() * 7);
+    for target in &optimized {
+        let scan = &scans[target];
+        if scan.camera_initial_position.is_none()
+            || scan.camera_initial_direction.is_none()
+        {
+            plan.warn(format!("scan '{}' has no initial camera pose", target));
+        }
+    }
+
+    plan.output(format!(
+        "{}: {} Scan and {} ScanFrame records",
+        output,
+        scans.len(),
+        scan_frames.len()
+    ));
+
+    Ok(plan)
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn optimize_scan_geometry(
     reader: &mut dyn fm::Read,
     writer: &mut dyn fm::Write,
     params: &OptimizeScanGeometryParams,
 ) -> Result<()> {
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
     info!("reading scans...");
     let (mut scans, scan_frames) = read_scans(reader, &params.scan)?;
 
@@ -88,30 +251,18 @@ pub fn optimize_scan_geometry(
         .point_cloud
         .validate(scans.keys().map(String::as_str))?;
 
-    let optimized: Vec<_> = if params.optimized_scans.is_empty() {
-        scans.keys().cloned().collect()
-    } else {
-        if let Some(target) = params
-            .optimized_scans
-            .iter()
-            .find(|t| !scans.contains_key(t.as_str()))
-        {
-            let desc = format!("unknown target scan '{}'", target);
-            return Err(Error::new(InconsistentState, desc));
-        }
-        params.optimized_scans.clone()
-    };
+    let optimized = optimized_scans(params, &scans)?;
 
     let mut init_params = Vec::new();
     for target in optimized.iter() {
         let scan = scans.get(target).unwrap();
 
-        let pos = scan.camera_initial_position.unwrap();
+        let pos = scan.camera_initial_position.unwrap_or_default();
         init_params.push(pos.x);
         init_params.push(pos.y);
         init_params.push(pos.z);
 
-        let dir = scan.camera_initial_direction.unwrap();
+        let dir = scan.camera_initial_direction.unwrap_or_default();
         init_params.push(dir.x);
         init_params.push(dir.y);
         init_params.push(dir.z);
@@ -119,20 +270,39 @@ pub fn optimize_scan_geometry(
         init_params.push(scan.camera_up_angle);
     }
 
-    info!("starting more-thuente line search...");
-
-    let res = if params.match_scans {
-        match_scans(params, &scans, &scan_frames, &optimized, init_params)
+    let pool = params.threads.pool()?;
+    let res = if params.num_iters == 0 {
+        Ok(init_params)
+    } else if params.match_scans {
+        info!("starting more-thuente line search...");
+        pool.install(|| {
+            match_scans(params, &scans, &scan_frames, &optimized, init_params)
+        })
     } else {
-        match_frames(params, &scans, &scan_frames, &optimized, init_params)
+        info!("starting more-thuente line search...");
+        pool.install(|| {
+            match_frames(params, &scans, &scan_frames, &optimized, init_params)
+        })
     };
 
     match res {
         Ok(best_params) => {
-            info!("writing scans with updated geometry...");
-
             apply_geometry_params(&mut scans, &optimized, &best_params);
 
+            if params.icp {
+                info!("refining camera poses by ICP...");
+                pool.install(|| {
+                    refine_camera_poses(
+                        params,
+                        &mut scans,
+                        &scan_frames,
+                        &optimized,
+                    )
+                })?;
+            }
+
+            info!("writing scans with updated geometry...");
+
             use fm::record::Type;
             for (_, scan) in scans {
                 writer.write_record(&fm::Record {
@@ -191,8 +361,12 @@ fn apply_geometry_params(
         let base = i * 7;
 
         let scan = scans.get_mut(target).unwrap();
-        let pos = scan.camera_initial_position.as_mut().unwrap();
-        let dir = scan.camera_initial_direction.as_mut().unwrap();
+        let pos = scan
+            .camera_initial_position
+            .get_or_insert_with(Default::default);
+        let dir = scan
+            .camera_initial_direction
+            .get_or_insert_with(Default::default);
 
         pos.x = params[base];
         pos.y = params[base + 1];
@@ -240,12 +414,12 @@ where
 {
     const DELTA: f32 = 0.001;
     let mut params = p.clone();
-    let base = apply(p).unwrap();
+    let base = apply(p)?;
     let mut grad = Vec::with_capacity(p.len());
 
     for (i, param) in p.iter().enumerate() {
         params[i] = *param + DELTA;
-        grad.push((apply(&params).unwrap() - base) / DELTA);
+        grad.push((apply(&params)? - base) / DELTA);
         params[i] = *param;
     }
 
@@ -276,7 +450,7 @@ impl<'a> ArgminOp for FrameOp<'a> {
             &scans,
             self.scan_frames,
             self.point_cloud_params,
-        );
+        )?;
 
         let mut sum = 0.0;
         let mut num = 0;
@@ -284,7 +458,7 @@ impl<'a> ArgminOp for FrameOp<'a> {
             if let Some(dist) = distance_between_point_clouds(
                 &clouds[i],
                 &clouds[(i + 1) % clouds.len()],
-            ) {
+            )? {
                 sum += dist;
                 num += 1;
             } else {
@@ -325,22 +499,28 @@ fn match_scans(
     optimized: &[String],
     mut init_params: Vec<f32>,
 ) -> StdResult<Vec<f32>, ArgminError> {
+    // Clouds are kept in the order of scans, so that scans without frames
+    // get empty ones.
     let frame_clouds =
-        build_frame_clouds(scans, scan_frames, &params.point_cloud);
-    let mut scan_clouds = HashMap::<String, Vec<_>>::new();
-    for (i, cloud) in frame_clouds.into_iter().enumerate() {
-        let name = &scan_frames[i].scan;
-        if let Some(points) = scan_clouds.get_mut(name) {
-            points.extend(cloud);
-        } else {
-            scan_clouds.insert(name.to_string(), cloud);
+        build_frame_clouds(scans, scan_frames, &params.point_cloud)?;
+    let mut clouds = vec![Vec::new(); scans.len()];
+    for (frame, cloud) in scan_frames.iter().zip(frame_clouds) {
+        if let Some(index) = scans.get_index_of(&frame.scan) {
+            clouds[index].extend(cloud);
         }
     }
 
+    let mut indices = Vec::with_capacity(optimized.len());
+    for name in optimized {
+        indices.push(scans.get_index_of(name).ok_or_else(|| {
+            let desc = format!("unknown target scan '{}'", name);
+            Error::new(InconsistentState, desc)
+        })?);
+    }
+
     let op = ScanOp {
-        all: scans.keys().cloned().collect(),
-        optimized: optimized.to_vec(),
-        scan_clouds,
+        optimized: indices,
+        clouds,
     };
 
     let linesearch = MoreThuenteLineSearch::new();
@@ -411,9 +591,9 @@ fn update_geometry_params(params: &mut [f32], icp_params: &[f32]) {
 }
 
 struct ScanOp {
-    all: Vec<String>,
-    optimized: Vec<String>,
-    scan_clouds: HashMap<String, Vec<PointNormal>>,
+    // Indices of optimized scans.
+    optimized: Vec<usize>,
+    clouds: Vec<Vec<PointNormal>>,
 }
 
 impl ArgminOp for ScanOp {
@@ -424,24 +604,22 @@ impl ArgminOp for ScanOp {
     type Float = f32;
 
     fn apply(&self, p: &Self::Param) -> StdResult<Self::Output, ArgminError> {
-        let mut clouds = self.scan_clouds.clone();
-        for (i, name) in self.optimized.iter().enumerate() {
-            let cloud = clouds.get_mut(name.as_str()).unwrap();
-
+        let mut clouds = self.clouds.clone();
+        for (i, &index) in self.optimized.iter().enumerate() {
             let transform =
                 IcpTransform::new(p[i * 2] as f64, p[i * 2 + 1] as f64);
-            for p in cloud.iter_mut() {
+            for p in clouds[index].iter_mut() {
                 p.0.coords = transform.apply(&p.0.coords);
             }
         }
 
         let mut sum = 0.0;
         let mut num = 0;
-        for i in 0..self.all.len() {
+        for i in 0..clouds.len() {
             if let Some(dist) = distance_between_point_clouds(
-                clouds.get(&self.all[i]).unwrap(),
-                clouds.get(&self.all[(i + 1) % self.all.len()]).unwrap(),
-            ) {
+                &clouds[i],
+                &clouds[(i + 1) % clouds.len()],
+            )? {
                 sum += dist;
                 num += 1;
             } else {
@@ -472,11 +650,194 @@ impl Observe<ScanOp> for ScanObserver {
     }
 }
 
+// Rigid correction of a camera pose at zero turntable angle.
+#[derive(Clone, Copy, Debug)]
+struct PoseCorrection {
+    rotation: Matrix3,
+    translation: Vector3,
+}
+
+impl PoseCorrection {
+    fn identity() -> Self {
+        Self {
+            rotation: Matrix3::identity(),
+            translation: Vector3::zeros(),
+        }
+    }
+
+    #[inline]
+    fn apply(&self, v: &Vector3) -> Vector3 {
+        self.rotation * v + self.translation
+    }
+}
+
+// Scan cloud point at zero turntable angle along with the turntable
+// rotation of its frame.
+struct IcpPoint {
+    point: Vector3,
+    time_rot: Matrix3,
+}
+
+const ICP_TOLERANCE: f64 = 1E-9;
+
+// Point-to-plane ICP: each iteration linearizes the distances from source
+// points to tangent planes of their nearest target points and solves the
+// resulting 6x6 least squares problem. Returns None if there are too few
+// correspondences to start with.
+fn find_pose_correction(
+    source: &[IcpPoint],
+    target: &[PointNormal],
+    max_iters: usize,
+    max_distance: f64,
+) -> Result<Option<PoseCorrection>> {
+    let mut kdtree = KdTree::new();
+    for (i, p) in target.iter().enumerate() {
+        kdtree
+            .add(p.0.coords.as_ref(), i)
+            .map_err(kdtree_err_to_err)?;
+    }
+
+    let mut correction = PoseCorrection::identity();
+    for iter in 0..max_iters {
+        let rows = source
+            .par_iter()
+            .map(|p| {
+                let y = correction.apply(&p.point);
+                let q = p.time_rot * y;
+                let (dist, i) = kdtree
+                    .nearest(q.as_ref(), 1, &squared_euclidean)
+                    .map_err(kdtree_err_to_err)?[0];
+                if dist > max_distance * max_distance {
+                    return Ok(None);
+                }
+
+                let PointNormal(c, n) = target[*i];
+                let m = p.time_rot.transpose() * n;
+                let jacobian = Vector6::from_iterator(
+                    y.cross(&m).iter().chain(m.iter()).copied(),
+                );
+                Ok(Some((jacobian, n.dot(&(q - c.coords)))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut a = Matrix6::zeros();
+        let mut b = Vector6::zeros();
+        let mut num = 0;
+        for (jacobian, residual) in rows.into_iter().flatten() {
+            a += jacobian * jacobian.transpose();
+            b -= jacobian * residual;
+            num += 1;
+        }
+
+        let x = match a.cholesky() {
+            Some(cholesky) if num >= 6 => cholesky.solve(&b),
+            _ if iter == 0 => return Ok(None),
+            _ => break,
+        };
+
+        let delta = Rotation3::new(x.fixed_rows::<3>(0).into_owned());
+        correction.rotation = delta * correction.rotation;
+        correction.translation =
+            delta * correction.translation + x.fixed_rows::<3>(3).into_owned();
+        if x.norm() < ICP_TOLERANCE {
+            break;
+        }
+    }
+
+    Ok(Some(correction))
+}
+
+// Matches clouds of optimized scans one by one to clouds of the other scans
+// (including already refined ones).
+fn refine_camera_poses(
+    params: &OptimizeScanGeometryParams,
+    scans: &mut IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+    optimized: &[String],
+) -> Result<()> {
+    let mut clouds =
+        build_frame_clouds(scans, scan_frames, &params.point_cloud)?;
+    let time_rots: Vec<_> = scan_frames
+        .iter()
+        .map(|frame| {
+            let angle = fm::camera_angle(&scans[&frame.scan], frame);
+            Rotation3::from_axis_angle(&Vector3::z_axis(), angle).into_inner()
+        })
+        .collect();
+
+    for target in optimized {
+        let (own, others): (Vec<_>, Vec<_>) =
+            (0..clouds.len()).partition(|&i| scan_frames[i].scan == *target);
+        let source: Vec<_> = own
+            .iter()
+            .flat_map(|&i| {
+                let time_rot = time_rots[i];
+                clouds[i].iter().map(move |p| IcpPoint {
+                    point: time_rot.transpose() * p.0.coords,
+                    time_rot,
+                })
+            })
+            .collect();
+        let reference: Vec<_> = others
+            .iter()
+            .flat_map(|&i| clouds[i].iter().copied())
+            .collect();
+
+        let correction = match find_pose_correction(
+            &source,
+            &reference,
+            params.icp_max_iters,
+            params.icp_max_distance,
+        )? {
+            Some(correction) => correction,
+            None => {
+                warn!("too few ICP correspondences for scan '{}'", target);
+                continue;
+            }
+        };
+
+        for &i in &own {
+            let time_rot = time_rots[i];
+            let rot = time_rot * correction.rotation * time_rot.transpose();
+            for p in clouds[i].iter_mut() {
+                let point =
+                    correction.apply(&(time_rot.transpose() * p.0.coords));
+                p.0.coords = time_rot * point;
+                p.1 = rot * p.1;
+            }
+        }
+
+        let scan = scans.get_mut(target).unwrap();
+        let view_rot = camera_view_rotation(scan)?;
+        let view_rot = view_rot.fixed_slice::<3, 3>(0, 0).into_owned();
+        let pos = scan.camera_initial_position.unwrap_or_default();
+        let eye = Vector3::new(pos.x as f64, pos.y as f64, pos.z as f64);
+        set_camera_pose(
+            scan,
+            &(correction.rotation * view_rot),
+            &correction.apply(&eye),
+        )?;
+
+        let angle = Rotation3::from_matrix_unchecked(correction.rotation)
+            .angle()
+            .to_degrees();
+        info!(
+            "scan '{}' corrected by {:.3} degrees and {:.4} offset",
+            target,
+            angle,
+            correction.translation.norm()
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use base::assert_eq_point3;
 
     use super::*;
+    use crate::point_cloud::Point3;
     use std::f64::consts::PI;
 
     #[test]
@@ -489,4 +850,70 @@ mod tests {
         let v = t.apply(&Vector3::new(2.0, 3.0, 4.0));
         assert_eq_point3!(v, &Vector3::new(3.0, -2.0, 3.0));
     }
+
+    #[test]
+    fn test_gradient_error() {
+        let apply = |p: &Vec<f32>| {
+            if p[1] > 0.0 {
+                let desc = "non-invertible look rotation".to_string();
+                return Err(Error::new(BadOperation, desc).into());
+            }
+            Ok(p[0] * 2.0)
+        };
+        let grad = gradient(apply, &vec![1.0, -1.0]).unwrap();
+        assert!((grad[0] - 2.0).abs() < 1E-2);
+        assert_eq!(grad[1], 0.0);
+        assert!(gradient(apply, &vec![1.0, 0.0]).is_err());
+    }
+
+    #[test]
+    fn test_find_pose_correction() {
+        // Three disjoint plane patches constraining all degrees of freedom.
+        let mut target = Vec::new();
+        for i in 0..35 {
+            for j in 0..35 {
+                let (u, v) = (0.3 + i as f64 * 0.02, 0.3 + j as f64 * 0.02);
+                for (p, n) in [
+                    (Vector3::new(0.0, u, v), Vector3::x()),
+                    (Vector3::new(u, 0.0, v), Vector3::y()),
+                    (Vector3::new(u, v, 0.0), Vector3::z()),
+                ] {
+                    target.push(PointNormal(p.into(), n));
+                }
+            }
+        }
+
+        let expected = PoseCorrection {
+            rotation: Rotation3::from_euler_angles(0.02, -0.01, 0.03)
+                .into_inner(),
+            translation: Vector3::new(0.01, -0.02, 0.015),
+        };
+        let inverse = expected.rotation.transpose();
+        let source: Vec<_> = target
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let angle = i as f64 * 0.1;
+                let time_rot =
+                    Rotation3::from_axis_angle(&Vector3::z_axis(), angle)
+                        .into_inner();
+                let point = time_rot.transpose() * p.0.coords;
+                IcpPoint {
+                    point: inverse * (point - expected.translation),
+                    time_rot,
+                }
+            })
+            .collect();
+
+        let correction = find_pose_correction(&source, &target, 30, 0.1)
+            .unwrap()
+            .unwrap();
+        assert!((correction.rotation - expected.rotation).norm() < 1E-6);
+        assert!((correction.translation - expected.translation).norm() < 1E-6);
+
+        let far = [PointNormal(Point3::new(5.0, 5.0, 5.0), Vector3::z())];
+        assert!(find_pose_correction(&source, &far, 30, 0.1)
+            .unwrap()
+            .is_none());
+    }
 }
@@ -0,0 +1,247 @@
+use indexmap::IndexMap;
+use kiddo::distance::squared_euclidean;
+use kiddo::KdTree;
+use log::info;
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::misc::kdtree_err_to_err;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{
+    build_frame_clouds, distance_between_point_clouds, PointCloudParams,
+    PointNormal,
+};
+use crate::scan::{
+    apply_scan_poses, read_scans, read_scans_config, ScanParams,
+};
+use crate::threads::ThreadParams;
+use base::defs::Result;
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Print overlap matrices of scan clouds")]
+pub struct OverlapCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    params: OverlapParams,
+}
+
+impl OverlapCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        overlap(reader.as_mut(), &self.params)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct OverlapParams {
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(flatten)]
+    pub point_cloud: PointCloudParams,
+
+    #[structopt(flatten)]
+    pub threads: ThreadParams,
+
+    #[structopt(
+        help = "Maximum distance to a point of other scan to overlap",
+        long,
+        default_value = "0.01"
+    )]
+    pub overlap_distance: f64,
+}
+
+impl OverlapParams {
+    pub fn with_scans_config(&self) -> Result<OverlapParams> {
+        let mut params = self.clone();
+        if let Some(path) = &self.scan.scans_config {
+            let config = read_scans_config(path)?;
+            params.scan.merge_config(&config);
+            params.point_cloud.merge_config(&config);
+        }
+        Ok(params)
+    }
+}
+
+impl CheckParams for OverlapParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        check.require(self.overlap_distance > 0.0, || {
+            "--overlap-distance should be positive".to_string()
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Overlap {
+    // Fraction of points close to the other cloud.
+    pub ratio: f64,
+    // Average distance as minimized by optimize-scan-geometry.
+    pub distance: Option<f64>,
+}
+
+// Prints overlaps of scans with stored poses and then with the poses
+// overridden by calibration, scans config and flags (if any).
+pub fn overlap(
+    reader: &mut dyn fm::Read,
+    params: &OverlapParams,
+) -> Result<()> {
+    let params = &params.with_scans_config()?;
+    params.check()?;
+
+    info!("reading scans...");
+    let (stored, scan_frames) =
+        read_scans(reader, &params.scan.without_poses())?;
+    let mut current = stored.clone();
+    apply_scan_poses(&mut current, &params.scan)?;
+    params
+        .point_cloud
+        .validate(stored.keys().map(String::as_str))?;
+
+    let pool = params.threads.pool()?;
+    let names: Vec<&str> = stored.keys().map(String::as_str).collect();
+    let print = |title: &str, scans: &IndexMap<String, fm::Scan>| {
+        info!("building point clouds with {}...", title);
+        let clouds = pool.install(|| {
+            build_frame_clouds(scans, &scan_frames, &params.point_cloud)
+        })?;
+        let mut scan_clouds = IndexMap::<&str, Vec<PointNormal>>::new();
+        for name in &names {
+            scan_clouds.insert(name, Vec::new());
+        }
+        for (frame, cloud) in scan_frames.iter().zip(clouds) {
+            if let Some(points) = scan_clouds.get_mut(frame.scan.as_str()) {
+                points.extend(cloud);
+            }
+        }
+
+        info!("matching scan clouds with {}...", title);
+        let clouds: Vec<_> = scan_clouds.into_values().collect();
+        let matrix =
+            pool.install(|| overlap_matrix(&clouds, params.overlap_distance))?;
+
+        println!("{} (overlap, %):", title);
+        print_matrix(&names, |i, j| {
+            format!("{:.1}", matrix[i][j].ratio * 100.0)
+        });
+        println!("{} (average distance):", title);
+        print_matrix(&names, |i, j| match matrix[i][j].distance {
+            Some(distance) => format!("{:.4}", distance),
+            None => "n/a".to_string(),
+        });
+        Ok(())
+    };
+
+    print("stored poses", &stored)?;
+    if current != stored {
+        print("current poses", &current)?;
+    } else {
+        println!("current poses are the stored ones");
+    }
+
+    Ok(())
+}
+
+// Computes overlap of each cloud (by row) with each other one (by column).
+pub fn overlap_matrix(
+    clouds: &[Vec<PointNormal>],
+    max_distance: f64,
+) -> Result<Vec<Vec<Overlap>>> {
+    let max_sq_dist = max_distance * max_distance;
+    let mut matrix = Vec::with_capacity(clouds.len());
+    for a in clouds {
+        let mut row = Vec::with_capacity(clouds.len());
+        for b in clouds {
+            let mut kdtree = KdTree::new();
+            for p in b {
+                kdtree
+                    .add(p.0.coords.as_ref(), ())
+                    .map_err(kdtree_err_to_err)?;
+            }
+
+            let num_close = if b.is_empty() {
+                0
+            } else {
+                let is_close = |p: &PointNormal| {
+                    let point = p.0.coords.as_ref();
+                    let nearest = kdtree
+                        .nearest(point, 1, &squared_euclidean)
+                        .map_err(kdtree_err_to_err)?;
+                    Ok(nearest[0].0 <= max_sq_dist)
+                };
+                a.par_iter()
+                    .map(is_close)
+                    .collect::<Result<Vec<bool>>>()?
+                    .into_iter()
+                    .filter(|c| *c)
+                    .count()
+            };
+
+            row.push(Overlap {
+                ratio: num_close as f64 / a.len().max(1) as f64,
+                distance: distance_between_point_clouds(a, b)?,
+            });
+        }
+        matrix.push(row);
+    }
+    Ok(matrix)
+}
+
+fn print_matrix<F: Fn(usize, usize) -> String>(names: &[&str], cell: F) {
+    let width = names.iter().map(|n| n.len()).max().unwrap_or(0).max(8);
+    let mut header = format!("{:width$}", "", width = width);
+    for name in names {
+        header += &format!(" {:>width$}", name, width = width);
+    }
+    println!("{}", header);
+    for (i, name) in names.iter().enumerate() {
+        let mut line = format!("{:width$}", name, width = width);
+        for j in 0..names.len() {
+            let value = if i == j { "-".to_string() } else { cell(i, j) };
+            line += &format!(" {:>width$}", value, width = width);
+        }
+        println!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::{Point3, Vector3};
+
+    fn create_cloud(from: usize, to: usize, z: f64) -> Vec<PointNormal> {
+        (from..to)
+            .map(|i| {
+                PointNormal(
+                    Point3::new(i as f64 * 0.01, 0.0, z),
+                    Vector3::new(0.0, 0.0, 1.0),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overlap_matrix() {
+        let clouds = vec![
+            create_cloud(0, 100, 0.0),
+            create_cloud(50, 100, 0.001),
+            create_cloud(0, 100, 1.0),
+            vec![],
+        ];
+        let matrix = overlap_matrix(&clouds, 0.005).unwrap();
+
+        assert_eq!(matrix[0][1].ratio, 0.5);
+        assert_eq!(matrix[1][0].ratio, 1.0);
+        assert!((matrix[1][0].distance.unwrap() - 0.001).abs() < 1e-9);
+        assert_eq!(matrix[0][2].ratio, 0.0);
+        assert!((matrix[0][2].distance.unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(matrix[0][3].ratio, 0.0);
+        assert!(matrix[0][3].distance.is_none());
+        assert_eq!(matrix[3][0].ratio, 0.0);
+    }
+}
@@ -0,0 +1,86 @@
+use base::defs::{Error, ErrorKind::*, Result};
+
+// Collects parameter clashes, so that all of them are reported at once.
+#[derive(Default)]
+pub struct ParamCheck {
+    problems: Vec<String>,
+}
+
+impl ParamCheck {
+    pub fn require<F: FnOnce() -> String>(&mut self, condition: bool, desc: F) {
+        if !condition {
+            self.problems.push(desc());
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self.problems.len() {
+            0 => Ok(()),
+            1 => Err(Error::new(BadOperation, self.problems[0].clone())),
+            _ => {
+                let desc = format!(
+                    "{} parameter problems:\n  {}",
+                    self.problems.len(),
+                    self.problems.join("\n  ")
+                );
+                Err(Error::new(BadOperation, desc))
+            }
+        }
+    }
+}
+
+pub trait CheckParams {
+    fn check_into(&self, check: &mut ParamCheck);
+
+    fn check(&self) -> Result<()> {
+        let mut check = ParamCheck::default();
+        self.check_into(&mut check);
+        check.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Range {
+        min: f64,
+        max: f64,
+    }
+
+    impl CheckParams for Range {
+        fn check_into(&self, check: &mut ParamCheck) {
+            check.require(self.min.is_finite(), || {
+                "min should be finite".to_string()
+            });
+            check.require(self.min <= self.max, || {
+                "min should not exceed max".to_string()
+            });
+        }
+    }
+
+    #[test]
+    fn test_check_params() {
+        assert!(Range { min: 0.0, max: 1.0 }.check().is_ok());
+
+        let err = Range {
+            min: 0.0,
+            max: -1.0,
+        }
+        .check()
+        .unwrap_err();
+        assert_eq!(err.description, "min should not exceed max");
+
+        let err = Range {
+            min: f64::NAN,
+            max: -1.0,
+        }
+        .check()
+        .unwrap_err();
+        assert_eq!(
+            err.description,
+            "2 parameter problems:\n  min should be finite\n  \
+             min should not exceed max"
+        );
+    }
+}
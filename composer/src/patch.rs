@@ -0,0 +1,182 @@
+use std::io;
+use std::path::PathBuf;
+
+use log::info;
+use structopt::StructOpt;
+
+use crate::telemetry;
+use base::defs::Result;
+use base::fm;
+use base::util::{cli, fs};
+use base::{define_raw_input, define_raw_output};
+
+define_raw_input!(PatchInput, "fmp");
+define_raw_output!(PatchOutput, "fmp");
+
+#[derive(StructOpt)]
+#[structopt(about = "Make patch turning one .fm file into another")]
+pub struct MakePatchCommand {
+    #[structopt(help = "Base .fm file the patch is applied to", long)]
+    base: PathBuf,
+
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: PatchOutput,
+}
+
+impl MakePatchCommand {
+    pub fn run(&self) -> Result<()> {
+        let base_input = cli::FmInput {
+            path: Some(self.base.clone()),
+            ..self.input
+        };
+        let mut base = base_input.get()?;
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        let stats =
+            fm::make_patch(base.as_mut(), reader.as_mut(), &mut writer)?;
+        report(&stats, "made");
+        Ok(())
+    }
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Apply patch to .fm file")]
+pub struct ApplyPatchCommand {
+    #[structopt(help = "Base .fm file the patch was made for", long)]
+    base: PathBuf,
+
+    #[structopt(flatten)]
+    images: cli::ImageParams,
+
+    #[structopt(flatten)]
+    input: PatchInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+}
+
+impl ApplyPatchCommand {
+    pub fn run(&self) -> Result<()> {
+        let base_input = cli::FmInput {
+            path: Some(self.base.clone()),
+            images: self.images,
+            limits: Default::default(),
+        };
+        let mut base = base_input.get()?;
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+
+        let stats =
+            fm::apply_patch(base.as_mut(), &mut reader, writer.as_mut())?;
+        report(&stats, "applied");
+        Ok(())
+    }
+}
+
+fn report(stats: &fm::PatchStats, action: &str) {
+    telemetry::count("copied_records", stats.num_copied);
+    telemetry::count("inserted_records", stats.num_inserted);
+    telemetry::count("base_images", stats.num_base_images);
+    info!(
+        "{} patch copying {} and inserting {} records \
+         (referring to {} base images)",
+        action, stats.num_copied, stats.num_inserted, stats.num_base_images
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+
+    fn new_frame(time: i64, image: u8) -> fm::Record {
+        new_scan_frame_rec(fm::ScanFrame {
+            scan: "a".to_string(),
+            time,
+            image: Some(fm::Image {
+                r#type: fm::image::Type::Png as i32,
+                data: vec![image; 100],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn new_view(element: &str, texture: u8) -> fm::Record {
+        new_element_view_rec(fm::ElementView {
+            element: element.to_string(),
+            texture: Some(fm::Image {
+                r#type: fm::image::Type::Png as i32,
+                data: vec![texture; 100],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn read_all(reader: &mut dyn fm::Read) -> Vec<fm::Record> {
+        let mut records = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            records.push(rec);
+        }
+        records
+    }
+
+    #[test]
+    fn test_patch() {
+        let base_records = [
+            new_frame(1, 1),
+            new_frame(2, 2),
+            new_frame(3, 3),
+            new_view("a", 4),
+        ];
+        // The second frame is dropped, the texture becomes the one of the
+        // first frame, a new view is appended.
+        let target_records = [
+            new_frame(1, 1),
+            new_frame(3, 3),
+            new_view("a", 1),
+            new_view("b", 5),
+        ];
+
+        let mut patch = Vec::new();
+        let stats = fm::make_patch(
+            &mut create_reader_with_records(&base_records),
+            &mut create_reader_with_records(&target_records),
+            &mut patch,
+        )
+        .unwrap();
+        let expected = fm::PatchStats {
+            num_copied: 2,
+            num_inserted: 2,
+            num_base_images: 1,
+        };
+        assert_eq!(stats, expected);
+
+        let mut writer = create_writer();
+        let stats = fm::apply_patch(
+            &mut create_reader_with_records(&base_records),
+            &mut patch.as_slice(),
+            &mut writer,
+        )
+        .unwrap();
+        assert_eq!(stats, expected);
+        let mut reader = writer_to_reader(writer);
+        assert_eq!(read_all(&mut reader), target_records);
+
+        // A patch doesn't apply to other files.
+        let res = fm::apply_patch(
+            &mut create_reader_with_records(&target_records),
+            &mut patch.as_slice(),
+            &mut create_writer(),
+        );
+        assert_eq!(
+            res.unwrap_err().kind,
+            base::defs::ErrorKind::InconsistentState
+        );
+    }
+}
@@ -1,20 +1,50 @@
-use std::f64::INFINITY;
+use std::borrow::Cow;
+use std::str::FromStr;
 
 use indexmap::IndexMap;
 use kiddo::distance::squared_euclidean;
 use kiddo::KdTree;
+use petgraph::algo::min_spanning_tree;
+use petgraph::data::FromElements;
+use petgraph::graph::{NodeIndex, UnGraph};
+use petgraph::visit::Bfs;
 use rand::rngs::StdRng;
 use rand::SeedableRng;
 use rayon::prelude::*;
 use structopt::StructOpt;
 
-use crate::misc::select_random;
+use crate::misc::{kdtree_err_to_err, select_random};
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::scan::{merge_scan_value, ScansConfig};
+use crate::texture::load_frame_image;
 use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
 use base::fm::scan_frame::DepthConfidence;
 use base::util::cli::parse_key_val;
 
-#[derive(Clone, StructOpt)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DepthUpsample {
+    None,
+    Color,
+}
+
+impl FromStr for DepthUpsample {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(DepthUpsample::None),
+            "color" => Ok(DepthUpsample::Color),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown depth upsampling (can be 'none' or 'color')"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
 pub struct PointCloudParams {
     #[structopt(
         help = "Minimum depth confidence",
@@ -84,6 +114,33 @@ pub struct PointCloudParams {
         short = "p"
     )]
     pub max_num_frame_points: Option<usize>,
+
+    #[structopt(
+        help = "Depth upsampling method (can be 'none' or 'color')",
+        long,
+        default_value = "none"
+    )]
+    pub depth_upsample: DepthUpsample,
+
+    #[structopt(
+        help = "Color range sigma for guided depth upsampling",
+        long,
+        default_value = "20"
+    )]
+    pub depth_upsample_color_sigma: f32,
+
+    #[structopt(
+        help = "Propagate consistent normal orientation across the cloud",
+        long
+    )]
+    pub orient_normals: bool,
+
+    #[structopt(
+        help = "Number of neighbors for normal orientation propagation",
+        long,
+        default_value = "10"
+    )]
+    pub orient_normals_num_neighbors: usize,
 }
 
 impl PointCloudParams {
@@ -103,6 +160,11 @@ impl PointCloudParams {
             .unwrap_or(self.max_z)
     }
 
+    pub fn merge_config(&mut self, config: &ScansConfig) {
+        merge_scan_value(&mut self.scan_min_zs, config, |c| c.min_z);
+        merge_scan_value(&mut self.scan_max_zs, config, |c| c.max_z);
+    }
+
     pub fn validate<'a, S>(&self, scans: S) -> Result<()>
     where
         S: Iterator<Item = &'a str> + Clone,
@@ -127,7 +189,49 @@ impl PointCloudParams {
     }
 }
 
+impl CheckParams for PointCloudParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.min_z <= self.max_z, || {
+            format!(
+                "--min-z {} exceeds --max-z {}, no points would remain",
+                self.min_z, self.max_z
+            )
+        });
+        for (scan, _) in self.scan_min_zs.iter().chain(&self.scan_max_zs) {
+            let (min_z, max_z) = (self.min_z(scan), self.max_z(scan));
+            check.require(min_z <= max_z, || {
+                format!(
+                    "minimum Z {} exceeds maximum Z {} for scan '{}' \
+                     (see --scan-min-z and --scan-max-z)",
+                    min_z, max_z, scan
+                )
+            });
+        }
+        check.require(self.max_z_distance > 0.0, || {
+            "--max-z-distance should be positive".to_string()
+        });
+        check.require(self.outlier_num_neighbors > 0, || {
+            "--outlier-num-neighbors should be positive".to_string()
+        });
+        check.require(self.outlier_std_ratio > 0.0, || {
+            "--outlier-std-ratio should be positive \
+             (use 'inf' to disable outlier removal)"
+                .to_string()
+        });
+        check.require(self.max_num_frame_points != Some(0), || {
+            "--max-num-frame-points should be positive".to_string()
+        });
+        check.require(self.depth_upsample_color_sigma > 0.0, || {
+            "--depth-upsample-color-sigma should be positive".to_string()
+        });
+        check.require(self.orient_normals_num_neighbors > 0, || {
+            "--orient-normals-num-neighbors should be positive".to_string()
+        });
+    }
+}
+
 pub type Point3 = nalgebra::Point3<f64>;
+pub type Matrix3 = nalgebra::Matrix3<f64>;
 pub type Matrix4 = nalgebra::Matrix4<f64>;
 pub type Vector3 = nalgebra::Vector3<f64>;
 pub type Vector4 = nalgebra::Vector4<f64>;
@@ -137,6 +241,85 @@ fn fm_point3_to_point3(p: &fm::Point3) -> Point3 {
     Point3::new(p.x as f64, p.y as f64, p.z as f64)
 }
 
+// Rotation from camera space (looking along -Z) to scene space at zero
+// turntable angle.
+pub fn camera_view_rotation(scan: &fm::Scan) -> Result<Matrix4> {
+    let eye =
+        fm_point3_to_point3(&scan.camera_initial_position.unwrap_or_default());
+    let dir =
+        fm_point3_to_point3(&scan.camera_initial_direction.unwrap_or_default());
+    let up_rot = Quaternion::from_axis_angle(
+        &Vector3::z_axis(),
+        scan.camera_up_angle as f64,
+    );
+    let look_rot =
+        Matrix4::look_at_rh(&eye, &dir, &Vector3::new(0.0, 0.0, 1.0));
+    Ok(look_rot.try_inverse().ok_or_else(|| {
+        let desc =
+            format!("degenerate camera geometry for scan '{}'", scan.name);
+        Error::new(GeometryError, desc)
+    })? * Matrix4::from(up_rot))
+}
+
+fn vector3_to_fm_point3(v: &Vector3) -> fm::Point3 {
+    fm::Point3 {
+        x: v.x as f32,
+        y: v.y as f32,
+        z: v.z as f32,
+    }
+}
+
+// Inverse of camera_view_rotation for given camera-to-scene rotation.
+pub fn set_camera_pose(
+    scan: &mut fm::Scan,
+    rotation: &Matrix3,
+    eye: &Vector3,
+) -> Result<()> {
+    let dir = rotation * Vector3::new(0.0, 0.0, -1.0);
+    if dir.z.abs() > 1.0 - 1e-6 {
+        let desc =
+            format!("vertical camera direction for scan '{}'", scan.name);
+        return Err(Error::new(GeometryError, desc));
+    }
+
+    let target = eye + dir;
+    let look_rot = Matrix4::look_at_rh(
+        &Point3::from(*eye),
+        &Point3::from(target),
+        &Vector3::new(0.0, 0.0, 1.0),
+    );
+    let up_rot = look_rot.fixed_slice::<3, 3>(0, 0) * rotation;
+
+    scan.camera_initial_position = Some(vector3_to_fm_point3(eye));
+    scan.camera_initial_direction = Some(vector3_to_fm_point3(&target));
+    scan.camera_up_angle = up_rot[(1, 0)].atan2(up_rot[(0, 0)]) as f32;
+    Ok(())
+}
+
+// Camera space point of depth map pixel at given row and column, where tan
+// is a tangent of half the angle of view.
+pub fn unproject_depth(
+    scan: &fm::Scan,
+    tan: f64,
+    (width, height): (usize, usize),
+    (i, j): (usize, usize),
+    mut depth: f64,
+) -> Vector3 {
+    let width = width as f64;
+    let w = j as f64 - width / 2.0;
+    let h = i as f64 - height as f64 / 2.0;
+
+    let u = w / (width / 2.0) * tan;
+    let v = h / (width / 2.0) * tan;
+
+    // If depth sensor measures distance rather than depth.
+    if !scan.sensor_plane_depth {
+        depth /= (1.0 + u * u + v * v).sqrt();
+    }
+
+    depth * Vector3::new(u, -v, -1.0)
+}
+
 #[derive(Clone, Copy)]
 pub struct PointNormal(pub Point3, pub Vector3);
 
@@ -144,35 +327,51 @@ pub fn build_point_cloud(
     scan: &fm::Scan,
     frame: &fm::ScanFrame,
     params: &PointCloudParams,
-) -> Vec<PointNormal> {
+) -> Result<Vec<PointNormal>> {
     if frame.depths.is_empty() {
-        return vec![];
+        return Ok(vec![]);
     }
 
-    let depth_width = scan.depth_width as usize;
-    let depth_height = scan.depth_height as usize;
+    let mut depth_width = scan.depth_width as usize;
+    let mut depth_height = scan.depth_height as usize;
+    let mut depths = Cow::Borrowed(&frame.depths);
+    let mut depth_confidences = Cow::Borrowed(&frame.depth_confidences);
+
+    // Cropped images don't cover the whole depth map.
+    if params.depth_upsample == DepthUpsample::Color
+        && frame.image_crop.is_none()
+    {
+        if let Some(image) = load_frame_image(frame) {
+            let (width, height) = image.dimensions();
+            if width as usize > depth_width && height as usize > depth_height {
+                let (ds, cs) = upsample_depths(
+                    &frame.depths,
+                    &frame.depth_confidences,
+                    depth_width,
+                    depth_height,
+                    &image,
+                    params.depth_upsample_color_sigma as f64,
+                );
+                depths = Cow::Owned(ds);
+                depth_confidences = Cow::Owned(cs);
+                depth_width = width as usize;
+                depth_height = height as usize;
+            }
+        }
+    }
 
     // Normal calculation is based on deltas.
     if depth_width < 2 || depth_height < 2 {
-        return vec![];
+        return Ok(vec![]);
     }
 
     let tan = (scan.camera_angle_of_view as f64 / 2.0).tan();
 
     let eye =
         fm_point3_to_point3(&scan.camera_initial_position.unwrap_or_default());
-    let dir =
-        fm_point3_to_point3(&scan.camera_initial_direction.unwrap_or_default());
-    let up_rot = Quaternion::from_axis_angle(
-        &Vector3::z_axis(),
-        scan.camera_up_angle as f64,
-    );
-    let look_rot =
-        Matrix4::look_at_rh(&eye, &dir, &Vector3::new(0.0, 0.0, 1.0));
-    let view_rot = look_rot.try_inverse().unwrap() * Matrix4::from(up_rot);
+    let view_rot = camera_view_rotation(scan)?;
 
-    let camera_angle =
-        frame.time as f64 / 1E9 * scan.camera_angular_velocity as f64;
+    let camera_angle = fm::camera_angle(scan, frame);
     let time_rot =
         Quaternion::from_axis_angle(&Vector3::z_axis(), camera_angle);
 
@@ -182,20 +381,15 @@ pub fn build_point_cloud(
     for i in 0..depth_height {
         for j in 0..depth_width {
             let depth_index = i * depth_width + j;
-            let mut depth = frame.depths[depth_index] as f64;
-            let depth_width_f64 = depth_width as f64;
-            let w = j as f64 - depth_width_f64 / 2.0;
-            let h = i as f64 - scan.depth_height as f64 / 2.0;
-
-            let u = w / (depth_width_f64 / 2.0) * tan;
-            let v = h / (depth_width_f64 / 2.0) * tan;
-
-            // If depth sensor measures distance rather than depth.
-            if !scan.sensor_plane_depth {
-                depth /= (1.0 + u * u + v * v).sqrt();
-            }
-
-            let focus_to_object = depth * Vector4::new(u, -v, -1.0, 0.0);
+            let depth = depths[depth_index] as f64;
+            let focus_to_object = unproject_depth(
+                scan,
+                tan,
+                (depth_width, depth_height),
+                (i, j),
+                depth,
+            )
+            .to_homogeneous();
             let point = (view_rot * focus_to_object).xyz() + eye.coords;
             points.push(Point3::from(time_rot * point));
         }
@@ -205,12 +399,12 @@ pub fn build_point_cloud(
     for i in 0..depth_height {
         for j in 0..depth_width {
             let depth_index = i * depth_width + j;
-            let depth = frame.depths[depth_index];
+            let depth = depths[depth_index];
             if depth.is_nan() || depth.is_infinite() {
                 continue;
             }
 
-            let confidence = frame.depth_confidences[depth_index];
+            let confidence = depth_confidences[depth_index];
             if confidence < params.min_depth_confidence as i32 {
                 continue;
             }
@@ -242,18 +436,114 @@ pub fn build_point_cloud(
         }
     }
 
-    point_normals
+    Ok(point_normals)
+}
+
+// Joint bilateral upsampling of a depth map to the resolution of the color
+// image: each output depth is a weighted average of nearby low-resolution
+// depths, where weights fall off with both spatial distance and difference
+// of colors, so that depth discontinuities snap to image edges.
+pub fn upsample_depths(
+    depths: &[f32],
+    depth_confidences: &[i32],
+    depth_width: usize,
+    depth_height: usize,
+    image: &image::RgbImage,
+    color_sigma: f64,
+) -> (Vec<f32>, Vec<i32>) {
+    const RADIUS: isize = 1;
+    const SPATIAL_SIGMA: f64 = 0.5;
+
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let (sx, sy) = (
+        depth_width as f64 / width as f64,
+        depth_height as f64 / height as f64,
+    );
+
+    let color_at = |x: usize, y: usize| {
+        let p = image.get_pixel(x as u32, y as u32);
+        [p[0] as f64, p[1] as f64, p[2] as f64]
+    };
+
+    let mut out_depths = Vec::with_capacity(width * height);
+    let mut out_confidences = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) =
+                ((x as f64 + 0.5) * sx - 0.5, (y as f64 + 0.5) * sy - 0.5);
+            let (ci, cj) = (dy.round() as isize, dx.round() as isize);
+            let color = color_at(x, y);
+
+            let mut sum = 0.0;
+            let mut weight_sum = 0.0;
+            let mut confidence = 0;
+            let mut nearest_dist = f64::INFINITY;
+            for i in ci - RADIUS..=ci + RADIUS {
+                for j in cj - RADIUS..=cj + RADIUS {
+                    if i < 0
+                        || j < 0
+                        || i >= depth_height as isize
+                        || j >= depth_width as isize
+                    {
+                        continue;
+                    }
+
+                    let index = i as usize * depth_width + j as usize;
+                    let depth = depths[index] as f64;
+                    if !depth.is_finite() {
+                        continue;
+                    }
+
+                    let spatial_dist =
+                        (i as f64 - dy).powi(2) + (j as f64 - dx).powi(2);
+                    if spatial_dist < nearest_dist {
+                        nearest_dist = spatial_dist;
+                        confidence = depth_confidences[index];
+                    }
+
+                    let (gx, gy) = (
+                        (((j as f64 + 0.5) / sx) as usize).min(width - 1),
+                        (((i as f64 + 0.5) / sy) as usize).min(height - 1),
+                    );
+                    let guide = color_at(gx, gy);
+                    let color_dist = (0..3)
+                        .map(|k| (color[k] - guide[k]).powi(2))
+                        .sum::<f64>();
+
+                    let weight = (-spatial_dist
+                        / (2.0 * SPATIAL_SIGMA * SPATIAL_SIGMA)
+                        - color_dist / (2.0 * color_sigma * color_sigma))
+                        .exp();
+                    sum += weight * depth;
+                    weight_sum += weight;
+                }
+            }
+
+            if weight_sum > 0.0 {
+                out_depths.push((sum / weight_sum) as f32);
+                out_confidences.push(confidence);
+            } else {
+                out_depths.push(f32::NAN);
+                out_confidences.push(DepthConfidence::None as i32);
+            }
+        }
+    }
+
+    (out_depths, out_confidences)
 }
 
 pub fn build_frame_clouds(
     scans: &IndexMap<String, fm::Scan>,
     scan_frames: &[fm::ScanFrame],
     params: &PointCloudParams,
-) -> Vec<Vec<PointNormal>> {
+) -> Result<Vec<Vec<PointNormal>>> {
     let mut clouds = Vec::new();
     for frame in scan_frames {
-        let scan = scans.get(&frame.scan).unwrap();
-        clouds.push(build_point_cloud(scan, frame, params))
+        let scan = scans.get(&frame.scan).ok_or_else(|| {
+            let desc = format!("frame for unknown scan '{}'", &frame.scan);
+            Error::new(InconsistentState, desc)
+        })?;
+        clouds.push(build_point_cloud(scan, frame, params)?)
     }
 
     if let Some(max_num_frame_points) = params.max_num_frame_points {
@@ -264,29 +554,35 @@ pub fn build_frame_clouds(
         &mut clouds,
         params.outlier_num_neighbors,
         params.outlier_std_ratio as f64,
-    );
+    )?;
 
-    clouds
+    if params.orient_normals {
+        orient_normals(&mut clouds, params.orient_normals_num_neighbors)?;
+    }
+
+    Ok(clouds)
 }
 
 pub fn distance_between_point_clouds(
     a: &[PointNormal],
     b: &[PointNormal],
-) -> Option<f64> {
+) -> Result<Option<f64>> {
     if a.is_empty() || b.is_empty() {
-        return None;
+        return Ok(None);
     }
 
     let mut kdtree = KdTree::new();
     for (i, p) in a.iter().enumerate() {
-        kdtree.add(p.0.coords.as_ref(), i).unwrap();
+        kdtree
+            .add(p.0.coords.as_ref(), i)
+            .map_err(kdtree_err_to_err)?;
     }
 
-    let mut dists = vec![INFINITY; a.len()];
+    let mut dists = vec![f64::INFINITY; a.len()];
     for p in b {
         let (dist, i) = kdtree
             .nearest(p.0.coords.as_ref(), 1, &squared_euclidean)
-            .unwrap()[0];
+            .map_err(kdtree_err_to_err)?[0];
         if dist < dists[*i] {
             dists[*i] = dist;
         }
@@ -307,10 +603,10 @@ pub fn distance_between_point_clouds(
     dists.truncate(dists.len() * 95 / 100);
 
     if dists.is_empty() {
-        None
+        Ok(None)
     } else {
         let sum = dists.iter().map(|d| d.sqrt()).sum::<f64>();
-        Some(sum / dists.len() as f64)
+        Ok(Some(sum / dists.len() as f64))
     }
 }
 
@@ -318,19 +614,21 @@ fn remove_outliers(
     clouds: &mut [Vec<PointNormal>],
     num_neighbors: usize,
     std_ratio: f64,
-) {
+) -> Result<()> {
     if std_ratio.is_infinite() {
-        return;
+        return Ok(());
     }
 
     let num_points = clouds.iter().map(Vec::len).sum::<usize>();
     if num_points < 1 + num_neighbors {
-        return;
+        return Ok(());
     }
 
-    let mut kdtree = KdTree::with_capacity(200).unwrap();
+    let mut kdtree = KdTree::with_capacity(200).map_err(kdtree_err_to_err)?;
     for point in clouds.iter().flatten() {
-        kdtree.add(point.0.coords.as_ref(), ()).unwrap();
+        kdtree
+            .add(point.0.coords.as_ref(), ())
+            .map_err(kdtree_err_to_err)?;
     }
 
     let local_deviation = |point: &PointNormal| {
@@ -340,13 +638,16 @@ fn remove_outliers(
                 1 + num_neighbors,
                 &squared_euclidean,
             )
-            .unwrap();
+            .map_err(kdtree_err_to_err)?;
 
-        nearest.iter().map(|p| p.0.sqrt()).sum::<f64>() / num_neighbors as f64
+        Ok(nearest.iter().map(|p| p.0.sqrt()).sum::<f64>()
+            / num_neighbors as f64)
     };
     let avgs: Vec<f64> = (0..clouds.len())
         .into_par_iter()
-        .map(|i| clouds[i].iter().map(local_deviation).collect::<Vec<f64>>())
+        .map(|i| clouds[i].iter().map(local_deviation).collect::<Result<_>>())
+        .collect::<Result<Vec<Vec<f64>>>>()?
+        .into_iter()
         .flatten()
         .collect();
 
@@ -368,6 +669,78 @@ fn remove_outliers(
         }
         points.truncate(j);
     }
+
+    Ok(())
+}
+
+// Makes normal orientation consistent by propagating it along the minimum
+// spanning tree of neighborhood graph, which is weighted to prefer nearly
+// parallel normals (see Hoppe et al. "Surface Reconstruction from
+// Unorganized Points"). Each connected part is seeded with its point of
+// maximum X, which normal is expected to look towards +X.
+pub fn orient_normals(
+    clouds: &mut [Vec<PointNormal>],
+    num_neighbors: usize,
+) -> Result<()> {
+    let mut points: Vec<&mut PointNormal> =
+        clouds.iter_mut().flatten().collect();
+    if points.is_empty() {
+        return Ok(());
+    }
+
+    let mut kdtree = KdTree::new();
+    for (i, p) in points.iter().enumerate() {
+        kdtree
+            .add(p.0.coords.as_ref(), i)
+            .map_err(kdtree_err_to_err)?;
+    }
+
+    let mut graph = UnGraph::<(), f64>::with_capacity(
+        points.len(),
+        points.len() * num_neighbors,
+    );
+    for _ in 0..points.len() {
+        graph.add_node(());
+    }
+    for (i, p) in points.iter().enumerate() {
+        let nearest = kdtree
+            .nearest(p.0.coords.as_ref(), 1 + num_neighbors, &squared_euclidean)
+            .map_err(kdtree_err_to_err)?;
+        for (_, &j) in nearest {
+            if j != i {
+                let weight = 1.0 - p.1.dot(&points[j].1).abs();
+                graph.add_edge(NodeIndex::new(i), NodeIndex::new(j), weight);
+            }
+        }
+    }
+    let tree = UnGraph::<(), f64>::from_elements(min_spanning_tree(&graph));
+
+    let mut seeds: Vec<usize> = (0..points.len()).collect();
+    seeds.sort_by(|&a, &b| points[b].0.x.partial_cmp(&points[a].0.x).unwrap());
+
+    let mut visited = vec![false; points.len()];
+    for seed in seeds {
+        if visited[seed] {
+            continue;
+        }
+        if points[seed].1.x < 0.0 {
+            points[seed].1 = -points[seed].1;
+        }
+
+        let mut bfs = Bfs::new(&tree, NodeIndex::new(seed));
+        while let Some(node) = bfs.next(&tree) {
+            let i = node.index();
+            visited[i] = true;
+            for neighbor in tree.neighbors(node) {
+                let j = neighbor.index();
+                if !visited[j] && points[i].1.dot(&points[j].1) < 0.0 {
+                    points[j].1 = -points[j].1;
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 fn select_random_points(
@@ -407,7 +780,10 @@ mod test {
 
     #[test]
     fn test_distance_between_point_clouds() {
-        assert_eq!(distance_between_point_clouds(&vec![], &vec![]), None);
+        assert_eq!(
+            distance_between_point_clouds(&vec![], &vec![]).unwrap(),
+            None
+        );
 
         let a = vec![
             new_point_normal(1.0, 0.0, 0.0),
@@ -420,6 +796,70 @@ mod test {
             new_point_normal(10.0, 0.0, 0.0),
             new_point_normal(21.0, 0.0, 0.0),
         ];
-        assert_approx_eq!(distance_between_point_clouds(&a, &b).unwrap(), 1.0);
+        assert_approx_eq!(
+            distance_between_point_clouds(&a, &b).unwrap().unwrap(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_orient_normals() {
+        // Unit sphere with every third normal flipped inwards.
+        let mut cloud = Vec::new();
+        for i in 0..20 {
+            let theta = (i as f64 + 0.5) / 20.0 * std::f64::consts::PI;
+            for j in 0..40 {
+                let phi = j as f64 / 40.0 * 2.0 * std::f64::consts::PI;
+                let normal = Vector3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let sign = if cloud.len() % 3 == 0 { -1.0 } else { 1.0 };
+                cloud.push(PointNormal(Point3::from(normal), sign * normal));
+            }
+        }
+
+        let mut clouds = vec![cloud.clone(), vec![]];
+        orient_normals(&mut clouds, 10).unwrap();
+        for p in &clouds[0] {
+            assert_approx_eq!(p.1.dot(&p.0.coords), 1.0);
+        }
+
+        // Inner sphere is a separate part, also looking outwards.
+        let inner = cloud
+            .iter()
+            .map(|p| PointNormal(Point3::from(p.0.coords * 0.2), p.1))
+            .collect();
+        let mut clouds = vec![cloud, inner];
+        orient_normals(&mut clouds, 10).unwrap();
+        for p in clouds.iter().flatten() {
+            assert!(p.1.dot(&p.0.coords) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_upsample_depths() {
+        // Left half of the image is black, right half is white, while the
+        // depth map has a step in the middle of its single row.
+        let image = image::RgbImage::from_fn(4, 2, |x, _| {
+            if x < 2 {
+                image::Rgb([0, 0, 0])
+            } else {
+                image::Rgb([255, 255, 255])
+            }
+        });
+        let depths = [1.0, 2.0];
+        let confidences = [3, 2];
+
+        let (ds, cs) =
+            upsample_depths(&depths, &confidences, 2, 1, &image, 20.0);
+
+        assert_eq!(ds.len(), 8);
+        assert_eq!(cs, vec![3, 3, 2, 2, 3, 3, 2, 2]);
+        let expected = [1.0, 1.0, 2.0, 2.0, 1.0, 1.0, 2.0, 2.0];
+        for (d, e) in ds.iter().zip(expected) {
+            assert!((d - e).abs() < 1E-3);
+        }
     }
 }
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::mem::transmute;
 use std::os::raw;
 use std::str::FromStr;
@@ -5,10 +6,12 @@ use std::str::FromStr;
 use num::traits::Float;
 use structopt::StructOpt;
 
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::threads::{parse_threads, resolve_threads, AUTO};
 use base::defs::{Error, ErrorKind::*, Result};
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub enum BoundaryType {
     Free,
@@ -32,7 +35,7 @@ impl FromStr for BoundaryType {
     }
 }
 
-#[derive(Clone, Copy, StructOpt)]
+#[derive(Clone, Copy, Debug, StructOpt)]
 #[repr(C)]
 pub struct Params {
     // Boundary type for the finite elements.
@@ -156,11 +159,12 @@ pub struct Params {
     pub linear_fit: bool,
 
     // This parameter specifies the number of threads across which the solver
-    // should be parallelized.
+    // should be parallelized. Zero stands for the size of current rayon pool.
     #[structopt(
-        help = "Number of threads to be used by Poisson.",
+        help = "Number of threads to be used by Poisson (or auto).",
         long = "poisson-threads",
-        default_value = "1"
+        default_value = "auto",
+        parse(try_from_str = parse_threads)
     )]
     pub threads: raw::c_int,
 
@@ -214,7 +218,7 @@ impl Default for Params {
             normal_confidence: 0.0,
             normal_confidence_bias: 0.0,
             linear_fit: false,
-            threads: 1,
+            threads: AUTO as raw::c_int,
             full_depth: 5,
             base_depth: 0,
             base_v_cycles: 1,
@@ -223,6 +227,39 @@ impl Default for Params {
     }
 }
 
+impl CheckParams for Params {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.depth >= 0, || {
+            "--poisson-depth should not be negative".to_string()
+        });
+        check.require(self.depth == 0 || self.finest_cell_width <= 0.0, || {
+            "--poisson-finest-cell-width is ignored unless \
+             --poisson-depth is set to 0"
+                .to_string()
+        });
+        check.require(self.depth != 0 || self.finest_cell_width > 0.0, || {
+            "--poisson-finest-cell-width should be positive \
+             when --poisson-depth is 0"
+                .to_string()
+        });
+        check.require(self.full_depth <= self.depth || self.depth == 0, || {
+            format!(
+                "--poisson-full-depth {} exceeds --poisson-depth {}",
+                self.full_depth, self.depth
+            )
+        });
+        check.require(self.scale >= 1.0, || {
+            "--poisson-scale should be at least 1".to_string()
+        });
+        check.require(self.samples_per_node > 0.0, || {
+            "--poisson-samples-per-node should be positive".to_string()
+        });
+        check.require(self.threads >= 0, || {
+            "--poisson-threads should not be negative".to_string()
+        });
+    }
+}
+
 pub trait Cloud<F: Float> {
     fn len(&self) -> usize;
     fn has_normals(&self) -> bool {
@@ -248,11 +285,41 @@ pub trait Mesh<F: Float> {
     fn add_triangle(&mut self, triangle: &[usize; 3]);
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum Stage {
+    Octree,
+    Solver,
+    Extraction,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Octree => "octree",
+            Stage::Solver => "solver",
+            Stage::Extraction => "extraction",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub trait Progress {
+    // Reports number of items (points for octree, vertices for extraction)
+    // processed at given stage, with total being 0 if unknown. Each stage
+    // starts with a report of 0 done items.
+    fn report(&mut self, _stage: Stage, _done: usize, _total: usize) {}
+}
+
+impl Progress for () {}
+
 pub trait Reconstruct<F: Float> {
     fn reconstruct(
         params: &Params,
         cloud: &dyn Cloud<F>,
         mesh: &mut dyn Mesh<F>,
+        progress: &mut dyn Progress,
     ) -> bool;
 }
 
@@ -261,9 +328,15 @@ impl Reconstruct<f32> for f32 {
         params: &Params,
         cloud: &dyn Cloud<f32>,
         mesh: &mut dyn Mesh<f32>,
+        progress: &mut dyn Progress,
     ) -> bool {
         unsafe {
-            poisson_reconstruct32(params, transmute(cloud), transmute(mesh))
+            poisson_reconstruct32(
+                params,
+                transmute(cloud),
+                transmute(mesh),
+                transmute::<&mut dyn Progress, TraitObj>(progress),
+            )
         }
     }
 }
@@ -273,9 +346,15 @@ impl Reconstruct<f64> for f64 {
         params: &Params,
         cloud: &dyn Cloud<f64>,
         mesh: &mut dyn Mesh<f64>,
+        progress: &mut dyn Progress,
     ) -> bool {
         unsafe {
-            poisson_reconstruct64(params, transmute(cloud), transmute(mesh))
+            poisson_reconstruct64(
+                params,
+                transmute(cloud),
+                transmute(mesh),
+                transmute::<&mut dyn Progress, TraitObj>(progress),
+            )
         }
     }
 }
@@ -284,8 +363,11 @@ pub fn reconstruct<F: Float + Reconstruct<F>>(
     params: &Params,
     cloud: &dyn Cloud<F>,
     mesh: &mut dyn Mesh<F>,
+    progress: &mut dyn Progress,
 ) -> bool {
-    F::reconstruct(params, cloud, mesh)
+    let mut params = *params;
+    params.threads = resolve_threads(params.threads as usize) as raw::c_int;
+    F::reconstruct(&params, cloud, mesh, progress)
 }
 
 #[repr(C)]
@@ -296,12 +378,14 @@ extern "C" {
         params: &Params,
         cloud: TraitObj,
         mesh: TraitObj,
+        progress: TraitObj,
     ) -> bool;
 
     fn poisson_reconstruct64(
         params: &Params,
         cloud: TraitObj,
         mesh: TraitObj,
+        progress: TraitObj,
     ) -> bool;
 }
 
@@ -466,3 +550,14 @@ pub unsafe extern "C" fn poisson_mesh64_add_triangle(
 ) {
     transmute::<TraitObj, &mut dyn Mesh<f64>>(mesh).add_triangle(&[i1, i2, i3]);
 }
+
+#[no_mangle]
+pub unsafe extern "C" fn poisson_progress_report(
+    progress: TraitObj,
+    stage: Stage,
+    done: usize,
+    total: usize,
+) {
+    transmute::<TraitObj, &mut dyn Progress>(progress)
+        .report(stage, done, total);
+}
@@ -40,12 +40,80 @@ void poisson_mesh64_add_normal(mesh64 mesh, const double *coords);
 void poisson_mesh64_add_color(mesh64 mesh, const double *rgb);
 void poisson_mesh64_add_density(mesh64 mesh, double d);
 void poisson_mesh64_add_triangle(mesh64 mesh, size_t i1, size_t i2, size_t i3);
+
+typedef trait_obj progress;
+
+enum progress_stage {
+  PROGRESS_STAGE_OCTREE,
+  PROGRESS_STAGE_SOLVER,
+  PROGRESS_STAGE_EXTRACTION,
+};
+
+void poisson_progress_report(progress progress, progress_stage stage,
+                             size_t done, size_t total);
 }
 
 namespace {
 
+// The library has no progress hooks, so stages are inferred from the order
+// it accesses cloud and mesh: points are read while building the octree,
+// the solver runs after the last point is read and the iso-surface is
+// extracted while vertices are being added.
+class Tracker {
+public:
+  explicit Tracker(progress progress) : progress_(progress) {}
+
+  void onPoint(size_t index, size_t size) {
+    if (stage_ != PROGRESS_STAGE_OCTREE || index < done_) {
+      return;
+    }
+    size_t done = index + 1;
+    bool step = done * 100 / size != done_ * 100 / size;
+    done_ = done;
+    if (step) {
+      report(size);
+    }
+    if (done == size) {
+      enter(PROGRESS_STAGE_SOLVER);
+    }
+  }
+
+  void onVertex() {
+    if (stage_ != PROGRESS_STAGE_EXTRACTION) {
+      enter(PROGRESS_STAGE_EXTRACTION);
+    }
+    if (++done_ % kVertexStep == 0) {
+      report(0);
+    }
+  }
+
+  void finish() {
+    if (stage_ == PROGRESS_STAGE_EXTRACTION) {
+      report(done_);
+    }
+  }
+
+private:
+  static const size_t kVertexStep = 65536;
+
+  void enter(progress_stage stage) {
+    stage_ = stage;
+    done_ = 0;
+    report(0);
+  }
+
+  void report(size_t total) {
+    poisson_progress_report(progress_, stage_, done_, total);
+  }
+
+  progress progress_;
+  progress_stage stage_ = PROGRESS_STAGE_OCTREE;
+  size_t done_ = 0;
+};
+
 struct Cloud32 : public PoissonReconLib::ICloud<float> {
   cloud32 cloud;
+  Tracker *tracker;
 
   size_t size() const override { return poisson_cloud32_size(cloud); }
   bool hasNormals() const override {
@@ -53,6 +121,7 @@ struct Cloud32 : public PoissonReconLib::ICloud<float> {
   }
   bool hasColors() const { return poisson_cloud32_has_colors(cloud); }
   void getPoint(size_t index, float *coords) const override {
+    tracker->onPoint(index, size());
     poisson_cloud32_get_point(cloud, index, coords);
   }
   void getNormal(size_t index, float *coords) const override {
@@ -65,6 +134,7 @@ struct Cloud32 : public PoissonReconLib::ICloud<float> {
 
 struct Cloud64 : public PoissonReconLib::ICloud<double> {
   cloud64 cloud;
+  Tracker *tracker;
 
   size_t size() const override { return poisson_cloud64_size(cloud); }
   bool hasNormals() const override {
@@ -72,6 +142,7 @@ struct Cloud64 : public PoissonReconLib::ICloud<double> {
   }
   bool hasColors() const { return poisson_cloud64_has_colors(cloud); }
   void getPoint(size_t index, double *coords) const override {
+    tracker->onPoint(index, size());
     poisson_cloud64_get_point(cloud, index, coords);
   }
   void getNormal(size_t index, double *coords) const override {
@@ -84,8 +155,10 @@ struct Cloud64 : public PoissonReconLib::ICloud<double> {
 
 struct Mesh32 : public PoissonReconLib::IMesh<float> {
   mesh32 mesh;
+  Tracker *tracker;
 
   void addVertex(const float *coords) override {
+    tracker->onVertex();
     poisson_mesh32_add_vertex(mesh, coords);
   }
   void addNormal(const float *coords) override {
@@ -102,8 +175,10 @@ struct Mesh32 : public PoissonReconLib::IMesh<float> {
 
 struct Mesh64 : public PoissonReconLib::IMesh<double> {
   mesh64 mesh;
+  Tracker *tracker;
 
   void addVertex(const double *coords) override {
+    tracker->onVertex();
     poisson_mesh64_add_vertex(mesh, coords);
   }
   void addNormal(const double *coords) override {
@@ -123,19 +198,35 @@ struct Mesh64 : public PoissonReconLib::IMesh<double> {
 extern "C" {
 typedef PoissonReconLib::Parameters params;
 
-bool poisson_reconstruct32(const params *params, cloud32 cloud, mesh32 mesh) {
+bool poisson_reconstruct32(const params *params, cloud32 cloud, mesh32 mesh,
+                           progress progress) {
+  Tracker tracker(progress);
   Mesh32 mesh_wrapper;
   mesh_wrapper.mesh = mesh;
+  mesh_wrapper.tracker = &tracker;
   Cloud32 cloud_wrapper;
   cloud_wrapper.cloud = cloud;
-  return PoissonReconLib::Reconstruct(*params, cloud_wrapper, mesh_wrapper);
+  cloud_wrapper.tracker = &tracker;
+  bool ok = PoissonReconLib::Reconstruct(*params, cloud_wrapper, mesh_wrapper);
+  if (ok) {
+    tracker.finish();
+  }
+  return ok;
 }
 
-bool poisson_reconstruct64(const params *params, cloud32 cloud, mesh32 mesh) {
+bool poisson_reconstruct64(const params *params, cloud64 cloud, mesh64 mesh,
+                           progress progress) {
+  Tracker tracker(progress);
   Mesh64 mesh_wrapper;
   mesh_wrapper.mesh = mesh;
+  mesh_wrapper.tracker = &tracker;
   Cloud64 cloud_wrapper;
   cloud_wrapper.cloud = cloud;
-  return PoissonReconLib::Reconstruct(*params, cloud_wrapper, mesh_wrapper);
+  cloud_wrapper.tracker = &tracker;
+  bool ok = PoissonReconLib::Reconstruct(*params, cloud_wrapper, mesh_wrapper);
+  if (ok) {
+    tracker.finish();
+  }
+  return ok;
 }
 }
@@ -0,0 +1,171 @@
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, Rgb, RgbImage};
+
+use crate::mesh::Mesh;
+use crate::point_cloud::Vector3;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+
+const BACKGROUND: Rgb<u8> = Rgb([255, 255, 255]);
+
+// Pixel coordinates along with depth (lesser is nearer).
+pub type ScreenPoint = (f64, f64, f64);
+
+// Calls draw with barycentric coordinates for each triangle pixel which is
+// nearer than the one drawn before, depths are row-major for a square image.
+pub fn rasterize_triangle<F: FnMut(u32, u32, [f64; 3])>(
+    points: [ScreenPoint; 3],
+    size: u32,
+    depths: &mut [f64],
+    draw: F,
+) {
+    rasterize_triangle_rect(points, (size, size), depths, draw)
+}
+
+// Same as rasterize_triangle, but for an image of any shape.
+pub fn rasterize_triangle_rect<F: FnMut(u32, u32, [f64; 3])>(
+    [a, b, c]: [ScreenPoint; 3],
+    (width, height): (u32, u32),
+    depths: &mut [f64],
+    mut draw: F,
+) {
+    let area = (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0);
+    if area.abs() < f64::EPSILON || width == 0 || height == 0 {
+        return;
+    }
+
+    let (max_x, max_y) = (a.0.max(b.0).max(c.0), a.1.max(b.1).max(c.1));
+    if max_x < 0.0 || max_y < 0.0 {
+        return;
+    }
+    let x0 = a.0.min(b.0).min(c.0).floor().max(0.0) as u32;
+    let x1 = (max_x.ceil() as u32).min(width - 1);
+    let y0 = a.1.min(b.1).min(c.1).floor().max(0.0) as u32;
+    let y1 = (max_y.ceil() as u32).min(height - 1);
+
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+            let w0 = ((b.0 - px) * (c.1 - py) - (b.1 - py) * (c.0 - px)) / area;
+            let w1 = ((c.0 - px) * (a.1 - py) - (c.1 - py) * (a.0 - px)) / area;
+            let w2 = 1.0 - w0 - w1;
+            if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                continue;
+            }
+
+            let depth = w0 * a.2 + w1 * b.2 + w2 * c.2;
+            let index = (y * width + x) as usize;
+            if depth < depths[index] {
+                depths[index] = depth;
+                draw(x, y, [w0, w1, w2]);
+            }
+        }
+    }
+}
+
+// Renders a normal-shaded front view (looking along +Y with Z up)
+// of the mesh using orthographic projection and a depth buffer.
+pub fn render_preview(mesh: &Mesh, size: u32) -> RgbImage {
+    let mut image = RgbImage::from_pixel(size, size, BACKGROUND);
+    if mesh.vertices.is_empty() || size == 0 {
+        return image;
+    }
+
+    let (mut min_x, mut max_x) = (f64::INFINITY, f64::NEG_INFINITY);
+    let (mut min_z, mut max_z) = (f64::INFINITY, f64::NEG_INFINITY);
+    for v in mesh.vertices.iter() {
+        min_x = min_x.min(v.x);
+        max_x = max_x.max(v.x);
+        min_z = min_z.min(v.z);
+        max_z = max_z.max(v.z);
+    }
+
+    // Fit the bigger side into the image leaving a small margin.
+    let extent = (max_x - min_x).max(max_z - min_z).max(f64::EPSILON);
+    let scale = size as f64 * 0.9 / extent;
+    let (cx, cz) = ((min_x + max_x) / 2.0, (min_z + max_z) / 2.0);
+    let project = |i: usize| {
+        let v = mesh.vertices[i];
+        (
+            (v.x - cx) * scale + size as f64 / 2.0,
+            (cz - v.z) * scale + size as f64 / 2.0,
+            v.y,
+        )
+    };
+
+    let light = Vector3::new(0.3, -1.0, 0.5).normalize();
+    let mut depths = vec![f64::INFINITY; (size * size) as usize];
+
+    for face in mesh.faces.iter() {
+        let [a, b, c] = [project(face[0]), project(face[1]), project(face[2])];
+        let n = (mesh.normals[face[0]]
+            + mesh.normals[face[1]]
+            + mesh.normals[face[2]])
+            .normalize();
+        let shade = 0.2 + 0.8 * n.dot(&light).abs();
+        let color = Rgb([(shade * 220.0) as u8; 3]);
+
+        rasterize_triangle([a, b, c], size, &mut depths, |x, y, _| {
+            image.put_pixel(x, y, color)
+        });
+    }
+
+    image
+}
+
+pub fn encode_png(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> Result<fm::Image> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(data, width, height, color)
+        .map_err(|e| {
+            let desc = "failed to encode PNG image".to_string();
+            Error::with_source(ImageError, desc, e)
+        })?;
+
+    Ok(fm::Image {
+        r#type: fm::image::Type::Png as i32,
+        data: png,
+        ..Default::default()
+    })
+}
+
+pub fn create_preview(mesh: &Mesh, size: u32) -> Result<fm::Preview> {
+    let image = render_preview(mesh, size);
+    let (width, height) = image.dimensions();
+    Ok(fm::Preview {
+        image: Some(encode_png(
+            image.as_ref(),
+            width,
+            height,
+            ColorType::Rgb8,
+        )?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::Point3;
+
+    #[test]
+    fn test_render_preview() {
+        let mesh = Mesh {
+            vertices: vec![
+                Point3::new(-1.0, 0.0, -1.0),
+                Point3::new(1.0, 0.0, -1.0),
+                Point3::new(-1.0, 0.0, 1.0),
+            ],
+            normals: vec![Vector3::new(0.0, -1.0, 0.0); 3],
+            faces: vec![[0, 1, 2]],
+        };
+
+        let image = render_preview(&mesh, 10);
+        assert_eq!(*image.get_pixel(2, 7), Rgb([196, 196, 196]));
+        assert_eq!(*image.get_pixel(8, 1), BACKGROUND);
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use log::info;
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::telemetry;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Redact identifying metadata of .fm file for sharing")]
+pub struct RedactCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(flatten)]
+    params: RedactParams,
+}
+
+impl RedactCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        redact(reader.as_mut(), writer.as_mut(), &self.params)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct RedactParams {
+    #[structopt(
+        help = "Secret prepended to names before hashing (so that hashes of \
+                guessable names can't be looked up)",
+        long,
+        default_value = ""
+    )]
+    pub salt: String,
+
+    #[structopt(help = "Hash element names along with scan names", long)]
+    pub hash_elements: bool,
+
+    #[structopt(
+        help = "Resolution of frame times in seconds (0 keeps them as is)",
+        long,
+        default_value = "1"
+    )]
+    pub time_resolution: f64,
+
+    #[structopt(help = "Keep image metadata (EXIF, XMP, text chunks)", long)]
+    pub keep_image_metadata: bool,
+}
+
+impl CheckParams for RedactParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.time_resolution >= 0.0, || {
+            "--time-resolution should be non-negative".to_string()
+        });
+    }
+}
+
+#[derive(Default)]
+struct Redactor {
+    scans: HashMap<String, fm::Scan>,
+    // The last frame time and its redacted value.
+    last_time: Option<(i64, i64)>,
+    num_images: usize,
+}
+
+impl Redactor {
+    fn redact(&mut self, record: &mut fm::Record, params: &RedactParams) {
+        use fm::record::Type::*;
+        let hash = |prefix, name: &mut String| {
+            if !name.is_empty() {
+                *name = hash_name(prefix, name, &params.salt);
+            }
+        };
+        let hash_element = |name: &mut String| {
+            if params.hash_elements {
+                hash("element", name);
+            }
+        };
+
+        match &mut record.r#type {
+            Some(ElementView(v)) => hash_element(&mut v.element),
+            Some(ElementViewState(s)) => hash_element(&mut s.element),
+            Some(ElementViewRefinement(r)) => hash_element(&mut r.element),
+            Some(ElementTexture(t)) => hash_element(&mut t.element),
+            Some(Scan(s)) => {
+                self.scans.insert(s.name.clone(), s.clone());
+                hash("scan", &mut s.name);
+            }
+            Some(ScanFrame(f)) => {
+                // Angles following from times are kept.
+                if let Some(scan) = self.scans.get(&f.scan) {
+                    fm::pin_camera_angle(scan, f);
+                }
+                f.time = self.coarsen_time(f.time, params.time_resolution);
+                hash("scan", &mut f.scan);
+            }
+            _ => (),
+        }
+    }
+
+    // Times are rounded down to the resolution, frames falling into the same
+    // round are then a nanosecond apart to stay distinct and ordered. Equal
+    // times (e.g. of rig cameras) stay equal.
+    fn coarsen_time(&mut self, time: i64, resolution: f64) -> i64 {
+        let resolution = (resolution * 1E9) as i64;
+        if resolution == 0 {
+            return time;
+        }
+        match self.last_time {
+            Some((last, redacted)) if last == time => return redacted,
+            _ => (),
+        }
+        let coarse = time.div_euclid(resolution) * resolution;
+        let redacted = match self.last_time {
+            Some((_, last)) if coarse <= last => last + 1,
+            _ => coarse,
+        };
+        self.last_time = Some((time, redacted));
+        redacted
+    }
+}
+
+fn hash_name(prefix: &str, name: &str, salt: &str) -> String {
+    let digest = fm::image_digest(format!("{}{}", salt, name).as_bytes());
+    let hex: String =
+        digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", prefix, hex)
+}
+
+pub fn redact(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    params: &RedactParams,
+) -> Result<()> {
+    params.check()?;
+
+    let mut redactor = Redactor::default();
+    let mut num = 0;
+    while let Some(mut rec) = reader.read_record()? {
+        redactor.redact(&mut rec, params);
+        if !params.keep_image_metadata {
+            for image in fm::record_images_mut(&mut rec) {
+                if strip_image_metadata(image)? {
+                    redactor.num_images += 1;
+                }
+            }
+        }
+        writer.write_record(&rec)?;
+        num += 1;
+    }
+
+    telemetry::count("records", num);
+    info!(
+        "redacted {} records (stripped metadata of {} images)",
+        num, redactor.num_images
+    );
+    Ok(())
+}
+
+// Returns whether anything was stripped.
+fn strip_image_metadata(image: &mut fm::Image) -> Result<bool> {
+    use fm::image::Type::*;
+    let stripped = match fm::image::Type::from_i32(image.r#type) {
+        Some(Jpeg) => strip_jpeg_metadata(&image.data),
+        Some(Png) => strip_png_metadata(&image.data),
+        _ => return Ok(false),
+    };
+    let stripped = stripped.ok_or_else(|| {
+        let desc = "malformed image, unable to strip metadata".to_string();
+        Error::new(MalformedData, desc)
+    })?;
+    if stripped.len() == image.data.len() {
+        return Ok(false);
+    }
+    image.data = stripped;
+    image.digest.clear();
+    Ok(true)
+}
+
+// Drops EXIF/XMP (APP1), IPTC (APP13) and comment segments, keeping the
+// rest (e.g. ICC profiles) which affects decoding.
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const SOI: u8 = 0xD8;
+    const SOS: u8 = 0xDA;
+    const APP1: u8 = 0xE1;
+    const APP13: u8 = 0xED;
+    const COM: u8 = 0xFE;
+
+    if data.get(..2)? != [0xFF, SOI] {
+        return None;
+    }
+    let mut stripped = data[..2].to_vec();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        if marker == 0xFF {
+            pos += 1; // Fill byte.
+            continue;
+        }
+        if marker == SOS {
+            stripped.extend_from_slice(&data[pos..]);
+            return Some(stripped);
+        }
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            stripped.extend_from_slice(&data[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+        let len =
+            u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]);
+        let end = pos + 2 + len as usize;
+        let segment = data.get(pos..end)?;
+        if ![APP1, APP13, COM].contains(&marker) {
+            stripped.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+// Drops text, EXIF and modification time chunks.
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    const DROPPED: [&[u8]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+    if data.get(..8)? != SIGNATURE {
+        return None;
+    }
+    let mut stripped = SIGNATURE.to_vec();
+    let mut pos = 8;
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?);
+        let end = pos + 12 + len as usize;
+        let chunk = data.get(pos..end)?;
+        if !DROPPED.contains(&&chunk[4..8]) {
+            stripped.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    Some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn create_params(args: &[&str]) -> RedactParams {
+        RedactParams::from_iter([&["redact"], args].concat())
+    }
+
+    #[test]
+    fn test_redact() {
+        let new_frame = |time, camera| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "john-doe".to_string(),
+                time,
+                camera,
+                ..Default::default()
+            })
+        };
+        let mut reader = create_reader_with_records(&[
+            new_scan_rec(fm::Scan {
+                name: "john-doe".to_string(),
+                camera_angular_velocity: 0.5,
+                ..Default::default()
+            }),
+            new_frame(1_700_000_000_400_000_000, 0),
+            new_frame(1_700_000_000_400_000_000, 1),
+            new_frame(1_700_000_000_900_000_000, 0),
+            new_frame(1_700_000_001_100_000_000, 0),
+            new_element_view_rec(fm::ElementView {
+                element: "body".to_string(),
+                ..Default::default()
+            }),
+        ]);
+
+        let mut writer = create_writer();
+        let params = create_params(&["--salt", "secret"]);
+        redact(&mut reader, &mut writer, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        let scan = record_variant!(Scan, rec);
+        assert!(scan.name.starts_with("scan-"));
+        assert_ne!(scan.name, hash_name("scan", "john-doe", ""));
+
+        let mut frames = vec![];
+        for _ in 0..4 {
+            let rec = reader.read_record().unwrap().unwrap();
+            frames.push(record_variant!(ScanFrame, rec));
+        }
+        assert!(frames.iter().all(|f| f.scan == scan.name));
+        let times: Vec<_> = frames.iter().map(|f| f.time).collect();
+        let second = 1_700_000_000_000_000_000;
+        assert_eq!(times, [second, second, second + 1, second + 1_000_000_000]);
+        let radians = frames[3].camera_angle.as_ref().unwrap().radians;
+        assert_eq!(radians, (1_700_000_001.1f64 * 0.5) as f32);
+
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(ElementView, rec).element, "body");
+    }
+
+    #[test]
+    fn test_strip_image_metadata() {
+        let mut jpeg = vec![];
+        let image = image::RgbImage::new(2, 2);
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode_image(&image)
+            .unwrap();
+        let exif = [0xFF, 0xE1, 0x00, 0x06, b'E', b'x', b'i', b'f'];
+        let mut tagged = jpeg[..2].to_vec();
+        tagged.extend_from_slice(&exif);
+        tagged.extend_from_slice(&jpeg[2..]);
+        assert_eq!(strip_jpeg_metadata(&tagged).unwrap(), jpeg);
+        assert_eq!(strip_jpeg_metadata(&jpeg).unwrap(), jpeg);
+        assert!(strip_jpeg_metadata(&tagged[..20]).is_none());
+
+        let mut png = vec![];
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        let text = b"\x00\x00\x00\x03tEXtabc\x00\x00\x00\x00";
+        let mut tagged = png[..33].to_vec(); // Signature and IHDR chunk.
+        tagged.extend_from_slice(text);
+        tagged.extend_from_slice(&png[33..]);
+
+        let mut image = fm::Image {
+            r#type: fm::image::Type::Png as i32,
+            data: tagged,
+            ..Default::default()
+        };
+        assert!(strip_image_metadata(&mut image).unwrap());
+        assert_eq!(image.data, png);
+        assert!(!strip_image_metadata(&mut image).unwrap());
+    }
+}
@@ -0,0 +1,704 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use image::RgbImage;
+use indexmap::IndexMap;
+use log::{info, warn};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::build_view::{downscale_texture, encode_image};
+use crate::mesh::{element_faces, Mesh};
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::texture::{
+    all_nonneg, get_pixel_ij_as_vector3, load_frame_image, project_like_camera,
+    sample_pixel, set_pixel_ij_as_vector3, BarycentricCoordinateSystem,
+    ImageGrid, Point3, Quaternion, Rectangle, Vector2, Vector3,
+};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::{cli, fs};
+
+#[derive(StructOpt)]
+#[structopt(about = "Repair collapsed regions (e.g. eyes) around landmarks")]
+pub struct RepairRegionsCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(
+        help = "Element to repair (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(flatten)]
+    params: RepairRegionsParams,
+}
+
+impl RepairRegionsCommand {
+    pub fn run(&self) -> Result<()> {
+        let landmarks = read_landmarks(&self.params.landmarks)?;
+        let scans = match &self.params.scans {
+            Some(path) => {
+                let mut params = self.params.scan.clone();
+                if let Some(path) = &params.scans_config {
+                    params.merge_config(&read_scans_config(path)?);
+                }
+                let mut reader = fm::Reader::new(fs::open_input(path)?)?;
+                info!("reading scans...");
+                Some(read_scans(&mut reader, &params)?)
+            }
+            None => None,
+        };
+
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        repair_regions(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.elements,
+            &landmarks,
+            scans
+                .as_ref()
+                .map(|(scans, frames)| (scans, frames.as_slice())),
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct RepairRegionsParams {
+    #[structopt(help = "Input TOML file with landmark regions", long)]
+    pub landmarks: PathBuf,
+
+    #[structopt(help = "Scans .fm file to re-bake region textures from", long)]
+    pub scans: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(
+        help = "Number of Laplacian smoothing iterations within regions",
+        long,
+        default_value = "10"
+    )]
+    pub region_smooth_iters: usize,
+
+    #[structopt(
+        help = "Texture JPEG quality (1-100) of re-baked textures",
+        long,
+        default_value = "80"
+    )]
+    pub texture_jpeg_quality: u8,
+}
+
+impl CheckParams for RepairRegionsParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        check.require((1..=100).contains(&self.texture_jpeg_quality), || {
+            "--texture-jpeg-quality should be within [1, 100]".to_string()
+        });
+    }
+}
+
+// Section of --landmarks file named after the region.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Landmark {
+    pub center: [f64; 3],
+    pub radius: f64,
+    // Offset along normals at the center, fading out towards the radius.
+    #[serde(default)]
+    pub inflate: f64,
+}
+
+pub type Landmarks = BTreeMap<String, Landmark>;
+
+fn read_landmarks(path: &Path) -> Result<Landmarks> {
+    let text = fs::read_file_to_string(path)?;
+    let landmarks: Landmarks = toml::from_str(&text).map_err(|err| {
+        let desc = format!("malformed landmarks: {}", err);
+        Error::with_source(MalformedData, desc, err)
+    })?;
+    check_landmarks(&landmarks)?;
+    Ok(landmarks)
+}
+
+fn check_landmarks(landmarks: &Landmarks) -> Result<()> {
+    let mut check = ParamCheck::default();
+    for (name, landmark) in landmarks {
+        check.require(landmark.radius > 0.0, || {
+            format!("radius of landmark '{}' should be positive", name)
+        });
+        check.require(
+            landmark.center.iter().all(|c| c.is_finite())
+                && landmark.inflate.is_finite(),
+            || format!("landmark '{}' should have finite values", name),
+        );
+    }
+    check.finish()
+}
+
+// Smooth falloff from 1 at the center to 0 at the radius.
+fn landmark_weights(vertices: &[Point3], landmark: &Landmark) -> Vec<f64> {
+    let center = Point3::from(landmark.center);
+    vertices
+        .iter()
+        .map(|p| {
+            let t = (p - center).norm() / landmark.radius;
+            if t < 1.0 {
+                (1.0 - t * t).powi(2)
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+fn vertex_neighbors(
+    num_vertices: usize,
+    faces: &[[usize; 3]],
+) -> Vec<Vec<usize>> {
+    let mut neighbors = vec![HashSet::new(); num_vertices];
+    for face in faces {
+        for k in 0..3 {
+            neighbors[face[k]].insert(face[(k + 1) % 3]);
+            neighbors[face[k]].insert(face[(k + 2) % 3]);
+        }
+    }
+    neighbors
+        .into_iter()
+        .map(|n| {
+            let mut n: Vec<_> = n.into_iter().collect();
+            n.sort_unstable();
+            n
+        })
+        .collect()
+}
+
+// Inflates and smooths vertices in proportion to their weights.
+fn repair_mesh(
+    mesh: &mut Mesh,
+    neighbors: &[Vec<usize>],
+    weights: &[f64],
+    inflate: f64,
+    num_smooth_iters: usize,
+) {
+    mesh.set_area_weighted_normals();
+    for (v, &w) in weights.iter().enumerate() {
+        if w > 0.0 && mesh.normals[v].iter().all(|c| c.is_finite()) {
+            mesh.vertices[v] += inflate * w * mesh.normals[v];
+        }
+    }
+
+    for _ in 0..num_smooth_iters {
+        let vertices = mesh.vertices.clone();
+        for (v, &w) in weights.iter().enumerate() {
+            if w > 0.0 && !neighbors[v].is_empty() {
+                let mean = neighbors[v]
+                    .iter()
+                    .map(|&n| vertices[n].coords)
+                    .sum::<Vector3>()
+                    / neighbors[v].len() as f64;
+                mesh.vertices[v] += w * (mean - vertices[v].coords);
+            }
+        }
+    }
+    mesh.set_area_weighted_normals();
+}
+
+fn state_mesh(state: &fm::ElementViewState, faces: &[[usize; 3]]) -> Mesh {
+    Mesh {
+        vertices: state
+            .vertices
+            .iter()
+            .map(|p| Point3::new(p.x as f64, p.y as f64, p.z as f64))
+            .collect(),
+        faces: faces.to_vec(),
+        ..Default::default()
+    }
+}
+
+fn update_state(
+    state: &mut fm::ElementViewState,
+    view: &fm::ElementView,
+    mesh: &Mesh,
+    weights: &[f64],
+) {
+    let point = |p: &Vector3| fm::Point3 {
+        x: p.x as f32,
+        y: p.y as f32,
+        z: p.z as f32,
+    };
+    for (v, p) in mesh.vertices.iter().enumerate() {
+        if weights[v] > 0.0 {
+            state.vertices[v] = point(&p.coords);
+        }
+    }
+
+    // Normals of face corners at repaired vertices follow new geometry.
+    for face in &view.faces {
+        let corners = [
+            (face.vertex1, face.normal1),
+            (face.vertex2, face.normal2),
+            (face.vertex3, face.normal3),
+        ];
+        for (vertex, normal) in corners {
+            let (v, n) = (vertex as usize - 1, normal as usize);
+            if n > 0 && n <= state.normals.len() && weights[v] > 0.0 {
+                state.normals[n - 1] = point(&mesh.normals[v]);
+            }
+        }
+    }
+}
+
+// The most frontal frame which sees all region vertices.
+fn choose_frame(
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+    mesh: &Mesh,
+    weights: &[f64],
+    landmark: &Landmark,
+) -> Result<Option<usize>> {
+    let region: Vec<usize> =
+        (0..weights.len()).filter(|&v| weights[v] > 0.0).collect();
+    let normal = region
+        .iter()
+        .map(|&v| weights[v] * mesh.normals[v])
+        .filter(|n| n.iter().all(|c| c.is_finite()))
+        .sum::<Vector3>();
+    let points: Vec<Point3> =
+        region.iter().map(|&v| mesh.vertices[v]).collect();
+    let center = Point3::from(landmark.center);
+
+    let mut best = None;
+    for (i, frame) in scan_frames.iter().enumerate() {
+        let scan = match scans.get(&frame.scan) {
+            Some(scan) if frame.image.is_some() => scan,
+            _ => continue,
+        };
+        let eye = scan.camera_initial_position.unwrap_or_default();
+        let eye = Point3::new(eye.x as f64, eye.y as f64, eye.z as f64);
+        let angle = fm::camera_angle(scan, frame);
+        let camera =
+            Quaternion::from_axis_angle(&Vector3::z_axis(), angle) * eye;
+        let score = (camera - center).normalize().dot(&normal);
+        if score <= 0.0 || best.is_some_and(|(_, s)| s >= score) {
+            continue;
+        }
+
+        let visible =
+            project_like_camera(scan, frame, &points)?.iter().all(|p| {
+                p.depth > 0.0 && p.point.iter().all(|c| (0.0..=1.0).contains(c))
+            });
+        if visible {
+            best = Some((i, score));
+        }
+    }
+    Ok(best.map(|(i, _)| i))
+}
+
+// Region faces are blended into the texture by vertex weights, so that
+// there are no seams at region borders.
+fn rebake_region(
+    texture: &mut RgbImage,
+    view: &fm::ElementView,
+    mesh: &Mesh,
+    weights: &[f64],
+    scan: &fm::Scan,
+    frame: &fm::ScanFrame,
+    image: &RgbImage,
+) -> Result<usize> {
+    let source = project_like_camera(scan, frame, &mesh.vertices)?;
+    let texture_uv = |t: u32| {
+        let p = &view.texture_points[t as usize - 1];
+        Vector2::new(p.y as f64, p.x as f64)
+    };
+
+    let mut num_faces = 0;
+    for (face, f) in mesh.faces.iter().zip(&view.faces) {
+        let corners = [f.texture1, f.texture2, f.texture3];
+        if face.iter().all(|&v| weights[v] == 0.0)
+            || corners
+                .iter()
+                .any(|&t| t == 0 || t as usize > view.texture_points.len())
+        {
+            continue;
+        }
+
+        let grid = ImageGrid::new(texture);
+        let ijs = corners.map(|t| grid.uv_to_ij(texture_uv(t)));
+        let bcs = match BarycentricCoordinateSystem::new(ijs) {
+            Some(bcs) => bcs,
+            None => continue,
+        };
+        let uvs = face.map(|v| source[v].point);
+        let w =
+            Vector3::new(weights[face[0]], weights[face[1]], weights[face[2]]);
+
+        let g = |ij: Vector2| [ij[0] as u32, ij[1] as u32];
+        let rect = match Rectangle::bounding(&ijs.map(g)) {
+            Some(rect) => rect,
+            None => continue,
+        };
+        for i in rect.pos[0]..=rect.pos[0] + rect.size[0] {
+            for j in rect.pos[1]..=rect.pos[1] + rect.size[1] {
+                let bary = bcs.infer(Vector2::new(i as f64, j as f64));
+                if !all_nonneg(bary) || !grid.contains(i, j) {
+                    continue;
+                }
+                let uv = bary[0] * uvs[0] + bary[1] * uvs[1] + bary[2] * uvs[2];
+                let weight = bary.dot(&w);
+                let old = get_pixel_ij_as_vector3(i, j, texture);
+                let color = old + weight * (sample_pixel(uv, image) - old);
+                set_pixel_ij_as_vector3(i, j, color, texture);
+            }
+        }
+        num_faces += 1;
+    }
+    Ok(num_faces)
+}
+
+type Scans<'a> = (&'a IndexMap<String, fm::Scan>, &'a [fm::ScanFrame]);
+
+fn repair_element(
+    view: &mut fm::ElementView,
+    states: &mut [&mut fm::ElementViewState],
+    landmarks: &Landmarks,
+    scans: Option<Scans>,
+    params: &RepairRegionsParams,
+) -> Result<()> {
+    let num_vertices = states[0].vertices.len();
+    if states.iter().any(|s| s.vertices.len() != num_vertices) {
+        let desc = format!(
+            "states of element '{}' differ in number of vertices",
+            view.element
+        );
+        return Err(Error::new(InconsistentState, desc));
+    }
+    let faces = element_faces(view, num_vertices)?;
+    let neighbors = vertex_neighbors(num_vertices, &faces);
+
+    // Regions are found in the first state and shared by others.
+    let first = state_mesh(states[0], &faces);
+    let regions: Vec<(&String, &Landmark, Vec<f64>)> = landmarks
+        .iter()
+        .map(|(name, l)| (name, l, landmark_weights(&first.vertices, l)))
+        .collect();
+
+    let mut meshes = Vec::with_capacity(states.len());
+    for state in states.iter_mut() {
+        let mut mesh = state_mesh(state, &faces);
+        for (_, landmark, weights) in &regions {
+            repair_mesh(
+                &mut mesh,
+                &neighbors,
+                weights,
+                landmark.inflate,
+                params.region_smooth_iters,
+            );
+            update_state(state, view, &mesh, weights);
+        }
+        meshes.push(mesh);
+    }
+    for (name, _, weights) in &regions {
+        info!(
+            "repaired {} vertices of region '{}' of element '{}'",
+            weights.iter().filter(|&&w| w > 0.0).count(),
+            name,
+            view.element
+        );
+    }
+
+    if let Some((scans, scan_frames)) = scans {
+        rebake_element(view, &meshes[0], &regions, scans, scan_frames, params)?;
+    }
+    Ok(())
+}
+
+fn rebake_element(
+    view: &mut fm::ElementView,
+    mesh: &Mesh,
+    regions: &[(&String, &Landmark, Vec<f64>)],
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+    params: &RepairRegionsParams,
+) -> Result<()> {
+    let image_type = match &view.texture {
+        Some(texture) if !texture.data.is_empty() => texture.r#type(),
+        _ => {
+            warn!("element '{}' has no embedded texture", view.element);
+            return Ok(());
+        }
+    };
+    if image_type == fm::image::Type::None {
+        warn!("texture of element '{}' has no type", view.element);
+        return Ok(());
+    }
+    let data = &view.texture.as_ref().unwrap().data;
+    let mut texture = image::load_from_memory(data)
+        .map_err(|e| {
+            let desc =
+                format!("failed to decode texture of '{}'", view.element);
+            Error::with_source(ImageError, desc, e)
+        })?
+        .into_rgb8();
+
+    for (name, landmark, weights) in regions {
+        let frame_idx =
+            choose_frame(scans, scan_frames, mesh, weights, landmark)?;
+        let frame = match frame_idx {
+            Some(frame_idx) => &scan_frames[frame_idx],
+            None => {
+                warn!("no frame sees the whole region '{}'", name);
+                continue;
+            }
+        };
+        let image = load_frame_image(frame).ok_or_else(|| {
+            let desc = format!(
+                "failed to decode frame image of scan '{}'",
+                frame.scan
+            );
+            Error::new(ImageError, desc)
+        })?;
+        let num_faces = rebake_region(
+            &mut texture,
+            view,
+            mesh,
+            weights,
+            &scans[&frame.scan],
+            frame,
+            &image,
+        )?;
+        info!(
+            "re-baked {} faces of region '{}' from frame of scan '{}' at {}",
+            num_faces, name, frame.scan, frame.time
+        );
+    }
+
+    view.texture = Some(encode_image(
+        &texture,
+        image_type,
+        params.texture_jpeg_quality,
+    ));
+    let num_levels = view.texture_levels.len() as u32;
+    view.texture_levels = downscale_texture(&texture, num_levels)
+        .iter()
+        .map(|image| {
+            encode_image(image, image_type, params.texture_jpeg_quality)
+        })
+        .collect();
+    Ok(())
+}
+
+pub fn repair_regions(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    elements: &[String],
+    landmarks: &Landmarks,
+    scans: Option<Scans>,
+    params: &RepairRegionsParams,
+) -> Result<()> {
+    params.check()?;
+    let selected = |element: &str| {
+        elements.is_empty() || elements.iter().any(|e| e == element)
+    };
+
+    let mut records = Vec::new();
+    while let Some(rec) = reader.read_record()? {
+        records.push(rec);
+    }
+
+    let mut views = Vec::new();
+    let mut states: Vec<(String, &mut fm::ElementViewState)> = Vec::new();
+    for rec in records.iter_mut() {
+        use fm::record::Type::*;
+        match &mut rec.r#type {
+            Some(ElementView(v)) if selected(&v.element) => views.push(v),
+            Some(ElementViewState(s)) if selected(&s.element) => {
+                states.push((s.element.clone(), s))
+            }
+            _ => {}
+        }
+    }
+
+    for view in views {
+        let mut view_states: Vec<&mut fm::ElementViewState> = states
+            .iter_mut()
+            .filter(|(e, _)| *e == view.element)
+            .map(|(_, s)| &mut **s)
+            .collect();
+        if view_states.is_empty() {
+            let desc = format!("no state for element '{}'", view.element);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        repair_element(view, &mut view_states, landmarks, scans, params)?;
+    }
+
+    for rec in records {
+        writer.write_record(&rec)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::Read as _;
+
+    const GRID: u32 = 5;
+
+    // Grid facing the camera with a dent in the middle.
+    fn create_element() -> (fm::ElementView, fm::ElementViewState) {
+        let mut view = fm::ElementView {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+        let mut state = fm::ElementViewState {
+            element: "a".to_string(),
+            ..Default::default()
+        };
+        for i in 0..GRID {
+            for j in 0..GRID {
+                let (x, z) = (i as f32 * 0.25 - 0.5, j as f32 * 0.25 - 0.5);
+                let y = if x == 0.0 && z == 0.0 { 0.05 } else { 0.0 };
+                state.vertices.push(new_point3(x, y, z));
+                state.normals.push(new_point3(0.0, -1.0, 0.0));
+                let (u, v) = (i as f32 / 4.0, j as f32 / 4.0);
+                view.texture_points.push(new_point2(u, v));
+            }
+        }
+        let index = |i, j| i * GRID + j + 1;
+        for i in 0..GRID - 1 {
+            for j in 0..GRID - 1 {
+                for [a, b, c] in [
+                    [index(i, j), index(i + 1, j), index(i, j + 1)],
+                    [index(i + 1, j), index(i + 1, j + 1), index(i, j + 1)],
+                ] {
+                    view.faces.push(new_ev_face(a, b, c, a, b, c, a, b, c));
+                }
+            }
+        }
+        let texture = RgbImage::from_pixel(8, 8, Rgb([0, 0, 255]));
+        view.texture = Some(encode_image(&texture, fm::image::Type::Png, 80));
+        (view, state)
+    }
+
+    fn create_scans() -> (IndexMap<String, fm::Scan>, Vec<fm::ScanFrame>) {
+        let scan = fm::Scan {
+            name: "s".to_string(),
+            camera_initial_position: Some(new_point3(0.0, -2.0, 0.0)),
+            camera_initial_direction: Some(new_point3(0.0, 1.0, 0.0)),
+            camera_angle_of_view: 1.0,
+            depth_width: 40,
+            depth_height: 40,
+            ..Default::default()
+        };
+        let image = RgbImage::from_pixel(40, 40, Rgb([255, 0, 0]));
+        let frame = fm::ScanFrame {
+            scan: "s".to_string(),
+            image: Some(encode_image(&image, fm::image::Type::Png, 80)),
+            ..Default::default()
+        };
+        let scans = [("s".to_string(), scan)].into_iter().collect();
+        (scans, vec![frame])
+    }
+
+    fn create_landmarks() -> Landmarks {
+        toml::from_str(
+            "[eye]\ncenter = [0, 0, 0]\nradius = 0.3\ninflate = 0.05",
+        )
+        .unwrap()
+    }
+
+    fn repair(
+        scans: Option<Scans>,
+        params: &RepairRegionsParams,
+    ) -> (fm::ElementView, fm::ElementViewState) {
+        let (view, state) = create_element();
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(view),
+            new_element_view_state_rec(state),
+        ]);
+        let mut writer = create_writer();
+        repair_regions(
+            &mut reader,
+            &mut writer,
+            &[],
+            &create_landmarks(),
+            scans,
+            params,
+        )
+        .unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let view = reader.read_record().unwrap().unwrap();
+        let state = reader.read_record().unwrap().unwrap();
+        (
+            record_variant!(fm::record::Type::ElementView, view),
+            record_variant!(fm::record::Type::ElementViewState, state),
+        )
+    }
+
+    #[test]
+    fn test_repair_regions() {
+        let mut params = RepairRegionsParams::from_iter([
+            "repair-regions",
+            "--landmarks",
+            "landmarks.toml",
+            "--region-smooth-iters",
+            "0",
+        ]);
+        let (original, original_state) = create_element();
+        let (view, state) = repair(None, &params);
+        assert_eq!(view, original);
+
+        // The dent is inflated towards the camera, the rest stays intact.
+        let center = (GRID * GRID / 2) as usize;
+        assert!(state.vertices[center].y < 0.01);
+        assert!(state.vertices[center].y > -0.01);
+        for (v, p) in state.vertices.iter().enumerate() {
+            let q = &original_state.vertices[v];
+            if (p.x.powi(2) + p.z.powi(2)).sqrt() > 0.3 {
+                assert_eq!(p, q);
+            }
+        }
+        assert!(state.normals[center].y < -0.99);
+
+        params.region_smooth_iters = 10;
+        let (_, state) = repair(None, &params);
+        assert!(state.vertices[center].y.abs() < 0.005);
+
+        let (scans, frames) = create_scans();
+        let (view, _) = repair(Some((&scans, &frames)), &params);
+        let data = &view.texture.unwrap().data;
+        let texture = image::load_from_memory(data).unwrap().into_rgb8();
+        let center = texture.get_pixel(4, 4);
+        assert!(center[0] > 200 && center[2] < 50, "{:?}", center);
+        assert_eq!(texture.get_pixel(0, 0), &Rgb([0, 0, 255]));
+        assert_eq!(texture.get_pixel(7, 7), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn test_check_landmarks() {
+        let landmarks: Landmarks = toml::from_str(
+            "[eye]\ncenter = [0, 0, 0]\nradius = 0\n\
+             [mouth]\ncenter = [0, 0, 1]\nradius = 0.1\ninflate = nan",
+        )
+        .unwrap();
+        let err = check_landmarks(&landmarks).unwrap_err();
+        assert!(err.description.contains("2 parameter problems"));
+
+        let text = "[eye]\ncenter = [0, 0]\nradius = 0.1";
+        assert!(toml::from_str::<Landmarks>(text).is_err());
+    }
+}
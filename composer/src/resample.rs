@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use indexmap::IndexMap;
+use structopt::StructOpt;
+
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::render::{self, Interpolation, DEFAULT_INTERPOLATION};
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Resample element view states to a fixed frame rate")]
+pub struct ResampleCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(help = "Frames per second", long)]
+    fps: f64,
+
+    #[structopt(
+        help = "Element to resample (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(
+        help = "Interpolation of states between their times",
+        long,
+        default_value = DEFAULT_INTERPOLATION
+    )]
+    interpolation: Interpolation,
+}
+
+impl ResampleCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        resample(
+            reader.as_mut(),
+            writer.as_mut(),
+            self.fps,
+            &self.elements,
+            self.interpolation,
+        )
+    }
+}
+
+type RecordIter = Box<dyn Iterator<Item = Result<fm::Record>>>;
+
+// Resampled states are put onto a common time grid starting at the earliest
+// state, each element being limited to its original time span.
+pub fn resample(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    fps: f64,
+    elements: &[String],
+    interpolation: Interpolation,
+) -> Result<()> {
+    if !(fps > 0.0 && fps.is_finite()) {
+        let desc = format!("bad frame rate {}", fps);
+        return Err(Error::new(BadOperation, desc));
+    }
+
+    let mut others = Vec::new();
+    let mut states = IndexMap::<String, BTreeMap<fm::Time, _>>::new();
+
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match rec.r#type {
+            Some(ElementViewState(s))
+                if elements.is_empty() || elements.contains(&s.element) =>
+            {
+                let element_states = states.entry(s.element.clone());
+                element_states.or_default().insert(fm::Time(s.time), s);
+            }
+            _ => others.push(rec),
+        }
+    }
+
+    // Element state maps are never empty.
+    let spans: Vec<_> = states
+        .values()
+        .map(|s| (*s.keys().next().unwrap(), *s.keys().next_back().unwrap(), s))
+        .collect();
+    let origin = spans.iter().map(|s| s.0).min();
+    let end = spans.iter().map(|s| s.1).max();
+
+    let mut resampled = Vec::new();
+    if let (Some(origin), Some(end)) = (origin, end) {
+        let period = fm::NANOS_PER_SEC as f64 / fps;
+        for i in 0.. {
+            let at = origin + fm::Time((i as f64 * period).round() as i64);
+            if at > end {
+                break;
+            }
+
+            for (first, last, element_states) in &spans {
+                if at < *first || at > *last {
+                    continue;
+                }
+                let mut state =
+                    render::state_at(element_states, at, interpolation)
+                        .unwrap();
+                state.time = at.0;
+                resampled.push(fm::Record {
+                    r#type: Some(fm::record::Type::ElementViewState(state)),
+                });
+            }
+        }
+    }
+
+    let streams: Vec<RecordIter> = vec![
+        Box::new(others.into_iter().map(Ok)),
+        Box::new(resampled.into_iter().map(Ok)),
+    ];
+    for rec in fm::merge_sorted_by_time(streams) {
+        writer.write_record(&rec?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+    use base::{assert_eq_point3, record_variant};
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn new_state_rec(element: &str, secs: f64) -> fm::Record {
+        let x = secs as f32;
+        new_element_view_state_rec(fm::ElementViewState {
+            element: element.to_string(),
+            time: fm::Time::from_secs_f64(secs).0,
+            vertices: vec![new_point3(x, 2.0 * x, 0.0)],
+            normals: vec![new_point3(0.0, 0.0, 1.0)],
+        })
+    }
+
+    #[test]
+    fn test_resample() {
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(fm::ElementView {
+                element: "a".to_string(),
+                ..Default::default()
+            }),
+            new_state_rec("a", 0.0),
+            new_state_rec("b", 0.3),
+            new_state_rec("a", 1.0),
+            new_state_rec("a", 2.0),
+        ]);
+
+        let mut writer = create_writer();
+        let elements = ["a".to_string()];
+        resample(&mut reader, &mut writer, 2.0, &elements, Default::default())
+            .unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        record_variant!(ElementView, rec);
+
+        let mut times = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            let state = record_variant!(ElementViewState, rec);
+            let secs = fm::Time(state.time).as_secs_f64();
+            if state.element == "a" {
+                let x = secs as f32;
+                assert_eq_point3!(
+                    state.vertices[0],
+                    new_point3(x, 2.0 * x, 0.0)
+                );
+            }
+            times.push((state.element, secs));
+        }
+
+        let a = |secs| ("a".to_string(), secs);
+        assert_eq!(
+            times,
+            vec![
+                a(0.0),
+                ("b".to_string(), 0.3),
+                a(0.5),
+                a(1.0),
+                a(1.5),
+                a(2.0)
+            ]
+        );
+    }
+
+    fn resample_x(interpolation: Interpolation) -> Vec<f32> {
+        let mut reader = create_reader_with_records(&[
+            new_state_rec("a", 0.0),
+            new_state_rec("a", 1.0),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "a".to_string(),
+                time: fm::NANOS_PER_SEC * 2,
+                vertices: vec![new_point3(4.0, 0.0, 0.0)],
+                normals: vec![new_point3(0.0, 0.0, 1.0)],
+            }),
+        ]);
+
+        let mut writer = create_writer();
+        resample(&mut reader, &mut writer, 4.0, &[], interpolation).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let mut xs = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            xs.push(record_variant!(ElementViewState, rec).vertices[0].x);
+        }
+        xs
+    }
+
+    #[test]
+    fn test_resample_interpolation() {
+        // Nearest states are the earlier ones at equal distances.
+        assert_eq!(
+            resample_x(Interpolation::Nearest),
+            vec![0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 4.0, 4.0]
+        );
+        assert_eq!(
+            resample_x(Interpolation::Linear),
+            vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.75, 2.5, 3.25, 4.0]
+        );
+        let xs = resample_x(Interpolation::Quadratic);
+        for (i, x) in xs.into_iter().enumerate() {
+            let t = i as f32 / 4.0;
+            assert!((x - t * t).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_turntable_frames() {
+        let secs = fm::Time::from_secs_f64;
+        let mut params = render::TurntableParams {
+            fps: 2.0,
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+
+        // Animation span is rendered with a full orbit.
+        let frames = params.frames(Some((secs(1.0), secs(3.0))));
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[1].time, secs(1.5));
+        assert_eq!(frames[3].time, secs(2.5));
+        let step = std::f64::consts::FRAC_PI_2;
+        assert!((frames[1].camera_angle - step).abs() < 1e-9);
+        assert!((frames[3].camera_angle - 3.0 * step).abs() < 1e-9);
+
+        // Static models orbit over the default duration.
+        params.camera_orbit_degrees = -90.0;
+        let frames = params.frames(None);
+        assert_eq!(frames.len(), 20);
+        assert_eq!(frames[19].time, secs(9.5));
+        assert!((frames[10].camera_angle + step / 2.0).abs() < 1e-9);
+
+        params.duration = Some(fm::Time(0));
+        assert!(params.validate().is_err());
+    }
+}
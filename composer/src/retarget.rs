@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, HashSet};
+
+use kiddo::distance::squared_euclidean;
+use kiddo::KdTree;
+use structopt::StructOpt;
+
+use crate::misc::kdtree_err_to_err;
+use crate::point_cloud::Vector3;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Transfer animation of one element to another")]
+pub struct RetargetCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(help = "Element to take animation from", long)]
+    source: String,
+
+    #[structopt(help = "Element to apply animation to", long)]
+    target: String,
+
+    #[structopt(flatten)]
+    params: RetargetParams,
+}
+
+impl RetargetCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        retarget(
+            reader.as_mut(),
+            writer.as_mut(),
+            &self.source,
+            &self.target,
+            &self.params,
+        )
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct RetargetParams {
+    #[structopt(
+        help = "Number of closest source vertices per target vertex",
+        long,
+        default_value = "4"
+    )]
+    pub retarget_num_neighbors: usize,
+
+    #[structopt(
+        help = "Number of displacement smoothing iterations",
+        long,
+        default_value = "2"
+    )]
+    pub retarget_smooth_iters: usize,
+}
+
+// Weighted source vertices for every target vertex.
+type Correspondence = Vec<Vec<(usize, f64)>>;
+
+fn to_vector(p: &fm::Point3) -> Vector3 {
+    Vector3::new(p.x as f64, p.y as f64, p.z as f64)
+}
+
+fn to_point(v: &Vector3) -> fm::Point3 {
+    fm::Point3 {
+        x: v.x as f32,
+        y: v.y as f32,
+        z: v.z as f32,
+    }
+}
+
+fn find_correspondence(
+    source: &[fm::Point3],
+    target: &[fm::Point3],
+    num_neighbors: usize,
+) -> Result<Correspondence> {
+    let mut kdtree = KdTree::new();
+    for (i, p) in source.iter().enumerate() {
+        let p = to_vector(p);
+        kdtree.add(p.as_ref(), i).map_err(kdtree_err_to_err)?;
+    }
+
+    target
+        .iter()
+        .map(|p| {
+            let nearest = kdtree
+                .nearest(
+                    to_vector(p).as_ref(),
+                    num_neighbors,
+                    &squared_euclidean,
+                )
+                .map_err(kdtree_err_to_err)?;
+
+            // Inverse distance weighting, an exact match takes it all.
+            let mut weights: Vec<_> = nearest
+                .iter()
+                .map(|(dist, i)| (**i, 1.0 / dist.sqrt().max(f64::EPSILON)))
+                .collect();
+            let sum = weights.iter().map(|w| w.1).sum::<f64>();
+            for w in weights.iter_mut() {
+                w.1 /= sum;
+            }
+            Ok(weights)
+        })
+        .collect()
+}
+
+fn find_neighbors(
+    faces: &[fm::element_view::Face],
+    num: usize,
+) -> Vec<Vec<usize>> {
+    let mut neighbors = vec![HashSet::new(); num];
+    for face in faces {
+        let face = [face.vertex1, face.vertex2, face.vertex3];
+        for i in 0..3 {
+            for j in 0..3 {
+                let (a, b) = (face[i] as usize, face[j] as usize);
+                if i != j && (1..=num).contains(&a) && (1..=num).contains(&b) {
+                    neighbors[a - 1].insert(b - 1);
+                }
+            }
+        }
+    }
+    neighbors
+        .into_iter()
+        .map(|n| n.into_iter().collect())
+        .collect()
+}
+
+fn smoothen(values: &mut Vec<Vector3>, neighbors: &[Vec<usize>], iters: usize) {
+    for _ in 0..iters {
+        let smoothed = values
+            .iter()
+            .zip(neighbors)
+            .map(|(v, n)| {
+                if n.is_empty() {
+                    return *v;
+                }
+                let sum = n.iter().map(|i| values[*i]).sum::<Vector3>();
+                (v + sum / n.len() as f64) / 2.0
+            })
+            .collect();
+        *values = smoothed;
+    }
+}
+
+fn transfer(
+    values: &[fm::Point3],
+    rest_values: &[fm::Point3],
+    correspondence: &Correspondence,
+) -> Vec<Vector3> {
+    correspondence
+        .iter()
+        .map(|weights| {
+            weights
+                .iter()
+                .map(|(i, w)| {
+                    (to_vector(&values[*i]) - to_vector(&rest_values[*i])) * *w
+                })
+                .sum()
+        })
+        .collect()
+}
+
+// The first target state is taken as a rest pose matching the first
+// source state, target states are replaced with displaced rest poses.
+pub fn retarget(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    source: &str,
+    target: &str,
+    params: &RetargetParams,
+) -> Result<()> {
+    let mut others = Vec::new();
+    let mut source_states = BTreeMap::new();
+    let mut target_view = None;
+    let mut target_rest = None;
+
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementViewState(s)) if s.element == source => {
+                source_states.insert(s.time, s.clone());
+                others.push(rec);
+            }
+            Some(ElementViewState(s)) if s.element == target => {
+                if target_rest.is_none() {
+                    target_rest = Some(s.clone());
+                }
+            }
+            Some(ElementView(v)) if v.element == target => {
+                target_view = Some(v.clone());
+                others.push(rec);
+            }
+            _ => others.push(rec),
+        }
+    }
+
+    let source_rest =
+        source_states.values().next().cloned().ok_or_else(|| {
+            let desc = format!("no states for source element '{}'", source);
+            Error::new(InconsistentState, desc)
+        })?;
+    let target_rest = target_rest.ok_or_else(|| {
+        let desc = format!("no states for target element '{}'", target);
+        Error::new(InconsistentState, desc)
+    })?;
+    let target_view = target_view.unwrap_or_default();
+
+    let correspondence = find_correspondence(
+        &source_rest.vertices,
+        &target_rest.vertices,
+        params.retarget_num_neighbors.max(1),
+    )?;
+    let neighbors =
+        find_neighbors(&target_view.faces, target_rest.vertices.len());
+
+    // Target normals follow source normals around their first vertex.
+    let mut normal_vertices = vec![None; target_rest.normals.len()];
+    for face in &target_view.faces {
+        for (v, n) in [
+            (face.vertex1, face.normal1),
+            (face.vertex2, face.normal2),
+            (face.vertex3, face.normal3),
+        ] {
+            let (v, n) = (v as usize, n as usize);
+            if (1..=normal_vertices.len()).contains(&n)
+                && (1..=correspondence.len()).contains(&v)
+            {
+                normal_vertices[n - 1].get_or_insert(v - 1);
+            }
+        }
+    }
+    let normal_correspondence: Correspondence = normal_vertices
+        .iter()
+        .map(|v| v.map(|v| correspondence[v].clone()).unwrap_or_default())
+        .collect();
+    let source_normals =
+        source_rest.normals.len() == source_rest.vertices.len();
+
+    let mut states = Vec::with_capacity(source_states.len());
+    for state in source_states.values() {
+        if state.vertices.len() != source_rest.vertices.len() {
+            let desc = format!(
+                "inconsistent number of vertices for element '{}'",
+                source
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+
+        let mut displacements =
+            transfer(&state.vertices, &source_rest.vertices, &correspondence);
+        smoothen(&mut displacements, &neighbors, params.retarget_smooth_iters);
+
+        let vertices = target_rest
+            .vertices
+            .iter()
+            .zip(displacements)
+            .map(|(v, d)| to_point(&(to_vector(v) + d)))
+            .collect();
+
+        let normals = if source_normals
+            && state.normals.len() == source_rest.normals.len()
+        {
+            let deltas = transfer(
+                &state.normals,
+                &source_rest.normals,
+                &normal_correspondence,
+            );
+            target_rest
+                .normals
+                .iter()
+                .zip(deltas)
+                .map(|(n, d)| {
+                    let n = to_vector(n) + d;
+                    to_point(&n.try_normalize(f64::EPSILON).unwrap_or(n))
+                })
+                .collect()
+        } else {
+            target_rest.normals.clone()
+        };
+
+        states.push(fm::Record {
+            r#type: Some(fm::record::Type::ElementViewState(
+                fm::ElementViewState {
+                    element: target.to_string(),
+                    time: state.time,
+                    vertices,
+                    normals,
+                },
+            )),
+        });
+    }
+
+    let streams: Vec<Box<dyn Iterator<Item = Result<fm::Record>>>> = vec![
+        Box::new(others.into_iter().map(Ok)),
+        Box::new(states.into_iter().map(Ok)),
+    ];
+    for rec in fm::merge_sorted_by_time(streams) {
+        writer.write_record(&rec?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+    use base::{assert_eq_point3, record_variant};
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn new_state_rec(
+        element: &str,
+        time: i64,
+        vertices: Vec<fm::Point3>,
+    ) -> fm::Record {
+        let normals = vec![new_point3(0.0, 0.0, 1.0); vertices.len()];
+        new_element_view_state_rec(fm::ElementViewState {
+            element: element.to_string(),
+            time,
+            vertices,
+            normals,
+        })
+    }
+
+    #[test]
+    fn test_retarget() {
+        let square = |dx| {
+            vec![
+                new_point3(dx, 0.0, 0.0),
+                new_point3(dx + 1.0, 0.0, 0.0),
+                new_point3(dx + 1.0, 1.0, 0.0),
+                new_point3(dx, 1.0, 0.0),
+            ]
+        };
+        let segment =
+            vec![new_point3(0.25, 0.5, 0.0), new_point3(0.75, 0.5, 0.0)];
+
+        let mut reader = create_reader_with_records(&[
+            new_element_view_rec(fm::ElementView {
+                element: "b".to_string(),
+                faces: vec![fm::element_view::Face {
+                    vertex1: 1,
+                    vertex2: 2,
+                    vertex3: 2,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            new_state_rec("a", 0, square(0.0)),
+            new_state_rec("b", 0, segment),
+            new_state_rec("a", 10, square(2.0)),
+        ]);
+
+        let mut writer = create_writer();
+        let params = RetargetParams {
+            retarget_num_neighbors: 4,
+            retarget_smooth_iters: 2,
+        };
+        retarget(&mut reader, &mut writer, "a", "b", &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        record_variant!(ElementView, rec);
+
+        let mut targets = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            let state = record_variant!(ElementViewState, rec);
+            if state.element == "b" {
+                targets.push(state);
+            }
+        }
+
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].time, 10);
+        assert_eq_point3!(targets[0].vertices[0], new_point3(0.25, 0.5, 0.0));
+        assert_eq_point3!(targets[1].vertices[0], new_point3(2.25, 0.5, 0.0));
+        assert_eq_point3!(targets[1].vertices[1], new_point3(2.75, 0.5, 0.0));
+        assert_eq_point3!(targets[1].normals[0], new_point3(0.0, 0.0, 1.0));
+    }
+}
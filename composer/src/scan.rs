@@ -1,14 +1,152 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use structopt::StructOpt;
 use indexmap::IndexMap;
+use log::warn;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{
+    camera_view_rotation, set_camera_pose, unproject_depth, Vector3,
+};
 
 use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
 use base::util::cli::{parse_key_val, Array as CliArray};
+use base::util::fs;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownScanPolicy {
+    Error,
+    Skip,
+    Warn,
+}
+
+impl FromStr for UnknownScanPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(UnknownScanPolicy::Error),
+            "skip" => Ok(UnknownScanPolicy::Skip),
+            "warn" => Ok(UnknownScanPolicy::Warn),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown scan policy (can be 'error', 'skip' or 'warn')"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+    Strict,
+    Lenient,
+}
+
+impl FromStr for ReadMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(ReadMode::Strict),
+            "lenient" => Ok(ReadMode::Lenient),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown read mode (can be 'strict' or 'lenient')".to_string(),
+            )),
+        }
+    }
+}
+
+// Checks consistency of scan and scan frame records in stream order.
+#[derive(Default)]
+pub struct ScanValidator {
+    scans: HashMap<String, usize>, // Number of rig cameras by scan.
+    frame_times: HashSet<(String, u32, fm::Time)>,
+    last_time: fm::Time,
+}
 
-#[derive(StructOpt)]
+impl ScanValidator {
+    pub fn check_scan(&mut self, scan: &fm::Scan) -> Result<()> {
+        if !self.frame_times.is_empty() {
+            let desc = format!("scan '{}' after scan frame", &scan.name);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        if self
+            .scans
+            .insert(scan.name.clone(), scan.cameras.len())
+            .is_some()
+        {
+            let desc = format!("duplicate scan '{}'", &scan.name);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        Ok(())
+    }
+
+    pub fn check_frame(&mut self, frame: &fm::ScanFrame) -> Result<()> {
+        let num_cameras = match self.scans.get(&frame.scan) {
+            Some(&num) => num,
+            None => {
+                let desc = format!("frame for unknown scan '{}'", &frame.scan);
+                return Err(Error::new(InconsistentState, desc));
+            }
+        };
+        if frame.camera as usize >= num_cameras.max(1) {
+            let desc = format!(
+                "unknown camera {} in frame for scan '{}'",
+                frame.camera, &frame.scan
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+        let time = fm::Time(frame.time);
+        if let Some(crop) = &frame.image_crop {
+            if !crop.is_valid() {
+                let desc = format!(
+                    "bad image crop in frame {} for scan '{}'",
+                    time, &frame.scan
+                );
+                return Err(Error::new(InconsistentState, desc));
+            }
+        }
+        if time < self.last_time {
+            let desc = format!(
+                "non-monotonic frame time {} for scan '{}'",
+                time, &frame.scan
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+        let key = (frame.scan.clone(), frame.camera, time);
+        if !self.frame_times.insert(key) {
+            let desc = format!(
+                "duplicate frame time {} for scan '{}'",
+                time, &frame.scan
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+        self.last_time = time;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
 pub struct ScanParams {
+    #[structopt(
+        help = "Per-scan parameters .toml file (flags take precedence)",
+        long
+    )]
+    pub scans_config: Option<PathBuf>,
+
+    #[structopt(
+        help = "Input .fm file with calibrated scans (see calibrate-rig)",
+        long
+    )]
+    pub calibration: Option<PathBuf>,
+
     #[structopt(
         help = "Camera initial position to override with",
         long = "camera-initial-position",
@@ -45,6 +183,30 @@ pub struct ScanParams {
     )]
     pub camera_angles_of_view: Vec<(String, f32)>,
 
+    #[structopt(
+        help = "Color camera rotation (axis-angle) to override with",
+        long = "color-camera-rotation",
+            number_of_values = 1,
+            parse(try_from_str = parse_key_val),
+    )]
+    pub color_camera_rotations: Vec<(String, CliArray<f32, 3>)>,
+
+    #[structopt(
+        help = "Color camera translation to override with",
+        long = "color-camera-translation",
+            number_of_values = 1,
+            parse(try_from_str = parse_key_val),
+    )]
+    pub color_camera_translations: Vec<(String, CliArray<f32, 3>)>,
+
+    #[structopt(
+        help = "Color camera angle of view to override with",
+        long = "color-camera-angle-of-view",
+            number_of_values = 1,
+            parse(try_from_str = parse_key_val),
+    )]
+    pub color_camera_angles_of_view: Vec<(String, f32)>,
+
     #[structopt(
         help = "Downsample factor",
         long = "downsample-factor",
@@ -74,15 +236,277 @@ pub struct ScanParams {
             parse(try_from_str = parse_key_val),
     )]
     pub names: Vec<(String, String)>,
+
+    #[structopt(
+        help = "Policy for frames of unknown scans",
+        long,
+        default_value = "error"
+    )]
+    pub unknown_scan: UnknownScanPolicy,
+
+    #[structopt(
+        help = "Scan read mode (lenient skips inconsistent records)",
+        long,
+        default_value = "strict"
+    )]
+    pub read_mode: ReadMode,
+}
+
+// Section of --scans-config file named after the scan.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ScanConfig {
+    pub camera_initial_position: Option<[f32; 3]>,
+    pub camera_initial_direction: Option<[f32; 3]>,
+    pub camera_up_angle: Option<f32>,
+    pub camera_angle_of_view: Option<f32>,
+    pub color_camera_rotation: Option<[f32; 3]>,
+    pub color_camera_translation: Option<[f32; 3]>,
+    pub color_camera_angle_of_view: Option<f32>,
+    pub downsample_factor: Option<usize>,
+    pub drop_depths: bool,
+    pub drop_images: bool,
+    pub name: Option<String>,
+    pub min_z: Option<f32>,
+    pub max_z: Option<f32>,
+}
+
+pub type ScansConfig = BTreeMap<String, ScanConfig>;
+
+pub fn read_scans_config(path: &Path) -> Result<ScansConfig> {
+    let text = fs::read_file_to_string(path)?;
+    toml::from_str(&text).map_err(|err| {
+        let desc = format!("malformed scans config: {}", err);
+        Error::with_source(MalformedData, desc, err)
+    })
+}
+
+// Adds config value unless the scan has one specified by flags.
+pub fn merge_scan_value<T, U, F>(
+    values: &mut Vec<(String, T)>,
+    config: &ScansConfig,
+    get: F,
+) where
+    F: Fn(&ScanConfig) -> Option<U>,
+    U: Into<T>,
+{
+    for (scan, scan_config) in config {
+        if let Some(value) = get(scan_config) {
+            if !values.iter().any(|(s, _)| s == scan) {
+                values.push((scan.clone(), value.into()));
+            }
+        }
+    }
+}
+
+impl ScanParams {
+    // Parameters to read scans with their stored poses and names, so that
+    // the poses can be overridden later (see apply_scan_poses).
+    pub fn without_poses(&self) -> ScanParams {
+        ScanParams {
+            calibration: None,
+            camera_initial_positions: Vec::new(),
+            camera_initial_directions: Vec::new(),
+            camera_up_angles: Vec::new(),
+            camera_angles_of_view: Vec::new(),
+            color_camera_rotations: Vec::new(),
+            color_camera_translations: Vec::new(),
+            color_camera_angles_of_view: Vec::new(),
+            names: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    pub fn merge_config(&mut self, config: &ScansConfig) {
+        merge_scan_value(&mut self.camera_initial_positions, config, |c| {
+            c.camera_initial_position.map(CliArray)
+        });
+        merge_scan_value(&mut self.camera_initial_directions, config, |c| {
+            c.camera_initial_direction.map(CliArray)
+        });
+        merge_scan_value(&mut self.camera_up_angles, config, |c| {
+            c.camera_up_angle
+        });
+        merge_scan_value(&mut self.camera_angles_of_view, config, |c| {
+            c.camera_angle_of_view
+        });
+        merge_scan_value(&mut self.color_camera_rotations, config, |c| {
+            c.color_camera_rotation.map(CliArray)
+        });
+        merge_scan_value(&mut self.color_camera_translations, config, |c| {
+            c.color_camera_translation.map(CliArray)
+        });
+        merge_scan_value(&mut self.color_camera_angles_of_view, config, |c| {
+            c.color_camera_angle_of_view
+        });
+        merge_scan_value(&mut self.downsample_factors, config, |c| {
+            c.downsample_factor
+        });
+        merge_scan_value(&mut self.names, config, |c| c.name.clone());
+
+        for (scan, scan_config) in config {
+            if scan_config.drop_depths && !self.drop_depths.contains(scan) {
+                self.drop_depths.push(scan.clone());
+            }
+            if scan_config.drop_images && !self.drop_images.contains(scan) {
+                self.drop_images.push(scan.clone());
+            }
+        }
+    }
+}
+
+fn check_unique_scans<T>(
+    check: &mut ParamCheck,
+    option: &str,
+    values: &[(String, T)],
+) {
+    let mut seen = HashSet::new();
+    for (scan, _) in values {
+        check.require(seen.insert(scan), || {
+            format!(
+                "{} is specified more than once for scan '{}'",
+                option, scan
+            )
+        });
+    }
+}
+
+impl CheckParams for ScanParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check_unique_scans(
+            check,
+            "--camera-initial-position",
+            &self.camera_initial_positions,
+        );
+        check_unique_scans(
+            check,
+            "--camera-initial-direction",
+            &self.camera_initial_directions,
+        );
+        check_unique_scans(check, "--camera-up-angle", &self.camera_up_angles);
+        check_unique_scans(
+            check,
+            "--camera-angle-of-view",
+            &self.camera_angles_of_view,
+        );
+        check_unique_scans(
+            check,
+            "--color-camera-rotation",
+            &self.color_camera_rotations,
+        );
+        check_unique_scans(
+            check,
+            "--color-camera-translation",
+            &self.color_camera_translations,
+        );
+        check_unique_scans(
+            check,
+            "--color-camera-angle-of-view",
+            &self.color_camera_angles_of_view,
+        );
+        check_unique_scans(
+            check,
+            "--downsample-factor",
+            &self.downsample_factors,
+        );
+        check_unique_scans(check, "--name", &self.names);
+
+        let angles = self
+            .camera_angles_of_view
+            .iter()
+            .map(|v| ("--camera-angle-of-view", v))
+            .chain(
+                self.color_camera_angles_of_view
+                    .iter()
+                    .map(|v| ("--color-camera-angle-of-view", v)),
+            );
+        for (option, (scan, angle)) in angles {
+            check.require(*angle > 0.0 && *angle < PI, || {
+                format!(
+                    "{} for scan '{}' should be within (0, pi) radians",
+                    option, scan
+                )
+            });
+        }
+        for (scan, factor) in &self.downsample_factors {
+            check.require(*factor > 0, || {
+                format!("--downsample-factor for scan '{}' is zero", scan)
+            });
+        }
+    }
+}
+
+// Name of scan standing for camera of multi-camera rig.
+pub fn rig_camera_scan_name(rig: &str, camera: u32) -> String {
+    format!("{}/{}", rig, camera)
+}
+
+// Splits multi-camera rig scan into per-camera scans.
+pub fn split_rig_scan(scan: &fm::Scan) -> Vec<fm::Scan> {
+    scan.cameras
+        .iter()
+        .enumerate()
+        .map(|(i, camera)| fm::Scan {
+            name: rig_camera_scan_name(&scan.name, i as u32),
+            camera_angle_of_view: camera.angle_of_view,
+            camera_up_angle: camera.up_angle,
+            camera_angular_velocity: scan.camera_angular_velocity,
+            camera_initial_position: camera.initial_position,
+            camera_initial_direction: camera.initial_direction,
+            image_width: camera.image_width,
+            image_height: camera.image_height,
+            depth_width: camera.depth_width,
+            depth_height: camera.depth_height,
+            sensor_plane_depth: camera.sensor_plane_depth,
+            cameras: Vec::new(),
+            color_camera: camera.color_camera.clone(),
+        })
+        .collect()
+}
+
+// Camera poses of calibrated scans override ones of same-named scans.
+pub fn apply_calibration(
+    reader: &mut dyn fm::Read,
+    scans: &mut IndexMap<String, fm::Scan>,
+) -> Result<()> {
+    while let Some(rec) = reader.read_record()? {
+        if let Some(fm::record::Type::Scan(calibrated)) = rec.r#type {
+            if let Some(scan) = scans.get_mut(&calibrated.name) {
+                scan.camera_initial_position =
+                    calibrated.camera_initial_position;
+                scan.camera_initial_direction =
+                    calibrated.camera_initial_direction;
+                scan.camera_up_angle = calibrated.camera_up_angle;
+            } else {
+                warn!(
+                    "skipped calibration of unknown scan '{}'",
+                    calibrated.name
+                );
+            }
+        }
+    }
+    Ok(())
 }
 
+// Rig scans are split into per-camera scans (see rig_camera_scan_name).
 pub fn read_scans(
     reader: &mut dyn fm::Read,
     scan_params: &ScanParams,
 ) -> Result<(IndexMap<String, fm::Scan>, Vec<fm::ScanFrame>)> {
     let mut scans = IndexMap::<String, fm::Scan>::new();
     let mut frames = Vec::<fm::ScanFrame>::new();
-    let mut last_time = 0;
+    let mut orphans = IndexMap::<String, usize>::new();
+    let mut rigs = HashSet::new();
+    let mut validator = ScanValidator::default();
+
+    let check = |res: Result<()>| match res {
+        Err(err) if scan_params.read_mode == ReadMode::Lenient => {
+            warn!("skipped record: {}", err);
+            Ok(false)
+        }
+        Err(err) => Err(err),
+        Ok(()) => Ok(true),
+    };
 
     loop {
         let rec = reader.read_record()?;
@@ -93,77 +517,47 @@ pub fn read_scans(
         use fm::record::Type::*;
         match rec.unwrap().r#type {
             Some(Scan(s)) => {
-                if !frames.is_empty() {
-                    let desc = format!("scan '{}' after scan frame ", &s.name);
-                    return Err(Error::new(InconsistentState, desc));
+                let res = validator.check_scan(&s);
+                if !check(res)? {
+                    continue;
+                }
+                if s.cameras.is_empty() {
+                    scans.insert(s.name.clone(), s);
+                } else {
+                    for camera_scan in split_rig_scan(&s) {
+                        scans.insert(camera_scan.name.clone(), camera_scan);
+                    }
+                    rigs.insert(s.name);
                 }
-                scans.insert(s.name.clone(), s);
             }
-            Some(ScanFrame(f)) => {
-                if !scans.contains_key(&f.scan) {
-                    let desc = format!("frame for unknown scan '{}'", &f.scan);
-                    return Err(Error::new(InconsistentState, desc));
+            Some(ScanFrame(mut f)) => {
+                if !scans.contains_key(&f.scan) && !rigs.contains(&f.scan) {
+                    if scan_params.unknown_scan == UnknownScanPolicy::Error {
+                        let desc =
+                            format!("frame for unknown scan '{}'", &f.scan);
+                        return Err(Error::new(InconsistentState, desc));
+                    }
+                    *orphans.entry(f.scan).or_default() += 1;
+                    continue;
                 }
-                if f.time < last_time {
-                    let desc = format!(
-                        "non-monotonic frame time for scan '{}'",
-                        &f.scan
-                    );
-                    return Err(Error::new(InconsistentState, desc));
+                if check(validator.check_frame(&f))? {
+                    if rigs.contains(&f.scan) {
+                        f.scan = rig_camera_scan_name(&f.scan, f.camera);
+                    }
+                    frames.push(f);
                 }
-                last_time = f.time;
-                frames.push(f);
             }
             _ => (),
         }
     }
 
-    let unknown_scan_err = |name| {
-        Err(Error::new(
-            InconsistentState,
-            format!("unknown scan '{}' specified", name),
-        ))
-    };
-
-    for (name, eye) in scan_params.camera_initial_positions.iter() {
-        if let Some(scan) = scans.get_mut(name) {
-            scan.camera_initial_position = Some(fm::Point3 {
-                x: eye.0[0],
-                y: eye.0[1],
-                z: eye.0[2],
-            });
-        } else {
-            return unknown_scan_err(name);
-        }
-    }
-
-    for (name, dir) in scan_params.camera_initial_directions.iter() {
-        if let Some(scan) = scans.get_mut(name) {
-            scan.camera_initial_direction = Some(fm::Point3 {
-                x: dir.0[0],
-                y: dir.0[1],
-                z: dir.0[2],
-            });
-        } else {
-            return unknown_scan_err(name);
+    if scan_params.unknown_scan == UnknownScanPolicy::Warn {
+        for (name, num) in orphans.iter() {
+            warn!("skipped {} frames for unknown scan '{}'", num, name);
         }
     }
 
-    for (name, angle) in scan_params.camera_up_angles.iter() {
-        if let Some(scan) = scans.get_mut(name) {
-            scan.camera_up_angle = *angle;
-        } else {
-            return unknown_scan_err(name);
-        }
-    }
-
-    for (name, angle) in scan_params.camera_angles_of_view.iter() {
-        if let Some(scan) = scans.get_mut(name) {
-            scan.camera_angle_of_view = *angle;
-        } else {
-            return unknown_scan_err(name);
-        }
-    }
+    apply_scan_poses(&mut scans, scan_params)?;
 
     for (name, _) in scan_params.downsample_factors.iter() {
         if scans.get_mut(name).is_none() {
@@ -203,6 +597,10 @@ pub fn read_scans(
         }
     }
 
+    for scan in scans.values_mut() {
+        register_color_camera(scan, &mut frames)?;
+    }
+
     for (name, new_name) in scan_params.names.iter() {
         if let Some(scan) = scans.get_mut(name) {
             scan.name = new_name.clone();
@@ -223,6 +621,190 @@ pub fn read_scans(
     Ok((scans, frames))
 }
 
+fn unknown_scan_err<T>(name: &str) -> Result<T> {
+    Err(Error::new(
+        InconsistentState,
+        format!("unknown scan '{}' specified", name),
+    ))
+}
+
+// Overrides camera poses of scans with calibration and flags.
+pub fn apply_scan_poses(
+    scans: &mut IndexMap<String, fm::Scan>,
+    scan_params: &ScanParams,
+) -> Result<()> {
+    if let Some(path) = &scan_params.calibration {
+        let mut reader = fm::Reader::new(fs::open_input(path)?)?;
+        apply_calibration(&mut reader, scans)?;
+    }
+
+    for (name, eye) in scan_params.camera_initial_positions.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            scan.camera_initial_position = Some(fm::Point3 {
+                x: eye.0[0],
+                y: eye.0[1],
+                z: eye.0[2],
+            });
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    for (name, dir) in scan_params.camera_initial_directions.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            scan.camera_initial_direction = Some(fm::Point3 {
+                x: dir.0[0],
+                y: dir.0[1],
+                z: dir.0[2],
+            });
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    for (name, angle) in scan_params.camera_up_angles.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            scan.camera_up_angle = *angle;
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    for (name, angle) in scan_params.camera_angles_of_view.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            scan.camera_angle_of_view = *angle;
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    let to_point3 = |a: &CliArray<f32, 3>| fm::Point3 {
+        x: a.0[0],
+        y: a.0[1],
+        z: a.0[2],
+    };
+
+    for (name, rotation) in scan_params.color_camera_rotations.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            color_camera(scan).rotation = Some(to_point3(rotation));
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    for (name, translation) in scan_params.color_camera_translations.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            color_camera(scan).translation = Some(to_point3(translation));
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    for (name, angle) in scan_params.color_camera_angles_of_view.iter() {
+        if let Some(scan) = scans.get_mut(name) {
+            color_camera(scan).angle_of_view = *angle;
+        } else {
+            return unknown_scan_err(name);
+        }
+    }
+
+    Ok(())
+}
+
+// Color camera defaults to one aligned with depth camera.
+fn color_camera(scan: &mut fm::Scan) -> &mut fm::scan::ColorCamera {
+    let angle_of_view = scan.camera_angle_of_view;
+    scan.color_camera
+        .get_or_insert_with(|| fm::scan::ColorCamera {
+            angle_of_view,
+            ..Default::default()
+        })
+}
+
+// Reprojects depths into color camera, which then becomes the scan camera,
+// so that depths get aligned with images.
+pub fn register_color_camera(
+    scan: &mut fm::Scan,
+    frames: &mut [fm::ScanFrame],
+) -> Result<()> {
+    let color_camera = match scan.color_camera.take() {
+        Some(color_camera) => color_camera,
+        None => return Ok(()),
+    };
+
+    let to_vector = |p: Option<fm::Point3>| {
+        let p = p.unwrap_or_default();
+        Vector3::new(p.x as f64, p.y as f64, p.z as f64)
+    };
+    let rotation = nalgebra::Rotation3::new(to_vector(color_camera.rotation));
+    let translation = to_vector(color_camera.translation);
+
+    let (width, height) =
+        (scan.depth_width as usize, scan.depth_height as usize);
+    let depth_tan = (scan.camera_angle_of_view as f64 / 2.0).tan();
+    let color_tan = (color_camera.angle_of_view as f64 / 2.0).tan();
+    let half_width = width as f64 / 2.0;
+
+    for frame in frames.iter_mut().filter(|f| f.scan == scan.name) {
+        if frame.depths.len() != width * height {
+            continue;
+        }
+
+        let has_confidences = !frame.depth_confidences.is_empty();
+        let mut depths = vec![f32::NAN; width * height];
+        let mut confidences = vec![0; frame.depth_confidences.len()];
+
+        for (k, &depth) in frame.depths.iter().enumerate() {
+            if !depth.is_finite() {
+                continue;
+            }
+            let pixel = (k / width, k % width);
+            let point = unproject_depth(
+                scan,
+                depth_tan,
+                (width, height),
+                pixel,
+                depth as f64,
+            );
+            let point = rotation * point + translation;
+
+            let depth = -point.z;
+            if depth <= 0.0 {
+                continue;
+            }
+            let j = point.x / depth * half_width / color_tan + half_width;
+            let i =
+                -point.y / depth * half_width / color_tan + height as f64 / 2.0;
+            let (i, j) = (i.round(), j.round());
+            if i < 0.0 || j < 0.0 || i >= height as f64 || j >= width as f64 {
+                continue;
+            }
+
+            let index = i as usize * width + j as usize;
+            if depths[index].is_nan() || depth < depths[index] as f64 {
+                depths[index] = depth as f32;
+                if has_confidences {
+                    confidences[index] = frame.depth_confidences[k];
+                }
+            }
+        }
+
+        frame.depths = depths;
+        frame.depth_confidences = confidences;
+    }
+
+    let view_rot = camera_view_rotation(scan)?;
+    let view_rot = view_rot.fixed_slice::<3, 3>(0, 0).into_owned();
+    let eye = to_vector(scan.camera_initial_position);
+    let color_eye = eye - view_rot * (rotation.inverse() * translation);
+    let color_view_rot = view_rot * rotation.inverse().into_inner();
+    set_camera_pose(scan, &color_view_rot, &color_eye)?;
+    scan.camera_angle_of_view = color_camera.angle_of_view;
+    scan.sensor_plane_depth = true;
+
+    Ok(())
+}
+
 pub fn downsample_scan_frames(
     downsample_factors: &HashMap<String, usize>,
     frames: &mut Vec<fm::ScanFrame>,
@@ -272,11 +854,13 @@ pub fn downsample_scan_frames(
 mod test {
     use super::*;
 
+    use crate::point_cloud::PointCloudParams;
     use base::assert_approx_eq;
+    use base::util::test::*;
 
     fn new_scan_frame(
         scan: &str,
-        time: fm::Time,
+        time: i64,
         depth: &[f32],
         depth_confidences: &[i32],
     ) -> fm::ScanFrame {
@@ -305,6 +889,213 @@ mod test {
         assert_eq!(a.depth_confidences, b.depth_confidences);
     }
 
+    fn new_named_scan_rec(name: &str) -> fm::Record {
+        new_scan_rec(fm::Scan {
+            name: name.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn new_timed_scan_frame_rec(scan: &str, time: i64) -> fm::Record {
+        new_scan_frame_rec(new_scan_frame(scan, time, &[], &[]))
+    }
+
+    #[test]
+    fn test_read_scans_unknown_scan() {
+        let records = [
+            new_named_scan_rec("a"),
+            new_timed_scan_frame_rec("a", 1),
+            new_timed_scan_frame_rec("b", 2),
+            new_timed_scan_frame_rec("a", 3),
+        ];
+
+        let params = ScanParams::from_iter(&["test"]);
+        let mut reader = create_reader_with_records(&records);
+        let err = read_scans(&mut reader, &params).err().unwrap();
+        assert_eq!(err.kind, InconsistentState);
+
+        for policy in ["skip", "warn"] {
+            let params =
+                ScanParams::from_iter(&["test", "--unknown-scan", policy]);
+            let mut reader = create_reader_with_records(&records);
+            let (scans, frames) = read_scans(&mut reader, &params).unwrap();
+            assert_eq!(scans.len(), 1);
+            assert_eq!(frames.len(), 2);
+            assert_eq!(frames[0].time, 1);
+            assert_eq!(frames[1].time, 3);
+        }
+    }
+
+    #[test]
+    fn test_read_scans_read_mode() {
+        let records = [
+            new_named_scan_rec("a"),
+            new_timed_scan_frame_rec("a", 1),
+            new_timed_scan_frame_rec("a", 1),
+            new_timed_scan_frame_rec("a", 0),
+            new_timed_scan_frame_rec("a", 2),
+        ];
+
+        let params = ScanParams::from_iter(&["test"]);
+        let mut reader = create_reader_with_records(&records);
+        let err = read_scans(&mut reader, &params).err().unwrap();
+        assert_eq!(err.kind, InconsistentState);
+
+        let params = ScanParams::from_iter(&["test", "--read-mode", "lenient"]);
+        let mut reader = create_reader_with_records(&records);
+        let (_, frames) = read_scans(&mut reader, &params).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].time, 1);
+        assert_eq!(frames[1].time, 2);
+    }
+
+    #[test]
+    fn test_read_scans_rig() {
+        let camera = |x| fm::scan::Camera {
+            initial_position: Some(new_point3(x, 0.0, 0.0)),
+            ..Default::default()
+        };
+        let frame = |time, camera| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "rig".to_string(),
+                time,
+                camera,
+                ..Default::default()
+            })
+        };
+        let records = [
+            new_scan_rec(fm::Scan {
+                name: "rig".to_string(),
+                camera_angular_velocity: 0.5,
+                cameras: vec![camera(1.0), camera(2.0)],
+                ..Default::default()
+            }),
+            frame(1, 0),
+            frame(1, 1),
+            frame(2, 1),
+        ];
+
+        let params = ScanParams::from_iter(&["test"]);
+        let mut reader = create_reader_with_records(&records);
+        let (scans, frames) = read_scans(&mut reader, &params).unwrap();
+        assert_eq!(scans.keys().collect::<Vec<_>>(), vec!["rig/0", "rig/1"]);
+        assert_eq!(scans["rig/1"].camera_initial_position.unwrap().x, 2.0);
+        assert_eq!(scans["rig/1"].camera_angular_velocity, 0.5);
+        let frame_scans: Vec<_> = frames.iter().map(|f| &f.scan).collect();
+        assert_eq!(frame_scans, vec!["rig/0", "rig/1", "rig/1"]);
+
+        let mut reader =
+            create_reader_with_records(&[records[0].clone(), frame(1, 2)]);
+        let err = read_scans(&mut reader, &params).err().unwrap();
+        assert_eq!(err.kind, InconsistentState);
+    }
+
+    #[test]
+    fn test_apply_calibration() {
+        let mut reader = create_reader_with_records(&[
+            new_scan_rec(fm::Scan {
+                name: "a".to_string(),
+                camera_up_angle: 0.5,
+                camera_initial_position: Some(new_point3(1.0, 2.0, 3.0)),
+                camera_angle_of_view: 2.0,
+                ..Default::default()
+            }),
+            new_named_scan_rec("b"),
+        ]);
+        let mut scans = IndexMap::new();
+        scans.insert(
+            "a".to_string(),
+            fm::Scan {
+                name: "a".to_string(),
+                camera_angle_of_view: 1.0,
+                ..Default::default()
+            },
+        );
+
+        apply_calibration(&mut reader, &mut scans).unwrap();
+        assert_eq!(scans.len(), 1);
+        assert_eq!(scans["a"].camera_up_angle, 0.5);
+        assert_eq!(scans["a"].camera_initial_position.unwrap().z, 3.0);
+        assert_eq!(scans["a"].camera_angle_of_view, 1.0);
+    }
+
+    #[test]
+    fn test_merge_scans_config() {
+        let config: ScansConfig = toml::from_str(
+            r#"
+            [a]
+            camera-up-angle = 0.5
+            camera-initial-position = [1.0, 2.0, 3.0]
+            drop-images = true
+            min-z = 0.1
+
+            [b]
+            camera-up-angle = 0.7
+            "#,
+        )
+        .unwrap();
+
+        let mut params =
+            ScanParams::from_iter(&["test", "--camera-up-angle", "b=0.2"]);
+        params.merge_config(&config);
+        assert_eq!(params.camera_up_angles.len(), 2);
+        assert_eq!(params.camera_up_angles[0], ("b".to_string(), 0.2));
+        assert_eq!(params.camera_up_angles[1], ("a".to_string(), 0.5));
+        assert_eq!(params.camera_initial_positions[0].1 .0, [1.0, 2.0, 3.0]);
+        assert_eq!(params.drop_images, vec!["a".to_string()]);
+
+        let mut point_cloud_params = PointCloudParams::from_iter(&["test"]);
+        point_cloud_params.merge_config(&config);
+        assert_eq!(point_cloud_params.min_z("a"), 0.1);
+
+        let records = [new_named_scan_rec("a")];
+        let mut reader = create_reader_with_records(&records);
+        let err = read_scans(&mut reader, &params).err().unwrap();
+        assert_eq!(err.kind, InconsistentState);
+
+        assert!(toml::from_str::<ScansConfig>("[a]\nmin-y = 0").is_err());
+    }
+
+    #[test]
+    fn test_register_color_camera() {
+        let mut scan = fm::Scan {
+            name: "a".to_string(),
+            camera_angle_of_view: 2.0 * 0.5f32.atan(),
+            camera_initial_position: Some(new_point3(0.0, -1.0, 0.0)),
+            depth_width: 16,
+            depth_height: 8,
+            sensor_plane_depth: true,
+            color_camera: Some(fm::scan::ColorCamera {
+                translation: Some(new_point3(0.1, 0.0, 0.0)),
+                angle_of_view: 2.0 * 0.5f32.atan(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut frames = vec![new_scan_frame(
+            "a",
+            0,
+            &(0..128)
+                .map(|k| 1.0 + (k % 16) as f32 / 100.0)
+                .collect::<Vec<_>>(),
+            &[1; 128],
+        )];
+
+        register_color_camera(&mut scan, &mut frames).unwrap();
+        assert!(scan.color_camera.is_none());
+        let eye = scan.camera_initial_position.unwrap();
+        assert_approx_eq!(eye.x, -0.1);
+        assert_approx_eq!(eye.y, -1.0);
+
+        // Shift of 0.1 at depth 1 is 1.6 pixels.
+        let depths = &frames[0].depths;
+        assert!(depths[0].is_nan() && depths[1].is_nan());
+        assert_approx_eq!(depths[2], 1.0);
+        assert_approx_eq!(depths[15], 1.14);
+        assert_eq!(frames[0].depth_confidences[2], 1);
+        assert_eq!(frames[0].depth_confidences[0], 0);
+    }
+
     #[test]
     fn test_downsample_scan_frames() {
         let mut frames = vec![
@@ -0,0 +1,311 @@
+use kiddo::distance::squared_euclidean;
+use kiddo::KdTree;
+use log::info;
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::misc::kdtree_err_to_err;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{build_frame_clouds, PointCloudParams, PointNormal};
+use crate::scan::{read_scans, read_scans_config, ScanParams};
+use crate::telemetry;
+use crate::threads::ThreadParams;
+use base::defs::Result;
+use base::fm;
+use base::fm::record::Type::*;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Split scan point cloud into smooth segments")]
+pub struct SegmentCloudCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(flatten)]
+    params: SegmentCloudParams,
+}
+
+impl SegmentCloudCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        segment_cloud(reader.as_mut(), writer.as_mut(), &self.params)
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct SegmentCloudParams {
+    #[structopt(flatten)]
+    pub scan: ScanParams,
+
+    #[structopt(flatten)]
+    pub point_cloud: PointCloudParams,
+
+    #[structopt(flatten)]
+    pub threads: ThreadParams,
+
+    #[structopt(
+        help = "Number of neighbors to grow segments through",
+        long,
+        default_value = "15"
+    )]
+    pub segment_num_neighbors: usize,
+
+    #[structopt(
+        help = "Maximum angle between neighbor normals (in degrees)",
+        long,
+        default_value = "10"
+    )]
+    pub segment_max_angle: f64,
+
+    #[structopt(
+        help = "Maximum distance between neighbor points",
+        long,
+        default_value = "inf"
+    )]
+    pub segment_max_distance: f64,
+
+    #[structopt(
+        help = "Minimum number of points per segment",
+        long,
+        default_value = "100"
+    )]
+    pub segment_min_size: usize,
+
+    #[structopt(
+        help = "Output element name prefix",
+        long,
+        short = "e",
+        default_value = "segment"
+    )]
+    pub element: String,
+}
+
+impl CheckParams for SegmentCloudParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        self.scan.check_into(check);
+        self.point_cloud.check_into(check);
+        check.require(self.segment_num_neighbors > 0, || {
+            "--segment-num-neighbors should be positive".to_string()
+        });
+        check.require(
+            self.segment_max_angle >= 0.0 && self.segment_max_angle <= 90.0,
+            || "--segment-max-angle should be within [0, 90]".to_string(),
+        );
+        check.require(self.segment_max_distance > 0.0, || {
+            "--segment-max-distance should be positive".to_string()
+        });
+        check.require(self.segment_min_size > 0, || {
+            "--segment-min-size should be positive".to_string()
+        });
+    }
+}
+
+pub fn segment_cloud(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    params: &SegmentCloudParams,
+) -> Result<()> {
+    let mut params = params.clone();
+    if let Some(path) = &params.scan.scans_config {
+        let config = read_scans_config(path)?;
+        params.scan.merge_config(&config);
+        params.point_cloud.merge_config(&config);
+    }
+    params.check()?;
+
+    info!("reading scans...");
+    let (scans, scan_frames) = read_scans(reader, &params.scan)?;
+    params
+        .point_cloud
+        .validate(scans.keys().map(String::as_str))?;
+
+    info!(
+        "building point clouds from {} scans ({} frames)...",
+        scans.len(),
+        scan_frames.len()
+    );
+    let pool = params.threads.pool()?;
+    let points: Vec<PointNormal> = pool
+        .install(|| {
+            build_frame_clouds(&scans, &scan_frames, &params.point_cloud)
+        })?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    info!("segmenting cloud of {} points...", points.len());
+    let segments = pool.install(|| segment_points(&points, &params))?;
+    telemetry::count("segments", segments.len());
+
+    for (i, segment) in segments.iter().enumerate() {
+        let element = format!("{}-{}", params.element, i);
+        info!("writing '{}' of {} points...", element, segment.len());
+        let point = |p: &[f64]| fm::Point3 {
+            x: p[0] as f32,
+            y: p[1] as f32,
+            z: p[2] as f32,
+        };
+        writer.write_record(&fm::Record {
+            r#type: Some(ElementView(fm::ElementView {
+                element: element.clone(),
+                ..Default::default()
+            })),
+        })?;
+        writer.write_record(&fm::Record {
+            r#type: Some(ElementViewState(fm::ElementViewState {
+                element,
+                vertices: segment
+                    .iter()
+                    .map(|&j| point(points[j].0.coords.as_slice()))
+                    .collect(),
+                normals: segment
+                    .iter()
+                    .map(|&j| point(points[j].1.as_slice()))
+                    .collect(),
+                ..Default::default()
+            })),
+        })?;
+    }
+
+    info!("done");
+    Ok(())
+}
+
+// Grows regions of points with nearly parallel normals, so that surfaces
+// meeting at an angle (e.g. a person and a chair) fall into different
+// segments. Seeds are taken from the smoothest points first. Returns point
+// indices of segments not smaller than the minimum size, largest first.
+pub fn segment_points(
+    points: &[PointNormal],
+    params: &SegmentCloudParams,
+) -> Result<Vec<Vec<usize>>> {
+    let mut kdtree = KdTree::new();
+    for (i, p) in points.iter().enumerate() {
+        kdtree
+            .add(p.0.coords.as_ref(), i)
+            .map_err(kdtree_err_to_err)?;
+    }
+
+    let max_sq_dist = params.segment_max_distance.powi(2);
+    let min_cos = params.segment_max_angle.to_radians().cos();
+    let num = 1 + params.segment_num_neighbors;
+    let neighbors = points
+        .par_iter()
+        .map(|p| {
+            let nearest = kdtree
+                .nearest(p.0.coords.as_ref(), num, &squared_euclidean)
+                .map_err(kdtree_err_to_err)?;
+            Ok(nearest
+                .into_iter()
+                .filter(|(d, _)| *d <= max_sq_dist)
+                .map(|(_, &j)| j)
+                .collect())
+        })
+        .collect::<Result<Vec<Vec<usize>>>>()?;
+
+    // Normals are compared up to sign as they might be flipped.
+    let parallel =
+        |i: usize, j: usize| points[i].1.dot(&points[j].1).abs() >= min_cos;
+
+    let residuals: Vec<f64> = neighbors
+        .iter()
+        .enumerate()
+        .map(|(i, js)| {
+            let sum: f64 = js
+                .iter()
+                .map(|&j| points[i].1.dot(&points[j].1).abs())
+                .sum();
+            1.0 - sum / js.len().max(1) as f64
+        })
+        .collect();
+    let mut seeds: Vec<usize> = (0..points.len()).collect();
+    seeds.sort_by(|&a, &b| residuals[a].total_cmp(&residuals[b]));
+
+    let mut visited = vec![false; points.len()];
+    let mut segments = Vec::new();
+    for seed in seeds {
+        if visited[seed] {
+            continue;
+        }
+        visited[seed] = true;
+
+        let (mut segment, mut stack) = (vec![seed], vec![seed]);
+        while let Some(i) = stack.pop() {
+            for &j in &neighbors[i] {
+                if !visited[j] && parallel(i, j) {
+                    visited[j] = true;
+                    segment.push(j);
+                    stack.push(j);
+                }
+            }
+        }
+
+        if segment.len() >= params.segment_min_size {
+            segment.sort_unstable();
+            segments.push(segment);
+        }
+    }
+
+    segments.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::{Point3, Vector3};
+
+    fn create_params() -> SegmentCloudParams {
+        SegmentCloudParams::from_iter(["segment-cloud"])
+    }
+
+    // Floor at Z=0 with a wall standing on it at X=0.
+    fn create_corner(num: usize) -> Vec<PointNormal> {
+        let mut points = Vec::new();
+        for i in 0..num {
+            for j in 0..num {
+                let (a, b) = (i as f64 * 0.1, j as f64 * 0.1 - 1.0);
+                points.push(PointNormal(
+                    Point3::new(a + 0.1, b, 0.0),
+                    Vector3::new(0.0, 0.0, 1.0),
+                ));
+                points.push(PointNormal(
+                    Point3::new(0.0, b, a),
+                    Vector3::new(1.0, 0.0, 0.0),
+                ));
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn test_segment_points() {
+        let points = create_corner(20);
+        let segments = segment_points(&points, &create_params()).unwrap();
+        assert_eq!(segments.len(), 2);
+        for segment in &segments {
+            assert_eq!(segment.len(), 400);
+            let normal = points[segment[0]].1;
+            assert!(segment.iter().all(|&i| points[i].1 == normal));
+        }
+    }
+
+    #[test]
+    fn test_segment_points_min_size() {
+        let mut points = create_corner(20);
+        points.truncate(300);
+        let mut params = create_params();
+        params.segment_min_size = 160;
+        let segments = segment_points(&points, &params).unwrap();
+        assert_eq!(segments.len(), 0);
+
+        params.segment_min_size = 150;
+        let segments = segment_points(&points, &params).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(segment_points(&[], &params).unwrap().is_empty());
+    }
+}
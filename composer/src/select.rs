@@ -1,9 +1,11 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use structopt::StructOpt;
 
-use crate::misc::{lua_err_to_err, lua_table_from_record};
-use base::defs::Result;
+use crate::misc::{glob_match, lua_err_to_err, lua_table_from_record};
+use crate::param_check::{CheckParams, ParamCheck};
+use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
 use base::util::cli;
 use base::util::fs;
@@ -21,15 +23,11 @@ pub struct SelectCommand {
         help = "Lua predicate expression",
         long,
         short = "p",
-        conflicts_with = "predicate-path",
-        required_unless = "predicate-path"
+        conflicts_with = "predicate-path"
     )]
     predicate: Option<String>,
 
-    #[structopt(
-        help = "Input .lua file with predicate expression",
-        long
-    )]
+    #[structopt(help = "Input .lua file with predicate expression", long)]
     predicate_path: Option<PathBuf>,
 
     #[structopt(
@@ -47,6 +45,9 @@ pub struct SelectCommand {
         conflicts_with = "no-rec-decoding",
     )]
     truncate_len: Option<usize>,
+
+    #[structopt(flatten)]
+    params: SelectParams,
 }
 
 impl SelectCommand {
@@ -54,55 +55,244 @@ impl SelectCommand {
         let mut reader = self.input.get()?;
         let mut writer = self.output.get()?;
 
-        let predicate = if let Some(path) = &self.predicate_path {
-            fs::read_file_to_string(path)?
-        } else {
-            self.predicate.as_ref().unwrap().to_string()
+        let predicate = match (&self.predicate_path, &self.predicate) {
+            (Some(path), _) => Some(fs::read_file_to_string(path)?),
+            (None, predicate) => predicate.clone(),
         };
 
         select(
             reader.as_mut(),
             writer.as_mut(),
-            &predicate,
+            predicate.as_deref(),
             self.no_rec_decoding,
             self.truncate_len,
+            &self.params,
         )
     }
 }
 
-#[derive(StructOpt)]
-pub struct SelectParams {}
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct SelectParams {
+    #[structopt(
+        help = "Element name glob (e.g. 'shirt*') of element records to keep",
+        long = "element",
+        number_of_values = 1
+    )]
+    pub elements: Vec<String>,
+
+    #[structopt(
+        help = "Scan name glob of scan and frame records to keep",
+        long = "scan",
+        number_of_values = 1
+    )]
+    pub scans: Vec<String>,
+
+    #[structopt(
+        help = "Keep states, textures and frames since given time (e.g. 1m30s)",
+        long,
+        allow_hyphen_values = true
+    )]
+    pub from_time: Option<fm::Time>,
+
+    #[structopt(
+        help = "Keep states, textures and frames until given time (inclusive)",
+        long,
+        allow_hyphen_values = true
+    )]
+    pub to_time: Option<fm::Time>,
+
+    #[structopt(
+        help = "Time offset added to kept records (before --time-scale)",
+        long,
+        allow_hyphen_values = true
+    )]
+    pub time_offset: Option<fm::Time>,
+
+    #[structopt(help = "Factor multiplying times of kept records", long)]
+    pub time_scale: Option<f64>,
+}
+
+impl CheckParams for SelectParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        if let (Some(from), Some(to)) = (self.from_time, self.to_time) {
+            check.require(from.0 <= to.0, || {
+                "--from-time should not exceed --to-time".to_string()
+            });
+        }
+        if let Some(scale) = self.time_scale {
+            check.require(scale > 0.0, || {
+                "--time-scale should be positive".to_string()
+            });
+        }
+    }
+}
+
+impl SelectParams {
+    fn is_active(&self) -> bool {
+        !self.elements.is_empty()
+            || !self.scans.is_empty()
+            || self.from_time.is_some()
+            || self.to_time.is_some()
+            || self.time_offset.is_some()
+            || self.time_scale.is_some()
+    }
+
+    // Filters apply to records of their kinds, others pass.
+    fn selects(&self, rec: &fm::Record) -> bool {
+        use fm::record::Type::*;
+        let (element, scan) = match &rec.r#type {
+            Some(ElementView(v)) => (Some(&v.element), None),
+            Some(ElementViewState(s)) => (Some(&s.element), None),
+            Some(ElementTexture(t)) => (Some(&t.element), None),
+            Some(ElementViewRefinement(r)) => (Some(&r.element), None),
+            Some(Scan(s)) => (None, Some(&s.name)),
+            Some(ScanFrame(f)) => (None, Some(&f.scan)),
+            _ => (None, None),
+        };
+        let matches = |globs: &[String], name: Option<&String>| {
+            globs.is_empty()
+                || name.is_none_or(|n| globs.iter().any(|g| glob_match(g, n)))
+        };
 
+        let in_range = match fm::record_order_key(rec) {
+            (1, time) => {
+                self.from_time.is_none_or(|from| time >= from)
+                    && self.to_time.is_none_or(|to| time <= to)
+            }
+            _ => true,
+        };
+        matches(&self.elements, element)
+            && matches(&self.scans, scan)
+            && in_range
+    }
+
+    // Clamps loop of view to the time range, removing it if it's outside,
+    // returns false if there is no loop to change.
+    fn trim_loop(&self, rec: &mut fm::Record) -> bool {
+        let view = match rec.r#type.as_mut() {
+            Some(fm::record::Type::ElementView(view)) => view,
+            _ => return false,
+        };
+        let animation_loop = match view.animation_loop.as_mut() {
+            Some(animation_loop) => animation_loop,
+            None => return false,
+        };
+        let from = self.from_time.unwrap_or(fm::Time::MIN).0;
+        let to = self.to_time.unwrap_or(fm::Time::MAX).0;
+        let start = animation_loop.start.max(from);
+        let end = animation_loop.end.min(to);
+        if (start, end) == (animation_loop.start, animation_loop.end) {
+            return false;
+        }
+        if start < end {
+            animation_loop.start = start;
+            animation_loop.end = end;
+        } else {
+            view.animation_loop = None;
+        }
+        true
+    }
+
+    // Shifts and scales the record time (or loop bounds of view), returns
+    // false if there is none. Camera angles following from frame times of
+    // known scans are kept.
+    fn retime(
+        &self,
+        rec: &mut fm::Record,
+        scans: &HashMap<String, fm::Scan>,
+    ) -> bool {
+        if self.time_offset.is_none() && self.time_scale.is_none() {
+            return false;
+        }
+        let retime = |time: i64| {
+            let shifted = fm::Time(time) + self.time_offset.unwrap_or_default();
+            match self.time_scale {
+                Some(scale) => (shifted.0 as f64 * scale).round() as i64,
+                None => shifted.0,
+            }
+        };
+        use fm::record::Type::*;
+        match rec.r#type.as_mut() {
+            Some(ElementViewState(state)) => state.time = retime(state.time),
+            Some(ElementTexture(texture)) => {
+                texture.time = retime(texture.time)
+            }
+            Some(ScanFrame(frame)) => {
+                if let Some(scan) = scans.get(&frame.scan) {
+                    fm::pin_camera_angle(scan, frame);
+                }
+                frame.time = retime(frame.time)
+            }
+            Some(ElementView(fm::ElementView {
+                animation_loop: Some(animation_loop),
+                ..
+            })) => {
+                animation_loop.start = retime(animation_loop.start);
+                animation_loop.end = retime(animation_loop.end);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+// Keeps records passing both the filters and the predicate (if any).
 pub fn select(
     reader: &mut dyn fm::Read,
     writer: &mut dyn fm::Write,
-    predicate: &str,
+    predicate: Option<&str>,
     no_rec_decoding: bool,
     truncate_len: Option<usize>,
+    params: &SelectParams,
 ) -> Result<()> {
+    params.check()?;
+    if no_rec_decoding && params.is_active() {
+        let desc = "record filters require record decoding".to_string();
+        return Err(Error::new(BadOperation, desc));
+    }
+
     let lua = rlua::Lua::new();
+    let mut scans = HashMap::new();
     let mut num = 1;
 
     while let Some(raw) = reader.read_raw_record()? {
-        let rec = if no_rec_decoding {
+        let mut rec = if no_rec_decoding {
             None
         } else {
             Some(raw.decode()?)
         };
+        if let Some(fm::Record {
+            r#type: Some(fm::record::Type::Scan(scan)),
+        }) = &rec
+        {
+            scans.insert(scan.name.clone(), scan.clone());
+        }
 
-        let res = lua
-            .context(|ctx| {
-                ctx.globals().set("n", num)?;
-                if let Some(rec) = rec {
-                    let tbl = lua_table_from_record(ctx, &rec, truncate_len)?;
-                    ctx.globals().set("r", tbl)?;
-                }
-                ctx.load(predicate).eval()
-            })
-            .map_err(lua_err_to_err)?;
+        let mut res = rec.as_ref().is_none_or(|rec| params.selects(rec));
+        if let (Some(predicate), true) = (predicate, res) {
+            res = lua
+                .context(|ctx| {
+                    ctx.globals().set("n", num)?;
+                    if let Some(rec) = &rec {
+                        let tbl =
+                            lua_table_from_record(ctx, rec, truncate_len)?;
+                        ctx.globals().set("r", tbl)?;
+                    }
+                    ctx.load(predicate).eval()
+                })
+                .map_err(lua_err_to_err)?;
+        }
 
         if res {
-            writer.write_raw_record(&raw)?;
+            // Loops are trimmed to the time range before retiming.
+            let changed = rec.as_mut().is_some_and(|rec| {
+                let trimmed = params.trim_loop(rec);
+                params.retime(rec, &scans) || trimmed
+            });
+            match rec {
+                Some(rec) if changed => writer.write_record(&rec)?,
+                _ => writer.write_raw_record(&raw)?,
+            }
         }
         num += 1;
     }
@@ -124,17 +314,17 @@ mod tests {
         no_rec_decoding: bool,
         truncate_len: Option<usize>,
     ) -> fm::Reader<io::Cursor<Vec<u8>>> {
-        let mut reader = create_reader_with_records(&vec![
+        let mut reader = create_reader_with_records(&[
             new_element_view_rec(fm::ElementView {
-                element: format!("e123"),
+                element: "e123".to_string(),
                 ..Default::default()
             }),
             new_element_view_state_rec(fm::ElementViewState {
-                element: format!("e124"),
+                element: "e124".to_string(),
                 ..Default::default()
             }),
             new_element_view_state_rec(fm::ElementViewState {
-                element: format!("e134"),
+                element: "e134".to_string(),
                 ..Default::default()
             }),
         ]);
@@ -143,9 +333,10 @@ mod tests {
         select(
             &mut reader,
             &mut writer,
-            predicate,
+            Some(predicate),
             no_rec_decoding,
             truncate_len,
+            &Default::default(),
         )
         .unwrap();
         writer_to_reader(writer)
@@ -194,4 +385,199 @@ mod tests {
 
         assert!(reader.read_record().unwrap().is_none());
     }
+
+    #[test]
+    fn test_select_filters() {
+        const SEC: i64 = fm::NANOS_PER_SEC;
+        let records = [
+            new_element_view_rec(fm::ElementView {
+                element: "shirt1".to_string(),
+                ..Default::default()
+            }),
+            new_element_view_rec(fm::ElementView {
+                element: "pants".to_string(),
+                ..Default::default()
+            }),
+            new_scan_rec(fm::Scan {
+                name: "front".to_string(),
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "shirt1".to_string(),
+                time: SEC,
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "pants".to_string(),
+                time: 2 * SEC,
+                ..Default::default()
+            }),
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "front".to_string(),
+                time: 2 * SEC,
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: "shirt1".to_string(),
+                time: 3 * SEC,
+                ..Default::default()
+            }),
+        ];
+        let select_with = |predicate, args: &[&str]| {
+            let mut reader = create_reader_with_records(&records);
+            let mut writer = create_writer();
+            let args = ["test"].iter().chain(args);
+            let params = SelectParams::from_iter(args);
+            select(&mut reader, &mut writer, predicate, false, None, &params)?;
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader)
+                .map(|r| {
+                    Ok(match r?.r#type {
+                        Some(ElementView(v)) => v.element,
+                        Some(Scan(s)) => s.name,
+                        Some(ElementViewState(s)) => {
+                            format!("{}@{}", s.element, s.time / SEC)
+                        }
+                        Some(ScanFrame(f)) => {
+                            format!("{}@{}", f.scan, f.time / SEC)
+                        }
+                        _ => String::new(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+
+        let selected = select_with(None, &["--element", "sh?rt*"]).unwrap();
+        assert_eq!(
+            selected,
+            ["shirt1", "front", "shirt1@1", "front@2", "shirt1@3"]
+        );
+
+        let args = ["--scan", "back", "--from-time", "1.5s", "--to-time", "3s"];
+        let selected = select_with(None, &args).unwrap();
+        assert_eq!(selected, ["shirt1", "pants", "pants@2", "shirt1@3"]);
+
+        // Trims to a window starting at zero and slowed down twice.
+        let args = [
+            "--from-time",
+            "2s",
+            "--time-offset=-2s",
+            "--time-scale",
+            "2",
+        ];
+        let predicate = Some("r.type.Scan == nil");
+        let selected = select_with(predicate, &args).unwrap();
+        assert_eq!(
+            selected,
+            ["shirt1", "pants", "pants@0", "front@0", "shirt1@2"]
+        );
+
+        let args = ["--from-time", "2s", "--to-time", "1s"];
+        assert_eq!(select_with(None, &args).unwrap_err().kind, BadOperation);
+
+        let mut reader = create_reader_with_records(&records);
+        let params = SelectParams::from_iter(["test", "--element", "pants"]);
+        let mut writer = create_writer();
+        let res = select(&mut reader, &mut writer, None, true, None, &params);
+        assert_eq!(res.unwrap_err().kind, BadOperation);
+    }
+
+    #[test]
+    fn test_select_loops() {
+        const SEC: i64 = fm::NANOS_PER_SEC;
+        let new_view = |element: &str, start, end| {
+            new_element_view_rec(fm::ElementView {
+                element: element.to_string(),
+                animation_loop: Some(fm::element_view::Loop {
+                    start: start * SEC,
+                    end: end * SEC,
+                    ping_pong: false,
+                }),
+                ..Default::default()
+            })
+        };
+        let records = [
+            new_view("inside", 2, 3),
+            new_view("across", 1, 5),
+            new_view("before", 0, 1),
+        ];
+        let select_with = |args: &[&str]| {
+            let mut reader = create_reader_with_records(&records);
+            let mut writer = create_writer();
+            let args = ["test"].iter().chain(args);
+            let params = SelectParams::from_iter(args);
+            select(&mut reader, &mut writer, None, false, None, &params)
+                .unwrap();
+            let mut reader = writer_to_reader(writer);
+            fm::records(&mut reader)
+                .map(|r| {
+                    let view = record_variant!(ElementView, r.unwrap());
+                    view.animation_loop.map(|l| (l.start / SEC, l.end / SEC))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let loops = select_with(&["--time-offset=-1s", "--time-scale", "2"]);
+        assert_eq!(loops, [Some((2, 4)), Some((0, 8)), Some((-2, 0))]);
+
+        let args = ["--from-time", "2s", "--to-time", "4s"];
+        let loops = select_with(&args);
+        assert_eq!(loops, [Some((2, 3)), Some((2, 4)), None]);
+
+        let args = ["--from-time", "2s", "--time-offset=-2s"];
+        let loops = select_with(&args);
+        assert_eq!(loops, [Some((0, 1)), Some((0, 3)), None]);
+    }
+
+    #[test]
+    fn test_select_retimed_camera_angles() {
+        const SEC: i64 = fm::NANOS_PER_SEC;
+        let scan = fm::Scan {
+            name: "s".to_string(),
+            camera_angular_velocity: 0.5,
+            ..Default::default()
+        };
+        let new_frame = |time, camera_angle| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: "s".to_string(),
+                time,
+                camera_angle,
+                ..Default::default()
+            })
+        };
+        let fixed = fm::scan_frame::CameraAngle { radians: 3.0 };
+        let mut reader = create_reader_with_records(&[
+            new_scan_rec(scan.clone()),
+            new_frame(SEC, None),
+            new_frame(2 * SEC, Some(fixed.clone())),
+            new_frame(4 * SEC, None),
+        ]);
+        let mut writer = create_writer();
+        let args = ["test", "--time-offset", "3s", "--time-scale", "0.5"];
+        let params = SelectParams::from_iter(args);
+        select(&mut reader, &mut writer, None, false, None, &params).unwrap();
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(Scan, rec), scan);
+        let expected = [(2 * SEC, 0.5), (5 * SEC / 2, 3.0), (7 * SEC / 2, 2.0)];
+        for (time, radians) in expected {
+            let rec = reader.read_record().unwrap().unwrap();
+            let frame = record_variant!(ScanFrame, rec);
+            assert_eq!(frame.time, time);
+            assert_eq!(fm::camera_angle(&scan, &frame), radians);
+        }
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("shirt*", "shirt"));
+        assert!(glob_match("*-lod?", "body-lod1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a*b", "aXbY"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("pants", "pants2"));
+    }
 }
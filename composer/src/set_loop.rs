@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Set animation loops of .fm file elements")]
+pub struct SetLoopCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(flatten)]
+    params: SetLoopParams,
+}
+
+impl SetLoopCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let mut writer = self.output.get()?;
+        set_loop(reader.as_mut(), writer.as_mut(), &self.params)
+    }
+}
+
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct SetLoopParams {
+    #[structopt(
+        help = "Element to set loop of (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    pub elements: Vec<String>,
+
+    #[structopt(help = "Loop start (the first state time if omitted)", long)]
+    pub start: Option<fm::Time>,
+
+    #[structopt(help = "Loop end (the last state time if omitted)", long)]
+    pub end: Option<fm::Time>,
+
+    #[structopt(
+        help = "Play the loop there and back instead of restarting it",
+        long
+    )]
+    pub ping_pong: bool,
+
+    #[structopt(help = "Drop states and textures outside of the loop", long)]
+    pub trim: bool,
+
+    #[structopt(
+        conflicts_with_all = &["start", "end", "ping-pong", "trim"],
+        help = "Remove loops of elements instead of setting them",
+        long
+    )]
+    pub clear: bool,
+}
+
+impl CheckParams for SetLoopParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            check.require(start.0 < end.0, || {
+                "--start should precede --end".to_string()
+            });
+        }
+    }
+}
+
+impl SetLoopParams {
+    fn selects(&self, element: &str) -> bool {
+        self.elements.is_empty() || self.elements.iter().any(|e| e == element)
+    }
+}
+
+pub fn set_loop(
+    reader: &mut dyn fm::Read,
+    writer: &mut dyn fm::Write,
+    params: &SetLoopParams,
+) -> Result<()> {
+    params.check()?;
+
+    let mut records = Vec::new();
+    let mut views = Vec::new();
+    // First and last state times of elements.
+    let mut spans: HashMap<String, (i64, i64)> = HashMap::new();
+    while let Some(rec) = reader.read_record()? {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementView(v)) if params.selects(&v.element) => {
+                views.push(records.len());
+            }
+            Some(ElementViewState(s)) => {
+                let span = spans.entry(s.element.clone());
+                let span = span.or_insert((s.time, s.time));
+                span.0 = span.0.min(s.time);
+                span.1 = span.1.max(s.time);
+            }
+            _ => {}
+        }
+        records.push(rec);
+    }
+
+    let mut loops = HashMap::new();
+    for &index in &views {
+        let view = match &mut records[index].r#type {
+            Some(fm::record::Type::ElementView(view)) => view,
+            _ => unreachable!(),
+        };
+        if params.clear {
+            view.animation_loop = None;
+            continue;
+        }
+
+        let span = spans.get(&view.element);
+        let start = params.start.map(|t| t.0).or(span.map(|s| s.0));
+        let end = params.end.map(|t| t.0).or(span.map(|s| s.1));
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            (Some(_), Some(_)) => {
+                let desc = format!("empty loop of element '{}'", view.element);
+                return Err(Error::new(InconsistentState, desc));
+            }
+            _ => {
+                let desc = format!("no states of element '{}'", view.element);
+                return Err(Error::new(InconsistentState, desc));
+            }
+        };
+        view.animation_loop = Some(fm::element_view::Loop {
+            start,
+            end,
+            ping_pong: params.ping_pong,
+        });
+        loops.insert(view.element.clone(), (start, end));
+    }
+
+    for element in &params.elements {
+        let found = views.iter().any(|&i| match &records[i].r#type {
+            Some(fm::record::Type::ElementView(v)) => &v.element == element,
+            _ => false,
+        });
+        if !found {
+            let desc = format!("unknown element '{}'", element);
+            return Err(Error::new(BadOperation, desc));
+        }
+    }
+
+    if params.trim {
+        records = trim_records(records, &loops);
+    }
+    for rec in records {
+        writer.write_record(&rec)?;
+    }
+    Ok(())
+}
+
+// Drops states outside of element loops and textures replaced before the
+// loop start or following its end.
+fn trim_records(
+    records: Vec<fm::Record>,
+    loops: &HashMap<String, (i64, i64)>,
+) -> Vec<fm::Record> {
+    // Times of textures shown at loop starts.
+    let mut start_textures: HashMap<String, i64> = HashMap::new();
+    for rec in &records {
+        if let Some(fm::record::Type::ElementTexture(t)) = &rec.r#type {
+            match loops.get(&t.element) {
+                Some(&(start, _)) if t.time <= start => {
+                    let time = start_textures
+                        .entry(t.element.clone())
+                        .or_insert(t.time);
+                    *time = (*time).max(t.time);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let keeps = |rec: &fm::Record| {
+        use fm::record::Type::*;
+        match &rec.r#type {
+            Some(ElementViewState(s)) => match loops.get(&s.element) {
+                Some(&(start, end)) => s.time >= start && s.time <= end,
+                None => true,
+            },
+            Some(ElementTexture(t)) => match loops.get(&t.element) {
+                Some(&(start, end)) => {
+                    let first = start_textures.get(&t.element);
+                    t.time >= *first.unwrap_or(&start) && t.time <= end
+                }
+                None => true,
+            },
+            _ => true,
+        }
+    };
+    records.into_iter().filter(|rec| keeps(rec)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    fn new_state_rec(element: &str, time: i64) -> fm::Record {
+        new_element_view_state_rec(fm::ElementViewState {
+            element: element.to_string(),
+            time,
+            ..Default::default()
+        })
+    }
+
+    fn new_texture_rec(element: &str, time: i64) -> fm::Record {
+        fm::Record {
+            r#type: Some(fm::record::Type::ElementTexture(
+                fm::ElementTexture {
+                    element: element.to_string(),
+                    time,
+                    texture: None,
+                },
+            )),
+        }
+    }
+
+    fn run(records: &[fm::Record], args: &[&str]) -> Result<Vec<fm::Record>> {
+        let params = SetLoopParams::from_iter_safe(
+            ["set-loop"].iter().chain(args.iter()),
+        )
+        .unwrap();
+        let mut reader = create_reader_with_records(records);
+        let mut writer = create_writer();
+        set_loop(&mut reader, &mut writer, &params)?;
+
+        let mut reader = writer_to_reader(writer);
+        let mut records = Vec::new();
+        while let Some(rec) = reader.read_record().unwrap() {
+            records.push(rec);
+        }
+        Ok(records)
+    }
+
+    fn animation_loop(rec: &fm::Record) -> Option<fm::element_view::Loop> {
+        let view = record_variant!(ElementView, rec.clone());
+        view.animation_loop
+    }
+
+    #[test]
+    fn test_set_loop() {
+        let new_view_rec = |element: &str| {
+            new_element_view_rec(fm::ElementView {
+                element: element.to_string(),
+                ..Default::default()
+            })
+        };
+        let records = [
+            new_view_rec("a"),
+            new_view_rec("b"),
+            new_texture_rec("a", 0),
+            new_state_rec("a", 1),
+            new_state_rec("b", 1),
+            new_texture_rec("a", 2),
+            new_state_rec("a", 3),
+            new_state_rec("a", 5),
+            new_texture_rec("a", 6),
+            new_state_rec("a", 7),
+            new_state_rec("b", 3),
+        ];
+
+        let output = run(&records, &[]).unwrap();
+        assert_eq!(output.len(), records.len());
+        let expected = fm::element_view::Loop {
+            start: 1,
+            end: 7,
+            ping_pong: false,
+        };
+        assert_eq!(animation_loop(&output[0]), Some(expected));
+
+        let args = [
+            "--element=a",
+            "--start=3ns",
+            "--end=5ns",
+            "--ping-pong",
+            "--trim",
+        ];
+        let output = run(&records, &args).unwrap();
+        let expected = fm::element_view::Loop {
+            start: 3,
+            end: 5,
+            ping_pong: true,
+        };
+        assert_eq!(animation_loop(&output[0]), Some(expected));
+        assert_eq!(animation_loop(&output[1]), None);
+        let times: Vec<_> = output[2..]
+            .iter()
+            .map(|rec| match &rec.r#type {
+                Some(fm::record::Type::ElementViewState(s)) => (s.time, 's'),
+                Some(fm::record::Type::ElementTexture(t)) => (t.time, 't'),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(times, [(1, 's'), (2, 't'), (3, 's'), (5, 's'), (3, 's')]);
+
+        let output = run(&output, &["--clear"]).unwrap();
+        assert_eq!(animation_loop(&output[0]), None);
+
+        assert!(run(&records, &["--element=c"]).is_err());
+        assert!(run(&records, &["--start=5ns", "--end=3ns"]).is_err());
+        assert!(run(&records, &["--start=2ns"]).is_ok());
+        assert!(run(&records, &["--start=3ns"]).is_err());
+    }
+}
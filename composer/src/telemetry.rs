@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::{Read as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+use tracing::{span, Subscriber};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use uuid::Uuid;
+
+use base::defs::{Error, ErrorKind::*, Result};
+use base::util::fs;
+
+#[derive(StructOpt)]
+pub struct TelemetryParams {
+    #[structopt(
+        help = "OpenTelemetry collector to send events to \
+                (otlp://host:port or otlps://host:port)",
+        long
+    )]
+    telemetry: Option<String>,
+
+    #[structopt(help = "Output JSON lines file with telemetry events", long)]
+    telemetry_file: Option<PathBuf>,
+}
+
+// Reports progress of a command run as structured events, written to a JSON
+// lines file as they come and sent to a collector when the command finishes.
+pub struct Telemetry {
+    command: String,
+    run: String,
+    started: Instant,
+    file: Option<Mutex<File>>,
+    otlp: Option<(String, Mutex<Vec<Value>>)>,
+}
+
+static TELEMETRY: OnceLock<Telemetry> = OnceLock::new();
+
+impl Telemetry {
+    pub fn new(
+        params: &TelemetryParams,
+        command: &str,
+    ) -> Result<Option<Telemetry>> {
+        if params.telemetry.is_none() && params.telemetry_file.is_none() {
+            return Ok(None);
+        }
+
+        let otlp = match &params.telemetry {
+            Some(location) => {
+                let url = otlp_logs_url(location)?;
+                #[cfg(not(feature = "remote"))]
+                {
+                    let desc = format!(
+                        "telemetry location '{}' requires 'remote' feature",
+                        url
+                    );
+                    return Err(Error::new(UnsupportedFeature, desc));
+                }
+                #[cfg(feature = "remote")]
+                Some((url, Mutex::new(Vec::new())))
+            }
+            None => None,
+        };
+
+        let file = match &params.telemetry_file {
+            Some(path) => Some(Mutex::new(fs::create_file(path)?)),
+            None => None,
+        };
+
+        Ok(Some(Telemetry {
+            command: command.to_string(),
+            run: Uuid::new_v4().to_string(),
+            started: Instant::now(),
+            file,
+            otlp,
+        }))
+    }
+
+    pub fn emit(&self, event: &str, fields: Value) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut obj = Map::new();
+        obj.insert("time".to_string(), json!(time));
+        obj.insert("run".to_string(), json!(self.run));
+        obj.insert("command".to_string(), json!(self.command));
+        obj.insert("event".to_string(), json!(event));
+        if let Value::Object(fields) = fields {
+            obj.extend(fields);
+        }
+        let obj = Value::Object(obj);
+
+        if let Some(file) = &self.file {
+            let line = format!("{}\n", obj);
+            if let Err(err) = file.lock().unwrap().write_all(line.as_bytes()) {
+                warn!("failed to write telemetry event: {}", err);
+            }
+        }
+        if let Some((_, events)) = &self.otlp {
+            events.lock().unwrap().push(obj);
+        }
+    }
+
+    pub fn finish(&self, res: &Result<()>) {
+        if let Err(err) = res {
+            self.emit(
+                "error",
+                json!({
+                    "kind": format!("{:?}", err.kind),
+                    "description": err.to_string(),
+                }),
+            );
+        }
+
+        let mut fields = json!({
+            "ok": res.is_ok(),
+            "duration": self.started.elapsed().as_secs_f64(),
+        });
+        if let Some(peak_memory) = peak_memory() {
+            fields["peak_memory"] = json!(peak_memory);
+        }
+        if let Some(cpu_time) = cpu_time() {
+            fields["cpu_time"] = json!(cpu_time);
+        }
+        self.emit("command_finish", fields);
+
+        if let Some((url, events)) = &self.otlp {
+            let data = to_otlp_logs(&events.lock().unwrap()).to_string();
+            #[cfg(feature = "remote")]
+            if let Err(err) =
+                base::util::remote::post_json(url, data.as_bytes())
+            {
+                warn!("failed to send telemetry: {}", err);
+            }
+            #[cfg(not(feature = "remote"))]
+            let _ = (url, data);
+        }
+    }
+}
+
+// Initializes the global telemetry, reporting the command start.
+pub fn init(params: &TelemetryParams, command: &str) -> Result<()> {
+    if let Some(telemetry) = Telemetry::new(params, command)? {
+        let args: Vec<_> = std::env::args().skip(1).collect();
+        telemetry.emit("command_start", json!({ "args": args }));
+        let _ = TELEMETRY.set(telemetry);
+    }
+    Ok(())
+}
+
+// Reports a number of processed items, e.g. records or vertices.
+pub fn count(name: &str, value: usize) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        telemetry.emit("count", json!({ "name": name, "value": value }));
+    }
+}
+
+pub fn finish(res: &Result<()>) {
+    if let Some(telemetry) = TELEMETRY.get() {
+        telemetry.finish(res);
+    }
+}
+
+// Installs the global tracing subscriber, reporting spans as telemetry stages
+// and optionally as a chrome-tracing profile. The guard flushes the profile
+// when dropped.
+pub fn init_tracing(profile: Option<&Path>) -> Option<FlushGuard> {
+    let stages = TELEMETRY.get().map(StageLayer);
+    let (profile, guard) = match profile {
+        Some(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new().file(path).build();
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+    if stages.is_some() || profile.is_some() {
+        // Not init(), as the log crate is already served by the terminal
+        // logger.
+        let subscriber =
+            tracing_subscriber::registry().with(stages).with(profile);
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+    }
+    guard
+}
+
+// Emits start and finish events of tracing spans.
+pub struct StageLayer(&'static Telemetry);
+
+impl<S> Layer<S> for StageLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: Context<'_, S>,
+    ) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Instant::now());
+            let parent = span.parent().map(|p| p.name());
+            self.0.emit(
+                "stage_start",
+                json!({ "stage": span.name(), "parent": parent }),
+            );
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let started = span.extensions().get::<Instant>().copied();
+            let duration = started.map(|s| s.elapsed().as_secs_f64());
+            self.0.emit(
+                "stage_finish",
+                json!({ "stage": span.name(), "duration": duration }),
+            );
+        }
+    }
+}
+
+// Maps otlp://host:port to the OTLP/HTTP logs endpoint.
+fn otlp_logs_url(location: &str) -> Result<String> {
+    let (scheme, rest) = if let Some(rest) = location.strip_prefix("otlp://") {
+        ("http", rest)
+    } else if let Some(rest) = location.strip_prefix("otlps://") {
+        ("https", rest)
+    } else {
+        let desc = format!("unsupported telemetry location '{}'", location);
+        return Err(Error::new(BadOperation, desc));
+    };
+    Ok(format!(
+        "{}://{}/v1/logs",
+        scheme,
+        rest.trim_end_matches('/')
+    ))
+}
+
+fn to_otlp_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        Value::Array(a) => json!({
+            "arrayValue": {
+                "values": a.iter().map(to_otlp_value).collect::<Vec<_>>()
+            }
+        }),
+        _ => json!({ "stringValue": value.to_string() }),
+    }
+}
+
+// Converts events to an OTLP/HTTP JSON logs request.
+fn to_otlp_logs(events: &[Value]) -> Value {
+    let records: Vec<_> = events
+        .iter()
+        .map(|event| {
+            let time = event["time"].as_f64().unwrap_or_default();
+            let error = event["event"] == "error";
+            let attributes: Vec<_> = event
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter(|(k, v)| *k != "time" && !v.is_null())
+                .map(|(k, v)| json!({ "key": k, "value": to_otlp_value(v) }))
+                .collect();
+            json!({
+                "timeUnixNano": format!("{}", (time * 1e9) as u64),
+                "severityText": if error { "ERROR" } else { "INFO" },
+                "body": { "stringValue": event["event"] },
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceLogs": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": "composer" },
+                }],
+            },
+            "scopeLogs": [{
+                "scope": { "name": "composer" },
+                "logRecords": records,
+            }],
+        }],
+    })
+}
+
+fn read_proc_file(name: &str) -> Option<String> {
+    let mut text = String::new();
+    File::open(Path::new("/proc/self").join(name))
+        .ok()?
+        .read_to_string(&mut text)
+        .ok()?;
+    Some(text)
+}
+
+// Returns peak resident set size in bytes (Linux only).
+fn peak_memory() -> Option<u64> {
+    let status = read_proc_file("status")?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Returns user and system CPU time in seconds (Linux only).
+fn cpu_time() -> Option<f64> {
+    let stat = read_proc_file("stat")?;
+    // Skip the command name, as it may contain spaces.
+    let fields: Vec<_> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    // Assume the usual clock tick of 100 Hz.
+    Some((utime + stime) as f64 / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_events(path: &Path) -> Vec<Value> {
+        let text = std::fs::read_to_string(path).unwrap();
+        text.lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_telemetry_file() {
+        let path = std::env::temp_dir().join("test_telemetry_file.jsonl");
+        let params = TelemetryParams {
+            telemetry: None,
+            telemetry_file: Some(path.clone()),
+        };
+        let telemetry = Telemetry::new(&params, "foo").unwrap().unwrap();
+        let telemetry: &'static Telemetry = Box::leak(Box::new(telemetry));
+
+        let subscriber =
+            tracing_subscriber::registry().with(StageLayer(telemetry));
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("bar").entered();
+            tracing::info_span!("baz").in_scope(|| {
+                telemetry.emit("count", json!({ "name": "qux", "value": 3 }));
+            });
+        });
+        let err = Error::new(MalformedData, "broken".to_string());
+        telemetry.finish(&Err(err));
+
+        let events = read_events(&path);
+        let names: Vec<_> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "stage_start",
+                "stage_start",
+                "count",
+                "stage_finish",
+                "stage_finish",
+                "error",
+                "command_finish",
+            ]
+        );
+        for event in &events {
+            assert_eq!(event["command"], "foo");
+            assert_eq!(event["run"], events[0]["run"]);
+        }
+        assert_eq!(events[0]["stage"], "bar");
+        assert!(events[0]["parent"].is_null());
+        assert_eq!(events[1]["stage"], "baz");
+        assert_eq!(events[1]["parent"], "bar");
+        assert_eq!(events[2]["value"], 3);
+        assert_eq!(events[3]["stage"], "baz");
+        assert!(events[3]["duration"].as_f64().unwrap() >= 0.0);
+        assert_eq!(events[4]["stage"], "bar");
+        assert_eq!(events[5]["kind"], "MalformedData");
+        assert_eq!(events[5]["description"], "broken");
+        assert_eq!(events[6]["ok"], false);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_otlp_logs() {
+        assert_eq!(
+            otlp_logs_url("otlp://localhost:4318/").unwrap(),
+            "http://localhost:4318/v1/logs"
+        );
+        assert_eq!(
+            otlp_logs_url("otlps://example.com").unwrap(),
+            "https://example.com/v1/logs"
+        );
+        assert_eq!(
+            otlp_logs_url("http://example.com").unwrap_err().kind,
+            BadOperation
+        );
+
+        let logs = to_otlp_logs(&[json!({
+            "time": 1.5,
+            "event": "count",
+            "name": "records",
+            "value": 7,
+            "parent": null,
+        })]);
+        let record = &logs["resourceLogs"][0]["scopeLogs"][0]["logRecords"][0];
+        assert_eq!(
+            *record,
+            json!({
+                "timeUnixNano": "1500000000",
+                "severityText": "INFO",
+                "body": { "stringValue": "count" },
+                "attributes": [
+                    { "key": "event", "value": { "stringValue": "count" } },
+                    { "key": "name", "value": { "stringValue": "records" } },
+                    { "key": "value", "value": { "intValue": "7" } },
+                ],
+            })
+        );
+    }
+}
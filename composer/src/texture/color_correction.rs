@@ -80,6 +80,7 @@ fn build_color_sample_array(
     vertex_metrics: &[FrameMetrics],
     chosen_cameras: &[Option<usize>],
     images: &[Option<RgbImage>],
+    shading: &Shading,
 ) -> Vec<[Vector3; 3]> {
     (0..mesh.faces.len())
         .map(|face_idx| {
@@ -91,7 +92,11 @@ fn build_color_sample_array(
                     mesh,
                 );
                 let image = images[frame_idx].as_ref().unwrap();
-                let f = |i| sample_pixel(uvs[i], image);
+                let f = |i| {
+                    let gain =
+                        shading.sample_gain(face_idx, Vector3::ith(i, 1.0));
+                    sample_pixel(uvs[i], image) * gain
+                };
                 [f(0), f(1), f(2)]
             } else {
                 [Vector3::new(0.0, 0.0, 0.0); 3]
@@ -192,6 +197,7 @@ pub struct ColorCorrection {
     // The implementation is hidden and may be changed to improve correction
     // resolution. Currently a piecewise linear correction is used.
     face_vertex_color_offsets: Vec<[Vector3; 3]>,
+    shading: Shading,
 }
 
 impl ColorCorrection {
@@ -201,6 +207,7 @@ impl ColorCorrection {
         vertex_metrics: &[FrameMetrics],
         chosen_cameras: &[Option<usize>],
         images: &[Option<RgbImage>],
+        shading: Shading,
         color_correction_steps: usize,
     ) -> ColorCorrection {
         if color_correction_steps == 0 {
@@ -209,6 +216,7 @@ impl ColorCorrection {
                     [Vector3::zeros(); 3];
                     mesh.faces.len()
                 ],
+                shading,
             };
         }
 
@@ -226,6 +234,7 @@ impl ColorCorrection {
             vertex_metrics,
             chosen_cameras,
             images,
+            &shading,
         );
 
         let mut face_vertex_color_offsets =
@@ -269,16 +278,19 @@ impl ColorCorrection {
 
         ColorCorrection {
             face_vertex_color_offsets,
+            shading,
         }
     }
 
-    pub fn sample_color_offset(
+    pub fn correct_color(
         &self,
         face_idx: usize,
         barycentric_coordinates: Vector3,
+        color: Vector3,
     ) -> Vector3 {
         let face = self.face_vertex_color_offsets[face_idx];
         let co = barycentric_coordinates;
-        face[0] * co[0] + face[1] * co[1] + face[2] * co[2]
+        let offset = face[0] * co[0] + face[1] * co[1] + face[2] * co[2];
+        color * self.shading.sample_gain(face_idx, co) + offset
     }
 }
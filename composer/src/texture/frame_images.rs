@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use image::RgbImage;
+use rayon::prelude::*;
+
+use crate::texture::load_frame_image;
+use base::fm;
+
+type CachedImage = Option<Arc<RgbImage>>;
+
+#[derive(Default)]
+struct CacheState {
+    // Images (None for frames without them) with their last use ticks.
+    images: HashMap<usize, (CachedImage, u64)>,
+    size: usize,
+    tick: u64,
+    num_decoded: usize,
+}
+
+impl CacheState {
+    fn touch(&mut self, index: usize) -> Option<CachedImage> {
+        self.tick += 1;
+        let (image, last_use) = self.images.get_mut(&index)?;
+        *last_use = self.tick;
+        Some(image.clone())
+    }
+
+    fn insert(&mut self, index: usize, image: CachedImage, capacity: usize) {
+        self.tick += 1;
+        self.num_decoded += 1;
+        self.size += image_size(&image);
+        self.images.insert(index, (image, self.tick));
+
+        while self.size > capacity && self.images.len() > 1 {
+            let (&lru, _) =
+                self.images.iter().min_by_key(|(_, (_, t))| *t).unwrap();
+            self.remove(lru);
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Option<CachedImage> {
+        let (image, _) = self.images.remove(&index)?;
+        self.size -= image_size(&image);
+        Some(image)
+    }
+}
+
+fn image_size(image: &CachedImage) -> usize {
+    image.as_ref().map_or(0, |i| i.as_raw().len())
+}
+
+// Frame images decoded once and shared between texturing stages, the least
+// recently used ones are evicted when their total size exceeds capacity.
+pub struct FrameImageCache<'a> {
+    frames: &'a [fm::ScanFrame],
+    capacity: usize, // In bytes of decoded images.
+    state: Mutex<CacheState>,
+}
+
+impl<'a> FrameImageCache<'a> {
+    pub fn new(frames: &'a [fm::ScanFrame], capacity: usize) -> Self {
+        Self {
+            frames,
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    // Returns None for frames without (decodable) images.
+    pub fn get(&self, index: usize) -> Option<Arc<RgbImage>> {
+        if let Some(image) = self.state.lock().unwrap().touch(index) {
+            return image;
+        }
+
+        // Decoded without the lock, so that frames are decoded in parallel.
+        let image = load_frame_image(&self.frames[index]).map(Arc::new);
+        let mut state = self.state.lock().unwrap();
+        state.insert(index, image.clone(), self.capacity);
+        image
+    }
+
+    // Takes images of the given frames out of the cache (decoding evicted
+    // ones in parallel), leaving others None as texturing doesn't use them.
+    pub fn take(&self, used: &[bool]) -> Vec<Option<RgbImage>> {
+        (0..self.frames.len())
+            .into_par_iter()
+            .map(|index| {
+                if !used[index] {
+                    return None;
+                }
+                let cached = self.state.lock().unwrap().remove(index);
+                let image = match cached {
+                    Some(image) => image?,
+                    None => {
+                        self.state.lock().unwrap().num_decoded += 1;
+                        return load_frame_image(&self.frames[index]);
+                    }
+                };
+                Some(Arc::try_unwrap(image).unwrap_or_else(|i| (*i).clone()))
+            })
+            .collect()
+    }
+
+    // Number of decodings so far (including repeated ones after eviction).
+    pub fn num_decoded(&self) -> usize {
+        self.state.lock().unwrap().num_decoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+
+    use super::*;
+    use crate::preview::encode_png;
+
+    fn new_frames(num: usize) -> Vec<fm::ScanFrame> {
+        (0..num)
+            .map(|i| {
+                let image =
+                    RgbImage::from_pixel(4, 4, image::Rgb([i as u8; 3]));
+                let image = encode_png(image.as_ref(), 4, 4, ColorType::Rgb8);
+                fm::ScanFrame {
+                    image: Some(image.unwrap()),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_image_cache() {
+        let mut frames = new_frames(4);
+        frames[3].image = None;
+        let cache = FrameImageCache::new(&frames, 1 << 20);
+
+        let images: Vec<_> =
+            (0..4).into_par_iter().map(|i| cache.get(i)).collect();
+        assert!(images[3].is_none());
+        assert_eq!(images[2].as_ref().unwrap().get_pixel(0, 0).0, [2; 3]);
+        assert!((0..4).all(|i| cache.get(i) == images[i]));
+        assert_eq!(cache.num_decoded(), 4);
+
+        let images = cache.take(&[false, true, true, true]);
+        assert!(images[0].is_none() && images[3].is_none());
+        assert_eq!(images[1].as_ref().unwrap().get_pixel(3, 3).0, [1; 3]);
+        assert_eq!(cache.num_decoded(), 4);
+    }
+
+    #[test]
+    fn test_frame_image_cache_eviction() {
+        let frames = new_frames(3);
+        // Two 4x4 RGB images fit.
+        let cache = FrameImageCache::new(&frames, 2 * 48);
+
+        cache.get(0);
+        cache.get(1);
+        cache.get(0);
+        cache.get(2); // Evicts the least recently used one.
+        assert_eq!(cache.num_decoded(), 3);
+        cache.get(0);
+        assert_eq!(cache.num_decoded(), 3);
+        cache.get(1);
+        assert_eq!(cache.num_decoded(), 4);
+
+        // Evicted images are decoded again.
+        let images = cache.take(&[true, true, true]);
+        assert!(images.iter().all(Option::is_some));
+        assert_eq!(cache.num_decoded(), 5);
+    }
+}
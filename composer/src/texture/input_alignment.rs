@@ -0,0 +1,160 @@
+use petgraph::unionfind::UnionFind;
+
+use crate::mesh::Mesh;
+use crate::texture::*;
+
+// Barycentric coordinates of points sampled on border faces.
+const FACE_SAMPLES: [[f64; 3]; 4] = [
+    [1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0],
+    [2.0 / 3.0, 1.0 / 6.0, 1.0 / 6.0],
+    [1.0 / 6.0, 2.0 / 3.0, 1.0 / 6.0],
+    [1.0 / 6.0, 1.0 / 6.0, 2.0 / 3.0],
+];
+
+const SHIFT_STEP: f64 = 0.25; // In pixels.
+const NUM_PASSES: usize = 2;
+
+// Surface point on a patch border seen by the patch and its neighbour.
+struct BorderSample {
+    uv: Vector2,
+    neighbour: usize,
+    neighbour_uv: Vector2,
+}
+
+struct InputPatch {
+    frame_idx: usize,
+    num_faces: usize,
+    samples: Vec<BorderSample>,
+}
+
+fn form_input_patches(
+    mesh: &Mesh,
+    topo: &BasicMeshTopology,
+    chosen_cameras: &[Option<usize>],
+    vertex_metrics: &[FrameMetrics],
+) -> (Vec<InputPatch>, Vec<Option<usize>>) {
+    let num_faces = mesh.faces.len();
+    let mut partition = UnionFind::new(num_faces);
+    for f0 in 0..num_faces {
+        for &f1 in &topo.neighbouring_faces[f0] {
+            if chosen_cameras[f0].is_some()
+                && chosen_cameras[f0] == chosen_cameras[f1]
+            {
+                partition.union(f0, f1);
+            }
+        }
+    }
+
+    let mut patches = Vec::new();
+    let mut patch_idxs = HashMap::new();
+    let mut face_patches = vec![None; num_faces];
+    for (face_idx, label) in partition.into_labeling().into_iter().enumerate() {
+        if let Some(frame_idx) = chosen_cameras[face_idx] {
+            let patch_idx = *patch_idxs.entry(label).or_insert_with(|| {
+                patches.push(InputPatch {
+                    frame_idx,
+                    num_faces: 0,
+                    samples: Vec::new(),
+                });
+                patches.len() - 1
+            });
+            patches[patch_idx].num_faces += 1;
+            face_patches[face_idx] = Some(patch_idx);
+        }
+    }
+
+    let project = |face_idx, frame_idx, bary: &[f64; 3]| {
+        let uvs =
+            uv_coords_from_metrics(face_idx, frame_idx, vertex_metrics, mesh);
+        uvs[0] * bary[0] + uvs[1] * bary[1] + uvs[2] * bary[2]
+    };
+
+    for f0 in 0..num_faces {
+        let p0 = match face_patches[f0] {
+            Some(p0) => p0,
+            None => continue,
+        };
+        for &f1 in &topo.neighbouring_faces[f0] {
+            let p1 = match face_patches[f1] {
+                Some(p1) if p1 != p0 => p1,
+                _ => continue,
+            };
+            for bary in &FACE_SAMPLES {
+                let sample = BorderSample {
+                    uv: project(f0, patches[p0].frame_idx, bary),
+                    neighbour: p1,
+                    neighbour_uv: project(f0, patches[p1].frame_idx, bary),
+                };
+                patches[p0].samples.push(sample);
+            }
+        }
+    }
+
+    (patches, face_patches)
+}
+
+// Finds subpixel shifts of input patches (connected faces textured by the
+// same frame) making colors along their borders consistent with those of
+// neighbouring patches. Returns texture coordinate offsets by face.
+pub fn refine_alignment(
+    mesh: &Mesh,
+    topo: &BasicMeshTopology,
+    chosen_cameras: &[Option<usize>],
+    vertex_metrics: &[FrameMetrics],
+    images: &[Option<RgbImage>],
+    max_shift: f64,
+) -> Vec<Vector2> {
+    let (patches, face_patches) =
+        form_input_patches(mesh, topo, chosen_cameras, vertex_metrics);
+    let mut offsets = vec![Vector2::zeros(); patches.len()];
+
+    let image = |patch: &InputPatch| images[patch.frame_idx].as_ref().unwrap();
+    let cost = |patch: &InputPatch, offset: Vector2, offsets: &[Vector2]| {
+        patch
+            .samples
+            .iter()
+            .map(|s| {
+                let neighbour = &patches[s.neighbour];
+                let color = sample_pixel(s.uv + offset, image(patch));
+                let neighbour_color = sample_pixel(
+                    s.neighbour_uv + offsets[s.neighbour],
+                    image(neighbour),
+                );
+                (color - neighbour_color).norm_squared()
+            })
+            .sum::<f64>()
+    };
+
+    // The largest patch is kept in place to anchor the others.
+    let anchor = (0..patches.len()).max_by_key(|&p| patches[p].num_faces);
+    let num_steps = (max_shift / SHIFT_STEP).floor() as i32;
+
+    for _ in 0..NUM_PASSES {
+        for (p, patch) in patches.iter().enumerate() {
+            if Some(p) == anchor || patch.samples.is_empty() {
+                continue;
+            }
+
+            let grid = ImageGrid::new(image(patch));
+            let mut best = (cost(patch, offsets[p], &offsets), offsets[p]);
+            for di in -num_steps..=num_steps {
+                for dj in -num_steps..=num_steps {
+                    let offset = grid.ij_to_uv(Vector2::new(
+                        di as f64 * SHIFT_STEP,
+                        dj as f64 * SHIFT_STEP,
+                    ));
+                    let cost = cost(patch, offset, &offsets);
+                    if cost < best.0 {
+                        best = (cost, offset);
+                    }
+                }
+            }
+            offsets[p] = best.1;
+        }
+    }
+
+    face_patches
+        .iter()
+        .map(|p| p.map_or_else(Vector2::zeros, |p| offsets[p]))
+        .collect()
+}
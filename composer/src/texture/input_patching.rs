@@ -78,8 +78,9 @@ fn face_is_acceptable(
     face_metrics: &[FrameMetrics],
     all_costs: &[Option<Vec<f64>>],
     chosen_cameras: &[Option<usize>],
-    patching_threshold: f64,
+    labels: &LabelTextureParams,
 ) -> bool {
+    let params = labels.face(face_idx);
     chosen_cameras[face_idx].map_or(false, |old_frame_idx| {
         let f = |frame_idx: usize| {
             face_metrics[frame_idx].as_ref().unwrap()[face_idx]
@@ -93,7 +94,13 @@ fn face_is_acceptable(
         let old_is_bg = f(old_frame_idx).is_background;
         let alt_cost = g(frame_idx);
         let alt_is_bg = f(frame_idx).is_background;
-        (patching_threshold * old_cost > alt_cost || old_is_bg) && !alt_is_bg
+        // Patches do not grow onto highlights which the face avoids.
+        let alt_is_avoided = params.highlight_penalty > 1.0
+            && f(frame_idx).is_highlight
+            && !f(old_frame_idx).is_highlight;
+        (params.input_patching_threshold * old_cost > alt_cost || old_is_bg)
+            && !alt_is_bg
+            && !alt_is_avoided
     })
 }
 
@@ -102,7 +109,7 @@ fn build_acceptability_record(
     face_metrics: &[FrameMetrics],
     all_costs: &[Option<Vec<f64>>],
     chosen_cameras: &[Option<usize>],
-    patching_threshold: f64,
+    labels: &LabelTextureParams,
 ) -> Vec<Option<Vec<usize>>> {
     let build_for_single_frame = |frame_idx| {
         (0..mesh.faces.len())
@@ -113,7 +120,7 @@ fn build_acceptability_record(
                     face_metrics,
                     all_costs,
                     chosen_cameras,
-                    patching_threshold,
+                    labels,
                 )
             })
             .collect()
@@ -137,7 +144,7 @@ pub fn form_patches(
     all_costs: &[Option<Vec<f64>>],
     mesh: &Mesh,
     topo: &BasicMeshTopology,
-    patching_threshold: f64,
+    labels: &LabelTextureParams,
 ) {
     // For each image frame and each mesh face,
     // record whether it is acceptably well-visible.
@@ -146,7 +153,7 @@ pub fn form_patches(
         face_metrics,
         all_costs,
         chosen_cameras,
-        patching_threshold,
+        labels,
     );
 
     // Collection of available faces that have only been assigned
@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
 use indexmap::IndexMap;
 use kiddo::distance::squared_euclidean;
 use kiddo::KdTree;
@@ -5,14 +9,19 @@ use rayon::prelude::*;
 use structopt::StructOpt;
 
 use crate::mesh::Mesh;
+use crate::misc::kdtree_err_to_err;
+use crate::param_check::{CheckParams, ParamCheck};
 use crate::texture::*;
+use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
+use base::util::cli::parse_key_val;
+use base::util::fs;
 
 pub fn project_like_camera(
     scan: &fm::Scan,
     frame: &fm::ScanFrame,
     points: &[Point3],
-) -> Vec<ProjectedPoint> {
+) -> Result<Vec<ProjectedPoint>> {
     let tan = (scan.camera_angle_of_view as f64 / 2.0).tan();
 
     let eye = scan.camera_initial_position.unwrap_or_default();
@@ -26,10 +35,13 @@ pub fn project_like_camera(
     );
     let look_rot =
         Matrix4::look_at_rh(&eye, &dir, &Vector3::new(0.0, 0.0, 1.0));
-    let view_rot = look_rot.try_inverse().unwrap() * Matrix4::from(up_rot);
+    let view_rot = look_rot.try_inverse().ok_or_else(|| {
+        let desc =
+            format!("degenerate camera geometry for scan '{}'", scan.name);
+        Error::new(GeometryError, desc)
+    })? * Matrix4::from(up_rot);
 
-    let camera_angle =
-        frame.time as f64 / 1E9 * scan.camera_angular_velocity as f64;
+    let camera_angle = fm::camera_angle(scan, frame);
     let time_rot =
         Quaternion::from_axis_angle(&Vector3::z_axis(), camera_angle);
 
@@ -40,8 +52,9 @@ pub fn project_like_camera(
 
     let depth_width = scan.depth_width as f64;
     let depth_height = scan.depth_height as f64;
+    let crop = frame.image_crop.as_ref();
 
-    points
+    Ok(points
         .iter()
         .map(|point3d| {
             // Undo rigid 3d transformations.
@@ -61,12 +74,18 @@ pub fn project_like_camera(
             let i = (h + depth_height / 2.0) / depth_height;
             let j = (w + depth_width / 2.0) / depth_width;
 
+            // Cropped images keep the camera geometry of whole ones.
+            let [i, j] = match crop {
+                Some(crop) => crop.crop_point([i, j]),
+                None => [i, j],
+            };
+
             ProjectedPoint {
                 point: Vector2::new(i, j),
                 depth,
             }
         })
-        .collect()
+        .collect())
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -77,6 +96,7 @@ pub struct Metrics {
     pub within_bounds: bool,
     pub is_occluded: bool,
     pub is_background: bool,
+    pub is_highlight: bool,
 }
 
 pub type FrameMetrics = Option<Vec<Metrics>>; // Either by vertex, or by face.
@@ -90,6 +110,7 @@ fn summarize_metrics(ms: &[Metrics]) -> Metrics {
         within_bounds: ms.iter().all(|m| m.within_bounds),
         is_occluded: ms.iter().any(|m| m.is_occluded),
         is_background: ms.iter().any(|m| m.is_background),
+        is_highlight: ms.iter().any(|m| m.is_highlight),
     }
 }
 
@@ -117,11 +138,11 @@ fn max(a: [f64; 3]) -> f64 {
 fn compute_occlusion_for_all_vertices(
     vertices_proj: &[ProjectedPoint],
     mesh: &Mesh,
-) -> Vec<bool> {
+) -> Result<Vec<bool>> {
     // Build 2d kdtree of all vertices.
     let mut kdtree = KdTree::new();
     for (i, v) in vertices_proj.iter().enumerate() {
-        kdtree.add(v.point.as_ref(), i).unwrap();
+        kdtree.add(v.point.as_ref(), i).map_err(kdtree_err_to_err)?;
     }
 
     // Set all vertices to visible initially.
@@ -153,7 +174,7 @@ fn compute_occlusion_for_all_vertices(
             ]);
         for (_dist, &i) in kdtree
             .within_unsorted(v.as_ref(), radius, &squared_euclidean)
-            .unwrap()
+            .map_err(kdtree_err_to_err)?
         {
             let ProjectedPoint {
                 point: vi,
@@ -169,7 +190,7 @@ fn compute_occlusion_for_all_vertices(
         }
     }
 
-    occluded
+    Ok(occluded)
 }
 
 struct VertexAndFaceMetricsOfSingleFrame {
@@ -180,26 +201,23 @@ struct VertexAndFaceMetricsOfSingleFrame {
 fn make_frame_metrics(
     scan: &fm::Scan,
     frame: &fm::ScanFrame,
+    image: &RgbImage,
     mesh: &Mesh,
     background_params: &BackgroundParams,
-) -> Option<VertexAndFaceMetricsOfSingleFrame> {
-    let image = load_frame_image(frame)?;
+    background_image: Option<&RgbImage>,
+) -> Result<VertexAndFaceMetricsOfSingleFrame> {
+    let vertices_proj = project_like_camera(scan, frame, &mesh.vertices)?;
 
-    let vertices_proj = project_like_camera(scan, frame, &mesh.vertices);
-
-    let camera_angle =
-        frame.time as f64 / 1E9 * scan.camera_angular_velocity as f64;
+    let camera_angle = fm::camera_angle(scan, frame);
     let time_rot =
         Quaternion::from_axis_angle(&Vector3::z_axis(), camera_angle);
     let eye = scan.camera_initial_position.unwrap_or_default();
     let eye = Point3::new(eye.x as f64, eye.y as f64, eye.z as f64);
     let camera = time_rot * eye;
 
-    let occlusions = compute_occlusion_for_all_vertices(&vertices_proj, mesh);
-    let background = BackgroundDetector::new(
-        &image,
-       background_params
-    );
+    let occlusions = compute_occlusion_for_all_vertices(&vertices_proj, mesh)?;
+    let background =
+        BackgroundDetector::new(image, background_params, background_image);
 
     let mut vertex_metrics = vec![];
     for i in 0..mesh.vertices.len() {
@@ -218,6 +236,7 @@ fn make_frame_metrics(
                 && pixel[1] <= 0.99,
             is_occluded: occlusions[i],
             is_background: background.detect(pixel),
+            is_highlight: detect_highlight(pixel, image),
         });
     }
     let face_metrics = mesh
@@ -229,12 +248,21 @@ fn make_frame_metrics(
             summarize_metrics(&ms)
         })
         .collect();
-    Some(VertexAndFaceMetricsOfSingleFrame {
+    Ok(VertexAndFaceMetricsOfSingleFrame {
         vertex_metrics,
         face_metrics,
     })
 }
 
+// Luma above which pixels are deemed specular highlights (or overexposed).
+const HIGHLIGHT_LUMA: f64 = 240.0;
+
+fn detect_highlight(pixel: Vector2, image: &RgbImage) -> bool {
+    let color = sample_pixel(pixel, image);
+    let luma = 0.299 * color[0] + 0.587 * color[1] + 0.114 * color[2];
+    luma > HIGHLIGHT_LUMA
+}
+
 pub struct VertexAndFaceMetricsOfAllFrames {
     pub vertex_metrics: Vec<FrameMetrics>,
     pub face_metrics: Vec<FrameMetrics>,
@@ -243,37 +271,52 @@ pub struct VertexAndFaceMetricsOfAllFrames {
 pub fn make_all_frame_metrics(
     scans: &IndexMap<String, fm::Scan>,
     scan_frames: &[fm::ScanFrame],
+    images: &FrameImageCache,
     mesh: &Mesh,
     background_params: &BackgroundParams,
-) -> VertexAndFaceMetricsOfAllFrames {
+) -> Result<VertexAndFaceMetricsOfAllFrames> {
     let mut vertex_metrics = vec![];
     let mut face_metrics = vec![];
 
+    let background_images = background_params.load_images()?;
+    if let Some(scan) =
+        background_images.keys().find(|s| !scans.contains_key(*s))
+    {
+        let desc = format!("background image for unknown scan '{}'", scan);
+        return Err(Error::new(InconsistentState, desc));
+    }
+
     let results: Vec<(FrameMetrics, FrameMetrics)> = (0..scan_frames.len())
         .into_par_iter()
         .map(|frame_idx| {
             let frame = &scan_frames[frame_idx];
-            let scan = scans.get(&frame.scan).unwrap();
-            if let Some(m) = make_frame_metrics(
+            let scan = scans.get(&frame.scan).ok_or_else(|| {
+                let desc = format!("frame for unknown scan '{}'", &frame.scan);
+                Error::new(InconsistentState, desc)
+            })?;
+            let image = match images.get(frame_idx) {
+                Some(image) => image,
+                None => return Ok((None, None)),
+            };
+            let m = make_frame_metrics(
                 scan,
                 frame,
+                &image,
                 mesh,
                 background_params,
-            ) {
-                (Some(m.vertex_metrics), Some(m.face_metrics))
-            } else {
-                (None, None)
-            }
+                background_images.get(&frame.scan),
+            )?;
+            Ok((Some(m.vertex_metrics), Some(m.face_metrics)))
         })
-        .collect();
+        .collect::<Result<_>>()?;
     for (vm, fm) in results {
         vertex_metrics.push(vm);
         face_metrics.push(fm);
     }
-    VertexAndFaceMetricsOfAllFrames {
+    Ok(VertexAndFaceMetricsOfAllFrames {
         vertex_metrics,
         face_metrics,
-    }
+    })
 }
 
 pub fn build_cost_for_single_face(metrics: &Metrics) -> f64 {
@@ -323,23 +366,30 @@ pub fn select_cameras(
     all_costs: &[Option<Vec<f64>>],
     metrics: &[FrameMetrics],
     mesh: &Mesh,
-    selection_cost_limit: f64,
+    labels: &LabelTextureParams,
 ) -> Vec<Option<usize>> {
     let mut chosen = vec![None; mesh.faces.len()];
     let mut costs = vec![f64::INFINITY; mesh.faces.len()];
     for (frame_idx, all_costs_option) in all_costs.iter().enumerate() {
         if let Some(alt_costs) = all_costs_option {
+            let frame_metrics = metrics[frame_idx].as_ref().unwrap();
             for face_idx in 0..mesh.faces.len() {
-                if alt_costs[face_idx] > selection_cost_limit
-                    || metrics[frame_idx].as_ref().unwrap()[face_idx]
-                        .is_background
+                let face_params = labels.face(face_idx);
+                if alt_costs[face_idx] > face_params.selection_cost_limit
+                    || frame_metrics[face_idx].is_background
                 {
                     // Skip option which is too expensive to be sensible.
                     // Also skip option which is part of the background.
                     continue;
                 }
-                if costs[face_idx] > alt_costs[face_idx] {
-                    costs[face_idx] = alt_costs[face_idx];
+                // Highlights are avoided unless there is no better option.
+                let alt_cost = if frame_metrics[face_idx].is_highlight {
+                    face_params.highlight_penalty * alt_costs[face_idx]
+                } else {
+                    alt_costs[face_idx]
+                };
+                if costs[face_idx] > alt_cost {
+                    costs[face_idx] = alt_cost;
                     chosen[face_idx] = Some(frame_idx);
                 }
             }
@@ -363,7 +413,78 @@ pub fn detect_background_static(
     diff2.norm() < background_deviation
 }
 
-#[derive(Clone, StructOpt)]
+// Minimal value of chroma-keyed pixels, hues of darker ones are unreliable.
+const CHROMA_KEY_MIN_VALUE: f64 = 0.1;
+
+// Hue in degrees, saturation and value within [0, 1].
+fn rgb_to_hsv(color: Vector3) -> Vector3 {
+    let color = color / 255.0;
+    let (max, min) = (color.max(), color.min());
+    let delta = max - min;
+    let hue = if delta <= 0.0 {
+        0.0
+    } else if max == color[0] {
+        60.0 * ((color[1] - color[2]) / delta).rem_euclid(6.0)
+    } else if max == color[1] {
+        60.0 * ((color[2] - color[0]) / delta + 2.0)
+    } else {
+        60.0 * ((color[0] - color[1]) / delta + 4.0)
+    };
+    let saturation = if max > 0.0 { delta / max } else { 0.0 };
+    Vector3::new(hue, saturation, max)
+}
+
+pub fn detect_background_chroma_key(
+    pixel: Vector2,
+    image: &RgbImage,
+    background_color: Vector3,
+    hue_deviation: f64,
+    min_saturation: f64,
+) -> bool {
+    let &[hue, saturation, value] =
+        rgb_to_hsv(sample_pixel(pixel, image)).as_ref();
+    let diff = (hue - rgb_to_hsv(background_color)[0]).abs();
+    diff.min(360.0 - diff) < hue_deviation
+        && saturation >= min_saturation
+        && value >= CHROMA_KEY_MIN_VALUE
+}
+
+// Compares against a clean plate (the scene shot without the object).
+pub fn detect_background_by_image(
+    pixel: Vector2,
+    image: &RgbImage,
+    background_image: &RgbImage,
+    background_deviation: f64,
+) -> bool {
+    let diff =
+        sample_pixel(pixel, image) - sample_pixel(pixel, background_image);
+    diff.norm() < background_deviation
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackgroundMode {
+    // Color distance ignoring brightness.
+    Rgb,
+    // Chroma keying by hue.
+    Hsv,
+}
+
+impl FromStr for BackgroundMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rgb" => Ok(BackgroundMode::Rgb),
+            "hsv" => Ok(BackgroundMode::Hsv),
+            _ => Err(Error::new(
+                MalformedData,
+                "unknown background mode".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
 pub struct BackgroundParams {
     #[structopt(
         help = "Mean color for background detection",
@@ -388,6 +509,71 @@ pub struct BackgroundParams {
         use_delimiter = true
     )]
     pub dilations: Vec<f64>,
+
+    #[structopt(
+        help = "Background detection mode: rgb or hsv (chroma keying \
+                with deviation as hue tolerance in degrees)",
+        long = "background-mode",
+        default_value = "rgb"
+    )]
+    pub mode: BackgroundMode,
+
+    #[structopt(
+        help = "Minimal saturation of chroma-keyed background",
+        long = "background-min-saturation",
+        default_value = "0.25"
+    )]
+    pub min_saturation: f64,
+
+    #[structopt(
+        help = "Clean plate diffed against frames of a scan in form \
+                'scan=image' (overrides the mode for the scan)",
+        long = "background-image",
+        number_of_values = 1,
+        parse(try_from_str = parse_key_val)
+    )]
+    pub images: Vec<(String, PathBuf)>,
+}
+
+impl BackgroundParams {
+    // Clean plates by scan names.
+    pub fn load_images(&self) -> Result<HashMap<String, RgbImage>> {
+        self.images
+            .iter()
+            .map(|(scan, path)| {
+                let data = fs::read_file(path)?;
+                let image = image::load_from_memory(&data).map_err(|e| {
+                    let desc = format!(
+                        "failed to decode background image '{}'",
+                        path.display()
+                    );
+                    Error::with_source(ImageError, desc, e)
+                })?;
+                Ok((scan.clone(), image.into_rgb8()))
+            })
+            .collect()
+    }
+}
+
+impl CheckParams for BackgroundParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(self.dilations.len() == 2, || {
+            format!(
+                "--background-dilations expects 2 values \
+                 (erosion and dilation), got {}",
+                self.dilations.len()
+            )
+        });
+        check.require((0.0..=1.0).contains(&self.min_saturation), || {
+            "--background-min-saturation should be within [0, 1]".to_string()
+        });
+        for (i, (scan, _)) in self.images.iter().enumerate() {
+            check.require(
+                self.images[..i].iter().all(|(s, _)| s != scan),
+                || format!("duplicate --background-image for scan '{}'", scan),
+            );
+        }
+    }
 }
 
 pub struct BackgroundDetector {
@@ -399,10 +585,12 @@ impl BackgroundDetector {
     pub fn new(
         image: &RgbImage,
         params: &BackgroundParams,
+        background_image: Option<&RgbImage>,
     ) -> BackgroundDetector {
         let image = image.clone();
-        let (w, h) = image.dimensions();
-        let (w, h) = (Dim::from_usize(w as usize), Dim::from_usize(h as usize));
+        let grid = ImageGrid::new(&image);
+        let (w, h) = (grid.width as usize, grid.height as usize);
+        let (w, h) = (Dim::from_usize(w), Dim::from_usize(h));
         let mut bgmask = ImageMask::from_element_generic(h, w, false);
 
         // Short-circuit when possible.
@@ -410,14 +598,32 @@ impl BackgroundDetector {
             // Pixel-by-pixel detection.
             for i in 0..bgmask.nrows() {
                 for j in 0..bgmask.ncols() {
-                    let pixel =
-                        ij_to_uv(Vector2::new(i as f64, j as f64), &image);
-                    bgmask[(i, j)] = detect_background_static(
-                        pixel,
-                        &image,
-                        params.color,
-                        params.deviation,
-                    );
+                    let pixel = grid.ij_to_uv(Vector2::new(i as f64, j as f64));
+                    bgmask[(i, j)] = match (background_image, params.mode) {
+                        (Some(background), _) => detect_background_by_image(
+                            pixel,
+                            &image,
+                            background,
+                            params.deviation,
+                        ),
+                        (None, BackgroundMode::Rgb) => {
+                            detect_background_static(
+                                pixel,
+                                &image,
+                                params.color,
+                                params.deviation,
+                            )
+                        }
+                        (None, BackgroundMode::Hsv) => {
+                            detect_background_chroma_key(
+                                pixel,
+                                &image,
+                                params.color,
+                                params.deviation,
+                                params.min_saturation,
+                            )
+                        }
+                    };
                 }
             }
 
@@ -435,16 +641,15 @@ impl BackgroundDetector {
     }
 
     pub fn detect(&self, pixel: Vector2) -> bool {
-        let &[i, j] = uv_to_ij(pixel, &self.image).as_ref();
-        self.bgmask
-            .get((i as usize, j as usize))
-            .cloned()
-            .unwrap_or_default()
+        let grid = ImageGrid::new(&self.image);
+        match grid.nearest(grid.uv_to_ij(pixel)) {
+            Some([i, j]) => self.bgmask[(i as usize, j as usize)],
+            None => false,
+        }
     }
 }
 
 pub struct BackgroundDisqualificationParams {
-    pub cost_limit: f64,
     pub consensus_threshold: f64,
     pub consensus_spread: usize,
 }
@@ -455,11 +660,14 @@ pub fn disqualify_background_faces(
     all_costs: &[Option<Vec<f64>>],
     mesh: &Mesh,
     topo: &BasicMeshTopology,
+    labels: &LabelTextureParams,
     params: BackgroundDisqualificationParams,
 ) {
     let mut chosen_cameras_result = chosen_cameras.clone();
     for face_idx in 0..mesh.faces.len() {
         if let Some(_frame_idx) = chosen_cameras[face_idx] {
+            let cost_limit = labels.face(face_idx).selection_cost_limit;
+
             // Count how many reasonable frames say that the face is background.
             let mut bg_count_true = 0;
             let mut bg_count_false = 0;
@@ -468,7 +676,7 @@ pub fn disqualify_background_faces(
                     face_metrics[other_frame_idx].as_ref()
                 {
                     if all_costs[other_frame_idx].as_ref().unwrap()[face_idx]
-                        < params.cost_limit
+                        < cost_limit
                     {
                         if other_frame[face_idx].is_background {
                             bg_count_true += 1;
@@ -497,3 +705,98 @@ pub fn disqualify_background_faces(
     }
     *chosen_cameras = chosen_cameras_result;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_cameras_avoids_highlights() {
+        let mesh = Mesh {
+            vertices: vec![Point3::origin(); 3],
+            normals: vec![Vector3::z(); 3],
+            faces: vec![[0, 1, 2], [0, 1, 2]],
+        };
+        let metrics = |is_highlight| Metrics {
+            pixel: Vector2::new(0.5, 0.5),
+            depth: 1.0,
+            dot_product: 1.0,
+            within_bounds: true,
+            is_occluded: false,
+            is_background: false,
+            is_highlight,
+        };
+        let face_metrics = vec![
+            Some(vec![metrics(true), metrics(true)]),
+            Some(vec![metrics(false), metrics(false)]),
+        ];
+        let all_costs = vec![Some(vec![1.0, 1.0]), Some(vec![3.0, 3.0])];
+
+        let params = TextureParams::from_iter(&["test"]);
+        let mut labels = LabelTextureParams::uniform(&params, 2);
+        let chosen = select_cameras(&all_costs, &face_metrics, &mesh, &labels);
+        assert_eq!(chosen, vec![Some(0), Some(0)]);
+
+        let mut avoiding = labels.groups[0];
+        avoiding.highlight_penalty = 4.0;
+        labels.groups.push(avoiding);
+        labels.face_groups[1] = 1;
+        let chosen = select_cameras(&all_costs, &face_metrics, &mesh, &labels);
+        assert_eq!(chosen, vec![Some(0), Some(1)]);
+
+        // Highlights are still better than nothing.
+        labels.groups[1].selection_cost_limit = 2.0;
+        let chosen = select_cameras(&all_costs, &face_metrics, &mesh, &labels);
+        assert_eq!(chosen, vec![Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn test_background_detector() {
+        // Left half is a lit and shadowed green screen, right half is gray.
+        let image = RgbImage::from_fn(4, 2, |x, y| match (x, y) {
+            (0 | 1, 0) => Rgb([0, 180, 70]),
+            (0 | 1, _) => Rgb([10, 60, 30]),
+            _ => Rgb([120, 120, 120]),
+        });
+        let detected = |detector: &BackgroundDetector| {
+            (0..2)
+                .flat_map(|i| {
+                    (0..4).map(move |j| Vector2::new(i as f64, j as f64))
+                })
+                .map(|ij| detector.detect(ImageGrid::new(&image).ij_to_uv(ij)))
+                .collect::<Vec<_>>()
+        };
+        let params = |args: &[&str]| {
+            let common = ["test", "--background-dilations", "0,0"];
+            BackgroundParams::from_iter(common.iter().chain(args))
+        };
+        let left = [true, true, false, false];
+
+        let rgb = params(&["--background-deviation", "20"]);
+        let detector = BackgroundDetector::new(&image, &rgb, None);
+        assert_eq!(detected(&detector), [left, [false; 4]].concat());
+
+        // Chroma keying doesn't mind shadows.
+        let hsv = params(&[
+            "--background-deviation",
+            "15",
+            "--background-mode",
+            "hsv",
+        ]);
+        let detector = BackgroundDetector::new(&image, &hsv, None);
+        assert_eq!(detected(&detector), [left, left].concat());
+
+        // The clean plate differs from the frame by the right half only.
+        let mut plate = image.clone();
+        for y in 0..2 {
+            plate.put_pixel(2, y, Rgb([200, 200, 200]));
+            plate.put_pixel(3, y, Rgb([200, 200, 200]));
+        }
+        let detector = BackgroundDetector::new(&image, &hsv, Some(&plate));
+        assert_eq!(detected(&detector), [left, left].concat());
+
+        let disabled = params(&[]);
+        let detector = BackgroundDetector::new(&image, &disabled, None);
+        assert_eq!(detected(&detector), [false; 8]);
+    }
+}
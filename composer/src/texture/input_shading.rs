@@ -0,0 +1,156 @@
+use image::RgbImage;
+use nalgebra::SMatrix;
+
+use crate::mesh::Mesh;
+use crate::texture::*;
+
+type Matrix9 = SMatrix<f64, 9, 9>;
+
+const MIN_SAMPLES_PER_COEFFICIENT: usize = 4;
+const REGULARIZATION: f64 = 1e-3;
+const MAX_GAIN: f64 = 4.0;
+
+fn spherical_harmonics(n: Vector3) -> Vector<9> {
+    let (x, y, z) = (n[0], n[1], n[2]);
+    Vector::<9>::from([
+        1.0,
+        y,
+        z,
+        x,
+        x * y,
+        y * z,
+        3.0 * z * z - 1.0,
+        x * z,
+        x * x - y * y,
+    ])
+}
+
+fn luma(color: Vector3) -> f64 {
+    0.299 * color[0] + 0.587 * color[1] + 0.114 * color[2]
+}
+
+fn is_visible(metrics: &Metrics) -> bool {
+    metrics.within_bounds
+        && !metrics.is_occluded
+        && !metrics.is_background
+        && metrics.dot_product > 0.0
+}
+
+// Fits low-frequency illumination of a single frame, assuming the albedo
+// is roughly constant over the visible surface.
+fn fit_frame_shading(
+    mesh: &Mesh,
+    frame_metrics: &[Metrics],
+    image: &RgbImage,
+) -> Option<Vector<9>> {
+    let mut ata = Matrix9::zeros();
+    let mut atb = Vector::<9>::zeros();
+    let mut num_samples = 0;
+    for (metrics, normal) in frame_metrics.iter().zip(mesh.normals.iter()) {
+        if !is_visible(metrics) {
+            continue;
+        }
+        let basis = spherical_harmonics(normal.normalize());
+        ata += basis * basis.transpose();
+        atb += basis * luma(sample_pixel(metrics.pixel, image));
+        num_samples += 1;
+    }
+    if num_samples < 9 * MIN_SAMPLES_PER_COEFFICIENT {
+        return None;
+    }
+
+    let scale = ata.trace() / 9.0;
+    ata += Matrix9::identity() * REGULARIZATION * scale;
+    ata.cholesky().map(|c| c.solve(&atb))
+}
+
+// Computes gains which divide out shading while keeping the mean brightness
+// of visible vertices.
+fn frame_gains(
+    mesh: &Mesh,
+    frame_metrics: &[Metrics],
+    coeffs: &Vector<9>,
+) -> Vec<f64> {
+    let shading: Vec<f64> = mesh
+        .normals
+        .iter()
+        .map(|n| spherical_harmonics(n.normalize()).dot(coeffs))
+        .collect();
+    let visible: Vec<f64> = frame_metrics
+        .iter()
+        .zip(shading.iter())
+        .filter(|(m, _)| is_visible(m))
+        .map(|(_, &s)| s)
+        .collect();
+    let mean = visible.iter().sum::<f64>() / visible.len() as f64;
+    shading
+        .iter()
+        .map(|&s| {
+            if mean <= 0.0 {
+                1.0
+            } else {
+                (mean / s.max(mean / MAX_GAIN)).min(MAX_GAIN)
+            }
+        })
+        .collect()
+}
+
+pub struct Shading {
+    // Multiplicative gains per face vertex, cancelling per-frame shading.
+    face_vertex_gains: Vec<[f64; 3]>,
+}
+
+impl Shading {
+    pub fn uniform(mesh: &Mesh) -> Shading {
+        Shading {
+            face_vertex_gains: vec![[1.0; 3]; mesh.faces.len()],
+        }
+    }
+
+    pub fn new(
+        mesh: &Mesh,
+        vertex_metrics: &[FrameMetrics],
+        chosen_cameras: &[Option<usize>],
+        images: &[Option<RgbImage>],
+    ) -> Shading {
+        let used_frames: HashSet<usize> =
+            chosen_cameras.iter().flatten().copied().collect();
+
+        let mut gains = HashMap::new();
+        for frame_idx in used_frames {
+            let frame_metrics = vertex_metrics[frame_idx].as_ref().unwrap();
+            let image = images[frame_idx].as_ref().unwrap();
+            if let Some(coeffs) = fit_frame_shading(mesh, frame_metrics, image)
+            {
+                gains.insert(
+                    frame_idx,
+                    frame_gains(mesh, frame_metrics, &coeffs),
+                );
+            }
+        }
+
+        let face_vertex_gains = mesh
+            .faces
+            .iter()
+            .zip(chosen_cameras.iter())
+            .map(|(face, frame_idx)| {
+                match frame_idx.and_then(|idx| gains.get(&idx)) {
+                    Some(gains) => face.map(|v| gains[v]),
+                    None => [1.0; 3],
+                }
+            })
+            .collect();
+
+        Shading { face_vertex_gains }
+    }
+
+    pub fn sample_gain(
+        &self,
+        face_idx: usize,
+        barycentric_coordinates: Vector3,
+    ) -> f64 {
+        let face = self.face_vertex_gains[face_idx];
+        let co = barycentric_coordinates;
+        face[0] * co[0] + face[1] * co[1] + face[2] * co[2]
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::param_check::ParamCheck;
+use crate::texture::*;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::util::fs;
+
+// Section of --texture-labels-config file named after the label, unset
+// values fall back to the corresponding texturing flags.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LabelTextureConfig {
+    pub label: u16,
+    pub texel_density: Option<f64>,
+    pub selection_cost_limit: Option<f64>,
+    pub input_patching_threshold: Option<f64>,
+    pub highlight_penalty: Option<f64>,
+    pub texture_sharpen_amount: Option<f64>,
+    pub texture_chroma_denoise: Option<f64>,
+    pub texture_smoothing: Option<f64>,
+}
+
+pub type TextureLabelsConfig = BTreeMap<String, LabelTextureConfig>;
+
+pub fn read_texture_labels_config(path: &Path) -> Result<TextureLabelsConfig> {
+    let text = fs::read_file_to_string(path)?;
+    let config: TextureLabelsConfig = toml::from_str(&text).map_err(|err| {
+        let desc = format!("malformed texture labels config: {}", err);
+        Error::with_source(MalformedData, desc, err)
+    })?;
+    check_texture_labels_config(&config)?;
+    Ok(config)
+}
+
+fn check_texture_labels_config(config: &TextureLabelsConfig) -> Result<()> {
+    let mut check = ParamCheck::default();
+    let mut names = HashMap::new();
+    for (name, section) in config {
+        if let Some(other) = names.insert(section.label, name) {
+            check.require(false, || {
+                format!(
+                    "labels '{}' and '{}' share label {}",
+                    other, name, section.label
+                )
+            });
+        }
+        let positive = |value: Option<f64>| value.is_none_or(|v| v > 0.0);
+        check.require(positive(section.texel_density), || {
+            format!("texel-density of label '{}' should be positive", name)
+        });
+        check.require(positive(section.selection_cost_limit), || {
+            format!(
+                "selection-cost-limit of label '{}' should be positive",
+                name
+            )
+        });
+        check.require(
+            section.highlight_penalty.is_none_or(|v| v >= 1.0),
+            || {
+                format!(
+                    "highlight-penalty of label '{}' should be at least 1",
+                    name
+                )
+            },
+        );
+        let unit =
+            |value: Option<f64>| value.is_none_or(|v| (0.0..=1.0).contains(&v));
+        check.require(unit(section.texture_chroma_denoise), || {
+            format!(
+                "texture-chroma-denoise of label '{}' should be within [0, 1]",
+                name
+            )
+        });
+        check.require(unit(section.texture_smoothing), || {
+            format!(
+                "texture-smoothing of label '{}' should be within [0, 1]",
+                name
+            )
+        });
+    }
+    check.finish()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaceTextureParams {
+    // Relative to other faces, so that 2 doubles texels along each axis.
+    pub texel_density: f64,
+    pub selection_cost_limit: f64,
+    pub input_patching_threshold: f64,
+    // Cost factor of image sources with highlights (1 means no avoidance).
+    pub highlight_penalty: f64,
+    pub sharpen_amount: f64,
+    pub chroma_denoise: f64,
+    pub smoothing: f64,
+}
+
+impl FaceTextureParams {
+    pub fn new(params: &TextureParams) -> FaceTextureParams {
+        FaceTextureParams {
+            texel_density: 1.0,
+            selection_cost_limit: params.selection_cost_limit,
+            input_patching_threshold: params.input_patching_threshold,
+            highlight_penalty: 1.0,
+            sharpen_amount: params.texture_sharpen_amount,
+            chroma_denoise: params.texture_chroma_denoise,
+            smoothing: params.texture_smoothing,
+        }
+    }
+
+    fn with_config(self, config: &LabelTextureConfig) -> FaceTextureParams {
+        FaceTextureParams {
+            texel_density: config.texel_density.unwrap_or(self.texel_density),
+            selection_cost_limit: config
+                .selection_cost_limit
+                .unwrap_or(self.selection_cost_limit),
+            input_patching_threshold: config
+                .input_patching_threshold
+                .unwrap_or(self.input_patching_threshold),
+            highlight_penalty: config
+                .highlight_penalty
+                .unwrap_or(self.highlight_penalty),
+            sharpen_amount: config
+                .texture_sharpen_amount
+                .unwrap_or(self.sharpen_amount),
+            chroma_denoise: config
+                .texture_chroma_denoise
+                .unwrap_or(self.chroma_denoise),
+            smoothing: config.texture_smoothing.unwrap_or(self.smoothing),
+        }
+    }
+}
+
+// Faces are split into groups with common texturing parameters,
+// the first group is for faces with labels missing in the config.
+pub struct LabelTextureParams {
+    pub groups: Vec<FaceTextureParams>,
+    pub face_groups: Vec<u16>,
+}
+
+impl LabelTextureParams {
+    pub fn uniform(params: &TextureParams, num_faces: usize) -> Self {
+        LabelTextureParams {
+            groups: vec![FaceTextureParams::new(params)],
+            face_groups: vec![0; num_faces],
+        }
+    }
+
+    pub fn new(
+        params: &TextureParams,
+        config: &TextureLabelsConfig,
+        face_labels: &[u16],
+    ) -> Self {
+        let default = FaceTextureParams::new(params);
+        let mut groups = vec![default];
+        let mut label_groups = HashMap::new();
+        for section in config.values() {
+            label_groups.insert(section.label, groups.len() as u16);
+            groups.push(default.with_config(section));
+        }
+
+        let face_groups = face_labels
+            .iter()
+            .map(|label| label_groups.get(label).cloned().unwrap_or_default())
+            .collect();
+
+        LabelTextureParams {
+            groups,
+            face_groups,
+        }
+    }
+
+    pub fn face(&self, face_idx: usize) -> &FaceTextureParams {
+        &self.groups[self.face_groups[face_idx] as usize]
+    }
+
+    pub fn any<F: Fn(&FaceTextureParams) -> bool>(&self, pred: F) -> bool {
+        self.groups.iter().any(pred)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_label_texture_params() {
+        let params = TextureParams::from_iter(&[
+            "test",
+            "--selection-cost-limit",
+            "5",
+            "--texture-sharpen-amount",
+            "0.5",
+        ]);
+        let config: TextureLabelsConfig = toml::from_str(
+            r#"
+            [face]
+            label = 1
+            texel-density = 2.0
+            highlight-penalty = 4.0
+
+            [backdrop]
+            label = 2
+            texture-sharpen-amount = 0.0
+            texture-smoothing = 1.0
+            "#,
+        )
+        .unwrap();
+        check_texture_labels_config(&config).unwrap();
+
+        let labels = LabelTextureParams::new(&params, &config, &[0, 1, 2, 3]);
+        assert_eq!(labels.groups.len(), 3);
+        assert_eq!(labels.face(0), labels.face(3));
+        assert_eq!(labels.face(0), &FaceTextureParams::new(&params));
+        assert_eq!(labels.face(1).texel_density, 2.0);
+        assert_eq!(labels.face(1).highlight_penalty, 4.0);
+        assert_eq!(labels.face(1).selection_cost_limit, 5.0);
+        assert_eq!(labels.face(1).sharpen_amount, 0.5);
+        assert_eq!(labels.face(2).sharpen_amount, 0.0);
+        assert_eq!(labels.face(2).smoothing, 1.0);
+        assert!(labels.any(|p| p.highlight_penalty > 1.0));
+
+        let uniform = LabelTextureParams::uniform(&params, 4);
+        assert!(!uniform.any(|p| p.highlight_penalty > 1.0));
+        assert_eq!(uniform.face(3), labels.face(0));
+    }
+
+    #[test]
+    fn test_check_texture_labels_config() {
+        let config: TextureLabelsConfig = toml::from_str(
+            r#"
+            [face]
+            label = 1
+            texel-density = 0.0
+
+            [skin]
+            label = 1
+            texture-smoothing = 2.0
+            "#,
+        )
+        .unwrap();
+        let err = check_texture_labels_config(&config).unwrap_err();
+        assert!(err.description.contains("3 parameter problems"));
+        assert!(err.description.contains("share label 1"));
+
+        let text = "[face]\nlabel = 1\ntexel-densiti = 2.0\n";
+        assert!(toml::from_str::<TextureLabelsConfig>(text).is_err());
+    }
+}
@@ -1,9 +1,14 @@
 mod color_correction;
+mod frame_images;
+mod input_alignment;
 mod input_patching;
 mod input_selection;
+mod input_shading;
+mod label_params;
 mod output_baking;
 mod output_packing;
 mod output_patching;
+mod seams;
 mod textured_mesh;
 
 use std::cmp::Ordering;
@@ -14,13 +19,16 @@ use std::ops::Sub;
 use image::io::Reader as ImageReader;
 use image::{Rgb, RgbImage};
 use nalgebra::{
-    vector, ArrayStorage, Const, Dim, Dynamic, Matrix, Matrix3, OMatrix, SVD,
+    vector, ArrayStorage, Const, DMatrix, Dim, Dynamic, Matrix, Matrix3,
+    OMatrix, SVD,
 };
 
 use crate::mesh::Mesh;
 pub use crate::texture::{
-    color_correction::*, input_patching::*, input_selection::*,
-    output_baking::*, output_packing::*, output_patching::*, textured_mesh::*,
+    color_correction::*, frame_images::*, input_alignment::*,
+    input_patching::*, input_selection::*, input_shading::*, label_params::*,
+    output_baking::*, output_packing::*, output_patching::*, seams::*,
+    textured_mesh::*,
 };
 use base::fm;
 
@@ -40,22 +48,77 @@ pub struct ProjectedPoint {
     pub depth: f64,
 }
 
+// Maps normalized image coordinates (uv, with u going down the rows and v
+// along the columns) to pixel coordinates (ij, row and column) and back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageGrid {
+    pub height: u32,
+    pub width: u32,
+}
+
+impl ImageGrid {
+    pub fn new(image: &RgbImage) -> ImageGrid {
+        let (width, height) = image.dimensions(); // Beware: x comes before y.
+        ImageGrid { height, width }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.height == 0 || self.width == 0
+    }
+
+    pub fn contains(&self, i: u32, j: u32) -> bool {
+        i < self.height && j < self.width
+    }
+
+    // Clamps uv to [0, 1] (NaN to 0), so that ij is within [0, h] x [0, w].
+    pub fn uv_to_ij(&self, uv: Vector2) -> Vector2 {
+        let clamp = |x: f64| if x.is_nan() { 0.0 } else { x.clamp(0.0, 1.0) };
+        Vector2::new(
+            self.height as f64 * clamp(uv[0]),
+            self.width as f64 * clamp(uv[1]),
+        )
+    }
+
+    pub fn ij_to_uv(&self, ij: Vector2) -> Vector2 {
+        let scale = |x: f64, dim: u32| match dim {
+            0 => 0.0,
+            _ => x / dim as f64,
+        };
+        Vector2::new(scale(ij[0], self.height), scale(ij[1], self.width))
+    }
+
+    // Closest pixel within grid (none if it's empty).
+    pub fn clamp(&self, i: u32, j: u32) -> Option<[u32; 2]> {
+        match self.is_empty() {
+            true => None,
+            false => Some([i.min(self.height - 1), j.min(self.width - 1)]),
+        }
+    }
+
+    // Pixel containing ij (casts saturate and map NaN to 0).
+    pub fn nearest(&self, ij: Vector2) -> Option<[u32; 2]> {
+        self.clamp(ij[0] as u32, ij[1] as u32)
+    }
+}
+
+// Samples image bilinearly (black if it's empty), clamping rounding errors
+// to the channel range.
 pub fn sample_pixel(uv: Vector2, image: &RgbImage) -> Vector3 {
-    let (w, h) = image.dimensions();
-    let (i, j) = (
-        uv[0].clamp(0.0, 1.0) * h as f64,
-        uv[1].clamp(0.0, 1.0) * w as f64,
-    );
-    let (i0, i1) = ((i as u32).clamp(0, h - 1), (i as u32 + 1).clamp(0, h - 1));
-    let (j0, j1) = ((j as u32).clamp(0, w - 1), (j as u32 + 1).clamp(0, w - 1));
-    let (di, dj) = (i - i0 as f64, j - j0 as f64);
+    let grid = ImageGrid::new(image);
+    let ij = grid.uv_to_ij(uv);
+    let [i0, j0] = match grid.nearest(ij) {
+        Some(ij0) => ij0,
+        None => return Vector3::zeros(),
+    };
+    let [i1, j1] = grid.clamp(i0 + 1, j0 + 1).unwrap();
+    let (di, dj) = (ij[0] - i0 as f64, ij[1] - j0 as f64);
     let s00 = get_pixel_ij_as_vector3(i0, j0, image);
     let s01 = get_pixel_ij_as_vector3(i0, j1, image);
     let s10 = get_pixel_ij_as_vector3(i1, j0, image);
     let s11 = get_pixel_ij_as_vector3(i1, j1, image);
     let s0 = (1.0 - dj) * s00 + dj * s01;
     let s1 = (1.0 - dj) * s10 + dj * s11;
-    (1.0 - di) * s0 + di * s1
+    ((1.0 - di) * s0 + di * s1).map(|c| c.clamp(0.0, 255.0))
 }
 
 pub fn load_frame_image(frame: &fm::ScanFrame) -> Option<image::RgbImage> {
@@ -67,24 +130,27 @@ pub fn load_frame_image(frame: &fm::ScanFrame) -> Option<image::RgbImage> {
     Some(img.into_rgb8())
 }
 
-pub fn load_all_frame_images(
-    frames: &[fm::ScanFrame],
-) -> Vec<Option<image::RgbImage>> {
-    frames.iter().map(load_frame_image).collect()
-}
-
+// Gets color of the closest pixel within image (black if it's empty).
 pub fn get_pixel_ij_as_vector3(i: u32, j: u32, image: &RgbImage) -> Vector3 {
+    let [i, j] = match ImageGrid::new(image).clamp(i, j) {
+        Some(ij) => ij,
+        None => return Vector3::zeros(),
+    };
     let (x, y) = (j, i); // Beware: Transposing indices.
     let p = image.get_pixel(x, y);
     Vector3::new(p[0] as f64, p[1] as f64, p[2] as f64)
 }
 
+// Sets color of pixel, ignoring it if outside image.
 pub fn set_pixel_ij_as_vector3(
     i: u32,
     j: u32,
     color: Vector3,
     image: &mut RgbImage,
 ) {
+    if !ImageGrid::new(image).contains(i, j) {
+        return;
+    }
     let (x, y) = (j, i); // Beware: Transposing indices.
     let [r, g, b] = color.as_ref();
     let r1 = r.clamp(0.0, 255.0).round() as u8;
@@ -146,6 +212,9 @@ impl BasicMeshTopology {
     }
 }
 
+// Minimal sine of angles of non-degenerate triangles.
+const DEGENERACY_TOLERANCE: f64 = 1e-9;
+
 pub struct BarycentricCoordinateSystem {
     vs: [Vector2; 3],
     inv22: Matrix2,
@@ -153,9 +222,19 @@ pub struct BarycentricCoordinateSystem {
 
 impl BarycentricCoordinateSystem {
     pub fn new(vs: [Vector2; 3]) -> Option<Self> {
-        let m22 = Matrix2::from_columns(&[vs[1] - vs[0], vs[2] - vs[0]]);
-        // None is returned when the triangle is degenerate.
-        m22.try_inverse().map(|inv22| Self { vs, inv22 })
+        let (e1, e2) = (vs[1] - vs[0], vs[2] - vs[0]);
+        let m22 = Matrix2::from_columns(&[e1, e2]);
+        // None is returned when the triangle is degenerate, including nearly
+        // collinear vertices (e.g. rounded ones), whose inverse is useless.
+        let det = m22.determinant().abs();
+        if det.is_nan() || det <= DEGENERACY_TOLERANCE * e1.norm() * e2.norm() {
+            return None;
+        }
+        let inv22 = m22.try_inverse()?;
+        inv22
+            .iter()
+            .all(|c| c.is_finite())
+            .then_some(Self { vs, inv22 })
     }
 
     // The functions 'infer' and 'apply' are mutually inverse.
@@ -194,21 +273,29 @@ pub fn extremum<
 }
 
 impl<T> Rectangle<T> {
-    pub fn bounding(ijs: &[[T; 2]]) -> Rectangle<T>
+    // None is returned for no points or incomparable coordinates (NaN).
+    pub fn bounding(ijs: &[[T; 2]]) -> Option<Rectangle<T>>
     where
         T: Copy + PartialOrd + Sub<Output = T>,
     {
-        let ijs_coord = |k: usize| ijs.iter().map(move |ij| ij[k]);
-
-        let imin = extremum(ijs_coord(0), Iterator::min_by);
-        let imax = extremum(ijs_coord(0), Iterator::max_by);
-        let jmin = extremum(ijs_coord(1), Iterator::min_by);
-        let jmax = extremum(ijs_coord(1), Iterator::max_by);
-
-        Rectangle {
-            pos: [imin, jmin],
-            size: [imax - imin, jmax - jmin],
+        let (&first, rest) = ijs.split_first()?;
+        let (mut min, mut max) = (first, first);
+        for k in 0..2 {
+            first[k].partial_cmp(&first[k])?;
+            for ij in rest {
+                if ij[k].partial_cmp(&min[k])? == Ordering::Less {
+                    min[k] = ij[k];
+                }
+                if ij[k].partial_cmp(&max[k])? == Ordering::Greater {
+                    max[k] = ij[k];
+                }
+            }
         }
+
+        Some(Rectangle {
+            pos: min,
+            size: [max[0] - min[0], max[1] - min[1]],
+        })
     }
 }
 
@@ -283,21 +370,6 @@ pub fn bisect<T>(
     (succeeds, best_result)
 }
 
-pub fn uv_to_ij(uv: Vector2, img: &RgbImage) -> Vector2 {
-    let (dimx, dimy) = img.dimensions(); // Beware that x comes before y here.
-    let [u, v] = uv.as_ref();
-    Vector2::new(
-        dimy as f64 * u.clamp(0.0, 1.0),
-        dimx as f64 * v.clamp(0.0, 1.0),
-    )
-}
-
-pub fn ij_to_uv(ij: Vector2, img: &RgbImage) -> Vector2 {
-    let (dimx, dimy) = img.dimensions(); // Beware that x comes before y here.
-    let [i, j] = ij.as_ref();
-    Vector2::new(i / dimy as f64, j / dimx as f64)
-}
-
 fn mesh_fill<T>(
     known: &[Option<T>],
     mesh: &Mesh,
@@ -376,64 +448,380 @@ fn map_vec_option<T, U>(
     v.iter().map(|i| i.as_ref().map(f)).collect()
 }
 
+// Clears pixels closer than the radius to any cleared one, pixels outside
+// the mask count as set.
 pub fn erode(mask: &ImageMask, radius: f64) -> ImageMask {
-    let mut output = mask.clone();
+    dilate(&mask.map(|p| !p), radius).map(|p| !p)
+}
+
+// Sets pixels within the radius from any set one, i.e. at offsets (di, dj)
+// with di^2 + dj^2 <= radius^2.
+pub fn dilate(mask: &ImageMask, radius: f64) -> ImageMask {
+    if radius.is_nan() || radius <= 0.0 {
+        return mask.clone();
+    }
+    let max_distance = radius * radius;
+    // Infinite distances (no set pixels) stay unset even for infinite radii.
+    squared_distance_transform(mask).map(|d| d.is_finite() && d <= max_distance)
+}
+
+// Squared Euclidean distances to the nearest set pixel (infinite if there is
+// none). Computed by lower envelopes of parabolas (Felzenszwalb and
+// Huttenlocher) along columns and then rows, so in linear time regardless
+// of distances.
+fn squared_distance_transform(mask: &ImageMask) -> DMatrix<f64> {
+    let (rows, cols) = mask.shape();
+    // Exceeds any squared distance within the image.
+    let far = ((rows + cols) * (rows + cols)) as f64 + 1.0;
+    let mut distances =
+        DMatrix::from_fn(
+            rows,
+            cols,
+            |i, j| if mask[(i, j)] { 0.0 } else { far },
+        );
+
+    let n = rows.max(cols);
+    let mut line = vec![0.0; n];
+    let mut output = vec![0.0; n];
+    let mut envelope = Envelope::new(n);
+
+    for j in 0..cols {
+        for i in 0..rows {
+            line[i] = distances[(i, j)];
+        }
+        envelope.transform(&line[..rows], &mut output[..rows]);
+        for i in 0..rows {
+            distances[(i, j)] = output[i];
+        }
+    }
+
+    for i in 0..rows {
+        for j in 0..cols {
+            line[j] = distances[(i, j)];
+        }
+        envelope.transform(&line[..cols], &mut output[..cols]);
+        for j in 0..cols {
+            distances[(i, j)] = output[j];
+        }
+    }
+
+    distances.map(|d| if d >= far { f64::INFINITY } else { d })
+}
+
+// Buffers of the one-dimensional distance transform.
+struct Envelope {
+    // Locations of parabolas in the lower envelope.
+    vertices: Vec<usize>,
+    // Boundaries between the parabolas.
+    bounds: Vec<f64>,
+}
 
-    for i in 0..mask.nrows() as isize {
-        for j in 0..mask.ncols() as isize {
-            output[(i as usize, j as usize)] = mask[(i as usize, j as usize)];
+impl Envelope {
+    fn new(n: usize) -> Self {
+        Self {
+            vertices: vec![0; n],
+            bounds: vec![0.0; n + 1],
+        }
+    }
+
+    fn transform(&mut self, input: &[f64], output: &mut [f64]) {
+        if input.is_empty() {
+            return;
+        }
 
-            // Short-circuit when possible.
-            if !output[(i as usize, j as usize)] {
-                continue;
+        let (v, z) = (&mut self.vertices, &mut self.bounds);
+        let parabola = |q: usize| input[q] + (q * q) as f64;
+        let mut k = 0;
+        v[0] = 0;
+        z[0] = f64::NEG_INFINITY;
+        z[1] = f64::INFINITY;
+
+        for q in 1..input.len() {
+            let mut s;
+            loop {
+                let p = v[k];
+                s = (parabola(q) - parabola(p)) / (2 * (q - p)) as f64;
+                if s > z[k] {
+                    break;
+                }
+                k -= 1;
             }
+            k += 1;
+            v[k] = q;
+            z[k] = s;
+            z[k + 1] = f64::INFINITY;
+        }
+
+        k = 0;
+        for (q, out) in output.iter_mut().enumerate() {
+            while z[k + 1] < q as f64 {
+                k += 1;
+            }
+            let (p, d) = (v[k], q.abs_diff(v[k]));
+            *out = (d * d) as f64 + input[p];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
 
-            'check: for di in -(radius as isize)..radius as isize {
-                for dj in -(radius as isize)..radius as isize {
-                    if Vector2::new(di as f64, dj as f64).norm() <= radius
-                        && !mask
-                            .get(((i + di) as usize, (j + dj) as usize))
-                            .cloned()
-                            .unwrap_or(true)
+    // Reference implementation checking every offset within the radius.
+    fn naive_morph(mask: &ImageMask, radius: f64, value: bool) -> ImageMask {
+        let (rows, cols) = mask.shape();
+        let r = radius.max(0.0) as isize;
+        ImageMask::from_fn(rows, cols, |i, j| {
+            if mask[(i, j)] == value {
+                return value;
+            }
+            for di in -r..=r {
+                for dj in -r..=r {
+                    if ((di * di + dj * dj) as f64) > radius * radius {
+                        continue;
+                    }
+                    let (i, j) = (i as isize + di, j as isize + dj);
+                    if i < 0
+                        || j < 0
+                        || i >= rows as isize
+                        || j >= cols as isize
                     {
-                        output[(i as usize, j as usize)] = false;
-                        break 'check;
+                        continue; // Outside pixels don't propagate.
+                    }
+                    if mask[(i as usize, j as usize)] == value {
+                        return value;
                     }
                 }
             }
+            !value
+        })
+    }
+
+    #[test]
+    fn test_morphology_matches_naive() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        for &(rows, cols, density) in
+            &[(1, 1, 0.5), (1, 17, 0.2), (23, 31, 0.05), (40, 29, 0.6)]
+        {
+            let mask =
+                ImageMask::from_fn(rows, cols, |_, _| rng.gen_bool(density));
+            for radius in [0.0, 0.7, 1.0, 1.5, 2.9, 5.0, 12.3, 100.0] {
+                assert_eq!(
+                    dilate(&mask, radius),
+                    naive_morph(&mask, radius, true)
+                );
+                assert_eq!(
+                    erode(&mask, radius),
+                    naive_morph(&mask, radius, false)
+                );
+            }
         }
     }
 
-    output
-}
+    #[test]
+    fn test_morphology_of_uniform_masks() {
+        let empty = ImageMask::from_element(5, 7, false);
+        assert_eq!(dilate(&empty, 1000.0), empty);
+        let full = ImageMask::from_element(5, 7, true);
+        assert_eq!(erode(&full, 1000.0), full);
+
+        let mut point = empty.clone();
+        point[(2, 3)] = true;
+        let dilated = dilate(&point, 1.0);
+        assert_eq!(dilated.iter().filter(|p| **p).count(), 5);
+        assert!(dilated[(1, 3)] && dilated[(2, 4)] && !dilated[(1, 2)]);
+        assert_eq!(erode(&dilated, 1.0), point);
+    }
 
-pub fn dilate(mask: &ImageMask, radius: f64) -> ImageMask {
-    let mut output = mask.clone();
+    #[test]
+    fn test_image_grid() {
+        // Rows go from black to red, columns from black to green.
+        let image = RgbImage::from_fn(3, 2, |x, y| {
+            Rgb([y as u8 * 100, x as u8 * 100, 0])
+        });
+        let grid = ImageGrid::new(&image);
+        assert_eq!(
+            grid,
+            ImageGrid {
+                height: 2,
+                width: 3
+            }
+        );
+
+        let ij = grid.uv_to_ij(Vector2::new(0.5, 1.0 / 3.0));
+        assert_eq!(ij, Vector2::new(1.0, 1.0));
+        assert_eq!(grid.ij_to_uv(ij), Vector2::new(0.5, 1.0 / 3.0));
+        let uv = Vector2::new(f64::NAN, 2.0);
+        assert_eq!(grid.uv_to_ij(uv), Vector2::new(0.0, 3.0));
+        assert_eq!(grid.nearest(Vector2::new(2.0, 3.0)), Some([1, 2]));
+        assert_eq!(grid.nearest(Vector2::new(-1.0, f64::NAN)), Some([0, 0]));
+
+        let sample = |u, v| sample_pixel(Vector2::new(u, v), &image);
+        assert_eq!(sample(0.0, 0.0), Vector3::zeros());
+        assert_eq!(sample(0.5, 0.5), Vector3::new(100.0, 150.0, 0.0));
+        assert_eq!(sample(1.0, 1.0), Vector3::new(100.0, 200.0, 0.0));
+
+        let mut image = image;
+        assert_eq!(
+            get_pixel_ij_as_vector3(5, 5, &image),
+            Vector3::new(100.0, 200.0, 0.0)
+        );
+        set_pixel_ij_as_vector3(2, 0, Vector3::repeat(255.0), &mut image);
+        set_pixel_ij_as_vector3(1, 0, Vector3::repeat(255.0), &mut image);
+        assert_eq!(image.get_pixel(0, 1), &Rgb([255, 255, 255]));
+
+        let mut empty = RgbImage::new(0, 4);
+        assert_eq!(
+            sample_pixel(Vector2::new(0.5, 0.5), &empty),
+            Vector3::zeros()
+        );
+        assert_eq!(get_pixel_ij_as_vector3(0, 0, &empty), Vector3::zeros());
+        set_pixel_ij_as_vector3(0, 0, Vector3::zeros(), &mut empty);
+    }
 
-    for i in 0..mask.nrows() as isize {
-        for j in 0..mask.ncols() as isize {
-            output[(i as usize, j as usize)] = mask[(i as usize, j as usize)];
+    #[test]
+    fn test_sample_pixel_clamps_rounding() {
+        let image = RgbImage::from_pixel(1, 2, Rgb([255, 255, 255]));
+        let color = sample_pixel(Vector2::new(0.129, 0.0), &image);
+        assert_eq!(color, Vector3::repeat(255.0));
+    }
+
+    fn vector2_strategy() -> impl Strategy<Value = Vector2> {
+        (-1e3..1e3, -1e3..1e3).prop_map(|(x, y)| Vector2::new(x, y))
+    }
+
+    fn mask_strategy() -> impl Strategy<Value = ImageMask> {
+        (0..12usize, 0..12usize).prop_flat_map(|(rows, cols)| {
+            prop::collection::vec(any::<bool>(), rows * cols)
+                .prop_map(move |v| ImageMask::from_vec(rows, cols, v))
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_sample_pixel_within_image(
+            (width, height) in (0..5u32, 0..5u32),
+            uv in prop::array::uniform2(prop_oneof![
+                -2.0..2.0,
+                Just(f64::NAN),
+                Just(f64::INFINITY),
+            ]),
+        ) {
+            let image = RgbImage::from_fn(width, height, |x, y| {
+                Rgb([x as u8 * 50, y as u8 * 50, 255])
+            });
+            let color = sample_pixel(Vector2::from(uv), &image);
+            let max = if image.is_empty() { 0.0 } else { 255.0 };
+            prop_assert!(color.iter().all(|c| (0.0..=max).contains(c)));
+        }
 
-            // Short-circuit when possible.
-            if output[(i as usize, j as usize)] {
-                continue;
+        #[test]
+        fn test_barycentric_round_trip(
+            vs in prop::array::uniform3(vector2_strategy()),
+            v in vector2_strategy(),
+        ) {
+            if let Some(bcs) = BarycentricCoordinateSystem::new(vs) {
+                let u = bcs.infer(v);
+                prop_assert!((u.sum() - 1.0).abs() <= 1e-6 * u.amax().max(1.0));
+                prop_assert!((bcs.apply(u) - v).norm() <= 1e-3);
+                for (k, &vertex) in vs.iter().enumerate() {
+                    let u = bcs.infer(vertex);
+                    prop_assert!((u - Vector3::ith(k, 1.0)).amax() <= 1e-6);
+                }
             }
+        }
 
-            'check: for di in -(radius as isize)..radius as isize {
-                for dj in -(radius as isize)..radius as isize {
-                    if Vector2::new(di as f64, dj as f64).norm() <= radius
-                        && mask
-                            .get(((i + di) as usize, (j + dj) as usize))
-                            .cloned()
-                            .unwrap_or(false)
-                    {
-                        output[(i as usize, j as usize)] = true;
-                        break 'check;
+        #[test]
+        fn test_barycentric_of_degenerate_triangles(
+            v0 in vector2_strategy(),
+            v1 in vector2_strategy(),
+            t in -10.0..10.0,
+        ) {
+            // Rounding keeps the third vertex nearly (not exactly) collinear.
+            let v2 = v0 + t * (v1 - v0);
+            let new = BarycentricCoordinateSystem::new;
+            prop_assert!(new([v0, v1, v2]).is_none());
+            prop_assert!(new([v0, v0, v1]).is_none());
+        }
+
+        #[test]
+        fn test_bounding_rectangle(
+            ijs in prop::collection::vec(
+                prop::array::uniform2(0..100u32),
+                0..20,
+            ),
+        ) {
+            let rect = Rectangle::bounding(&ijs);
+            prop_assert_eq!(rect.is_some(), !ijs.is_empty());
+            if let Some(rect) = rect {
+                for ij in &ijs {
+                    let sides = ij.iter().zip(rect.pos).zip(rect.size);
+                    for ((&c, pos), size) in sides {
+                        prop_assert!(c >= pos && c <= pos + size);
                     }
                 }
+                for k in 0..2 {
+                    prop_assert!(ijs.iter().any(|ij| ij[k] == rect.pos[k]));
+                    let end = rect.pos[k] + rect.size[k];
+                    prop_assert!(ijs.iter().any(|ij| ij[k] == end));
+                }
             }
         }
-    }
 
-    output
+        #[test]
+        fn test_bounding_rectangle_of_nan(
+            mut ijs in prop::collection::vec(
+                prop::array::uniform2(-1e3..1e3),
+                1..20,
+            ),
+            index in any::<prop::sample::Index>(),
+            k in 0..2usize,
+        ) {
+            let index = index.index(ijs.len());
+            ijs[index][k] = f64::NAN;
+            prop_assert!(Rectangle::<f64>::bounding(&ijs).is_none());
+        }
+
+        #[test]
+        fn test_morphology_properties(
+            mask in mask_strategy(),
+            radius in prop_oneof![
+                -2.0..20.0,
+                Just(f64::NAN),
+                Just(f64::INFINITY),
+            ],
+        ) {
+            let dilated = dilate(&mask, radius);
+            let eroded = erode(&mask, radius);
+            prop_assert_eq!(dilated.shape(), mask.shape());
+            prop_assert_eq!(eroded.shape(), mask.shape());
+            for ((&m, &d), &e) in mask.iter().zip(&dilated).zip(&eroded) {
+                prop_assert!(!m || d);
+                prop_assert!(m || !e);
+            }
+            // Nothing to grow from or to shrink into.
+            if !mask.iter().any(|&m| m) {
+                prop_assert_eq!(&dilated, &mask);
+            }
+            if mask.iter().all(|&m| m) {
+                prop_assert_eq!(&eroded, &mask);
+            }
+            if radius.is_finite() {
+                prop_assert_eq!(dilated, naive_morph(&mask, radius, true));
+                prop_assert_eq!(eroded, naive_morph(&mask, radius, false));
+            }
+        }
+
+        #[test]
+        fn test_ordered(e in prop::array::uniform2(any::<usize>())) {
+            let o = ordered(e);
+            prop_assert!(o[0] <= o[1]);
+            prop_assert_eq!(ordered(o), o);
+            prop_assert_eq!(ordered([e[1], e[0]]), o);
+            prop_assert!(o == e || o == [e[1], e[0]]);
+        }
+    }
 }
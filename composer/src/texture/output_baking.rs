@@ -19,18 +19,17 @@ pub struct ImageTriangle<'a> {
 }
 
 fn uv3_to_ij3(uv_coords: [Vector2; 3], image: &RgbImage) -> [Vector2; 3] {
-    [
-        uv_to_ij(uv_coords[0], image),
-        uv_to_ij(uv_coords[1], image),
-        uv_to_ij(uv_coords[2], image),
-    ]
+    let grid = ImageGrid::new(image);
+    uv_coords.map(|uv| grid.uv_to_ij(uv))
 }
 
 fn copy_triangle(
     input: &Result<ImageTriangle, Vector3>,
     output: &mut ImageTriangleMut,
     emptiness_mask: &mut ImageMask,
+    texel_groups: &mut [u16],
     face_idx: usize,
+    group: u16,
     color_correction: &ColorCorrection,
 ) -> Option<()> {
     // Rescale coordinates 0 <= [u,v] <= 1 to 0 <= [i,j] <= [h,w].
@@ -39,6 +38,7 @@ fn copy_triangle(
         Err(_) => None,
     };
     let ijs1 = uv3_to_ij3(output.uv_coords, output.image);
+    let grid1 = ImageGrid::new(output.image);
 
     // Create local coordinate systems in both source and target images.
     let bcs0 = match input {
@@ -49,7 +49,7 @@ fn copy_triangle(
 
     // Create a bounding box for the triangle in the target image.
     let g = |ij: Vector2| [ij[0] as u32, ij[1] as u32];
-    let rect1 = Rectangle::bounding(&[g(ijs1[0]), g(ijs1[1]), g(ijs1[2])]);
+    let rect1 = Rectangle::bounding(&[g(ijs1[0]), g(ijs1[1]), g(ijs1[2])])?;
 
     // Iterate over pixels inside the bounding box and fetch color values.
     let mut dbg_any = false;
@@ -61,23 +61,23 @@ fn copy_triangle(
                 // Sample color value and apply color correction.
                 Ok(input1) => {
                     let ij0 = bcs0.as_ref().unwrap().apply(bary);
-                    let uv0 = ij_to_uv(ij0, input1.image);
+                    let uv0 = ImageGrid::new(input1.image).ij_to_uv(ij0);
 
                     let sampled_color = sample_pixel(uv0, input1.image);
-                    let offset =
-                        color_correction.sample_color_offset(face_idx, bary);
-                    sampled_color + offset
+                    color_correction.correct_color(
+                        face_idx,
+                        bary,
+                        sampled_color,
+                    )
                 }
                 // Allow missing data to be filled in with user-specified color.
                 Err(color) => *color,
             };
 
-            if all_nonneg(bary)
-                && i1 < output.image.height()
-                && j1 < output.image.width()
-            {
+            if all_nonneg(bary) && grid1.contains(i1, j1) {
                 set_pixel_ij_as_vector3(i1, j1, color, output.image);
                 emptiness_mask[(i1 as usize, j1 as usize)] = false;
+                texel_groups[(i1 * grid1.width + j1) as usize] = group;
                 dbg_any = true;
             }
         }
@@ -94,23 +94,30 @@ pub struct BakingParams {
     pub missing_data_color: Option<Vector3>,
 }
 
+// Only the given faces are baked (e.g. ones of an atlas page). Also returns
+// the face group of each texel (0 for empty ones).
+#[allow(clippy::too_many_arguments)]
 pub fn bake_texture(
     mesh: &Mesh,
+    faces: &[usize],
     images: &[Option<RgbImage>],
     chosen_cameras: &[Option<usize>],
     vertex_metrics: &[FrameMetrics],
     uv_coords_tri: &[[Vector2; 3]],
+    uv_offsets: &[Vector2],
     color_correction: &ColorCorrection,
+    labels: &LabelTextureParams,
     params: &BakingParams,
-) -> (RgbImage, ImageMask) {
+) -> (RgbImage, ImageMask, Vec<u16>) {
     let mut buffer =
         RgbImage::new(params.image_res as u32, params.image_res as u32);
     let dim = Dim::from_usize(params.image_res);
     let mut emask = ImageMask::from_element_generic(dim, dim, true);
+    let mut texel_groups = vec![0; params.image_res * params.image_res];
 
     let dummy_image_source_black = dummy_image_source(Rgb([0, 0, 0]));
 
-    for face_idx in 0..mesh.faces.len() {
+    for &face_idx in faces {
         let input_triangle = if let Some(frame_idx) = chosen_cameras[face_idx] {
             Ok(ImageTriangle {
                 // Load image source.
@@ -122,7 +129,8 @@ pub fn bake_texture(
                     frame_idx,
                     vertex_metrics,
                     mesh,
-                ),
+                )
+                .map(|uv| uv + uv_offsets[face_idx]),
             })
         } else if let Some(color) = params.missing_data_color {
             Err(color)
@@ -148,12 +156,54 @@ pub fn bake_texture(
             &input_triangle,
             &mut output_triangle,
             &mut emask,
+            &mut texel_groups,
             face_idx,
+            labels.face_groups[face_idx],
             color_correction,
         );
     }
 
-    (buffer, emask)
+    (buffer, emask, texel_groups)
+}
+
+// Averages corrected colors of face corners around each vertex, vertices
+// of faces without chosen frames only get the missing data color (or black).
+pub fn bake_vertex_colors(
+    mesh: &Mesh,
+    images: &[Option<RgbImage>],
+    chosen_cameras: &[Option<usize>],
+    vertex_metrics: &[FrameMetrics],
+    color_correction: &ColorCorrection,
+    missing_data_color: Option<Vector3>,
+) -> Vec<Vector3> {
+    let mut sums = vec![Vector3::zeros(); mesh.vertices.len()];
+    let mut counts = vec![0; mesh.vertices.len()];
+    for (face_idx, face) in mesh.faces.iter().enumerate() {
+        let frame_idx = match chosen_cameras[face_idx] {
+            Some(frame_idx) => frame_idx,
+            None => continue,
+        };
+        let image = images[frame_idx].as_ref().unwrap();
+        let uvs =
+            uv_coords_from_metrics(face_idx, frame_idx, vertex_metrics, mesh);
+        for (corner, &vertex_idx) in face.iter().enumerate() {
+            let mut bary = Vector3::zeros();
+            bary[corner] = 1.0;
+            let color = sample_pixel(uvs[corner], image);
+            sums[vertex_idx] +=
+                color_correction.correct_color(face_idx, bary, color);
+            counts[vertex_idx] += 1;
+        }
+    }
+
+    let missing_color = missing_data_color.unwrap_or_else(Vector3::zeros);
+    sums.into_iter()
+        .zip(counts)
+        .map(|(sum, count)| match count {
+            0 => missing_color,
+            _ => sum / count as f64,
+        })
+        .collect()
 }
 
 pub fn uv_coords_from_metrics(
@@ -197,6 +247,153 @@ pub fn compress_uv_coords(
     (uv_ordered, uv_idxs)
 }
 
+pub struct PostprocessingParams {
+    pub sharpen_radius: f64,
+}
+
+// Radius in pixels of chroma smoothing at full denoising strength.
+const CHROMA_DENOISE_RADIUS: f64 = 2.0;
+
+// Radius in pixels of color smoothing at full smoothing strength.
+const SMOOTHING_RADIUS: f64 = 4.0;
+
+// Strengths of all steps are taken from the face group of each texel.
+pub fn postprocess_texture(
+    buffer: &mut RgbImage,
+    emask: &ImageMask,
+    texel_groups: &[u16],
+    labels: &LabelTextureParams,
+    params: &PostprocessingParams,
+) {
+    let smoothing = labels.any(|p| p.smoothing > 0.0);
+    let chroma_denoise = labels.any(|p| p.chroma_denoise > 0.0);
+    let sharpen = labels.any(|p| p.sharpen_amount > 0.0);
+    if !smoothing && !chroma_denoise && !sharpen {
+        return;
+    }
+
+    let (height, width) = emask.shape();
+    let mut pixels = vec![Vector3::zeros(); height * width];
+    for i in 0..height {
+        for j in 0..width {
+            let color = get_pixel_ij_as_vector3(i as u32, j as u32, buffer);
+            pixels[i * width + j] = rgb_to_ycbcr(color);
+        }
+    }
+    let group = |k: usize| &labels.groups[texel_groups[k] as usize];
+
+    // Smooth all channels, which evens out noise at the cost of detail.
+    if smoothing {
+        let blurred = masked_blur(&pixels, emask, SMOOTHING_RADIUS);
+        for (k, (pixel, smooth)) in
+            pixels.iter_mut().zip(blurred.iter()).enumerate()
+        {
+            *pixel += group(k).smoothing.min(1.0) * (smooth - *pixel);
+        }
+    }
+
+    // Smooth chroma channels only, so that no detail is lost in luma.
+    if chroma_denoise {
+        let blurred = masked_blur(&pixels, emask, CHROMA_DENOISE_RADIUS);
+        for (k, (pixel, smooth)) in
+            pixels.iter_mut().zip(blurred.iter()).enumerate()
+        {
+            let strength = group(k).chroma_denoise.min(1.0);
+            for c in 1..3 {
+                pixel[c] += strength * (smooth[c] - pixel[c]);
+            }
+        }
+    }
+
+    // Unsharp mask applied to luma, so that colors are not shifted.
+    if sharpen {
+        let blurred = masked_blur(&pixels, emask, params.sharpen_radius);
+        for (k, (pixel, smooth)) in
+            pixels.iter_mut().zip(blurred.iter()).enumerate()
+        {
+            pixel[0] += group(k).sharpen_amount * (pixel[0] - smooth[0]);
+        }
+    }
+
+    for i in 0..height {
+        for j in 0..width {
+            if !emask[(i, j)] {
+                let color = ycbcr_to_rgb(pixels[i * width + j]);
+                set_pixel_ij_as_vector3(i as u32, j as u32, color, buffer);
+            }
+        }
+    }
+}
+
+fn rgb_to_ycbcr(rgb: Vector3) -> Vector3 {
+    let y = 0.299 * rgb[0] + 0.587 * rgb[1] + 0.114 * rgb[2];
+    Vector3::new(y, 0.564 * (rgb[2] - y), 0.713 * (rgb[0] - y))
+}
+
+fn ycbcr_to_rgb(ycbcr: Vector3) -> Vector3 {
+    let (y, cb, cr) = (ycbcr[0], ycbcr[1], ycbcr[2]);
+    Vector3::new(y + 1.403 * cr, y - 0.344 * cb - 0.714 * cr, y + 1.773 * cb)
+}
+
+// Separable Gaussian blur ignoring empty pixels, so that patch colors do not
+// bleed into each other across the empty space between patches.
+fn masked_blur(
+    pixels: &[Vector3],
+    emask: &ImageMask,
+    sigma: f64,
+) -> Vec<Vector3> {
+    let (height, width) = emask.shape();
+    let radius = (3.0 * sigma).ceil().max(1.0) as isize;
+    let kernel: Vec<f64> = (-radius..=radius)
+        .map(|d| (-((d * d) as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+
+    let pass = |input: &[(Vector3, f64)], step: [isize; 2]| {
+        let mut output = vec![(Vector3::zeros(), 0.0); input.len()];
+        for i in 0..height as isize {
+            for j in 0..width as isize {
+                let mut sum = (Vector3::zeros(), 0.0);
+                for (k, &weight) in kernel.iter().enumerate() {
+                    let d = k as isize - radius;
+                    let (i1, j1) = (i + d * step[0], j + d * step[1]);
+                    if 0 <= i1
+                        && (i1 as usize) < height
+                        && 0 <= j1
+                        && (j1 as usize) < width
+                    {
+                        let (value, w) =
+                            input[i1 as usize * width + j1 as usize];
+                        sum.0 += weight * value;
+                        sum.1 += weight * w;
+                    }
+                }
+                output[i as usize * width + j as usize] = sum;
+            }
+        }
+        output
+    };
+
+    let weighted: Vec<(Vector3, f64)> = pixels
+        .iter()
+        .enumerate()
+        .map(|(k, &p)| {
+            let valid = !emask[(k / width, k % width)];
+            if valid {
+                (p, 1.0)
+            } else {
+                (Vector3::zeros(), 0.0)
+            }
+        })
+        .collect();
+    let blurred = pass(&pass(&weighted, [1, 0]), [0, 1]);
+
+    blurred
+        .iter()
+        .zip(pixels.iter())
+        .map(|(&(sum, w), &p)| if w > 0.0 { sum / w } else { p })
+        .collect()
+}
+
 pub fn extrapolate_gutter(
     buffer: &mut RgbImage,
     emask: &mut ImageMask,
@@ -239,3 +436,104 @@ fn dummy_image_source(color: Rgb<u8>) -> RgbImage {
     img[(0, 0)] = color;
     img
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_postprocess_texture_by_group() {
+        // Checkerboard of two rows, the upper one is smoothed.
+        let mut buffer = RgbImage::from_fn(8, 2, |x, _| {
+            if x % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([200, 200, 200])
+            }
+        });
+        let original = buffer.clone();
+        let dim = Dim::from_usize(2);
+        let emask =
+            ImageMask::from_element_generic(dim, Dim::from_usize(8), false);
+        let texel_groups: Vec<u16> = (0..16).map(|k| (k < 8) as u16).collect();
+
+        let params = TextureParams::from_iter(&["test"]);
+        let mut labels = LabelTextureParams::uniform(&params, 0);
+        let mut smoothed = labels.groups[0];
+        smoothed.smoothing = 1.0;
+        labels.groups.push(smoothed);
+
+        postprocess_texture(
+            &mut buffer,
+            &emask,
+            &texel_groups,
+            &labels,
+            &PostprocessingParams {
+                sharpen_radius: 1.0,
+            },
+        );
+        for x in 0..8 {
+            assert_eq!(buffer[(x, 1)], original[(x, 1)]);
+            let value = buffer[(x, 0)][0];
+            assert!(60 < value && value < 140, "{} at {}", value, x);
+        }
+    }
+
+    #[test]
+    fn test_bake_vertex_colors() {
+        let mesh = Mesh {
+            vertices: vec![Point3::origin(); 4],
+            normals: vec![],
+            faces: vec![[0, 1, 2], [1, 2, 3]],
+        };
+        // The left column is black, the right one is white.
+        let image = RgbImage::from_fn(2, 2, |x, _| Rgb([255 * x as u8; 3]));
+        let metrics = |u, v| Metrics {
+            pixel: Vector2::new(u, v),
+            depth: 1.0,
+            dot_product: 1.0,
+            within_bounds: true,
+            is_occluded: false,
+            is_background: false,
+            is_highlight: false,
+        };
+        let vertex_metrics = vec![Some(vec![
+            metrics(0.0, 0.0),
+            metrics(0.0, 1.0),
+            metrics(1.0, 1.0),
+            metrics(1.0, 1.0),
+        ])];
+        let chosen_cameras = [Some(0), None];
+        let images = [Some(image)];
+        let topo = BasicMeshTopology::new(&mesh);
+        let color_correction = ColorCorrection::new(
+            &mesh,
+            &topo,
+            &vertex_metrics,
+            &chosen_cameras,
+            &images,
+            Shading::uniform(&mesh),
+            0,
+        );
+
+        let missing = Vector3::new(255.0, 0.0, 255.0);
+        let colors = bake_vertex_colors(
+            &mesh,
+            &images,
+            &chosen_cameras,
+            &vertex_metrics,
+            &color_correction,
+            Some(missing),
+        );
+        assert_eq!(
+            colors,
+            [
+                Vector3::zeros(),
+                Vector3::repeat(255.0),
+                Vector3::repeat(255.0),
+                missing,
+            ]
+        );
+    }
+}
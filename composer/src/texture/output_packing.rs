@@ -36,6 +36,114 @@ pub fn pack_rectangles_with_automatic_stretching(
     (rectangles, scale)
 }
 
+pub struct AtlasParams {
+    pub spacing: f64,
+    // Resolution of pages, or the maximum one for a density target.
+    pub image_res: usize,
+    // Target number of texels per meter of (density-scaled) patch size.
+    pub texels_per_meter: Option<f64>,
+    pub max_pages: usize,
+}
+
+pub struct Atlas {
+    pub image_res: usize,
+    pub num_pages: usize,
+    pub patch_pages: Vec<usize>,
+    // Placements within the pages of patches.
+    pub placements: Vec<Rectangle<f64>>,
+    // The least one among pages.
+    pub texels_per_meter: f64,
+}
+
+// Pages are added until the density target is met at the maximum page
+// resolution (or pages run out), otherwise the resolution is lowered.
+pub fn pack_atlas(sizes: &[[f64; 2]], params: &AtlasParams) -> Atlas {
+    let target = match params.texels_per_meter {
+        Some(target) => target,
+        None => {
+            let (placements, scale) = pack_rectangles_with_automatic_stretching(
+                sizes,
+                params.spacing,
+            );
+            return Atlas {
+                image_res: params.image_res,
+                num_pages: 1,
+                patch_pages: vec![0; sizes.len()],
+                placements,
+                texels_per_meter: scale * params.image_res as f64,
+            };
+        }
+    };
+
+    let max_pages = params.max_pages.clamp(1, sizes.len().max(1));
+    let mut num_pages = 1;
+    loop {
+        let patch_pages = split_into_pages(sizes, num_pages);
+        let mut placements = vec![
+            Rectangle {
+                pos: [0.0; 2],
+                size: [0.0; 2]
+            };
+            sizes.len()
+        ];
+        let mut scale = f64::INFINITY;
+        for page in 0..num_pages {
+            let idxs: Vec<usize> = (0..sizes.len())
+                .filter(|&i| patch_pages[i] == page)
+                .collect();
+            let page_sizes: Vec<[f64; 2]> =
+                idxs.iter().map(|&i| sizes[i]).collect();
+            let (rectangles, page_scale) =
+                pack_rectangles_with_automatic_stretching(
+                    &page_sizes,
+                    params.spacing,
+                );
+            for (&i, rectangle) in idxs.iter().zip(rectangles) {
+                placements[i] = rectangle;
+            }
+            scale = scale.min(page_scale);
+        }
+
+        // Powers of 2 keep the pages friendly to GPU mipmapping.
+        let needed = (target / scale).ceil() as usize;
+        let image_res = needed.next_power_of_two().min(params.image_res);
+        if image_res >= needed || num_pages == max_pages {
+            return Atlas {
+                image_res,
+                num_pages,
+                patch_pages,
+                placements,
+                texels_per_meter: scale * image_res as f64,
+            };
+        }
+        num_pages += 1;
+    }
+}
+
+// Patches are spread evenly by area, the largest ones first.
+fn split_into_pages(sizes: &[[f64; 2]], num_pages: usize) -> Vec<usize> {
+    let area = |i: usize| sizes[i][0] * sizes[i][1];
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by(|&a, &b| area(b).total_cmp(&area(a)));
+
+    let mut page_areas = vec![0.0f64; num_pages];
+    let mut page_counts = vec![0; num_pages];
+    let mut pages = vec![0; sizes.len()];
+    for i in order {
+        let page = (0..num_pages)
+            .min_by(|&a, &b| {
+                page_areas[a]
+                    .total_cmp(&page_areas[b])
+                    .then(page_counts[a].cmp(&page_counts[b]))
+            })
+            .unwrap();
+        pages[i] = page;
+        page_areas[page] += area(i);
+        page_counts[page] += 1;
+    }
+    pages
+}
+
 fn apply2<T: Clone, U: Clone>(v: &[[T; 2]], f: &dyn Fn(T) -> U) -> Vec<[U; 2]> {
     v.iter()
         .map(|[a, b]| [f(a.clone()), f(b.clone())])
@@ -126,3 +234,59 @@ pub fn globalize_uv(
     }
     uv_coords
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_atlas() {
+        let sizes = vec![[1.0, 1.0], [1.0, 0.5], [0.5, 0.5], [0.5, 0.25]];
+        let mut params = AtlasParams {
+            spacing: 0.0,
+            image_res: 1000,
+            texels_per_meter: None,
+            max_pages: 1,
+        };
+        let atlas = pack_atlas(&sizes, &params);
+        assert_eq!((atlas.image_res, atlas.num_pages), (1000, 1));
+        assert_eq!(atlas.patch_pages, vec![0; 4]);
+
+        // A low target is met by a small page.
+        params.texels_per_meter = Some(100.0);
+        let atlas = pack_atlas(&sizes, &params);
+        assert_eq!(atlas.num_pages, 1);
+        assert!(atlas.image_res.is_power_of_two());
+        assert!(atlas.image_res < 1000);
+        assert!(atlas.texels_per_meter >= 100.0);
+
+        // A high target needs more pages.
+        params.texels_per_meter = Some(800.0);
+        params.max_pages = 4;
+        let atlas = pack_atlas(&sizes, &params);
+        assert!(atlas.num_pages > 1);
+        assert_eq!(atlas.image_res, 1000);
+        assert!(atlas.texels_per_meter > 500.0);
+        for page in 0..atlas.num_pages {
+            assert!(atlas.patch_pages.contains(&page));
+        }
+        for rect in &atlas.placements {
+            assert!(rect.pos[0] + rect.size[0] <= 1.0);
+            assert!(rect.pos[1] + rect.size[1] <= 1.0);
+        }
+
+        // Running out of pages leaves the target unmet.
+        params.max_pages = 2;
+        params.texels_per_meter = Some(1e6);
+        let atlas = pack_atlas(&sizes, &params);
+        assert_eq!((atlas.image_res, atlas.num_pages), (1000, 2));
+        assert!(atlas.texels_per_meter < 1e6);
+    }
+
+    #[test]
+    fn test_split_into_pages() {
+        let sizes = [[2.0, 1.0], [1.0, 1.0], [1.0, 1.0], [0.0, 0.0]];
+        assert_eq!(split_into_pages(&sizes, 2), vec![0, 1, 1, 0]);
+        assert_eq!(split_into_pages(&sizes, 4), vec![0, 1, 2, 3]);
+    }
+}
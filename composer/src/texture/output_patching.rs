@@ -182,6 +182,7 @@ fn get_big_chunk(
     faces_mask: &[bool],
     mesh: &Mesh,
     topo: &BasicMeshTopology,
+    seams: &SeamGroups,
 ) -> (Vec<usize>, Vector3, Vec<bool>) {
     // Project UVs from a heuristically good direction.
     let mut major_axis = get_major_axis(faces_mask, mesh);
@@ -195,7 +196,14 @@ fn get_big_chunk(
 
     // Fix glitches caused by UV self-overlap.
     let biggest_mask = idxs_to_mask(mesh.faces.len(), &biggest);
-    let biggest_mask = visible_faces(&biggest_mask, major_axis, mesh);
+    let mut biggest_mask = visible_faces(&biggest_mask, major_axis, mesh);
+
+    // Faces across forbidden seams are taken along, even at cost of overlap.
+    for k in mask_to_idxs(&biggest_mask) {
+        for &m in seams.members(k).unwrap_or_default() {
+            biggest_mask[m] |= faces_mask[m];
+        }
+    }
     let biggest = mask_to_idxs(&biggest_mask);
 
     // Remember the faces that were projected, to avoid duplication.
@@ -207,17 +215,19 @@ fn get_big_chunk(
     (biggest, major_axis, faces_mask_remaining)
 }
 
+// Patches are chosen among the masked faces only.
 pub fn choose_uv_patches(
     mesh: &Mesh,
     topo: &BasicMeshTopology,
+    mut faces_mask: Vec<bool>,
+    seams: &SeamGroups,
 ) -> Vec<(Vec<usize>, Vector3)> {
-    let mut faces_mask = vec![true; mesh.faces.len()];
     // ^ To make it faster, maybe replace this mask by a set of indices.
     let mut result = vec![];
 
     while faces_mask.iter().map(|&b| b as usize).sum::<usize>() > 0 {
         let (faces_idx_taken, major_axis, faces_mask_remaining) =
-            get_big_chunk(&faces_mask, mesh, topo);
+            get_big_chunk(&faces_mask, mesh, topo, seams);
 
         faces_mask = faces_mask_remaining;
 
@@ -281,11 +291,12 @@ impl LocalPatch {
             .map(|[uv0, uv1, uv2]| [f(uv0), f(uv1), f(uv2)])
             .collect();
 
-        // Normalize to [0,1]x[0,1].
+        // Normalize to [0,1]x[0,1] (patches aren't empty).
         let f = |uv: &Vector2| [uv[0], uv[1]];
         let uv_rect = Rectangle::<f64>::bounding(
             &uvs.iter().flatten().map(f).collect::<Vec<_>>(),
-        );
+        )
+        .unwrap();
         let [u_min, v_min] = uv_rect.pos;
         let [u_size, v_size] = uv_rect.size;
 
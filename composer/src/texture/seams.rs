@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use log::warn;
+use petgraph::unionfind::UnionFind;
+use serde::{Deserialize, Serialize};
+
+use crate::mesh::Mesh;
+use crate::misc::vec_inv_many;
+use crate::texture::*;
+use base::defs::{IntoResult, Result};
+use base::util::fs;
+
+// Edges are ordered pairs of (0-based) vertex indices of the output mesh.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct SeamsReport {
+    // Edges across which the texture atlas is cut.
+    pub uv_seams: Vec<[usize; 2]>,
+    // Edges between faces textured from different frames.
+    pub source_seams: Vec<[usize; 2]>,
+}
+
+impl SeamsReport {
+    pub fn new(
+        mesh: &Mesh,
+        topo: &BasicMeshTopology,
+        uv_idxs: &[[usize; 3]],
+        face_pages: &[usize],
+        chosen_cameras: &[Option<usize>],
+    ) -> SeamsReport {
+        let corner_uv = |face_idx: usize, vertex: usize| {
+            let pos = mesh.faces[face_idx].iter().position(|&v| v == vertex);
+            uv_idxs[face_idx][pos.unwrap()]
+        };
+
+        let mut report = SeamsReport::default();
+        for (&edge, faces) in &topo.faces_around_edge {
+            let (mut uv_seam, mut source_seam) = (false, false);
+            for (i, &f0) in faces.iter().enumerate() {
+                for &f1 in &faces[i + 1..] {
+                    uv_seam |= face_pages[f0] != face_pages[f1];
+                    uv_seam |= edge
+                        .iter()
+                        .any(|&v| corner_uv(f0, v) != corner_uv(f1, v));
+                    source_seam |= chosen_cameras[f0] != chosen_cameras[f1];
+                }
+            }
+            if uv_seam {
+                report.uv_seams.push(edge);
+            }
+            if source_seam {
+                report.source_seams.push(edge);
+            }
+        }
+        report.uv_seams.sort_unstable();
+        report.source_seams.sort_unstable();
+        report
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let write_err =
+            || format!("failed to write seams report '{}'", path.display());
+        let mut writer = BufWriter::new(fs::create_output(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)
+            .into_result(write_err)?;
+        writer.flush().into_result(write_err)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForbiddenSeamsFile {
+    edges: Vec<[usize; 2]>,
+}
+
+pub fn read_forbidden_seams(path: &Path) -> Result<Vec<[usize; 2]>> {
+    let text = fs::read_file_to_string(path)?;
+    let file: ForbiddenSeamsFile =
+        serde_json::from_str(&text).into_result(|| {
+            format!("malformed forbidden seams file '{}'", path.display())
+        })?;
+    Ok(file.edges)
+}
+
+// Faces joined by forbidden seam edges, which should be textured together.
+pub struct SeamGroups {
+    face_groups: Vec<usize>,
+    members: HashMap<usize, Vec<usize>>,
+}
+
+impl SeamGroups {
+    pub fn new(
+        mesh: &Mesh,
+        topo: &BasicMeshTopology,
+        edges: &[[usize; 2]],
+    ) -> SeamGroups {
+        let mut partition = UnionFind::new(mesh.faces.len());
+        let mut num_missing = 0;
+        for &edge in edges {
+            match topo.faces_around_edge.get(&ordered(edge)) {
+                Some(faces) => {
+                    for pair in faces.windows(2) {
+                        partition.union(pair[0], pair[1]);
+                    }
+                }
+                None => num_missing += 1,
+            }
+        }
+        if num_missing > 0 {
+            warn!("{} forbidden seam edges are missing in mesh", num_missing);
+        }
+
+        let face_groups = partition.into_labeling();
+        let mut members = vec_inv_many(&face_groups);
+        members.retain(|_, faces| faces.len() > 1);
+        SeamGroups {
+            face_groups,
+            members,
+        }
+    }
+
+    // Other faces of the group (including the given one), if any.
+    pub fn members(&self, face_idx: usize) -> Option<&[usize]> {
+        self.members
+            .get(&self.face_groups[face_idx])
+            .map(Vec::as_slice)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    // Each group is textured from a single frame: the one of least total
+    // cost among frames fit for all group faces, or else the most chosen.
+    pub fn unify_sources(
+        &self,
+        chosen_cameras: &mut [Option<usize>],
+        face_metrics: &[FrameMetrics],
+        all_costs: &[Option<Vec<f64>>],
+        labels: &LabelTextureParams,
+    ) {
+        let mut groups: Vec<_> = self.members.values().collect();
+        groups.sort_unstable();
+        for faces in groups {
+            let mut best: Option<(usize, f64)> = None;
+            for (frame_idx, costs) in all_costs.iter().enumerate() {
+                let (costs, metrics) = match (costs, &face_metrics[frame_idx]) {
+                    (Some(costs), Some(metrics)) => (costs, metrics),
+                    _ => continue,
+                };
+                let fit = faces.iter().all(|&f| {
+                    costs[f] <= labels.face(f).selection_cost_limit
+                        && !metrics[f].is_background
+                });
+                if !fit {
+                    continue;
+                }
+                let total: f64 = faces.iter().map(|&f| costs[f]).sum();
+                if best.is_none_or(|(_, best_total)| total < best_total) {
+                    best = Some((frame_idx, total));
+                }
+            }
+
+            if let Some((frame_idx, _)) = best {
+                for &f in faces {
+                    chosen_cameras[f] = Some(frame_idx);
+                }
+                continue;
+            }
+
+            // Faces unable to see the most chosen frame keep their choice.
+            let chosen: Vec<usize> =
+                faces.iter().filter_map(|&f| chosen_cameras[f]).collect();
+            let frame_idx = match vec_inv_many(&chosen).into_iter().max_by_key(
+                |(frame_idx, fs)| (fs.len(), usize::MAX - frame_idx),
+            ) {
+                Some((frame_idx, _)) => frame_idx,
+                None => continue,
+            };
+            let costs = all_costs[frame_idx].as_ref().unwrap();
+            for &f in faces {
+                if costs[f] <= labels.face(f).selection_cost_limit {
+                    chosen_cameras[f] = Some(frame_idx);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    // Two triangles sharing edge [1, 2], plus a separate one.
+    fn create_mesh() -> Mesh {
+        Mesh {
+            vertices: vec![
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+                Point3::new(1.0, 1.0, 0.0),
+                Point3::new(2.0, 0.0, 0.0),
+            ],
+            normals: vec![Vector3::z(); 5],
+            faces: vec![[0, 1, 2], [1, 3, 2], [1, 4, 3]],
+        }
+    }
+
+    #[test]
+    fn test_seams_report() {
+        let mesh = create_mesh();
+        let topo = BasicMeshTopology::new(&mesh);
+        // Faces 0 and 1 share UVs, face 2 has its own.
+        let uv_idxs = [[0, 1, 2], [1, 3, 2], [4, 5, 6]];
+        let chosen = [Some(0), Some(1), Some(1)];
+        let report = SeamsReport::new(&mesh, &topo, &uv_idxs, &[0; 3], &chosen);
+        assert_eq!(report.uv_seams, vec![[1, 3]]);
+        assert_eq!(report.source_seams, vec![[1, 2]]);
+
+        // Faces on different atlas pages are always separated.
+        let pages = [0, 1, 1];
+        let paged = SeamsReport::new(&mesh, &topo, &uv_idxs, &pages, &chosen);
+        assert_eq!(paged.uv_seams, vec![[1, 2], [1, 3]]);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(json, r#"{"uv_seams":[[1,3]],"source_seams":[[1,2]]}"#);
+    }
+
+    #[test]
+    fn test_unify_sources() {
+        let mesh = create_mesh();
+        let topo = BasicMeshTopology::new(&mesh);
+        let groups = SeamGroups::new(&mesh, &topo, &[[2, 1], [7, 8]]);
+        assert!(!groups.is_empty());
+        assert_eq!(groups.members(0).unwrap().len(), 2);
+        assert!(groups.members(2).is_none());
+
+        let params = TextureParams::from_iter(&["test"]);
+        let labels = LabelTextureParams::uniform(&params, 3);
+        let metrics = Some(vec![
+            Metrics {
+                pixel: Vector2::new(0.5, 0.5),
+                depth: 1.0,
+                dot_product: 1.0,
+                within_bounds: true,
+                is_occluded: false,
+                is_background: false,
+                is_highlight: false,
+            };
+            3
+        ]);
+        let face_metrics = vec![metrics.clone(), metrics];
+        // Frame 1 is worse for face 0 but better for the group as a whole.
+        let all_costs =
+            vec![Some(vec![1.0, 5.0, 1.0]), Some(vec![2.0, 1.0, 5.0])];
+        let mut chosen = vec![Some(0), Some(1), Some(0)];
+        groups.unify_sources(&mut chosen, &face_metrics, &all_costs, &labels);
+        assert_eq!(chosen, vec![Some(1), Some(1), Some(0)]);
+    }
+}
@@ -1,14 +1,19 @@
+use std::path::PathBuf;
+
 use indexmap::IndexMap;
 use log::warn;
 use structopt::StructOpt;
+use tracing::info_span;
 
 use crate::mesh::Mesh;
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::telemetry;
 use crate::texture::*;
 use base::defs::Result;
 use base::fm;
 use base::util::cli::parse_color;
 
-#[derive(Clone, StructOpt)]
+#[derive(Clone, Debug, StructOpt)]
 pub struct TextureParams {
     #[structopt(
         help = "Spacing between patches in the final texture",
@@ -32,6 +37,21 @@ pub struct TextureParams {
     )]
     pub image_resolution: usize,
 
+    #[structopt(
+        help = "Target texels per meter of texture (makes --image-resolution \
+                the maximum one)",
+        long
+    )]
+    pub texels_per_meter: Option<f64>,
+
+    #[structopt(
+        help = "Maximum number of texture atlas pages to meet \
+                --texels-per-meter",
+        long,
+        default_value = "1"
+    )]
+    pub max_atlas_pages: usize,
+
     #[structopt(
         help = "Threshold beyond which a mesh face is deemed not visible",
         long,
@@ -83,6 +103,120 @@ pub struct TextureParams {
         parse(try_from_str = parse_color_into_vector3),
     )]
     pub missing_data_color: Option<Vector3>,
+
+    #[structopt(
+        help = "Refine texture patch alignment by photometric consistency",
+        long
+    )]
+    pub texture_align_refine: bool,
+
+    #[structopt(
+        help = "Maximum shift in pixels of texture patch alignment",
+        long,
+        default_value = "3"
+    )]
+    pub texture_align_max_shift: f64,
+
+    #[structopt(
+        help = "Remove low-frequency per-frame shading from the texture",
+        long
+    )]
+    pub delight: bool,
+
+    #[structopt(
+        help = "Strength of unsharp mask applied to the baked texture",
+        long,
+        default_value = "0"
+    )]
+    pub texture_sharpen_amount: f64,
+
+    #[structopt(
+        help = "Radius in pixels of unsharp mask applied to the baked texture",
+        long,
+        default_value = "1.0"
+    )]
+    pub texture_sharpen_radius: f64,
+
+    #[structopt(
+        help = "Strength (0 to 1) of chroma denoising of the baked texture",
+        long,
+        default_value = "0"
+    )]
+    pub texture_chroma_denoise: f64,
+
+    #[structopt(
+        help = "Strength (0 to 1) of color smoothing of the baked texture",
+        long,
+        default_value = "0"
+    )]
+    pub texture_smoothing: f64,
+
+    #[structopt(
+        help = "Input TOML file with texturing overrides by face label",
+        long
+    )]
+    pub texture_labels_config: Option<PathBuf>,
+
+    #[structopt(
+        help = "Input JSON file with mesh edges not to be texture seams",
+        long
+    )]
+    pub forbidden_seams: Option<PathBuf>,
+
+    #[structopt(help = "Output JSON file with final texture seams", long)]
+    pub seams_report: Option<PathBuf>,
+
+    #[structopt(
+        help = "Memory (in MB) to keep decoded frame images between stages",
+        long,
+        default_value = "4096"
+    )]
+    pub frame_image_cache_mb: usize,
+}
+
+impl CheckParams for TextureParams {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require((0.0..0.5).contains(&self.patch_spacing), || {
+            "--patch-spacing should be within [0, 0.5)".to_string()
+        });
+        check.require(self.image_resolution > 0, || {
+            "--image-resolution should be positive".to_string()
+        });
+        check.require(self.texels_per_meter.is_none_or(|t| t > 0.0), || {
+            "--texels-per-meter should be positive".to_string()
+        });
+        check.require(self.max_atlas_pages > 0, || {
+            "--max-atlas-pages should be positive".to_string()
+        });
+        check.require(
+            self.max_atlas_pages == 1 || self.texels_per_meter.is_some(),
+            || "--max-atlas-pages needs --texels-per-meter".to_string(),
+        );
+        check.require(self.selection_cost_limit > 0.0, || {
+            "--selection-cost-limit should be positive".to_string()
+        });
+        check.require(
+            (0.0..=1.0).contains(&self.background_consensus_threshold),
+            || {
+                "--background-consensus-threshold should be within [0, 1]"
+                    .to_string()
+            },
+        );
+        check.require(self.texture_align_max_shift >= 0.0, || {
+            "--texture-align-max-shift should not be negative".to_string()
+        });
+        check.require(self.texture_sharpen_radius > 0.0, || {
+            "--texture-sharpen-radius should be positive".to_string()
+        });
+        check.require(
+            (0.0..=1.0).contains(&self.texture_chroma_denoise),
+            || "--texture-chroma-denoise should be within [0, 1]".to_string(),
+        );
+        check.require((0.0..=1.0).contains(&self.texture_smoothing), || {
+            "--texture-smoothing should be within [0, 1]".to_string()
+        });
+        self.background.check_into(check);
+    }
 }
 
 pub fn parse_color_into_vector3(src: &str) -> Result<Vector3> {
@@ -95,6 +229,10 @@ pub struct TexturedMesh {
     pub uv_coords: Vec<Vector2>,
     pub uv_idxs: Vec<[usize; 3]>,
     pub image: RgbImage,
+    // Further atlas pages, the image being the first one.
+    pub pages: Vec<RgbImage>,
+    pub face_pages: Vec<usize>,
+    pub seams: SeamsReport,
 }
 
 impl TexturedMesh {
@@ -102,105 +240,319 @@ impl TexturedMesh {
         scans: &IndexMap<String, fm::Scan>,
         scan_frames: &[fm::ScanFrame],
         mesh: Mesh,
+        labels: &LabelTextureParams,
+        forbidden_seams: &[[usize; 2]],
         params: &TextureParams,
     ) -> Result<TexturedMesh> {
-        let topo = BasicMeshTopology::new(&mesh);
-
-        let VertexAndFaceMetricsOfAllFrames {
+        let Sources {
+            topo,
+            seam_groups,
+            cache,
             vertex_metrics,
-            face_metrics,
-        } = make_all_frame_metrics(
+            chosen_cameras,
+        } = select_sources(
             scans,
             scan_frames,
             &mesh,
-            &params.background,
-        );
-        let all_costs = build_all_costs(
-            &face_metrics,
-            &topo,
-            params.selection_corner_radius,
-        );
-        let mut chosen_cameras =
-            select_cameras(
-                &all_costs,
-                &face_metrics,
-                &mesh,
-                params.selection_cost_limit);
-        if params.input_patching_threshold > 1.0 {
-            if params.background.deviation >= 0.0 {
-                form_patches(
-                    &mut chosen_cameras,
-                    &face_metrics,
-                    &all_costs,
-                    &mesh,
-                    &topo,
-                    params.input_patching_threshold,
-                );
-            } else {
-                warn!(
-                    "input patching was disabled because \
-                       background_deviation < 0"
-                )
+            labels,
+            forbidden_seams,
+            params,
+        )?;
+
+        let packing_span = info_span!("packing").entered();
+        // Patches do not mix face groups, so that each has its own density.
+        let mut local_patches: Vec<LocalPatch> = vec![];
+        for group in 0..labels.groups.len() as u16 {
+            let faces_mask: Vec<bool> =
+                labels.face_groups.iter().map(|&g| g == group).collect();
+            if !faces_mask.contains(&true) {
+                continue;
+            }
+            let patches =
+                choose_uv_patches(&mesh, &topo, faces_mask, &seam_groups);
+            for (chunk, major) in patches {
+                local_patches
+                    .push(LocalPatch::calculate_from(&chunk, major, &mesh));
             }
         }
-        disqualify_background_faces(
-            &mut chosen_cameras,
-            &face_metrics,
-            &all_costs,
-            &mesh,
-            &topo,
-            BackgroundDisqualificationParams {
-                cost_limit: params.selection_cost_limit,
-                consensus_threshold: params.background_consensus_threshold,
-                consensus_spread: params.background_consensus_spread,
-            },
-        );
-
-        let local_patches: Vec<LocalPatch> = choose_uv_patches(&mesh, &topo)
+        let local_patch_sizes: Vec<[f64; 2]> = local_patches
             .iter()
-            .map(|(chunk, major)| {
-                LocalPatch::calculate_from(chunk, *major, &mesh)
+            .map(|patch| {
+                let density = labels.face(patch.chunk[0]).texel_density;
+                patch.size.map(|size| size * density)
             })
             .collect();
-        let local_patch_sizes: Vec<[f64; 2]> =
-            local_patches.iter().map(|patch| patch.size).collect();
-        let (rectangle_placements_vec, _scale) =
-            pack_rectangles_with_automatic_stretching(
-                &local_patch_sizes,
-                params.patch_spacing,
+        let atlas = pack_atlas(
+            &local_patch_sizes,
+            &AtlasParams {
+                spacing: params.patch_spacing,
+                image_res: params.image_resolution,
+                texels_per_meter: params.texels_per_meter,
+                max_pages: params.max_atlas_pages,
+            },
+        );
+        if params
+            .texels_per_meter
+            .is_some_and(|t| atlas.texels_per_meter < t)
+        {
+            warn!(
+                "texture has {:.0} texels per meter only \
+                 (see --max-atlas-pages)",
+                atlas.texels_per_meter
             );
+        }
+        telemetry::count("texture atlas pages", atlas.num_pages);
+        let mut face_pages = vec![0; mesh.faces.len()];
+        for (patch, &page) in local_patches.iter().zip(&atlas.patch_pages) {
+            for &face_idx in &patch.chunk {
+                face_pages[face_idx] = page;
+            }
+        }
         let uv_coords_tri =
-            globalize_uv(&local_patches, &rectangle_placements_vec, &mesh);
+            globalize_uv(&local_patches, &atlas.placements, &mesh);
         let (uv_coords, uv_idxs_tri) = compress_uv_coords(&uv_coords_tri);
-
-        let images = load_all_frame_images(scan_frames);
-        let color_correction = ColorCorrection::new(
+        let seams = SeamsReport::new(
             &mesh,
             &topo,
-            &vertex_metrics,
+            &uv_idxs_tri,
+            &face_pages,
             &chosen_cameras,
-            &images,
-            params.color_correction_steps,
         );
-        let (mut buffer, mut emask) = bake_texture(
+        drop(packing_span);
+
+        let _baking_span = info_span!("baking").entered();
+
+        let images =
+            take_chosen_images(&cache, scan_frames.len(), &chosen_cameras);
+        let uv_offsets = if params.texture_align_refine {
+            let _span = info_span!("alignment").entered();
+            refine_alignment(
+                &mesh,
+                &topo,
+                &chosen_cameras,
+                &vertex_metrics,
+                &images,
+                params.texture_align_max_shift,
+            )
+        } else {
+            vec![Vector2::zeros(); mesh.faces.len()]
+        };
+        let color_correction = correct_colors(
             &mesh,
-            &images,
-            &chosen_cameras,
+            &topo,
             &vertex_metrics,
-            &uv_coords_tri,
-            &color_correction,
-            &BakingParams {
-                image_res: params.image_resolution,
-                missing_data_color: params.missing_data_color,
-            },
+            &chosen_cameras,
+            &images,
+            params,
         );
-        extrapolate_gutter(&mut buffer, &mut emask, params.gutter_size);
+        let mut pages = Vec::with_capacity(atlas.num_pages);
+        for page in 0..atlas.num_pages {
+            let faces: Vec<usize> = (0..mesh.faces.len())
+                .filter(|&face_idx| face_pages[face_idx] == page)
+                .collect();
+            let (mut buffer, mut emask, texel_groups) = bake_texture(
+                &mesh,
+                &faces,
+                &images,
+                &chosen_cameras,
+                &vertex_metrics,
+                &uv_coords_tri,
+                &uv_offsets,
+                &color_correction,
+                labels,
+                &BakingParams {
+                    image_res: atlas.image_res,
+                    missing_data_color: params.missing_data_color,
+                },
+            );
+            postprocess_texture(
+                &mut buffer,
+                &emask,
+                &texel_groups,
+                labels,
+                &PostprocessingParams {
+                    sharpen_radius: params.texture_sharpen_radius,
+                },
+            );
+            extrapolate_gutter(&mut buffer, &mut emask, params.gutter_size);
+            pages.push(buffer);
+        }
+        let image = pages.remove(0);
 
         Ok(TexturedMesh {
             mesh,
             uv_coords,
             uv_idxs: uv_idxs_tri,
-            image: buffer,
+            image,
+            pages,
+            face_pages,
+            seams,
         })
     }
 }
+
+// Frames chosen to color each face, along with what they are chosen by.
+struct Sources<'a> {
+    topo: BasicMeshTopology,
+    seam_groups: SeamGroups,
+    cache: FrameImageCache<'a>,
+    vertex_metrics: Vec<FrameMetrics>,
+    chosen_cameras: Vec<Option<usize>>,
+}
+
+fn select_sources<'a>(
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &'a [fm::ScanFrame],
+    mesh: &Mesh,
+    labels: &LabelTextureParams,
+    forbidden_seams: &[[usize; 2]],
+    params: &TextureParams,
+) -> Result<Sources<'a>> {
+    let topo = BasicMeshTopology::new(mesh);
+    let seam_groups = SeamGroups::new(mesh, &topo, forbidden_seams);
+
+    let cache =
+        FrameImageCache::new(scan_frames, params.frame_image_cache_mb << 20);
+
+    let metrics_span = info_span!("metrics").entered();
+    let VertexAndFaceMetricsOfAllFrames {
+        vertex_metrics,
+        face_metrics,
+    } = make_all_frame_metrics(
+        scans,
+        scan_frames,
+        &cache,
+        mesh,
+        &params.background,
+    )?;
+    drop(metrics_span);
+
+    let selection_span = info_span!("selection").entered();
+    let all_costs =
+        build_all_costs(&face_metrics, &topo, params.selection_corner_radius);
+    let mut chosen_cameras =
+        select_cameras(&all_costs, &face_metrics, mesh, labels);
+    if labels.any(|p| p.input_patching_threshold > 1.0) {
+        if params.background.deviation >= 0.0 {
+            form_patches(
+                &mut chosen_cameras,
+                &face_metrics,
+                &all_costs,
+                mesh,
+                &topo,
+                labels,
+            );
+        } else {
+            warn!(
+                "input patching was disabled because \
+                 background_deviation < 0"
+            )
+        }
+    }
+    if !seam_groups.is_empty() {
+        seam_groups.unify_sources(
+            &mut chosen_cameras,
+            &face_metrics,
+            &all_costs,
+            labels,
+        );
+    }
+    disqualify_background_faces(
+        &mut chosen_cameras,
+        &face_metrics,
+        &all_costs,
+        mesh,
+        &topo,
+        labels,
+        BackgroundDisqualificationParams {
+            consensus_threshold: params.background_consensus_threshold,
+            consensus_spread: params.background_consensus_spread,
+        },
+    );
+    drop(selection_span);
+
+    Ok(Sources {
+        topo,
+        seam_groups,
+        cache,
+        vertex_metrics,
+        chosen_cameras,
+    })
+}
+
+// Only images of chosen frames are sampled from.
+fn take_chosen_images(
+    cache: &FrameImageCache,
+    num_frames: usize,
+    chosen_cameras: &[Option<usize>],
+) -> Vec<Option<RgbImage>> {
+    let mut used = vec![false; num_frames];
+    for &frame_idx in chosen_cameras.iter().flatten() {
+        used[frame_idx] = true;
+    }
+    let images = cache.take(&used);
+    telemetry::count("decoded frame images", cache.num_decoded());
+    images
+}
+
+fn correct_colors(
+    mesh: &Mesh,
+    topo: &BasicMeshTopology,
+    vertex_metrics: &[FrameMetrics],
+    chosen_cameras: &[Option<usize>],
+    images: &[Option<RgbImage>],
+    params: &TextureParams,
+) -> ColorCorrection {
+    let shading = if params.delight {
+        let _span = info_span!("delighting").entered();
+        Shading::new(mesh, vertex_metrics, chosen_cameras, images)
+    } else {
+        Shading::uniform(mesh)
+    };
+    ColorCorrection::new(
+        mesh,
+        topo,
+        vertex_metrics,
+        chosen_cameras,
+        images,
+        shading,
+        params.color_correction_steps,
+    )
+}
+
+// Colors of vertices sampled from the same frames as textures are, which
+// skips UV patching, packing and baking (e.g. for fast previews).
+pub fn vertex_colors(
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+    mesh: &Mesh,
+    labels: &LabelTextureParams,
+    params: &TextureParams,
+) -> Result<Vec<Vector3>> {
+    let Sources {
+        topo,
+        cache,
+        vertex_metrics,
+        chosen_cameras,
+        ..
+    } = select_sources(scans, scan_frames, mesh, labels, &[], params)?;
+
+    let _span = info_span!("baking").entered();
+    let images = take_chosen_images(&cache, scan_frames.len(), &chosen_cameras);
+    let color_correction = correct_colors(
+        mesh,
+        &topo,
+        &vertex_metrics,
+        &chosen_cameras,
+        &images,
+        params,
+    );
+    Ok(bake_vertex_colors(
+        mesh,
+        &images,
+        &chosen_cameras,
+        &vertex_metrics,
+        &color_correction,
+        params.missing_data_color,
+    ))
+}
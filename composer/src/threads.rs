@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use structopt::StructOpt;
+
+use base::defs::{Error, ErrorKind::*, Result};
+
+// Thread count denoting automatic detection.
+pub const AUTO: usize = 0;
+
+// Parses number of threads, either positive or "auto" (given as zero).
+pub fn parse_threads<T: FromStr + Default + PartialOrd>(s: &str) -> Result<T> {
+    if s == "auto" {
+        return Ok(T::default());
+    }
+    match s.parse::<T>() {
+        Ok(num) if num > T::default() => Ok(num),
+        _ => Err(Error::new(
+            MalformedData,
+            "number of threads should be positive or 'auto'".to_string(),
+        )),
+    }
+}
+
+// Resolves automatic thread count into the size of the current rayon pool,
+// which is the number of available CPUs unless configured otherwise.
+pub fn resolve_threads(threads: usize) -> usize {
+    if threads == AUTO {
+        rayon::current_num_threads()
+    } else {
+        threads
+    }
+}
+
+// Creates a pool to run parallel stages in, so that they all share the same
+// thread configuration.
+pub fn create_thread_pool(threads: usize) -> Result<ThreadPool> {
+    let threads = if threads == AUTO {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        threads
+    };
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| {
+            let desc = "failed to create thread pool".to_string();
+            Error::with_source(UnknownError, desc, e)
+        })
+}
+
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub struct ThreadParams {
+    #[structopt(
+        help = "Number of threads for parallel stages (or auto)",
+        long = "threads",
+        default_value = "auto",
+        parse(try_from_str = parse_threads)
+    )]
+    pub num_threads: usize,
+}
+
+impl ThreadParams {
+    // Creates a pool scoped to command rather than using the global one.
+    pub fn pool(&self) -> Result<ThreadPool> {
+        create_thread_pool(self.num_threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_threads() {
+        assert_eq!(parse_threads::<usize>("auto").unwrap(), AUTO);
+        assert_eq!(parse_threads::<i32>("4").unwrap(), 4);
+        for s in ["0", "-1", "many"] {
+            let err = parse_threads::<i32>(s).unwrap_err();
+            assert_eq!(err.kind, MalformedData);
+        }
+    }
+
+    #[test]
+    fn test_resolve_threads() {
+        let pool = create_thread_pool(3).unwrap();
+        assert_eq!(pool.install(|| resolve_threads(AUTO)), 3);
+        assert_eq!(pool.install(|| resolve_threads(5)), 5);
+    }
+
+    #[test]
+    fn test_thread_params() {
+        let params = ThreadParams::from_iter(["test", "--threads=2"]);
+        assert_eq!(params.pool().unwrap().current_num_threads(), 2);
+        let params = ThreadParams::from_iter(["test"]);
+        assert_eq!(params.num_threads, AUTO);
+        assert!(ThreadParams::from_iter_safe(["test", "--threads=0"]).is_err());
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::param_check::{CheckParams, ParamCheck};
+use crate::point_cloud::{
+    camera_view_rotation, Matrix3, Point3, PointCloudParams, Vector3,
+};
+use crate::poisson::{Cloud, Mesh};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+
+const MAX_RESOLUTION: usize = 512;
+
+#[derive(Clone, Copy, Debug, StructOpt)]
+pub struct Params {
+    #[structopt(
+        help = "Number of TSDF voxels along longest cloud side",
+        long = "tsdf-resolution",
+        default_value = "128"
+    )]
+    pub tsdf_resolution: usize,
+
+    #[structopt(
+        help = "TSDF distance truncation (in voxels)",
+        long = "tsdf-truncation",
+        default_value = "3"
+    )]
+    pub tsdf_truncation: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            tsdf_resolution: 128,
+            tsdf_truncation: 3,
+        }
+    }
+}
+
+impl CheckParams for Params {
+    fn check_into(&self, check: &mut ParamCheck) {
+        check.require(
+            self.tsdf_resolution > 0 && self.tsdf_resolution <= MAX_RESOLUTION,
+            || {
+                format!(
+                    "--tsdf-resolution should be within [1, {}]",
+                    MAX_RESOLUTION
+                )
+            },
+        );
+        check.require(self.tsdf_truncation > 0, || {
+            "--tsdf-truncation should be positive".to_string()
+        });
+    }
+}
+
+// Depth map of frame projecting scene points the way build_point_cloud
+// unprojects its pixels.
+struct DepthView<'a> {
+    scan: &'a fm::Scan,
+    frame: &'a fm::ScanFrame,
+    min_confidence: i32,
+    tan: f64,
+    eye: Vector3,
+    // Inverses of view and turntable rotations.
+    view_inv: Matrix3,
+    time_inv: Matrix3,
+}
+
+impl<'a> DepthView<'a> {
+    fn new(
+        scan: &'a fm::Scan,
+        frame: &'a fm::ScanFrame,
+        params: &PointCloudParams,
+    ) -> Result<DepthView<'a>> {
+        let size = scan.depth_width as usize * scan.depth_height as usize;
+        if !frame.depths.is_empty() && frame.depths.len() != size {
+            let desc = format!(
+                "{} depths of frame for scan '{}' instead of {}",
+                frame.depths.len(),
+                scan.name,
+                size
+            );
+            return Err(Error::new(MalformedData, desc));
+        }
+
+        let eye = scan.camera_initial_position.unwrap_or_default();
+        let view_rot = camera_view_rotation(scan)?;
+        let view_rot = view_rot.fixed_slice::<3, 3>(0, 0).into_owned();
+        let time_rot = nalgebra::UnitQuaternion::from_axis_angle(
+            &Vector3::z_axis(),
+            fm::camera_angle(scan, frame),
+        );
+        Ok(DepthView {
+            scan,
+            frame,
+            min_confidence: params.min_depth_confidence as i32,
+            tan: (scan.camera_angle_of_view as f64 / 2.0).tan(),
+            eye: Vector3::new(eye.x as f64, eye.y as f64, eye.z as f64),
+            view_inv: view_rot.try_inverse().unwrap_or_default(),
+            time_inv: time_rot.inverse().to_rotation_matrix().into_inner(),
+        })
+    }
+
+    // Distance from point to measured surface along the view direction,
+    // positive in front of the surface.
+    fn signed_distance(&self, point: &Point3) -> Option<f64> {
+        let (width, height) = (
+            self.scan.depth_width as usize,
+            self.scan.depth_height as usize,
+        );
+        if self.frame.depths.is_empty() {
+            return None;
+        }
+
+        let camera = self.view_inv * (self.time_inv * point.coords - self.eye);
+        let depth = -camera.z;
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let half_width = width as f64 / 2.0;
+        let j = (camera.x / depth / self.tan * half_width + half_width).round();
+        let i = (-camera.y / depth / self.tan * half_width
+            + height as f64 / 2.0)
+            .round();
+        if !(0.0..width as f64).contains(&j)
+            || !(0.0..height as f64).contains(&i)
+        {
+            return None;
+        }
+
+        let index = i as usize * width + j as usize;
+        let confidence = self.frame.depth_confidences.get(index).copied();
+        if confidence.unwrap_or_default() < self.min_confidence {
+            return None;
+        }
+        let mut measured = self.frame.depths[index] as f64;
+        if !measured.is_finite() {
+            return None;
+        }
+
+        // If depth sensor measures distance rather than depth.
+        if !self.scan.sensor_plane_depth {
+            let u = (j - half_width) / half_width * self.tan;
+            let v = (i - height as f64 / 2.0) / half_width * self.tan;
+            measured /= (1.0 + u * u + v * v).sqrt();
+        }
+        Some(measured - depth)
+    }
+}
+
+// Averages of truncated signed distances (in truncation units), unseen
+// voxels have zero weight.
+#[derive(Clone, Copy, Default)]
+struct Voxel {
+    distance: f32,
+    weight: f32,
+}
+
+// Dense voxel grid around cloud.
+struct Field {
+    origin: Point3,
+    cell: f64,
+    dims: [usize; 3],
+    voxels: Vec<Voxel>,
+}
+
+impl Field {
+    fn new(cloud: &dyn Cloud<f64>, params: &Params) -> Option<Field> {
+        let mut points = (0..cloud.len())
+            .map(|i| Vector3::from(cloud.point(i)))
+            .filter(|p| p.norm().is_finite());
+        let first = points.next()?;
+        let (mut min, mut max) = (first, first);
+        for point in points {
+            min = min.inf(&point);
+            max = max.sup(&point);
+        }
+        let extent = (max - min).max();
+        let cell = if extent > 0.0 {
+            extent / params.tsdf_resolution as f64
+        } else {
+            1.0
+        };
+
+        let margin = params.tsdf_truncation + 1;
+        let dims = [0, 1, 2].map(|axis| {
+            ((max[axis] - min[axis]) / cell).ceil() as usize + 2 * margin + 1
+        });
+        Some(Field {
+            origin: Point3::from(min) - Vector3::repeat(margin as f64 * cell),
+            cell,
+            dims,
+            voxels: vec![Voxel::default(); dims[0] * dims[1] * dims[2]],
+        })
+    }
+
+    fn index(&self, key: [usize; 3]) -> usize {
+        (key[2] * self.dims[1] + key[1]) * self.dims[0] + key[0]
+    }
+
+    fn position(&self, key: [usize; 3]) -> Point3 {
+        let index = Vector3::new(key[0] as f64, key[1] as f64, key[2] as f64);
+        self.origin + index * self.cell
+    }
+
+    // Voxels far behind measured surfaces are left unseen, since they can
+    // be either inside of an object or occluded by it.
+    fn fuse(&mut self, views: &[DepthView], truncation: f64) {
+        let (origin, cell, dims) = (self.origin, self.cell, self.dims);
+        self.voxels.par_chunks_mut(dims[0]).enumerate().for_each(
+            |(row, voxels)| {
+                let (y, z) = (row % dims[1], row / dims[1]);
+                for (x, voxel) in voxels.iter_mut().enumerate() {
+                    let index = Vector3::new(x as f64, y as f64, z as f64);
+                    let point = origin + index * cell;
+                    for view in views {
+                        match view.signed_distance(&point) {
+                            Some(d) if d >= -truncation => {
+                                voxel.distance +=
+                                    (d / truncation).min(1.0) as f32;
+                                voxel.weight += 1.0;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    fn distance(&self, key: [usize; 3]) -> Option<f64> {
+        let voxel = &self.voxels[self.index(key)];
+        (voxel.weight > 0.0).then(|| (voxel.distance / voxel.weight) as f64)
+    }
+}
+
+// Corner of cube given by bit flags of its coordinates.
+fn corner_key(cell: [usize; 3], corner: usize) -> [usize; 3] {
+    [0, 1, 2].map(|axis| cell[axis] + (corner >> axis & 1))
+}
+
+// Polygons of isosurface within cube as loops of crossed edges (given by
+// their corners), oriented to face positive distances. Faces of the cube
+// are walked counter-clockwise when looking from outside, so that each
+// crossing enters the negative part of one face and leaves the other.
+fn cube_polygons(distances: &[f64; 8]) -> Vec<Vec<[usize; 2]>> {
+    let inside = |corner: usize| distances[corner] < 0.0;
+    let edge = |a: usize, b: usize| [a.min(b), a.max(b)];
+
+    let mut segments = HashMap::new();
+    for axis in 0..3 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        for side in 0..2 {
+            let mut corners = [(0, 0), (1, 0), (1, 1), (0, 1)]
+                .map(|(du, dv)| side << axis | du << u | dv << v);
+            if side == 0 {
+                corners.reverse();
+            }
+
+            let mut crossings = Vec::new();
+            for k in 0..4 {
+                let (a, b) = (corners[k], corners[(k + 1) % 4]);
+                if inside(a) != inside(b) {
+                    crossings.push((edge(a, b), inside(b)));
+                }
+            }
+
+            // Ambiguous faces connect their negative corners if the center
+            // is negative, which depends on the face only and so is the same
+            // for both cubes sharing it.
+            let n = crossings.len();
+            let sum: f64 = corners.iter().map(|&c| distances[c]).sum();
+            let shift = if n == 4 && sum < 0.0 { n - 1 } else { 1 };
+            for k in 0..n {
+                let (start, entering) = crossings[k];
+                if entering {
+                    segments.insert(start, crossings[(k + shift) % n].0);
+                }
+            }
+        }
+    }
+
+    // Sorted for the output not to depend on hashing.
+    let mut starts: Vec<[usize; 2]> = segments.keys().copied().collect();
+    starts.sort_unstable();
+    let mut polygons = Vec::new();
+    for start in starts {
+        let mut polygon = Vec::new();
+        let mut edge = start;
+        while let Some(next) = segments.remove(&edge) {
+            polygon.push(edge);
+            edge = next;
+        }
+        if !polygon.is_empty() {
+            polygons.push(polygon);
+        }
+    }
+    polygons
+}
+
+// Reconstructs surface by fusing depth maps of frames into a truncated
+// signed distance field around the cloud and extracting its zero level by
+// marching cubes, which (unlike Poisson) keeps thin structures seen from
+// both sides.
+pub fn reconstruct(
+    params: &Params,
+    point_cloud: &PointCloudParams,
+    scans: &IndexMap<String, fm::Scan>,
+    scan_frames: &[fm::ScanFrame],
+    cloud: &dyn Cloud<f64>,
+    mesh: &mut dyn Mesh<f64>,
+) -> Result<()> {
+    let err = || {
+        let desc = "failed to reconstruct surface by TSDF fusion";
+        Error::new(GeometryError, desc.to_string())
+    };
+
+    let mut views = Vec::with_capacity(scan_frames.len());
+    for frame in scan_frames {
+        let scan = scans.get(&frame.scan).ok_or_else(|| {
+            let desc = format!("frame for unknown scan '{}'", &frame.scan);
+            Error::new(InconsistentState, desc)
+        })?;
+        views.push(DepthView::new(scan, frame, point_cloud)?);
+    }
+
+    let mut field = Field::new(cloud, params).ok_or_else(err)?;
+    field.fuse(&views, params.tsdf_truncation as f64 * field.cell);
+
+    let dims = field.dims;
+    let mut vertices = Vec::new();
+    let mut indices = HashMap::new();
+    let mut faces = Vec::new();
+    for z in 0..dims[2] - 1 {
+        for y in 0..dims[1] - 1 {
+            for x in 0..dims[0] - 1 {
+                let cell = [x, y, z];
+                let mut distances = [0.0; 8];
+                let mut seen = true;
+                for (corner, distance) in distances.iter_mut().enumerate() {
+                    match field.distance(corner_key(cell, corner)) {
+                        Some(d) => *distance = d,
+                        None => seen = false,
+                    }
+                }
+                if !seen {
+                    continue;
+                }
+
+                for polygon in cube_polygons(&distances) {
+                    let polygon: Vec<usize> = polygon
+                        .into_iter()
+                        .map(|[a, b]| {
+                            let (ka, kb) =
+                                (corner_key(cell, a), corner_key(cell, b));
+                            // Edges are shared by cubes around them.
+                            let key = (field.index(ka), b - a);
+                            *indices.entry(key).or_insert_with(|| {
+                                let (da, db) = (distances[a], distances[b]);
+                                let (pa, pb) =
+                                    (field.position(ka), field.position(kb));
+                                let t = da / (da - db);
+                                vertices.push(pa.coords.lerp(&pb.coords, t));
+                                vertices.len() - 1
+                            })
+                        })
+                        .collect();
+                    for k in 1..polygon.len() - 1 {
+                        faces.push([polygon[0], polygon[k], polygon[k + 1]]);
+                    }
+                }
+            }
+        }
+    }
+    if faces.is_empty() {
+        return Err(err());
+    }
+
+    // Area-weighted normals of adjacent faces.
+    let mut normals = vec![Vector3::zeros(); vertices.len()];
+    for face in &faces {
+        let [a, b, c] = face.map(|i| vertices[i]);
+        let normal = (b - a).cross(&(c - a));
+        for &i in face {
+            normals[i] += normal;
+        }
+    }
+
+    for (vertex, normal) in vertices.iter().zip(&normals) {
+        mesh.add_vertex(vertex.as_ref());
+        let normal = normal.try_normalize(f64::EPSILON).unwrap_or_default();
+        mesh.add_normal(normal.as_ref());
+    }
+    for face in &faces {
+        mesh.add_triangle(face);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_cloud::build_point_cloud;
+    use base::fm::scan_frame::DepthConfidence;
+
+    const RADIUS: f64 = 0.25;
+    const ANGLE_OF_VIEW: f64 = 0.8;
+    const DEPTH_SIZE: (u32, u32) = (64, 48);
+
+    struct TestCloud(Vec<Point3>);
+
+    impl Cloud<f64> for TestCloud {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn point(&self, index: usize) -> [f64; 3] {
+            self.0[index].coords.into()
+        }
+    }
+
+    #[derive(Default)]
+    struct TestMesh {
+        vertices: Vec<Point3>,
+        normals: Vec<Vector3>,
+        faces: Vec<[usize; 3]>,
+    }
+
+    impl Mesh<f64> for TestMesh {
+        fn add_vertex(&mut self, vertex: &[f64; 3]) {
+            self.vertices.push(Point3::from(*vertex));
+        }
+
+        fn add_normal(&mut self, normal: &[f64; 3]) {
+            self.normals.push(Vector3::from(*normal));
+        }
+
+        fn add_triangle(&mut self, triangle: &[usize; 3]) {
+            self.faces.push(*triangle);
+        }
+    }
+
+    // Turntable scan of a sphere at the origin, the camera looking at it
+    // horizontally from -Y.
+    fn create_sphere_scan(
+        num_frames: i64,
+    ) -> (IndexMap<String, fm::Scan>, Vec<fm::ScanFrame>) {
+        let interval = 500_000_000;
+        let scan = fm::Scan {
+            name: "sphere".to_string(),
+            camera_angle_of_view: ANGLE_OF_VIEW as f32,
+            camera_angular_velocity: (2.0 * std::f64::consts::PI
+                / (num_frames * interval) as f64
+                * 1E9) as f32,
+            camera_initial_position: Some(fm::Point3 {
+                x: 0.0,
+                y: -1.0,
+                z: 0.0,
+            }),
+            camera_initial_direction: Some(fm::Point3::default()),
+            depth_width: DEPTH_SIZE.0,
+            depth_height: DEPTH_SIZE.1,
+            sensor_plane_depth: true,
+            ..Default::default()
+        };
+
+        // Rays through pixels for a unit depth, see unproject_depth.
+        let half_width = DEPTH_SIZE.0 as f64 / 2.0;
+        let tan = (ANGLE_OF_VIEW / 2.0).tan();
+        let mut depths = Vec::new();
+        for i in 0..DEPTH_SIZE.1 {
+            for j in 0..DEPTH_SIZE.0 {
+                let u = (j as f64 - half_width) / half_width * tan;
+                let v =
+                    (i as f64 - DEPTH_SIZE.1 as f64 / 2.0) / half_width * tan;
+                let ray = Vector3::new(u, 1.0, -v);
+                let (a, b) = (ray.norm_squared(), -ray.y);
+                let disc = b * b - a * (1.0 - RADIUS * RADIUS);
+                let depth = (disc >= 0.0).then(|| (-b - disc.sqrt()) / a);
+                depths.push(depth.map_or(f32::NAN, |d| d as f32));
+            }
+        }
+
+        let frames = (0..num_frames)
+            .map(|i| fm::ScanFrame {
+                scan: "sphere".to_string(),
+                time: i * interval,
+                depths: depths.clone(),
+                depth_confidences: vec![
+                    DepthConfidence::High as i32;
+                    depths.len()
+                ],
+                ..Default::default()
+            })
+            .collect();
+        (IndexMap::from([(scan.name.clone(), scan)]), frames)
+    }
+
+    fn create_cloud(
+        scans: &IndexMap<String, fm::Scan>,
+        frames: &[fm::ScanFrame],
+        params: &PointCloudParams,
+    ) -> TestCloud {
+        let mut points = Vec::new();
+        for frame in frames {
+            let cloud = build_point_cloud(&scans[0], frame, params).unwrap();
+            points.extend(cloud.into_iter().map(|p| p.0));
+        }
+        TestCloud(points)
+    }
+
+    #[test]
+    fn test_reconstruct_sphere() {
+        let (scans, frames) = create_sphere_scan(12);
+        let point_cloud = PointCloudParams::from_iter(["test"]);
+        let cloud = create_cloud(&scans, &frames, &point_cloud);
+        let params = Params {
+            tsdf_resolution: 32,
+            tsdf_truncation: 3,
+        };
+        let mut mesh = TestMesh::default();
+        reconstruct(&params, &point_cloud, &scans, &frames, &cloud, &mut mesh)
+            .unwrap();
+
+        assert!(mesh.faces.len() > 100);
+        assert_eq!(mesh.vertices.len(), mesh.normals.len());
+        for vertex in &mesh.vertices {
+            let dist = vertex.coords.norm();
+            assert!((dist - RADIUS).abs() < 0.02, "{} is off surface", vertex);
+        }
+
+        // Faces and normals look outwards.
+        for face in &mesh.faces {
+            let [a, b, c] = face.map(|i| mesh.vertices[i]);
+            let normal = (b - a).cross(&(c - a));
+            let center = (a.coords + b.coords + c.coords) / 3.0;
+            assert!(normal.dot(&center) > 0.0);
+        }
+        for (vertex, normal) in mesh.vertices.iter().zip(&mesh.normals) {
+            assert!(normal.dot(&vertex.coords) > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_unseen() {
+        let (scans, frames) = create_sphere_scan(4);
+        let point_cloud = PointCloudParams::from_iter(["test"]);
+        let cloud = create_cloud(&scans, &frames, &point_cloud);
+        let mut mesh = TestMesh::default();
+        let params = Params::default();
+
+        let empty = TestCloud(vec![]);
+        let err = reconstruct(
+            &params,
+            &point_cloud,
+            &scans,
+            &frames,
+            &empty,
+            &mut mesh,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, GeometryError);
+
+        // Nothing is fused without depths.
+        let err =
+            reconstruct(&params, &point_cloud, &scans, &[], &cloud, &mut mesh)
+                .unwrap_err();
+        assert_eq!(err.kind, GeometryError);
+
+        let mut frames = frames;
+        frames[0].depths.pop();
+        let err = reconstruct(
+            &params,
+            &point_cloud,
+            &scans,
+            &frames,
+            &cloud,
+            &mut mesh,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, MalformedData);
+    }
+}
@@ -0,0 +1,113 @@
+use log::warn;
+use structopt::StructOpt;
+
+use crate::scan::ScanValidator;
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Validate scan records of .fm file")]
+pub struct ValidateCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(flatten)]
+    output: cli::FmOutput,
+
+    #[structopt(help = "Write a copy with inconsistent records skipped", long)]
+    fix: bool,
+}
+
+impl ValidateCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+
+        if self.fix {
+            let mut writer = self.output.get()?;
+            validate(reader.as_mut(), Some(writer.as_mut()))?;
+            Ok(())
+        } else {
+            let num = validate(reader.as_mut(), None)?;
+            if num > 0 {
+                let desc = format!("found {} inconsistent records", num);
+                return Err(Error::new(InconsistentState, desc));
+            }
+            Ok(())
+        }
+    }
+}
+
+pub fn validate(
+    reader: &mut dyn fm::Read,
+    mut writer: Option<&mut dyn fm::Write>,
+) -> Result<usize> {
+    let mut validator = ScanValidator::default();
+    let mut num = 0;
+
+    while let Some(raw) = reader.read_raw_record()? {
+        use fm::record::Type::*;
+        let res = match raw.decode()?.r#type {
+            Some(Scan(s)) => validator.check_scan(&s),
+            Some(ScanFrame(f)) => validator.check_frame(&f),
+            _ => Ok(()),
+        };
+
+        match res {
+            Ok(()) => {
+                if let Some(writer) = writer.as_mut() {
+                    writer.write_raw_record(&raw)?;
+                }
+            }
+            Err(err) => {
+                warn!("{}", err);
+                num += 1;
+            }
+        }
+    }
+
+    Ok(num)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::record_variant;
+    use base::util::test::*;
+    use fm::record::Type::*;
+    use fm::Read as _;
+
+    #[test]
+    fn test_validate_fix() {
+        let new_frame = |scan: &str, time| {
+            new_scan_frame_rec(fm::ScanFrame {
+                scan: scan.to_string(),
+                time,
+                ..Default::default()
+            })
+        };
+        let mut reader = create_reader_with_records(&[
+            new_scan_rec(fm::Scan {
+                name: "a".to_string(),
+                ..Default::default()
+            }),
+            new_frame("a", 1),
+            new_frame("a", 1),
+            new_frame("b", 2),
+            new_frame("a", 3),
+        ]);
+
+        let mut writer = create_writer();
+        let num = validate(&mut reader, Some(&mut writer)).unwrap();
+        assert_eq!(num, 2);
+
+        let mut reader = writer_to_reader(writer);
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(Scan, rec).name, "a");
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(ScanFrame, rec).time, 1);
+        let rec = reader.read_record().unwrap().unwrap();
+        assert_eq!(record_variant!(ScanFrame, rec).time, 3);
+        assert!(reader.read_record().unwrap().is_none());
+    }
+}
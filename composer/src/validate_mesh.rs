@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use petgraph::unionfind::UnionFind;
+use structopt::StructOpt;
+
+use crate::bvh::{Aabb, Bvh};
+use crate::mesh::{element_faces, read_elements};
+use crate::point_cloud::{Point3, Vector3};
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+use base::util::cli;
+
+#[derive(StructOpt)]
+#[structopt(about = "Check watertightness of element meshes")]
+pub struct ValidateMeshCommand {
+    #[structopt(flatten)]
+    input: cli::FmInput,
+
+    #[structopt(
+        help = "Element to check (all elements if omitted)",
+        long = "element",
+        number_of_values = 1
+    )]
+    elements: Vec<String>,
+
+    #[structopt(
+        help = "Maximal number of example locations per issue",
+        long,
+        default_value = "3"
+    )]
+    max_examples: usize,
+}
+
+impl ValidateMeshCommand {
+    pub fn run(&self) -> Result<()> {
+        let mut reader = self.input.get()?;
+        let reports =
+            validate_mesh(reader.as_mut(), &self.elements, self.max_examples)?;
+
+        let mut num = 0;
+        for report in &reports {
+            print!("{}", report);
+            num += report.num_issues();
+        }
+
+        if num > 0 {
+            let desc = format!("found {} mesh issues", num);
+            return Err(Error::new(InconsistentState, desc));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Issue {
+    pub count: usize,
+    pub examples: Vec<Point3>,
+}
+
+impl Issue {
+    fn add(&mut self, location: Point3, max_examples: usize) {
+        self.count += 1;
+        if self.examples.len() < max_examples {
+            self.examples.push(location);
+        }
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.count)?;
+        for (i, p) in self.examples.iter().enumerate() {
+            let prefix = if i == 0 { " at" } else { "," };
+            write!(f, "{} ({:.4}, {:.4}, {:.4})", prefix, p.x, p.y, p.z)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MeshReport {
+    pub element: String,
+    pub num_vertices: usize,
+    pub num_faces: usize,
+    pub boundary_loops: Issue,
+    pub non_manifold_edges: Issue,
+    pub misoriented_edges: Issue,
+    pub flipped_faces: Issue,
+    pub self_intersections: Issue,
+}
+
+impl MeshReport {
+    pub fn num_issues(&self) -> usize {
+        self.boundary_loops.count
+            + self.non_manifold_edges.count
+            + self.misoriented_edges.count
+            + self.flipped_faces.count
+            + self.self_intersections.count
+    }
+}
+
+impl Display for MeshReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(
+            f,
+            "element '{}': {} vertices, {} faces",
+            self.element, self.num_vertices, self.num_faces
+        )?;
+        writeln!(f, "  boundary loops: {}", self.boundary_loops)?;
+        writeln!(f, "  non-manifold edges: {}", self.non_manifold_edges)?;
+        writeln!(f, "  misoriented edges: {}", self.misoriented_edges)?;
+        writeln!(f, "  flipped faces: {}", self.flipped_faces)?;
+        writeln!(f, "  self-intersections: {}", self.self_intersections)
+    }
+}
+
+// The first state of each element is checked.
+pub fn validate_mesh(
+    reader: &mut dyn fm::Read,
+    elements: &[String],
+    max_examples: usize,
+) -> Result<Vec<MeshReport>> {
+    read_elements(reader, elements)?
+        .iter()
+        .map(|(view, state)| check_element(view, state, max_examples))
+        .collect()
+}
+
+fn to_point(p: &fm::Point3) -> Point3 {
+    Point3::new(p.x as f64, p.y as f64, p.z as f64)
+}
+
+fn check_element(
+    view: &fm::ElementView,
+    state: &fm::ElementViewState,
+    max_examples: usize,
+) -> Result<MeshReport> {
+    let vertices: Vec<_> = state.vertices.iter().map(to_point).collect();
+    let faces = element_faces(view, vertices.len())?;
+
+    let mut report = MeshReport {
+        element: view.element.clone(),
+        num_vertices: vertices.len(),
+        num_faces: faces.len(),
+        ..Default::default()
+    };
+
+    check_edges(&vertices, &faces, &mut report, max_examples);
+    check_normals(view, state, &vertices, &faces, &mut report, max_examples);
+    check_self_intersections(&vertices, &faces, &mut report, max_examples);
+    Ok(report)
+}
+
+fn check_edges(
+    vertices: &[Point3],
+    faces: &[[usize; 3]],
+    report: &mut MeshReport,
+    max_examples: usize,
+) {
+    // Directed edge counts per undirected edge.
+    let mut edges = HashMap::<(usize, usize), (usize, usize)>::new();
+    for face in faces {
+        for (a, b) in
+            [(face[0], face[1]), (face[1], face[2]), (face[2], face[0])]
+        {
+            let counts = edges.entry((a.min(b), a.max(b))).or_default();
+            if a < b {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    let mut loops = UnionFind::new(vertices.len());
+    let mut boundary = Vec::new();
+    let mut sorted: Vec<_> = edges.into_iter().collect();
+    sorted.sort_unstable_by_key(|e| e.0);
+
+    for ((a, b), (forward, backward)) in sorted {
+        let middle = nalgebra::center(&vertices[a], &vertices[b]);
+        match forward + backward {
+            1 => {
+                loops.union(a, b);
+                boundary.push(a);
+            }
+            2 if forward != 1 => {
+                report.misoriented_edges.add(middle, max_examples)
+            }
+            2 => (),
+            _ => report.non_manifold_edges.add(middle, max_examples),
+        }
+    }
+
+    let mut roots = HashMap::new();
+    for v in boundary {
+        roots.entry(loops.find(v)).or_insert(v);
+    }
+    let mut starts: Vec<_> = roots.into_values().collect();
+    starts.sort_unstable();
+    for v in starts {
+        report.boundary_loops.add(vertices[v], max_examples);
+    }
+}
+
+fn face_normal(vertices: &[Point3], face: &[usize; 3]) -> Vector3 {
+    let [a, b, c] = face.map(|v| vertices[v]);
+    (b - a).cross(&(c - a))
+}
+
+// A face is flipped if its winding disagrees with its vertex normals.
+fn check_normals(
+    view: &fm::ElementView,
+    state: &fm::ElementViewState,
+    vertices: &[Point3],
+    faces: &[[usize; 3]],
+    report: &mut MeshReport,
+    max_examples: usize,
+) {
+    for (face, indices) in view.faces.iter().zip(faces) {
+        let normals = [face.normal1, face.normal2, face.normal3];
+        if normals
+            .iter()
+            .any(|&n| n == 0 || n as usize > state.normals.len())
+        {
+            continue;
+        }
+
+        let normal = normals
+            .iter()
+            .map(|&n| to_point(&state.normals[n as usize - 1]).coords)
+            .sum::<Vector3>();
+        if face_normal(vertices, indices).dot(&normal) < 0.0 {
+            let [a, b, c] = indices.map(|v| vertices[v].coords);
+            let center = Point3::from((a + b + c) / 3.0);
+            report.flipped_faces.add(center, max_examples);
+        }
+    }
+}
+
+fn segment_intersects_triangle(
+    p: &Point3,
+    q: &Point3,
+    triangle: &[Point3; 3],
+) -> Option<Point3> {
+    const EPSILON: f64 = 1e-12;
+
+    let dir = q - p;
+    let (e1, e2) = (triangle[1] - triangle[0], triangle[2] - triangle[0]);
+    let h = dir.cross(&e2);
+    let det = e1.dot(&h);
+    if det.abs() < EPSILON {
+        return None; // Parallel or coplanar.
+    }
+
+    let s = p - triangle[0];
+    let u = s.dot(&h) / det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let r = s.cross(&e1);
+    let v = dir.dot(&r) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = e2.dot(&r) / det;
+    (0.0..=1.0).contains(&t).then(|| p + dir * t)
+}
+
+// Faces sharing vertices are skipped and coplanar overlaps aren't detected.
+fn check_self_intersections(
+    vertices: &[Point3],
+    faces: &[[usize; 3]],
+    report: &mut MeshReport,
+    max_examples: usize,
+) {
+    let triangles: Vec<_> =
+        faces.iter().map(|f| f.map(|v| vertices[v])).collect();
+    let aabbs: Vec<_> =
+        triangles.iter().map(|t| Aabb::from_points(t)).collect();
+    let bvh = Bvh::new(&aabbs);
+
+    for (i, face) in faces.iter().enumerate() {
+        let mut location = None;
+        bvh.for_each_intersecting(&aabbs[i], |j| {
+            if j <= i
+                || location.is_some()
+                || face.iter().any(|v| faces[j].contains(v))
+                || !aabbs[i].intersects(&aabbs[j])
+            {
+                return;
+            }
+
+            let (a, b) = (&triangles[i], &triangles[j]);
+            location = (0..3).find_map(|k| {
+                segment_intersects_triangle(&a[k], &a[(k + 1) % 3], b).or_else(
+                    || segment_intersects_triangle(&b[k], &b[(k + 1) % 3], a),
+                )
+            });
+        });
+
+        if let Some(location) = location {
+            report.self_intersections.add(location, max_examples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base::util::test::*;
+
+    fn new_element_recs(
+        element: &str,
+        vertices: Vec<fm::Point3>,
+        normals: Vec<fm::Point3>,
+        faces: &[[u32; 3]],
+    ) -> [fm::Record; 2] {
+        let has_normals = !normals.is_empty();
+        let faces = faces
+            .iter()
+            .map(|f| fm::element_view::Face {
+                vertex1: f[0],
+                vertex2: f[1],
+                vertex3: f[2],
+                normal1: if has_normals { f[0] } else { 0 },
+                normal2: if has_normals { f[1] } else { 0 },
+                normal3: if has_normals { f[2] } else { 0 },
+                ..Default::default()
+            })
+            .collect();
+
+        [
+            new_element_view_rec(fm::ElementView {
+                element: element.to_string(),
+                faces,
+                ..Default::default()
+            }),
+            new_element_view_state_rec(fm::ElementViewState {
+                element: element.to_string(),
+                vertices,
+                normals,
+                ..Default::default()
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_validate_mesh() {
+        let tetrahedron = vec![
+            new_point3(0.0, 0.0, 0.0),
+            new_point3(1.0, 0.0, 0.0),
+            new_point3(0.0, 1.0, 0.0),
+            new_point3(0.0, 0.0, 1.0),
+        ];
+        let outward: Vec<_> = tetrahedron
+            .iter()
+            .map(|p| new_point3(p.x - 0.25, p.y - 0.25, p.z - 0.25))
+            .collect();
+        let inward: Vec<_> = outward
+            .iter()
+            .map(|p| new_point3(-p.x, -p.y, -p.z))
+            .collect();
+
+        let crossing = vec![
+            new_point3(0.0, 0.0, 0.0),
+            new_point3(2.0, 0.0, 0.0),
+            new_point3(1.0, 2.0, 0.0),
+            new_point3(1.0, 1.0, -1.0),
+            new_point3(1.0, 1.0, 1.0),
+            new_point3(1.0, 3.0, 0.0),
+        ];
+
+        let faces = [[1, 3, 2], [1, 2, 4], [2, 3, 4], [3, 1, 4]];
+        let mut records = Vec::new();
+        records.extend(new_element_recs(
+            "closed",
+            tetrahedron.clone(),
+            outward,
+            &faces,
+        ));
+        records.extend(new_element_recs(
+            "open",
+            tetrahedron,
+            inward,
+            &faces[1..],
+        ));
+        records.extend(new_element_recs(
+            "crossing",
+            crossing,
+            vec![],
+            &[[1, 2, 3], [4, 5, 6]],
+        ));
+        let mut reader = create_reader_with_records(&records);
+
+        let reports = validate_mesh(&mut reader, &[], 3).unwrap();
+        assert_eq!(reports.len(), 3);
+
+        assert_eq!(reports[0].element, "closed");
+        assert_eq!(reports[0].num_issues(), 0);
+
+        assert_eq!(reports[1].element, "open");
+        assert_eq!(reports[1].boundary_loops.count, 1);
+        assert_eq!(reports[1].flipped_faces.count, 3);
+        assert_eq!(reports[1].non_manifold_edges.count, 0);
+        assert_eq!(reports[1].misoriented_edges.count, 0);
+
+        assert_eq!(reports[2].element, "crossing");
+        assert_eq!(reports[2].self_intersections.count, 1);
+        let location = reports[2].self_intersections.examples[0];
+        assert!((location - Point3::new(1.0, 1.0, 0.0)).norm() < 1e-9);
+        assert_eq!(reports[2].boundary_loops.count, 2);
+    }
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use base::fm::{self, Read as _};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = match fm::Reader::new(data) {
+        Ok(reader) => reader,
+        Err(_) => return,
+    };
+    while let Ok(Some(_)) = reader.read_record() {}
+});
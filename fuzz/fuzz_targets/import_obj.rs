@@ -0,0 +1,41 @@
+#![no_main]
+
+use std::path::Path;
+
+use base::defs::{Error, ErrorKind::*};
+use base::fm;
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../composer/src/import_from_obj.rs"]
+mod import_from_obj;
+
+// The input is OBJ data optionally followed by zero byte and MTL data,
+// other referenced files are empty.
+fuzz_target!(|data: &[u8]| {
+    let (mut obj, mtl) = match data.iter().position(|&b| b == 0) {
+        Some(pos) => (&data[..pos], &data[pos + 1..]),
+        None => (data, &[][..]),
+    };
+
+    let read_file = |path: &Path| {
+        if path.extension().is_some_and(|e| e == "mtl") {
+            Ok(mtl.to_vec())
+        } else if path.starts_with("obj") {
+            Ok(Vec::new())
+        } else {
+            let desc = "unexpected path".to_string();
+            Err(Error::new(IoError, desc))
+        }
+    };
+
+    let params = fm::WriterParams::default();
+    let mut writer = fm::Writer::new(Vec::new(), &params).unwrap();
+    let _ = import_from_obj::import_obj(
+        &mut obj,
+        &mut writer,
+        read_file,
+        "obj".as_ref(),
+        "element",
+    );
+});
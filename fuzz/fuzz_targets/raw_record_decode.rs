@@ -0,0 +1,9 @@
+#![no_main]
+
+use base::fm;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let limits = fm::ReaderLimits::default();
+    let _ = fm::RawRecord::with_limits(data, limits).decode();
+});
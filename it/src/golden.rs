@@ -0,0 +1,229 @@
+// Comparison of test outputs with golden files in it/golden/<test>, which
+// are overwritten by the outputs instead if UPDATE_GOLDEN is set.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::workspace_dir;
+
+pub struct Tolerances {
+    // Maximum absolute difference of numbers (e.g. coordinates).
+    pub number: f64,
+    // Maximum mean absolute difference of image channel values.
+    pub pixel: f64,
+}
+
+pub fn check_golden(test: &str, dir: &Path, tolerances: &Tolerances) {
+    let golden_dir = workspace_dir().join("it").join("golden").join(test);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        if golden_dir.exists() {
+            fs::remove_dir_all(&golden_dir).unwrap();
+        }
+        fs::create_dir_all(&golden_dir).unwrap();
+        for name in file_names(dir) {
+            fs::copy(dir.join(&name), golden_dir.join(&name)).unwrap();
+        }
+        return;
+    }
+
+    assert!(
+        golden_dir.exists(),
+        "no golden files of '{}' (run with UPDATE_GOLDEN=1 to create them)",
+        test
+    );
+    let names = file_names(dir);
+    assert_eq!(
+        names,
+        file_names(&golden_dir),
+        "outputs of '{}' differ from golden files",
+        test
+    );
+    for name in names {
+        let (actual, golden) = (dir.join(&name), golden_dir.join(&name));
+        let ext = actual.extension().and_then(|e| e.to_str());
+        match ext.unwrap_or_default() {
+            "obj" => compare_obj(&actual, &golden, tolerances),
+            "gltf" => compare_gltf(&actual, &golden, tolerances),
+            "bin" => {} // Compared along with .gltf files.
+            "jpg" | "png" => {
+                let (a, b) = (fs::read(&actual).unwrap(), fs::read(&golden));
+                compare_images(&name, &a, &b.unwrap(), tolerances);
+            }
+            _ => assert!(
+                fs::read(&actual).unwrap() == fs::read(&golden).unwrap(),
+                "'{}' differs from golden one",
+                name
+            ),
+        }
+    }
+}
+
+fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+fn approx_eq(a: f64, b: f64, tolerances: &Tolerances) -> bool {
+    (a - b).abs() <= tolerances.number
+}
+
+// Compares vertex data with tolerance, the rest of lines as is.
+fn compare_obj(actual: &Path, golden: &Path, tolerances: &Tolerances) {
+    let actual_text = fs::read_to_string(actual).unwrap();
+    let golden_text = fs::read_to_string(golden).unwrap();
+    let (actual_lines, golden_lines): (Vec<_>, Vec<_>) =
+        (actual_text.lines().collect(), golden_text.lines().collect());
+    assert_eq!(
+        actual_lines.len(),
+        golden_lines.len(),
+        "'{}' has {} lines instead of {}",
+        actual.display(),
+        actual_lines.len(),
+        golden_lines.len()
+    );
+
+    for (i, (a, g)) in actual_lines.iter().zip(&golden_lines).enumerate() {
+        let (a_tokens, g_tokens): (Vec<_>, Vec<_>) = (
+            a.split_whitespace().collect(),
+            g.split_whitespace().collect(),
+        );
+        let numeric = matches!(g_tokens.first(), Some(&("v" | "vt" | "vn")));
+        let same = if numeric && a_tokens.len() == g_tokens.len() {
+            a_tokens[0] == g_tokens[0]
+                && a_tokens[1..].iter().zip(&g_tokens[1..]).all(|(a, g)| {
+                    let (a, g) = (a.parse().unwrap(), g.parse().unwrap());
+                    approx_eq(a, g, tolerances)
+                })
+        } else {
+            a == g
+        };
+        assert!(
+            same,
+            "{}:{}: '{}' instead of '{}'",
+            actual.display(),
+            i + 1,
+            a,
+            g
+        );
+    }
+}
+
+// Compares JSON with tolerance, then accessor data and images of buffers.
+fn compare_gltf(actual: &Path, golden: &Path, tolerances: &Tolerances) {
+    let read_json = |path: &Path| -> Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    };
+    let (json, golden_json) = (read_json(actual), read_json(golden));
+    compare_json("", &json, &golden_json, tolerances);
+
+    let read_bin = |path: &Path| {
+        let uri = json["buffers"][0]["uri"].as_str().unwrap();
+        fs::read(path.with_file_name(uri)).unwrap()
+    };
+    let (bin, golden_bin) = (read_bin(actual), read_bin(golden));
+    let view_data = |bin: &[u8], view: &Value, offset: u64| {
+        let start =
+            (view["byteOffset"].as_u64().unwrap_or(0) + offset) as usize;
+        let len = view["byteLength"].as_u64().unwrap() as usize;
+        bin[start..start + len].to_vec()
+    };
+
+    let views = json["bufferViews"].as_array().unwrap();
+    let accessors = json["accessors"].as_array().cloned().unwrap_or_default();
+    for (i, accessor) in accessors.iter().enumerate() {
+        let view = &views[accessor["bufferView"].as_u64().unwrap() as usize];
+        let offset = accessor["byteOffset"].as_u64().unwrap_or(0);
+        let (data, golden_data) = (
+            view_data(&bin, view, offset),
+            view_data(&golden_bin, view, offset),
+        );
+        let num_components = match accessor["type"].as_str().unwrap() {
+            "SCALAR" => 1,
+            "VEC2" => 2,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            "MAT4" => 16,
+            t => panic!("unsupported accessor type '{}'", t),
+        };
+        let count = accessor["count"].as_u64().unwrap() as usize;
+        let num_values = count * num_components;
+        let same = match accessor["componentType"].as_u64().unwrap() {
+            // Floats.
+            5126 => (0..num_values).all(|k| {
+                let value = |data: &[u8]| {
+                    let bytes = data[k * 4..k * 4 + 4].try_into().unwrap();
+                    f32::from_le_bytes(bytes) as f64
+                };
+                approx_eq(value(&data), value(&golden_data), tolerances)
+            }),
+            5121 => data[..num_values] == golden_data[..num_values],
+            5123 => data[..num_values * 2] == golden_data[..num_values * 2],
+            5125 => data[..num_values * 4] == golden_data[..num_values * 4],
+            t => panic!("unsupported accessor component type {}", t),
+        };
+        assert!(same, "accessor {} of '{}' differs", i, actual.display());
+    }
+
+    let images = json["images"].as_array().cloned().unwrap_or_default();
+    for (i, image) in images.iter().enumerate() {
+        let view = &views[image["bufferView"].as_u64().unwrap() as usize];
+        let name = format!("image {} of '{}'", i, actual.display());
+        let (data, golden_data) =
+            (view_data(&bin, view, 0), view_data(&golden_bin, view, 0));
+        compare_images(&name, &data, &golden_data, tolerances);
+    }
+}
+
+fn compare_json(path: &str, a: &Value, b: &Value, tolerances: &Tolerances) {
+    let same = match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            approx_eq(a.as_f64().unwrap(), b.as_f64().unwrap(), tolerances)
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                compare_json(&format!("{}/{}", path, i), a, b, tolerances);
+            }
+            true
+        }
+        (Value::Object(a), Value::Object(b)) if a.keys().eq(b.keys()) => {
+            for (key, value) in a {
+                let path = format!("{}/{}", path, key);
+                compare_json(&path, value, &b[key], tolerances);
+            }
+            true
+        }
+        (a, b) => a == b,
+    };
+    assert!(same, "glTF value at '{}' is {} instead of {}", path, a, b);
+}
+
+fn compare_images(name: &str, a: &[u8], b: &[u8], tolerances: &Tolerances) {
+    let a = image::load_from_memory(a).unwrap().to_rgb8();
+    let b = image::load_from_memory(b).unwrap().to_rgb8();
+    assert_eq!(
+        a.dimensions(),
+        b.dimensions(),
+        "{} has different dimensions",
+        name
+    );
+    let sum: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum();
+    let mean = sum as f64 / a.as_raw().len().max(1) as f64;
+    assert!(
+        mean <= tolerances.pixel,
+        "{} differs by {:.2} on average",
+        name,
+        mean
+    );
+}
@@ -0,0 +1,64 @@
+// Harness of end-to-end tests running the composer CLI on synthetic inputs
+// and comparing its outputs with golden files (see README.md).
+
+pub mod golden;
+pub mod scan;
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub fn workspace_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_owned()
+}
+
+// Composer binary given by COMPOSER or the one of `cargo build -p composer`.
+pub fn composer_path() -> PathBuf {
+    if let Some(path) = env::var_os("COMPOSER") {
+        return path.into();
+    }
+    let target = match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => workspace_dir().join("target"),
+    };
+    let name = format!("composer{}", env::consts::EXE_SUFFIX);
+    let path = target.join("debug").join(name);
+    assert!(
+        path.exists(),
+        "no composer at '{}' (run 'cargo build -p composer' or set COMPOSER)",
+        path.display()
+    );
+    path
+}
+
+// Runs composer with given arguments, panics on its failure.
+pub fn run_composer<I, S>(args: I)
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new(composer_path());
+    command.args(args);
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// Empty directory for outputs of given test.
+pub fn output_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join("tdscan-it").join(test);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,131 @@
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+use image::{ImageOutputFormat, Rgb, RgbImage};
+
+use base::fm;
+use fm::Write as _;
+
+pub const SPHERE_RADIUS: f64 = 0.25;
+
+const CAMERA_DISTANCE: f64 = 1.0;
+const ANGLE_OF_VIEW: f64 = 0.8;
+const NUM_FRAMES: i64 = 12;
+const FRAME_INTERVAL: fm::Time = fm::Time(500_000_000);
+const DEPTH_SIZE: (u32, u32) = (64, 48);
+const IMAGE_SIZE: (u32, u32) = (128, 96);
+const BACKGROUND: Rgb<u8> = Rgb([40, 40, 40]);
+
+// Writes a turntable scan of a sphere at the origin with horizontal color
+// bands, the camera looking at it horizontally from -Y.
+pub fn write_sphere_scan(path: &Path) {
+    let scan = fm::Scan {
+        name: "sphere".to_string(),
+        camera_angle_of_view: ANGLE_OF_VIEW as f32,
+        camera_angular_velocity: (2.0 * PI
+            / (NUM_FRAMES * FRAME_INTERVAL.0) as f64
+            * fm::NANOS_PER_SEC as f64) as f32,
+        camera_initial_position: Some(fm::Point3 {
+            x: 0.0,
+            y: -CAMERA_DISTANCE as f32,
+            z: 0.0,
+        }),
+        camera_initial_direction: Some(fm::Point3::default()),
+        image_width: IMAGE_SIZE.0,
+        image_height: IMAGE_SIZE.1,
+        depth_width: DEPTH_SIZE.0,
+        depth_height: DEPTH_SIZE.1,
+        sensor_plane_depth: true,
+        ..Default::default()
+    };
+
+    let file = File::create(path).unwrap();
+    let mut writer =
+        fm::Writer::new(file, &fm::WriterParams::default()).unwrap();
+    writer
+        .write_record(&fm::Record {
+            r#type: Some(fm::record::Type::Scan(scan)),
+        })
+        .unwrap();
+
+    // The sphere looks the same from all angles.
+    let (image, depths) = (render_image(), render_depths());
+    let num_depths = depths.len();
+    for i in 0..NUM_FRAMES {
+        let frame = fm::ScanFrame {
+            scan: "sphere".to_string(),
+            time: i * FRAME_INTERVAL.0,
+            image: Some(image.clone()),
+            depths: depths.clone(),
+            depth_confidences: vec![
+                fm::scan_frame::DepthConfidence::High as i32;
+                num_depths
+            ],
+            ..Default::default()
+        };
+        writer
+            .write_record(&fm::Record {
+                r#type: Some(fm::record::Type::ScanFrame(frame)),
+            })
+            .unwrap();
+    }
+    writer.into_inner().map_err(|(_, err)| err).unwrap();
+}
+
+// Camera ray through pixel center (for a unit distance from the sensor
+// plane) in world coordinates, see composer's unproject_depth.
+fn ray(size: (u32, u32), (i, j): (u32, u32)) -> [f64; 3] {
+    let half_width = size.0 as f64 / 2.0;
+    let tan = (ANGLE_OF_VIEW / 2.0).tan();
+    let u = (j as f64 - half_width) / half_width * tan;
+    let v = (i as f64 - size.1 as f64 / 2.0) / half_width * tan;
+    [u, 1.0, -v]
+}
+
+// Distance from the sensor plane to the sphere along the ray.
+fn hit_depth(ray: [f64; 3]) -> Option<f64> {
+    let a = ray.iter().map(|c| c * c).sum::<f64>();
+    let b = -CAMERA_DISTANCE * ray[1];
+    let c = CAMERA_DISTANCE * CAMERA_DISTANCE - SPHERE_RADIUS * SPHERE_RADIUS;
+    let disc = b * b - a * c;
+    (disc >= 0.0).then(|| (-b - disc.sqrt()) / a)
+}
+
+fn render_depths() -> Vec<f32> {
+    let mut depths = Vec::new();
+    for i in 0..DEPTH_SIZE.1 {
+        for j in 0..DEPTH_SIZE.0 {
+            let depth = hit_depth(ray(DEPTH_SIZE, (i, j)));
+            depths.push(depth.map_or(f32::NAN, |d| d as f32));
+        }
+    }
+    depths
+}
+
+// Colors depend on heights only, so that frames agree whatever the angle.
+fn render_image() -> fm::Image {
+    let image = RgbImage::from_fn(IMAGE_SIZE.0, IMAGE_SIZE.1, |j, i| {
+        let ray = ray(IMAGE_SIZE, (i, j));
+        match hit_depth(ray) {
+            Some(depth) => {
+                let height = ray[2] * depth / SPHERE_RADIUS;
+                let band = ((height + 1.0) * 3.0).floor() as usize;
+                let shade = (160.0 + 60.0 * height) as u8;
+                [Rgb([shade, 60, 40]), Rgb([40, shade, 200])][band % 2]
+            }
+            None => BACKGROUND,
+        }
+    });
+
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+        .unwrap();
+    fm::Image {
+        r#type: fm::image::Type::Png as i32,
+        data,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,50 @@
+use it::golden::{check_golden, Tolerances};
+use it::scan::write_sphere_scan;
+use it::{output_dir, run_composer};
+
+const TOLERANCES: Tolerances = Tolerances {
+    number: 1e-4,
+    pixel: 2.0,
+};
+
+// Synthetic scan → build-view → export-to-obj and export-to-gltf.
+#[test]
+fn test_sphere_pipeline() {
+    let work_dir = output_dir("sphere_pipeline");
+    let scan = work_dir.join("scan.fm");
+    write_sphere_scan(&scan);
+
+    let model = work_dir.join("model.fm");
+    run_composer([
+        "build-view".as_ref(),
+        scan.as_os_str(),
+        "-o".as_ref(),
+        model.as_os_str(),
+        "--element=sphere".as_ref(),
+        "--reconstruction=dc".as_ref(),
+        "--dc-resolution=32".as_ref(),
+        "--decimate-ratio=0.1".as_ref(),
+        "--image-resolution=256".as_ref(),
+        "--texture-image-type=png".as_ref(),
+        "--threads=1".as_ref(),
+    ]);
+
+    let out_dir = work_dir.join("out");
+    std::fs::create_dir(&out_dir).unwrap();
+    let obj = out_dir.join("sphere.obj");
+    run_composer([
+        "export-to-obj".as_ref(),
+        model.as_os_str(),
+        "-o".as_ref(),
+        obj.as_os_str(),
+    ]);
+    let gltf = out_dir.join("sphere.gltf");
+    run_composer([
+        "export-to-gltf".as_ref(),
+        model.as_os_str(),
+        "-o".as_ref(),
+        gltf.as_os_str(),
+    ]);
+
+    check_golden("sphere_pipeline", &out_dir, &TOLERANCES);
+}
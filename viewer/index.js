@@ -10,6 +10,9 @@ document.addEventListener('DOMContentLoaded', async event => {
   canvas.setAttribute('width', doc.clientWidth);
 
   let viewer = fmViewer.Viewer.create(canvas);
+  viewer.events.addEventListener('error', event => {
+    console.error(event.detail);
+  });
 
   let resp = await fetch('./pkg/model.fm');
   if (!resp.ok) {
@@ -0,0 +1,207 @@
+use std::cell::Cell;
+use std::mem::size_of;
+
+use js_sys::Float32Array;
+use web_sys::{
+    WebGlBuffer, WebGlFramebuffer, WebGlProgram, WebGlRenderingContext,
+    WebGlTexture,
+};
+
+use crate::defs::IntoResult;
+use crate::util::webgl;
+use base::defs::{Error, ErrorKind::*, Result};
+
+type Gl = WebGlRenderingContext;
+
+// Two triangles covering the viewport.
+const QUAD: [f32; 12] = [
+    -1.0, -1.0, 1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, 1.0,
+];
+
+// Screen-space ambient occlusion: the scene is rendered into color and depth
+// textures, which are then drawn on the canvas darkening creases and
+// cavities found by depth differences.
+pub struct AmbientOcclusion {
+    color: WebGlTexture,
+    depth: WebGlTexture,
+    framebuffer: WebGlFramebuffer,
+    program: WebGlProgram,
+    quad: WebGlBuffer,
+    size: Cell<(i32, i32)>,
+}
+
+impl AmbientOcclusion {
+    // Leaves the quad buffer bound to ARRAY_BUFFER and changes the texture
+    // of unit 0, like begin() and finish() do.
+    pub fn create(context: &Gl) -> Result<Self> {
+        let ext = context.get_extension("WEBGL_depth_texture").into_result()?;
+        if ext.is_none() {
+            let desc = "ambient occlusion requires WebGL depth textures";
+            return Err(Error::new(UnsupportedFeature, desc.to_string()));
+        }
+
+        let vert_shader = webgl::compile_shader(
+            context,
+            Gl::VERTEX_SHADER,
+            include_str!("shader/ssao_vert.glsl"),
+        )?;
+        let frag_shader = webgl::compile_shader(
+            context,
+            Gl::FRAGMENT_SHADER,
+            include_str!("shader/ssao_frag.glsl"),
+        )?;
+        let program = webgl::link_program(context, &vert_shader, &frag_shader)?;
+
+        let quad = context.create_buffer().unwrap();
+        context.bind_buffer(Gl::ARRAY_BUFFER, Some(&quad));
+        context.buffer_data_with_array_buffer_view(
+            Gl::ARRAY_BUFFER,
+            &Float32Array::from(&QUAD[..]),
+            Gl::STATIC_DRAW,
+        );
+
+        context.active_texture(Gl::TEXTURE0);
+        Ok(Self {
+            color: create_texture(context),
+            depth: create_texture(context),
+            framebuffer: context.create_framebuffer().unwrap(),
+            program,
+            quad,
+            size: Cell::new((0, 0)),
+        })
+    }
+
+    // Directs rendering into the textures, resized to the canvas.
+    pub fn begin(&self, context: &Gl) -> Result<()> {
+        let size = (
+            context.drawing_buffer_width(),
+            context.drawing_buffer_height(),
+        );
+        context.bind_framebuffer(Gl::FRAMEBUFFER, Some(&self.framebuffer));
+        if size != self.size.get() {
+            self.resize(context, size)?;
+        }
+
+        context.clear_color(0.0, 0.0, 0.0, 0.0);
+        context.clear(Gl::COLOR_BUFFER_BIT | Gl::DEPTH_BUFFER_BIT);
+        Ok(())
+    }
+
+    // Draws the rendered scene on the canvas. Changes the program, array
+    // buffer and textures of units 0 and 1.
+    pub fn finish(
+        &self,
+        context: &Gl,
+        near_plane: f32,
+        far_plane: f32,
+    ) -> Result<()> {
+        context.bind_framebuffer(Gl::FRAMEBUFFER, None);
+        context.use_program(Some(&self.program));
+
+        context.active_texture(Gl::TEXTURE0);
+        context.bind_texture(Gl::TEXTURE_2D, Some(&self.color));
+        context.active_texture(Gl::TEXTURE1);
+        context.bind_texture(Gl::TEXTURE_2D, Some(&self.depth));
+
+        let (width, height) = self.size.get();
+        let uniforms = [
+            ("aspect", width as f32 / height.max(1) as f32),
+            ("near_plane", near_plane),
+            ("far_plane", far_plane),
+        ];
+        for (name, value) in uniforms {
+            let location =
+                webgl::get_uniform_location(context, &self.program, name)?;
+            context.uniform1f(Some(&location), value);
+        }
+        for (name, unit) in [("color", 0), ("depth", 1)] {
+            let location =
+                webgl::get_uniform_location(context, &self.program, name)?;
+            context.uniform1i(Some(&location), unit);
+        }
+
+        context.bind_buffer(Gl::ARRAY_BUFFER, Some(&self.quad));
+        let stride = 2 * size_of::<f32>();
+        webgl::define_attribute::<f32>(
+            context,
+            &self.program,
+            "position",
+            stride,
+            stride,
+            0,
+        )?;
+
+        context.disable(Gl::DEPTH_TEST);
+        context.draw_arrays(Gl::TRIANGLES, 0, (QUAD.len() / 2) as i32);
+        context.enable(Gl::DEPTH_TEST);
+
+        let location = context.get_attrib_location(&self.program, "position");
+        context.disable_vertex_attrib_array(location as u32);
+        Ok(())
+    }
+
+    fn resize(&self, context: &Gl, (width, height): (i32, i32)) -> Result<()> {
+        context.active_texture(Gl::TEXTURE0);
+        let formats = [
+            (
+                &self.color,
+                Gl::RGBA,
+                Gl::UNSIGNED_BYTE,
+                Gl::COLOR_ATTACHMENT0,
+            ),
+            (
+                &self.depth,
+                Gl::DEPTH_COMPONENT,
+                Gl::UNSIGNED_SHORT,
+                Gl::DEPTH_ATTACHMENT,
+            ),
+        ];
+        for (texture, format, r#type, attachment) in formats {
+            context.bind_texture(Gl::TEXTURE_2D, Some(texture));
+            context
+                .tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                    Gl::TEXTURE_2D,
+                    0,
+                    format as i32,
+                    width,
+                    height,
+                    0,
+                    format,
+                    r#type,
+                    None,
+                )
+                .into_result()?;
+            context.framebuffer_texture_2d(
+                Gl::FRAMEBUFFER,
+                attachment,
+                Gl::TEXTURE_2D,
+                Some(texture),
+                0,
+            );
+        }
+
+        let status = context.check_framebuffer_status(Gl::FRAMEBUFFER);
+        if status != Gl::FRAMEBUFFER_COMPLETE {
+            let desc = format!("incomplete WebGL framebuffer ({:#x})", status);
+            return Err(Error::new(WebGlError, desc));
+        }
+
+        self.size.set((width, height));
+        Ok(())
+    }
+}
+
+fn create_texture(context: &Gl) -> WebGlTexture {
+    let texture = context.create_texture().unwrap();
+    context.bind_texture(Gl::TEXTURE_2D, Some(&texture));
+    let params = [
+        (Gl::TEXTURE_WRAP_S, Gl::CLAMP_TO_EDGE),
+        (Gl::TEXTURE_WRAP_T, Gl::CLAMP_TO_EDGE),
+        (Gl::TEXTURE_MIN_FILTER, Gl::NEAREST),
+        (Gl::TEXTURE_MAG_FILTER, Gl::NEAREST),
+    ];
+    for (name, value) in params {
+        context.tex_parameteri(Gl::TEXTURE_2D, name, value as i32);
+    }
+    texture
+}
@@ -1,17 +1,23 @@
 use async_trait::async_trait;
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::f32::consts::FRAC_PI_2;
+use std::future::Future;
 use std::mem;
-use std::ops::Bound::*;
+use std::ops::Range;
+use std::pin::Pin;
 use std::rc::Rc;
 
 use crate::util::glam::{point3_to_vec3, vec3_to_point3};
 use arrayvec::ArrayVec;
-use glam::{EulerRot, Quat, Vec3};
+use glam::{Quat, Vec3};
 
+use crate::metrics::{AdaptiveQuality, FrameStats, FrameSummary};
+use crate::recorder::{parse_recording, Interaction, Recorder};
 use crate::util::sync::LevelLock;
 use base::defs::{Error, ErrorKind::*, Result};
 use base::fm;
+use base::render::{self, Interpolation};
 
 const DEFAULT_EYE_POSITION: fm::Point3 = fm::Point3 {
     x: 1.0,
@@ -20,7 +26,150 @@ const DEFAULT_EYE_POSITION: fm::Point3 = fm::Point3 {
 };
 
 const POINTER_MOVE_ANGLE_FACTOR: f32 = 0.01;
-const WHEEL_SCALE_FACTOR: f32 = -0.001;
+const WHEEL_SCALE_FACTOR: f32 = 0.001;
+const MAX_ELEVATION: f32 = 1.5;
+const MAX_FRAME_INTERVAL: f32 = 0.1;
+const MIN_ORBIT_SPEED: f32 = 0.05; // Radians per second.
+const MIN_ZOOM_SPEED: f32 = 0.05; // Relative distance change per second.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewerOptions {
+    // Rotation angle (radians) per pointer movement pixel.
+    pub rotation_sensitivity: f32,
+    // Relative change of eye distance per wheel delta unit.
+    pub zoom_sensitivity: f32,
+    pub min_eye_distance: f32,
+    pub max_eye_distance: f32,
+    // Eye elevation limits in radians, within (-pi/2, pi/2).
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    // Time constant (seconds) of orbit and zoom velocity decay after
+    // user input stops, zero disables inertia.
+    pub inertia: f32,
+    // Duration (seconds) of programmatic camera changes.
+    pub transition_duration: f32,
+    // Vertical field of view in radians.
+    pub field_of_view: f32,
+    pub near_plane: f32,
+    pub far_plane: f32,
+    pub eye_position: fm::Point3,
+    // Show pre-rendered impostors instead of meshes if a loaded model has
+    // them (e.g. on low-end devices), takes effect on the next load.
+    pub impostors: bool,
+    pub interpolation: Interpolation,
+    // Render coarser levels of detail, then no textures while playback FPS
+    // stays below min_fps, restoring quality when it recovers.
+    pub adaptive_quality: bool,
+    pub min_fps: f32,
+    // Darken creases and cavities by screen-space ambient occlusion, which
+    // makes shape readable on untextured or flat-lit meshes.
+    pub ambient_occlusion: bool,
+    // Number of times render_all plays element loops (see composer
+    // set-loop), a ping-pong loop going there and back each time.
+    pub loop_count: u32,
+}
+
+impl Default for ViewerOptions {
+    fn default() -> Self {
+        Self {
+            rotation_sensitivity: POINTER_MOVE_ANGLE_FACTOR,
+            zoom_sensitivity: WHEEL_SCALE_FACTOR,
+            min_eye_distance: 0.2,
+            max_eye_distance: 500.0,
+            min_elevation: 0.0,
+            max_elevation: MAX_ELEVATION,
+            inertia: 0.15,
+            transition_duration: 0.4,
+            field_of_view: std::f32::consts::FRAC_PI_4,
+            near_plane: 0.1,
+            far_plane: 1000.0,
+            eye_position: DEFAULT_EYE_POSITION,
+            impostors: false,
+            interpolation: Interpolation::default(),
+            adaptive_quality: false,
+            min_fps: 30.0,
+            ambient_occlusion: false,
+            loop_count: 1,
+        }
+    }
+}
+
+impl ViewerOptions {
+    pub fn validate(&self) -> Result<()> {
+        let distance = point3_to_vec3(&self.eye_position).length();
+        let problem = if !(self.rotation_sensitivity.is_finite()
+            && self.zoom_sensitivity.is_finite())
+        {
+            "sensitivities should be finite"
+        } else if !(self.min_eye_distance > 0.0
+            && self.min_eye_distance <= self.max_eye_distance)
+        {
+            "eye distance limits should be positive and ordered"
+        } else if !(self.min_elevation > -FRAC_PI_2
+            && self.min_elevation <= self.max_elevation
+            && self.max_elevation < FRAC_PI_2)
+        {
+            "elevation limits should be ordered and within (-pi/2, pi/2)"
+        } else if !(self.inertia >= 0.0 && self.transition_duration >= 0.0) {
+            "inertia and transition duration should be non-negative"
+        } else if !(self.field_of_view > 0.0
+            && self.field_of_view < std::f32::consts::PI)
+        {
+            "field of view should be within (0, pi) radians"
+        } else if !(self.near_plane > 0.0 && self.near_plane < self.far_plane) {
+            "clipping planes should be positive and ordered"
+        } else if !(distance > 0.0 && distance.is_finite()) {
+            "eye position should differ from origin"
+        } else if !(self.min_fps > 0.0 && self.min_fps.is_finite()) {
+            "minimal FPS should be positive"
+        } else if self.loop_count == 0 {
+            "loop count should be positive"
+        } else {
+            return Ok(());
+        };
+        Err(Error::new(BadOperation, problem.to_string()))
+    }
+
+    // Rotates eye vertically keeping its elevation within limits.
+    fn raise_eye(&self, eye: Vec3, angle: f32) -> Vec3 {
+        let elevation = (eye.z / eye.length()).clamp(-1.0, 1.0).asin();
+        let target =
+            (elevation + angle).clamp(self.min_elevation, self.max_elevation);
+
+        // The axis is undefined at poles, so any horizontal one does.
+        let axis = eye.cross(Vec3::Z);
+        let axis = if axis.length_squared() > 0.0 {
+            axis.normalize()
+        } else {
+            Vec3::X
+        };
+
+        Quat::from_axis_angle(axis, target - elevation).mul_vec3(eye)
+    }
+
+    fn orbit_eye(&self, eye: fm::Point3, dx: f32, dy: f32) -> fm::Point3 {
+        // Orbit around the vertical axis, then raise or lower the eye
+        // (quaternions avoid gimbal issues near the poles).
+        let sensitivity = self.rotation_sensitivity;
+        let hor_rot = Quat::from_rotation_z(-dx * sensitivity);
+        let eye = hor_rot.mul_vec3(point3_to_vec3(&eye));
+        vec3_to_point3(&self.raise_eye(eye, dy * sensitivity))
+    }
+
+    fn zoom_eye(&self, eye: fm::Point3, dy: f32) -> fm::Point3 {
+        let scale = 1.0 - dy * self.zoom_sensitivity;
+        let eye = point3_to_vec3(&eye) * scale;
+        self.constrain_eye(vec3_to_point3(&eye))
+    }
+
+    fn constrain_eye(&self, pos: fm::Point3) -> fm::Point3 {
+        let pos = point3_to_vec3(&pos);
+        let distance = pos.length();
+        let clamped =
+            distance.clamp(self.min_eye_distance, self.max_eye_distance);
+        vec3_to_point3(&self.raise_eye(pos * (clamped / distance), 0.0))
+    }
+}
 
 #[derive(Clone, Copy, Default)]
 #[repr(C)]
@@ -39,6 +188,13 @@ pub struct Face {
     pub vertex3: u16,
 }
 
+// Bounding sphere of a model over all its states.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub center: fm::Point3,
+    pub radius: f32,
+}
+
 #[derive(Debug, Default)]
 pub struct PointerEvent {
     pub dx: f32,
@@ -54,22 +210,50 @@ pub trait Adapter {
 
     async fn next_frame(self: &Rc<Self>) -> fm::Time;
 
+    // Monotonic time unaffected by set_now.
+    fn now(self: &Rc<Self>) -> fm::Time;
+
     fn render_frame(self: &Rc<Self>) -> Result<()>;
 
+    // Renders vertices of each layer summing up colors weighted by layer
+    // opacities, so that coplanar layers blend instead of depth testing.
+    fn render_layers(
+        self: &Rc<Self>,
+        layers: &[(&[VertexData], f32)],
+    ) -> Result<()>;
+
+    fn set_ambient_occlusion(self: &Rc<Self>, enabled: bool) -> Result<()>;
+
     fn set_faces(self: &Rc<Self>, faces: &[Face]) -> Result<()>;
 
     async fn set_now(self: &Rc<Self>, now: fm::Time);
 
+    fn set_projection(
+        self: &Rc<Self>,
+        field_of_view: f32,
+        near_plane: f32,
+        far_plane: f32,
+    ) -> Result<()>;
+
     async fn set_texture(
         self: &Rc<Self>,
         index: usize,
         image: fm::Image,
     ) -> Result<()>;
 
+    // Untextured faces are shaded by their normals.
+    fn set_textured(self: &Rc<Self>, textured: bool) -> Result<()>;
+
     fn set_vertices(self: &Rc<Self>, vertices: &[VertexData]) -> Result<()>;
 
+    // Eye position is relative to the view center.
     fn set_eye_position(self: &Rc<Self>, eye: &fm::Point3) -> Result<()>;
 
+    // Runs the future concurrently with the caller.
+    fn spawn(self: &Rc<Self>, future: Pin<Box<dyn Future<Output = ()>>>);
+
+    fn set_view_center(self: &Rc<Self>, center: &fm::Point3) -> Result<()>;
+
     fn subscribe_to_pointer_move<F: Fn(&PointerEvent) + 'static>(
         self: &Rc<Self>,
         handler: F,
@@ -90,9 +274,15 @@ enum ControllerState {
 
 #[derive(Default)]
 struct ElementData {
+    // Range of the element in ControllerData::faces.
+    faces: Range<usize>,
     index: usize,
+    // View texture, kept while loading in case animated textures follow.
+    texture: Option<fm::Image>,
     vertex_base: u16,
     vertices: Vec<(u16, u16)>,
+    // Faces and texture points of the view, kept for refinements.
+    view: fm::ElementView,
 }
 
 #[derive(Clone, Default)]
@@ -101,169 +291,430 @@ struct ElementState {
     normals: Vec<fm::Point3>,
 }
 
+// Pre-rendered views laid out in a texture atlas.
+struct ImpostorData {
+    center: Vec3,
+    radius: f32,
+    columns: u32,
+    rows: u32,
+    directions: Vec<Vec3>,
+}
+
+impl ImpostorData {
+    const NUM_LAYERS: usize = 3;
+
+    fn new(impostors: &fm::Impostors) -> Result<Self> {
+        let num_cells = impostors.columns as usize * impostors.rows as usize;
+        if impostors.atlas.is_none()
+            || impostors.directions.is_empty()
+            || impostors.directions.len() > num_cells
+            || !(impostors.radius > 0.0 && impostors.radius.is_finite())
+        {
+            let desc = "malformed impostors".to_string();
+            return Err(Error::new(InconsistentState, desc));
+        }
+
+        Ok(Self {
+            center: point3_to_vec3(&impostors.center.unwrap_or_default()),
+            radius: impostors.radius,
+            columns: impostors.columns,
+            rows: impostors.rows,
+            directions: impostors
+                .directions
+                .iter()
+                .map(|d| point3_to_vec3(d).normalize_or_zero())
+                .collect(),
+        })
+    }
+
+    // Camera-facing quad for the atlas cell.
+    fn quad(&self, cell: usize, right: Vec3, up: Vec3) -> [VertexData; 4] {
+        let (columns, rows) = (self.columns as f32, self.rows as f32);
+        let u = (cell as u32 % self.columns) as f32;
+        let v = (cell as u32 / self.columns) as f32;
+        let corner = |x: f32, y: f32| {
+            let pos = self.center + (right * x + up * y) * self.radius;
+            VertexData {
+                texture: fm::Point2 {
+                    x: (u + (x + 1.0) / 2.0) / columns,
+                    y: (v + (1.0 - y) / 2.0) / rows,
+                },
+                vertex: vec3_to_point3(&pos),
+                ..Default::default()
+            }
+        };
+        [
+            corner(-1.0, -1.0),
+            corner(1.0, -1.0),
+            corner(1.0, 1.0),
+            corner(-1.0, 1.0),
+        ]
+    }
+
+    // Blends views nearest to the eye direction, a view fades out by the time
+    // it stops being one of the nearest.
+    fn layers(&self, eye: Vec3) -> Vec<([VertexData; 4], f32)> {
+        let dir = (eye - self.center).normalize_or_zero();
+        let up = if dir.z.abs() > 0.999 {
+            Vec3::Y
+        } else {
+            Vec3::Z
+        };
+        let right = up.cross(dir).normalize_or_zero();
+        let up = dir.cross(right);
+
+        let mut nearest: Vec<_> = self
+            .directions
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (i, d.dot(dir)))
+            .collect();
+        nearest.sort_by(|a, b| b.1.total_cmp(&a.1));
+        // Few views are all kept, so the base is below any dot product.
+        let base = nearest.get(Self::NUM_LAYERS).map_or(-2.0, |n| n.1);
+        nearest.truncate(Self::NUM_LAYERS);
+
+        let total: f32 = nearest.iter().map(|n| n.1 - base).sum();
+        nearest
+            .into_iter()
+            .map(|(i, dot)| (i, (dot - base) / total))
+            .filter(|(_, weight)| *weight > 0.0)
+            .map(|(i, weight)| (self.quad(i, right, up), weight))
+            .collect()
+    }
+}
+
 #[derive(Default)]
 struct ControllerData {
+    // Times of animated textures to be bound to elements.
+    bound_textures: Vec<fm::Time>,
+    center: fm::Point3,
     elements: HashMap<String, ElementData>,
     eye_pos: fm::Point3,
     faces: Vec<Face>,
+    // Incremented by each reset to stop streaming textures of the previous
+    // model.
+    generation: u32,
+    impostors: Option<ImpostorData>,
+    // Animation loops of elements.
+    loops: Vec<Option<fm::element_view::Loop>>,
+    // Texture level, element index and image of textures to stream.
+    pending_textures: Vec<(usize, usize, fm::Image)>,
     states: Vec<BTreeMap<fm::Time, ElementState>>,
+    // Elements with animated texture uploads in progress.
+    swapping: HashSet<usize>,
+    // Animated textures of elements (with the view one at fm::Time::MIN),
+    // empty for static ones.
+    textures: Vec<BTreeMap<fm::Time, fm::Image>>,
 }
 
-impl ControllerData {
+impl fm::Interpolate for ElementState {
     fn interpolate_linear(
         at: fm::Time,
-        a: (&fm::Time, &ElementState),
-        b: (&fm::Time, &ElementState),
-    ) -> ElementState {
-        #[inline]
-        fn interpolate(at: f32, a: (f32, f32), b: (f32, f32)) -> f32 {
-            (b.1 - a.1) / (b.0 - a.0) * (at - b.0) + b.1
-        }
-
-        fn interpolate_points(
-            at: f32,
-            a: (f32, &Vec<fm::Point3>),
-            b: (f32, &Vec<fm::Point3>),
-        ) -> Vec<fm::Point3> {
-            a.1.iter()
-                .zip(b.1)
-                .map(|(a1, b1)| fm::Point3 {
-                    x: interpolate(at, (a.0, a1.x), (b.0, b1.x)),
-                    y: interpolate(at, (a.0, a1.y), (b.0, b1.y)),
-                    z: interpolate(at, (a.0, a1.z), (b.0, b1.z)),
-                })
-                .collect()
-        }
-
-        let (atf, a0, b0) = (at as f32, *a.0 as f32, *b.0 as f32);
-
+        a: (fm::Time, &Self),
+        b: (fm::Time, &Self),
+    ) -> Self {
         ElementState {
-            vertices: interpolate_points(
-                atf,
-                (a0, &a.1.vertices),
-                (b0, &b.1.vertices),
+            vertices: fm::interpolate_points_linear(
+                at,
+                (a.0, &a.1.vertices),
+                (b.0, &b.1.vertices),
             ),
-            normals: interpolate_points(
-                atf,
-                (a0, &a.1.normals),
-                (b0, &b.1.normals),
+            normals: fm::interpolate_points_linear(
+                at,
+                (a.0, &a.1.normals),
+                (b.0, &b.1.normals),
             ),
         }
     }
 
     fn interpolate_quadratic(
         at: fm::Time,
-        a: (&fm::Time, &ElementState),
-        b: (&fm::Time, &ElementState),
-        c: (&fm::Time, &ElementState),
-    ) -> ElementState {
-        #[inline]
-        fn interpolate(
-            at: f32,
-            a: (f32, f32),
-            b: (f32, f32),
-            c: (f32, f32),
-        ) -> f32 {
-            ((at - c.0)
-                * ((at - b.0) * (b.0 - c.0) * a.1
-                    + (at - a.0) * (-a.0 + c.0) * b.1)
-                + (at - a.0) * (at - b.0) * (a.0 - b.0) * c.1)
-                / ((a.0 - b.0) * (a.0 - c.0) * (b.0 - c.0))
-        }
-
-        fn interpolate_points(
-            at: f32,
-            a: (f32, &Vec<fm::Point3>),
-            b: (f32, &Vec<fm::Point3>),
-            c: (f32, &Vec<fm::Point3>),
-        ) -> Vec<fm::Point3> {
-            a.1.iter()
-                .zip(b.1)
-                .zip(c.1)
-                .map(|((a1, b1), c1)| fm::Point3 {
-                    x: interpolate(at, (a.0, a1.x), (b.0, b1.x), (c.0, c1.x)),
-                    y: interpolate(at, (a.0, a1.y), (b.0, b1.y), (c.0, c1.y)),
-                    z: interpolate(at, (a.0, a1.z), (b.0, b1.z), (c.0, c1.z)),
-                })
-                .collect()
-        }
-
-        let (atf, a0, b0, c0) =
-            (at as f32, *a.0 as f32, *b.0 as f32, *c.0 as f32);
-
+        a: (fm::Time, &Self),
+        b: (fm::Time, &Self),
+        c: (fm::Time, &Self),
+    ) -> Self {
         ElementState {
-            vertices: interpolate_points(
-                atf,
-                (a0, &a.1.vertices),
-                (b0, &b.1.vertices),
-                (c0, &c.1.vertices),
+            vertices: fm::interpolate_points_quadratic(
+                at,
+                (a.0, &a.1.vertices),
+                (b.0, &b.1.vertices),
+                (c.0, &c.1.vertices),
             ),
-            normals: interpolate_points(
-                atf,
-                (a0, &a.1.normals),
-                (b0, &b.1.normals),
-                (c0, &c.1.normals),
+            normals: fm::interpolate_points_quadratic(
+                at,
+                (a.0, &a.1.normals),
+                (b.0, &b.1.normals),
+                (c.0, &c.1.normals),
             ),
         }
     }
+}
+
+// Animated camera change from one eye position and view center to another.
+struct Transition {
+    from: (Vec3, Vec3),
+    to: (Vec3, Vec3),
+    start: Option<fm::Time>,
+}
+
+impl Transition {
+    fn at(&self, t: f32) -> (fm::Point3, fm::Point3) {
+        let t = t * t * (3.0 - 2.0 * t); // Smooth step.
+        let (from_eye, to_eye) = (self.from.0, self.to.0);
+        let distance = from_eye.length() * (1.0 - t) + to_eye.length() * t;
+        let eye = from_eye.lerp(to_eye, t).normalize_or_zero() * distance;
+        let center = self.from.1.lerp(self.to.1, t);
+        (vec3_to_point3(&eye), vec3_to_point3(&center))
+    }
+}
+
+#[derive(Default)]
+struct CameraMotion {
+    animating: bool,
+    last_frame: Option<fm::Time>,
+    // Orbit (dx, dy) and zoom (dy) input since the last frame.
+    pending: (f32, f32, f32),
+    // The same input per second, which continues after user stops.
+    velocity: (f32, f32, f32),
+    transition: Option<Transition>,
+}
 
+impl ControllerData {
     pub fn no_states(&self) -> bool {
         self.states.iter().map(|s| s.len()).max().unwrap_or(0) == 0
     }
 
-    fn state_at(
-        states: &BTreeMap<fm::Time, ElementState>,
-        at: fm::Time,
-    ) -> Option<ElementState> {
-        if let Some(state) = states.get(&at) {
-            return Some(state.clone());
+    pub fn bounds(&self) -> Option<Bounds> {
+        if let Some(impostors) = &self.impostors {
+            return Some(Bounds {
+                center: vec3_to_point3(&impostors.center),
+                radius: impostors.radius,
+            });
         }
 
-        let mut prange = states.range((Unbounded, Excluded(at)));
-        let prev = prange.next_back()?;
-
-        let mut nrange = states.range((Excluded(at), Unbounded));
-        let next = if let Some(next) = nrange.next() {
-            next
-        } else {
-            return Some(prev.1.clone());
+        let vertices = || {
+            self.states
+                .iter()
+                .flat_map(|s| s.values())
+                .flat_map(|s| s.vertices.iter())
+                .map(point3_to_vec3)
         };
 
-        Some(if let Some(nnext) = nrange.next() {
-            ControllerData::interpolate_quadratic(at, prev, next, nnext)
-        } else if let Some(pprev) = prange.next_back() {
-            ControllerData::interpolate_quadratic(at, pprev, prev, next)
-        } else {
-            ControllerData::interpolate_linear(at, prev, next)
+        let min = vertices().reduce(|a, b| a.min(b))?;
+        let max = vertices().reduce(|a, b| a.max(b))?;
+        let center = (min + max) / 2.0;
+        let radius = vertices().map(|v| v.distance(center)).fold(0.0, f32::max);
+
+        Some(Bounds {
+            center: vec3_to_point3(&center),
+            radius,
         })
     }
 
-    pub fn states_at(&self, at: fm::Time) -> Vec<Option<ElementState>> {
+    // Levels of detail of base elements (by index) from the finest one,
+    // which are elements named '<element>-lod<N>' by build-view --lods.
+    fn lod_groups(&self) -> HashMap<usize, Vec<usize>> {
+        let mut groups: HashMap<_, Vec<_>> = HashMap::new();
+        for (name, element) in &self.elements {
+            let lod = name.rsplit_once("-lod").and_then(|(base, n)| {
+                Some((self.elements.get(base)?, n.parse::<u32>().ok()?))
+            });
+            if let Some((base, n)) = lod {
+                let group = groups.entry(base.index).or_default();
+                group.push((n, element.index));
+            }
+        }
+
+        groups
+            .into_iter()
+            .map(|(base, mut lods)| {
+                lods.sort_unstable();
+                (base, lods.into_iter().map(|(_, i)| i).collect())
+            })
+            .collect()
+    }
+
+    // Quality levels below the full one: levels of detail, then the coarsest
+    // one without textures.
+    fn max_quality(&self) -> usize {
+        let lods = self.lod_groups().values().map(Vec::len).max();
+        lods.unwrap_or(0) + 1
+    }
+
+    // Faces of elements shown at the quality level, i.e. their level of
+    // detail if there is one.
+    fn quality_faces(&self, level: usize) -> Vec<Face> {
+        let mut visible = vec![true; self.states.len()];
+        for (base, lods) in self.lod_groups() {
+            let shown = level.min(lods.len());
+            visible[base] = shown == 0;
+            for (i, lod) in lods.into_iter().enumerate() {
+                visible[lod] = shown == i + 1;
+            }
+        }
+
+        let mut elements: Vec<_> = self.elements.values().collect();
+        elements.sort_unstable_by_key(|e| e.index);
+        elements
+            .into_iter()
+            .filter(|e| visible[e.index])
+            .flat_map(|e| self.faces[e.faces.clone()].iter().copied())
+            .collect()
+    }
+
+    fn loop_time(
+        &self,
+        at: fm::Time,
+        animation_loop: &Option<fm::element_view::Loop>,
+    ) -> fm::Time {
+        match animation_loop {
+            Some(animation_loop) => render::loop_time(at, animation_loop),
+            None => at,
+        }
+    }
+
+    // Time span of states with element loops played given number of times.
+    fn animation_range(&self, loop_count: u32) -> Option<(fm::Time, fm::Time)> {
+        let (from, to) = render::animation_range(&self.states)?;
+        let loop_ends = self.loops.iter().flatten();
+        let loop_ends = loop_ends.map(|l| render::loop_end(l, loop_count));
+        Some((from, loop_ends.fold(to, fm::Time::max)))
+    }
+
+    pub fn states_at(
+        &self,
+        at: fm::Time,
+        interpolation: Interpolation,
+    ) -> Vec<Option<ElementState>> {
         let mut states = Vec::with_capacity(self.elements.len());
-        for element_states in &self.states {
-            states.push(Self::state_at(element_states, at));
+        let loops = self.states.iter().zip(&self.loops);
+        for (element_states, animation_loop) in loops {
+            let at = self.loop_time(at, animation_loop);
+            states.push(render::state_at(element_states, at, interpolation));
         }
         states
     }
 }
 
+// Vertices (distinct combinations of vertex, texture point and normal
+// numbers) and faces of the view, along with vertex and normal numbers of
+// the vertices. The vertices are placed at the base among others.
+#[allow(clippy::type_complexity)]
+fn view_geometry(
+    view: &fm::ElementView,
+    index: usize,
+    num_other_vertices: usize,
+    vertex_base: usize,
+) -> Result<(Vec<VertexData>, Vec<(u16, u16)>, Vec<Face>)> {
+    #[derive(Eq, PartialEq, PartialOrd, Ord)]
+    struct VertexDesc(u32, u32, u32);
+
+    let mut vertex_descs: Vec<_> = view
+        .faces
+        .iter()
+        .flat_map(|f| {
+            ArrayVec::from([
+                VertexDesc(f.vertex1, f.texture1, f.normal1),
+                VertexDesc(f.vertex2, f.texture2, f.normal2),
+                VertexDesc(f.vertex3, f.texture3, f.normal3),
+            ])
+        })
+        .collect();
+    vertex_descs.sort();
+    vertex_descs.dedup();
+
+    if num_other_vertices + vertex_descs.len() > u16::MAX as usize {
+        let desc = "too many vertices".to_string();
+        return Err(Error::new(UnsupportedFeature, desc));
+    }
+
+    let mut vertices = Vec::with_capacity(vertex_descs.len());
+    let mut element_vertices = Vec::with_capacity(vertex_descs.len());
+    let mut faces = Vec::with_capacity(view.faces.len());
+
+    let vertex_base = vertex_base as u16;
+    for face in &view.faces {
+        let v1 = VertexDesc(face.vertex1, face.texture1, face.normal1);
+        let v2 = VertexDesc(face.vertex2, face.texture2, face.normal2);
+        let v3 = VertexDesc(face.vertex3, face.texture3, face.normal3);
+        let v1i = vertex_descs.binary_search(&v1).unwrap();
+        let v2i = vertex_descs.binary_search(&v2).unwrap();
+        let v3i = vertex_descs.binary_search(&v3).unwrap();
+        faces.push(Face {
+            vertex1: vertex_base + v1i as u16,
+            vertex2: vertex_base + v2i as u16,
+            vertex3: vertex_base + v3i as u16,
+        })
+    }
+
+    let in_face_err = |what| {
+        let desc =
+            format!("{} in view face for element '{}'", what, view.element);
+        Error::new(InconsistentState, desc)
+    };
+
+    let check_not_zero = |num, what| {
+        if num == 0 {
+            return Err(in_face_err(what));
+        }
+        Ok(())
+    };
+
+    for VertexDesc(vn, tn, nn) in vertex_descs {
+        check_not_zero(vn, "zero vertex number")?;
+        check_not_zero(tn, "zero texture point number")?;
+
+        let tn = tn as usize;
+        if tn > view.texture_points.len() {
+            return Err(in_face_err("unknown texture point number"));
+        }
+
+        vertices.push(VertexData {
+            element: index as u8,
+            texture: view.texture_points[tn - 1],
+            ..Default::default()
+        });
+
+        element_vertices.push((vn as u16, nn as u16));
+    }
+
+    Ok((vertices, element_vertices, faces))
+}
+
 pub struct Controller<A: Adapter> {
     adapter: Rc<A>,
     data: RefCell<ControllerData>,
+    frame_stats: RefCell<FrameStats>,
+    motion: RefCell<CameraMotion>,
+    options: RefCell<ViewerOptions>,
     pointer_move_sub: RefCell<Option<A::Subscription>>,
+    quality: RefCell<AdaptiveQuality>,
+    recorder: RefCell<Option<Recorder>>,
     wheel_sub: RefCell<Option<A::Subscription>>,
     state: LevelLock<ControllerState>,
+    textured: Cell<bool>,
+    uploading: Cell<usize>, // Number of textures being uploaded.
     vertices: RefCell<Vec<VertexData>>,
 }
 
 impl<A: Adapter + 'static> Controller<A> {
-    pub fn create(adapter: Rc<A>) -> Result<Rc<Self>> {
+    pub fn create(adapter: Rc<A>, options: ViewerOptions) -> Result<Rc<Self>> {
+        options.validate()?;
+
         let controller = Rc::new(Self {
             adapter: adapter.clone(),
             data: RefCell::new(ControllerData::default()),
+            frame_stats: RefCell::new(FrameStats::default()),
+            motion: RefCell::new(CameraMotion::default()),
+            options: RefCell::new(options),
             pointer_move_sub: RefCell::new(None),
+            quality: RefCell::new(AdaptiveQuality::default()),
+            recorder: RefCell::new(None),
             wheel_sub: RefCell::new(None),
             state: LevelLock::new(ControllerState::Idle),
+            textured: Cell::new(true),
+            uploading: Cell::new(0),
             vertices: RefCell::new(Vec::new()),
         });
 
@@ -286,8 +737,19 @@ impl<A: Adapter + 'static> Controller<A> {
             .get_or_insert(wheel_sub);
 
         {
+            let options = controller.options.borrow();
+            controller.adapter.set_projection(
+                options.field_of_view,
+                options.near_plane,
+                options.far_plane,
+            )?;
+
+            if options.ambient_occlusion {
+                controller.adapter.set_ambient_occlusion(true)?;
+            }
+
             let mut data = controller.data.borrow_mut();
-            data.eye_pos = DEFAULT_EYE_POSITION;
+            data.eye_pos = options.eye_position;
             controller.adapter.set_eye_position(&data.eye_pos)?;
         }
 
@@ -300,6 +762,7 @@ impl<A: Adapter + 'static> Controller<A> {
 
         self.pointer_move_sub.borrow_mut().take();
         self.wheel_sub.borrow_mut().take();
+        *self.motion.borrow_mut() = CameraMotion::default();
 
         self.reset();
 
@@ -314,54 +777,228 @@ impl<A: Adapter + 'static> Controller<A> {
         let _guard = self.state.try_lock(ControllerState::HandlingEvent)?;
 
         let mut data = self.data.borrow_mut();
+        let options = self.options.borrow();
+        data.eye_pos = options.orbit_eye(data.eye_pos, event.dx, event.dy);
+        self.record_camera(&data);
+
+        if options.inertia > 0.0 {
+            let mut motion = self.motion.borrow_mut();
+            motion.transition = None;
+            motion.pending.0 += event.dx;
+            motion.pending.1 += event.dy;
+            drop(motion);
+            self.start_animation();
+        }
 
-        let hor_rot_angle = -event.dx * POINTER_MOVE_ANGLE_FACTOR;
-        let hor_rot = Quat::from_euler(EulerRot::YZX, 0.0, hor_rot_angle, 0.0);
-        let eye_pos = point3_to_vec3(&data.eye_pos);
-        data.eye_pos = vec3_to_point3(&hor_rot.mul_vec3(eye_pos));
-
-        let vert_rot_axis = if data.eye_pos.y != 0.0 {
-            let slope = -data.eye_pos.x / data.eye_pos.y;
-            let x = 1.0 / (1.0 + slope * slope).sqrt();
-            let y = slope * x;
-            Vec3::new(x, y, 0.0)
-        } else {
-            Vec3::new(0.0, 1.0, 0.0)
-        };
-
-        let vert_rot_angle =
-            data.eye_pos.y.signum() * event.dy * POINTER_MOVE_ANGLE_FACTOR;
-        let vert_rot = Quat::from_axis_angle(vert_rot_axis, vert_rot_angle);
-        let eye_pos = point3_to_vec3(&data.eye_pos);
-        let eye_pos = vec3_to_point3(&vert_rot.mul_vec3(eye_pos));
+        self.adapter.set_eye_position(&data.eye_pos)?;
+        self.render_frame(&data)
+    }
 
-        let angle_z = (eye_pos.z
-            / (eye_pos.x * eye_pos.x
-                + eye_pos.y * eye_pos.y
-                + eye_pos.z * eye_pos.z)
-                .sqrt())
-        .acos();
+    fn handle_wheel(self: &Rc<Self>, event: &PointerEvent) -> Result<()> {
+        let _guard = self.state.try_lock(ControllerState::HandlingEvent)?;
 
-        if eye_pos.z >= 0.0 && vert_rot_angle.abs() < angle_z {
-            data.eye_pos = eye_pos;
+        let mut data = self.data.borrow_mut();
+        let options = self.options.borrow();
+        data.eye_pos = options.zoom_eye(data.eye_pos, event.dy);
+        self.record_camera(&data);
+
+        if options.inertia > 0.0 {
+            let mut motion = self.motion.borrow_mut();
+            motion.transition = None;
+            motion.pending.2 += event.dy;
+            drop(motion);
+            self.start_animation();
         }
 
         self.adapter.set_eye_position(&data.eye_pos)?;
-        self.adapter.render_frame()
+        self.render_frame(&data)
     }
 
-    fn handle_wheel(self: &Rc<Self>, event: &PointerEvent) -> Result<()> {
+    // Starts the loop animating the camera unless it runs already.
+    fn start_animation(self: &Rc<Self>) {
+        let mut motion = self.motion.borrow_mut();
+        if motion.animating {
+            return;
+        }
+        motion.animating = true;
+        motion.last_frame = None;
+
+        let controller = self.clone();
+        self.adapter.spawn(Box::pin(async move {
+            loop {
+                let now = controller.adapter.next_frame().await;
+                if !matches!(controller.animate_frame(now), Ok(true)) {
+                    break;
+                }
+            }
+            controller.motion.borrow_mut().animating = false;
+        }));
+    }
+
+    // Advances camera transition or inertial motion, returns whether
+    // the animation should continue.
+    fn animate_frame(self: &Rc<Self>, now: fm::Time) -> Result<bool> {
         let _guard = self.state.try_lock(ControllerState::HandlingEvent)?;
 
         let mut data = self.data.borrow_mut();
+        let mut motion = self.motion.borrow_mut();
+        let options = self.options.borrow();
+
+        let dt = match motion.last_frame.replace(now) {
+            Some(prev) => (now - prev).as_secs_f64() as f32,
+            None => 0.0,
+        };
+        let dt = dt.clamp(0.0, MAX_FRAME_INTERVAL);
+
+        if let Some(transition) = &mut motion.transition {
+            let start = *transition.start.get_or_insert(now);
+            let elapsed = (now - start).as_secs_f64() as f32;
+            let t = (elapsed / options.transition_duration).min(1.0);
+            let (eye_pos, center) = transition.at(t);
+            if t >= 1.0 {
+                motion.transition = None;
+            }
+
+            data.eye_pos = eye_pos;
+            data.center = center;
+            self.record_camera(&data);
+            self.adapter.set_view_center(&data.center)?;
+            self.adapter.set_eye_position(&data.eye_pos)?;
+            self.render_frame(&data)?;
+            return Ok(motion.transition.is_some());
+        }
+
+        if dt == 0.0 {
+            return Ok(true);
+        }
+
+        let (pending, velocity) = (motion.pending, motion.velocity);
+        if pending != (0.0, 0.0, 0.0) {
+            // The input is applied already, just measure its speed.
+            motion.velocity = (pending.0 / dt, pending.1 / dt, pending.2 / dt);
+            motion.pending = (0.0, 0.0, 0.0);
+            return Ok(true);
+        }
+
+        let eye_pos = data.eye_pos;
+        let eye_pos =
+            options.orbit_eye(eye_pos, velocity.0 * dt, velocity.1 * dt);
+        data.eye_pos = options.zoom_eye(eye_pos, velocity.2 * dt);
+        self.record_camera(&data);
+        self.adapter.set_eye_position(&data.eye_pos)?;
+        self.render_frame(&data)?;
+
+        let decay = (-dt / options.inertia).exp();
+        let velocity =
+            (velocity.0 * decay, velocity.1 * decay, velocity.2 * decay);
+        motion.velocity = velocity;
 
-        let scale = 1.0 + event.dy * WHEEL_SCALE_FACTOR;
-        data.eye_pos.x *= scale;
-        data.eye_pos.y *= scale;
-        data.eye_pos.z *= scale;
+        let orbit_speed =
+            velocity.0.hypot(velocity.1) * options.rotation_sensitivity;
+        let zoom_speed = velocity.2.abs() * options.zoom_sensitivity;
+        Ok(orbit_speed >= MIN_ORBIT_SPEED || zoom_speed >= MIN_ZOOM_SPEED)
+    }
+
+    // Moves camera immediately or with animated transition.
+    fn move_camera(
+        self: &Rc<Self>,
+        data: &mut ControllerData,
+        eye_pos: fm::Point3,
+        center: fm::Point3,
+    ) -> Result<()> {
+        let duration = self.options.borrow().transition_duration;
+        if duration > 0.0 {
+            let mut motion = self.motion.borrow_mut();
+            motion.velocity = (0.0, 0.0, 0.0);
+            motion.pending = (0.0, 0.0, 0.0);
+            motion.transition = Some(Transition {
+                from: (
+                    point3_to_vec3(&data.eye_pos),
+                    point3_to_vec3(&data.center),
+                ),
+                to: (point3_to_vec3(&eye_pos), point3_to_vec3(&center)),
+                start: None,
+            });
+            drop(motion);
+            self.start_animation();
+            return Ok(());
+        }
 
+        data.eye_pos = eye_pos;
+        data.center = center;
+        self.record_camera(data);
+        self.adapter.set_view_center(&data.center)?;
         self.adapter.set_eye_position(&data.eye_pos)?;
-        self.adapter.render_frame()
+        self.render_frame(data)
+    }
+
+    fn render_frame(self: &Rc<Self>, data: &ControllerData) -> Result<()> {
+        let impostors = match &data.impostors {
+            Some(impostors) => impostors,
+            None => return self.adapter.render_frame(),
+        };
+
+        let eye = point3_to_vec3(&data.center) + point3_to_vec3(&data.eye_pos);
+        let layers = impostors.layers(eye);
+        let layers: Vec<_> = layers.iter().map(|(v, w)| (&v[..], *w)).collect();
+        self.adapter.render_layers(&layers)
+    }
+
+    fn record(self: &Rc<Self>, interaction: Interaction) {
+        if let Some(recorder) = self.recorder.borrow_mut().as_mut() {
+            recorder.record(self.adapter.now(), interaction);
+        }
+    }
+
+    fn record_camera(self: &Rc<Self>, data: &ControllerData) {
+        self.record(Interaction::camera(&data.eye_pos, &data.center));
+    }
+
+    // Starts recording camera and playback changes (opt-in for support).
+    pub fn start_recording(self: &Rc<Self>) {
+        let now = self.adapter.now();
+        let mut recorder = Recorder::new(now);
+        let data = self.data.borrow();
+        recorder.record(now, Interaction::camera(&data.eye_pos, &data.center));
+        *self.recorder.borrow_mut() = Some(recorder);
+    }
+
+    // Stops recording returning the interactions as JSON.
+    pub fn stop_recording(self: &Rc<Self>) -> Option<String> {
+        self.recorder.borrow_mut().take().map(|r| r.to_json())
+    }
+
+    // Repeats recorded interactions with their original timing.
+    pub async fn replay(self: &Rc<Self>, recording: &str) -> Result<()> {
+        let interactions = parse_recording(recording)?;
+        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+
+        let start = self.adapter.now();
+        for recorded in interactions {
+            while self.adapter.now() - start < recorded.time {
+                self.adapter.next_frame().await;
+            }
+
+            match recorded.interaction {
+                Interaction::Camera { eye, center } => {
+                    let mut data = self.data.borrow_mut();
+                    data.eye_pos = vec3_to_point3(&Vec3::from(eye));
+                    data.center = vec3_to_point3(&Vec3::from(center));
+                    self.adapter.set_view_center(&data.center)?;
+                    self.adapter.set_eye_position(&data.eye_pos)?;
+                    self.render_frame(&data)?;
+                }
+                Interaction::RenderMoment { at } => {
+                    self.set_vertices(at)?;
+                    self.render_frame(&self.data.borrow())?;
+                }
+                Interaction::RenderPeriod { from, to } => {
+                    self.render(from, to).await?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub async fn load(
@@ -370,7 +1007,17 @@ impl<A: Adapter + 'static> Controller<A> {
     ) -> Result<()> {
         let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
 
+        // Otherwise the streamed texture would replace one of the new model.
+        while self.uploading.get() > 0 {
+            self.adapter.next_frame().await;
+        }
         self.reset();
+        self.quality.borrow_mut().reset();
+        self.set_textured(true)?;
+
+        let use_impostors = self.options.borrow().impostors;
+        let mut impostors = None;
+        let mut deferred = Vec::new();
 
         loop {
             let rec = reader.read_record()?;
@@ -378,14 +1025,185 @@ impl<A: Adapter + 'static> Controller<A> {
                 break;
             }
 
+            // Meshes are loaded at the end unless impostors are found.
             use fm::record::Type::*;
             match rec.unwrap().r#type {
-                Some(ElementView(v)) => self.load_element_view(v).await?,
-                Some(ElementViewState(s)) => self.load_element_view_state(s)?,
-                _ => (),
+                Some(Impostors(i)) if use_impostors => impostors = Some(i),
+                Some(
+                    r @ (ElementView(_)
+                    | ElementViewState(_)
+                    | ElementTexture(_)
+                    | ElementViewRefinement(_)),
+                ) if use_impostors => deferred.push(r),
+                Some(r) => self.load_record(r).await?,
+                None => (),
+            }
+        }
+
+        match impostors {
+            Some(impostors) => self.load_impostors(impostors).await?,
+            None => {
+                for r in deferred {
+                    self.load_record(r).await?;
+                }
+            }
+        }
+
+        for element in self.data.borrow_mut().elements.values_mut() {
+            element.texture = None;
+        }
+
+        self.stream_textures();
+        Ok(())
+    }
+
+    // Replaces coarse textures with finer ones level by level in background,
+    // so that the model is shown before its textures are fully loaded.
+    fn stream_textures(self: &Rc<Self>) {
+        let (generation, mut pending) = {
+            let mut data = self.data.borrow_mut();
+            (data.generation, mem::take(&mut data.pending_textures))
+        };
+        if pending.is_empty() {
+            return;
+        }
+        pending.sort_by_key(|(level, index, _)| (*level, *index));
+
+        let controller = self.clone();
+        self.adapter.spawn(Box::pin(async move {
+            // The data is mutably borrowed while a new model is loaded.
+            let current = || {
+                let data = controller.data.try_borrow().ok()?;
+                Some(data).filter(|d| d.generation == generation)
+            };
+
+            for (_, index, img) in pending {
+                match current() {
+                    // An animated texture is bound instead.
+                    Some(data)
+                        if data.bound_textures[index] != fm::Time::MIN =>
+                    {
+                        continue
+                    }
+                    Some(_) => (),
+                    None => return,
+                }
+
+                controller.start_upload();
+                let result = controller.adapter.set_texture(index, img).await;
+                controller.finish_upload();
+                if result.is_err() {
+                    return; // The coarser texture stays.
+                }
+
+                match current() {
+                    Some(data) => {
+                        let _ = controller.render_frame(&data);
+                    }
+                    None => return,
+                }
+            }
+        }));
+    }
+
+    // Binds animated textures of the given time, they are uploaded in
+    // background and the frame is rendered again once they are bound.
+    fn swap_textures(self: &Rc<Self>, at: fm::Time) {
+        let (generation, swapped) = {
+            let mut data = self.data.borrow_mut();
+            let mut swapped = Vec::new();
+            for index in 0..data.textures.len() {
+                let at = data.loop_time(at, &data.loops[index]);
+                let time = match data.textures[index].range(..=at).next_back() {
+                    Some((&time, _)) => time,
+                    None => continue, // Static texture.
+                };
+                if data.bound_textures[index] != time {
+                    data.bound_textures[index] = time;
+                    if data.swapping.insert(index) {
+                        swapped.push(index);
+                    }
+                }
             }
+            (data.generation, swapped)
+        };
+
+        for index in swapped {
+            let controller = self.clone();
+            self.adapter.spawn(Box::pin(async move {
+                let mut uploaded = None;
+                loop {
+                    // Textures bound while uploading are uploaded next.
+                    let (time, img) = {
+                        let mut data = match controller.data.try_borrow_mut() {
+                            Ok(data) if data.generation == generation => data,
+                            _ => return,
+                        };
+                        let time = data.bound_textures[index];
+                        if uploaded == Some(time) {
+                            data.swapping.remove(&index);
+                            break;
+                        }
+                        (time, data.textures[index][&time].clone())
+                    };
+
+                    controller.start_upload();
+                    // On failure the previous texture stays.
+                    let _ = controller.adapter.set_texture(index, img).await;
+                    controller.finish_upload();
+                    uploaded = Some(time);
+                }
+
+                if let Ok(data) = controller.data.try_borrow() {
+                    let _ = controller.render_frame(&data);
+                }
+            }));
+        }
+    }
+
+    fn start_upload(&self) {
+        self.uploading.set(self.uploading.get() + 1);
+    }
+
+    fn finish_upload(&self) {
+        self.uploading.set(self.uploading.get() - 1);
+    }
+
+    async fn load_record(
+        self: &Rc<Self>,
+        record: fm::record::Type,
+    ) -> Result<()> {
+        use fm::record::Type::*;
+        match record {
+            ElementView(v) => self.load_element_view(v).await,
+            ElementViewState(s) => self.load_element_view_state(s),
+            ElementTexture(t) => self.load_element_texture(t),
+            ElementViewRefinement(r) => self.load_element_view_refinement(r),
+            _ => Ok(()),
         }
+    }
 
+    async fn load_impostors(
+        self: &Rc<Self>,
+        impostors: fm::Impostors,
+    ) -> Result<()> {
+        let data = ImpostorData::new(&impostors)?;
+        self.adapter
+            .set_texture(0, impostors.atlas.unwrap())
+            .await?;
+        self.adapter.set_faces(&[
+            Face {
+                vertex1: 0,
+                vertex2: 1,
+                vertex3: 2,
+            },
+            Face {
+                vertex1: 0,
+                vertex2: 2,
+                vertex3: 3,
+            },
+        ])?;
+        self.data.borrow_mut().impostors = Some(data);
         Ok(())
     }
 
@@ -415,92 +1233,88 @@ impl<A: Adapter + 'static> Controller<A> {
             return Err(Error::new(InconsistentState, desc));
         }
 
-        #[derive(Eq, PartialEq, PartialOrd, Ord)]
-        struct VertexDesc(u32, u32, u32);
+        // Elements are bound to a single texture, so faces can't be paged
+        // (normal maps of decimated views aren't rendered for the same reason).
+        if !view.texture_pages.is_empty() {
+            let desc =
+                format!("multi-page texture of element '{}'", view.element);
+            return Err(Error::new(UnsupportedFeature, desc));
+        }
 
-        let mut vertex_descs: Vec<_> = view
-            .faces
-            .iter()
-            .flat_map(|f| {
-                ArrayVec::from([
-                    VertexDesc(f.vertex1, f.texture1, f.normal1),
-                    VertexDesc(f.vertex2, f.texture2, f.normal2),
-                    VertexDesc(f.vertex3, f.texture3, f.normal3),
-                ])
-            })
-            .collect();
-        vertex_descs.sort();
-        vertex_descs.dedup();
-
-        if all_vertices.len() + vertex_descs.len() > u16::MAX as usize {
-            let desc = "too many vertices".to_string();
-            return Err(Error::new(UnsupportedFeature, desc));
-        }
-
-        let mut element = ElementData {
-            index: data.elements.len(),
-            vertex_base: all_vertices.len() as u16,
-            vertices: Vec::with_capacity(vertex_descs.len()),
-        };
-        let mut vertices = Vec::with_capacity(vertex_descs.len());
-        let mut faces = Vec::with_capacity(vertex_descs.len());
-
-        for face in &view.faces {
-            let v1 = VertexDesc(face.vertex1, face.texture1, face.normal1);
-            let v2 = VertexDesc(face.vertex2, face.texture2, face.normal2);
-            let v3 = VertexDesc(face.vertex3, face.texture3, face.normal3);
-            let v1i = vertex_descs.binary_search(&v1).unwrap();
-            let v2i = vertex_descs.binary_search(&v2).unwrap();
-            let v3i = vertex_descs.binary_search(&v3).unwrap();
-            faces.push(Face {
-                vertex1: element.vertex_base + v1i as u16,
-                vertex2: element.vertex_base + v2i as u16,
-                vertex3: element.vertex_base + v3i as u16,
-            })
-        }
-
-        let in_face_err_res = |what| {
-            let desc =
-                format!("{} in view face for element '{}'", what, view.element);
-            Err(Error::new(InconsistentState, desc))
+        let index = data.elements.len();
+        let vertex_base = all_vertices.len();
+        let (mut vertices, element_vertices, mut faces) =
+            view_geometry(&view, index, vertex_base, vertex_base)?;
+        let element = ElementData {
+            faces: data.faces.len()..data.faces.len() + faces.len(),
+            index,
+            texture: view.texture.clone(),
+            vertex_base: vertex_base as u16,
+            vertices: element_vertices,
+            view: fm::ElementView {
+                faces: view.faces,
+                texture_points: view.texture_points,
+                ..Default::default()
+            },
         };
 
-        let check_not_zero = |num, what| {
-            if num == 0 {
-                return in_face_err_res(what);
+        let mut levels = view.texture_levels.into_iter();
+        match (view.texture, levels.next()) {
+            (Some(img), Some(coarsest)) => {
+                self.adapter.set_texture(index, coarsest).await?;
+                let levels = levels.chain(Some(img)).enumerate();
+                let pending = levels.map(|(level, img)| (level, index, img));
+                data.pending_textures.extend(pending);
             }
-            Ok(())
-        };
-
-        for VertexDesc(vn, tn, nn) in vertex_descs {
-            check_not_zero(vn, "zero vertex number")?;
-            check_not_zero(tn, "zero texture point number")?;
-
-            let tn = tn as usize;
-            if tn > view.texture_points.len() {
-                return in_face_err_res("unknown texture point number");
+            (Some(img), None) => self.adapter.set_texture(index, img).await?,
+            (None, _) => {
+                let desc = format!("textureless element '{}'", view.element);
+                return Err(Error::new(UnsupportedFeature, desc));
             }
-
-            vertices.push(VertexData {
-                element: data.elements.len() as u8,
-                texture: view.texture_points[tn - 1],
-                ..Default::default()
-            });
-
-            element.vertices.push((vn as u16, nn as u16));
-        }
-
-        if let Some(img) = view.texture {
-            self.adapter.set_texture(data.elements.len(), img).await?;
-        } else {
-            let desc = format!("textureless element '{}'", view.element);
-            return Err(Error::new(UnsupportedFeature, desc));
         }
 
         all_vertices.append(&mut vertices);
         data.elements.insert(view.element, element);
         data.faces.append(&mut faces);
+        data.loops.push(view.animation_loop);
         data.states.push(BTreeMap::new());
+        data.textures.push(BTreeMap::new());
+        data.bound_textures.push(fm::Time::MIN);
+        Ok(())
+    }
+
+    fn load_element_texture(
+        self: &Rc<Self>,
+        texture: fm::ElementTexture,
+    ) -> Result<()> {
+        let mut data = self.data.borrow_mut();
+        let data = &mut *data;
+
+        let element =
+            data.elements.get_mut(&texture.element).ok_or_else(|| {
+                let desc = format!(
+                    "texture for unknown element '{}'",
+                    texture.element
+                );
+                Error::new(InconsistentState, desc)
+            })?;
+
+        let textures = &mut data.textures[element.index];
+        if let Some(view_texture) = element.texture.take() {
+            textures.insert(fm::Time::MIN, view_texture);
+        }
+        let time = fm::Time(texture.time);
+        if textures.contains_key(&time) {
+            let desc = format!(
+                "duplicate texture time {} for element '{}'",
+                texture.time, texture.element
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+
+        if let Some(image) = texture.texture {
+            textures.insert(time, image);
+        }
         Ok(())
     }
 
@@ -555,22 +1369,22 @@ impl<A: Adapter + 'static> Controller<A> {
         };
 
         let index = element.index;
-        if data.states[index].contains_key(&view_state.time) {
+        let time = fm::Time(view_state.time);
+        if data.states[index].contains_key(&time) {
             return view_state_time_err_res("duplicate");
         }
 
         let last = data.states[index].iter().next_back();
-        if last.map_or(false, |(&t, _)| t > view_state.time) {
+        if last.map_or(false, |(&t, _)| t > time) {
             return view_state_time_err_res("non-monotonic");
         }
 
         if data.no_states() {
-            self.adapter.set_faces(&data.faces)?;
-            data.faces = Vec::new(); // It's not used anymore, so deallocate.
+            self.apply_quality(&data, 0)?;
         }
 
         data.states[index].insert(
-            view_state.time,
+            time,
             ElementState {
                 vertices: view_state.vertices,
                 normals: view_state.normals,
@@ -580,61 +1394,181 @@ impl<A: Adapter + 'static> Controller<A> {
         Ok(())
     }
 
+    // Refines statically posed element (e.g. splitting vertices of coarse
+    // base mesh) as refinements arrive, re-uploading faces. Vertices of the
+    // element are rebuilt, shifting ones of the following elements.
+    fn load_element_view_refinement(
+        self: &Rc<Self>,
+        refinement: fm::ElementViewRefinement,
+    ) -> Result<()> {
+        let mut data = self.data.borrow_mut();
+        let data = &mut *data;
+        let mut all_vertices = self.vertices.borrow_mut();
+
+        let element =
+            data.elements.get_mut(&refinement.element).ok_or_else(|| {
+                let desc = format!(
+                    "refinement for unknown element '{}'",
+                    refinement.element
+                );
+                Error::new(InconsistentState, desc)
+            })?;
+
+        let states = &mut data.states[element.index];
+        if states.len() != 1 {
+            let desc = format!(
+                "refinement of element '{}' without single view state",
+                refinement.element
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+        let (&time, state) = states.iter_mut().next().unwrap();
+
+        let mut view_state = fm::ElementViewState {
+            element: refinement.element.clone(),
+            time: time.0,
+            vertices: mem::take(&mut state.vertices),
+            normals: mem::take(&mut state.normals),
+        };
+        let view = &mut element.view;
+        let result = fm::apply_refinement(view, &mut view_state, &refinement);
+        state.vertices = view_state.vertices;
+        state.normals = view_state.normals;
+        result?;
+
+        let base = element.vertex_base as usize;
+        let num_vertices = element.vertices.len();
+        let (vertices, element_vertices, faces) = view_geometry(
+            &element.view,
+            element.index,
+            all_vertices.len() - num_vertices,
+            base,
+        )?;
+
+        let num_state_vertices = element_vertices.iter().map(|d| d.0).max();
+        let num_state_normals = element_vertices.iter().map(|d| d.1).max();
+        if num_state_vertices.unwrap_or(0) as usize > state.vertices.len()
+            || num_state_normals.unwrap_or(0) as usize > state.normals.len()
+        {
+            let desc = format!(
+                "unknown vertex or normal in refinement of element '{}'",
+                refinement.element
+            );
+            return Err(Error::new(InconsistentState, desc));
+        }
+
+        let (index, old_faces) = (element.index, element.faces.clone());
+        let vertex_shift = vertices.len() as isize - num_vertices as isize;
+        let face_shift = faces.len() as isize - old_faces.len() as isize;
+        element.faces = old_faces.start..old_faces.start + faces.len();
+        element.vertices = element_vertices;
+        all_vertices.splice(base..base + num_vertices, vertices);
+        data.faces.splice(old_faces, faces);
+
+        // Elements are laid out in order of their indices.
+        let shift = |n: usize, shift: isize| (n as isize + shift) as usize;
+        for other in data.elements.values_mut().filter(|e| e.index > index) {
+            other.vertex_base =
+                shift(other.vertex_base as usize, vertex_shift) as u16;
+            let start = shift(other.faces.start, face_shift);
+            other.faces = start..start + other.faces.len();
+            for face in &mut data.faces[other.faces.clone()] {
+                for vertex in
+                    [&mut face.vertex1, &mut face.vertex2, &mut face.vertex3]
+                {
+                    *vertex = shift(*vertex as usize, vertex_shift) as u16;
+                }
+            }
+        }
+
+        // Vertices are uploaded by rendering.
+        self.apply_quality(data, self.quality.borrow().level())
+    }
+
     async fn render(
         self: &Rc<Self>,
         from: fm::Time,
         to: fm::Time,
     ) -> Result<()> {
+        self.record(Interaction::RenderPeriod { from, to });
         self.adapter.set_now(from).await;
 
         self.set_vertices(from)?;
-        self.adapter.render_frame()?;
+        self.swap_textures(from);
+        self.render_frame(&self.data.borrow())?;
+        self.frame_stats.borrow_mut().start(from);
+        self.quality.borrow_mut().start(from);
 
         loop {
             let now = self.adapter.next_frame().await;
             if now > to {
                 break;
             }
+            self.frame_stats.borrow_mut().add_frame(now);
+            self.adapt_quality(now)?;
             self.set_vertices(now)?;
-            self.adapter.render_frame()?;
+            self.swap_textures(now);
+            self.render_frame(&self.data.borrow())?;
         }
 
         Ok(())
     }
 
-    pub async fn render_all(self: &Rc<Self>) -> Result<()> {
-        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+    fn adapt_quality(self: &Rc<Self>, now: fm::Time) -> Result<()> {
+        let data = self.data.borrow();
+        let options = self.options.borrow();
+        if !options.adaptive_quality || data.impostors.is_some() {
+            return Ok(());
+        }
 
-        let from;
-        let to;
-        {
-            let data = self.data.borrow();
-            if data.states.is_empty() {
-                return Ok(());
-            }
+        let max_level = data.max_quality();
+        let mut quality = self.quality.borrow_mut();
+        match quality.add_frame(now, options.min_fps, max_level) {
+            Some(level) => self.apply_quality(&data, level),
+            None => Ok(()),
+        }
+    }
 
-            from = *data
-                .states
-                .iter()
-                .map(|s| s.keys().next().unwrap_or(&fm::Time::MAX))
-                .min()
-                .unwrap();
+    // Renders coarser levels of detail for lower quality levels, and no
+    // textures for the lowest one.
+    fn apply_quality(
+        self: &Rc<Self>,
+        data: &ControllerData,
+        level: usize,
+    ) -> Result<()> {
+        self.adapter.set_faces(&data.quality_faces(level))?;
+        self.set_textured(level < data.max_quality())
+    }
 
-            to = *data
-                .states
-                .iter()
-                .map(|s| s.keys().next_back().unwrap_or(&fm::Time::MIN))
-                .max()
-                .unwrap();
+    fn set_textured(self: &Rc<Self>, textured: bool) -> Result<()> {
+        if self.textured.replace(textured) != textured {
+            self.adapter.set_textured(textured)?;
         }
+        Ok(())
+    }
 
-        self.render(from, to).await
+    pub async fn render_all(self: &Rc<Self>) -> Result<()> {
+        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+
+        let loop_count = self.options.borrow().loop_count;
+        let range = self.data.borrow().animation_range(loop_count);
+        match range {
+            Some((from, to)) => self.render(from, to).await,
+            None => Ok(()),
+        }
+    }
+
+    // Playback quality of the last (or current) animation run.
+    pub fn frame_summary(&self) -> Option<FrameSummary> {
+        self.frame_stats.borrow().summary()
     }
 
     pub fn render_moment(self: &Rc<Self>, at: fm::Time) -> Result<()> {
         let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+        self.record(Interaction::RenderMoment { at });
         self.set_vertices(at)?;
-        self.adapter.render_frame()
+        self.swap_textures(at);
+        self.render_frame(&self.data.borrow())
     }
 
     pub async fn render_period(
@@ -648,24 +1582,105 @@ impl<A: Adapter + 'static> Controller<A> {
 
     fn reset(self: &Rc<Self>) {
         let mut data = self.data.borrow_mut();
+        data.bound_textures = Vec::new();
         data.elements = HashMap::new();
         data.faces = Vec::new();
+        data.generation = data.generation.wrapping_add(1);
+        data.impostors = None;
+        data.loops = Vec::new();
+        data.pending_textures = Vec::new();
         data.states = Vec::new();
+        data.swapping = HashSet::new();
+        data.textures = Vec::new();
     }
 
     pub fn reset_eye_position(self: &Rc<Self>) -> Result<()> {
         let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
         let mut data = self.data.borrow_mut();
-        data.eye_pos = DEFAULT_EYE_POSITION;
+        let eye_pos = self.options.borrow().eye_position;
+        let center = data.center;
+        self.move_camera(&mut data, eye_pos, center)
+    }
+
+    // Applies new options keeping the current eye direction.
+    pub fn set_options(self: &Rc<Self>, options: ViewerOptions) -> Result<()> {
+        options.validate()?;
+
+        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+        self.adapter.set_projection(
+            options.field_of_view,
+            options.near_plane,
+            options.far_plane,
+        )?;
+
+        let enabled = options.ambient_occlusion;
+        if enabled != self.options.borrow().ambient_occlusion {
+            self.adapter.set_ambient_occlusion(enabled)?;
+        }
+
+        let mut data = self.data.borrow_mut();
+        if !options.adaptive_quality && self.quality.borrow().level() > 0 {
+            self.quality.borrow_mut().reset();
+            self.apply_quality(&data, 0)?;
+        }
+
+        data.eye_pos = options.constrain_eye(data.eye_pos);
+        self.record_camera(&data);
         self.adapter.set_eye_position(&data.eye_pos)?;
-        self.adapter.render_frame()
+        *self.options.borrow_mut() = options;
+        self.render_frame(&data)
+    }
+
+    pub fn options(&self) -> ViewerOptions {
+        self.options.borrow().clone()
+    }
+
+    pub fn set_eye_position(self: &Rc<Self>, pos: fm::Point3) -> Result<()> {
+        if pos.x == 0.0 && pos.y == 0.0 && pos.z == 0.0 {
+            let desc = "eye position should differ from origin".to_string();
+            return Err(Error::new(BadOperation, desc));
+        }
+
+        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+        let mut data = self.data.borrow_mut();
+        let eye_pos = self.options.borrow().constrain_eye(pos);
+        let center = data.center;
+        self.move_camera(&mut data, eye_pos, center)
+    }
+
+    pub fn bounds(&self) -> Option<Bounds> {
+        self.data.borrow().bounds()
+    }
+
+    // Centers the view on the model and moves the eye along its current
+    // direction, so that the whole model gets visible.
+    pub fn fit_view(self: &Rc<Self>) -> Result<()> {
+        let _guard = self.state.try_lock(ControllerState::HandlingOp).unwrap();
+        let mut data = self.data.borrow_mut();
+        let bounds = match data.bounds() {
+            Some(bounds) => bounds,
+            None => return Ok(()),
+        };
+
+        let options = self.options.borrow();
+        let distance = (bounds.radius / (options.field_of_view / 2.0).sin())
+            .max(options.min_eye_distance);
+        let dir = point3_to_vec3(&data.eye_pos).normalize();
+        let eye_pos = options.constrain_eye(vec3_to_point3(&(dir * distance)));
+        drop(options);
+
+        self.move_camera(&mut data, eye_pos, bounds.center)
     }
 
     fn set_vertices(self: &Rc<Self>, at: fm::Time) -> Result<()> {
         let data = self.data.borrow();
+        if data.impostors.is_some() {
+            return Ok(()); // Impostors are static.
+        }
+
         let mut vertices = self.vertices.borrow_mut();
 
-        let states = data.states_at(at);
+        let states = data.states_at(at, self.options.borrow().interpolation);
 
         for element in data.elements.values() {
             let element_state = &states[element.index];
@@ -701,19 +1716,25 @@ mod tests {
     use super::*;
     use base::assert_eq_point3;
     use base::util::test::{
-        create_reader_with_records, new_element_view_rec,
-        new_element_view_state_rec, new_ev_face, new_point2, new_point3,
-        MethodMock,
+        create_reader_with_records, new_element_texture_rec,
+        new_element_view_rec, new_element_view_state_rec, new_ev_face,
+        new_point2, new_point3, MethodMock,
     };
 
     struct TestAdapterData {
         destroy_mock: MethodMock<(), Result<()>>,
         next_frame_mock: MethodMock<(), fm::Time>,
+        now_mock: MethodMock<(), fm::Time>,
+        render_layers_mock: MethodMock<Vec<(Vec<VertexData>, f32)>, Result<()>>,
         render_moment_mock: MethodMock<(), Result<()>>,
+        set_ambient_occlusion_mock: MethodMock<bool, Result<()>>,
         set_eye_position_mock: MethodMock<fm::Point3, Result<()>>,
         set_faces_mock: MethodMock<Vec<Face>, Result<()>>,
         set_now_mock: MethodMock<fm::Time, ()>,
+        set_projection_mock: MethodMock<(f32, f32, f32), Result<()>>,
         set_texture_mock: MethodMock<(usize, fm::Image), Result<()>>,
+        set_textured_mock: MethodMock<bool, Result<()>>,
+        set_view_center_mock: MethodMock<fm::Point3, Result<()>>,
         set_vertices_mock: MethodMock<Vec<VertexData>, Result<()>>,
         subscribe_to_pointer_move_mock:
             MethodMock<Box<dyn Fn(&PointerEvent)>, Result<String>>,
@@ -723,6 +1744,7 @@ mod tests {
 
     struct TestAdapter {
         data: RefCell<TestAdapterData>,
+        spawned: RefCell<Vec<Pin<Box<dyn Future<Output = ()>>>>>,
     }
 
     impl TestAdapter {
@@ -731,15 +1753,22 @@ mod tests {
                 data: RefCell::new(TestAdapterData {
                     destroy_mock: MethodMock::new(),
                     next_frame_mock: MethodMock::new(),
+                    now_mock: MethodMock::new(),
+                    render_layers_mock: MethodMock::new(),
                     render_moment_mock: MethodMock::new(),
+                    set_ambient_occlusion_mock: MethodMock::new(),
                     set_eye_position_mock: MethodMock::new(),
                     set_faces_mock: MethodMock::new(),
                     set_now_mock: MethodMock::new(),
+                    set_projection_mock: MethodMock::new(),
                     set_texture_mock: MethodMock::new(),
+                    set_textured_mock: MethodMock::new(),
+                    set_view_center_mock: MethodMock::new(),
                     set_vertices_mock: MethodMock::new(),
                     subscribe_to_pointer_move_mock: MethodMock::new(),
                     subscribe_to_wheel_mock: MethodMock::new(),
                 }),
+                spawned: RefCell::new(Vec::new()),
             })
         }
 
@@ -747,14 +1776,32 @@ mod tests {
             let data = self.data.borrow();
             data.destroy_mock.finish();
             data.next_frame_mock.finish();
+            data.now_mock.finish();
+            data.render_layers_mock.finish();
             data.render_moment_mock.finish();
+            data.set_ambient_occlusion_mock.finish();
             data.set_eye_position_mock.finish();
             data.set_faces_mock.finish();
             data.set_now_mock.finish();
+            data.set_projection_mock.finish();
             data.set_texture_mock.finish();
+            data.set_textured_mock.finish();
+            data.set_view_center_mock.finish();
             data.set_vertices_mock.finish();
             data.subscribe_to_pointer_move_mock.finish();
             data.subscribe_to_wheel_mock.finish();
+            assert!(self.spawned.borrow().is_empty());
+        }
+
+        // Runs spawned futures till completion.
+        pub async fn run_spawned(&self) {
+            loop {
+                let future = self.spawned.borrow_mut().pop();
+                match future {
+                    Some(future) => future.await,
+                    None => break,
+                }
+            }
         }
     }
 
@@ -770,10 +1817,29 @@ mod tests {
             self.data.borrow_mut().next_frame_mock.call(())
         }
 
+        fn now(self: &Rc<Self>) -> fm::Time {
+            self.data.borrow_mut().now_mock.call(())
+        }
+
         fn render_frame(self: &Rc<Self>) -> Result<()> {
             self.data.borrow_mut().render_moment_mock.call(())
         }
 
+        fn render_layers(
+            self: &Rc<Self>,
+            layers: &[(&[VertexData], f32)],
+        ) -> Result<()> {
+            let layers = layers.iter().map(|(v, w)| (v.to_vec(), *w)).collect();
+            self.data.borrow_mut().render_layers_mock.call(layers)
+        }
+
+        fn set_ambient_occlusion(self: &Rc<Self>, enabled: bool) -> Result<()> {
+            self.data
+                .borrow_mut()
+                .set_ambient_occlusion_mock
+                .call(enabled)
+        }
+
         fn set_faces(self: &Rc<Self>, faces: &[Face]) -> Result<()> {
             self.data.borrow_mut().set_faces_mock.call(faces.to_vec())
         }
@@ -782,6 +1848,19 @@ mod tests {
             self.data.borrow_mut().set_now_mock.call(now)
         }
 
+        fn set_projection(
+            self: &Rc<Self>,
+            field_of_view: f32,
+            near_plane: f32,
+            far_plane: f32,
+        ) -> Result<()> {
+            self.data.borrow_mut().set_projection_mock.call((
+                field_of_view,
+                near_plane,
+                far_plane,
+            ))
+        }
+
         async fn set_texture(
             self: &Rc<Self>,
             index: usize,
@@ -790,6 +1869,10 @@ mod tests {
             self.data.borrow_mut().set_texture_mock.call((index, image))
         }
 
+        fn set_textured(self: &Rc<Self>, textured: bool) -> Result<()> {
+            self.data.borrow_mut().set_textured_mock.call(textured)
+        }
+
         fn set_vertices(
             self: &Rc<Self>,
             vertices: &[VertexData],
@@ -807,6 +1890,14 @@ mod tests {
                 .call(eye.clone())
         }
 
+        fn spawn(self: &Rc<Self>, future: Pin<Box<dyn Future<Output = ()>>>) {
+            self.spawned.borrow_mut().push(future);
+        }
+
+        fn set_view_center(self: &Rc<Self>, center: &fm::Point3) -> Result<()> {
+            self.data.borrow_mut().set_view_center_mock.call(*center)
+        }
+
         fn subscribe_to_pointer_move<F: Fn(&PointerEvent) + 'static>(
             self: &Rc<Self>,
             handler: F,
@@ -825,6 +1916,16 @@ mod tests {
     }
 
     fn create_controller() -> Rc<Controller<TestAdapter>> {
+        create_controller_with(ViewerOptions {
+            inertia: 0.0,
+            transition_duration: 0.0,
+            ..Default::default()
+        })
+    }
+
+    fn create_controller_with(
+        options: ViewerOptions,
+    ) -> Rc<Controller<TestAdapter>> {
         let adapter = TestAdapter::new();
 
         {
@@ -834,14 +1935,16 @@ mod tests {
             let ret = Ok(format!("wheel_sub"));
             data.subscribe_to_wheel_mock.rets.push(ret);
             data.set_eye_position_mock.rets.push(Ok(()));
+            data.set_projection_mock.rets.push(Ok(()));
         }
 
-        let controller = Controller::create(adapter).unwrap();
+        let controller = Controller::create(adapter, options).unwrap();
 
         {
             let mut data = controller.adapter.data.borrow_mut();
             let _ = data.subscribe_to_pointer_move_mock.args.pop().unwrap();
             let _ = data.subscribe_to_wheel_mock.args.pop().unwrap();
+            let _ = data.set_projection_mock.args.pop().unwrap();
             let args = data.set_eye_position_mock.args.pop().unwrap();
             assert_eq!(args, DEFAULT_EYE_POSITION);
         }
@@ -1092,6 +2195,7 @@ mod tests {
         let image = fm::Image {
             r#type: png,
             data: vec![1, 2, 3],
+            ..Default::default()
         };
 
         let view = new_element_view_rec(fm::ElementView {
@@ -1217,118 +2321,672 @@ mod tests {
     }
 
     #[test]
-    async fn test_interpolate() {
+    async fn test_fit_view() {
         let controller = create_controller();
-        let view_a = new_simple_view("a");
-        let view_b = new_simple_view("b");
-        let view_c = new_simple_view("c");
-
-        let state_a1 = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("a"),
-            time: 0,
-            vertices: vec![new_point3(3.0, 6.0, 12.0)],
-            normals: vec![new_point3(6.0, 12.0, 24.0)],
-        });
 
-        let state_b1 = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("b"),
-            time: 0,
-            vertices: vec![new_point3(1.0, 2.0, 4.0)],
-            normals: vec![new_point3(2.0, 4.0, 8.0)],
-        });
+        controller.fit_view().unwrap();
+        assert!(controller.bounds().is_none());
 
-        let state_a2 = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("a"),
-            time: 10,
-            vertices: vec![new_point3(6.0, 12.0, 24.0)],
-            normals: vec![new_point3(12.0, 24.0, 48.0)],
-        });
+        let view = new_simple_view("a");
 
-        let state_b2 = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("b"),
-            time: 10,
-            vertices: vec![new_point3(2.0, 4.0, 8.0)],
-            normals: vec![new_point3(4.0, 8.0, 16.0)],
+        let state = new_element_view_state_rec(fm::ElementViewState {
+            element: "a".to_string(),
+            time: 1,
+            vertices: vec![new_point3(0.0, 0.0, 1.0)],
+            normals: vec![new_point3(0.0, 0.0, 1.0)],
         });
 
-        let state_a3 = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("a"),
-            time: 20,
-            vertices: vec![new_point3(11.0, 22.0, 44.0)],
-            normals: vec![new_point3(22.0, 44.0, 88.0)],
+        let state2 = new_element_view_state_rec(fm::ElementViewState {
+            element: "a".to_string(),
+            time: 2,
+            vertices: vec![new_point3(2.0, 0.0, 1.0)],
+            normals: vec![new_point3(0.0, 0.0, 1.0)],
         });
 
-        let mut reader = create_reader_with_records(&vec![
-            view_a, view_b, view_c, state_a1, state_b1, state_a2, state_b2,
-            state_a3,
-        ]);
+        let mut reader = create_reader_with_records(&vec![view, state, state2]);
 
         {
             let mut data = controller.adapter.data.borrow_mut();
             data.set_texture_mock.rets.push(Ok(()));
-            data.set_texture_mock.rets.push(Ok(()));
-            data.set_texture_mock.rets.push(Ok(()));
             data.set_faces_mock.rets.push(Ok(()));
-            data.set_vertices_mock.rets.push(Ok(()));
+            data.set_view_center_mock.rets.push(Ok(()));
+            data.set_eye_position_mock.rets.push(Ok(()));
             data.render_moment_mock.rets.push(Ok(()));
         }
 
         controller.load(&mut reader).await.unwrap();
-        controller.render_moment(5).unwrap();
+        let bounds = controller.bounds().unwrap();
+        assert_eq_point3!(bounds.center, new_point3(1.0, 0.0, 1.0));
+        assert_eq!(bounds.radius, 1.0);
+
+        controller.fit_view().unwrap();
 
-        let vertices;
         {
             let mut data = controller.adapter.data.borrow_mut();
             data.set_texture_mock.args.pop().unwrap();
-            data.set_texture_mock.args.pop().unwrap();
-            data.set_texture_mock.args.pop().unwrap();
             data.set_faces_mock.args.pop().unwrap();
-            vertices = data.set_vertices_mock.args.pop().unwrap();
+            let center = data.set_view_center_mock.args.pop().unwrap();
+            assert_eq_point3!(center, new_point3(1.0, 0.0, 1.0));
+            let eye = data.set_eye_position_mock.args.pop().unwrap();
+            assert_eq_point3!(eye, new_point3(1.5086888, 1.5086888, 1.5086888));
             data.render_moment_mock.args.pop().unwrap();
-
-            data.set_vertices_mock.rets.push(Ok(()));
-            data.render_moment_mock.rets.push(Ok(()));
         }
 
-        controller.render_moment(15).unwrap();
+        controller.adapter.finish();
+    }
 
-        assert_eq!(vertices.len(), 3);
-        assert_eq_point3!(vertices[0].vertex, new_point3(4.25, 8.5, 17.0));
-        assert_eq_point3!(vertices[0].normal, new_point3(8.5, 17.0, 34.0));
-        assert_eq_point3!(vertices[1].vertex, new_point3(1.5, 3.0, 6.0));
-        assert_eq_point3!(vertices[1].normal, new_point3(3.0, 6.0, 12.0));
-        assert_eq_point3!(vertices[2].vertex, new_point3(0.0, 0.0, 0.0));
-        assert_eq_point3!(vertices[2].normal, new_point3(0.0, 0.0, 0.0));
+    #[test]
+    async fn test_handle_pointer_move() {
+        let controller = create_controller();
 
-        let vertices;
-        {
+        for _ in 0..3 {
             let mut data = controller.adapter.data.borrow_mut();
-            vertices = data.set_vertices_mock.args.pop().unwrap();
-            data.render_moment_mock.args.pop().unwrap();
+            data.set_eye_position_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
         }
 
-        assert_eq!(vertices.len(), 3);
-        assert_eq_point3!(vertices[0].vertex, new_point3(8.25, 16.5, 33.0));
-        assert_eq_point3!(vertices[0].normal, new_point3(16.5, 33.0, 66.0));
-        assert_eq_point3!(vertices[1].vertex, new_point3(2.0, 4.0, 8.0));
-        assert_eq_point3!(vertices[1].normal, new_point3(4.0, 8.0, 16.0));
-        assert_eq_point3!(vertices[2].vertex, new_point3(0.0, 0.0, 0.0));
-        assert_eq_point3!(vertices[2].normal, new_point3(0.0, 0.0, 0.0));
+        let move_pointer = |dx, dy| {
+            let event = PointerEvent {
+                dx,
+                dy,
+                primary_button: true,
+            };
+            controller.handle_pointer_move(&event).unwrap();
+            let mut data = controller.adapter.data.borrow_mut();
+            data.render_moment_mock.args.pop().unwrap();
+            data.set_eye_position_mock.args.pop().unwrap()
+        };
+
+        // Horizontal orbiting keeps elevation and distance.
+        let eye = move_pointer(-100.0, 0.0);
+        assert_eq_point3!(eye, new_point3(-0.30116874, 1.3817732, 1.0));
+
+        // Going over the pole stops at the elevation limit.
+        let eye = move_pointer(0.0, 1000.0);
+        let elevation = (eye.z / point3_to_vec3(&eye).length()).asin();
+        assert!((elevation - MAX_ELEVATION).abs() < 1e-5);
+        assert!((eye.y / eye.x - 1.3817732 / -0.30116874).abs() < 1e-3);
+
+        // The camera doesn't go below the equator by default.
+        let eye = move_pointer(0.0, -2000.0);
+        let elevation = (eye.z / point3_to_vec3(&eye).length()).asin();
+        assert!(elevation.abs() < 1e-5);
+
+        controller.adapter.finish();
     }
 
     #[test]
-    async fn test_render_moment() {
-        let controller = create_controller();
-
-        let view = new_simple_view("a");
-        let view2 = new_simple_view("b");
-        let view3 = new_simple_view("c");
+    async fn test_impostors() {
+        let controller = create_controller_with(ViewerOptions {
+            impostors: true,
+            ..Default::default()
+        });
 
+        let atlas = fm::Image {
+            data: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let impostors = fm::Record {
+            r#type: Some(fm::record::Type::Impostors(fm::Impostors {
+                center: Some(new_point3(0.0, 0.0, 1.0)),
+                radius: 2.0,
+                atlas: Some(atlas.clone()),
+                columns: 2,
+                rows: 2,
+                directions: vec![
+                    new_point3(1.0, 0.0, 0.0),
+                    new_point3(0.0, 1.0, 0.0),
+                    new_point3(-1.0, 0.0, 0.0),
+                    new_point3(0.0, -1.0, 0.0),
+                ],
+            })),
+        };
         let state = new_element_view_state_rec(fm::ElementViewState {
-            element: format!("a"),
-            time: 123,
-            vertices: vec![new_point3(0.123, 0.234, 0.345)],
-            normals: vec![new_point3(0.456, 0.567, 0.678)],
+            element: "a".to_string(),
+            vertices: vec![new_point3(0.0, 0.0, 1.0)],
+            normals: vec![new_point3(0.0, 0.0, 1.0)],
+            ..Default::default()
+        });
+        let mut reader = create_reader_with_records(&vec![
+            new_simple_view("a"),
+            state,
+            impostors,
+        ]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+            data.render_layers_mock.rets.push(Ok(()));
+        }
+
+        controller.load(&mut reader).await.unwrap();
+        let bounds = controller.bounds().unwrap();
+        assert_eq_point3!(bounds.center, new_point3(0.0, 0.0, 1.0));
+        assert_eq!(bounds.radius, 2.0);
+
+        controller.render_moment(fm::Time(0)).unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            assert_eq!(data.set_texture_mock.args.pop().unwrap(), (0, atlas));
+            let faces = data.set_faces_mock.args.pop().unwrap();
+            assert_eq!(faces, vec![new_face(0, 1, 2), new_face(0, 2, 3)]);
+
+            // Eye is along (1, 1, 0) from the impostor center, so the two
+            // nearest views are blended equally.
+            let layers = data.render_layers_mock.args.pop().unwrap();
+            assert_eq!(layers.len(), 2);
+            assert!((layers[0].1 - 0.5).abs() < 1e-6);
+            assert!((layers[1].1 - 0.5).abs() < 1e-6);
+            let (bottom_left, top_right) = (&layers[0].0[0], &layers[1].0[2]);
+            assert_eq!(bottom_left.texture, new_point2(0.0, 0.5));
+            assert_eq!(top_right.texture, new_point2(1.0, 0.0));
+            let (x, z) = (2.0 / 2f32.sqrt(), 1.0 - 2.0);
+            assert_eq_point3!(bottom_left.vertex, new_point3(x, -x, z));
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_inertia() {
+        let controller = create_controller_with(ViewerOptions {
+            inertia: 0.01,
+            ..Default::default()
+        });
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            for i in (0..5).rev() {
+                data.next_frame_mock.rets.push(fm::Time(i * 10_000_000));
+            }
+            for _ in 0..4 {
+                data.set_eye_position_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+        }
+
+        let event = PointerEvent {
+            dx: 1.0,
+            dy: 0.0,
+            primary_button: true,
+        };
+        controller.handle_pointer_move(&event).unwrap();
+        controller.adapter.run_spawned().await;
+
+        // The eye keeps orbiting for 3 frames with decaying speed.
+        let mut data = controller.adapter.data.borrow_mut();
+        let eyes = mem::take(&mut data.set_eye_position_mock.args);
+        let angles: Vec<_> = eyes.iter().map(|e| e.y.atan2(e.x)).collect();
+        let steps: Vec<_> = angles.windows(2).map(|a| a[0] - a[1]).collect();
+        assert_eq!(steps.len(), 3);
+        assert!((steps[0] - 0.01).abs() < 1e-5);
+        assert!((steps[1] - 0.01 * (-1f32).exp()).abs() < 1e-5);
+        assert!((steps[2] - 0.01 * (-2f32).exp()).abs() < 1e-5);
+        data.next_frame_mock.args.clear();
+        data.render_moment_mock.args.clear();
+        drop(data);
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_transition() {
+        let controller = create_controller_with(ViewerOptions {
+            transition_duration: 0.5,
+            ..Default::default()
+        });
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            for time in [500_000_000, 250_000_000, 0] {
+                data.next_frame_mock.rets.push(fm::Time(time));
+            }
+            for _ in 0..3 {
+                data.set_view_center_mock.rets.push(Ok(()));
+                data.set_eye_position_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+        }
+
+        controller.data.borrow_mut().eye_pos = new_point3(3.0, 0.0, 0.0);
+        controller.reset_eye_position().unwrap();
+        controller.adapter.run_spawned().await;
+
+        let mut data = controller.adapter.data.borrow_mut();
+        let eyes = mem::take(&mut data.set_eye_position_mock.args);
+        assert_eq_point3!(eyes[0], new_point3(3.0, 0.0, 0.0));
+        let distance = point3_to_vec3(&eyes[1]).length();
+        assert!((distance - (3.0 + 3f32.sqrt()) / 2.0).abs() < 1e-5);
+        assert_eq_point3!(eyes[2], DEFAULT_EYE_POSITION);
+        data.next_frame_mock.args.clear();
+        data.set_view_center_mock.args.clear();
+        data.render_moment_mock.args.clear();
+        drop(data);
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_interpolate() {
+        let controller = create_controller();
+        let view_a = new_simple_view("a");
+        let view_b = new_simple_view("b");
+        let view_c = new_simple_view("c");
+
+        let state_a1 = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("a"),
+            time: 0,
+            vertices: vec![new_point3(3.0, 6.0, 12.0)],
+            normals: vec![new_point3(6.0, 12.0, 24.0)],
+        });
+
+        let state_b1 = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("b"),
+            time: 0,
+            vertices: vec![new_point3(1.0, 2.0, 4.0)],
+            normals: vec![new_point3(2.0, 4.0, 8.0)],
+        });
+
+        let state_a2 = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("a"),
+            time: 10,
+            vertices: vec![new_point3(6.0, 12.0, 24.0)],
+            normals: vec![new_point3(12.0, 24.0, 48.0)],
+        });
+
+        let state_b2 = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("b"),
+            time: 10,
+            vertices: vec![new_point3(2.0, 4.0, 8.0)],
+            normals: vec![new_point3(4.0, 8.0, 16.0)],
+        });
+
+        let state_a3 = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("a"),
+            time: 20,
+            vertices: vec![new_point3(11.0, 22.0, 44.0)],
+            normals: vec![new_point3(22.0, 44.0, 88.0)],
+        });
+
+        let mut reader = create_reader_with_records(&vec![
+            view_a, view_b, view_c, state_a1, state_b1, state_a2, state_b2,
+            state_a3,
+        ]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+            data.set_vertices_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        controller.load(&mut reader).await.unwrap();
+        controller.render_moment(fm::Time(5)).unwrap();
+
+        let vertices;
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.args.pop().unwrap();
+            data.set_texture_mock.args.pop().unwrap();
+            data.set_texture_mock.args.pop().unwrap();
+            data.set_faces_mock.args.pop().unwrap();
+            vertices = data.set_vertices_mock.args.pop().unwrap();
+            data.render_moment_mock.args.pop().unwrap();
+
+            data.set_vertices_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        controller.render_moment(fm::Time(15)).unwrap();
+
+        assert_eq!(vertices.len(), 3);
+        assert_eq_point3!(vertices[0].vertex, new_point3(4.25, 8.5, 17.0));
+        assert_eq_point3!(vertices[0].normal, new_point3(8.5, 17.0, 34.0));
+        assert_eq_point3!(vertices[1].vertex, new_point3(1.5, 3.0, 6.0));
+        assert_eq_point3!(vertices[1].normal, new_point3(3.0, 6.0, 12.0));
+        assert_eq_point3!(vertices[2].vertex, new_point3(0.0, 0.0, 0.0));
+        assert_eq_point3!(vertices[2].normal, new_point3(0.0, 0.0, 0.0));
+
+        let vertices;
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            vertices = data.set_vertices_mock.args.pop().unwrap();
+            data.render_moment_mock.args.pop().unwrap();
+        }
+
+        assert_eq!(vertices.len(), 3);
+        assert_eq_point3!(vertices[0].vertex, new_point3(8.25, 16.5, 33.0));
+        assert_eq_point3!(vertices[0].normal, new_point3(16.5, 33.0, 66.0));
+        assert_eq_point3!(vertices[1].vertex, new_point3(2.0, 4.0, 8.0));
+        assert_eq_point3!(vertices[1].normal, new_point3(4.0, 8.0, 16.0));
+        assert_eq_point3!(vertices[2].vertex, new_point3(0.0, 0.0, 0.0));
+        assert_eq_point3!(vertices[2].normal, new_point3(0.0, 0.0, 0.0));
+
+        let data = controller.data.borrow();
+        let states = data.states_at(fm::Time(5), Interpolation::Linear);
+        let state = states[0].as_ref().unwrap();
+        assert_eq_point3!(state.vertices[0], new_point3(4.5, 9.0, 18.0));
+        let states = data.states_at(fm::Time(6), Interpolation::Nearest);
+        let state = states[0].as_ref().unwrap();
+        assert_eq_point3!(state.vertices[0], new_point3(6.0, 12.0, 24.0));
+    }
+
+    #[test]
+    async fn test_load_limits() {
+        let controller = create_controller();
+
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&20_000u32.to_be_bytes());
+        png.extend_from_slice(&1u32.to_be_bytes());
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(fm::Image {
+                data: png,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let mut reader = create_reader_with_records(&vec![view]);
+
+        assert_eq!(
+            controller.load(&mut reader).await,
+            Err(Error::new(
+                UnsupportedFeature,
+                "image size 20000x1 exceeds limit 16384".to_string()
+            )),
+        );
+
+        let mut reader =
+            create_reader_with_records(&vec![new_simple_view("a")]);
+        reader.set_limits(fm::ReaderLimits {
+            max_vertices: 0,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            controller.load(&mut reader).await,
+            Err(Error::new(
+                UnsupportedFeature,
+                "number of texture points 1 exceeds limit 0".to_string()
+            )),
+        );
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_set_options() {
+        let controller = create_controller();
+
+        let options = ViewerOptions {
+            near_plane: 2.0,
+            far_plane: 1.0,
+            ..Default::default()
+        };
+        let err = controller.set_options(options).unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_projection_mock.rets.push(Ok(()));
+            data.set_eye_position_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        let options = ViewerOptions {
+            min_eye_distance: 3.0,
+            field_of_view: 0.5,
+            ..Default::default()
+        };
+        controller.set_options(options.clone()).unwrap();
+        assert_eq!(controller.options(), options);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            let args = data.set_projection_mock.args.pop().unwrap();
+            assert_eq!(args, (0.5, 0.1, 1000.0));
+            let args = data.set_eye_position_mock.args.pop().unwrap();
+            assert_eq_point3!(
+                args,
+                new_point3(1.7320508, 1.7320508, 1.7320508)
+            );
+            data.render_moment_mock.args.pop().unwrap();
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_set_eye_position() {
+        let controller = create_controller();
+
+        let err = controller
+            .set_eye_position(new_point3(0.0, 0.0, 0.0))
+            .unwrap_err();
+        assert_eq!(err.kind, BadOperation);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_view_center_mock.rets.push(Ok(()));
+            data.set_eye_position_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        let pos = new_point3(0.0, 2.0, 3.0);
+        controller.set_eye_position(pos).unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            let args = data.set_view_center_mock.args.pop().unwrap();
+            assert_eq!(args, fm::Point3::default());
+            let args = data.set_eye_position_mock.args.pop().unwrap();
+            assert_eq!(args, pos);
+            data.render_moment_mock.args.pop().unwrap();
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_record_and_replay() {
+        let controller = create_controller();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            for time in [1_200, 1_000] {
+                data.now_mock.rets.push(fm::Time(time));
+            }
+            data.set_eye_position_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        controller.start_recording();
+        let event = PointerEvent {
+            dy: 100.0,
+            ..Default::default()
+        };
+        controller.handle_wheel(&event).unwrap();
+        let json = controller.stop_recording().unwrap();
+        assert!(controller.stop_recording().is_none());
+
+        let zoomed;
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.now_mock.args.clear();
+            zoomed = data.set_eye_position_mock.args.pop().unwrap();
+            data.render_moment_mock.args.pop().unwrap();
+        }
+        assert_eq!(
+            json,
+            format!(
+                "{{\"version\":1,\"interactions\":[\
+                 {{\"time\":0,\"type\":\"camera\",\
+                 \"eye\":[1.0,1.0,1.0],\"center\":[0.0,0.0,0.0]}},\
+                 {{\"time\":200,\"type\":\"camera\",\
+                 \"eye\":[{0:?},{0:?},{0:?}],\"center\":[0.0,0.0,0.0]}}]}}",
+                zoomed.x
+            )
+        );
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            for time in [2_300, 2_100, 2_000, 2_000] {
+                data.now_mock.rets.push(fm::Time(time));
+            }
+            data.next_frame_mock.rets.push(fm::Time(0));
+            for _ in 0..2 {
+                data.set_view_center_mock.rets.push(Ok(()));
+                data.set_eye_position_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+        }
+
+        controller.replay(&json).await.unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.now_mock.args.clear();
+            data.next_frame_mock.args.pop().unwrap();
+            data.set_view_center_mock.args.clear();
+            let eye = data.set_eye_position_mock.args.pop().unwrap();
+            assert_eq!(eye, zoomed);
+            let eye = data.set_eye_position_mock.args.pop().unwrap();
+            assert_eq!(eye, DEFAULT_EYE_POSITION);
+            data.render_moment_mock.args.clear();
+        }
+
+        let err = controller.replay("{\"version\":2,\"interactions\":[]}");
+        assert_eq!(err.await.unwrap_err().kind, UnsupportedFeature);
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_refinement() {
+        use fm::element_view_refinement::{Face as RefinedFace, Vertex};
+
+        let controller = create_controller();
+
+        let new_state = |element: &str, x| {
+            new_element_view_state_rec(fm::ElementViewState {
+                element: element.to_string(),
+                time: 0,
+                vertices: vec![new_point3(x, 0.0, 0.0)],
+                normals: vec![new_point3(0.0, 0.0, 1.0)],
+            })
+        };
+        let refinement = fm::Record {
+            r#type: Some(fm::record::Type::ElementViewRefinement(
+                fm::ElementViewRefinement {
+                    element: "a".to_string(),
+                    vertices: vec![
+                        Vertex {
+                            index: 2,
+                            vertex: Some(new_point3(2.0, 0.0, 0.0)),
+                            normal: Some(new_point3(0.0, 1.0, 0.0)),
+                            texture_point: Some(new_point2(0.5, 0.5)),
+                        },
+                        Vertex {
+                            index: 3,
+                            vertex: Some(new_point3(3.0, 0.0, 0.0)),
+                            normal: None,
+                            texture_point: Some(new_point2(1.0, 1.0)),
+                        },
+                    ],
+                    faces: vec![
+                        RefinedFace {
+                            index: 1,
+                            face: Some(new_ev_face(1, 2, 3, 1, 2, 3, 1, 2, 1)),
+                            label: 0,
+                        },
+                        RefinedFace {
+                            index: 2,
+                            face: Some(new_ev_face(1, 3, 2, 1, 3, 2, 1, 1, 2)),
+                            label: 0,
+                        },
+                    ],
+                },
+            )),
+        };
+        let mut reader = create_reader_with_records(&[
+            new_simple_view("a"),
+            new_simple_view("b"),
+            new_state("a", 1.0),
+            new_state("b", 5.0),
+            refinement,
+        ]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            for _ in 0..2 {
+                data.set_texture_mock.rets.push(Ok(()));
+                data.set_faces_mock.rets.push(Ok(()));
+            }
+            data.set_vertices_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        controller.load(&mut reader).await.unwrap();
+        controller.render_moment(fm::Time(0)).unwrap();
+
+        {
+            let data = controller.data.borrow();
+            assert_eq!(data.elements["a"].vertices, [(1, 1), (2, 2), (3, 1)]);
+            assert_eq!(data.elements["a"].faces, 0..2);
+            assert_eq!(data.elements["b"].vertex_base, 3);
+            assert_eq!(data.elements["b"].faces, 2..3);
+        }
+
+        let vertices;
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.args.clear();
+            let faces = data.set_faces_mock.args.pop().unwrap();
+            assert_eq!(
+                faces,
+                [new_face(0, 1, 2), new_face(0, 2, 1), new_face(3, 3, 3)]
+            );
+            let faces = data.set_faces_mock.args.pop().unwrap();
+            assert_eq!(faces, [new_face(0, 0, 0), new_face(1, 1, 1)]);
+            vertices = data.set_vertices_mock.args.pop().unwrap();
+            data.render_moment_mock.args.pop().unwrap();
+        }
+
+        let expected = [
+            (0, (1.0, 0.0, 0.0), (0.0, 0.0, 1.0), (0.0, 0.0)),
+            (0, (2.0, 0.0, 0.0), (0.0, 1.0, 0.0), (0.5, 0.5)),
+            (0, (3.0, 0.0, 0.0), (0.0, 0.0, 1.0), (1.0, 1.0)),
+            (1, (5.0, 0.0, 0.0), (0.0, 0.0, 1.0), (0.0, 0.0)),
+        ];
+        assert_eq!(vertices.len(), expected.len());
+        for (vertex, (element, v, n, t)) in vertices.iter().zip(expected) {
+            assert_eq!(vertex.element, element);
+            assert_eq!(vertex.vertex, new_point3(v.0, v.1, v.2));
+            assert_eq!(vertex.normal, new_point3(n.0, n.1, n.2));
+            assert_eq!(vertex.texture, new_point2(t.0, t.1));
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_render_moment() {
+        let controller = create_controller();
+
+        let view = new_simple_view("a");
+        let view2 = new_simple_view("b");
+        let view3 = new_simple_view("c");
+
+        let state = new_element_view_state_rec(fm::ElementViewState {
+            element: format!("a"),
+            time: 123,
+            vertices: vec![new_point3(0.123, 0.234, 0.345)],
+            normals: vec![new_point3(0.456, 0.567, 0.678)],
         });
 
         let state2 = new_element_view_state_rec(fm::ElementViewState {
@@ -1360,7 +3018,7 @@ mod tests {
         }
 
         controller.load(&mut reader).await.unwrap();
-        controller.render_moment(456).unwrap();
+        controller.render_moment(fm::Time(456)).unwrap();
 
         let vertices;
         {
@@ -1386,4 +3044,499 @@ mod tests {
 
         controller.adapter.finish();
     }
+
+    #[test]
+    async fn test_animation_loop() {
+        let state = |x| ElementState {
+            vertices: vec![new_point3(x, 0.0, 0.0)],
+            normals: vec![],
+        };
+        let mut data = ControllerData::default();
+        data.states.push(BTreeMap::from([
+            (fm::Time(0), state(0.0)),
+            (fm::Time(10), state(10.0)),
+            (fm::Time(20), state(20.0)),
+        ]));
+        data.loops.push(Some(fm::element_view::Loop {
+            start: 10,
+            end: 20,
+            ping_pong: false,
+        }));
+        data.states.push(BTreeMap::from([
+            (fm::Time(0), state(0.0)),
+            (fm::Time(40), state(40.0)),
+        ]));
+        data.loops.push(None);
+
+        let xs = |data: &ControllerData, at| -> Vec<f32> {
+            let states = data.states_at(fm::Time(at), Interpolation::Linear);
+            states
+                .iter()
+                .map(|s| s.as_ref().unwrap().vertices[0].x)
+                .collect()
+        };
+        assert_eq!(xs(&data, 5), [5.0, 5.0]);
+        assert_eq!(xs(&data, 20), [20.0, 20.0]);
+        assert_eq!(xs(&data, 28), [18.0, 28.0]);
+        assert_eq!(xs(&data, 30), [10.0, 30.0]);
+        let range = |from, to| Some((fm::Time(from), fm::Time(to)));
+        assert_eq!(data.animation_range(1), range(0, 40));
+        assert_eq!(data.animation_range(4), range(0, 50));
+
+        data.loops[0].as_mut().unwrap().ping_pong = true;
+        assert_eq!(xs(&data, 28), [12.0, 28.0]);
+        assert_eq!(xs(&data, 30), [10.0, 30.0]);
+        assert_eq!(xs(&data, 33), [13.0, 33.0]);
+        assert_eq!(data.animation_range(2), range(0, 50));
+    }
+
+    #[test]
+    async fn test_frame_summary() {
+        let controller = create_controller();
+        assert!(controller.frame_summary().is_none());
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_now_mock.rets.push(());
+            // The third frame is late by one display refresh.
+            for time in [100_000_000, 64_000_000, 32_000_000, 16_000_000] {
+                data.next_frame_mock.rets.push(fm::Time(time));
+            }
+            for _ in 0..4 {
+                data.set_vertices_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+        }
+
+        controller
+            .render_period(fm::Time(0), fm::Time(90_000_000))
+            .await
+            .unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            assert_eq!(data.set_now_mock.args.pop().unwrap(), fm::Time(0));
+            data.next_frame_mock.args.clear();
+            data.set_vertices_mock.args.clear();
+            data.render_moment_mock.args.clear();
+        }
+
+        let summary = controller.frame_summary().unwrap();
+        assert_eq!(summary.num_frames, 3);
+        assert_eq!(summary.num_dropped, 1);
+        assert!((summary.average_fps - 3.0 / 0.064).abs() < 1E-3);
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_adaptive_quality() {
+        let controller = create_controller_with(ViewerOptions {
+            inertia: 0.0,
+            transition_duration: 0.0,
+            adaptive_quality: true,
+            ..Default::default()
+        });
+
+        let state = |element: &str| {
+            new_element_view_state_rec(fm::ElementViewState {
+                element: element.to_string(),
+                time: 0,
+                vertices: vec![new_point3(0.0, 0.0, 0.0)],
+                normals: vec![new_point3(0.0, 0.0, 1.0)],
+            })
+        };
+        let mut reader = create_reader_with_records(&[
+            new_simple_view("a"),
+            new_simple_view("a-lod1"),
+            state("a"),
+            state("a-lod1"),
+        ]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+        }
+
+        controller.load(&mut reader).await.unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.args.clear();
+            let faces = data.set_faces_mock.args.pop().unwrap();
+            assert_eq!(faces, [new_face(0, 0, 0)]);
+
+            // Ten FPS switch to the level of detail in two seconds, then
+            // disable textures in two more.
+            data.set_now_mock.rets.push(());
+            for i in (1..=42).rev() {
+                data.next_frame_mock.rets.push(fm::Time(i * 100_000_000));
+            }
+            for _ in 0..42 {
+                data.set_vertices_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+            data.set_faces_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+            data.set_textured_mock.rets.push(Ok(()));
+        }
+
+        controller
+            .render_period(fm::Time(0), fm::Time(4_100_000_000))
+            .await
+            .unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_now_mock.args.clear();
+            data.next_frame_mock.args.clear();
+            data.set_vertices_mock.args.clear();
+            data.render_moment_mock.args.clear();
+            for _ in 0..2 {
+                let faces = data.set_faces_mock.args.pop().unwrap();
+                assert_eq!(faces, [new_face(1, 1, 1)]);
+            }
+            assert!(!data.set_textured_mock.args.pop().unwrap());
+
+            data.set_projection_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+            data.set_textured_mock.rets.push(Ok(()));
+            data.set_eye_position_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        // Disabling restores the full quality.
+        let mut options = controller.options();
+        options.adaptive_quality = false;
+        controller.set_options(options).unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_projection_mock.args.clear();
+            let faces = data.set_faces_mock.args.pop().unwrap();
+            assert_eq!(faces, [new_face(0, 0, 0)]);
+            assert!(data.set_textured_mock.args.pop().unwrap());
+            data.set_eye_position_mock.args.clear();
+            data.render_moment_mock.args.clear();
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_ambient_occlusion() {
+        let controller = create_controller();
+        let mut options = controller.options();
+        options.ambient_occlusion = true;
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_projection_mock.rets.push(Ok(()));
+            let err = Error::new(UnsupportedFeature, "no depth".to_string());
+            data.set_ambient_occlusion_mock.rets.push(Err(err));
+        }
+
+        let err = controller.set_options(options.clone()).unwrap_err();
+        assert_eq!(err.kind, UnsupportedFeature);
+        assert!(!controller.options().ambient_occlusion);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_projection_mock.args.clear();
+            assert!(data.set_ambient_occlusion_mock.args.pop().unwrap());
+
+            for _ in 0..2 {
+                data.set_projection_mock.rets.push(Ok(()));
+                data.set_eye_position_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+            }
+            data.set_ambient_occlusion_mock.rets.push(Ok(()));
+        }
+
+        // Unchanged options don't toggle it again.
+        controller.set_options(options.clone()).unwrap();
+        controller.set_options(options).unwrap();
+        assert!(controller.options().ambient_occlusion);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_projection_mock.args.clear();
+            assert!(data.set_ambient_occlusion_mock.args.pop().unwrap());
+            data.set_eye_position_mock.args.clear();
+            data.render_moment_mock.args.clear();
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_texture_streaming() {
+        let controller = create_controller();
+
+        let image = |data| fm::Image {
+            data,
+            ..Default::default()
+        };
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(image(vec![3])),
+            texture_levels: vec![image(vec![1]), image(vec![2])],
+            texture_points: vec![new_point2(0.0, 0.0)],
+            faces: vec![new_ev_face(1, 1, 1, 1, 1, 1, 1, 1, 1)],
+            ..Default::default()
+        });
+        let mut reader = create_reader_with_records(&vec![view]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+        }
+
+        controller.load(&mut reader).await.unwrap();
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            let args = data.set_texture_mock.args.pop().unwrap();
+            assert_eq!(args, (0, image(vec![1])));
+
+            // The full texture fails to upload, so the finer level stays.
+            let err = Error::new(MalformedData, "bad texture".to_string());
+            data.set_texture_mock.rets.push(Err(err));
+            data.set_texture_mock.rets.push(Ok(()));
+            data.render_moment_mock.rets.push(Ok(()));
+        }
+
+        controller.adapter.run_spawned().await;
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            let args = data.set_texture_mock.args.pop().unwrap();
+            assert_eq!(args, (0, image(vec![3])));
+            let args = data.set_texture_mock.args.pop().unwrap();
+            assert_eq!(args, (0, image(vec![2])));
+            data.render_moment_mock.args.pop().unwrap();
+        }
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_texture_pages() {
+        let controller = create_controller();
+
+        let image = |data| fm::Image {
+            data,
+            ..Default::default()
+        };
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(image(vec![1])),
+            texture_pages: vec![image(vec![2])],
+            texture_points: vec![new_point2(0.0, 0.0)],
+            faces: vec![new_ev_face(1, 1, 1, 1, 1, 1, 1, 1, 1)],
+            face_pages: vec![1],
+            ..Default::default()
+        });
+        let mut reader = create_reader_with_records(&[view]);
+
+        let err = controller.load(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind, UnsupportedFeature);
+
+        controller.adapter.finish();
+    }
+
+    #[test]
+    async fn test_texture_swapping() {
+        let controller = create_controller();
+
+        let image = |data| fm::Image {
+            data,
+            ..Default::default()
+        };
+        let view = new_element_view_rec(fm::ElementView {
+            element: "a".to_string(),
+            texture: Some(image(vec![1])),
+            texture_points: vec![new_point2(0.0, 0.0)],
+            faces: vec![new_ev_face(1, 1, 1, 1, 1, 1, 1, 1, 1)],
+            ..Default::default()
+        });
+        let state = new_element_view_state_rec(fm::ElementViewState {
+            element: "a".to_string(),
+            time: 0,
+            vertices: vec![new_point3(0.0, 0.0, 0.0)],
+            normals: vec![new_point3(0.0, 0.0, 1.0)],
+        });
+        let texture = new_element_texture_rec(fm::ElementTexture {
+            element: "a".to_string(),
+            time: 100,
+            texture: Some(image(vec![2])),
+        });
+        let mut reader = create_reader_with_records(&[
+            view.clone(),
+            state.clone(),
+            texture.clone(),
+        ]);
+
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+            data.set_faces_mock.rets.push(Ok(()));
+        }
+        controller.load(&mut reader).await.unwrap();
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.args.pop().unwrap();
+            data.set_faces_mock.args.pop().unwrap();
+        }
+
+        // Textures are swapped only when the bound one changes.
+        let moments = [(50, None), (150, Some(2)), (120, None), (0, Some(1))];
+        for (at, bound) in moments {
+            {
+                let mut data = controller.adapter.data.borrow_mut();
+                data.set_vertices_mock.rets.push(Ok(()));
+                data.render_moment_mock.rets.push(Ok(()));
+                if bound.is_some() {
+                    data.set_texture_mock.rets.push(Ok(()));
+                    data.render_moment_mock.rets.push(Ok(()));
+                }
+            }
+
+            controller.render_moment(fm::Time(at)).unwrap();
+            controller.adapter.run_spawned().await;
+
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_vertices_mock.args.pop().unwrap();
+            data.render_moment_mock.args.clear();
+            let args = data.set_texture_mock.args.pop();
+            assert_eq!(args, bound.map(|b| (0, image(vec![b]))));
+        }
+
+        let mut reader =
+            create_reader_with_records(&[view, texture.clone(), texture]);
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.rets.push(Ok(()));
+        }
+        let err = controller.load(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind, InconsistentState);
+        {
+            let mut data = controller.adapter.data.borrow_mut();
+            data.set_texture_mock.args.pop().unwrap();
+        }
+
+        controller.adapter.finish();
+    }
+
+    // Property tests, kept apart from the async test attribute.
+    mod interpolation {
+        use std::collections::BTreeMap;
+
+        use proptest::prelude::*;
+
+        use super::super::{ControllerData, ElementState};
+        use base::fm;
+        use base::render::{self, Interpolation};
+
+        // Uniform motion, which all interpolations reproduce.
+        fn point_at(
+            at: fm::Time,
+            origin: [f32; 3],
+            velocity: [f32; 3],
+        ) -> fm::Point3 {
+            let t = at.as_secs_f64();
+            let coord = |k: usize| origin[k] as f64 + velocity[k] as f64 * t;
+            fm::Point3 {
+                x: coord(0) as f32,
+                y: coord(1) as f32,
+                z: coord(2) as f32,
+            }
+        }
+
+        fn is_near(a: &fm::Point3, b: &fm::Point3) -> bool {
+            let tolerance = 1e-3 * (1.0 + b.x.abs() + b.y.abs() + b.z.abs());
+            (a.x - b.x).abs() <= tolerance
+                && (a.y - b.y).abs() <= tolerance
+                && (a.z - b.z).abs() <= tolerance
+        }
+
+        proptest! {
+            #[test]
+            fn test_states_at_uniform_motion(
+                millis in prop::collection::btree_set(0..10_000i64, 1..8),
+                origin in prop::array::uniform3(-10f32..10.0),
+                velocity in prop::array::uniform3(-10f32..10.0),
+                at in -1000..11_000i64,
+                interpolation in prop_oneof![
+                    Just(Interpolation::Nearest),
+                    Just(Interpolation::Linear),
+                    Just(Interpolation::Quadratic),
+                ],
+            ) {
+                let state = |t| ElementState {
+                    vertices: vec![point_at(t, origin, velocity)],
+                    normals: vec![point_at(t, velocity, origin)],
+                };
+                let times: Vec<_> =
+                    millis.iter().map(|&t| fm::Time::from_millis(t)).collect();
+                let states: BTreeMap<_, _> =
+                    times.iter().map(|&t| (t, state(t))).collect();
+                let data = ControllerData {
+                    states: vec![states],
+                    loops: vec![None],
+                    ..Default::default()
+                };
+
+                let at = fm::Time::from_millis(at);
+                let (first, last) = (times[0], *times.last().unwrap());
+                let actual = data.states_at(at, interpolation).pop().unwrap();
+                if at < first {
+                    prop_assert!(actual.is_none());
+                    return Ok(());
+                }
+
+                let expected_at = if at >= last {
+                    last
+                } else if interpolation == Interpolation::Nearest {
+                    let prev = *times.iter().rev().find(|&&t| t <= at).unwrap();
+                    let next = *times.iter().find(|&&t| t >= at).unwrap();
+                    if at - prev <= next - at { prev } else { next }
+                } else {
+                    at
+                };
+                let (actual, expected) = (actual.unwrap(), state(expected_at));
+                let vertex = &actual.vertices[0];
+                prop_assert!(is_near(vertex, &expected.vertices[0]));
+                let normal = &actual.normals[0];
+                prop_assert!(is_near(normal, &expected.normals[0]));
+            }
+
+            #[test]
+            fn test_loop_time(
+                start in -1000..1000i64,
+                span in 1..1000i64,
+                at in -2000..10_000i64,
+                ping_pong in any::<bool>(),
+            ) {
+                let end = start + span;
+                let animation_loop = fm::element_view::Loop {
+                    start,
+                    end,
+                    ping_pong,
+                };
+                let time = render::loop_time(fm::Time(at), &animation_loop).0;
+                if at <= end {
+                    prop_assert_eq!(time, at);
+                } else {
+                    prop_assert!(time >= start && time <= end);
+                    let period = if ping_pong { 2 * span } else { span };
+                    let next = fm::Time(at + period);
+                    let next = render::loop_time(next, &animation_loop).0;
+                    prop_assert_eq!(next, time);
+                }
+            }
+        }
+    }
 }
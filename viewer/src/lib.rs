@@ -1,7 +1,10 @@
 #[macro_use]
 mod log;
+mod ambient_occlusion;
 mod controller;
 mod defs;
+mod metrics;
+mod recorder;
 mod util;
 mod viewer;
 mod webgl_adapter;
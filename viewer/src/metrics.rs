@@ -0,0 +1,247 @@
+use std::mem;
+
+use base::fm;
+
+// Frame intervals longer than this number of typical ones mean drops.
+const DROPPED_FRAME_FACTOR: f32 = 1.5;
+
+// Percentile of slowest frames reported as the low FPS.
+const LOW_FPS_PERCENTILE: f32 = 0.05;
+
+// Playback duration over which FPS should stay low (or high) to change
+// adaptive quality.
+const QUALITY_WINDOW: fm::Time = fm::Time(2_000_000_000);
+
+// Quality is restored once FPS exceeds the minimum by this factor.
+const RECOVERY_FACTOR: f32 = 1.5;
+
+// Limit of good windows required to restore quality.
+const MAX_RECOVERY_WINDOWS: u32 = 16;
+
+// Playback quality of a single animation run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameSummary {
+    pub num_frames: usize,
+    pub average_fps: f32,
+    pub median_fps: f32,
+    pub low_fps: f32,
+    // Display refreshes missed, estimated against the median interval.
+    pub num_dropped: usize,
+}
+
+// Collects frame times of the current (or last) playback.
+#[derive(Default)]
+pub struct FrameStats {
+    last_frame: Option<fm::Time>,
+    intervals: Vec<fm::Time>,
+}
+
+impl FrameStats {
+    pub fn start(&mut self, now: fm::Time) {
+        self.last_frame = Some(now);
+        self.intervals.clear();
+    }
+
+    pub fn add_frame(&mut self, now: fm::Time) {
+        if let Some(last) = self.last_frame.replace(now) {
+            self.intervals.push((now - last).max(fm::Time(0)));
+        }
+    }
+
+    pub fn summary(&self) -> Option<FrameSummary> {
+        let total = self.intervals.iter().sum::<fm::Time>().0;
+        if total == 0 {
+            return None;
+        }
+
+        let mut sorted: Vec<_> = self.intervals.iter().map(|i| i.0).collect();
+        sorted.sort_unstable();
+        let fps = |interval: i64| 1E9 / interval.max(1) as f32;
+
+        let median = sorted[sorted.len() / 2];
+        let low = ((sorted.len() - 1) as f32 * (1.0 - LOW_FPS_PERCENTILE))
+            .round() as usize;
+
+        let num_dropped = if median > 0 {
+            sorted
+                .iter()
+                .filter(|i| **i as f32 > median as f32 * DROPPED_FRAME_FACTOR)
+                .map(|i| (*i as f32 / median as f32).round() as usize - 1)
+                .sum()
+        } else {
+            0
+        };
+
+        Some(FrameSummary {
+            num_frames: sorted.len(),
+            average_fps: sorted.len() as f32 * 1E9 / total as f32,
+            median_fps: fps(median),
+            low_fps: fps(sorted[low]),
+            num_dropped,
+        })
+    }
+}
+
+// Lowers quality level when FPS stays below the minimum during playback and
+// restores it when FPS recovers. A restored level which turns out too slow
+// again doubles the time required for the next restoring, so that quality
+// doesn't oscillate.
+#[derive(Default)]
+pub struct AdaptiveQuality {
+    level: usize,
+    window_start: Option<fm::Time>,
+    num_frames: usize,
+    good_windows: u32,
+    required_windows: u32,
+    just_restored: bool,
+}
+
+impl AdaptiveQuality {
+    // Current level, zero is the full quality.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    // Starts a new playback, pauses between them aren't measured.
+    pub fn start(&mut self, now: fm::Time) {
+        self.window_start = Some(now);
+        self.num_frames = 0;
+        self.good_windows = 0;
+    }
+
+    // Returns the new level if it has to change.
+    pub fn add_frame(
+        &mut self,
+        now: fm::Time,
+        min_fps: f32,
+        max_level: usize,
+    ) -> Option<usize> {
+        let start = *self.window_start.get_or_insert(now);
+        self.num_frames += 1;
+        let elapsed = now - start;
+        if elapsed < QUALITY_WINDOW {
+            return None;
+        }
+
+        let fps = self.num_frames as f32 * 1E9 / elapsed.0 as f32;
+        self.window_start = Some(now);
+        self.num_frames = 0;
+        let just_restored = mem::take(&mut self.just_restored);
+        let required = self.required_windows.max(1);
+
+        if fps < min_fps {
+            self.good_windows = 0;
+            if just_restored {
+                self.required_windows =
+                    (required * 2).min(MAX_RECOVERY_WINDOWS);
+            }
+            if self.level < max_level {
+                self.level += 1;
+                return Some(self.level);
+            }
+        } else if fps >= min_fps * RECOVERY_FACTOR && self.level > 0 {
+            self.good_windows += 1;
+            if self.good_windows >= required {
+                self.good_windows = 0;
+                self.just_restored = true;
+                self.level -= 1;
+                return Some(self.level);
+            }
+        } else {
+            self.good_windows = 0;
+        }
+
+        None
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpuInfo {
+    pub vendor: String,
+    pub renderer: String,
+}
+
+// Everything reported to the embedding product via the metrics callback.
+#[derive(Clone, Debug, Default)]
+pub struct ViewerMetrics {
+    pub gpu: GpuInfo,
+    pub load_time: Option<fm::Time>,
+    pub time_to_first_render: Option<fm::Time>,
+    pub frames: Option<FrameSummary>,
+    pub num_errors: usize,
+    pub last_error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: fm::Time = fm::Time(1000000);
+
+    #[test]
+    fn test_frame_stats() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.summary(), None);
+
+        stats.start(100 * MS);
+        let mut now = 100 * MS;
+        for i in 0..20 {
+            // Two frames are late, skipping one and two refreshes.
+            now += match i {
+                5 => 32 * MS,
+                12 => 48 * MS,
+                _ => 16 * MS,
+            };
+            stats.add_frame(now);
+        }
+
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.num_frames, 20);
+        assert_eq!(summary.num_dropped, 3);
+        assert!((summary.median_fps - 62.5).abs() < 1E-3);
+        assert!((summary.low_fps - 31.25).abs() < 1E-3);
+        assert!((summary.average_fps - 20.0 / 0.368).abs() < 1E-3);
+
+        stats.start(now);
+        assert_eq!(stats.summary(), None);
+    }
+
+    #[test]
+    fn test_adaptive_quality() {
+        let mut quality = AdaptiveQuality::default();
+        let mut now = fm::Time(0);
+        quality.start(now);
+
+        // Plays with the given frame interval, returns level changes.
+        let mut play = |quality: &mut AdaptiveQuality,
+                        interval: fm::Time,
+                        duration: fm::Time| {
+            let mut changes = Vec::new();
+            for _ in 0..duration.0 / interval.0 {
+                now += interval;
+                changes.extend(quality.add_frame(now, 30.0, 2));
+            }
+            changes
+        };
+
+        assert!(play(&mut quality, 16 * MS, 3000 * MS).is_empty());
+        assert_eq!(play(&mut quality, 50 * MS, 7000 * MS), vec![1, 2]);
+        assert_eq!(quality.level(), 2);
+        assert!(play(&mut quality, 40 * MS, 4000 * MS).is_empty());
+
+        // The restored level is too slow, so it takes twice longer to try
+        // it again.
+        assert_eq!(play(&mut quality, 16 * MS, 2100 * MS), vec![1]);
+        assert_eq!(play(&mut quality, 50 * MS, 2000 * MS), vec![2]);
+        assert!(play(&mut quality, 16 * MS, 2100 * MS).is_empty());
+        assert_eq!(play(&mut quality, 16 * MS, 2000 * MS), vec![1]);
+        assert_eq!(play(&mut quality, 16 * MS, 4000 * MS), vec![0]);
+
+        quality.reset();
+        assert_eq!(quality.level(), 0);
+    }
+}
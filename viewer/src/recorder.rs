@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+use base::defs::{Error, ErrorKind::*, Result};
+use base::fm;
+
+const RECORDING_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum Interaction {
+    // Eye position relative to the view center.
+    Camera { eye: [f32; 3], center: [f32; 3] },
+    RenderMoment { at: fm::Time },
+    RenderPeriod { from: fm::Time, to: fm::Time },
+}
+
+impl Interaction {
+    pub fn camera(eye: &fm::Point3, center: &fm::Point3) -> Self {
+        Interaction::Camera {
+            eye: [eye.x, eye.y, eye.z],
+            center: [center.x, center.y, center.z],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedInteraction {
+    // Time since the recording start.
+    pub time: fm::Time,
+    #[serde(flatten)]
+    pub interaction: Interaction,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Recording {
+    version: u32,
+    interactions: Vec<RecordedInteraction>,
+}
+
+pub struct Recorder {
+    start: fm::Time,
+    interactions: Vec<RecordedInteraction>,
+}
+
+impl Recorder {
+    pub fn new(start: fm::Time) -> Self {
+        Self {
+            start,
+            interactions: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, now: fm::Time, interaction: Interaction) {
+        self.interactions.push(RecordedInteraction {
+            time: now - self.start,
+            interaction,
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        let recording = Recording {
+            version: RECORDING_VERSION,
+            interactions: self.interactions.clone(),
+        };
+        serde_json::to_string(&recording).unwrap()
+    }
+}
+
+pub fn parse_recording(json: &str) -> Result<Vec<RecordedInteraction>> {
+    let recording: Recording = serde_json::from_str(json).map_err(|e| {
+        Error::with_source(
+            MalformedData,
+            "failed to parse interaction recording".to_string(),
+            e,
+        )
+    })?;
+
+    if recording.version != RECORDING_VERSION {
+        let desc = format!(
+            "unsupported interaction recording version {}",
+            recording.version
+        );
+        return Err(Error::new(UnsupportedFeature, desc));
+    }
+
+    Ok(recording.interactions)
+}
@@ -1,9 +1,13 @@
 precision mediump float;
 
 varying float vert_element;
+varying vec3 vert_normal;
 varying vec2 vert_texture;
 
 uniform sampler2D textures[MAX_TEXTURE_IMAGE_UNITS];
+uniform bool textured;
+
+const vec3 LIGHT_DIRECTION = vec3(0.3, 0.5, 0.81);
 
 vec4 get_texture_color(int index, vec2 point) {
     if (index == 0) return texture2D(textures[0], point);
@@ -45,6 +49,19 @@ vec4 get_texture_color(int index, vec2 point) {
     return vec4(0.0, 0.0, 0.0, 0.0);
 }
 
+// Gray diffuse shading, faces without normals are flat.
+vec4 get_shaded_color() {
+    float light = 0.5;
+    if (length(vert_normal) > 0.0) {
+        light = abs(dot(normalize(vert_normal), LIGHT_DIRECTION));
+    }
+    return vec4(vec3(0.25 + 0.65 * light), 1.0);
+}
+
 void main() {
-    gl_FragColor = get_texture_color(int(vert_element), vert_texture);
+    if (textured) {
+        gl_FragColor = get_texture_color(int(vert_element), vert_texture);
+    } else {
+        gl_FragColor = get_shaded_color();
+    }
 }
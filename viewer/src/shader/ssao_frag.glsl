@@ -0,0 +1,50 @@
+#ifdef GL_FRAGMENT_PRECISION_HIGH
+precision highp float;
+#else
+precision mediump float;
+#endif
+
+varying vec2 vert_point;
+
+uniform sampler2D color;
+uniform sampler2D depth;
+uniform float aspect;
+uniform float near_plane;
+uniform float far_plane;
+
+const int NUM_SAMPLES = 16;
+const float RADIUS = 0.02; // Relative to the viewport height.
+const float STRENGTH = 0.8;
+const float GOLDEN_ANGLE = 2.39996;
+
+float linear_depth(vec2 point) {
+    float z = texture2D(depth, point).r * 2.0 - 1.0;
+    float range = far_plane - near_plane;
+    return 2.0 * near_plane * far_plane / (far_plane + near_plane - z * range);
+}
+
+void main() {
+    vec4 base = texture2D(color, vert_point);
+    if (texture2D(depth, vert_point).r >= 1.0) {
+        gl_FragColor = base; // Background.
+        return;
+    }
+
+    // Samples in front of the point (along a spiral around it) occlude
+    // it, unless they are too far to be its surroundings.
+    float center = linear_depth(vert_point);
+    float max_diff = center * RADIUS * 4.0;
+    float occlusion = 0.0;
+    for (int i = 0; i < NUM_SAMPLES; i++) {
+        float t = (float(i) + 0.5) / float(NUM_SAMPLES);
+        float angle = float(i) * GOLDEN_ANGLE;
+        vec2 offset = vec2(cos(angle) / aspect, sin(angle)) * RADIUS * sqrt(t);
+        float diff = center - linear_depth(vert_point + offset);
+        if (diff > center * 0.001 && diff < max_diff) {
+            occlusion += 1.0 - diff / max_diff;
+        }
+    }
+
+    float light = 1.0 - STRENGTH * occlusion / float(NUM_SAMPLES);
+    gl_FragColor = vec4(base.rgb * light, base.a);
+}
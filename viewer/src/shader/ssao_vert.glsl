@@ -0,0 +1,10 @@
+precision mediump float;
+
+attribute vec2 position;
+
+varying vec2 vert_point;
+
+void main() {
+    vert_point = position * 0.5 + 0.5;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
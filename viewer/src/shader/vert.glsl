@@ -1,10 +1,12 @@
 precision mediump float;
 
 attribute float element;
+attribute vec3 normal;
 attribute vec2 texture;
 attribute vec3 vertex;
 
 varying float vert_element;
+varying vec3 vert_normal;
 varying vec2 vert_texture;
 
 uniform mat4 projection;
@@ -12,6 +14,7 @@ uniform mat4 view;
 
 void main() {
     vert_element = element;
+    vert_normal = normal;
     vert_texture = texture;
     gl_Position = projection * view * vec4(vertex, 1.0);
 }
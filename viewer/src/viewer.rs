@@ -1,87 +1,568 @@
+use std::cell::RefCell;
 use std::io::Cursor;
 use std::rc::Rc;
 use std::result::Result as StdResult;
 
-use js_sys::{ArrayBuffer, Promise};
+use js_sys::{Array, ArrayBuffer, Function, Promise, Reflect};
 use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::JsCast;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::future_to_promise;
 use web_sys::HtmlCanvasElement;
+use web_sys::{CustomEvent, CustomEventInit, Event, EventTarget};
 
-use crate::controller::Controller;
-use crate::defs::IntoJsResult;
+use crate::controller::{Adapter, Controller, ViewerOptions};
+use crate::defs::{IntoJsResult, JsResult};
+use crate::metrics::ViewerMetrics;
+use crate::util::web;
 use crate::webgl_adapter::WebGlAdapter;
 use base::fm;
 
 // The async-syntax is avoided because of a known wasm-bindgen issue,
 // see https://github.com/rustwasm/wasm-bindgen/issues/2195.
 
+// The doc comments below are copied by wasm-bindgen into viewer.d.ts.
+
+#[wasm_bindgen(typescript_custom_section)]
+const VIEWER_EVENTS: &'static str = r#"
+/**
+ * Events dispatched by `Viewer.events`:
+ * - `load` when `loadFmBuffer` succeeds;
+ * - `play` when `renderAll`, `renderPeriod` or `replay` starts;
+ * - `ended` when `renderAll`, `renderPeriod` or `replay` finishes;
+ * - `error` when an operation fails, `detail` holds the message.
+ */
+export interface ViewerEventMap {
+  load: Event;
+  play: Event;
+  ended: Event;
+  error: CustomEvent<string>;
+}
+
+export interface ViewerEventTarget extends EventTarget {
+  addEventListener<K extends keyof ViewerEventMap>(
+    type: K,
+    listener: (event: ViewerEventMap[K]) => void,
+    options?: boolean | AddEventListenerOptions,
+  ): void;
+  removeEventListener<K extends keyof ViewerEventMap>(
+    type: K,
+    listener: (event: ViewerEventMap[K]) => void,
+    options?: boolean | EventListenerOptions,
+  ): void;
+}
+"#;
+
+#[wasm_bindgen(typescript_custom_section)]
+const VIEWER_OPTIONS: &'static str = r#"
+/**
+ * Viewer settings, omitted fields keep their current (or default) values.
+ * Angles are in radians.
+ */
+export interface ViewerOptions {
+  /** Rotation angle per pointer movement pixel (0.01). */
+  rotationSensitivity?: number;
+  /** Relative change of eye distance per wheel delta unit (0.001). */
+  zoomSensitivity?: number;
+  /** Minimal eye distance from the view center (0.2). */
+  minEyeDistance?: number;
+  /** Maximal eye distance from the view center (500). */
+  maxEyeDistance?: number;
+  /** Minimal eye elevation above the horizon, negative below it (0). */
+  minElevation?: number;
+  /** Maximal eye elevation above the horizon (1.5). */
+  maxElevation?: number;
+  /** Decay time (seconds) of orbit and zoom velocity, 0 disables (0.15). */
+  inertia?: number;
+  /** Duration (seconds) of programmatic camera changes (0.4). */
+  transitionDuration?: number;
+  /** Vertical field of view (pi / 4). */
+  fieldOfView?: number;
+  /** Near clipping plane distance (0.1). */
+  nearPlane?: number;
+  /** Far clipping plane distance (1000). */
+  farPlane?: number;
+  /** Initial camera position relative to the view center ([1, 1, 1]). */
+  eyePosition?: [number, number, number];
+  /**
+   * Show pre-rendered impostors instead of meshes if a loaded model has
+   * them, e.g. on low-end devices; applies to the next load (false).
+   */
+  impostors?: boolean;
+  /** Interpolation of animation states between their times ("quadratic"). */
+  interpolation?: "nearest" | "linear" | "quadratic";
+  /**
+   * Render coarser levels of detail (see `composer build-view --lods`), then
+   * no textures while playback FPS stays below `minFps`, restoring quality
+   * when it recovers (false).
+   */
+  adaptiveQuality?: boolean;
+  /** FPS threshold of adaptive quality (30). */
+  minFps?: number;
+  /**
+   * Darken creases and cavities by screen-space ambient occlusion, which
+   * makes shape readable on untextured meshes; fails without WebGL depth
+   * textures (false).
+   */
+  ambientOcclusion?: boolean;
+  /**
+   * Number of times `renderAll` plays element loops set by
+   * `composer set-loop`, a ping-pong loop going there and back each time (1).
+   */
+  loopCount?: number;
+}
+"#;
+
+#[wasm_bindgen(typescript_custom_section)]
+const MODEL_BOUNDS: &'static str = r#"
+/** Bounding sphere of the loaded model over all its states. */
+export interface ModelBounds {
+  center: [number, number, number];
+  radius: number;
+}
+"#;
+
+#[wasm_bindgen(typescript_custom_section)]
+const VIEWER_METRICS: &'static str = r#"
+/**
+ * Playback quality passed to the `setMetricsCallback` callback after each
+ * load, playback and error. Times are in seconds, frame fields describe
+ * the last `renderAll`, `renderPeriod` or `replay`.
+ */
+export interface ViewerMetrics {
+  /** GPU vendor, unmasked if the browser allows. */
+  gpuVendor?: string;
+  /** GPU and driver description, unmasked if the browser allows. */
+  gpuRenderer?: string;
+  /** Duration of parsing and uploading of the last loaded model. */
+  loadTime?: number;
+  /** Time from the `loadFmBuffer` call until the model is shown. */
+  timeToFirstRender?: number;
+  /** Number of rendered frames. */
+  frames?: number;
+  averageFps?: number;
+  medianFps?: number;
+  /** FPS of the 5% slowest frames. */
+  lowFps?: number;
+  /** Display refreshes missed because of slow frames. */
+  droppedFrames?: number;
+  /** Number of failed operations since the viewer creation. */
+  errors: number;
+  lastError?: string;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(
+        extends = EventTarget,
+        typescript_type = "ViewerEventTarget"
+    )]
+    pub type ViewerEventTarget;
+
+    #[wasm_bindgen(typescript_type = "ViewerOptions")]
+    pub type JsViewerOptions;
+
+    #[wasm_bindgen(typescript_type = "ModelBounds")]
+    pub type JsModelBounds;
+
+    #[wasm_bindgen(typescript_type = "ViewerMetrics")]
+    pub type JsViewerMetrics;
+
+    #[wasm_bindgen(typescript_type = "(metrics: ViewerMetrics) => void")]
+    pub type JsMetricsCallback;
+}
+
+/// Renders .fm models and their animation on a canvas using WebGL.
 #[wasm_bindgen]
 pub struct Viewer {
+    adapter: Rc<WebGlAdapter>,
     controller: Rc<Controller<WebGlAdapter>>,
+    reporter: Reporter,
 }
 
 #[wasm_bindgen]
 impl Viewer {
-    pub fn create(canvas: HtmlCanvasElement) -> StdResult<Viewer, JsValue> {
+    /// Creates a viewer drawing on the given canvas.
+    pub fn create(
+        canvas: HtmlCanvasElement,
+        options: Option<JsViewerOptions>,
+    ) -> StdResult<Viewer, JsValue> {
         #[cfg(feature = "console_error_panic_hook")]
         console_error_panic_hook::set_once();
 
+        let mut opts = ViewerOptions::default();
+        if let Some(options) = options {
+            merge_options(&mut opts, &options)?;
+        }
+
         let adapter = WebGlAdapter::create(canvas).into_result()?;
-        let controller = Controller::create(adapter).into_result()?;
-        Ok(Viewer { controller })
+        let controller =
+            Controller::create(adapter.clone(), opts).into_result()?;
+
+        let metrics = ViewerMetrics {
+            gpu: adapter.gpu_info(),
+            ..Default::default()
+        };
+        let reporter = Reporter {
+            events: EventTarget::new()?,
+            metrics: Rc::new(RefCell::new(metrics)),
+            callback: Rc::new(RefCell::new(None)),
+        };
+
+        Ok(Viewer {
+            adapter,
+            controller,
+            reporter,
+        })
     }
 
+    /// Releases WebGL resources; the viewer is unusable afterwards.
     pub fn destroy(&self) {
         self.controller.destroy();
     }
 
+    /// Changes given options leaving the others intact.
+    #[wasm_bindgen(js_name = setOptions)]
+    pub fn set_options(
+        &self,
+        options: JsViewerOptions,
+    ) -> StdResult<(), JsValue> {
+        let mut opts = self.controller.options();
+        merge_options(&mut opts, &options)?;
+        let result = self.controller.set_options(opts).into_result();
+        self.reporter.report_error(result)
+    }
+
+    /// Target of viewer events, see `ViewerEventMap` for their types.
+    #[wasm_bindgen(getter)]
+    pub fn events(&self) -> ViewerEventTarget {
+        self.reporter.events.clone().unchecked_into()
+    }
+
+    /// Sets a function receiving `ViewerMetrics` (e.g. to monitor playback
+    /// quality across devices), undefined removes it.
+    #[wasm_bindgen(js_name = setMetricsCallback)]
+    pub fn set_metrics_callback(&self, callback: Option<JsMetricsCallback>) {
+        *self.reporter.callback.borrow_mut() =
+            callback.map(|c| c.unchecked_into());
+    }
+
+    /// Current metrics, see `ViewerMetrics`.
+    #[wasm_bindgen(js_name = getMetrics)]
+    pub fn get_metrics(&self) -> StdResult<JsViewerMetrics, JsValue> {
+        metrics_to_js(&self.reporter.metrics.borrow())
+    }
+
+    /// Loads .fm file contents replacing the previously loaded model,
+    /// then fits the view to it.
     #[wasm_bindgen(js_name = loadFmBuffer)]
     pub fn load_fm_buffer(&self, buffer: ArrayBuffer) -> Promise {
+        let adapter = self.adapter.clone();
         let controller = self.controller.clone();
+        let reporter = self.reporter.clone();
         let buffer = Cursor::new(js_sys::Uint8Array::new(&buffer).to_vec());
 
         future_to_promise(async move {
-            let mut reader = fm::Reader::new(buffer).into_result()?;
-            controller.load(&mut reader).await.into_result()?;
+            let start = adapter.now();
+            let result = async {
+                let mut reader = fm::Reader::new(buffer)?;
+                controller.load(&mut reader).await?;
+                let loaded = adapter.now();
+                controller.fit_view()?;
+                Ok(loaded)
+            };
+            let loaded = reporter.report_error(result.await.into_result())?;
+
+            // The first frame is drawn by the next animation frame.
+            web::next_frame().await;
+            let mut metrics = reporter.metrics.borrow_mut();
+            metrics.load_time = Some(loaded - start);
+            metrics.time_to_first_render = Some(adapter.now() - start);
+            metrics.frames = None;
+            drop(metrics);
+
+            reporter.dispatch("load")?;
+            reporter.report_metrics()?;
             Ok(JsValue::NULL)
         })
     }
 
+    /// Plays the whole loaded animation in real time.
     #[wasm_bindgen(js_name = renderAll)]
     pub fn render_all(&self) -> Promise {
         let controller = self.controller.clone();
+        let reporter = self.reporter.clone();
 
         future_to_promise(async move {
-            controller.render_all().await.into_result()?;
+            reporter.dispatch("play")?;
+            let result = controller.render_all().await.into_result();
+            reporter.report_error(result)?;
+            reporter.report_playback(&controller)?;
             Ok(JsValue::NULL)
         })
     }
 
+    /// Renders a single animation moment given in seconds (i.e. seeks).
     #[wasm_bindgen(js_name = renderMoment)]
     pub fn render_moment(&self, at: f64) -> StdResult<(), JsValue> {
         let at = Self::seconds_to_time(at);
-        self.controller.render_moment(at).into_result()
+        let result = self.controller.render_moment(at).into_result();
+        self.reporter.report_error(result)
     }
 
+    /// Plays the animation between two moments given in seconds.
     #[wasm_bindgen(js_name = renderPeriod)]
     pub fn render_period(&self, from: f64, to: f64) -> Promise {
         let controller = self.controller.clone();
+        let reporter = self.reporter.clone();
         let from = Self::seconds_to_time(from);
         let to = Self::seconds_to_time(to);
 
         future_to_promise(async move {
-            controller.render_period(from, to).await.into_result()?;
+            reporter.dispatch("play")?;
+            let result = controller.render_period(from, to).await.into_result();
+            reporter.report_error(result)?;
+            reporter.report_playback(&controller)?;
             Ok(JsValue::NULL)
         })
     }
 
+    /// Centers the view on the model making it fully visible.
+    #[wasm_bindgen(js_name = fitView)]
+    pub fn fit_view(&self) -> StdResult<(), JsValue> {
+        let result = self.controller.fit_view().into_result();
+        self.reporter.report_error(result)
+    }
+
+    /// Bounds of the loaded model or undefined if it has no states.
+    #[wasm_bindgen(js_name = getBounds)]
+    pub fn get_bounds(&self) -> StdResult<Option<JsModelBounds>, JsValue> {
+        let bounds = match self.controller.bounds() {
+            Some(bounds) => bounds,
+            None => return Ok(None),
+        };
+
+        let center = Array::of3(
+            &bounds.center.x.into(),
+            &bounds.center.y.into(),
+            &bounds.center.z.into(),
+        );
+        let object = js_sys::Object::new();
+        Reflect::set(&object, &"center".into(), &center)?;
+        Reflect::set(&object, &"radius".into(), &bounds.radius.into())?;
+        Ok(Some(object.unchecked_into()))
+    }
+
+    /// Moves the camera back to its default position.
     #[wasm_bindgen(js_name = resetEyePosition)]
     pub fn reset_eye_position(&self) -> StdResult<(), JsValue> {
-        self.controller.reset_eye_position().into_result()
+        let result = self.controller.reset_eye_position().into_result();
+        self.reporter.report_error(result)
+    }
+
+    /// Places the camera at the given point relative to the view center.
+    #[wasm_bindgen(js_name = setEyePosition)]
+    pub fn set_eye_position(
+        &self,
+        x: f32,
+        y: f32,
+        z: f32,
+    ) -> StdResult<(), JsValue> {
+        let pos = fm::Point3 { x, y, z };
+        let result = self.controller.set_eye_position(pos).into_result();
+        self.reporter.report_error(result)
+    }
+
+    /// Starts recording camera and playback interactions (e.g. to attach
+    /// them to a bug report), dropping any unfinished recording.
+    #[wasm_bindgen(js_name = startRecording)]
+    pub fn start_recording(&self) {
+        self.controller.start_recording();
+    }
+
+    /// Stops recording and returns it as JSON or undefined if not recording.
+    #[wasm_bindgen(js_name = stopRecording)]
+    pub fn stop_recording(&self) -> Option<String> {
+        self.controller.stop_recording()
+    }
+
+    /// Replays JSON returned by `stopRecording` over the loaded model.
+    pub fn replay(&self, recording: String) -> Promise {
+        let controller = self.controller.clone();
+        let reporter = self.reporter.clone();
+
+        future_to_promise(async move {
+            reporter.dispatch("play")?;
+            let result = controller.replay(&recording).await.into_result();
+            reporter.report_error(result)?;
+            reporter.report_playback(&controller)?;
+            Ok(JsValue::NULL)
+        })
     }
 
     fn seconds_to_time(seconds: f64) -> fm::Time {
-        (seconds * 1E9) as fm::Time
+        fm::Time::from_secs_f64(seconds)
+    }
+}
+
+// Delivers events and metrics, shared with pending operations.
+#[derive(Clone)]
+struct Reporter {
+    events: EventTarget,
+    metrics: Rc<RefCell<ViewerMetrics>>,
+    callback: Rc<RefCell<Option<Function>>>,
+}
+
+impl Reporter {
+    fn dispatch(&self, r#type: &str) -> JsResult<()> {
+        self.events.dispatch_event(&Event::new(r#type)?)?;
+        Ok(())
+    }
+
+    // Dispatches 'error' event with the message of a failed operation.
+    fn report_error<T>(&self, result: JsResult<T>) -> JsResult<T> {
+        if let Err(err) = &result {
+            let mut metrics = self.metrics.borrow_mut();
+            metrics.num_errors += 1;
+            metrics.last_error = err.as_string();
+            drop(metrics);
+
+            let init = CustomEventInit::new();
+            init.set_detail(err);
+            let event = CustomEvent::new_with_event_init_dict("error", &init)?;
+            self.events.dispatch_event(&event)?;
+            self.report_metrics()?;
+        }
+        result
+    }
+
+    fn report_playback(
+        &self,
+        controller: &Controller<WebGlAdapter>,
+    ) -> JsResult<()> {
+        self.metrics.borrow_mut().frames = controller.frame_summary();
+        self.dispatch("ended")?;
+        self.report_metrics()
+    }
+
+    fn report_metrics(&self) -> JsResult<()> {
+        let callback = self.callback.borrow().clone();
+        if let Some(callback) = callback {
+            let metrics = metrics_to_js(&self.metrics.borrow())?;
+            callback.call1(&JsValue::UNDEFINED, &metrics)?;
+        }
+        Ok(())
+    }
+}
+
+fn metrics_to_js(metrics: &ViewerMetrics) -> JsResult<JsViewerMetrics> {
+    let object = js_sys::Object::new();
+    let set = |name: &str, value: JsValue| {
+        Reflect::set(&object, &JsValue::from_str(name), &value).map(|_| ())
+    };
+    let seconds = |time: fm::Time| JsValue::from_f64(time.as_secs_f64());
+
+    if !metrics.gpu.vendor.is_empty() {
+        set("gpuVendor", metrics.gpu.vendor.as_str().into())?;
+    }
+    if !metrics.gpu.renderer.is_empty() {
+        set("gpuRenderer", metrics.gpu.renderer.as_str().into())?;
+    }
+    if let Some(time) = metrics.load_time {
+        set("loadTime", seconds(time))?;
+    }
+    if let Some(time) = metrics.time_to_first_render {
+        set("timeToFirstRender", seconds(time))?;
+    }
+    if let Some(frames) = &metrics.frames {
+        set("frames", (frames.num_frames as u32).into())?;
+        set("averageFps", frames.average_fps.into())?;
+        set("medianFps", frames.median_fps.into())?;
+        set("lowFps", frames.low_fps.into())?;
+        set("droppedFrames", (frames.num_dropped as u32).into())?;
     }
+    set("errors", (metrics.num_errors as u32).into())?;
+    if let Some(err) = &metrics.last_error {
+        set("lastError", err.as_str().into())?;
+    }
+
+    Ok(object.unchecked_into())
+}
+
+fn merge_options(
+    options: &mut ViewerOptions,
+    object: &JsViewerOptions,
+) -> JsResult<()> {
+    let get = |name: &str| Reflect::get(object, &JsValue::from_str(name));
+    let malformed = |name: &str| {
+        JsValue::from_str(&format!("malformed viewer option '{}'", name))
+    };
+
+    let numbers = [
+        ("rotationSensitivity", &mut options.rotation_sensitivity),
+        ("zoomSensitivity", &mut options.zoom_sensitivity),
+        ("minEyeDistance", &mut options.min_eye_distance),
+        ("maxEyeDistance", &mut options.max_eye_distance),
+        ("minElevation", &mut options.min_elevation),
+        ("maxElevation", &mut options.max_elevation),
+        ("inertia", &mut options.inertia),
+        ("transitionDuration", &mut options.transition_duration),
+        ("fieldOfView", &mut options.field_of_view),
+        ("nearPlane", &mut options.near_plane),
+        ("farPlane", &mut options.far_plane),
+        ("minFps", &mut options.min_fps),
+    ];
+    for (name, field) in numbers {
+        let value = get(name)?;
+        if !value.is_undefined() {
+            *field = value.as_f64().ok_or_else(|| malformed(name))? as f32;
+        }
+    }
+
+    let flags = [
+        ("impostors", &mut options.impostors),
+        ("adaptiveQuality", &mut options.adaptive_quality),
+        ("ambientOcclusion", &mut options.ambient_occlusion),
+    ];
+    for (name, field) in flags {
+        let value = get(name)?;
+        if !value.is_undefined() {
+            *field = value.as_bool().ok_or_else(|| malformed(name))?;
+        }
+    }
+
+    let value = get("loopCount")?;
+    if !value.is_undefined() {
+        options.loop_count = value
+            .as_f64()
+            .filter(|n| n.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(n))
+            .ok_or_else(|| malformed("loopCount"))?
+            as u32;
+    }
+
+    let value = get("interpolation")?;
+    if !value.is_undefined() {
+        options.interpolation = value
+            .as_string()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| malformed("interpolation"))?;
+    }
+
+    let value = get("eyePosition")?;
+    if !value.is_undefined() {
+        let coords: Vec<_> = Array::from(&value)
+            .iter()
+            .map(|c| c.as_f64().map(|c| c as f32))
+            .collect::<Option<_>>()
+            .filter(|c: &Vec<_>| c.len() == 3)
+            .ok_or_else(|| malformed("eyePosition"))?;
+        options.eye_position = fm::Point3 {
+            x: coords[0],
+            y: coords[1],
+            z: coords[2],
+        };
+    }
+
+    Ok(())
 }
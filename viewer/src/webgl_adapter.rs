@@ -1,6 +1,7 @@
-use std::cell::Cell;
-use std::f32::consts::PI;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
 use std::mem::size_of;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::slice::from_raw_parts;
 
@@ -10,21 +11,37 @@ use js_sys::Reflect::{get, set};
 use js_sys::{Uint16Array, Uint8Array};
 use memoffset::offset_of;
 use wasm_bindgen::JsCast;
-use web_sys::{window, HtmlCanvasElement, WebGlProgram, WebGlRenderingContext};
+use web_sys::{
+    window, HtmlCanvasElement, WebGlBuffer, WebGlProgram,
+    WebGlRenderingContext, WebGlTexture,
+};
 
+use crate::ambient_occlusion::AmbientOcclusion;
 use crate::controller::{Adapter, Face, PointerEvent, VertexData};
 use crate::defs::IntoResult;
+use crate::metrics::GpuInfo;
 use crate::util::glam::point3_to_vec3;
 use crate::util::web;
 use crate::util::webgl;
 use base::defs::Result;
 use base::fm;
 
+// Constants of WEBGL_debug_renderer_info extension.
+const UNMASKED_VENDOR_WEBGL: u32 = 0x9245;
+const UNMASKED_RENDERER_WEBGL: u32 = 0x9246;
+
 pub struct WebGlAdapter {
+    ambient_occlusion: RefCell<Option<AmbientOcclusion>>,
     canvas: HtmlCanvasElement,
+    clip_planes: Cell<(f32, f32)>,
     context: WebGlRenderingContext,
+    eye: Cell<Vec3>,
     now_offset: Cell<fm::Time>,
     program: WebGlProgram,
+    // Element textures by their indices (i.e. texture units).
+    textures: RefCell<Vec<Option<WebGlTexture>>>,
+    vertex_buffer: WebGlBuffer,
+    view_center: Cell<Vec3>,
 }
 
 impl WebGlAdapter {
@@ -66,66 +83,160 @@ impl WebGlAdapter {
             webgl::link_program(&context, &vert_shader, &frag_shader)?;
         context.use_program(Some(&program));
 
-        let buf = context.create_buffer().unwrap();
-        context.bind_buffer(WebGlRenderingContext::ARRAY_BUFFER, Some(&buf));
+        let vertex_buffer = context.create_buffer().unwrap();
+        context.bind_buffer(
+            WebGlRenderingContext::ARRAY_BUFFER,
+            Some(&vertex_buffer),
+        );
 
-        webgl::define_attribute::<u8>(
-            &context,
-            &program,
-            "element",
-            size_of::<u8>(),
-            size_of::<VertexData>(),
-            offset_of!(VertexData, element),
-        )?;
+        define_attributes(&context, &program)?;
 
-        webgl::define_attribute::<f32>(
-            &context,
-            &program,
-            "texture",
-            size_of::<fm::Point2>(),
-            size_of::<VertexData>(),
-            offset_of!(VertexData, texture),
-        )?;
+        let location =
+            webgl::get_uniform_location(&context, &program, "textured")?;
+        context.uniform1i(Some(&location), 1);
 
-        webgl::define_attribute::<f32>(
-            &context,
-            &program,
-            "vertex",
-            size_of::<fm::Point3>(),
-            size_of::<VertexData>(),
-            offset_of!(VertexData, vertex),
-        )?;
-
-        let adapter = Rc::new(Self {
+        Ok(Rc::new(Self {
+            ambient_occlusion: RefCell::new(None),
             canvas,
+            clip_planes: Cell::new((0.0, 0.0)),
             context,
-            now_offset: Cell::new(0),
+            eye: Cell::new(Vec3::ZERO),
+            now_offset: Cell::new(fm::Time(0)),
             program,
-        });
+            textures: RefCell::new(Vec::new()),
+            vertex_buffer,
+            view_center: Cell::new(Vec3::ZERO),
+        }))
+    }
 
-        adapter.set_projection()?;
+    // Unmasked GPU vendor and renderer if the browser exposes them.
+    pub fn gpu_info(&self) -> GpuInfo {
+        let debug_info = self
+            .context
+            .get_extension("WEBGL_debug_renderer_info")
+            .ok()
+            .flatten()
+            .is_some();
+        let (vendor, renderer) = if debug_info {
+            (UNMASKED_VENDOR_WEBGL, UNMASKED_RENDERER_WEBGL)
+        } else {
+            (
+                WebGlRenderingContext::VENDOR,
+                WebGlRenderingContext::RENDERER,
+            )
+        };
 
-        Ok(adapter)
+        let get = |name| {
+            let value = self.context.get_parameter(name).ok();
+            value.and_then(|v| v.as_string()).unwrap_or_default()
+        };
+        GpuInfo {
+            vendor: get(vendor),
+            renderer: get(renderer),
+        }
     }
 
-    fn set_projection(self: &Rc<Self>) -> Result<()> {
-        let width = self.canvas.client_width() as f32;
-        let height = self.canvas.client_height() as f32;
+    fn draw_faces(&self) {
+        let size = self.context.get_buffer_parameter(
+            WebGlRenderingContext::ELEMENT_ARRAY_BUFFER,
+            WebGlRenderingContext::BUFFER_SIZE,
+        );
 
-        let projection = Mat4::perspective_rh_gl(
-            45.0 * PI / 180.0,
-            width / height,
-            0.1,
-            1000.0,
+        let size = size.as_f64().unwrap() as usize / size_of::<u16>();
+
+        self.context.draw_elements_with_i32(
+            WebGlRenderingContext::TRIANGLES,
+            size as i32,
+            WebGlRenderingContext::UNSIGNED_SHORT,
+            0,
         );
+    }
 
-        webgl::set_uniform_mat4(
-            &self.context,
-            &self.program,
-            "projection",
-            &projection,
-        )
+    // Renders faces into textures and draws them with ambient occlusion.
+    fn draw_occluded_faces(&self, ao: &AmbientOcclusion) -> Result<()> {
+        ao.begin(&self.context)?;
+        self.rebind_textures(&[0, 1]);
+        self.draw_faces();
+
+        for name in ATTRIBUTES {
+            let location =
+                self.context.get_attrib_location(&self.program, name);
+            self.context.disable_vertex_attrib_array(location as u32);
+        }
+        let (near_plane, far_plane) = self.clip_planes.get();
+        ao.finish(&self.context, near_plane, far_plane)?;
+
+        self.context.use_program(Some(&self.program));
+        self.context.bind_buffer(
+            WebGlRenderingContext::ARRAY_BUFFER,
+            Some(&self.vertex_buffer),
+        );
+        define_attributes(&self.context, &self.program)?;
+        self.rebind_textures(&[0, 1]);
+        Ok(())
+    }
+
+    // Binds element textures back to their units.
+    fn rebind_textures(&self, indices: &[usize]) {
+        let textures = self.textures.borrow();
+        for index in indices {
+            self.context.active_texture(texture_num(*index));
+            let texture = textures.get(*index).and_then(Option::as_ref);
+            self.context
+                .bind_texture(WebGlRenderingContext::TEXTURE_2D, texture);
+        }
     }
+
+    fn set_view(self: &Rc<Self>) -> Result<()> {
+        let center = self.view_center.get();
+        let eye = center + self.eye.get();
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let view = Mat4::look_at_rh(eye, center, up);
+        webgl::set_uniform_mat4(&self.context, &self.program, "view", &view)
+    }
+}
+
+const ATTRIBUTES: [&str; 4] = ["element", "normal", "texture", "vertex"];
+
+fn define_attributes(
+    context: &WebGlRenderingContext,
+    program: &WebGlProgram,
+) -> Result<()> {
+    webgl::define_attribute::<u8>(
+        context,
+        program,
+        "element",
+        size_of::<u8>(),
+        size_of::<VertexData>(),
+        offset_of!(VertexData, element),
+    )?;
+
+    webgl::define_attribute::<f32>(
+        context,
+        program,
+        "normal",
+        size_of::<fm::Point3>(),
+        size_of::<VertexData>(),
+        offset_of!(VertexData, normal),
+    )?;
+
+    webgl::define_attribute::<f32>(
+        context,
+        program,
+        "texture",
+        size_of::<fm::Point2>(),
+        size_of::<VertexData>(),
+        offset_of!(VertexData, texture),
+    )?;
+
+    webgl::define_attribute::<f32>(
+        context,
+        program,
+        "vertex",
+        size_of::<fm::Point3>(),
+        size_of::<VertexData>(),
+        offset_of!(VertexData, vertex),
+    )
 }
 
 fn texture_num(index: usize) -> u32 {
@@ -133,7 +244,7 @@ fn texture_num(index: usize) -> u32 {
 }
 
 fn milliseconds_to_time(milliseconds: f64) -> fm::Time {
-    (milliseconds * 1000000.0) as fm::Time
+    fm::Time((milliseconds * 1000000.0) as i64)
 }
 
 #[async_trait(?Send)]
@@ -147,21 +258,63 @@ impl Adapter for WebGlAdapter {
         milliseconds_to_time(now) + self.now_offset.get()
     }
 
+    fn now(self: &Rc<Self>) -> fm::Time {
+        let performance = window().unwrap().performance().unwrap();
+        milliseconds_to_time(performance.now())
+    }
+
     fn render_frame(self: &Rc<Self>) -> Result<()> {
-        let size = self.context.get_buffer_parameter(
-            WebGlRenderingContext::ELEMENT_ARRAY_BUFFER,
-            WebGlRenderingContext::BUFFER_SIZE,
+        match self.ambient_occlusion.borrow().as_ref() {
+            Some(ao) => self.draw_occluded_faces(ao),
+            None => {
+                self.draw_faces();
+                Ok(())
+            }
+        }
+    }
+
+    fn render_layers(
+        self: &Rc<Self>,
+        layers: &[(&[VertexData], f32)],
+    ) -> Result<()> {
+        self.context.clear_color(0.0, 0.0, 0.0, 0.0);
+        self.context.clear(
+            WebGlRenderingContext::COLOR_BUFFER_BIT
+                | WebGlRenderingContext::DEPTH_BUFFER_BIT,
+        );
+        self.context.disable(WebGlRenderingContext::DEPTH_TEST);
+        self.context.enable(WebGlRenderingContext::BLEND);
+        self.context.blend_func(
+            WebGlRenderingContext::CONSTANT_ALPHA,
+            WebGlRenderingContext::ONE,
         );
 
-        let size = size.as_f64().unwrap() as usize / size_of::<u16>();
+        for (vertices, opacity) in layers {
+            self.set_vertices(vertices)?;
+            self.context.blend_color(0.0, 0.0, 0.0, *opacity);
+            self.draw_faces();
+        }
 
-        self.context.draw_elements_with_i32(
-            WebGlRenderingContext::TRIANGLES,
-            size as i32,
-            WebGlRenderingContext::UNSIGNED_SHORT,
-            0,
-        );
+        self.context.disable(WebGlRenderingContext::BLEND);
+        self.context.enable(WebGlRenderingContext::DEPTH_TEST);
+        Ok(())
+    }
 
+    fn set_ambient_occlusion(self: &Rc<Self>, enabled: bool) -> Result<()> {
+        let mut ambient_occlusion = self.ambient_occlusion.borrow_mut();
+        if !enabled {
+            *ambient_occlusion = None;
+            return Ok(());
+        }
+        if ambient_occlusion.is_none() {
+            let ao = AmbientOcclusion::create(&self.context);
+            self.context.bind_buffer(
+                WebGlRenderingContext::ARRAY_BUFFER,
+                Some(&self.vertex_buffer),
+            );
+            self.rebind_textures(&[0]);
+            *ambient_occlusion = Some(ao?);
+        }
         Ok(())
     }
 
@@ -196,11 +349,38 @@ impl Adapter for WebGlAdapter {
         self.now_offset.set(now - milliseconds_to_time(jsnow));
     }
 
+    fn set_projection(
+        self: &Rc<Self>,
+        field_of_view: f32,
+        near_plane: f32,
+        far_plane: f32,
+    ) -> Result<()> {
+        let width = self.canvas.client_width() as f32;
+        let height = self.canvas.client_height() as f32;
+        self.clip_planes.set((near_plane, far_plane));
+
+        let projection = Mat4::perspective_rh_gl(
+            field_of_view,
+            width / height,
+            near_plane,
+            far_plane,
+        );
+
+        webgl::set_uniform_mat4(
+            &self.context,
+            &self.program,
+            "projection",
+            &projection,
+        )
+    }
+
     async fn set_texture(
         self: &Rc<Self>,
         index: usize,
         image: fm::Image,
     ) -> Result<()> {
+        // Decoded first, so that rendering doesn't change bound textures.
+        let image = web::decode_image(&image).await?;
         self.context.active_texture(texture_num(index));
 
         let texture = self.context.create_texture().unwrap();
@@ -235,7 +415,7 @@ impl Adapter for WebGlAdapter {
                 WebGlRenderingContext::RGBA as i32,
                 WebGlRenderingContext::RGBA,
                 WebGlRenderingContext::UNSIGNED_BYTE,
-                &web::decode_image(&image).await?,
+                &image,
             )
             .into_result()?;
 
@@ -249,6 +429,23 @@ impl Adapter for WebGlAdapter {
         self.context
             .bind_texture(WebGlRenderingContext::TEXTURE_2D, Some(&texture));
 
+        let mut textures = self.textures.borrow_mut();
+        if textures.len() <= index {
+            textures.resize(index + 1, None);
+        }
+        if let Some(prev) = textures[index].replace(texture) {
+            self.context.delete_texture(Some(&prev));
+        }
+        Ok(())
+    }
+
+    fn set_textured(self: &Rc<Self>, textured: bool) -> Result<()> {
+        let location = webgl::get_uniform_location(
+            &self.context,
+            &self.program,
+            "textured",
+        )?;
+        self.context.uniform1i(Some(&location), textured as i32);
         Ok(())
     }
 
@@ -270,11 +467,17 @@ impl Adapter for WebGlAdapter {
     }
 
     fn set_eye_position(self: &Rc<Self>, eye: &fm::Point3) -> Result<()> {
-        let eye = point3_to_vec3(eye);
-        let center = Vec3::new(0.0, 0.0, 0.0);
-        let up = Vec3::new(0.0, 0.0, 1.0);
-        let view = Mat4::look_at_rh(eye, center, up);
-        webgl::set_uniform_mat4(&self.context, &self.program, "view", &view)
+        self.eye.set(point3_to_vec3(eye));
+        self.set_view()
+    }
+
+    fn spawn(self: &Rc<Self>, future: Pin<Box<dyn Future<Output = ()>>>) {
+        wasm_bindgen_futures::spawn_local(future);
+    }
+
+    fn set_view_center(self: &Rc<Self>, center: &fm::Point3) -> Result<()> {
+        self.view_center.set(point3_to_vec3(center));
+        self.set_view()
     }
 
     fn subscribe_to_pointer_move<F: Fn(&PointerEvent) + 'static>(